@@ -0,0 +1,46 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Throughput of a typical event in the `json` log format and in the binary
+//! log format, both written to a sink.
+use cli_batteries::logs::BinaryLog;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::io::sink;
+use tracing::{info, info_span, Subscriber};
+use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+fn events(subscriber: impl Subscriber + Send + Sync, name: &str, criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("log_event");
+    group.throughput(Throughput::Elements(1));
+    tracing::subscriber::with_default(subscriber, || {
+        let span = info_span!("packet");
+        let _entered = span.enter();
+        group.bench_function(name, |bencher| {
+            let mut sequence = 0_u64;
+            bencher.iter(|| {
+                sequence += 1;
+                info!(
+                    sequence = black_box(sequence),
+                    port = 4433_u64,
+                    bytes = 1350_u64,
+                    dropped = false,
+                    peer = "10.1.2.3",
+                    "packet forwarded"
+                );
+            });
+        });
+    });
+    group.finish();
+}
+
+fn json(criterion: &mut Criterion) {
+    let subscriber = Registry::default().with(fmt::layer().json().with_writer(sink));
+    events(subscriber, "json", criterion);
+}
+
+fn binary(criterion: &mut Criterion) {
+    let log = BinaryLog::new(sink()).unwrap();
+    events(Registry::default().with(log.clone()), "binary", criterion);
+    log.finish().unwrap();
+}
+
+criterion_group!(benches, json, binary);
+criterion_main!(benches);
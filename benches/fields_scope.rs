@@ -0,0 +1,39 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! The cost per span of the `fields_scope` layer: without the layer, with it
+//! outside any scope, and three levels deep in a scope. The log output stores
+//! the formatted span fields the layer adds to.
+use cli_batteries::{fields_scope, FieldsScopeLayer};
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use std::io::sink;
+use tracing::info_span;
+use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+fn new_span(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("new_span");
+    let output = || fmt::layer().compact().with_writer(sink);
+    tracing::subscriber::with_default(Registry::default().with(output()), || {
+        group.bench_function("without_layer", |bencher| {
+            bencher.iter(|| info_span!("row").in_scope(|| {}));
+        });
+    });
+    let subscriber = Registry::default()
+        .with(output())
+        .with(FieldsScopeLayer::default());
+    tracing::subscriber::with_default(subscriber, || {
+        group.bench_function("outside_scope", |bencher| {
+            bencher.iter(|| info_span!("row").in_scope(|| {}));
+        });
+        block_on(fields_scope([("request_id", 7), ("tenant", 3)], async {
+            let _handler = info_span!("handler").entered();
+            let _query = info_span!("query").entered();
+            group.bench_function("in_scope", |bencher| {
+                bencher.iter(|| info_span!("row").in_scope(|| {}));
+            });
+        }));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, new_span);
+criterion_main!(benches);
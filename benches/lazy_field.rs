@@ -0,0 +1,40 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! The cost of a large field on a hot-path DEBUG event that the log filter
+//! rejects, computed before the event or with `lazy_field`.
+use cli_batteries::{debug_lazy, lazy_field};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::io::sink;
+use tracing::debug;
+use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, Layer, Registry};
+
+fn disabled_event(criterion: &mut Criterion) {
+    let rows = (0..10_000_u64).collect::<Vec<_>>();
+    let subscriber = Registry::default().with(
+        fmt::layer()
+            .with_writer(sink)
+            .with_filter(LevelFilter::INFO),
+    );
+    tracing::subscriber::with_default(subscriber, || {
+        let mut group = criterion.benchmark_group("disabled_event");
+        group.bench_function("format_before_event", |bencher| {
+            bencher.iter(|| {
+                let rows = format!("{:?}", black_box(&rows));
+                debug!(%rows, "loaded");
+            });
+        });
+        group.bench_function("lazy_field", |bencher| {
+            bencher.iter(|| {
+                debug!(rows = %lazy_field(|| format!("{:?}", black_box(&rows))), "loaded");
+            });
+        });
+        group.bench_function("debug_lazy", |bencher| {
+            bencher.iter(|| {
+                debug_lazy!(rows = || black_box(&rows), "loaded");
+            });
+        });
+        group.finish();
+    });
+}
+
+criterion_group!(benches, disabled_event);
+criterion_main!(benches);
@@ -1,12 +1,14 @@
 #![doc = include_str!("../Readme.md")]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
-use clap::Parser;
-use cli_batteries::{trace_from_headers, trace_to_headers, version};
+use cli_batteries::{
+    prelude::*,
+    reexports::tracing::{event, Level},
+    trace_from_headers, trace_to_headers,
+};
 use http::header::HeaderMap;
-use std::{io::Result, path::PathBuf};
+use std::path::PathBuf;
 use tokio::{fs::File, io::AsyncReadExt};
-use tracing::{event, info, info_span, instrument, Instrument, Level};
 
 #[derive(Clone, Debug, Parser)]
 #[group(skip)]
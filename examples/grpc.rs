@@ -0,0 +1,143 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! A gRPC server and a client calling it, with the trace of the client
+//! continued by the server. Run it with `--trace-otlp` and a collector to see
+//! both sides in one trace:
+//!
+//! ```shell
+//! cargo run --example grpc --features tonic -- --trace-otlp
+//! ```
+//!
+//! The service is written out by hand to keep the example free of a build
+//! step, generated services work the same.
+use clap::Parser;
+use cli_batteries::{await_shutdown, inject_trace, run, GrpcTraceLayer, Version};
+use eyre::{eyre, Result};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    client::Grpc as GrpcClient,
+    codec::ProstCodec,
+    codegen::{http, BoxFuture, Service},
+    server::{Grpc as GrpcServer, NamedService, UnaryService},
+    service::interceptor::InterceptedService,
+    transport::{self, server::TcpIncoming, Channel, Server},
+    Request, Response, Status,
+};
+use tracing::{info, info_span, instrument, Instrument};
+
+const VERSION: Version = Version {
+    pkg_name:     "cli-grpc",
+    pkg_version:  env!("CARGO_PKG_VERSION"),
+    pkg_repo:     env!("CARGO_PKG_REPOSITORY"),
+    crate_name:   "grpc",
+    commit_hash:  "0000000000000000000000000000000000000000",
+    long_version: env!("CARGO_PKG_VERSION"),
+    target:       "unknown",
+    app_crates:   vec![],
+};
+
+#[derive(Clone, Debug, Parser)]
+#[group(skip)]
+struct Options {
+    /// Address to serve on
+    #[clap(long, env, default_value = "127.0.0.1:50051")]
+    listen: SocketAddr,
+
+    /// Name to greet
+    #[clap(long, env, default_value = "world")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+struct Text {
+    #[prost(string, tag = "1")]
+    text: String,
+}
+
+/// `example.Greeter/SayHello`
+#[derive(Clone)]
+struct Greeter;
+
+impl NamedService for Greeter {
+    const NAME: &'static str = "example.Greeter";
+}
+
+impl Service<http::Request<transport::Body>> for Greeter {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<transport::Body>) -> Self::Future {
+        Box::pin(async move {
+            let mut grpc = GrpcServer::new(ProstCodec::<Text, Text>::default());
+            Ok(match request.uri().path() {
+                "/example.Greeter/SayHello" => grpc.unary(SayHello, request).await,
+                _ => Status::unimplemented("").to_http(),
+            })
+        })
+    }
+}
+
+struct SayHello;
+
+impl UnaryService<Text> for SayHello {
+    type Response = Text;
+    type Future = BoxFuture<Response<Text>, Status>;
+
+    fn call(&mut self, request: Request<Text>) -> Self::Future {
+        let name = request.into_inner().text;
+        info!(%name, "Greeting");
+        Box::pin(async move {
+            Ok(Response::new(Text {
+                text: format!("Hello, {name}!"),
+            }))
+        })
+    }
+}
+
+#[instrument(skip(channel))]
+async fn say_hello(channel: Channel, name: String) -> Result<String> {
+    let mut client = GrpcClient::new(InterceptedService::new(channel, inject_trace));
+    client.ready().await?;
+    Ok(client
+        .unary(
+            Request::new(Text { text: name }),
+            http::uri::PathAndQuery::from_static("/example.Greeter/SayHello"),
+            ProstCodec::<Text, Text>::default(),
+        )
+        .await?
+        .into_inner()
+        .text)
+}
+
+async fn app(options: Options) -> Result<()> {
+    // Bound before the client connects
+    let incoming = TcpIncoming::new(options.listen, true, None).map_err(|err| eyre!(err))?;
+    let server = tokio::spawn(
+        Server::builder()
+            .layer(GrpcTraceLayer)
+            .add_service(Greeter)
+            .serve_with_incoming_shutdown(incoming, await_shutdown())
+            .instrument(info_span!("server")),
+    );
+
+    let channel = Channel::from_shared(format!("http://{}", options.listen))?;
+    let greeting = say_hello(channel.connect().await?, options.name).await?;
+    info!(%greeting, "Answered");
+
+    cli_batteries::shutdown();
+    server.await??;
+    Ok(())
+}
+
+fn main() {
+    run(VERSION, app);
+}
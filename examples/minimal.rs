@@ -0,0 +1,45 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Smallest possible application, used by the `build-matrix` test harness to
+//! inspect the `--help` output for a given feature set.
+//!
+//! It also shows what the `minimal` feature saves. On `x86_64` Linux the
+//! release binary is 3.9 MiB with the default features and 2.0 MiB with
+//! `minimal`:
+//!
+//! ```shell
+//! cargo build --release --example minimal
+//! cargo build --release --example minimal --features minimal
+//! ```
+use clap::Parser;
+use cli_batteries::{run, Version};
+use std::io::Result;
+use tracing::info;
+
+const VERSION: Version = Version {
+    pkg_name:     "cli-minimal",
+    pkg_version:  env!("CARGO_PKG_VERSION"),
+    pkg_repo:     env!("CARGO_PKG_REPOSITORY"),
+    crate_name:   "minimal",
+    commit_hash:  "0000000000000000000000000000000000000000",
+    long_version: env!("CARGO_PKG_VERSION"),
+    target:       "unknown",
+    app_crates:   vec![],
+};
+
+#[derive(Clone, Debug, Parser)]
+#[group(skip)]
+struct Options {
+    /// Name to greet
+    #[clap(long, env, default_value = "world")]
+    name: String,
+}
+
+#[allow(clippy::unused_async)]
+async fn app(options: Options) -> Result<()> {
+    info!("Hello, {}!", options.name);
+    Ok(())
+}
+
+fn main() {
+    run(VERSION, app);
+}
@@ -0,0 +1,76 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Procedural macros for [`cli-batteries`](https://docs.rs/cli-batteries).
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Error, ItemFn, ReturnType};
+
+/// A test with its tracing output shown only when it fails.
+///
+/// See `cli_batteries::test_subscriber`. Works on `fn` and `async fn` tests,
+/// async ones run on a current thread Tokio runtime. Tests may return a
+/// `Result`, an `Err` counts as a failure.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return Error::new_spanned(
+            proc_macro2::TokenStream::from(args),
+            "#[cli_batteries::test] takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let input = parse_macro_input!(item as ItemFn);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &ItemFn) -> Result<proc_macro2::TokenStream, Error> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = input;
+    if !sig.inputs.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.inputs,
+            "test functions can not have arguments",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "test functions can not be generic",
+        ));
+    }
+    let name = &sig.ident;
+    let output = &sig.output;
+    let body = if sig.asyncness.is_some() {
+        quote! {
+            async fn body() #output #block
+            ::cli_batteries::__block_on(body())
+        }
+    } else {
+        quote! {
+            fn body() #output #block
+            body()
+        }
+    };
+    let result_type = match output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    Ok(quote! {
+        #[::core::prelude::v1::test]
+        #(#attrs)*
+        #vis fn #name() #output {
+            let subscriber = ::cli_batteries::test_subscriber();
+            let result: #result_type = { #body };
+            if ::cli_batteries::__Outcome::failed(&result) {
+                subscriber.fail();
+            }
+            result
+        }
+    })
+}
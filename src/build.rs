@@ -14,6 +14,10 @@ use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
 /// * `COMMIT_DATE`: The commit date.
 /// * `BUILD_DATE`: The current date.
 ///
+/// And writes the log defaults from `log-defaults.toml`, if any, to
+/// `$OUT_DIR/log_defaults.rs` for
+/// [`include_log_defaults!`](crate::include_log_defaults).
+///
 /// # Errors
 ///
 /// Returns an error if something went wrong, for example `git` failed.
@@ -55,6 +59,7 @@ pub fn build_rs() -> Result<()> {
         "cargo:rustc-env=TARGET={}",
         var("TARGET").wrap_err("Fetching environment variable TARGET")?
     );
+    crate::trace::embed_log_defaults()?;
 
     Ok(())
 }
@@ -0,0 +1,185 @@
+//! Resource limits imposed through Linux control groups.
+//!
+//! In containers [`available_parallelism`] and the system memory reflect the
+//! host, not the limits the container runs under. On Linux the cgroup v2
+//! (`cpu.max`, `memory.max`) and v1 (`cpu.cfs_quota_us`,
+//! `memory.limit_in_bytes`) interfaces are consulted. Elsewhere these functions
+//! fall back to the standard library behaviour.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+use std::{fs, num::NonZeroUsize, path::Path, thread::available_parallelism};
+
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+/// Values of `memory.limit_in_bytes` at or above this are cgroup v1's way of
+/// saying "unlimited".
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// Number of CPUs the process can use, including cgroup CPU quotas.
+///
+/// This is never more than [`available_parallelism`] and at least one.
+#[must_use]
+pub fn effective_cpus() -> NonZeroUsize {
+    let available = available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    cpu_quota().map_or(available, |quota| limit_cpus(available, quota))
+}
+
+/// Memory limit of the process in bytes, if any.
+#[must_use]
+pub fn memory_limit() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let (v2, v1) = cgroup_roots();
+        v2.and_then(|root| read_memory_v2(&root))
+            .or_else(|| read_memory_v1(&v1))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// CPU quota as a fractional number of CPUs, if any.
+fn cpu_quota() -> Option<f64> {
+    #[cfg(target_os = "linux")]
+    {
+        let (v2, v1) = cgroup_roots();
+        v2.and_then(|root| read_cpu_v2(&root))
+            .or_else(|| read_cpu_v1(&v1))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Locations of the unified (v2) cgroup of this process, if any, and the
+/// legacy (v1) hierarchy.
+#[cfg(target_os = "linux")]
+fn cgroup_roots() -> (Option<PathBuf>, PathBuf) {
+    let mount = Path::new("/sys/fs/cgroup");
+    let v2 = fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|contents| {
+            contents
+                .lines()
+                .find_map(|line| line.strip_prefix("0::"))
+                .map(|path| mount.join(path.trim_start_matches('/')))
+        });
+    (v2, mount.to_path_buf())
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn limit_cpus(available: NonZeroUsize, quota: f64) -> NonZeroUsize {
+    let limit = NonZeroUsize::new(quota.ceil() as usize).unwrap_or(NonZeroUsize::MIN);
+    available.min(limit)
+}
+
+/// Parse a cgroup v2 `cpu.max` file: `$MAX $PERIOD` where `$MAX` can be
+/// `max`.
+fn read_cpu_v2(root: &Path) -> Option<f64> {
+    let contents = fs::read_to_string(root.join("cpu.max")).ok()?;
+    let mut parts = contents.split_whitespace();
+    let quota = parts.next()?.parse::<f64>().ok()?;
+    let period = parts.next()?.parse::<f64>().ok()?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Read cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us`. A quota of `-1`
+/// means unlimited.
+fn read_cpu_v1(root: &Path) -> Option<f64> {
+    let read = |file: &str| -> Option<f64> {
+        fs::read_to_string(root.join("cpu").join(file))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    let quota = read("cpu.cfs_quota_us")?;
+    let period = read("cpu.cfs_period_us")?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Parse a cgroup v2 `memory.max` file, which can be `max`.
+fn read_memory_v2(root: &Path) -> Option<u64> {
+    fs::read_to_string(root.join("memory.max"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn read_memory_v1(root: &Path) -> Option<u64> {
+    fs::read_to_string(root.join("memory").join("memory.limit_in_bytes"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&limit| limit < V1_UNLIMITED)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/cgroup")
+            .join(name)
+    }
+
+    #[test]
+    fn test_v2_limited() {
+        let root = fixture("v2-limited");
+        assert_eq!(read_cpu_v2(&root), Some(1.5));
+        assert_eq!(read_memory_v2(&root), Some(536_870_912));
+    }
+
+    #[test]
+    fn test_v2_unlimited() {
+        let root = fixture("v2-unlimited");
+        assert_eq!(read_cpu_v2(&root), None);
+        assert_eq!(read_memory_v2(&root), None);
+    }
+
+    #[test]
+    fn test_v1_limited() {
+        let root = fixture("v1-limited");
+        assert_eq!(read_cpu_v1(&root), Some(2.0));
+        assert_eq!(read_memory_v1(&root), Some(1_073_741_824));
+    }
+
+    #[test]
+    fn test_v1_unlimited() {
+        let root = fixture("v1-unlimited");
+        assert_eq!(read_cpu_v1(&root), None);
+        assert_eq!(read_memory_v1(&root), None);
+    }
+
+    #[test]
+    fn test_missing() {
+        let root = fixture("does-not-exist");
+        assert_eq!(read_cpu_v2(&root), None);
+        assert_eq!(read_cpu_v1(&root), None);
+        assert_eq!(read_memory_v2(&root), None);
+        assert_eq!(read_memory_v1(&root), None);
+    }
+
+    #[test]
+    fn test_limit_cpus() {
+        let n = |n| NonZeroUsize::new(n).unwrap();
+        assert_eq!(limit_cpus(n(8), 1.5), n(2));
+        assert_eq!(limit_cpus(n(8), 0.1), n(1));
+        assert_eq!(limit_cpus(n(2), 16.0), n(2));
+    }
+
+    #[test]
+    fn test_effective_cpus() {
+        assert!(effective_cpus() <= available_parallelism().unwrap());
+    }
+}
@@ -0,0 +1,368 @@
+//! Resumable batch jobs.
+//!
+//! A [`Store`] keeps a checkpoint per key, like the position of a job, in a
+//! directory. Each one is a JSON file with the value, when it was saved and a
+//! checksum, written to a temporary file and renamed over the previous one.
+//! [`Store::load`] logs the checkpoint it resumes from, and ignores those that
+//! do not match their checksum, or all with `--ignore-checkpoint`.
+//!
+//! ```rust,ignore
+//! let store = Store::open("state")?;
+//! let start = store.load::<u64>("items")?.unwrap_or(0);
+//! let position = Arc::new(AtomicU64::new(start));
+//! store.save_on_shutdown("items", {
+//!     let position = position.clone();
+//!     move || position.load(Ordering::Relaxed)
+//! });
+//! ```
+//!
+//! The position of [`Store::save_on_shutdown`] is saved when the app returns,
+//! within [`SAVE_GRACE`], and every `--checkpoint-interval` while it runs, so
+//! a killed process loses at most one interval.
+use crate::{default_from_clap, env_snapshot::fnv1a, shutdown};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Parser;
+use eyre::{bail, Result as EyreResult, WrapErr as _};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    runtime::Handle,
+    task::spawn_blocking,
+    time::{interval_at, timeout, Instant},
+};
+use tracing::{error, info, warn};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The time the final save of each checkpoint gets at shutdown.
+pub const SAVE_GRACE: Duration = Duration::from_secs(5);
+
+static IGNORE: AtomicBool = AtomicBool::new(false);
+
+/// In microseconds, `0` for none.
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// The positions saved at shutdown.
+static HOOKS: Mutex<Vec<Arc<Hook>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Start batch jobs from the beginning instead of resuming from their
+    /// checkpoints.
+    #[clap(long, env)]
+    ignore_checkpoint: bool,
+
+    /// Save the checkpoints of batch jobs this often while they run, e.g.
+    /// `1m`. They are always saved at shutdown.
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    checkpoint_interval: Option<Duration>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    pub fn init(self) {
+        IGNORE.store(self.ignore_checkpoint, Ordering::Relaxed);
+        if let Some(interval) = self.checkpoint_interval {
+            let micros = u64::try_from(interval.as_micros()).unwrap_or(u64::MAX);
+            INTERVAL.store(micros.max(1), Ordering::Relaxed);
+        }
+    }
+}
+
+/// A checkpoint as written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    schema_version: u32,
+    /// RFC 3339, in UTC.
+    saved_at:       String,
+    /// Of the value as serialized, to detect corruption.
+    checksum:       String,
+    value:          Value,
+}
+
+impl Checkpoint {
+    fn checksum(value: &Value) -> String {
+        let value = serde_json::to_vec(value).unwrap_or_default();
+        format!("{:016x}", fnv1a(&value))
+    }
+}
+
+/// Checkpoints in a directory, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir:     PathBuf,
+    /// Held while writing, so periodic and final saves do not interleave.
+    writing: Arc<Mutex<()>>,
+}
+
+impl Store {
+    /// The store in the directory `path`, which is created if needed.
+    ///
+    /// # Errors
+    ///
+    /// If the directory can not be created.
+    pub fn open(path: impl Into<PathBuf>) -> EyreResult<Self> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Error creating checkpoint directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            writing: Arc::default(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// Replace the checkpoint of `key` with `value`.
+    ///
+    /// # Errors
+    ///
+    /// If `value` can not be serialized or the file can not be written.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn save<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> EyreResult<()> {
+        let value = serde_json::to_value(value)?;
+        let checkpoint = Checkpoint {
+            schema_version: SCHEMA_VERSION,
+            saved_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            checksum: Checkpoint::checksum(&value),
+            value,
+        };
+        let path = self.path(key);
+        let temporary = path.with_extension("json.tmp");
+        let _writing = self.writing.lock().unwrap();
+        fs::write(&temporary, serde_json::to_vec_pretty(&checkpoint)?)
+            .and_then(|()| fs::rename(&temporary, &path))
+            .wrap_err_with(|| format!("Error writing checkpoint {}", path.display()))
+    }
+
+    /// The checkpoint of `key`, `None` if there is none, it is not valid or
+    /// `--ignore-checkpoint` is set. Logs where the job resumes.
+    ///
+    /// # Errors
+    ///
+    /// If the file can not be read or the value is not a `T`.
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> EyreResult<Option<T>> {
+        let path = self.path(key);
+        if IGNORE.load(Ordering::Relaxed) {
+            info!(
+                key,
+                ?path,
+                "Ignoring checkpoint, starting from the beginning"
+            );
+            return Ok(None);
+        }
+        let checkpoint = match read(&path) {
+            Ok(Some(checkpoint)) => checkpoint,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                warn!(key, ?path, "Ignoring invalid checkpoint: {err:#}");
+                return Ok(None);
+            }
+        };
+        let age = DateTime::parse_from_rfc3339(&checkpoint.saved_at)
+            .ok()
+            .and_then(|saved_at| (Utc::now() - saved_at.with_timezone(&Utc)).to_std().ok())
+            .unwrap_or_default();
+        let age = humantime::format_duration(Duration::from_secs(age.as_secs()));
+        let position = match &checkpoint.value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        let value = T::deserialize(checkpoint.value)
+            .wrap_err_with(|| format!("Error reading checkpoint {}", path.display()))?;
+        info!(
+            key,
+            saved_at = checkpoint.saved_at,
+            "Resuming from checkpoint saved {age} ago at {position}"
+        );
+        Ok(Some(value))
+    }
+
+    /// Remove the checkpoint of `key`, for a job that is done, and stop saving
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// If the file exists and can not be removed.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn remove(&self, key: &str) -> EyreResult<()> {
+        HOOKS
+            .lock()
+            .unwrap()
+            .retain(|hook| hook.key != key || hook.store.dir != self.dir);
+        let path = self.path(key);
+        let _writing = self.writing.lock().unwrap();
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).wrap_err_with(|| format!("Error removing checkpoint {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Save the value of `position` as the checkpoint of `key` when the app
+    /// returns, and every `--checkpoint-interval` until then.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn save_on_shutdown<T, F>(&self, key: &str, position: F)
+    where
+        T: Serialize,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        let hook = Arc::new(Hook {
+            store:    self.clone(),
+            key:      key.to_owned(),
+            position: Box::new(move || serde_json::to_value(position()).map_err(Into::into)),
+        });
+        HOOKS.lock().unwrap().push(hook.clone());
+
+        let interval = INTERVAL.load(Ordering::Relaxed);
+        if interval == 0 || Handle::try_current().is_err() {
+            return;
+        }
+        let period = Duration::from_micros(interval);
+        tokio::spawn(async move {
+            let mut interval = interval_at(Instant::now() + period, period);
+            let mut shutdown = shutdown::receiver();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => hook.save(),
+                    () = shutdown::wait(&mut shutdown) => break,
+                }
+            }
+        });
+    }
+}
+
+/// A position saved at shutdown.
+struct Hook {
+    store:    Store,
+    key:      String,
+    position: Box<dyn Fn() -> EyreResult<Value> + Send + Sync>,
+}
+
+impl Hook {
+    /// Save the position, logging failures.
+    fn save(&self) {
+        if let Err(err) = (self.position)().and_then(|value| self.store.save(&self.key, &value)) {
+            error!(key = self.key, "Error saving checkpoint: {err:#}");
+        }
+    }
+}
+
+/// The checkpoint at `path`, `None` if there is none.
+fn read(path: &Path) -> EyreResult<Option<Checkpoint>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let checkpoint = serde_json::from_slice::<Checkpoint>(&contents)?;
+    if checkpoint.schema_version != SCHEMA_VERSION {
+        bail!("unknown schema version {}", checkpoint.schema_version);
+    }
+    if checkpoint.checksum != Checkpoint::checksum(&checkpoint.value) {
+        bail!("checksum does not match the value");
+    }
+    Ok(Some(checkpoint))
+}
+
+/// Save the positions of [`Store::save_on_shutdown`], each within
+/// [`SAVE_GRACE`].
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub async fn save_all() {
+    let hooks = HOOKS.lock().unwrap().clone();
+    for hook in hooks {
+        let key = hook.key.clone();
+        if timeout(SAVE_GRACE, spawn_blocking(move || hook.save()))
+            .await
+            .is_err()
+        {
+            warn!(key, grace = ?SAVE_GRACE, "Checkpoint not saved in time");
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{env, process::id as pid};
+    use tracing_test::traced_test;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cli-batteries-checkpoint-{name}-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_save_load() {
+        let dir = temp_dir("save-load");
+        let store = Store::open(&dir).unwrap();
+        assert_eq!(store.load::<u64>("items").unwrap(), None);
+        store.save("items", &48210_u64).unwrap();
+        assert_eq!(store.load::<u64>("items").unwrap(), Some(48210));
+        assert!(logs_contain(
+            "Resuming from checkpoint saved 0s ago at 48210 key=\"items\""
+        ));
+        // Not a `String`
+        assert!(store.load::<String>("items").is_err());
+
+        store.remove("items").unwrap();
+        assert_eq!(store.load::<u64>("items").unwrap(), None);
+        store.remove("items").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_corrupt() {
+        let dir = temp_dir("corrupt");
+        let store = Store::open(&dir).unwrap();
+        store.save("job", "a.csv:10").unwrap();
+        let path = store.path("job");
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("a.csv:10", "a.csv:99")).unwrap();
+        assert_eq!(store.load::<String>("job").unwrap(), None);
+        assert!(logs_contain(
+            "Ignoring invalid checkpoint: checksum does not match the value"
+        ));
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(store.load::<String>("job").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_all() {
+        let dir = temp_dir("save-all");
+        let store = Store::open(&dir).unwrap();
+        let position = Arc::new(AtomicU64::new(0));
+        store.save_on_shutdown("position", {
+            let position = position.clone();
+            move || position.load(Ordering::Relaxed)
+        });
+        position.store(7, Ordering::Relaxed);
+        save_all().await;
+        assert_eq!(read(&store.path("position")).unwrap().unwrap().value, 7);
+
+        // Not saved once removed
+        store.remove("position").unwrap();
+        save_all().await;
+        assert!(!store.path("position").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
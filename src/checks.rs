@@ -0,0 +1,321 @@
+//! Named debug checks that can be switched on in release builds.
+//!
+//! [`debug_check!`](crate::debug_check) runs an expensive invariant only when
+//! its check is enabled, and logs an ERROR with the check name, location and
+//! span path when it does not hold. A disabled check costs one relaxed atomic
+//! load.
+//!
+//! Checks are enabled with `--enable-check <name>` (repeatable), a comma
+//! separated list in `ENABLE_CHECKS`, at runtime with [`enable_check`] and
+//! [`disable_check`], or with the `prometheus` feature through
+//! `POST /checks/<name>/enable` and `POST /checks/<name>/disable` on the
+//! metrics server. `GET /checks` lists the known checks and their state.
+//!
+//! A check is known once its callsite first runs or it is enabled. Checks
+//! declared with [`Runner::check`](crate::Runner::check) are known from the
+//! start and listed by `--list-checks`.
+use crate::i18n;
+use clap::Parser;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+};
+use tracing::info;
+
+const LIST_FLAG: &str = "--list-checks";
+
+const UNREGISTERED: u8 = 0;
+const DISABLED: u8 = 1;
+const ENABLED: u8 = 2;
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Mutex::default);
+
+#[derive(Default)]
+struct Registry {
+    enabled:   HashSet<String>,
+    callsites: Vec<&'static Check>,
+    /// Known check names with their description, if declared.
+    known:     BTreeMap<String, Option<&'static str>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Enable a named debug check, see `--list-checks`.
+    #[clap(long, env = "ENABLE_CHECKS", value_delimiter = ',')]
+    enable_check: Vec<String>,
+
+    /// Print the declared debug checks and exit.
+    #[clap(long)]
+    list_checks: bool,
+}
+
+impl Options {
+    /// Enable the checks given on the command line, and declare the ones of
+    /// the [`Runner`](crate::Runner).
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn init(&self, declared: &[(&'static str, &'static str)]) {
+        let mut registry = REGISTRY.lock().unwrap();
+        for (name, description) in declared {
+            registry
+                .known
+                .insert((*name).to_owned(), Some(*description));
+        }
+        drop(registry);
+        for name in &self.enable_check {
+            enable_check(name);
+        }
+        if !self.enable_check.is_empty() {
+            info!(checks = ?self.enable_check, "Debug checks enabled");
+        }
+    }
+}
+
+/// Whether `--list-checks` is on the command line. Like `--dump-cli-spec`,
+/// this is checked before regular argument parsing.
+#[must_use]
+pub fn list_requested() -> bool {
+    env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == LIST_FLAG)
+}
+
+/// Print the declared checks for `--list-checks`.
+pub fn list(declared: &[(&'static str, &'static str)]) {
+    if declared.is_empty() {
+        println!("{}", i18n::text("checks-none", &[]));
+    }
+    let width = declared
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, description) in declared {
+        println!("{name:width$}  {description}");
+    }
+}
+
+/// The callsite of a [`debug_check!`](crate::debug_check).
+#[doc(hidden)]
+pub struct Check {
+    name:  &'static str,
+    state: AtomicU8,
+}
+
+impl Check {
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: AtomicU8::new(UNREGISTERED),
+        }
+    }
+
+    /// Whether the check should run, registering it on first use.
+    #[inline]
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn is_enabled(&'static self) -> bool {
+        match self.state.load(Ordering::Relaxed) {
+            DISABLED => false,
+            ENABLED => true,
+            _ => self.register(),
+        }
+    }
+
+    #[cold]
+    fn register(&'static self) -> bool {
+        let mut registry = REGISTRY.lock().unwrap();
+        // Another thread may have registered it in the meantime.
+        if self.state.load(Ordering::Relaxed) == UNREGISTERED {
+            registry.callsites.push(self);
+            registry.known.entry(self.name.to_owned()).or_default();
+        }
+        let enabled = registry.enabled.contains(self.name);
+        self.state
+            .store(if enabled { ENABLED } else { DISABLED }, Ordering::Relaxed);
+        enabled
+    }
+}
+
+fn set(name: &str, enabled: bool) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if enabled {
+        registry.enabled.insert(name.to_owned());
+        registry.known.entry(name.to_owned()).or_default();
+    } else {
+        registry.enabled.remove(name);
+    }
+    let state = if enabled { ENABLED } else { DISABLED };
+    for check in registry.callsites.iter().filter(|check| check.name == name) {
+        check.state.store(state, Ordering::Relaxed);
+    }
+}
+
+/// Run the [`debug_check!`](crate::debug_check)s named `name` from now on.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn enable_check(name: &str) {
+    set(name, true);
+}
+
+/// Stop running the [`debug_check!`](crate::debug_check)s named `name`.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn disable_check(name: &str) {
+    set(name, false);
+}
+
+/// A known check, see [`checks`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CheckInfo {
+    pub name:        String,
+    pub description: Option<&'static str>,
+    pub enabled:     bool,
+}
+
+/// The declared checks and the ones that ran, by name.
+#[allow(clippy::missing_panics_doc)] // Never panics
+#[must_use]
+pub fn checks() -> Vec<CheckInfo> {
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .known
+        .iter()
+        .map(|(name, description)| CheckInfo {
+            name:        name.clone(),
+            description: *description,
+            enabled:     registry.enabled.contains(name),
+        })
+        .collect()
+}
+
+/// Run a named invariant check when it is enabled, and log an ERROR with the
+/// name, location and span path when `check` returns `false`. See the
+/// [module docs](crate::checks) for how checks are enabled.
+///
+/// A disabled check costs a single relaxed atomic load, `check` is not run.
+///
+/// ```
+/// # use cli_batteries::debug_check;
+/// let lines = [3, 4];
+/// let total = 7;
+/// debug_check!("verify-invoice-totals", || lines.iter().sum::<i32>()
+///     == total);
+/// ```
+#[macro_export]
+macro_rules! debug_check {
+    ($name:expr, $check:expr $(,)?) => {{
+        static CHECK: $crate::checks::Check = $crate::checks::Check::new($name);
+        if CHECK.is_enabled() && !($check)() {
+            $crate::util::__tracing::error!(
+                check = $name,
+                file = ::std::file!(),
+                line = ::std::line!(),
+                span_path = %$crate::util::span_path(),
+                "Debug check failed: {}",
+                $name
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use std::sync::atomic::AtomicUsize;
+    use tracing::info_span;
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry as Subscriber};
+
+    fn invoice(runs: &AtomicUsize, valid: bool) {
+        debug_check!("test-invoice", || {
+            runs.fetch_add(1, Ordering::Relaxed);
+            valid
+        });
+    }
+
+    #[test]
+    fn test_enable_disable() {
+        let runs = AtomicUsize::new(0);
+        invoice(&runs, true);
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+        assert!(checks()
+            .iter()
+            .any(|check| check.name == "test-invoice" && !check.enabled));
+
+        enable_check("test-invoice");
+        invoice(&runs, true);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert!(checks()
+            .iter()
+            .any(|check| check.name == "test-invoice" && check.enabled));
+
+        disable_check("test-invoice");
+        invoice(&runs, true);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_enabled_before_first_run() {
+        let options = Options::try_parse_from([
+            "arg0",
+            "--enable-check",
+            "test-early",
+            "--enable-check=other",
+        ])
+        .unwrap();
+        options.init(&[("test-declared", "Declared up front")]);
+        let runs = AtomicUsize::new(0);
+        debug_check!("test-early", || {
+            runs.fetch_add(1, Ordering::Relaxed);
+            true
+        });
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        let declared = checks()
+            .into_iter()
+            .find(|check| check.name == "test-declared")
+            .unwrap();
+        assert_eq!(declared.description, Some("Declared up front"));
+        assert!(!declared.enabled);
+    }
+
+    #[test]
+    fn test_env_list() {
+        let options = Options::try_parse_from(["arg0"]).unwrap();
+        assert!(options.enable_check.is_empty());
+        env::set_var("ENABLE_CHECKS", "test-env-a,test-env-b");
+        let options = Options::try_parse_from(["arg0"]).unwrap();
+        env::remove_var("ENABLE_CHECKS");
+        assert_eq!(options.enable_check, ["test-env-a", "test-env-b"]);
+    }
+
+    #[test]
+    fn test_failure_logged() {
+        let capture = Capture::default();
+        let subscriber = Subscriber::default()
+            .with(tracing_error::ErrorLayer::default())
+            .with(fmt::layer().json().with_writer(capture.clone()));
+        enable_check("test-failing");
+        let total = 2;
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("billing").in_scope(|| {
+                debug_check!("test-failing", || total == 3);
+                debug_check!("test-failing", || true);
+            });
+        });
+        let output = capture.contents();
+        assert_eq!(output.lines().count(), 1, "{output}");
+        let record = crate::logs::parse_line(output.trim_end()).unwrap();
+        assert_eq!(record.level, "ERROR");
+        assert_eq!(record.message, "Debug check failed: test-failing");
+        assert_eq!(record.fields["check"], "test-failing");
+        assert_eq!(record.fields["span_path"], "billing");
+        assert_eq!(record.span.as_deref(), Some("billing"));
+    }
+}
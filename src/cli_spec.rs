@@ -0,0 +1,217 @@
+//! Machine readable description of the command line interface.
+//!
+//! The output of `--dump-cli-spec` is versioned by [`SCHEMA_VERSION`]. Fields
+//! are only added within a schema version, never removed or changed.
+use crate::{
+    config_key::config_key,
+    deprecated::{aliases_of, Deprecation},
+};
+use clap::{builder::PossibleValue, Arg, ArgAction, Command, Parser};
+use serde_json::{json, Value};
+use std::{collections::HashMap, env};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+const FLAG: &str = "--dump-cli-spec";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Print a JSON description of all command line flags and exit.
+    #[clap(long)]
+    dump_cli_spec: bool,
+}
+
+/// Where an argument comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    /// The application.
+    App,
+    /// This crate, optionally behind a cargo feature.
+    Crate(Option<&'static str>),
+}
+
+/// Returns `true` if `--dump-cli-spec` is on the command line.
+///
+/// This is checked before regular argument parsing, so that it works even
+/// when required application arguments are missing.
+pub fn requested() -> bool {
+    env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == FLAG)
+}
+
+/// Describe `command` as JSON. Arguments are attributed using `provider`,
+/// renamed flags are listed with the flag that replaces them.
+pub fn spec(
+    command: &Command,
+    provider: &impl Fn(&str) -> Provider,
+    deprecations: &[Deprecation],
+) -> Value {
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "name": command.get_name(),
+        "version": command.get_version(),
+        "features": crate::features(),
+        "about": command.get_about().map(ToString::to_string),
+        "args": args(command, provider, deprecations),
+        "subcommands": subcommands(command, provider, deprecations),
+    })
+}
+
+fn subcommands(
+    command: &Command,
+    provider: &impl Fn(&str) -> Provider,
+    deprecations: &[Deprecation],
+) -> Value {
+    command
+        .get_subcommands()
+        .map(|sub| {
+            json!({
+                "name": sub.get_name(),
+                "about": sub.get_about().map(ToString::to_string),
+                "args": args(sub, provider, deprecations),
+                "subcommands": subcommands(sub, provider, deprecations),
+            })
+        })
+        .collect()
+}
+
+fn args(
+    command: &Command,
+    provider: &impl Fn(&str) -> Provider,
+    deprecations: &[Deprecation],
+) -> Value {
+    let groups: HashMap<&str, &str> = command
+        .get_groups()
+        .flat_map(|group| {
+            group
+                .get_args()
+                .map(|arg| (arg.as_str(), group.get_id().as_str()))
+        })
+        .collect();
+    command
+        .get_arguments()
+        .filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+        .map(|arg| {
+            let id = arg.get_id().as_str();
+            let (provided_by, feature) = match provider(id) {
+                Provider::App => ("app", None),
+                Provider::Crate(feature) => ("cli-batteries", feature),
+            };
+            json!({
+                "id": id,
+                "long": arg.get_long(),
+                "short": arg.get_short(),
+                "positional": arg.is_positional(),
+                "env": arg.get_env().map(|env| env.to_string_lossy()),
+                "config_key": arg.get_long().map(config_key),
+                "type": value_type(arg),
+                "value_names": arg
+                    .get_value_names()
+                    .filter(|_| arg.get_action().takes_values())
+                    .map(|names| names.iter().map(ToString::to_string).collect::<Vec<_>>()),
+                "default": arg
+                    .get_default_values()
+                    .iter()
+                    .map(|value| value.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "possible_values": arg
+                    .get_possible_values()
+                    .iter()
+                    .map(PossibleValue::get_name)
+                    .collect::<Vec<_>>(),
+                "required": arg.is_required_set(),
+                "hidden": arg.is_hide_set(),
+                "help": arg.get_help().map(ToString::to_string),
+                "long_help": arg.get_long_help().map(ToString::to_string),
+                "heading": arg.get_help_heading(),
+                "group": groups.get(id),
+                "provided_by": provided_by,
+                "feature": feature,
+                "deprecated_aliases": arg
+                    .get_long()
+                    .into_iter()
+                    .flat_map(|long| aliases_of(deprecations, long))
+                    .map(|deprecation| json!({
+                        "long": deprecation.old,
+                        "removal": deprecation.removal,
+                    }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+fn value_type(arg: &Arg) -> &'static str {
+    match arg.get_action() {
+        ArgAction::SetTrue | ArgAction::SetFalse => "bool",
+        ArgAction::Count => "count",
+        ArgAction::Append => "list",
+        _ => "value",
+    }
+}
+
+// The spec of the full command line.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        command, deprecated::add_aliases, provider, trace::test::mock_version,
+        Options as AllOptions,
+    };
+    use clap::CommandFactory;
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+    #[group(skip)]
+    struct App {
+        /// Input file
+        #[clap(long, env, default_value = "input.txt")]
+        file: String,
+
+        /// Output mode
+        #[clap(long, value_parser = ["fast", "slow"])]
+        mode: Option<String>,
+    }
+
+    #[test]
+    fn test_all_args_attributed() {
+        let command = command::<App>(&mock_version());
+        let provider = provider::<App>();
+        let spec = spec(&command, &provider, &[]);
+        for arg in spec["args"].as_array().unwrap() {
+            let expected = if ["file", "mode"].contains(&arg["id"].as_str().unwrap()) {
+                "app"
+            } else {
+                "cli-batteries"
+            };
+            assert_eq!(arg["provided_by"], expected, "{arg}");
+        }
+        assert_eq!(
+            spec["args"].as_array().unwrap().len(),
+            AllOptions::<App>::command().get_arguments().count()
+        );
+    }
+
+    #[test]
+    fn test_deprecated_aliases() {
+        let deprecations = [Deprecation {
+            old:     "input",
+            new:     "file",
+            removal: "1.0.0",
+        }];
+        let command = add_aliases(command::<App>(&mock_version()), &deprecations);
+        let spec = spec(&command, &provider::<App>(), &deprecations);
+        let file = spec["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|arg| arg["id"] == "file")
+            .unwrap();
+        assert_eq!(
+            file["deprecated_aliases"],
+            json!([{ "long": "input", "removal": "1.0.0" }])
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Problems with the configuration, reported all at once.
+//!
+//! Before anything starts, the options are checked in one pass that does not
+//! stop at the first problem: log filters, whether log files can be written,
+//! endpoint URLs, TLS files, combinations that do not work together and
+//! suspicious values. Each problem is a [`ConfigIssue`]. Errors stop startup
+//! after all issues are reported, warnings do not.
+//!
+//! Issues go to stderr as text, or as one JSON object per line with
+//! `--error-format json`. `--check-config` reports them and exits without
+//! running the app, with a failure status if there are errors.
+use crate::i18n;
+use clap::{Parser, ValueEnum};
+use eyre::{bail, Result as EyreResult};
+use serde::Serialize;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Legal but likely not what was meant, startup continues.
+    Warning,
+    /// Startup stops.
+    Error,
+}
+
+/// A problem with the value of one option.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConfigIssue {
+    pub severity:   Severity,
+    /// The long flag, without dashes, like `log-filter`.
+    pub field:      String,
+    /// The value as given.
+    pub value:      String,
+    /// What is wrong with it.
+    pub problem:    String,
+    /// How to fix it, if known.
+    pub suggestion: Option<String>,
+}
+
+impl ConfigIssue {
+    pub fn error(field: &str, value: impl Display, problem: impl Display) -> Self {
+        Self::new(Severity::Error, field, value, problem)
+    }
+
+    pub fn warning(field: &str, value: impl Display, problem: impl Display) -> Self {
+        Self::new(Severity::Warning, field, value, problem)
+    }
+
+    fn new(severity: Severity, field: &str, value: impl Display, problem: impl Display) -> Self {
+        Self {
+            severity,
+            field: field.to_owned(),
+            value: value.to_string(),
+            problem: problem.to_string(),
+            suggestion: None,
+        }
+    }
+
+    #[must_use]
+    pub fn suggest(mut self, suggestion: impl Display) -> Self {
+        self.suggestion = Some(suggestion.to_string());
+        self
+    }
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "--{} {:?}: {}", self.field, self.value, self.problem)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " ({suggestion})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Check the configuration, report every problem with it and exit without
+    /// running the program.
+    #[clap(long)]
+    check_config: bool,
+
+    /// Format of configuration problems on stderr: 'text' or 'json'.
+    #[clap(long, env, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+}
+
+crate::default_from_clap!(Options);
+
+impl Options {
+    /// Whether `--check-config` asks to stop after the check.
+    #[must_use]
+    pub const fn check_requested(&self) -> bool {
+        self.check_config
+    }
+
+    /// Write `issues` to stderr.
+    ///
+    /// # Errors
+    ///
+    /// If any of the issues is an error.
+    pub fn report(&self, issues: &[ConfigIssue]) -> EyreResult<()> {
+        for issue in issues {
+            eprintln!("{}", self.format(issue));
+        }
+        let errors = issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .count();
+        match errors {
+            0 => Ok(()),
+            1 => bail!("1 configuration error"),
+            errors => bail!("{errors} configuration errors"),
+        }
+    }
+
+    fn format(self, issue: &ConfigIssue) -> String {
+        match self.error_format {
+            ErrorFormat::Text => match issue.severity {
+                Severity::Warning => i18n::text("warning", &[("warning", issue)]),
+                Severity::Error => i18n::text("error", &[("error", issue)]),
+            },
+            ErrorFormat::Json => serde_json::to_string(issue).unwrap_or_default(),
+        }
+    }
+}
+
+/// An error if no file can be created or appended to at `path` by `field`.
+#[must_use]
+pub fn writable(field: &str, path: &Path) -> Option<ConfigIssue> {
+    if path.is_dir() {
+        return Some(ConfigIssue::error(field, path.display(), "is a directory"));
+    }
+    if path.exists() {
+        let err = fs::OpenOptions::new().append(true).open(path).err()?;
+        return Some(ConfigIssue::error(
+            field,
+            path.display(),
+            format!("can not be written: {err}"),
+        ));
+    }
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    creatable(field, path, parent.unwrap_or_else(|| Path::new(".")))
+}
+
+/// An error if files can not be created in the directory `dir` by `field`.
+#[must_use]
+pub fn writable_dir(field: &str, dir: &Path) -> Option<ConfigIssue> {
+    creatable(field, dir, dir)
+}
+
+/// An error if `dir` and its missing parents can not be created, or are not
+/// writable.
+fn creatable(field: &str, path: &Path, dir: &Path) -> Option<ConfigIssue> {
+    let existing = dir.ancestors().find(|dir| dir.exists())?;
+    let problem = if !existing.is_dir() {
+        format!("{} is not a directory", existing.display())
+    } else if !dir_writable(existing) {
+        format!("directory {} is not writable", existing.display())
+    } else {
+        return None;
+    };
+    Some(ConfigIssue::error(field, path.display(), problem))
+}
+
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn dir_writable(dir: &Path) -> bool {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let Ok(dir) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `dir` is a valid C string for the duration of the call.
+    unsafe { libc::access(dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn dir_writable(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_format() {
+        let issue = ConfigIssue::error("log-filter", "app=loud", "invalid level `loud`")
+            .suggest("like 'app=debug'");
+        assert_eq!(
+            issue.to_string(),
+            r#"--log-filter "app=loud": invalid level `loud` (like 'app=debug')"#
+        );
+        assert_eq!(Options::default().format(&issue), format!("Error: {issue}"));
+        let options = Options::try_parse_from(["arg0", "--error-format", "json"]).unwrap();
+        assert_eq!(
+            options.format(&issue),
+            r#"{"severity":"error","field":"log-filter","value":"app=loud","#.to_owned()
+                + r#""problem":"invalid level `loud`","suggestion":"like 'app=debug'"}"#
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let options = Options::default();
+        let warning = ConfigIssue::warning("log-keep", 0, "keeps no rotated files");
+        assert!(options.report(std::slice::from_ref(&warning)).is_ok());
+        let error = ConfigIssue::error("log-file", "/", "is a directory");
+        let err = options
+            .report(&[warning, error.clone(), error])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "2 configuration errors");
+    }
+
+    #[test]
+    fn test_paths() {
+        let dir = env::temp_dir().join(format!("cli-batteries-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(writable("log-file", &dir.join("new/nested.log")), None);
+        assert_eq!(writable_dir("session-log", &dir.join("new")), None);
+        assert_eq!(writable("log-file", &file), None);
+        let issue = writable("log-file", &dir).unwrap();
+        assert_eq!(issue.problem, "is a directory");
+        let issue = writable("log-file", &file.join("below.log")).unwrap();
+        assert!(issue.problem.ends_with("is not a directory"), "{issue}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
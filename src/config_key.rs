@@ -0,0 +1,162 @@
+//! How a setting is named on the command line, in the environment and as a
+//! configuration key.
+//!
+//! | Form              | Style                  | Example        |
+//! |-------------------|------------------------|----------------|
+//! | Flag              | `kebab-case`           | `--log-format` |
+//! | Environment       | `SCREAMING_SNAKE_CASE` | `LOG_FORMAT`   |
+//! | Configuration key | `snake_case`           | `log_format`   |
+//!
+//! The flag is the source of the other two: dashes become underscores, and
+//! the environment variable is upper case. Names of environment variables are
+//! matched exactly, so `log_format=json` or `LOG-FORMAT=json` in the
+//! environment are ignored. At startup [`near_misses`] finds such variables
+//! and the preflight checks warn about them, naming the variable that would
+//! have worked. See [`Check::Env`](crate::preflight::Check::Env).
+use clap::Command;
+use std::iter;
+
+/// The environment variable of the flag `long`, without the dashes.
+pub fn env_name(long: &str) -> String {
+    long.replace('-', "_").to_ascii_uppercase()
+}
+
+/// The configuration key of the flag `long`, without the dashes.
+pub fn config_key(long: &str) -> String {
+    long.replace('-', "_")
+}
+
+/// The environment variables read by the arguments of `command`.
+pub fn known_env(command: &Command) -> Vec<String> {
+    command
+        .get_arguments()
+        .filter_map(|arg| arg.get_env()?.to_str().map(str::to_owned))
+        .collect()
+}
+
+/// Variables in `vars` that are not in `known` but would be with a different
+/// case, dashes for underscores, or without one of `prefixes`, with the
+/// variable they were probably meant as.
+///
+/// Only names that become a known name this way are reported, other
+/// variables are left alone however similar they are.
+pub fn near_misses<'a>(
+    known: &'a [String],
+    prefixes: &[&str],
+    vars: impl IntoIterator<Item = String>,
+) -> Vec<(String, &'a str)> {
+    let prefixes = prefixes
+        .iter()
+        .filter(|prefix| !prefix.is_empty())
+        .map(|prefix| format!("{}_", env_name(prefix)))
+        .collect::<Vec<_>>();
+    vars.into_iter()
+        .filter(|name| !known.contains(name))
+        .filter_map(|name| {
+            let normalized = env_name(&name);
+            let unprefixed = prefixes
+                .iter()
+                .filter_map(|prefix| normalized.strip_prefix(prefix.as_str()));
+            let correct = iter::once(normalized.as_str())
+                .chain(unprefixed)
+                .find_map(|candidate| known.iter().find(|known| *known == candidate))?;
+            Some((name, correct.as_str()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn known() -> Vec<String> {
+        vec!["LOG_FORMAT".to_owned(), "LOG_FILTER".to_owned()]
+    }
+
+    fn check(vars: &[&str]) -> Vec<(String, String)> {
+        let known = known();
+        near_misses(
+            &known,
+            &["my-app", "APP", ""],
+            vars.iter().map(|name| (*name).to_owned()),
+        )
+        .into_iter()
+        .map(|(name, correct)| (name, correct.to_owned()))
+        .collect()
+    }
+
+    fn miss(name: &str, correct: &str) -> Vec<(String, String)> {
+        vec![(name.to_owned(), correct.to_owned())]
+    }
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(env_name("log-format"), "LOG_FORMAT");
+        assert_eq!(config_key("log-format"), "log_format");
+        assert_eq!(env_name("otlp-tls-ca"), "OTLP_TLS_CA");
+    }
+
+    #[test]
+    fn test_exact() {
+        assert!(check(&["LOG_FORMAT", "LOG_FILTER"]).is_empty());
+    }
+
+    #[test]
+    fn test_case() {
+        assert_eq!(check(&["log_format"]), miss("log_format", "LOG_FORMAT"));
+        assert_eq!(check(&["Log_Filter"]), miss("Log_Filter", "LOG_FILTER"));
+    }
+
+    #[test]
+    fn test_dashes() {
+        assert_eq!(check(&["LOG-FORMAT"]), miss("LOG-FORMAT", "LOG_FORMAT"));
+        assert_eq!(check(&["log-filter"]), miss("log-filter", "LOG_FILTER"));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        assert_eq!(
+            check(&["MY_APP_LOG_FORMAT"]),
+            miss("MY_APP_LOG_FORMAT", "LOG_FORMAT")
+        );
+        assert_eq!(
+            check(&["app-log-filter"]),
+            miss("app-log-filter", "LOG_FILTER")
+        );
+        assert!(check(&["OTHER_LOG_FORMAT"]).is_empty());
+    }
+
+    #[test]
+    fn test_unrelated() {
+        let unrelated = [
+            "LOG_FORMATS",
+            "LOGFORMAT",
+            "PATH",
+            "log_level",
+            "_LOG_FORMAT",
+        ];
+        assert!(check(&unrelated).is_empty());
+    }
+
+    /// Every argument of the full command line follows the mapping, except
+    /// the plural `ENABLE_CHECKS` of the repeatable `--enable-check`, which
+    /// predates it.
+    #[test]
+    fn test_consistent_names() {
+        #[derive(Clone, Debug, clap::Parser)]
+        #[group(skip)]
+        struct App {}
+
+        let command = crate::command::<App>(&crate::trace::test::mock_version());
+        for arg in command.get_arguments() {
+            let (Some(long), Some(env)) = (arg.get_long(), arg.get_env()) else {
+                continue;
+            };
+            if long != "enable-check" {
+                assert_eq!(env.to_str(), Some(env_name(long).as_str()), "--{long}");
+            }
+            let kebab_case = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-';
+            assert!(long.bytes().all(kebab_case), "--{long}");
+        }
+    }
+}
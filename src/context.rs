@@ -0,0 +1,239 @@
+//! Error context with the source location and span path.
+//!
+//! `wrap_err("query failed")` in a helper called from several places produces
+//! reports with identical layers. [`ResultExt`] adds the caller location and
+//! the current span path to the context, so every layer reads like
+//! `query failed at src/db.rs:42 in handle_request{id=7}::query`.
+//!
+//! The span path requires the [`ErrorLayer`](tracing_error::ErrorLayer) that
+//! is part of the default subscriber.
+use crate::ExitHint;
+use eyre::{Report, Result as EyreResult};
+use std::{
+    fmt::{self, Display, Formatter},
+    panic::Location,
+};
+use tracing_error::SpanTrace;
+
+/// Context helpers for [`Result`]s, see the [module docs](self).
+pub trait ResultExt<T> {
+    /// Add the caller location and span path as context.
+    #[allow(clippy::missing_errors_doc)]
+    fn ctx(self) -> EyreResult<T>;
+
+    /// Add a message, the caller location and span path as context.
+    #[allow(clippy::missing_errors_doc)]
+    fn ctx_msg<D>(self, message: D) -> EyreResult<T>
+    where
+        D: Display + Send + Sync + 'static;
+
+    /// Like [`ctx_msg`](Self::ctx_msg), but only include the span fields named
+    /// in `fields`.
+    #[allow(clippy::missing_errors_doc)]
+    fn ctx_fields<D>(self, message: D, fields: &[&str]) -> EyreResult<T>
+    where
+        D: Display + Send + Sync + 'static;
+
+    /// Classify the error for the `main` span, see [`ExitHint`].
+    #[allow(clippy::missing_errors_doc)]
+    fn exit_hint(self, hint: ExitHint) -> EyreResult<T>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: Into<Report>,
+{
+    #[track_caller]
+    fn ctx(self) -> EyreResult<T> {
+        let location = Location::caller();
+        self.map_err(|err| {
+            err.into()
+                .wrap_err(Context::<&str>::capture(None, location, None))
+        })
+    }
+
+    #[track_caller]
+    fn ctx_msg<D>(self, message: D) -> EyreResult<T>
+    where
+        D: Display + Send + Sync + 'static,
+    {
+        let location = Location::caller();
+        self.map_err(|err| {
+            err.into()
+                .wrap_err(Context::capture(Some(message), location, None))
+        })
+    }
+
+    #[track_caller]
+    fn ctx_fields<D>(self, message: D, fields: &[&str]) -> EyreResult<T>
+    where
+        D: Display + Send + Sync + 'static,
+    {
+        let location = Location::caller();
+        self.map_err(|err| {
+            err.into()
+                .wrap_err(Context::capture(Some(message), location, Some(fields)))
+        })
+    }
+
+    fn exit_hint(self, hint: ExitHint) -> EyreResult<T> {
+        self.map_err(|err| err.into().wrap_err(hint))
+    }
+}
+
+/// A single context layer.
+struct Context<D> {
+    message:  Option<D>,
+    location: &'static Location<'static>,
+    spans:    String,
+}
+
+impl<D: Display> Context<D> {
+    fn capture(
+        message: Option<D>,
+        location: &'static Location<'static>,
+        fields: Option<&[&str]>,
+    ) -> Self {
+        Self {
+            message,
+            location,
+            spans: span_path(fields),
+        }
+    }
+}
+
+/// The current span path, like `handle_request{id=7}::query`, with only the
+/// span fields named in `fields` if given.
+pub fn span_path(fields: Option<&[&str]>) -> String {
+    let mut spans = Vec::new();
+    SpanTrace::capture().with_spans(|metadata, formatted| {
+        let formatted =
+            fields.map_or_else(|| formatted.to_owned(), |fields| select(formatted, fields));
+        if formatted.is_empty() {
+            spans.push(metadata.name().to_owned());
+        } else {
+            spans.push(format!("{}{{{formatted}}}", metadata.name()));
+        }
+        true
+    });
+    spans.reverse();
+    spans.join("::")
+}
+
+impl<D: Display> Display for Context<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(message) = &self.message {
+            write!(f, "{message} ")?;
+        }
+        write!(f, "at {}:{}", self.location.file(), self.location.line())?;
+        if !self.spans.is_empty() {
+            write!(f, " in {}", self.spans)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep only the named `key=value` pairs of formatted span fields. Values can
+/// contain spaces inside quotes.
+fn select(formatted: &str, fields: &[&str]) -> String {
+    let mut pairs = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in formatted.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' if !quoted => {
+                pairs.push(&formatted[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    pairs.push(&formatted[start..]);
+    pairs
+        .into_iter()
+        .filter(|pair| {
+            pair.split_once('=')
+                .is_some_and(|(key, _)| fields.contains(&key))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use eyre::eyre;
+    use tracing::info_span;
+    use tracing_error::ErrorLayer;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    fn query() -> EyreResult<()> {
+        let _span = info_span!("query", table = "users").entered();
+        Err::<(), _>(eyre!("connection reset")).ctx_msg("failed to query db")
+    }
+
+    fn handle_request(id: u64) -> EyreResult<()> {
+        let _span = info_span!("handle_request", id, peer = "10.0.0.1 (lb)").entered();
+        query().ctx()
+    }
+
+    /// Render the error chain with line numbers normalized.
+    fn render(report: &Report) -> String {
+        let chain = report
+            .chain()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut parts = chain.split(".rs:");
+        let mut result = parts.next().unwrap_or_default().to_owned();
+        for part in parts {
+            result.push_str(".rs:N");
+            result.push_str(part.trim_start_matches(|c: char| c.is_ascii_digit()));
+        }
+        result
+    }
+
+    #[test]
+    fn test_report() {
+        let subscriber = Registry::default().with(ErrorLayer::default());
+        let report =
+            tracing::subscriber::with_default(subscriber, || handle_request(7)).unwrap_err();
+        assert_eq!(
+            render(&report),
+            "at src/context.rs:N in handle_request{id=7 peer=\"10.0.0.1 (lb)\"}\nfailed to query \
+             db at src/context.rs:N in handle_request{id=7 peer=\"10.0.0.1 \
+             (lb)\"}::query{table=\"users\"}\nconnection reset"
+        );
+    }
+
+    #[test]
+    fn test_selected_fields() {
+        let subscriber = Registry::default().with(ErrorLayer::default());
+        let report = tracing::subscriber::with_default(subscriber, || {
+            let _span = info_span!("handle_request", id = 7, peer = "10.0.0.1 (lb)").entered();
+            Err::<(), _>(std::io::Error::other("broken pipe")).ctx_fields("write failed", &["id"])
+        })
+        .unwrap_err();
+        assert_eq!(
+            render(&report),
+            "write failed at src/context.rs:N in handle_request{id=7}\nbroken pipe"
+        );
+    }
+
+    #[test]
+    fn test_without_spans() {
+        let report = Err::<(), _>(eyre!("boom")).ctx().unwrap_err();
+        assert_eq!(render(&report), "at src/context.rs:N\nboom");
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(select("a=1 b=\"x y\" c=3", &["b", "c"]), "b=\"x y\" c=3");
+        assert_eq!(select("a=\"q\\\" z\" b=2", &["b"]), "b=2");
+        assert_eq!(select("", &["a"]), "");
+    }
+}
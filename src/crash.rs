@@ -0,0 +1,348 @@
+//! Crash output that is safe to write while the process is dying.
+//!
+//! The identity line (name, version, commit, pid and host) is formatted into a
+//! fixed buffer without allocating. The name, version, commit and host name
+//! are looked up once at startup, the pid when the line is written, so it is
+//! the one of the daemon after `--daemonize`.
+//!
+//! Before the panic hook hands over to the regular (allocating) panic report,
+//! it prepares the identity line, the panic location and the payload. They are
+//! only written, using raw `write(2)` calls, if the process aborts during the
+//! report, for example because the allocator is out of memory. A report that
+//! completes is not repeated. On platforms other than unix they are written
+//! before the report.
+use crate::Version;
+use once_cell::sync::OnceCell;
+use std::{
+    any::Any,
+    panic::Location,
+    process::id as pid,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+const CAPACITY: usize = 1024;
+
+static IDENTITY: OnceCell<Identity> = OnceCell::new();
+
+/// Whether a thread is reporting a panic with [`PENDING`] prepared.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// The crash output of the panic being reported, written if the process
+/// aborts. Atomics, so the signal handler can read it without locks.
+#[allow(clippy::declare_interior_mutable_const)] // Only used to initialize
+const ZERO: AtomicU8 = AtomicU8::new(0);
+static PENDING: [AtomicU8; CAPACITY] = [ZERO; CAPACITY];
+
+/// The length of [`PENDING`], zero if nothing is to be written.
+static PENDING_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The parts of the identity line known at startup.
+struct Identity {
+    /// Name, version and commit.
+    name: FixedBuffer,
+    host: FixedBuffer,
+}
+
+/// Fixed capacity buffer that silently truncates.
+struct FixedBuffer {
+    bytes: [u8; CAPACITY],
+    len:   usize,
+}
+
+impl FixedBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; CAPACITY],
+            len:   0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(CAPACITY - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Look up the identity and prepare writing the crash output on abort. Only
+/// the first call has an effect.
+pub fn init(version: &Version) {
+    IDENTITY.get_or_init(|| {
+        let mut name = FixedBuffer::new();
+        name.push(version.pkg_name.as_bytes());
+        name.push(b" ");
+        name.push(version.pkg_version.as_bytes());
+        name.push(b" (");
+        name.push(&version.commit_hash.as_bytes()[..version.commit_hash.len().min(8)]);
+        name.push(b")");
+        let mut host = FixedBuffer::new();
+        host.push(hostname().as_bytes());
+        #[cfg(unix)]
+        install_abort_handler();
+        Identity { name, host }
+    });
+}
+
+/// The host name of the machine, `unknown` if it can not be determined.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+pub fn hostname() -> String {
+    let mut buffer = [0_u8; 256];
+    // SAFETY: The buffer is valid for its length.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == -1 {
+        return "unknown".to_owned();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+/// The host name of the machine, `unknown` if it can not be determined.
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// Add the identity line, if initialized.
+fn push_identity(buffer: &mut FixedBuffer) {
+    if let Some(identity) = IDENTITY.get() {
+        buffer.push(identity.name.as_bytes());
+        buffer.push(b" pid=");
+        push_decimal(buffer, pid().into());
+        buffer.push(b" host=");
+        buffer.push(identity.host.as_bytes());
+        buffer.push(b"\n");
+    }
+}
+
+/// Run the regular panic `report`, and write the identity line followed by
+/// the panic location and message to stderr only if the process aborts
+/// during it.
+///
+/// Preparing the output does not allocate or take locks.
+pub fn report_panic(
+    location: Option<&Location>,
+    payload: &(dyn Any + Send),
+    report: impl FnOnce(),
+) {
+    let buffer = panic_output(location, payload);
+    if cfg!(not(unix)) {
+        write_stderr(buffer.as_bytes());
+        report();
+        return;
+    }
+    // With concurrent panics only the first has a fallback.
+    if REPORTING.swap(true, Ordering::Acquire) {
+        report();
+        return;
+    }
+    for (slot, &byte) in PENDING.iter().zip(buffer.as_bytes()) {
+        slot.store(byte, Ordering::Relaxed);
+    }
+    PENDING_LEN.store(buffer.len, Ordering::Release);
+    report();
+    PENDING_LEN.store(0, Ordering::Release);
+    REPORTING.store(false, Ordering::Release);
+}
+
+fn panic_output(location: Option<&Location>, payload: &(dyn Any + Send)) -> FixedBuffer {
+    let mut buffer = FixedBuffer::new();
+    push_identity(&mut buffer);
+    buffer.push(b"panicked");
+    if let Some(location) = location {
+        buffer.push(b" at ");
+        buffer.push(location.file().as_bytes());
+        buffer.push(b":");
+        push_decimal(&mut buffer, location.line().into());
+        buffer.push(b":");
+        push_decimal(&mut buffer, location.column().into());
+    }
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+    if let Some(message) = message {
+        buffer.push(b": ");
+        buffer.push(message.as_bytes());
+    }
+    buffer.push(b"\n");
+    buffer
+}
+
+/// Write [`PENDING`] on `SIGABRT`, unless the app handles the signal itself.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn install_abort_handler() {
+    use std::ptr::{self, addr_of, addr_of_mut};
+    // SAFETY: `on_abort` is async-signal-safe. `SA_RESETHAND` restores the
+    // default action, so `abort` still ends the process after it returns.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGABRT, ptr::null(), addr_of_mut!(previous)) == -1
+            || previous.sa_sigaction != libc::SIG_DFL
+        {
+            return;
+        }
+        action.sa_sigaction = on_abort as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(addr_of_mut!(action.sa_mask));
+        libc::sigaction(libc::SIGABRT, addr_of!(action), ptr::null_mut());
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_abort(_signal: libc::c_int) {
+    let len = PENDING_LEN.swap(0, Ordering::Acquire);
+    let mut bytes = [0_u8; CAPACITY];
+    for (byte, slot) in bytes.iter_mut().zip(&PENDING[..len]) {
+        *byte = slot.load(Ordering::Relaxed);
+    }
+    write_stderr(&bytes[..len]);
+}
+
+/// Write bytes to stderr without allocating, locking or buffering.
+pub fn write_stderr(bytes: &[u8]) {
+    #[cfg(unix)]
+    {
+        let mut remaining = bytes;
+        while !remaining.is_empty() {
+            // SAFETY: The pointer and length come from a valid slice.
+            #[allow(unsafe_code)]
+            let written = unsafe {
+                libc::write(
+                    libc::STDERR_FILENO,
+                    remaining.as_ptr().cast(),
+                    remaining.len(),
+                )
+            };
+            match usize::try_from(written) {
+                Ok(0) => return,
+                Ok(n) => remaining = &remaining[n..],
+                Err(_) if last_error_is_eintr() => {}
+                Err(_) => return,
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        use std::io::Write;
+        let _ = std::io::stderr().write_all(bytes);
+    }
+}
+
+#[cfg(unix)]
+fn last_error_is_eintr() -> bool {
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+}
+
+fn push_decimal(buffer: &mut FixedBuffer, mut value: u64) {
+    let mut digits = [0_u8; 20];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        #[allow(clippy::cast_possible_truncation)] // Always < 10
+        {
+            digits[i] = b'0' + (value % 10) as u8;
+        }
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    buffer.push(&digits[i..]);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::mock_version;
+    use std::{env, panic, process::Command};
+
+    #[test]
+    fn test_push_decimal() {
+        for value in [0, 7, 10, 1234, u64::MAX] {
+            let mut buffer = FixedBuffer::new();
+            push_decimal(&mut buffer, value);
+            assert_eq!(buffer.as_bytes(), value.to_string().as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_truncates() {
+        let mut buffer = FixedBuffer::new();
+        buffer.push(&[b'a'; CAPACITY - 1]);
+        buffer.push(b"bcd");
+        assert_eq!(buffer.as_bytes().len(), CAPACITY);
+        assert_eq!(buffer.as_bytes()[CAPACITY - 1], b'b');
+    }
+
+    /// Runs in a child process, see [`test_identity_on_hook_failure`].
+    #[test]
+    #[ignore = "only meant to run as a child of test_identity_on_hook_failure"]
+    fn crash_child() {
+        let Ok(report) = env::var("CLI_BATTERIES_CRASH_CHILD") else {
+            return;
+        };
+        init(&mock_version());
+        panic::set_hook(Box::new(move |info| {
+            report_panic(info.location(), info.payload(), || {
+                if report == "abort" {
+                    // Simulate a failing report, like an allocation failure would.
+                    std::process::abort();
+                }
+                eprintln!("regular report");
+            });
+        }));
+        panic!("simulated crash");
+    }
+
+    fn crash(report: &str) -> String {
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "crash::test::crash_child",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env("CLI_BATTERIES_CRASH_CHILD", report)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    }
+
+    #[test]
+    fn test_identity_on_hook_failure() {
+        let stderr = crash("abort");
+        let identity = format!(
+            "test-app 0.0.0 (7cdd3615) pid={} host={}\n",
+            output_pid(&stderr),
+            hostname()
+        );
+        assert!(stderr.contains(&identity), "{stderr}");
+        assert!(stderr.contains("panicked at src/crash.rs:"), "{stderr}");
+        assert!(stderr.contains(": simulated crash\n"), "{stderr}");
+    }
+
+    #[test]
+    fn test_no_repeat_after_report() {
+        let stderr = crash("report");
+        assert!(stderr.contains("regular report"), "{stderr}");
+        assert!(!stderr.contains("pid="), "{stderr}");
+    }
+
+    #[test]
+    fn test_hostname() {
+        assert!(!hostname().is_empty());
+    }
+
+    fn output_pid(stderr: &str) -> &str {
+        let start = stderr.find("pid=").unwrap() + 4;
+        let end = start + stderr[start..].find(' ').unwrap();
+        &stderr[start..end]
+    }
+}
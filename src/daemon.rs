@@ -0,0 +1,230 @@
+//! Hand the process over to something else.
+//!
+//! Launchers that set up the environment and then `exec` the real binary call
+//! [`prepare_exec`] first, so buffered telemetry is not lost with the process
+//! image.
+//!
+//! With the `daemonize` feature on unix, `--daemonize` detaches from the
+//! terminal with the classic double fork and `setsid`, before the runtime or
+//! any other thread is started. Stdin is read from `/dev/null`, stdout and
+//! stderr are appended to `daemon.out` in the session log directory. The
+//! working directory and umask are kept, so relative paths in arguments keep
+//! working. `--pid-file` writes the pid of the daemon to a file and holds a
+//! lock on it for the life of the process, so a second instance fails to
+//! start while the first one runs.
+use crate::trace;
+use eyre::{Result as EyreResult, WrapErr as _};
+use std::io::{stderr, stdout, Write as _};
+use tracing::info;
+
+#[cfg(all(unix, feature = "daemonize"))]
+pub use self::daemonize::Options;
+
+/// Flush and complete all telemetry before the caller replaces the process
+/// with `exec`.
+///
+/// Writes the flame graph, exports the buffered OpenTelemetry spans and stops
+/// the exporter, completes the session log and stops its flush thread. Events
+/// logged afterwards only reach stderr. Call it as late as possible, right
+/// before `exec`. If `exec` fails the program can keep running and terminate
+/// normally.
+///
+/// # Errors
+///
+/// When the flame graph or the session log can not be written.
+pub fn prepare_exec() -> EyreResult<()> {
+    info!("Preparing to exec, telemetry ends here");
+    trace::prepare_exec()?;
+    stdout().flush().wrap_err("Error flushing stdout")?;
+    stderr().flush().wrap_err("Error flushing stderr")?;
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "daemonize"))]
+mod daemonize {
+    use crate::{
+        config_issue::{self, ConfigIssue},
+        trace, Version,
+    };
+    use clap::Parser;
+    use eyre::{bail, Result as EyreResult, WrapErr as _};
+    use once_cell::sync::OnceCell;
+    use std::{
+        fs::{self, File, OpenOptions},
+        io::{self, Read as _, Seek as _, Write as _},
+        os::unix::io::AsRawFd,
+        path::{Path, PathBuf},
+        process::id as pid,
+    };
+
+    /// Held until the process exits, and with it the lock.
+    static PID_FILE: OnceCell<File> = OnceCell::new();
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Parser)]
+    #[group(skip)]
+    pub struct Options {
+        /// Detach from the terminal and run in the background. Output goes to
+        /// `daemon.out` in the session log directory.
+        #[clap(long, env)]
+        daemonize: bool,
+
+        /// Write the pid to this file and lock it, so a second instance fails
+        /// to start.
+        #[clap(long, env)]
+        pid_file: Option<PathBuf>,
+    }
+
+    impl Options {
+        /// Whether the pid file can be written.
+        pub fn validate(&self) -> Option<ConfigIssue> {
+            config_issue::writable("pid-file", self.pid_file.as_deref()?)
+        }
+
+        /// Lock the pid file and detach if requested. Must be called before any
+        /// threads are started, only the calling thread survives the fork.
+        pub fn start(&self, version: &Version, tracing: &mut trace::Options) -> EyreResult<()> {
+            // Open everything before forking, so errors reach the terminal.
+            let pid_file = self.pid_file.as_deref().map(lock).transpose()?;
+            if self.daemonize {
+                let output = tracing.daemon_output(version)?;
+                let output = match &output {
+                    Some(path) => append(path)?,
+                    None => OpenOptions::new().write(true).open("/dev/null")?,
+                };
+                let null = File::open("/dev/null")?;
+                detach().wrap_err("Error daemonizing")?;
+                redirect(&null, &output).wrap_err("Error redirecting stdio")?;
+            }
+            if let Some(mut file) = pid_file {
+                file.set_len(0)?;
+                writeln!(file, "{}", pid())?;
+                let _ = PID_FILE.set(file);
+            }
+            Ok(())
+        }
+    }
+
+    /// Open and lock the pid file, or fail with the pid of the holder. The
+    /// lock is shared with forked children, the content is written later.
+    #[allow(unsafe_code)]
+    fn lock(path: &Path) -> EyreResult<File> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .wrap_err_with(|| format!("Error opening pid file {}", path.display()))?;
+        // SAFETY: The descriptor is valid for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            bail!(
+                "Pid file {} is locked, already running as pid {}",
+                path.display(),
+                holder.trim()
+            );
+        }
+        file.rewind()?;
+        Ok(file)
+    }
+
+    fn append(path: &Path) -> EyreResult<File> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Error creating directory {}", dir.display()))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Error opening {}", path.display()))
+    }
+
+    /// Returns the pid of the child in the parent, `None` in the child.
+    #[allow(unsafe_code)]
+    fn fork() -> io::Result<Option<libc::pid_t>> {
+        // SAFETY: Only the calling thread is running, see `Options::start`.
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(None),
+            child => Ok(Some(child)),
+        }
+    }
+
+    /// The double fork. Returns in the daemon only.
+    ///
+    /// The original process waits for the intermediate session leader, which
+    /// exits right after starting the daemon, and exits with its status. The
+    /// daemon is not a session leader, so it can never acquire a controlling
+    /// terminal.
+    #[allow(unsafe_code)]
+    fn detach() -> io::Result<()> {
+        if let Some(child) = fork()? {
+            let mut status = 0;
+            // SAFETY: `status` outlives the call.
+            if unsafe { libc::waitpid(child, &raw mut status, 0) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let code = if libc::WIFEXITED(status) {
+                libc::WEXITSTATUS(status)
+            } else {
+                1
+            };
+            // SAFETY: Exits without running destructors, they belong to the
+            // daemon now.
+            unsafe { libc::_exit(code) };
+        }
+        // SAFETY: No preconditions.
+        if unsafe { libc::setsid() } == -1 {
+            eprintln!("Error: setsid: {}", io::Error::last_os_error());
+            // SAFETY: As above.
+            unsafe { libc::_exit(1) };
+        }
+        match fork() {
+            Ok(None) => Ok(()),
+            // SAFETY: As above.
+            Ok(Some(_)) => unsafe { libc::_exit(0) },
+            Err(err) => {
+                eprintln!("Error: fork: {err}");
+                // SAFETY: As above.
+                unsafe { libc::_exit(1) };
+            }
+        }
+    }
+
+    /// Point stdin at `input` and stdout and stderr at `output`.
+    #[allow(unsafe_code)]
+    fn redirect(input: &File, output: &File) -> io::Result<()> {
+        for (file, fd) in [(input, 0), (output, 1), (output, 2)] {
+            // SAFETY: Both descriptors are valid, `fd` is replaced atomically.
+            if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub mod test {
+        use super::*;
+        use std::env;
+
+        #[test]
+        fn test_lock() {
+            let path = env::temp_dir().join(format!("cli-batteries-pid-{}", pid()));
+            fs::write(&path, "1234\n").unwrap();
+            let mut first = lock(&path).unwrap();
+            first.set_len(0).unwrap();
+            writeln!(first, "{}", pid()).unwrap();
+
+            let err = lock(&path).unwrap_err().to_string();
+            assert!(err.contains("is locked"), "{err}");
+            assert!(err.ends_with(&format!("pid {}", pid())), "{err}");
+
+            drop(first);
+            drop(lock(&path).unwrap());
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
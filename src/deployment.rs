@@ -0,0 +1,308 @@
+//! `--generate-deployment <target>`: the configuration of this run as a
+//! deployment snippet.
+//!
+//! Instead of running the application, the settings that are not defaults
+//! are printed in the form `target` takes them, under a comment with the
+//! version that generated them:
+//!
+//! * `systemd`: a `[Service]` section with an `Environment=` line for each
+//!   setting from the environment and the flags on the `ExecStart=` line.
+//! * `docker`: a Dockerfile fragment with `ENV` for the environment and `CMD`
+//!   for the flags.
+//! * `k8s-env`: a Kubernetes `env:` block with every setting that has an
+//!   environment variable. Flags without one are listed in a comment.
+//!
+//! The settings are read like `--print-config` prints them, and secrets are
+//! masked the same way. Built-in values are left out like defaults, the
+//! binary has them already.
+use crate::{
+    default_from_clap,
+    print_config::{config, MASK},
+    Version,
+};
+use clap::{Arg, ArgMatches, Command, Parser, ValueEnum};
+use serde_json::Value;
+use std::{fmt::Write as _, iter};
+
+const FLAG: &str = "generate_deployment";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Target {
+    Systemd,
+    Docker,
+    K8sEnv,
+}
+
+impl Target {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_owned())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Print the settings of this run as a 'systemd' unit, 'docker' or
+    /// 'k8s-env' snippet and exit.
+    #[clap(long, value_enum, value_name = "TARGET")]
+    generate_deployment: Option<Target>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// The requested target, if any.
+    #[must_use]
+    pub const fn requested(self) -> Option<Target> {
+        self.generate_deployment
+    }
+}
+
+/// A setting that is not a default.
+struct Setting<'a> {
+    arg:    &'a Arg,
+    values: Vec<String>,
+    /// Whether it was read from the environment.
+    env:    bool,
+}
+
+impl Setting<'_> {
+    /// The value as an environment variable, if the setting has one.
+    fn env(&self) -> Option<(String, String)> {
+        let name = self.arg.get_env()?.to_string_lossy().into_owned();
+        Some((name, self.values.join(",")))
+    }
+
+    /// The value as command line arguments.
+    fn args(&self) -> Vec<String> {
+        let Some(long) = self.arg.get_long() else {
+            return self.values.clone();
+        };
+        let flag = format!("--{long}");
+        if !self.arg.get_action().takes_values() {
+            return if self.values.iter().any(|value| value == "true") {
+                vec![flag]
+            } else {
+                vec![]
+            };
+        }
+        if let Some(delimiter) = self.arg.get_value_delimiter() {
+            return vec![flag, self.values.join(&delimiter.to_string())];
+        }
+        self.values
+            .iter()
+            .flat_map(|value| [flag.clone(), value.clone()])
+            .collect()
+    }
+}
+
+/// The snippet for the parsed command line, with the `built_in` values of
+/// [`config`].
+#[must_use]
+pub fn generate(
+    target: Target,
+    version: &Version,
+    command: &Command,
+    matches: &ArgMatches,
+    built_in: &[(&str, String)],
+) -> String {
+    let config = config(command, matches, built_in, |_| MASK.to_owned());
+    render(target, version, command, &config)
+}
+
+/// The snippet for `config`, the value and source of each flag of `command`
+/// as [`config`] returns them.
+pub fn render(target: Target, version: &Version, command: &Command, config: &Value) -> String {
+    let settings = settings(command, config);
+    let (env, flags): (Vec<_>, Vec<_>) = settings.iter().partition(|setting| setting.env);
+    let name = version.pkg_name;
+    let mut out = format!(
+        "# Generated by {name} {} with --generate-deployment {}\n",
+        version.pkg_version,
+        target.name()
+    );
+    match target {
+        Target::Systemd => {
+            out.push_str("[Service]\n");
+            for (key, value) in env.iter().filter_map(|setting| setting.env()) {
+                let _ = writeln!(
+                    out,
+                    "Environment={}",
+                    systemd_quote(&format!("{key}={value}"))
+                );
+            }
+            let _ = write!(out, "ExecStart=/usr/local/bin/{name}");
+            for arg in flags.iter().flat_map(|setting| setting.args()) {
+                let _ = write!(out, " {}", systemd_quote(&arg));
+            }
+            out.push('\n');
+        }
+        Target::Docker => {
+            for (key, value) in env.iter().filter_map(|setting| setting.env()) {
+                let _ = writeln!(out, "ENV {key}={}", Value::from(value));
+            }
+            let cmd = iter::once(name.to_owned())
+                .chain(flags.iter().flat_map(|setting| setting.args()))
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "CMD {}", Value::from(cmd));
+        }
+        Target::K8sEnv => {
+            out.push_str("env:\n");
+            for (key, value) in settings.iter().filter_map(Setting::env) {
+                let _ = writeln!(out, "  - name: {key}\n    value: {}", Value::from(value));
+            }
+            for setting in settings
+                .iter()
+                .filter(|setting| setting.arg.get_env().is_none())
+            {
+                let _ = writeln!(
+                    out,
+                    "# No environment variable: {}",
+                    setting.args().join(" ")
+                );
+            }
+        }
+    }
+    out
+}
+
+/// The settings of `config` that are not defaults, in the order of the
+/// arguments of `command`.
+fn settings<'a>(command: &'a Command, config: &Value) -> Vec<Setting<'a>> {
+    command
+        .get_arguments()
+        .filter(|arg| arg.get_id() != FLAG)
+        .filter_map(|arg| {
+            let entry = config.get(arg.get_id().as_str())?;
+            let env = match entry["source"].as_str()? {
+                "environment" => true,
+                "command-line" => false,
+                _ => return None,
+            };
+            let values = match &entry["value"] {
+                Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+                value => value.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            Some(Setting {
+                arg,
+                values: values.into_iter().map(str::to_owned).collect(),
+                env,
+            })
+        })
+        .collect()
+}
+
+/// A word of a unit file, quoted if needed. `%` starts a specifier and `$`
+/// a variable, both are escaped.
+fn systemd_quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::mock_version;
+    use clap::ArgAction;
+    use serde_json::json;
+    use std::{env, fs, path::Path};
+
+    fn command() -> Command {
+        Command::new("app")
+            .arg(Arg::new("log_filter").long("log-filter").env("LOG_FILTER"))
+            .arg(Arg::new("log_format").long("log-format").env("LOG_FORMAT"))
+            .arg(Arg::new("api_token").long("api-token").env("API_TOKEN"))
+            .arg(
+                Arg::new("log_fields")
+                    .long("log-fields")
+                    .env("LOG_FIELDS")
+                    .value_delimiter(','),
+            )
+            .arg(Arg::new("tag").long("tag").action(ArgAction::Append))
+            .arg(
+                Arg::new("trace_flame")
+                    .long("trace-flame")
+                    .env("TRACE_FLAME")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("name").long("name").env("NAME"))
+            .arg(Arg::new("log_target").long("log-target").env("LOG_TARGET"))
+            .arg(Arg::new(FLAG).long("generate-deployment"))
+    }
+
+    /// A run with settings from each source.
+    fn config() -> Value {
+        json!({
+            "log_filter": { "value": "info,app=debug", "source": "environment" },
+            "api_token": { "value": "***", "source": "environment" },
+            "log_format": { "value": "json", "source": "command-line" },
+            "log_fields": { "value": ["host", "pid"], "source": "command-line" },
+            "tag": { "value": ["blue green", "50%"], "source": "command-line" },
+            "trace_flame": { "value": "true", "source": "command-line" },
+            "name": { "value": "world", "source": "default" },
+            "log_target": { "value": "journald", "source": "built-in" },
+            FLAG: { "value": "systemd", "source": "command-line" },
+        })
+    }
+
+    /// Set `UPDATE_SNAPSHOTS=1` to update the snapshot after an intentional
+    /// change.
+    #[test]
+    fn test_snapshot() {
+        let actual = [Target::Systemd, Target::Docker, Target::K8sEnv]
+            .into_iter()
+            .map(|target| render(target, &mock_version(), &command(), &config()))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/deployment.txt");
+        if env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::write(&path, &actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            actual, expected,
+            "Deployment snippets changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+        );
+    }
+
+    #[test]
+    fn test_secret_masked() {
+        let command = command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["app", "--api-token", "hunter2", "--log-format", "json"])
+            .unwrap();
+        // Built-in values are in the binary already
+        let built_in = [("log_filter", "dep=warn".to_owned())];
+        let snippet = generate(
+            Target::Docker,
+            &mock_version(),
+            &command,
+            &matches,
+            &built_in,
+        );
+        assert!(!snippet.contains("dep=warn"), "{snippet}");
+        assert!(!snippet.contains("hunter2"), "{snippet}");
+        assert_eq!(
+            snippet.lines().last(),
+            Some(r#"CMD ["test-app","--log-format","json","--api-token","***"]"#)
+        );
+    }
+
+    #[test]
+    fn test_systemd_quote() {
+        assert_eq!(systemd_quote("info"), "info");
+        assert_eq!(systemd_quote("a b"), r#""a b""#);
+        assert_eq!(systemd_quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(systemd_quote("50%"), "50%%");
+        assert_eq!(systemd_quote(""), r#""""#);
+    }
+}
@@ -0,0 +1,189 @@
+//! Renamed flags.
+//!
+//! Old flag names keep working as hidden aliases of their replacement. Using
+//! one logs a warning naming the replacement, or fails startup with
+//! `--deny-deprecated`. `--dump-cli-spec` lists them with their removal
+//! version.
+use clap::{Command, Parser};
+use eyre::{bail, Result as EyreResult};
+use std::ffi::OsString;
+use tracing::warn;
+
+/// A renamed flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deprecation {
+    /// Old long flag name, without dashes.
+    pub old:     &'static str,
+    /// Long name of the replacement flag, without dashes.
+    pub new:     &'static str,
+    /// Version in which the old name will be removed.
+    pub removal: &'static str,
+}
+
+/// Flags of this crate that have been renamed.
+pub static FLAGS: &[Deprecation] = &[];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Fail on startup if any deprecated flag is used.
+    #[clap(long, env)]
+    deny_deprecated: bool,
+}
+
+/// Add the old names as hidden aliases of their replacements.
+pub fn add_aliases(mut command: Command, deprecations: &[Deprecation]) -> Command {
+    for deprecation in deprecations {
+        let id = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(deprecation.new))
+            .map(|arg| arg.get_id().clone());
+        if let Some(id) = id {
+            command = command.mut_arg(id, |arg| arg.alias(deprecation.old));
+        }
+    }
+    command
+}
+
+/// Deprecations of aliases of the flag `long`.
+pub fn aliases_of<'a>(
+    deprecations: &'a [Deprecation],
+    long: &'a str,
+) -> impl Iterator<Item = &'a Deprecation> + 'a {
+    deprecations.iter().filter(move |d| d.new == long)
+}
+
+impl Options {
+    /// Warn about (or with `--deny-deprecated` reject) deprecated flags in
+    /// `args`. Each deprecated flag is reported once.
+    pub fn check(
+        self,
+        deprecations: &[Deprecation],
+        args: impl IntoIterator<Item = OsString>,
+    ) -> EyreResult<()> {
+        let args = args
+            .into_iter()
+            .skip(1)
+            .take_while(|arg| arg != "--")
+            .collect::<Vec<_>>();
+        for deprecation in deprecations {
+            let used = args.iter().any(|arg| {
+                arg.to_str()
+                    .and_then(|arg| arg.strip_prefix("--"))
+                    .and_then(|arg| arg.strip_prefix(deprecation.old))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+            });
+            if !used {
+                continue;
+            }
+            if self.deny_deprecated {
+                bail!(
+                    "Flag --{} is deprecated, use --{} instead (denied by --deny-deprecated)",
+                    deprecation.old,
+                    deprecation.new
+                );
+            }
+            warn!(
+                flag = deprecation.old,
+                replacement = deprecation.new,
+                removal = deprecation.removal,
+                "Flag --{} is deprecated and will be removed in {}, use --{} instead",
+                deprecation.old,
+                deprecation.removal,
+                deprecation.new
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use clap::{Args, FromArgMatches};
+    use tracing_test::traced_test;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Parser)]
+    struct App {
+        #[clap(long)]
+        log_level: Option<String>,
+
+        #[clap(flatten)]
+        deprecated: Options,
+    }
+
+    const TABLE: &[Deprecation] = &[Deprecation {
+        old:     "verbosity",
+        new:     "log-level",
+        removal: "0.7.0",
+    }];
+
+    fn parse(args: &[&str]) -> (App, Vec<OsString>) {
+        let args = std::iter::once("arg0")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let command = add_aliases(App::augment_args(Command::new("app")), TABLE);
+        let matches = command.try_get_matches_from(&args).unwrap();
+        (App::from_arg_matches(&matches).unwrap(), args)
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_alias() {
+        let (app, args) = parse(&["--verbosity=info"]);
+        assert_eq!(app.log_level.as_deref(), Some("info"));
+        app.deprecated.check(TABLE, args).unwrap();
+
+        // Repeated use still warns once.
+        let (app, mut args) = parse(&["--verbosity", "debug"]);
+        assert_eq!(app.log_level.as_deref(), Some("debug"));
+        args.push("--verbosity".into());
+        app.deprecated.check(TABLE, args).unwrap();
+        assert!(logs_contain(
+            "Flag --verbosity is deprecated and will be removed in 0.7.0, use --log-level instead"
+        ));
+        logs_assert(|lines| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("is deprecated"))
+                .count()
+            {
+                2 => Ok(()),
+                n => Err(format!("Expected a warning per check, got {n}")),
+            }
+        });
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_new_name() {
+        let (app, mut args) = parse(&["--log-level", "debug"]);
+        assert_eq!(app.log_level.as_deref(), Some("debug"));
+        // Arguments after `--` are not flags.
+        args.extend(["--".into(), "--verbosity".into()]);
+        app.deprecated.check(TABLE, args).unwrap();
+        assert!(!logs_contain("deprecated"));
+    }
+
+    #[test]
+    fn test_hidden() {
+        let mut command = add_aliases(App::augment_args(Command::new("app")), TABLE);
+        let help = command.render_long_help().to_string();
+        assert!(help.contains("--log-level"));
+        assert!(!help.contains("verbosity"));
+    }
+
+    #[test]
+    fn test_deny() {
+        let (app, args) = parse(&["--deny-deprecated", "--verbosity", "debug"]);
+        let err = app.deprecated.check(TABLE, args).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Flag --verbosity is deprecated, use --log-level instead (denied by --deny-deprecated)"
+        );
+
+        let (app, args) = parse(&["--deny-deprecated", "--log-level", "debug"]);
+        app.deprecated.check(TABLE, args).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Find what changed since the last start, `--env-snapshot`.
+//!
+//! At startup a snapshot of the environment is compared with the one the
+//! previous run left at the path, and then replaces it. The snapshot holds:
+//!
+//! * `env.<NAME>`: the environment variables of the support bundle,
+//! * `config.<flag>`: the value of every flag and where it came from,
+//! * `version` and `features`,
+//! * `rlimit.<resource>`: the soft and hard resource limits, on Unix.
+//!
+//! The differences are logged at INFO as the `added`, `removed` and `changed`
+//! keys. Flags that hold secrets are stored as a fingerprint of the value, so
+//! a change is noticed, and are masked in the log. The snapshot carries a
+//! hash of its entries. A previous snapshot that does not parse or does not
+//! match its hash is reported and replaced.
+use crate::{
+    default_from_clap, features,
+    print_config::{config, environment, is_secret, MASK},
+    Version,
+};
+use clap::{ArgMatches, Command, Parser};
+use eyre::{bail, Result as EyreResult, WrapErr as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Compare the environment, configuration and resource limits with the
+    /// snapshot at this path, log the differences and update it.
+    #[clap(long, env, value_name = "PATH")]
+    env_snapshot: Option<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+/// The entries of a snapshot, see the [module docs](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    schema_version: u32,
+    /// Of the entries as serialized, to detect corruption.
+    hash:           String,
+    entries:        BTreeMap<String, String>,
+}
+
+/// Keys that differ between two snapshots, with the values as logged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// As `key=value`.
+    pub added:   Vec<String>,
+    pub removed: Vec<String>,
+    /// As `key: old -> new`.
+    pub changed: Vec<String>,
+}
+
+impl Options {
+    /// Log the differences with the previous snapshot and replace it, if
+    /// enabled. Failures are logged, the run continues.
+    pub fn update(
+        &self,
+        version: &Version,
+        command: &Command,
+        matches: &ArgMatches,
+        built_in: &[(&str, String)],
+    ) {
+        let Some(path) = &self.env_snapshot else {
+            return;
+        };
+        let snapshot = Snapshot::collect(version, command, matches, built_in);
+        if let Err(err) = update(path, &snapshot) {
+            warn!(?path, "Error updating environment snapshot: {err:#}");
+        }
+    }
+}
+
+impl Snapshot {
+    /// The snapshot of this run, with the `built_in` values of [`config`].
+    fn collect(
+        version: &Version,
+        command: &Command,
+        matches: &ArgMatches,
+        built_in: &[(&str, String)],
+    ) -> Self {
+        let mut snapshot = Self::default();
+        if let Value::Object(environment) = environment() {
+            for (name, value) in environment {
+                snapshot.insert(&format!("env.{name}"), &text(&value));
+            }
+        }
+        if let Value::Object(config) = config(command, matches, built_in, fingerprint) {
+            for (id, flag) in config {
+                let value = format!("{} ({})", text(&flag["value"]), text(&flag["source"]));
+                snapshot.insert(&format!("config.{id}"), &value);
+            }
+        }
+        snapshot.insert("version", version.long_version);
+        snapshot.insert("features", &features().join(","));
+        for (resource, limit) in rlimits() {
+            snapshot.insert(&format!("rlimit.{resource}"), &limit);
+        }
+        snapshot
+    }
+
+    /// Add an entry, as a fingerprint if `key` holds a secret.
+    fn insert(&mut self, key: &str, value: &str) {
+        let value = if is_secret(key) && !value.starts_with(MASK) {
+            fingerprint(value)
+        } else {
+            value.to_owned()
+        };
+        self.entries.insert(key.to_owned(), value);
+    }
+
+    fn hash(&self) -> String {
+        let entries = serde_json::to_vec(&self.entries).unwrap_or_default();
+        format!("{:016x}", fnv1a(&entries))
+    }
+
+    /// The snapshot at `path`, `None` if there is none.
+    fn load(path: &Path) -> EyreResult<Option<Self>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let snapshot = serde_json::from_slice::<Self>(&contents)?;
+        if snapshot.schema_version != SCHEMA_VERSION {
+            bail!("unknown schema version {}", snapshot.schema_version);
+        }
+        if snapshot.hash != snapshot.hash() {
+            bail!("hash does not match the entries");
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Replace the snapshot at `path`.
+    fn save(&self, path: &Path) -> EyreResult<()> {
+        let snapshot = Self {
+            schema_version: SCHEMA_VERSION,
+            hash:           self.hash(),
+            entries:        self.entries.clone(),
+        };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&snapshot)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// The differences from `previous` to `self`, secrets masked.
+    fn diff(&self, previous: &Self) -> Diff {
+        let shown = |key: &str, value: &str| {
+            if is_secret(key) {
+                MASK.to_owned()
+            } else {
+                value.to_owned()
+            }
+        };
+        let mut diff = Diff::default();
+        for (key, value) in &self.entries {
+            match previous.entries.get(key) {
+                None => diff.added.push(format!("{key}={}", shown(key, value))),
+                Some(old) if old != value => diff.changed.push(format!(
+                    "{key}: {} -> {}",
+                    shown(key, old),
+                    shown(key, value)
+                )),
+                Some(_) => {}
+            }
+        }
+        for key in previous.entries.keys() {
+            if !self.entries.contains_key(key) {
+                diff.removed.push(key.clone());
+            }
+        }
+        diff
+    }
+}
+
+impl Diff {
+    const fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Log the differences of `snapshot` with the one at `path`, then replace it.
+fn update(path: &Path, snapshot: &Snapshot) -> EyreResult<Option<Diff>> {
+    let previous = Snapshot::load(path).unwrap_or_else(|err| {
+        warn!(?path, "Ignoring corrupt environment snapshot: {err:#}");
+        None
+    });
+    let diff = previous.map(|previous| snapshot.diff(&previous));
+    match &diff {
+        Some(diff) if diff.is_empty() => debug!(?path, "Environment unchanged since the last run"),
+        Some(diff) => info!(
+            ?path,
+            added = ?diff.added,
+            removed = ?diff.removed,
+            changed = ?diff.changed,
+            "Environment changed since the last run"
+        ),
+        None => debug!(?path, "No previous environment snapshot"),
+    }
+    snapshot
+        .save(path)
+        .wrap_err("Error writing environment snapshot")?;
+    Ok(diff)
+}
+
+/// A JSON value as an entry: strings without quotes.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Stands for a secret `value`: the mask and a hash, which differs when the
+/// value does.
+fn fingerprint(value: &str) -> String {
+    format!("{MASK}{:016x}", fnv1a(value.as_bytes()))
+}
+
+/// The 64 bit FNV-1a hash, stable across builds.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Soft and hard limits by resource, like `1024/4096`.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+#[allow(clippy::useless_conversion)] // `rlim_t` is not `u64` everywhere
+fn rlimits() -> Vec<(&'static str, String)> {
+    let format = |limit: libc::rlim_t| {
+        if limit == libc::RLIM_INFINITY {
+            "unlimited".to_owned()
+        } else {
+            u64::from(limit).to_string()
+        }
+    };
+    [
+        ("as", libc::RLIMIT_AS),
+        ("core", libc::RLIMIT_CORE),
+        ("nofile", libc::RLIMIT_NOFILE),
+        ("nproc", libc::RLIMIT_NPROC),
+        ("stack", libc::RLIMIT_STACK),
+    ]
+    .into_iter()
+    .filter_map(|(name, resource)| {
+        // SAFETY: `getrlimit` only writes to the provided struct.
+        let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+        if unsafe { libc::getrlimit(resource, std::ptr::addr_of_mut!(limit)) } != 0 {
+            return None;
+        }
+        let limits = format!("{}/{}", format(limit.rlim_cur), format(limit.rlim_max));
+        Some((name, limits))
+    })
+    .collect()
+}
+
+#[cfg(not(unix))]
+fn rlimits() -> Vec<(&'static str, String)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{env, process::id as pid};
+    use tracing_test::traced_test;
+
+    fn snapshot(entries: &[(&str, &str)]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (key, value) in entries {
+            snapshot.insert(key, value);
+        }
+        snapshot
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("cli-batteries-env-snapshot-{name}-{}.json", pid()))
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_two_startups() {
+        let path = temp_path("startups");
+        let _ = fs::remove_file(&path);
+        let first = snapshot(&[
+            ("env.LANG", "en_US.UTF-8"),
+            ("env.TZ", "UTC"),
+            ("config.api_token", "hunter2"),
+            ("config.log_filter", "info (default)"),
+        ]);
+        assert_eq!(update(&path, &first).unwrap(), None);
+        assert!(!logs_contain("Environment changed"));
+
+        let second = snapshot(&[
+            ("env.LANG", "de_DE.UTF-8"),
+            ("env.RUST_LOG", "debug"),
+            ("config.api_token", "hunter3"),
+            ("config.log_filter", "info (default)"),
+        ]);
+        let diff = update(&path, &second).unwrap().unwrap();
+        assert_eq!(diff, Diff {
+            added:   vec!["env.RUST_LOG=debug".to_owned()],
+            removed: vec!["env.TZ".to_owned()],
+            changed: vec![
+                "config.api_token: *** -> ***".to_owned(),
+                "env.LANG: en_US.UTF-8 -> de_DE.UTF-8".to_owned(),
+            ],
+        });
+        assert!(logs_contain("Environment changed since the last run"));
+        assert!(logs_contain("env.LANG: en_US.UTF-8 -> de_DE.UTF-8"));
+
+        // The secret is not written
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter"), "{contents}");
+
+        assert_eq!(update(&path, &second).unwrap(), Some(Diff::default()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_corrupt() {
+        let entries = snapshot(&[("env.TZ", "UTC")]);
+        for (name, contents) in [
+            ("garbage", "{ not json".to_owned()),
+            ("tampered", {
+                let path = temp_path("source");
+                entries.save(&path).unwrap();
+                let contents = fs::read_to_string(&path).unwrap();
+                fs::remove_file(&path).unwrap();
+                contents.replace("UTC", "CET")
+            }),
+        ] {
+            let path = temp_path(name);
+            fs::write(&path, contents).unwrap();
+            assert_eq!(update(&path, &entries).unwrap(), None, "{name}");
+            let saved = Snapshot::load(&path).unwrap().unwrap();
+            assert_eq!(saved.entries, entries.entries);
+            fs::remove_file(&path).unwrap();
+        }
+        assert!(logs_contain("Ignoring corrupt environment snapshot"));
+    }
+
+    #[test]
+    fn test_collect() {
+        let command = Command::new("app")
+            .arg(clap::Arg::new("db_password").long("db-password"))
+            .arg(clap::Arg::new("name").long("name").default_value("world"));
+        let matches = command
+            .clone()
+            .try_get_matches_from(["app", "--db-password", "hunter2"])
+            .unwrap();
+        let version = crate::trace::test::mock_version();
+        let snapshot = Snapshot::collect(&version, &command, &matches, &[]);
+        let entries = &snapshot.entries;
+        assert_eq!(entries["config.name"], "world (default)");
+        let password = &entries["config.db_password"];
+        assert!(password.starts_with(MASK), "{password}");
+        assert!(!password.contains("hunter2"), "{password}");
+        assert_eq!(entries["version"], version.long_version);
+        #[cfg(unix)]
+        assert!(entries.contains_key("rlimit.nofile"), "{entries:?}");
+    }
+}
@@ -0,0 +1,211 @@
+//! Outcome of the app on the `main` span.
+//!
+//! The app future runs in a `main` span. When it returns, the span gets an
+//! explicit `otel.status_code` and, on error:
+//!
+//! * `error.chain_depth`: the number of layers in the report,
+//! * `error.root_cause_type`: the type of the innermost error, for common
+//!   standard library and dependency errors, `unknown` otherwise,
+//! * `error.kind`: the [`ExitHint`] of the report.
+//!
+//! Apps classify errors with [`ResultExt::exit_hint`](crate::ResultExt), which
+//! adds the hint as a context layer. Without a hint, I/O errors that are
+//! usually worth retrying are [`ExitHint::Transient`] and everything else is
+//! [`ExitHint::Internal`].
+use eyre::Report;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+};
+use tracing::{field::Empty, info_span, Span};
+
+/// Why the app failed, recorded as `error.kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExitHint {
+    /// Bad arguments, configuration or input data.
+    UserInput,
+    /// A failure that may go away on retry, like a timeout.
+    Transient,
+    /// A bug or an unexpected condition.
+    Internal,
+}
+
+impl ExitHint {
+    /// Classify a report: the outermost hint, or a guess from the root cause.
+    #[must_use]
+    pub fn of(report: &Report) -> Self {
+        if let Some(hint) = report.downcast_ref::<Self>() {
+            return *hint;
+        }
+        let transient = report
+            .chain()
+            .filter_map(|err| err.downcast_ref::<io::Error>())
+            .any(|err| is_transient(err.kind()));
+        if transient {
+            Self::Transient
+        } else {
+            Self::Internal
+        }
+    }
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::UserInput => "user_input",
+            Self::Transient => "transient",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+/// The context layer added by [`ResultExt::exit_hint`](crate::ResultExt).
+impl Display for ExitHint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UserInput => "invalid input",
+            Self::Transient => "temporary failure",
+            Self::Internal => "internal error",
+        })
+    }
+}
+
+const fn is_transient(kind: io::ErrorKind) -> bool {
+    use io::ErrorKind::{
+        ConnectionAborted, ConnectionRefused, ConnectionReset, Interrupted, TimedOut, WouldBlock,
+    };
+    matches!(
+        kind,
+        ConnectionAborted
+            | ConnectionRefused
+            | ConnectionReset
+            | Interrupted
+            | TimedOut
+            | WouldBlock
+    )
+}
+
+/// Name of the innermost error type, as far as it can be recognized.
+fn root_cause_type(report: &Report) -> &'static str {
+    error_type(report.root_cause())
+}
+
+/// Name of the type of `err` for common standard library and dependency
+/// errors, `unknown` otherwise.
+pub fn error_type(err: &(dyn Error + 'static)) -> &'static str {
+    macro_rules! known {
+        ($err:expr, $($ty:ty),* $(,)?) => {
+            $(if $err.is::<$ty>() {
+                return std::any::type_name::<$ty>();
+            })*
+        };
+    }
+    known!(
+        err,
+        io::Error,
+        std::fmt::Error,
+        std::num::ParseIntError,
+        std::num::ParseFloatError,
+        std::str::ParseBoolError,
+        std::str::Utf8Error,
+        std::string::FromUtf8Error,
+        std::net::AddrParseError,
+        std::env::VarError,
+        std::time::SystemTimeError,
+        serde_json::Error,
+        clap::Error,
+        tokio::task::JoinError,
+        tokio::time::error::Elapsed,
+    );
+    "unknown"
+}
+
+/// The span the app runs in.
+pub fn main_span() -> Span {
+    info_span!(
+        "main",
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+        error.chain_depth = Empty,
+        error.root_cause_type = Empty,
+        error.kind = Empty,
+    )
+}
+
+/// Record the outcome of the app on its span.
+pub fn record(span: &Span, error: Option<&Report>) {
+    let Some(report) = error else {
+        span.record("otel.status_code", "OK");
+        return;
+    };
+    span.record("otel.status_code", "ERROR");
+    span.record("otel.status_message", format!("{report:#}").as_str());
+    span.record("error.chain_depth", report.chain().count());
+    span.record("error.root_cause_type", root_cause_type(report));
+    span.record("error.kind", ExitHint::of(report).as_str());
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::ResultExt;
+    use eyre::{eyre, WrapErr as _};
+
+    /// A timeout three layers deep.
+    pub fn nested_error() -> Report {
+        let err = io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded");
+        Err::<(), _>(err)
+            .wrap_err("Error reading response")
+            .wrap_err("Error fetching config")
+            .unwrap_err()
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(ExitHint::of(&nested_error()), ExitHint::Transient);
+        assert_eq!(ExitHint::of(&eyre!("bug")), ExitHint::Internal);
+        let not_found = Report::new(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(ExitHint::of(&not_found), ExitHint::Internal);
+
+        // The outermost hint wins over the root cause.
+        let hinted = Err::<(), _>(nested_error())
+            .exit_hint(ExitHint::UserInput)
+            .wrap_err("Error starting")
+            .exit_hint(ExitHint::Internal)
+            .unwrap_err();
+        assert_eq!(ExitHint::of(&hinted), ExitHint::Internal);
+        let hinted = Err::<(), _>(eyre!("no such file"))
+            .exit_hint(ExitHint::UserInput)
+            .wrap_err("Error reading input")
+            .unwrap_err();
+        assert_eq!(ExitHint::of(&hinted), ExitHint::UserInput);
+        assert_eq!(
+            format!("{hinted:#}"),
+            "Error reading input: invalid input: no such file"
+        );
+
+        // The hinted error is still there to downcast and classify.
+        let hinted = Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut))
+            .exit_hint(ExitHint::Internal)
+            .unwrap_err();
+        let err = hinted.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(hinted
+            .chain()
+            .filter_map(|err| err.downcast_ref::<io::Error>())
+            .any(|err| is_transient(err.kind())));
+    }
+
+    #[test]
+    fn test_root_cause_type() {
+        assert_eq!(root_cause_type(&nested_error()), "std::io::error::Error");
+        let parse = "x".parse::<u8>().unwrap_err();
+        let report = Report::new(parse).wrap_err("Error parsing port");
+        assert_eq!(root_cause_type(&report), "core::num::error::ParseIntError");
+        assert_eq!(root_cause_type(&eyre!("ad hoc")), "unknown");
+        let hinted = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .exit_hint(ExitHint::UserInput)
+            .unwrap_err();
+        assert_eq!(root_cause_type(&hinted), "std::io::error::Error");
+    }
+}
@@ -0,0 +1,1391 @@
+//! `--explain <flag>`: the full documentation of one flag.
+//!
+//! `--help` has a line per flag, which is not enough for flags with a syntax
+//! of their own like `--log-filter`. `--explain log-filter` prints the help
+//! text of the flag, the accepted values with examples, the flags it
+//! interacts with, its environment variable and configuration key and its
+//! default. The text comes from the long help of the flag and the
+//! [`Explanation`] registered for it: [`BUILTIN`] for the flags of this crate,
+//! [`Runner::explain`](crate::Runner::explain) for those of the app.
+//!
+//! The flag can be named with or without dashes, by its environment variable
+//! or by its configuration key. An unknown name fails with the closest flags
+//! as suggestions. With `--output json` the explanation, or the suggestions,
+//! are written as a JSON object for tooling.
+//!
+//! Like `--dump-cli-spec`, the request is read from the command line before
+//! the arguments are parsed, so it works when required arguments are missing.
+//! `--output` is only read next to `--explain` and is not a flag of its own,
+//! so it does not collide with an `--output` of the app.
+use crate::config_key::{config_key, env_name};
+use clap::{builder::PossibleValue, Arg, ArgAction, Command, Parser, ValueEnum};
+use eyre::{bail, eyre, Result as EyreResult};
+use serde::Serialize;
+use serde_json::json;
+use std::{env, ffi::OsString, fmt::Write};
+
+const FLAG: &str = "--explain";
+const FORMAT_FLAG: &str = "--output";
+
+/// The width paragraphs are wrapped at.
+const WIDTH: usize = 80;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Print the full documentation of a flag, like 'log-filter', and exit.
+    /// With '--output json' as a JSON object.
+    #[clap(long, value_name = "FLAG")]
+    explain: Option<String>,
+}
+
+/// Output format of `--explain`, picked with `--output`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    /// A JSON object.
+    Json,
+}
+
+/// The longer documentation of a flag, shown by `--explain` after its help
+/// text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Explanation {
+    /// Paragraphs on the accepted values and how the flag behaves.
+    pub details:  &'static str,
+    /// Example command lines, with `{bin}` replaced by the binary name.
+    pub examples: &'static [&'static str],
+    /// Long names of the flags it interacts with, without dashes.
+    pub related:  &'static [&'static str],
+}
+
+/// What `--explain` was asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    /// The name as given.
+    pub flag:   String,
+    /// The `--output`.
+    pub format: Format,
+}
+
+/// The request if `--explain` is on the command line, see the
+/// [module docs](self).
+///
+/// # Errors
+///
+/// If the `--output` is not `text` or `json`.
+pub fn requested() -> Option<EyreResult<Request>> {
+    request(env::args_os().skip(1))
+}
+
+fn request(args: impl IntoIterator<Item = OsString>) -> Option<EyreResult<Request>> {
+    let args = args
+        .into_iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .take_while(|arg| arg != "--")
+        .collect::<Vec<_>>();
+    let value = |flag: &str| {
+        args.iter().enumerate().find_map(|(i, arg)| {
+            if arg == flag {
+                args.get(i + 1).cloned()
+            } else {
+                arg.strip_prefix(flag)?.strip_prefix('=').map(str::to_owned)
+            }
+        })
+    };
+    let flag = value(FLAG)?;
+    let format = match value(FORMAT_FLAG) {
+        Some(format) => match Format::from_str(&format, false) {
+            Ok(format) => format,
+            Err(_) => {
+                return Some(Err(eyre!(
+                    "Invalid `{FORMAT_FLAG} {format}` for `{FLAG}`, expected `text` or `json`"
+                )))
+            }
+        },
+        None => Format::default(),
+    };
+    Some(Ok(Request { flag, format }))
+}
+
+/// Print the explanation of the flag of `command` asked for by `request`,
+/// with the explanations of the app in `app`.
+///
+/// # Errors
+///
+/// If `command` has no such flag, after printing the suggestions with
+/// `--output json`.
+pub fn print(
+    command: &Command,
+    app: &[(&'static str, Explanation)],
+    request: &Request,
+) -> EyreResult<()> {
+    // With the `--help` and `--version` clap adds.
+    let mut command = command.clone();
+    command.build();
+    let Some(arg) = find(&command, &request.flag) else {
+        let suggestions = suggestions(&command, &request.flag);
+        if request.format == Format::Json {
+            let unknown = json!({
+                "flag": request.flag,
+                "error": "unknown flag",
+                "suggestions": suggestions,
+            });
+            println!("{}", serde_json::to_string_pretty(&unknown)?);
+        }
+        let suggestions = suggestions
+            .iter()
+            .map(|long| format!("`--{long}`"))
+            .collect::<Vec<_>>();
+        match suggestions.as_slice() {
+            [] => bail!("Unknown flag `{}`", request.flag),
+            _ => bail!(
+                "Unknown flag `{}`, did you mean {}?",
+                request.flag,
+                suggestions.join(", ")
+            ),
+        }
+    };
+    let page = Page::new(&command, arg, lookup(app, arg));
+    if request.format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&page)?);
+    } else {
+        print!("{}", page.text());
+    }
+    Ok(())
+}
+
+/// The explanation of `arg`, an app one over a built-in one.
+fn lookup(app: &[(&'static str, Explanation)], arg: &Arg) -> Option<Explanation> {
+    let long = arg.get_long()?;
+    app.iter()
+        .chain(BUILTIN)
+        .find(|(flag, _)| *flag == long)
+        .map(|(_, explanation)| *explanation)
+}
+
+/// The argument of `command` named `name`: its long flag with or without
+/// dashes, a hidden alias, its short flag, environment variable or
+/// configuration key.
+fn find<'a>(command: &'a Command, name: &str) -> Option<&'a Arg> {
+    let long = long_name(name);
+    command.get_arguments().find(|arg| {
+        arg.get_long() == Some(long.as_str())
+            || arg
+                .get_all_aliases()
+                .into_iter()
+                .flatten()
+                .any(|alias| alias == long)
+            || (name.len() == 2
+                && name.starts_with('-')
+                && arg.get_short().is_some_and(|short| name.ends_with(short)))
+            || arg
+                .get_env()
+                .is_some_and(|env| env.to_str() == Some(&env_name(&long)))
+    })
+}
+
+/// `name` as a long flag without dashes.
+fn long_name(name: &str) -> String {
+    name.trim_start_matches('-')
+        .replace('_', "-")
+        .to_ascii_lowercase()
+}
+
+/// Up to three long flags of `command` close to `name`, closest first.
+fn suggestions(command: &Command, name: &str) -> Vec<String> {
+    let name = long_name(name);
+    let mut close = command
+        .get_arguments()
+        .filter_map(Arg::get_long)
+        .filter_map(|long| {
+            let distance = edit_distance(&name, long);
+            let contained = name.len() >= 3 && long.contains(name.as_str());
+            (distance <= (name.len() / 3).max(2) || contained).then_some((distance, long))
+        })
+        .collect::<Vec<_>>();
+    close.sort_unstable();
+    close
+        .into_iter()
+        .take(3)
+        .map(|(_, long)| long.to_owned())
+        .collect()
+}
+
+/// The number of characters to insert, delete or replace to turn `a` into
+/// `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != *b);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Everything `--explain` shows about a flag.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct Page {
+    /// The long flag without dashes, or the id of a positional argument.
+    flag:            String,
+    short:           Option<char>,
+    /// The value names, without brackets, empty for switches.
+    value:           Vec<String>,
+    help:            Option<String>,
+    /// Further paragraphs of the help text, if any.
+    long_help:       Option<String>,
+    details:         Option<String>,
+    examples:        Vec<String>,
+    /// Related flags of the command, without dashes.
+    related:         Vec<String>,
+    env:             Option<String>,
+    config_key:      Option<String>,
+    default:         Vec<String>,
+    possible_values: Vec<String>,
+}
+
+impl Page {
+    fn new(command: &Command, arg: &Arg, explanation: Option<Explanation>) -> Self {
+        let explanation = explanation.unwrap_or_default();
+        let help = arg.get_help().map(ToString::to_string);
+        let long_help = arg
+            .get_long_help()
+            .map(ToString::to_string)
+            .filter(|long_help| Some(long_help) != help.as_ref());
+        let takes_values =
+            arg.get_action().takes_values() && !matches!(arg.get_action(), ArgAction::Count);
+        let value = arg.get_value_names().filter(|_| takes_values).map_or_else(
+            || {
+                takes_values
+                    .then(|| arg.get_id().as_str().to_ascii_uppercase())
+                    .into_iter()
+                    .collect()
+            },
+            |names| names.iter().map(ToString::to_string).collect(),
+        );
+        Self {
+            flag: arg
+                .get_long()
+                .unwrap_or_else(|| arg.get_id().as_str())
+                .to_owned(),
+            short: arg.get_short(),
+            value,
+            help,
+            long_help,
+            details: Some(explanation.details.trim().to_owned())
+                .filter(|details| !details.is_empty()),
+            examples: explanation
+                .examples
+                .iter()
+                .map(|example| example.replace("{bin}", command.get_name()))
+                .collect(),
+            related: explanation
+                .related
+                .iter()
+                .filter(|long| {
+                    command
+                        .get_arguments()
+                        .any(|arg| arg.get_long() == Some(long))
+                })
+                .map(|long| (*long).to_owned())
+                .collect(),
+            env: arg.get_env().map(|env| env.to_string_lossy().into_owned()),
+            // Not of the `--help` and `--version` of clap
+            config_key: arg
+                .get_long()
+                .filter(|_| !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+                .map(config_key),
+            default: arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().into_owned())
+                .filter(|value| !value.is_empty())
+                .collect(),
+            // Not the `true` and `false` of switches
+            possible_values: arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| takes_values && !value.is_hide_set())
+                .map(PossibleValue::get_name)
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+
+    /// The page as text, with two space indented sections and paragraphs
+    /// wrapped at [`WIDTH`].
+    fn text(&self) -> String {
+        let mut out = String::new();
+        let _ = write!(out, "--{}", self.flag);
+        if let Some(short) = self.short {
+            let _ = write!(out, ", -{short}");
+        }
+        for value in &self.value {
+            let _ = write!(out, " <{value}>");
+        }
+        out.push('\n');
+        for paragraph in [&self.help, &self.long_help, &self.details]
+            .into_iter()
+            .flatten()
+        {
+            out.push('\n');
+            for line in paragraph.lines() {
+                wrap(&mut out, line);
+            }
+        }
+        let mut section = |title: &str, lines: &[String]| {
+            if !lines.is_empty() {
+                let _ = writeln!(out, "\n{title}:");
+                for line in lines {
+                    let _ = writeln!(out, "  {line}");
+                }
+            }
+        };
+        section("Examples", &self.examples);
+        section("Possible values", &self.possible_values);
+        section(
+            "Related",
+            &self
+                .related
+                .iter()
+                .map(|long| format!("--{long}"))
+                .collect::<Vec<_>>(),
+        );
+        let default = if self.default.is_empty() {
+            "none".to_owned()
+        } else {
+            self.default.join(",")
+        };
+        let settings = [
+            self.env.as_ref().map(|env| format!("Environment: {env}")),
+            self.config_key
+                .as_ref()
+                .map(|key| format!("Config key:  {key}")),
+            Some(format!("Default:     {default}")),
+        ];
+        section(
+            "Settings",
+            &settings.into_iter().flatten().collect::<Vec<_>>(),
+        );
+        out
+    }
+}
+
+/// Append `line` to `out`, indented by two spaces and broken between words
+/// to fit in [`WIDTH`] columns.
+fn wrap(out: &mut String, line: &str) {
+    let mut column = 0;
+    for word in line.split_whitespace() {
+        let width = word.chars().count();
+        if column > 0 && column + 1 + width > WIDTH {
+            out.push('\n');
+            column = 0;
+        }
+        if column == 0 {
+            out.push_str("  ");
+            column = 2;
+        } else {
+            out.push(' ');
+            column += 1;
+        }
+        out.push_str(word);
+        column += width;
+    }
+    out.push('\n');
+}
+
+/// The explanations of the flags of this crate, by long flag. Flags of
+/// features that are not compiled in are left out of `--explain`.
+pub static BUILTIN: &[(&str, Explanation)] = &[
+    // Verbosity and the log filter
+    ("verbose", Explanation {
+        details:  "Without it the app crates log at INFO and other crates at ERROR. -v raises the \
+                   other crates to INFO, -vv and -vvv the app crates to DEBUG and TRACE, and more \
+                   raise the other crates further. The level can also be named, --verbose=debug \
+                   equals -vv, which suits templated flags and VERBOSE=debug. Mixing both forms \
+                   is an error. Applications can map the count to levels differently.",
+        examples: &["{bin} -vv", "{bin} --verbose=debug"],
+        related:  &["quiet", "log-filter", "print-log-filter"],
+    }),
+    ("quiet", Explanation {
+        details:  "Lowers the app crates to WARN with -q and all crates to ERROR with -qq. -qqq \
+                   turns the log output off. Each -q cancels one -v, and targets named in \
+                   --log-filter keep their level.",
+        examples: &["{bin} -q", "{bin} -qqq --log-filter app::report=info"],
+        related:  &["verbose", "log-filter"],
+    }),
+    ("log-filter", Explanation {
+        details:  "Comma separated directives in the syntax of tracing's EnvFilter: a level like \
+                   'warn' for all targets, 'target=level' for a module and the modules below it, \
+                   and 'target[span{field=value}]=level' to log more inside matching spans \
+                   only.\n\nThe directives override the levels set by --verbose and --quiet for \
+                   the targets they name. Without the flag, LOG_FILTER and then RUST_LOG are \
+                   read, and the filter built into the binary comes last. The filter can be \
+                   changed while running through SIGHUP or --log-control-socket.",
+        examples: &[
+            "{bin} --log-filter warn,app::db=debug",
+            "{bin} --log-filter 'info,app[request{method=POST}]=trace'",
+        ],
+        related:  &[
+            "verbose",
+            "quiet",
+            "print-log-filter",
+            "log-filter-file",
+            "log-control-socket",
+            "otlp-log-filter",
+        ],
+    }),
+    ("print-log-filter", Explanation {
+        details:  "Prints every directive of the effective log filter with where it comes from: \
+                   the built-in defaults, --verbose, --quiet, --log-filter or RUST_LOG. Use it to \
+                   find out why a target is or is not logged.",
+        examples: &["{bin} -v --log-filter hyper=warn --print-log-filter"],
+        related:  &["log-filter", "verbose", "quiet"],
+    }),
+    ("log-filter-file", Explanation {
+        details:  "On SIGHUP the log filter is read again and swapped in, from this file if given \
+                   and from LOG_FILTER otherwise. The file holds directives like --log-filter. A \
+                   filter that does not parse is logged and the current one stays in use.",
+        examples: &["{bin} --log-filter-file /etc/app/log-filter"],
+        related:  &["log-filter", "log-control-socket"],
+    }),
+    ("log-control-socket", Explanation {
+        details:  "Every line sent to the unix socket is a command, answered with 'ok' and the \
+                   effective filter, or 'error' and the problem. 'filter <directives>' replaces \
+                   --log-filter, combined with --verbose and --quiet. 'filter reset' goes back to \
+                   the filter of the command line.",
+        examples: &["echo 'filter app=trace' | socat - UNIX-CONNECT:/run/app/log.sock"],
+        related:  &["log-filter", "log-filter-file"],
+    }),
+    ("otlp-log-filter", Explanation {
+        details:  "Directives like --log-filter for the events exported over OpenTelemetry, to \
+                   send more detail to the collector than the log output shows.",
+        examples: &["{bin} --trace-otlp grpc://localhost:4317 --otlp-log-filter debug"],
+        related:  &["log-filter", "trace-otlp"],
+    }),
+    // Log lines
+    ("log-format", Explanation {
+        details:  "'tiny' is a short line with the uptime, for terminals. 'compact' is one line \
+                   with key=value fields, 'pretty' spreads an event over several lines and \
+                   'pretty-compact' is a one line 'pretty'. 'json' and 'logfmt' are for log \
+                   collectors, 'otlp' writes JSON in the OpenTelemetry log data model. The binary \
+                   can build in a different default.",
+        examples: &[
+            "{bin} --log-format json",
+            "{bin} --log-format compact --log-field-width 40",
+        ],
+        related:  &[
+            "log-timestamp",
+            "log-show-target",
+            "log-sink",
+            "log-json-flatten",
+        ],
+    }),
+    ("log-timestamp", Explanation {
+        details:  "Without the flag every format keeps its own: 'uptime' for 'tiny' and \
+                   'pretty-compact', 'rfc3339' for the others. 'none' leaves the timestamp out, \
+                   for tools that add their own.",
+        examples: &[
+            "{bin} --log-timestamp none",
+            "{bin} --log-timestamp unix-ms",
+        ],
+        related:  &["log-format", "log-utc", "log-local"],
+    }),
+    ("log-utc", Explanation {
+        details:  "The default for 'rfc3339' timestamps, spelled out. Overrides --log-local.",
+        examples: &["{bin} --log-timestamp rfc3339 --log-utc"],
+        related:  &["log-local", "log-timestamp"],
+    }),
+    ("log-local", Explanation {
+        details:  "Only affects 'rfc3339' timestamps. The offset is read once at startup.",
+        examples: &["{bin} --log-timestamp rfc3339 --log-local"],
+        related:  &["log-utc", "log-timestamp"],
+    }),
+    ("log-span-events", Explanation {
+        details:  "'new' logs a line when a span is created, 'close' when it ends with its busy \
+                   and idle time, 'enter' and 'exit' on every poll of a future in it. 'full' is \
+                   all of them and 'none' turns them off.",
+        examples: &[
+            "{bin} --log-span-events close",
+            "{bin} --log-span-events none",
+        ],
+        related:  &["log-format", "slow-span-threshold"],
+    }),
+    ("log-show-thread", Explanation {
+        details:  "Tells apart the events of threads working in parallel. Unnamed threads are \
+                   shown by their id.",
+        examples: &["{bin} --log-show-thread"],
+        related:  &["log-show-target", "log-show-module"],
+    }),
+    ("log-show-target", Explanation {
+        details:  "The target is the module path unless the event sets another one. Turn it off \
+                   with '--log-show-target false' for shorter lines.",
+        examples: &["{bin} --log-show-target false"],
+        related:  &["log-show-module", "log-target-width"],
+    }),
+    ("log-show-module", Explanation {
+        details:  "Useful when events set their own target, which then hides where they come from.",
+        examples: &["{bin} --log-show-module"],
+        related:  &["log-show-target", "tag"],
+    }),
+    ("log-json-flatten", Explanation {
+        details:  "Suits log stores like Loki that index top-level keys. 'span' is the name of \
+                   the innermost span, whose fields win over those of outer spans. A span field \
+                   named like an event field is prefixed with 'span.'.",
+        examples: &["{bin} --log-format json --log-json-flatten"],
+        related:  &["log-format"],
+    }),
+    ("log-target-width", Explanation {
+        details:  "Shorter targets are padded so messages line up, longer ones push the rest of \
+                   the line to the right.",
+        examples: &["{bin} --log-format pretty-compact --log-target-width 32"],
+        related:  &["log-format", "log-show-target"],
+    }),
+    ("log-field-width", Explanation {
+        details:  "Keeps lines readable when fields hold long values like request bodies. The \
+                   values of span fields are cut the same way. Messages are not cut.",
+        examples: &["{bin} --log-format compact --log-field-width 40"],
+        related:  &["log-format"],
+    }),
+    ("log-escape-control", Explanation {
+        details:  "Control characters in logged data, like an escape sequence in a user name, are \
+                   written as visible escapes so they can not change the terminal. 'tty-only' \
+                   escapes when writing to a terminal, 'always' also in files and pipes, 'never' \
+                   writes the data as is.",
+        examples: &["{bin} --log-escape-control always"],
+        related:  &["ascii-only", "color"],
+    }),
+    ("log-redact-fields", Explanation {
+        details:  "Matching fields are replaced before any output sees them: the log output and \
+                   sinks, the session log, the binary log and the OpenTelemetry export.",
+        examples: &["{bin} --log-redact-fields 'password,authorization,*token'"],
+        related:  &["tag"],
+    }),
+    ("tag", Explanation {
+        details:  "Each 'key=value' becomes a field of every event and a resource attribute, so \
+                   the logs and traces of one run can be found later. Fields an event sets itself \
+                   win. A key given twice keeps the last value.",
+        examples: &["{bin} --tag ticket=ABC-123 --tag attempt=2"],
+        related:  &["trace-resource", "log-show-module"],
+    }),
+    // Log outputs
+    ("log-stream", Explanation {
+        details:  "Use 'stdout' when the program writes nothing else there and the log should go \
+                   down a pipe.",
+        examples: &["{bin} --log-stream stdout | tee app.log"],
+        related:  &["log-file", "log-target"],
+    }),
+    ("log-file", Explanation {
+        details:  "The file is created if needed and appended to. With --log-rotate it is rotated \
+                   by time or size.",
+        examples: &["{bin} --log-file /var/log/app.log --log-rotate daily"],
+        related:  &[
+            "log-rotate",
+            "log-keep",
+            "log-file-compress",
+            "log-disk-full-policy",
+            "log-stream",
+        ],
+    }),
+    ("log-rotate", Explanation {
+        details:  "The file is renamed with the time appended and a new one started when the UTC \
+                   day or hour changes, or before a line would grow it past the size. Lines are \
+                   never split across files.",
+        examples: &["{bin} --log-file app.log --log-rotate size:100MB --log-keep 5"],
+        related:  &["log-file", "log-keep"],
+    }),
+    ("log-keep", Explanation {
+        details:  "Older rotated files are deleted when a new one is started.",
+        examples: &["{bin} --log-file app.log --log-rotate daily --log-keep 30"],
+        related:  &["log-rotate", "log-file"],
+    }),
+    ("log-file-compress", Explanation {
+        details:  "The --log-file, the file targets of --log-sink and the session log are zstd \
+                   compressed and get a .zst extension. The current zstd frame is completed every \
+                   second, at rotation and on exit, so the log of a crashed run is readable up to \
+                   the last second with --cat-session-log.",
+        examples: &["{bin} --log-file app.log --log-rotate daily --log-file-compress"],
+        related:  &["log-file", "log-sink", "session-log", "cat-session-log"],
+    }),
+    ("log-disk-full-policy", Explanation {
+        details:  "'drop' discards the event and counts it as lost telemetry. 'block' retries the \
+                   write for up to a second before dropping it. 'fallback-stderr' alerts once and \
+                   writes this and later events to stderr. 'drop' and 'block' resume once space \
+                   is freed.",
+        examples: &["{bin} --log-file app.log --log-disk-full-policy fallback-stderr"],
+        related:  &["log-file", "session-log"],
+    }),
+    ("log-target", Explanation {
+        details:  "'syslog' sends every event as a datagram to the local syslog daemon, tagged \
+                   with the crate name. 'journald' writes to the systemd journal with structured \
+                   fields. Without the daemon, the output stays on stderr with a warning.",
+        examples: &["{bin} --log-target syslog --syslog-facility local0"],
+        related:  &["syslog-facility", "log-stream"],
+    }),
+    ("syslog-facility", Explanation {
+        details:  "Combined with the severity of the level into the priority of the message.",
+        examples: &["{bin} --log-target syslog --syslog-facility local3"],
+        related:  &["log-target"],
+    }),
+    ("log-sink", Explanation {
+        details:  "A sink gets the events of the log output with its own copy of the log filter \
+                   and writes them in its own format. Keys are 'format', any --log-format, and \
+                   'target', 'stdout', 'stderr' or 'file:<path>'. Files rotate like --log-file.",
+        examples: &[
+            "{bin} --log-sink 'format=json,target=file:/var/log/app.json'",
+            "{bin} --log-sink 'format=logfmt,target=stdout;format=json,target=file:app.json'",
+        ],
+        related:  &["log-format", "log-file", "log-rotate"],
+    }),
+    ("log-rate-limit", Explanation {
+        details:  "A callsite in a hot loop can drown the log output. The limit applies per \
+                   callsite, the suppressed events are counted and summarized like 'Suppressed \
+                   12,345 similar messages'.",
+        examples: &["{bin} --log-rate-limit 100"],
+        related:  &["log-sink", "trace-otlp"],
+    }),
+    ("log-async", Explanation {
+        details:  "Events are still formatted on the thread that logs them, only the write is \
+                   queued. No line is lost: logging waits while the queue is full, and the queue \
+                   is drained at exit.",
+        examples: &["{bin} --log-async --log-file app.log"],
+        related:  &["log-file", "log-stream"],
+    }),
+    ("binary-log", Explanation {
+        details:  "For services that log faster than text can be written. Names are interned and \
+                   frames are checksummed, so a damaged file can still be read. Read it with \
+                   --decode-binary-log or --cat-session-log.",
+        examples: &[
+            "{bin} --binary-log app.blog",
+            "{bin} --decode-binary-log app.blog",
+        ],
+        related:  &["decode-binary-log", "record-events", "cat-session-log"],
+    }),
+    ("decode-binary-log", Explanation {
+        details:  "Damaged frames are skipped. Pipe the output into jq or a log collector.",
+        examples: &["{bin} --decode-binary-log app.blog | jq .message"],
+        related:  &["binary-log", "cat-session-log"],
+    }),
+    ("record-events", Explanation {
+        details:  "Writes the binary log format with the lifecycle of the spans added, so \
+                   --replay can send the events through other log options later.",
+        examples: &["{bin} --record-events run.events"],
+        related:  &["replay", "binary-log"],
+    }),
+    ("replay", Explanation {
+        details:  "Try another --log-format or --log-filter on real events offline. Replayed \
+                   events keep their recorded time and parents. Plain --binary-log files replay \
+                   without spans.",
+        examples: &["{bin} --replay run.events --log-format pretty --log-filter app=trace"],
+        related:  &["record-events", "binary-log", "log-format", "log-filter"],
+    }),
+    ("log-shmem", Explanation {
+        details:  "A fixed size ring in a file, by default /dev/shm/{crate}-{pid}.ring. Old \
+                   events are overwritten and the last ones survive a crash. The file is removed \
+                   at a regular shutdown.",
+        examples: &["{bin} --log-shmem /dev/shm/app.ring --log-shmem-size 16MiB"],
+        related:  &["log-shmem-size", "dump-shmem"],
+    }),
+    ("log-shmem-size", Explanation {
+        details:  "The ring never grows, a larger one keeps more history.",
+        examples: &["{bin} --log-shmem /dev/shm/app.ring --log-shmem-size 16MiB"],
+        related:  &["log-shmem"],
+    }),
+    ("dump-shmem", Explanation {
+        details:  "Works on the ring of a running process and of one that crashed.",
+        examples: &["{bin} --dump-shmem /dev/shm/app.ring"],
+        related:  &["log-shmem"],
+    }),
+    ("trace-flame", Explanation {
+        details:  "Records the time spent in each span stack, with the log filter. Turn the file \
+                   into an SVG with inferno-flamegraph.",
+        examples: &["{bin} --trace-flame run.folded && inferno-flamegraph run.folded > run.svg"],
+        related:  &["log-filter"],
+    }),
+    // Session log and diagnostics
+    ("session-log", Explanation {
+        details:  "A JSON log of every run at DEBUG, TRACE for the app crates, regardless of the \
+                   verbosity of the log output, to attach to a bug report. The path is logged \
+                   after the error report of a failed run.",
+        examples: &[
+            "{bin} --session-log off",
+            "{bin} --session-log dir=/tmp/app-logs",
+        ],
+        related:  &["session-log-keep", "log-file-compress", "cat-session-log"],
+    }),
+    ("session-log-keep", Explanation {
+        details:  "The oldest files are deleted when a new run starts.",
+        examples: &["{bin} --session-log-keep 50"],
+        related:  &["session-log"],
+    }),
+    ("cat-session-log", Explanation {
+        details:  "Prints one line per event. Reads compressed session logs and, with the \
+                   binary-log feature, binary logs as well.",
+        examples: &["{bin} --cat-session-log ~/.local/state/app/logs/latest.log"],
+        related:  &["session-log", "binary-log"],
+    }),
+    ("recent-errors-size", Explanation {
+        details:  "WARN and ERROR events are kept in a ring independent of the log filter. It is \
+                   served on /recent-errors of the metrics server, or dumped to stderr on SIGUSR1 \
+                   without the prometheus feature, and included in support bundles.",
+        examples: &["{bin} --recent-errors-size 1024"],
+        related:  &["prometheus", "support-bundle"],
+    }),
+    ("fail-on-error-logs", Explanation {
+        details:  "For batch tools that log errors and go on: a run that returns successfully \
+                   fails with the number of errors logged.",
+        examples: &["{bin} --fail-on-error-logs"],
+        related:  &["auto-debug"],
+    }),
+    ("warn-expensive-disabled-logging", Explanation {
+        details:  "Enables every callsite to measure what recording the fields of rejected events \
+                   costs, like a large ?value. The most expensive callsites are candidates for \
+                   lazy_field. For diagnosis only.",
+        examples: &["{bin} --warn-expensive-disabled-logging"],
+        related:  &["log-filter"],
+    }),
+    ("warn-span-cardinality", Explanation {
+        details:  "Trace backends index spans by name, so an id in otel.name creates a series per \
+                   id. Distinct values are counted per callsite and a warning points at the \
+                   callsite once it exceeds the limit.",
+        examples: &["{bin} --warn-span-cardinality --span-cardinality-limit 50"],
+        related:  &["span-cardinality-limit", "span-cardinality-field"],
+    }),
+    ("span-cardinality-limit", Explanation {
+        details:  "Counting stops for a callsite once it warned.",
+        examples: &["{bin} --warn-span-cardinality --span-cardinality-limit 50"],
+        related:  &["warn-span-cardinality", "span-cardinality-field"],
+    }),
+    ("span-cardinality-field", Explanation {
+        details:  "Repeatable, for backends that index other fields as well.",
+        examples: &["{bin} --span-cardinality-field otel.name --span-cardinality-field route"],
+        related:  &["warn-span-cardinality", "span-cardinality-limit"],
+    }),
+    ("slow-span-threshold", Explanation {
+        details:  "A target matches like in the log filter and the longest matching target wins. \
+                   Warnings are limited per span name. This does not depend on --log-span-events \
+                   or on OpenTelemetry.",
+        examples: &["{bin} --slow-span-threshold 500ms --slow-span-threshold db::*=100ms"],
+        related:  &["log-span-events"],
+    }),
+    ("debug-shutdown", Explanation {
+        details:  "Events logged while the log outputs shut down may be lost, typically from a \
+                   Drop impl. They are counted per callsite and listed as the last output of the \
+                   process, and a panic in a destructor is named with both panics.",
+        examples: &["{bin} --debug-shutdown"],
+        related:  &["session-log"],
+    }),
+    ("auto-debug", Explanation {
+        details:  "The errors logged are checked every second. When more than \
+                   --auto-debug-error-rate were logged in the last minute, the app crates are \
+                   logged at DEBUG for --auto-debug-duration and the filter is then restored. A \
+                   window starts at most once per --auto-debug-cooldown.",
+        examples: &["{bin} --auto-debug --auto-debug-error-rate 5 --auto-debug-duration 5m"],
+        related:  &[
+            "auto-debug-error-rate",
+            "auto-debug-duration",
+            "auto-debug-cooldown",
+            "log-filter",
+        ],
+    }),
+    ("auto-debug-error-rate", Explanation {
+        details:  "Counted over a sliding minute of the errors that pass the log filter.",
+        examples: &["{bin} --auto-debug --auto-debug-error-rate 5"],
+        related:  &["auto-debug"],
+    }),
+    ("auto-debug-duration", Explanation {
+        details:  "A duration like '2m' or '30s', longer than zero.",
+        examples: &["{bin} --auto-debug --auto-debug-duration 5m"],
+        related:  &["auto-debug", "auto-debug-cooldown"],
+    }),
+    ("auto-debug-cooldown", Explanation {
+        details:  "Counted from the start of the previous window, so it should be longer than \
+                   --auto-debug-duration.",
+        examples: &["{bin} --auto-debug --auto-debug-cooldown 30m"],
+        related:  &["auto-debug", "auto-debug-duration"],
+    }),
+    ("tokio-console", Explanation {
+        details:  "Inspect the async tasks of the running program with the tokio-console tool. \
+                   The binary has to be built with RUSTFLAGS=\"--cfg tokio_unstable\".",
+        examples: &[
+            "{bin} --tokio-console",
+            "tokio-console http://127.0.0.1:6669",
+        ],
+        related:  &["log-filter"],
+    }),
+    ("error-webhook-url", Explanation {
+        details:  "Every ERROR event, regardless of the log filter, is posted from a background \
+                   thread, deduplicated per callsite and retried with backoff. Logging never \
+                   waits for the webhook. https URLs need the tls feature.",
+        examples: &[
+            "{bin} --error-webhook-url http://alerts:8080/hook",
+            "{bin} --error-webhook-url https://hooks.slack.com/services/T0/B0/X",
+        ],
+        related:  &["error-webhook-format", "error-webhook-rate"],
+    }),
+    ("error-webhook-format", Explanation {
+        details:  "'slack' posts a {\"text\": ...} message. 'json' posts the message, target, \
+                   fields, trace id, version and hostname.",
+        examples: &["{bin} --error-webhook-url http://proxy/hook --error-webhook-format json"],
+        related:  &["error-webhook-url"],
+    }),
+    ("error-webhook-rate", Explanation {
+        details:  "Events over the limit are counted as lost telemetry, not queued.",
+        examples: &["{bin} --error-webhook-url http://proxy/hook --error-webhook-rate 30"],
+        related:  &["error-webhook-url"],
+    }),
+    // OpenTelemetry
+    ("trace-otlp", Explanation {
+        details:  "The scheme picks the protocol: 'grpc' and 'unix' for gRPC, 'http' for protobuf \
+                   over HTTP. Spans and the events that pass --otlp-log-filter are exported.",
+        examples: &[
+            "{bin} --trace-otlp grpc://localhost:4317",
+            "{bin} --trace-otlp unix:///run/otel/collector.sock",
+        ],
+        related:  &[
+            "otlp-log-filter",
+            "trace-resource",
+            "telemetry-init-timeout",
+            "otlp-tls-ca",
+            "trace-url",
+        ],
+    }),
+    ("trace-resource", Explanation {
+        details:  "Later sources win: the detected attributes, the version, \
+                   OTEL_RESOURCE_ATTRIBUTES, OTEL_SERVICE_NAME, TRACE_RESOURCE_*, these arguments \
+                   and --tag.",
+        examples: &["{bin} --trace-resource deployment.environment=prod"],
+        related:  &["trace-otlp", "tag"],
+    }),
+    ("telemetry-init-timeout", Explanation {
+        details:  "A duration like '3s'. What happens after it is set by --telemetry-init-policy.",
+        examples: &["{bin} --trace-otlp grpc://collector:4317 --telemetry-init-timeout 10s"],
+        related:  &["telemetry-init-policy", "trace-otlp"],
+    }),
+    ("telemetry-init-policy", Explanation {
+        details:  "'degrade' starts the app without a connected exporter, which catches up in the \
+                   background. 'fail' makes a missing collector a startup error.",
+        examples: &["{bin} --trace-otlp grpc://collector:4317 --telemetry-init-policy fail"],
+        related:  &["telemetry-init-timeout", "trace-otlp"],
+    }),
+    ("otlp-tls-ca", Explanation {
+        details:  "Any TLS option turns on TLS for the gRPC exporter. The file is checked at \
+                   startup.",
+        examples: &["{bin} --trace-otlp grpc://collector:4317 --otlp-tls-ca ca.pem"],
+        related:  &["otlp-tls-cert", "otlp-tls-key", "trace-otlp"],
+    }),
+    ("otlp-tls-cert", Explanation {
+        details:  "Needs --otlp-tls-key as well. Only used with the gRPC exporter.",
+        examples: &[
+            "{bin} --trace-otlp grpc://collector:4317 --otlp-tls-cert client.pem --otlp-tls-key \
+             client.key",
+        ],
+        related:  &["otlp-tls-key", "otlp-tls-ca"],
+    }),
+    ("otlp-tls-key", Explanation {
+        details:  "Needs --otlp-tls-cert as well. Only used with the gRPC exporter.",
+        examples: &[
+            "{bin} --trace-otlp grpc://collector:4317 --otlp-tls-cert client.pem --otlp-tls-key \
+             client.key",
+        ],
+        related:  &["otlp-tls-cert", "otlp-tls-ca"],
+    }),
+    ("trace-url", Explanation {
+        details:  "After the error report of a failed run, a line points to the trace of the run \
+                   in the tracing backend. Without --trace-otlp there is no trace and no line.",
+        examples: &["{bin} --trace-url 'https://jaeger.example.com/trace/{trace_id}'"],
+        related:  &["trace-otlp", "copy-trace-url"],
+    }),
+    ("copy-trace-url", Explanation {
+        details:  "Without a clipboard, like over SSH, only the line is written.",
+        examples: &["{bin} --trace-url 'https://jaeger/trace/{trace_id}' --copy-trace-url"],
+        related:  &["trace-url"],
+    }),
+    ("trace-context-fd", Explanation {
+        details:  "Joins the trace of the stage that started this process, so a pipeline of \
+                   programs ends up in one trace. Set up by the upstream stage, not by hand.",
+        examples: &["{bin} --trace-context-fd 3"],
+        related:  &["trace-otlp"],
+    }),
+    // Terminal
+    ("ascii-only", Explanation {
+        details:  "Log lines and error reports are written without colors, characters outside of \
+                   ASCII are escaped and phase progress is drawn as plain ticks. For serial \
+                   consoles and CI log viewers.",
+        examples: &["{bin} --ascii-only"],
+        related:  &["color", "log-escape-control"],
+    }),
+    ("color", Explanation {
+        details:  "'always' also colors output to pipes and files, and overrides NO_COLOR and \
+                   TERM=dumb.",
+        examples: &["{bin} --color always | less -R"],
+        related:  &["ascii-only"],
+    }),
+    ("lang", Explanation {
+        details:  "Only the messages for the user are translated, log and trace output stays in \
+                   English. By default the language is taken from LANG.",
+        examples: &["{bin} --lang de"],
+        related:  &["error-format"],
+    }),
+    // Startup checks
+    ("allow-root", Explanation {
+        details:  "Applications can also declare that they expect to run as root.",
+        examples: &["sudo {bin} --allow-root"],
+        related:  &["skip-preflight"],
+    }),
+    ("raise-nofile-limit", Explanation {
+        details:  "A warning is logged if the limit is still below what the application needs \
+                   afterwards.",
+        examples: &["{bin} --raise-nofile-limit false"],
+        related:  &["skip-preflight"],
+    }),
+    ("skip-preflight", Explanation {
+        details:  "'locale' warns about a locale without UTF-8, 'tz' about an unknown TZ, \
+                   'nofile' about a low open file limit and 'env' about variables that look like \
+                   a setting but do not match its name.",
+        examples: &["{bin} --skip-preflight tz,env"],
+        related:  &["raise-nofile-limit", "check-config"],
+    }),
+    ("check-config", Explanation {
+        details:  "Every problem is reported at once: filters, log files, URLs, TLS files and \
+                   combinations that do not work together. Exits with a failure if any is an \
+                   error.",
+        examples: &["{bin} --check-config --error-format json"],
+        related:  &["error-format", "skip-preflight"],
+    }),
+    ("error-format", Explanation {
+        details:  "'json' writes one object per line with the severity, field, value, problem and \
+                   suggestion.",
+        examples: &["{bin} --check-config --error-format json"],
+        related:  &["check-config"],
+    }),
+    ("deny-deprecated", Explanation {
+        details:  "Renamed flags keep working under their old name with a warning. This turns the \
+                   warning into an error, to catch them in CI.",
+        examples: &["{bin} --deny-deprecated"],
+        related:  &["dump-cli-spec"],
+    }),
+    // Introspection
+    ("dump-cli-spec", Explanation {
+        details:  "Lists every flag with its environment variable, type, default, help text, \
+                   feature and renamed aliases, versioned by a schema version. Works when \
+                   required arguments are missing.",
+        examples: &["{bin} --dump-cli-spec | jq '.args[].long'"],
+        related:  &["explain"],
+    }),
+    ("explain", Explanation {
+        details:  "The flag can be named with or without dashes, by its environment variable or \
+                   by its configuration key. Unknown names get the closest flags as suggestions.",
+        examples: &[
+            "{bin} --explain log-filter",
+            "{bin} --explain LOG_FORMAT --output json",
+            "{bin} --explain log-filter --output json | jq '.examples'",
+        ],
+        related:  &["dump-cli-spec"],
+    }),
+    ("version", Explanation {
+        details:  "With --json prints the name, version, repository, commit, target and the cargo \
+                   features of cli-batteries compiled into the binary as a JSON object instead of \
+                   the text version. Works when required arguments are missing.",
+        examples: &["{bin} --version", "{bin} --version --json | jq '.features'"],
+        related:  &["dump-cli-spec"],
+    }),
+    ("list-checks", Explanation {
+        details:  "The checks the application declared, with their description and whether they \
+                   are enabled.",
+        examples: &["{bin} --list-checks"],
+        related:  &["enable-check"],
+    }),
+    ("enable-check", Explanation {
+        details:  "A disabled check costs one atomic load, an enabled one runs its invariant and \
+                   logs an ERROR when it does not hold. Also a comma separated list in \
+                   ENABLE_CHECKS.",
+        examples: &["{bin} --enable-check ledger-balance"],
+        related:  &["list-checks"],
+    }),
+    ("emit-symbol-info", Explanation {
+        details:  "Holds the executable path, its build-id and the load addresses of the loaded \
+                   modules, to symbolize crash reports and profiles offline. Rewritten on the \
+                   heartbeat when shared objects were loaded.",
+        examples: &["{bin} --emit-symbol-info /var/run/app/symbols.json"],
+        related:  &["support-bundle"],
+    }),
+    ("support-bundle", Explanation {
+        details:  "The program starts up as usual, writes the bundle instead of running the app \
+                   and exits. Secrets in flags are masked.",
+        examples: &["{bin} --support-bundle bug-1234.tar.gz"],
+        related:  &[
+            "support-bundle-include",
+            "support-bundle-exclude",
+            "support-bundle-max-size",
+        ],
+    }),
+    ("support-bundle-include", Explanation {
+        details:  "Comma separated, by default all parts.",
+        examples: &["{bin} --support-bundle bug.tar.gz --support-bundle-include config,version"],
+        related:  &["support-bundle", "support-bundle-exclude"],
+    }),
+    ("support-bundle-exclude", Explanation {
+        details:  "Applied after --support-bundle-include.",
+        examples: &["{bin} --support-bundle bug.tar.gz --support-bundle-exclude environment"],
+        related:  &["support-bundle", "support-bundle-include"],
+    }),
+    ("support-bundle-max-size", Explanation {
+        details:  "Counted before compression. Files are added part by part until the size is \
+                   reached, the manifest of the bundle lists the files left out.",
+        examples: &["{bin} --support-bundle bug.tar.gz --support-bundle-max-size 20MB"],
+        related:  &["support-bundle"],
+    }),
+    ("print-config", Explanation {
+        details:  "Prints a JSON object with the value of every flag and its source: \
+                   command-line, environment, built-in for the log defaults built into the \
+                   binary, or default. Secrets are masked. The support bundle holds the same \
+                   object as config.json.",
+        examples: &["{bin} --print-config | jq '.log_filter'"],
+        related:  &["generate-deployment", "print-log-filter", "support-bundle"],
+    }),
+    ("generate-deployment", Explanation {
+        details:  "The settings that are not defaults, in the form the target takes them: \
+                   Environment= and ExecStart= for systemd, ENV and CMD for docker, an env: block \
+                   for k8s-env. Secrets are masked.",
+        examples: &["{bin} --log-format json --generate-deployment systemd"],
+        related:  &["print-config", "support-bundle", "env-snapshot"],
+    }),
+    ("env-snapshot", Explanation {
+        details:  "The snapshot holds environment variables, the value and source of every flag, \
+                   the version and the resource limits. Differences to the previous run are \
+                   logged at INFO. Secrets are stored as fingerprints.",
+        examples: &["{bin} --env-snapshot /var/lib/app/env.json"],
+        related:  &["generate-deployment"],
+    }),
+    // Runtime monitoring
+    ("fd-report", Explanation {
+        details:  "Snapshots the open file descriptors at startup and shutdown and logs the \
+                   difference by kind.",
+        examples: &["{bin} --fd-report"],
+        related:  &["fd-report-interval", "raise-nofile-limit"],
+    }),
+    ("fd-report-interval", Explanation {
+        details:  "A duration like '10m'.",
+        examples: &["{bin} --fd-report-interval 10m"],
+        related:  &["fd-report"],
+    }),
+    ("latency-report-interval", Explanation {
+        details:  "Percentiles and counts since the previous report, per measured operation. With \
+                   the prometheus feature the durations are exported as a histogram too.",
+        examples: &["{bin} --latency-report-interval 1m"],
+        related:  &["warn-schedule-delay", "warn-lock-wait"],
+    }),
+    ("warn-schedule-delay", Explanation {
+        details:  "A long delay means the runtime is overloaded or a task blocks a worker thread.",
+        examples: &["{bin} --warn-schedule-delay 20ms"],
+        related:  &["latency-report-interval"],
+    }),
+    ("warn-lock-wait", Explanation {
+        details:  "Warnings are rate limited. Waits are also recorded on the current span and in \
+                   the latency report.",
+        examples: &["{bin} --warn-lock-wait 100ms"],
+        related:  &["latency-report-interval"],
+    }),
+    ("memory-soft-limit", Explanation {
+        details:  "A size like '2GiB', or a percentage of the cgroup memory limit or of the \
+                   physical memory. Above it a warning is logged and memory_pressure() is true \
+                   until use falls below 90% of the limit. Linux only.",
+        examples: &["{bin} --memory-soft-limit 80% --memory-hard-limit 95%"],
+        related:  &["memory-hard-limit", "memory-check-interval"],
+    }),
+    ("memory-hard-limit", Explanation {
+        details:  "Acts before the OOM killer, which leaves no diagnostics: an error is logged \
+                   and the program shuts down gracefully with exit status 75. Linux only.",
+        examples: &["{bin} --memory-hard-limit 3GiB"],
+        related:  &["memory-soft-limit", "memory-check-interval"],
+    }),
+    ("memory-check-interval", Explanation {
+        details:  "A duration like '1s'. Shorter catches spikes sooner at a small cost.",
+        examples: &["{bin} --memory-soft-limit 2GiB --memory-check-interval 250ms"],
+        related:  &["memory-soft-limit", "memory-hard-limit"],
+    }),
+    ("dns-timeout", Explanation {
+        details:  "Applies to lookups through net::resolve, which fail after it.",
+        examples: &["{bin} --dns-timeout 2s"],
+        related:  &["dns-slow", "dns-cache-ttl"],
+    }),
+    ("dns-slow", Explanation {
+        details:  "Lookups that miss the cache get a dns.resolve span, slow ones a warning.",
+        examples: &["{bin} --dns-slow 50ms"],
+        related:  &["dns-timeout"],
+    }),
+    ("dns-cache-ttl", Explanation {
+        details:  "Answers are cached for this long regardless of the TTL of the record.",
+        examples: &["{bin} --dns-cache-ttl 5m"],
+        related:  &["dns-negative-ttl"],
+    }),
+    ("dns-negative-ttl", Explanation {
+        details:  "Keeps a failing name from being looked up on every call.",
+        examples: &["{bin} --dns-negative-ttl 1s"],
+        related:  &["dns-cache-ttl"],
+    }),
+    // Batch jobs
+    ("ignore-checkpoint", Explanation {
+        details:  "The checkpoints are still saved, so the next run resumes from this one.",
+        examples: &["{bin} --ignore-checkpoint"],
+        related:  &["checkpoint-interval"],
+    }),
+    ("checkpoint-interval", Explanation {
+        details:  "A killed process loses at most one interval of work.",
+        examples: &["{bin} --checkpoint-interval 30s"],
+        related:  &["ignore-checkpoint"],
+    }),
+    // Optional features
+    ("daemonize", Explanation {
+        details:  "Detaches with a double fork before any thread starts. Stdin is /dev/null, the \
+                   working directory and umask are kept. Unix only.",
+        examples: &["{bin} --daemonize --pid-file /run/app.pid"],
+        related:  &["pid-file", "session-log"],
+    }),
+    ("pid-file", Explanation {
+        details:  "The lock is held for the life of the process, also without --daemonize.",
+        examples: &["{bin} --daemonize --pid-file /run/app.pid"],
+        related:  &["daemonize"],
+    }),
+    ("random-seed", Explanation {
+        details:  "The seed used is logged at startup, pass it back to repeat a run.",
+        examples: &["{bin} --random-seed 42"],
+        related:  &["threads"],
+    }),
+    ("threads", Explanation {
+        details:  "The size of the global rayon pool, with workers named {crate}-rayon-{n}.",
+        examples: &["{bin} --threads 4"],
+        related:  &["random-seed"],
+    }),
+    ("prometheus", Explanation {
+        details:  "Also serves /healthz, /readyz, /recent-errors, /error-reports and /checks. The \
+                   path has to be /metrics and the host an IP address. With TLS files the scheme \
+                   is https.",
+        examples: &["{bin} --prometheus http://0.0.0.0:9998/metrics"],
+        related:  &["metrics-tls-cert", "metrics-tls-key", "recent-errors-size"],
+    }),
+    ("metrics-tls-cert", Explanation {
+        details:  "Needs --metrics-tls-key as well. Reloaded on SIGHUP.",
+        examples: &[
+            "{bin} --prometheus https://0.0.0.0:9998/metrics --metrics-tls-cert cert.pem \
+             --metrics-tls-key key.pem",
+        ],
+        related:  &["metrics-tls-key", "prometheus"],
+    }),
+    ("metrics-tls-key", Explanation {
+        details:  "Needs --metrics-tls-cert as well. Reloaded on SIGHUP.",
+        examples: &[
+            "{bin} --prometheus https://0.0.0.0:9998/metrics --metrics-tls-cert cert.pem \
+             --metrics-tls-key key.pem",
+        ],
+        related:  &["metrics-tls-cert", "prometheus"],
+    }),
+    ("watch", Explanation {
+        details:  "The process and its log outputs stay up between runs, and the app options are \
+                   parsed again for every run. Changes are debounced.",
+        examples: &["{bin} --watch src --watch config.toml"],
+        related:  &["log-format"],
+    }),
+];
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{command, trace::test::mock_version};
+    use clap::Args;
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+    #[group(skip)]
+    struct App {
+        /// Input file
+        #[clap(long, env, default_value = "input.txt")]
+        input: String,
+    }
+
+    fn args(args: &[&str]) -> Option<Request> {
+        request(args.iter().map(OsString::from)).map(Result::unwrap)
+    }
+
+    #[test]
+    fn test_request() {
+        assert_eq!(args(&[]), None);
+        assert_eq!(
+            args(&["--explain", "log-filter"]),
+            Some(Request {
+                flag:   "log-filter".to_owned(),
+                format: Format::Text,
+            })
+        );
+        assert_eq!(
+            args(&["--output=json", "--explain=LOG_FILTER"]),
+            Some(Request {
+                flag:   "LOG_FILTER".to_owned(),
+                format: Format::Json,
+            })
+        );
+        let invalid = ["--explain", "log-filter", "--output", "xml"];
+        assert!(matches!(
+            request(invalid.iter().map(OsString::from)),
+            Some(Err(_))
+        ));
+        // An `--output` of the app without `--explain`
+        assert_eq!(args(&["--output", "out.txt"]), None);
+        assert_eq!(args(&["--", "--explain", "log-filter"]), None);
+        assert_eq!(args(&["--explain"]), None);
+    }
+
+    #[test]
+    fn test_find() {
+        let command = command::<App>(&mock_version());
+        let long = |name| find(&command, name).and_then(Arg::get_long);
+        assert_eq!(long("log-filter"), Some("log-filter"));
+        assert_eq!(long("--log-filter"), Some("log-filter"));
+        assert_eq!(long("LOG_FILTER"), Some("log-filter"));
+        assert_eq!(long("log_filter"), Some("log-filter"));
+        assert_eq!(long("-v"), Some("verbose"));
+        assert_eq!(long("input"), Some("input"));
+        assert_eq!(long("log-filtr"), None);
+    }
+
+    /// `--json` is explained with the `--version` clap adds.
+    #[test]
+    fn test_version() {
+        let mut command = command::<App>(&mock_version());
+        command.build();
+        let version = find(&command, "version").unwrap();
+        let page = Page::new(&command, version, lookup(&[], version));
+        assert_eq!(page.short, Some('V'));
+        assert!(page
+            .examples
+            .contains(&"test-app --version --json | jq '.features'".to_owned()));
+        assert_eq!(page.config_key, None);
+        assert!(find(&command, "json").is_none());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let command = command::<App>(&mock_version());
+        let app = [("input", Explanation {
+            details:  "Read line by line.",
+            examples: &["{bin} --input data.txt"],
+            related:  &["log-filter", "not-a-flag"],
+        })];
+        let input = find(&command, "input").unwrap();
+        let explanation = lookup(&app, input).unwrap();
+        assert_eq!(explanation.details, "Read line by line.");
+        let page = Page::new(&command, input, Some(explanation));
+        assert_eq!(page.examples, ["test-app --input data.txt"]);
+        assert_eq!(page.related, ["log-filter"]);
+        assert_eq!(page.env.as_deref(), Some("INPUT"));
+        assert_eq!(page.default, ["input.txt"]);
+        assert_eq!(
+            page.text(),
+            [
+                "--input <INPUT>\n",
+                "\n",
+                "  Input file\n",
+                "\n",
+                "  Read line by line.\n",
+                "\n",
+                "Examples:\n",
+                "  test-app --input data.txt\n",
+                "\n",
+                "Related:\n",
+                "  --log-filter\n",
+                "\n",
+                "Settings:\n",
+                "  Environment: INPUT\n",
+                "  Config key:  input\n",
+                "  Default:     input.txt\n",
+            ]
+            .concat()
+        );
+
+        let filter = find(&command, "log-filter").unwrap();
+        assert!(lookup(&[], filter).is_some());
+        assert!(lookup(&app, filter).is_some());
+        let json = serde_json::to_value(Page::new(&command, filter, lookup(&[], filter))).unwrap();
+        assert_eq!(json["flag"], "log-filter");
+        assert_eq!(json["env"], "LOG_FILTER");
+        assert!(json["details"].is_string(), "{json}");
+        assert_eq!(json["default"], json!([]));
+        let text = Page::new(&command, filter, lookup(&[], filter)).text();
+        for line in text.lines() {
+            assert!(
+                line.chars().count() <= WIDTH && !line.ends_with(' '),
+                "{line:?}"
+            );
+        }
+
+        // Switches take no values
+        let utc = find(&command, "log-utc").unwrap();
+        let page = Page::new(&command, utc, lookup(&[], utc));
+        assert_eq!((page.value.len(), page.possible_values.len()), (0, 0));
+        let format = find(&command, "log-timestamp").unwrap();
+        assert!(Page::new(&command, format, None)
+            .possible_values
+            .contains(&"unix".to_owned()));
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let command = command::<App>(&mock_version());
+        assert_eq!(suggestions(&command, "log-filtr")[0], "log-filter");
+        assert_eq!(suggestions(&command, "--LOG_FORMT")[0], "log-format");
+        assert!(suggestions(&command, "sampler-of-everything").is_empty());
+        assert!(suggestions(&command, "span-cardinality")
+            .iter()
+            .all(|long| long.starts_with("span-cardinality") || long.contains("cardinality")));
+        let error = print(&command, &[], &Request {
+            flag:   "log-filtr".to_owned(),
+            format: Format::Text,
+        })
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("did you mean `--log-filter`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("log-filtr", "log-filter"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    /// Every flag of this crate in this build has an explanation, and the
+    /// related flags are flags of this crate.
+    #[test]
+    fn test_builtin_complete() {
+        let command = command::<App>(&mock_version());
+        let app = App::augment_args(Command::new("app"));
+        let missing = command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+            .filter(|arg| app.get_arguments().all(|app| app.get_id() != arg.get_id()))
+            .filter(|arg| lookup(&[], arg).is_none())
+            .map(|arg| arg.get_id().to_string())
+            .collect::<Vec<_>>();
+        assert!(missing.is_empty(), "No explanation for {missing:?}");
+
+        // Also of the flags of features that are not compiled in
+        let known = |long: &str| BUILTIN.iter().filter(|(flag, _)| *flag == long).count();
+        for (flag, explanation) in BUILTIN {
+            assert_eq!(known(flag), 1, "{flag} is explained more than once");
+            assert!(!explanation.details.trim().is_empty(), "{flag}");
+            for related in explanation.related {
+                assert_ne!(related, flag, "{flag} is related to itself");
+                assert_eq!(known(related), 1, "{flag}: unknown related flag {related}");
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! File descriptor leak reporting.
+//!
+//! With `--fd-report` the open file descriptors are snapshotted at startup and
+//! at shutdown, and the difference is logged grouped by kind. On Linux this
+//! uses `/proc/self/fd`, other Unixes fall back to `/dev/fd` and `fstat`.
+//! Failing to take a snapshot, e.g. due to permissions, is logged and
+//! otherwise ignored.
+use crate::time::interval;
+use clap::Parser;
+#[cfg(feature = "support-bundle")]
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::Path,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Maximum number of paths or peers logged per kind.
+const SAMPLE: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Log file descriptors opened but not closed between startup and
+    /// shutdown.
+    #[clap(long, env)]
+    fd_report: bool,
+
+    /// Also log the growth in file descriptors periodically, e.g. `10m`.
+    /// Implies `--fd-report`.
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    fd_report_interval: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    File,
+    Socket,
+    Pipe,
+    AnonInode,
+    Other,
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::File => "file",
+            Self::Socket => "socket",
+            Self::Pipe => "pipe",
+            Self::AnonInode => "anon_inode",
+            Self::Other => "other",
+        })
+    }
+}
+
+/// Open file descriptors and what they refer to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot(BTreeMap<u32, (Kind, String)>);
+
+/// File descriptors opened and closed between two snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub opened: Vec<(Kind, String)>,
+    pub closed: Vec<(Kind, String)>,
+}
+
+/// Running report, see [`Options::start`].
+pub struct Monitor {
+    baseline: Snapshot,
+    periodic: Option<JoinHandle<()>>,
+}
+
+impl Options {
+    /// Take the startup snapshot and start periodic reporting, if enabled.
+    pub fn start(self) -> Option<Monitor> {
+        if !self.fd_report && self.fd_report_interval.is_none() {
+            return None;
+        }
+        let baseline = Snapshot::take()?;
+        info!(open = baseline.0.len(), "File descriptor report enabled");
+        let periodic = self.fd_report_interval.map(|period| {
+            let baseline = baseline.clone();
+            tokio::spawn(async move {
+                let mut interval = interval(period);
+                while interval.tick().await.is_some() {
+                    if let Some(current) = Snapshot::take() {
+                        Delta::between(&baseline, &current).log("File descriptors since startup");
+                    }
+                }
+            })
+        });
+        Some(Monitor { baseline, periodic })
+    }
+}
+
+impl Monitor {
+    /// Stop periodic reporting and log the delta since startup.
+    pub async fn finish(self) {
+        if let Some(periodic) = self.periodic {
+            periodic.abort();
+            let _ = periodic.await;
+        }
+        if let Some(current) = Snapshot::take() {
+            Delta::between(&self.baseline, &current).log("File descriptors at shutdown");
+        }
+    }
+}
+
+impl Snapshot {
+    /// Snapshot the current process. Returns `None` (and logs why) if this is
+    /// not possible.
+    pub fn take() -> Option<Self> {
+        let result = if Path::new("/proc/self/fd").is_dir() {
+            Self::read_proc(Path::new("/proc/self/fd"))
+        } else {
+            Self::read_dev_fd()
+        };
+        result
+            .map_err(|err| warn!(%err, "Could not list open file descriptors"))
+            .ok()
+    }
+
+    /// The descriptors as a JSON array.
+    #[cfg(feature = "support-bundle")]
+    pub fn to_json(&self) -> Value {
+        self.0
+            .iter()
+            .map(|(fd, (kind, target))| {
+                json!({
+                    "fd": fd,
+                    "kind": kind.to_string(),
+                    "target": target,
+                })
+            })
+            .collect()
+    }
+
+    /// Read a Linux style `/proc/<pid>/fd` directory of symlinks.
+    fn read_proc(dir: &Path) -> io::Result<Self> {
+        // Listing the directory opens a descriptor of its own, leave it out.
+        let own = dir.canonicalize()?;
+        let mut fds = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Some(fd) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+                continue;
+            };
+            // The descriptor may be closed in the meantime, or unreadable.
+            let Ok(target) = fs::read_link(entry.path()) else {
+                continue;
+            };
+            if target == own {
+                continue;
+            }
+            let target = target.to_string_lossy().into_owned();
+            fds.insert(fd, (classify(&target), target));
+        }
+        Ok(Self(fds))
+    }
+
+    /// Best effort fallback for Unixes without `/proc`.
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    fn read_dev_fd() -> io::Result<Self> {
+        let mut fds = BTreeMap::new();
+        for entry in fs::read_dir("/dev/fd")? {
+            let Some(fd) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let Ok(raw) = libc::c_int::try_from(fd) else {
+                continue;
+            };
+            // SAFETY: `fstat` only writes to the provided struct.
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            if unsafe { libc::fstat(raw, std::ptr::addr_of_mut!(stat)) } != 0 {
+                // Closed in the meantime, this includes the directory handle.
+                continue;
+            }
+            let kind = match stat.st_mode & libc::S_IFMT {
+                libc::S_IFREG | libc::S_IFDIR => Kind::File,
+                libc::S_IFSOCK => Kind::Socket,
+                libc::S_IFIFO => Kind::Pipe,
+                _ => Kind::Other,
+            };
+            fds.insert(fd, (kind, format!("fd {fd}")));
+        }
+        Ok(Self(fds))
+    }
+
+    #[cfg(not(unix))]
+    fn read_dev_fd() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file descriptor listing is not supported on this platform",
+        ))
+    }
+}
+
+impl Delta {
+    pub fn between(before: &Snapshot, after: &Snapshot) -> Self {
+        let changed = |a: &Snapshot, b: &Snapshot| {
+            a.0.iter()
+                .filter(|(fd, target)| b.0.get(fd) != Some(target))
+                .map(|(_, target)| target.clone())
+                .collect()
+        };
+        Self {
+            opened: changed(after, before),
+            closed: changed(before, after),
+        }
+    }
+
+    /// Number of opened descriptors per kind, and a capped sample of their
+    /// targets.
+    fn opened_by_kind(&self) -> BTreeMap<Kind, (usize, Vec<&str>)> {
+        let mut groups = BTreeMap::<_, (usize, Vec<_>)>::new();
+        for (kind, target) in &self.opened {
+            let (count, sample) = groups.entry(*kind).or_default();
+            *count += 1;
+            if sample.len() < SAMPLE {
+                sample.push(target.as_str());
+            }
+        }
+        groups
+    }
+
+    fn log(&self, message: &str) {
+        if self.opened.is_empty() {
+            debug!(closed = self.closed.len(), "{message}: none leaked");
+            return;
+        }
+        warn!(
+            opened = self.opened.len(),
+            closed = self.closed.len(),
+            "{message}: {} more open",
+            self.opened.len()
+        );
+        for (kind, (count, sample)) in self.opened_by_kind() {
+            warn!(%kind, count, ?sample, "{message}: opened {kind}");
+        }
+    }
+}
+
+fn classify(target: &str) -> Kind {
+    if target.starts_with("socket:") {
+        Kind::Socket
+    } else if target.starts_with("pipe:") {
+        Kind::Pipe
+    } else if target.starts_with("anon_inode:") {
+        Kind::AnonInode
+    } else if target.starts_with('/') {
+        Kind::File
+    } else {
+        Kind::Other
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{fs::File, mem::forget};
+    use tracing_test::traced_test;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("/etc/hosts"), Kind::File);
+        assert_eq!(classify("socket:[12345]"), Kind::Socket);
+        assert_eq!(classify("pipe:[678]"), Kind::Pipe);
+        assert_eq!(classify("anon_inode:[eventpoll]"), Kind::AnonInode);
+        assert_eq!(classify("/memfd:x (deleted)"), Kind::File);
+        assert_eq!(classify("net:[4026531840]"), Kind::Other);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[traced_test]
+    fn test_leaked_file() {
+        let before = Snapshot::take().unwrap();
+        let leaked = File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        let after = Snapshot::take().unwrap();
+        // Leak deliberately, so nothing closes it behind our back.
+        forget(leaked);
+
+        let delta = Delta::between(&before, &after);
+        assert!(delta.opened.iter().any(|(kind, _)| *kind == Kind::File));
+        #[cfg(target_os = "linux")]
+        assert!(delta
+            .opened
+            .iter()
+            .any(|(_, target)| target.ends_with("/Cargo.toml")));
+
+        delta.log("File descriptors");
+        assert!(logs_contain("File descriptors: opened file"));
+    }
+
+    #[test]
+    fn test_sample_capped() {
+        let delta = Delta {
+            opened: (0..20)
+                .map(|i| (Kind::Socket, format!("socket:[{i}]")))
+                .chain([(Kind::Pipe, "pipe:[1]".to_owned())])
+                .collect(),
+            closed: vec![],
+        };
+        let groups = delta.opened_by_kind();
+        assert_eq!(groups[&Kind::Socket].0, 20);
+        assert_eq!(groups[&Kind::Socket].1.len(), SAMPLE);
+        assert_eq!(groups[&Kind::Pipe], (1, vec!["pipe:[1]"]));
+    }
+
+    #[test]
+    fn test_unreadable() {
+        assert!(Snapshot::read_proc(Path::new("/does/not/exist")).is_err());
+    }
+}
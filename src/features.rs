@@ -0,0 +1,58 @@
+/// Cargo features this crate was compiled with.
+static FEATURES: &[&str] = &[
+    #[cfg(feature = "support-bundle")]
+    "support-bundle",
+    #[cfg(feature = "log-compress")]
+    "log-compress",
+    #[cfg(feature = "log-async")]
+    "log-async",
+    #[cfg(feature = "log-defaults")]
+    "log-defaults",
+    #[cfg(feature = "signals")]
+    "signals",
+    #[cfg(feature = "mock-shutdown")]
+    "mock-shutdown",
+    #[cfg(feature = "metered-allocator")]
+    "metered-allocator",
+    #[cfg(feature = "tokio-console")]
+    "tokio-console",
+    #[cfg(feature = "mimalloc")]
+    "mimalloc",
+    #[cfg(feature = "rand")]
+    "rand",
+    #[cfg(feature = "rayon")]
+    "rayon",
+    #[cfg(feature = "prometheus")]
+    "prometheus",
+    #[cfg(feature = "otlp")]
+    "otlp",
+    #[cfg(feature = "tls")]
+    "tls",
+    #[cfg(feature = "daemonize")]
+    "daemonize",
+    #[cfg(feature = "binary-log")]
+    "binary-log",
+    #[cfg(feature = "journald")]
+    "journald",
+    #[cfg(feature = "shmem-logs")]
+    "shmem-logs",
+    #[cfg(feature = "webhook")]
+    "webhook",
+    #[cfg(feature = "watch")]
+    "watch",
+    #[cfg(feature = "tonic")]
+    "tonic",
+    #[cfg(feature = "clipboard")]
+    "clipboard",
+    #[cfg(feature = "minimal")]
+    "minimal",
+];
+
+/// The set of `cli-batteries` cargo features compiled into this binary.
+///
+/// Features implied by others (for example `prometheus` through
+/// `metered-allocator`) are listed individually.
+#[must_use]
+pub const fn features() -> &'static [&'static str] {
+    FEATURES
+}
@@ -0,0 +1,68 @@
+//! Liveness and readiness of the program.
+//!
+//! The program is live from the start. It becomes ready when the app calls
+//! [`ready`] and stops being ready once shutdown begins, so load balancers
+//! stop sending new work while it drains. With the `prometheus` feature the
+//! state is served on `/healthz` and `/readyz` next to `/metrics`.
+#![cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+use crate::{shutdown::is_process_shutting_down, Version};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+static VERSION: OnceCell<&'static str> = OnceCell::new();
+static READY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Readiness {
+    Starting,
+    Ready,
+    ShuttingDown,
+}
+
+impl Display for Readiness {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Starting => "starting",
+            Self::Ready => "ready",
+            Self::ShuttingDown => "shutting down",
+        })
+    }
+}
+
+/// Start the uptime clock.
+pub fn init(version: &Version) {
+    Lazy::force(&START);
+    let _ = VERSION.set(version.pkg_version);
+}
+
+/// Mark the program as ready to serve, see the [module docs](self).
+pub fn ready() {
+    if !READY.swap(true, Ordering::Relaxed) {
+        info!("Ready");
+    }
+}
+
+#[must_use]
+pub fn readiness() -> Readiness {
+    if is_process_shutting_down() {
+        Readiness::ShuttingDown
+    } else if READY.load(Ordering::Relaxed) {
+        Readiness::Ready
+    } else {
+        Readiness::Starting
+    }
+}
+
+pub fn uptime() -> Duration {
+    START.elapsed()
+}
+
+pub fn version() -> &'static str {
+    VERSION.get().copied().unwrap_or_default()
+}
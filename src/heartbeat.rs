@@ -1,25 +1,22 @@
-use crate::shutdown::await_shutdown;
+use crate::{latency, loss, shutdown, symbols, sync, time::Interval, trace};
 use std::time::{Duration, Instant};
-use tokio::time::{interval, MissedTickBehavior};
 use tracing::info;
 
 pub async fn heartbeat() {
     let start = Instant::now();
 
-    let mut interval = interval(Duration::from_secs(5 * 60));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-    interval.reset(); // Skip immediate first tick
-
-    loop {
-        tokio::select! {
-            _ = await_shutdown() => break,
-            _ = interval.tick() => {},
-        };
-
+    // Keeps going between the runs of `--watch`
+    let mut interval = Interval::new(Duration::from_secs(5 * 60), shutdown::process_receiver());
+    while interval.tick().await.is_some() {
         // Measure uptime
         let uptime = start.elapsed();
 
         info!(?uptime, "Heartbeat");
+        sync::log_depths();
+        latency::on_heartbeat();
+        trace::log_rate_limited();
+        loss::log_report();
+        symbols::on_heartbeat();
 
         // FEATURE: Log Tokio metrics once API is available.
     }
@@ -0,0 +1,124 @@
+//! Examples section of `--help`.
+//!
+//! Shows how to combine the flags of the compiled in features, followed by the
+//! examples the application added with
+//! [`Runner::help_example`](crate::Runner::help_example).
+#![allow(clippy::literal_string_with_formatting_args)] // `{bin}` placeholders
+use std::fmt::Write;
+
+/// An example: a description and the lines to run, with `{bin}` and `{crate}`
+/// replaced by the binary and crate name.
+type Example = (&'static str, &'static [&'static str]);
+
+/// Examples available without optional features.
+const BASE: &[Example] = &[
+    ("Log debug output as JSON", &["{bin} --log-format json -vv"]),
+    ("Log only warnings, except for one module", &[
+        "{bin} --log-filter warn,{crate}::db=debug",
+    ]),
+];
+
+/// Examples by the set of features they require.
+const FEATURES: &[(&[&str], Example)] = &[
+    (
+        &["otlp"],
+        (
+            "Export traces to an OpenTelemetry collector, with JSON logs",
+            &["{bin} --trace-otlp http://collector:4317 --log-format json -vv"],
+        ),
+    ),
+    (
+        &["otlp", "tls"],
+        ("Export traces over TLS with a client certificate", &[
+            "{bin} --trace-otlp https://collector:4317 --otlp-tls-ca ca.pem --otlp-tls-cert \
+             client.pem --otlp-tls-key client.key",
+        ]),
+    ),
+    (
+        &["tokio-console"],
+        (
+            "Inspect async tasks (build with RUSTFLAGS=\"--cfg tokio_unstable\")",
+            &[
+                "{bin} --tokio-console",
+                "tokio-console http://127.0.0.1:6669",
+            ],
+        ),
+    ),
+    (
+        &["prometheus"],
+        ("Serve metrics on all interfaces", &[
+            "{bin} --prometheus http://0.0.0.0:9998/metrics",
+        ]),
+    ),
+    (
+        &["prometheus", "tls"],
+        ("Serve metrics over HTTPS", &[
+            "{bin} --prometheus https://0.0.0.0:9998/metrics --metrics-tls-cert cert.pem \
+             --metrics-tls-key key.pem",
+        ]),
+    ),
+];
+
+/// Render the examples section for a binary compiled with `features`.
+pub fn examples(
+    bin: &str,
+    crate_name: &str,
+    features: &[&str],
+    app: &[(String, String)],
+) -> String {
+    let mut out = String::from("Examples:\n");
+    let builtin = BASE.iter().chain(
+        FEATURES
+            .iter()
+            .filter(|(required, _)| required.iter().all(|f| features.contains(f)))
+            .map(|(_, example)| example),
+    );
+    for (description, lines) in builtin {
+        let _ = writeln!(out, "  {description}:");
+        for line in *lines {
+            let line = line.replace("{bin}", bin).replace("{crate}", crate_name);
+            let _ = writeln!(out, "    {line}");
+        }
+    }
+    for (description, line) in app {
+        let _ = writeln!(out, "  {description}:\n    {line}");
+    }
+    out
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{env, fs, path::Path};
+
+    /// Set `UPDATE_SNAPSHOTS=1` to update the snapshot after an intentional
+    /// change.
+    #[test]
+    fn test_snapshot() {
+        let all = ["otlp", "tls", "tokio-console", "prometheus", "rayon"];
+        let app = [(
+            "Process a file".to_owned(),
+            "myapp --file data.csv".to_owned(),
+        )];
+        let actual = examples("myapp", "myapp", &all, &app);
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/help_examples.txt");
+        if env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::write(&path, &actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            actual, expected,
+            "Help examples changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+        );
+    }
+
+    #[test]
+    fn test_features() {
+        let text = examples("myapp", "myapp", &["prometheus"], &[]);
+        assert!(text.contains("--prometheus http://0.0.0.0:9998/metrics"));
+        assert!(!text.contains("--metrics-tls-cert"));
+        assert!(!text.contains("--trace-otlp"));
+        assert!(text.contains("myapp --log-format json -vv"));
+    }
+}
@@ -0,0 +1,289 @@
+//! Translations of the user facing messages of this crate.
+//!
+//! The application registers a [`Catalog`] per language with [`set_catalog`]
+//! before calling [`run`](crate::run). `--lang` picks the language, like `de`
+//! or `pt-BR`, by default it is taken from `LANG`. A catalog maps the keys of
+//! [`ENGLISH`] to templates, with fluent style placeables like `{ $path }`.
+//! Messages missing from the catalog, and all messages without a catalog for
+//! the language, are in English.
+//!
+//! Log and trace output stays in English, so it can be searched and parsed
+//! the same everywhere. Only the messages written for the user are
+//! translated: error reports on stderr, the pointer to the session log after
+//! an error and the output of the listing flags.
+//!
+//! Like `--color`, the language is read from the command line directly, so it
+//! is known before the arguments are parsed.
+use crate::default_from_clap;
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, env, fmt::Display, sync::RwLock};
+
+const FLAG: &str = "--lang";
+const ENV: &str = "LANG";
+
+/// The keys of the messages, with their English templates.
+pub const ENGLISH: &[(&str, &str)] = &[
+    ("error", "Error: { $error }"),
+    ("warning", "Warning: { $warning }"),
+    (
+        "session-log-path",
+        "The full log of this run is in { $path }",
+    ),
+    ("trace-url", "The trace of this run is at { $url }"),
+    (
+        "trace-url-copied",
+        "The trace of this run is at { $url }, copied to the clipboard",
+    ),
+    (
+        "session-log-disabled",
+        "Warning: not writing a session log: { $error }",
+    ),
+    ("root-required", "This program must be run as root"),
+    (
+        "checks-none",
+        "No debug checks declared, checks are known once they first run.",
+    ),
+];
+
+static CATALOGS: RwLock<Vec<Catalog>> = RwLock::new(Vec::new());
+
+static LANG: OnceCell<Option<String>> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Language of the messages, like 'de' or 'pt-BR', if the application
+    /// has a catalog for it. English otherwise.
+    #[clap(long, env = "LANG")]
+    lang: Option<String>,
+}
+
+default_from_clap!(Options);
+
+/// Message templates for one language.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Catalog {
+    lang:     String,
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    /// An empty catalog for `lang`, like `de` or `pt-BR`.
+    #[must_use]
+    pub fn new(lang: &str) -> Self {
+        Self {
+            lang:     normalize(lang),
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Add the template of the message `key`, one of the keys of [`ENGLISH`].
+    #[must_use]
+    pub fn message(mut self, key: &str, template: &str) -> Self {
+        self.messages.insert(key.to_owned(), template.to_owned());
+        self
+    }
+
+    /// Whether the catalog is for `lang`, or for its language without the
+    /// region.
+    fn matches(&self, lang: &str) -> bool {
+        self.lang == lang
+            || lang
+                .split_once('-')
+                .is_some_and(|(base, _)| self.lang == base)
+    }
+}
+
+/// Register `catalog`, replacing the one for the same language.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn set_catalog(catalog: Catalog) {
+    let mut catalogs = CATALOGS.write().unwrap();
+    catalogs.retain(|existing| existing.lang != catalog.lang);
+    catalogs.push(catalog);
+}
+
+/// The message `key` in the language of this run, with the placeables
+/// replaced by `args`.
+pub(crate) fn text(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let lang = LANG.get_or_init(requested).as_deref();
+    let catalogs = CATALOGS.read().unwrap();
+    render(&catalogs, lang, key, args)
+}
+
+fn render(
+    catalogs: &[Catalog],
+    lang: Option<&str>,
+    key: &str,
+    args: &[(&str, &dyn Display)],
+) -> String {
+    let template = lang
+        .and_then(|lang| {
+            // An exact match wins over the language without the region
+            catalogs
+                .iter()
+                .find(|catalog| catalog.lang == lang)
+                .or_else(|| catalogs.iter().find(|catalog| catalog.matches(lang)))
+        })
+        .and_then(|catalog| catalog.messages.get(key))
+        .map(String::as_str)
+        .or_else(|| {
+            ENGLISH
+                .iter()
+                .find(|(english, _)| *english == key)
+                .map(|(_, template)| *template)
+        })
+        .unwrap_or(key);
+    fill(template, args)
+}
+
+/// Replace the `{ $name }` placeables, unknown ones are kept.
+fn fill(template: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let placeable = &rest[start..];
+        let Some(end) = placeable.find('}') else {
+            text.push_str(placeable);
+            return text;
+        };
+        let name = placeable[1..end]
+            .trim()
+            .strip_prefix('$')
+            .unwrap_or_default();
+        match args.iter().find(|(arg, _)| *arg == name) {
+            Some((_, value)) => text.push_str(&value.to_string()),
+            None => text.push_str(&placeable[..=end]),
+        }
+        rest = &placeable[end + 1..];
+    }
+    text.push_str(rest);
+    text
+}
+
+/// `de_DE.UTF-8` as `de-de`, `C` and `POSIX` as no language.
+fn normalize(lang: &str) -> String {
+    let lang = lang.split(['.', '@']).next().unwrap_or_default();
+    let lang = lang.replace('_', "-").to_lowercase();
+    if lang == "c" || lang == "posix" {
+        String::new()
+    } else {
+        lang
+    }
+}
+
+/// The `--lang` value on the command line, or `LANG` in the environment.
+fn requested() -> Option<String> {
+    lang(env::args().skip(1), env::var(ENV).ok())
+}
+
+/// The language of the `--lang` flag in `args`, or of `env`.
+fn lang(args: impl Iterator<Item = String>, env: Option<String>) -> Option<String> {
+    let mut args = args.take_while(|arg| arg != "--");
+    let mut flag = None;
+    while let Some(arg) = args.next() {
+        if arg == FLAG {
+            flag = args.next();
+        } else if let Some(value) = arg.strip_prefix(FLAG) {
+            if let Some(value) = value.strip_prefix('=') {
+                flag = Some(value.to_owned());
+            }
+        }
+    }
+    let lang = normalize(&flag.or(env)?);
+    (!lang.is_empty()).then_some(lang)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn catalogs() -> Vec<Catalog> {
+        vec![
+            Catalog::new("de")
+                .message("error", "Fehler: { $error }")
+                .message("session-log-path", "Das vollständige Log ist in { $path }"),
+            Catalog::new("pt_BR").message("error", "Erro: { $error }"),
+            Catalog::new("pt").message("error", "Erro (pt): { $error }"),
+        ]
+    }
+
+    #[test]
+    fn test_render() {
+        let catalogs = catalogs();
+        let render = |lang, key, args: &[(&str, &dyn Display)]| render(&catalogs, lang, key, args);
+        let path = "/state/app/logs/1.log";
+        assert_eq!(
+            render(None, "session-log-path", &[("path", &path)]),
+            "The full log of this run is in /state/app/logs/1.log"
+        );
+        assert_eq!(
+            render(Some("de-at"), "session-log-path", &[("path", &path)]),
+            "Das vollständige Log ist in /state/app/logs/1.log"
+        );
+        assert_eq!(
+            render(Some("de"), "error", &[("error", &"boom")]),
+            "Fehler: boom"
+        );
+        assert_eq!(
+            render(Some("pt-br"), "error", &[("error", &"boom")]),
+            "Erro: boom"
+        );
+        assert_eq!(
+            render(Some("pt-pt"), "error", &[("error", &"boom")]),
+            "Erro (pt): boom"
+        );
+        // Missing from the catalog, or no catalog
+        assert_eq!(
+            render(Some("de"), "root-required", &[]),
+            "This program must be run as root"
+        );
+        assert_eq!(
+            render(Some("fr"), "error", &[("error", &"boom")]),
+            "Error: boom"
+        );
+    }
+
+    #[test]
+    fn test_fill() {
+        let count = 3;
+        let args: &[(&str, &dyn Display)] = &[("count", &count), ("path", &"a b")];
+        assert_eq!(fill("{ $count } files in {$path}", args), "3 files in a b");
+        assert_eq!(fill("{ $other } { }", args), "{ $other } { }");
+        assert_eq!(fill("open { $count", args), "open { $count");
+        assert_eq!(fill("no placeables", args), "no placeables");
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("de_DE.UTF-8"), "de-de");
+        assert_eq!(normalize("pt-BR"), "pt-br");
+        assert_eq!(normalize("sr_RS@latin"), "sr-rs");
+        assert_eq!(normalize("C.UTF-8"), "");
+        assert_eq!(normalize("POSIX"), "");
+    }
+
+    #[test]
+    fn test_lang() {
+        let lang = |args: &[&str], env: Option<&str>| {
+            lang(
+                args.iter().map(|arg| (*arg).to_owned()),
+                env.map(str::to_owned),
+            )
+        };
+        assert_eq!(lang(&[], None), None);
+        assert_eq!(lang(&[], Some("de_DE.UTF-8")).as_deref(), Some("de-de"));
+        assert_eq!(lang(&[], Some("C.UTF-8")), None);
+        let flag = Some("fr");
+        assert_eq!(
+            lang(&["--lang", "pt-BR"], Some("de")).as_deref(),
+            Some("pt-br")
+        );
+        assert_eq!(lang(&["-v", "--lang=fr"], None).as_deref(), flag);
+        assert_eq!(
+            lang(&["--", "--lang=fr"], Some("de")).as_deref(),
+            Some("de")
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! Latency histograms for marked operations.
+//!
+//! [`measure`] records how long a future took into a histogram per operation
+//! name. Percentiles and counts since the previous report are logged with the
+//! heartbeat, or every `--latency-report-interval`. With the `prometheus`
+//! feature durations are also observed into the `operation_duration_seconds`
+//! histogram.
+//!
+//! Histograms bucket microseconds logarithmically with 16 linear sub-buckets,
+//! so reported percentiles are bucket upper bounds within about 6% of the
+//! true value. Each histogram is sharded over a few sets of atomic counters
+//! to keep contention between threads low.
+//!
+//! [`spawn_instrumented`] records how long a task waited in the scheduler
+//! before its first poll, under [`SCHEDULE_DELAY`].
+use crate::time::interval;
+use clap::Parser;
+use once_cell::sync::Lazy;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+use tracing::{field::Empty, info, info_span, warn, Instrument, Span};
+
+#[cfg(feature = "prometheus")]
+use prometheus::{register_histogram_vec, Histogram, HistogramVec};
+
+/// Maximum number of distinct operation names.
+pub const MAX_NAMES: usize = 256;
+
+/// Name that operations beyond [`MAX_NAMES`] are recorded under.
+pub const OVERFLOW: &str = "other";
+
+/// Name that the schedule delays of [`spawn_instrumented`] tasks are recorded
+/// under.
+pub const SCHEDULE_DELAY: &str = "task.schedule_delay";
+
+/// Sub-buckets per power of two.
+const SUB_BITS: u32 = 4;
+const SUB: usize = 1 << SUB_BITS;
+
+/// Durations are capped at 2^40 µs (about 12 days).
+const MAX_EXPONENT: u32 = 40;
+const BUCKETS: usize = (MAX_EXPONENT - SUB_BITS + 2) as usize * SUB;
+
+const SHARDS: usize = 4;
+
+static HISTOGRAMS: Lazy<RwLock<HashMap<&'static str, Arc<Entry>>>> = Lazy::new(RwLock::default);
+
+/// Whether reports have their own interval instead of the heartbeat.
+static SEPARATE: AtomicBool = AtomicBool::new(false);
+
+/// Schedule delay in microseconds above which a warning is logged, `0` for
+/// none.
+static WARN_SCHEDULE_DELAY: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "prometheus")]
+static DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "operation_duration_seconds",
+        "Duration of operations marked with `measure`.",
+        &["name"]
+    )
+    .unwrap()
+});
+
+thread_local! {
+    static SHARD: Cell<usize> = Cell::new({
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS
+    });
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Log latency percentiles of measured operations at this interval, e.g.
+    /// `1m`, instead of with the heartbeat.
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    latency_report_interval: Option<Duration>,
+
+    /// Warn when a task started with `spawn_instrumented` waits longer than
+    /// this for its first poll, e.g. `20ms`.
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    warn_schedule_delay: Option<Duration>,
+}
+
+impl Options {
+    /// Start periodic reporting if a separate interval is configured.
+    pub fn init(self) {
+        if let Some(threshold) = self.warn_schedule_delay {
+            let micros = u64::try_from(threshold.as_micros()).unwrap_or(u64::MAX);
+            WARN_SCHEDULE_DELAY.store(micros.max(1), Ordering::Relaxed);
+        }
+        if let Some(period) = self.latency_report_interval {
+            SEPARATE.store(true, Ordering::Relaxed);
+            tokio::spawn(report_every(period));
+        }
+    }
+}
+
+/// Await `future` and record its duration under `name`.
+pub async fn measure<F: Future>(name: &'static str, future: F) -> F::Output {
+    let start = Instant::now();
+    let output = future.await;
+    record(name, start.elapsed());
+    output
+}
+
+/// Spawn `future` on the Tokio runtime in a span for the task `name`.
+///
+/// The time between the spawn and the first poll of the task is recorded as
+/// `task.schedule_delay_ms` on the span and under [`SCHEDULE_DELAY`], and
+/// logged as a warning above `--warn-schedule-delay`.
+///
+/// # Panics
+///
+/// Panics when called outside of a Tokio runtime, like [`tokio::spawn`].
+pub fn spawn_instrumented<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = info_span!(
+        "task",
+        otel.name = name,
+        task.name = name,
+        task.schedule_delay_ms = Empty
+    );
+    let spawned = Instant::now();
+    tokio::spawn(
+        async move {
+            let delay = spawned.elapsed();
+            Span::current().record("task.schedule_delay_ms", delay.as_secs_f64() * 1000.0);
+            record(SCHEDULE_DELAY, delay);
+            let threshold = WARN_SCHEDULE_DELAY.load(Ordering::Relaxed);
+            if threshold != 0 && delay > Duration::from_micros(threshold) {
+                warn!(
+                    task = name,
+                    ?delay,
+                    threshold = ?Duration::from_micros(threshold),
+                    "Task waited long for its first poll"
+                );
+            }
+            future.await
+        }
+        .instrument(span),
+    )
+}
+
+/// Record a duration under `name`.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn record(name: &'static str, duration: Duration) {
+    let entry = HISTOGRAMS.read().unwrap().get(name).cloned();
+    let entry = entry.unwrap_or_else(|| register(name));
+    entry.record(duration);
+}
+
+/// Log and reset the histograms, unless reports have their own interval.
+pub fn on_heartbeat() {
+    if !SEPARATE.load(Ordering::Relaxed) {
+        log_report();
+    }
+}
+
+/// Log percentiles of every operation measured since the previous report.
+pub fn log_report() {
+    for (name, summary) in report() {
+        info!(
+            name,
+            count = summary.count,
+            p50 = ?summary.p50,
+            p90 = ?summary.p90,
+            p99 = ?summary.p99,
+            max = ?summary.max,
+            "Latency"
+        );
+    }
+}
+
+/// Summaries of every operation measured since the previous report, sorted by
+/// name. Resets the histograms.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn report() -> Vec<(&'static str, Summary)> {
+    let mut summaries = HISTOGRAMS
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, entry)| Some((*name, entry.drain()?)))
+        .collect::<Vec<_>>();
+    summaries.sort_unstable_by_key(|(name, _)| *name);
+    summaries
+}
+
+async fn report_every(period: Duration) {
+    let mut interval = interval(period);
+    while interval.tick().await.is_some() {
+        log_report();
+    }
+}
+
+#[allow(clippy::missing_panics_doc)] // Never panics
+fn register(name: &'static str) -> Arc<Entry> {
+    let mut histograms = HISTOGRAMS.write().unwrap();
+    if let Some(entry) = histograms.get(name) {
+        return entry.clone();
+    }
+    let name = if histograms.len() < MAX_NAMES - 1 || name == OVERFLOW {
+        name
+    } else {
+        if !histograms.contains_key(OVERFLOW) {
+            warn!(
+                name,
+                max = MAX_NAMES,
+                "Too many measured operation names, recording further ones as `{OVERFLOW}`"
+            );
+        }
+        OVERFLOW
+    };
+    histograms
+        .entry(name)
+        .or_insert_with(|| Arc::new(Entry::new(name)))
+        .clone()
+}
+
+/// Latency percentiles of an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub count: u64,
+    pub p50:   Duration,
+    pub p90:   Duration,
+    pub p99:   Duration,
+    pub max:   Duration,
+}
+
+struct Entry {
+    shards:    [Shard; SHARDS],
+    #[cfg(feature = "prometheus")]
+    histogram: Histogram,
+}
+
+struct Shard {
+    buckets: Box<[AtomicU64]>,
+    max:     AtomicU64,
+}
+
+impl Entry {
+    fn new(#[allow(unused_variables)] name: &'static str) -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Shard {
+                buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+                max:     AtomicU64::new(0),
+            }),
+            #[cfg(feature = "prometheus")]
+            histogram: DURATION.with_label_values(&[name]),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let shard = &self.shards[SHARD.with(Cell::get)];
+        shard.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        shard.max.fetch_max(micros, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.histogram.observe(duration.as_secs_f64());
+    }
+
+    /// Summarize and reset, or `None` if nothing was recorded.
+    fn drain(&self) -> Option<Summary> {
+        let mut counts = vec![0; BUCKETS];
+        let mut max = 0;
+        for shard in &self.shards {
+            for (count, bucket) in counts.iter_mut().zip(shard.buckets.iter()) {
+                *count += bucket.swap(0, Ordering::Relaxed);
+            }
+            max = max.max(shard.max.swap(0, Ordering::Relaxed));
+        }
+        let count = counts.iter().sum::<u64>();
+        if count == 0 {
+            return None;
+        }
+        let percentile = |p: u64| {
+            // Rank of the percentile, rounded up.
+            let rank = (count * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Duration::from_micros(upper_bound(index).min(max))
+        };
+        Some(Summary {
+            count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: Duration::from_micros(max),
+        })
+    }
+}
+
+/// Bucket index of a value in microseconds.
+fn bucket(micros: u64) -> usize {
+    let micros = micros.min((1 << (MAX_EXPONENT + 1)) - 1);
+    if micros < SUB as u64 {
+        return usize::try_from(micros).unwrap_or_default();
+    }
+    let exponent = micros.ilog2();
+    let shift = exponent - SUB_BITS;
+    let sub = usize::try_from(micros >> shift).unwrap_or_default() - SUB;
+    (shift as usize + 1) * SUB + sub
+}
+
+/// Largest value in microseconds that falls in a bucket.
+const fn upper_bound(index: usize) -> u64 {
+    if index < SUB {
+        return index as u64;
+    }
+    let shift = index / SUB - 1;
+    let sub = (index % SUB + SUB) as u64;
+    ((sub + 1) << shift) - 1
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::time::{advance, sleep};
+    use tracing_test::traced_test;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(15), 15);
+        assert_eq!(bucket(16), 16);
+        assert_eq!(bucket(31), 31);
+        assert_eq!(bucket(32), 32);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        for micros in [0, 1, 15, 16, 17, 100, 1_000, 10_000, 123_456_789, 1 << 40] {
+            let index = bucket(micros);
+            assert!(upper_bound(index) >= micros);
+            assert!(index == 0 || upper_bound(index - 1) < micros);
+            // Within 1/16th
+            assert!(upper_bound(index) - micros <= micros / 16);
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let entry = Entry::new("test-summary");
+        assert_eq!(entry.drain(), None);
+        for _ in 0..90 {
+            entry.record(Duration::from_millis(10));
+        }
+        for _ in 0..10 {
+            entry.record(Duration::from_millis(100));
+        }
+        assert_eq!(
+            entry.drain(),
+            Some(Summary {
+                count: 100,
+                p50:   Duration::from_micros(10_239),
+                p90:   Duration::from_micros(10_239),
+                p99:   Duration::from_millis(100),
+                max:   Duration::from_millis(100),
+            })
+        );
+        // Reset by the report
+        assert_eq!(entry.drain(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_periodic_report() {
+        Options {
+            latency_report_interval: Some(Duration::from_secs(30)),
+            warn_schedule_delay:     None,
+        }
+        .init();
+        for _ in 0..9 {
+            measure("test.fast", sleep(Duration::from_millis(4))).await;
+        }
+        let answer = measure("test.slow", async {
+            sleep(Duration::from_secs(2)).await;
+            42
+        })
+        .await;
+        assert_eq!(answer, 42);
+
+        sleep(Duration::from_secs(30)).await;
+        assert!(logs_contain(
+            "Latency name=\"test.fast\" count=9 p50=4ms p90=4ms p99=4ms max=4ms"
+        ));
+        assert!(logs_contain(
+            "Latency name=\"test.slow\" count=1 p50=2s p90=2s p99=2s max=2s"
+        ));
+
+        // Only operations measured since the previous report are logged.
+        measure("test.slow", sleep(Duration::from_secs(1))).await;
+        sleep(Duration::from_secs(30)).await;
+        assert!(logs_contain(
+            "Latency name=\"test.slow\" count=1 p50=1s p90=1s p99=1s max=1s"
+        ));
+        logs_assert(|lines| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("test.fast"))
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("Expected one report of test.fast, got {n}")),
+            }
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_schedule_delay() {
+        Options {
+            latency_report_interval: None,
+            warn_schedule_delay:     Some(Duration::from_millis(20)),
+        }
+        .init();
+
+        // The test keeps the only worker busy for 50 ms before it yields.
+        let task = spawn_instrumented("test.busy", async {
+            info!("busy task polled");
+            42
+        });
+        advance(Duration::from_millis(50)).await;
+        assert_eq!(task.await.unwrap(), 42);
+        assert!(logs_contain(
+            "task{otel.name=\"test.busy\" task.name=\"test.busy\" task.schedule_delay_ms=50.0}: \
+             cli_batteries::latency::test: busy task polled"
+        ));
+        assert!(logs_contain(
+            "Task waited long for its first poll task=\"test.busy\" delay=50ms threshold=20ms"
+        ));
+
+        // An idle runtime polls right away.
+        spawn_instrumented("test.idle", async { info!("idle task polled") })
+            .await
+            .unwrap();
+        assert!(logs_contain(
+            "task.schedule_delay_ms=0.0}: cli_batteries::latency::test: idle task polled"
+        ));
+        assert!(!logs_contain("task=\"test.idle\""));
+    }
+}
@@ -6,38 +6,123 @@
 
 #![doc = include_str!("../Readme.md")]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+// `minimal` builds leave out the full startup sequence and what only it uses.
+#![cfg_attr(feature = "minimal", allow(dead_code, unused_imports))]
 
 mod allocator;
 mod build;
+mod cgroup;
+pub mod checkpoint;
+pub mod checks;
+mod cli_spec;
+pub mod config_issue;
+mod config_key;
+mod context;
+mod crash;
+mod daemon;
+mod deployment;
+mod deprecated;
+mod env_snapshot;
+mod exit_hint;
+mod explain;
+mod fd_report;
+mod features;
+mod health;
 mod heartbeat;
+mod help;
+pub mod i18n;
+mod latency;
+pub mod logs;
+mod loss;
+mod memory_guard;
 mod metered_allocator;
+mod minimal;
+pub mod net;
+pub mod output;
+mod phase;
+pub mod pipeline;
+mod preflight;
+pub mod prelude;
+mod print_config;
 mod prometheus;
 mod rand;
-mod rayon;
+pub mod rayon;
+pub mod replay;
+pub mod report_limit;
+mod root;
+mod runner;
 mod shutdown;
+mod support_bundle;
+mod symbols;
+pub mod sync;
+pub mod telemetry;
+pub mod time;
+mod tls;
 mod trace;
+pub mod util;
+pub mod verbosity;
 mod version;
+mod watch;
 
 pub use crate::{
     build::build_rs,
+    cgroup::{effective_cpus, memory_limit},
+    config_issue::ConfigIssue,
+    context::ResultExt,
+    daemon::prepare_exec,
+    exit_hint::ExitHint,
+    explain::Explanation,
+    features::features,
+    health::ready,
     heartbeat::heartbeat,
+    latency::{measure, spawn_instrumented},
+    loss::LossCounter,
+    memory_guard::memory_pressure,
+    phase::{phase, Phase},
+    runner::Runner,
     shutdown::{await_shutdown, is_shutting_down, shutdown},
+    trace::{
+        default_verbosity, fields_scope, log_counters, test_subscriber, ErrorReport,
+        FieldsScopeLayer, LogCounters, LogDefaults, ScopeFields, TestSubscriber, VerbosityMap,
+    },
+    util::lazy_field,
     version::Version,
 };
-use clap::{Args, CommandFactory, FromArgMatches, Parser};
+use clap::{Args, Command, CommandFactory, FromArgMatches, Parser};
+pub use cli_batteries_macros::test;
 use eyre::{Error as EyreError, Report, Result as EyreResult, WrapErr};
-use std::{future::Future, ptr::addr_of};
-use tokio::runtime;
-use tracing::{error, info};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, IsTerminal},
+    ptr::addr_of,
+};
+use tokio::{runtime, task::LocalSet};
+use tracing::{info, Instrument};
 
 #[cfg(feature = "mock-shutdown")]
 pub use crate::shutdown::reset_shutdown;
 
+/// Used by the `#[test]` expansion.
+#[doc(hidden)]
+pub use crate::trace::{block_on as __block_on, Outcome as __Outcome};
+
 #[cfg(feature = "metered-allocator")]
 use crate::metered_allocator::MeteredAllocator;
 
+#[cfg(feature = "tonic")]
+pub use crate::trace::{inject_trace, GrpcTraceBody, GrpcTraceLayer, GrpcTraceService};
 #[cfg(feature = "otlp")]
-pub use crate::trace::{trace_from_headers, trace_to_headers};
+pub use crate::trace::{link_to, span_with_links, trace_from_headers, trace_to_headers};
+
+/// The crates this crate is built against, for qualified access without
+/// depending on them directly. See also the [`prelude`].
+pub mod reexports {
+    pub use clap;
+    pub use eyre;
+    pub use tokio;
+    pub use tracing;
+}
 
 /// Implement [`Default`] for a type that implements [`Parser`] and has
 /// default values set for all fields.
@@ -46,8 +131,8 @@ macro_rules! default_from_clap {
     ($ty:ty) => {
         impl ::std::default::Default for $ty {
             fn default() -> Self {
-                use ::clap::Parser;
                 use ::std::ffi::OsString;
+                use $crate::reexports::clap::Parser;
                 <Self as Parser>::parse_from::<Option<OsString>, OsString>(None)
             }
         }
@@ -61,6 +146,78 @@ struct Options<O: Args> {
     #[clap(flatten)]
     tracing: trace::Options,
 
+    #[clap(flatten)]
+    output: output::Options,
+
+    #[clap(flatten)]
+    i18n: i18n::Options,
+
+    #[clap(flatten)]
+    root: root::Options,
+
+    #[clap(flatten)]
+    preflight: preflight::Options,
+
+    #[clap(flatten)]
+    cli_spec: cli_spec::Options,
+
+    #[clap(flatten)]
+    explain: explain::Options,
+
+    #[clap(flatten)]
+    logs: logs::Options,
+
+    #[clap(flatten)]
+    fd_report: fd_report::Options,
+
+    #[clap(flatten)]
+    deprecated: deprecated::Options,
+
+    #[clap(flatten)]
+    latency: latency::Options,
+
+    #[clap(flatten)]
+    sync: sync::Options,
+
+    #[clap(flatten)]
+    memory: memory_guard::Options,
+
+    #[clap(flatten)]
+    net: net::Options,
+
+    #[clap(flatten)]
+    symbols: symbols::Options,
+
+    #[clap(flatten)]
+    checks: checks::Options,
+
+    #[clap(flatten)]
+    checkpoint: checkpoint::Options,
+
+    #[cfg(feature = "support-bundle")]
+    #[clap(flatten)]
+    support_bundle: support_bundle::Options,
+
+    #[clap(flatten)]
+    print_config: print_config::Options,
+
+    #[clap(flatten)]
+    deployment: deployment::Options,
+
+    #[clap(flatten)]
+    env_snapshot: env_snapshot::Options,
+
+    #[clap(flatten)]
+    config: config_issue::Options,
+
+    #[cfg(unix)]
+    #[clap(flatten)]
+    pipeline: pipeline::Options,
+
+    #[cfg(all(unix, feature = "daemonize"))]
+    #[clap(flatten)]
+    daemon: daemon::Options,
+
     #[cfg(feature = "rand")]
     #[clap(flatten)]
     rand: rand::Options,
@@ -73,12 +230,77 @@ struct Options<O: Args> {
     #[clap(flatten)]
     prometheus: prometheus::Options,
 
+    #[cfg(feature = "watch")]
+    #[clap(flatten)]
+    watch: watch::Options,
+
     #[clap(flatten)]
     app: O,
 }
 
+impl<O: Args> Options<O> {
+    /// Every problem with the options, checked together so they can be
+    /// reported at once, see [`config_issue`].
+    fn validate(&self, version: &Version) -> Vec<ConfigIssue> {
+        let mut issues = self.tracing.validate(version);
+        issues.extend(self.memory.validate());
+        #[cfg(all(unix, feature = "daemonize"))]
+        issues.extend(self.daemon.validate());
+        #[cfg(feature = "prometheus")]
+        issues.extend(self.prometheus.validate());
+        issues
+    }
+}
+
+/// The full command line interface of the program.
+fn command<O: Args>(version: &Version) -> Command {
+    let command = Options::<O>::command()
+        .name(version.pkg_name)
+        .version(version.pkg_version)
+        .long_version(version.long_version);
+    deprecated::add_aliases(command, deprecated::FLAGS)
+}
+
+/// Attribute arguments to the application or to the feature providing them.
+fn provider<O: Args>() -> impl Fn(&str) -> cli_spec::Provider {
+    let app = O::augment_args(Command::new("app"))
+        .get_arguments()
+        .map(|arg| arg.get_id().to_string())
+        .collect::<Vec<_>>();
+    #[allow(unused_mut)]
+    let mut features = trace::feature_commands();
+    #[cfg(all(unix, feature = "daemonize"))]
+    features.push(("daemonize", daemon::Options::command()));
+    #[cfg(feature = "rand")]
+    features.push(("rand", rand::Options::command()));
+    #[cfg(feature = "rayon")]
+    features.push(("rayon", rayon::Options::command()));
+    #[cfg(feature = "prometheus")]
+    features.push(("prometheus", prometheus::Options::command()));
+    #[cfg(feature = "watch")]
+    features.push(("watch", watch::Options::command()));
+    #[cfg(feature = "support-bundle")]
+    features.push(("support-bundle", support_bundle::Options::command()));
+    let features = features
+        .iter()
+        .flat_map(|(feature, command)| {
+            command
+                .get_arguments()
+                .map(move |arg| (arg.get_id().to_string(), *feature))
+        })
+        .collect::<HashMap<_, _>>();
+    move |id| {
+        if app.iter().any(|arg| arg == id) {
+            cli_spec::Provider::App
+        } else {
+            cli_spec::Provider::Crate(features.get(id).copied())
+        }
+    }
+}
+
 /// Run the program.
-#[allow(clippy::needless_pass_by_value)]
+///
+/// Use [`Runner`] to customize how the program is run.
 pub fn run<A, O, F, E>(version: Version, app: A)
 where
     A: FnOnce(O) -> F,
@@ -86,41 +308,181 @@ where
     F: Future<Output = Result<(), E>>,
     E: Into<Report> + Send + Sync + 'static,
 {
-    if let Err(report) = run_fallible(&version, app) {
-        error!(?report, "{}", report);
-        error!("Program terminating abnormally");
-        std::process::exit(1);
-    }
+    Runner::new(version).run(app);
 }
 
-fn run_fallible<A, O, F, E>(version: &Version, app: A) -> EyreResult<()>
+#[allow(clippy::too_many_lines)] // Linear startup and shutdown sequence
+fn run_fallible<A, O, F, E>(runner: &Runner, app: A) -> EyreResult<()>
 where
     A: FnOnce(O) -> F,
     O: Args,
     F: Future<Output = Result<(), E>>,
     E: Into<Report> + Send + Sync + 'static,
 {
+    let version = &runner.version;
+
+    // Look up the crash identity while we can still allocate
+    crash::init(version);
+
+    // Start the uptime clock
+    health::init(version);
+
     // Install panic handler
     // TODO: write panics to log, like Err results.
-    color_eyre::config::HookBuilder::default()
+    let mut hooks = color_eyre::config::HookBuilder::default();
+    if !output::capabilities().color_on(io::stderr().is_terminal()) {
+        hooks = hooks.theme(color_eyre::config::Theme::new());
+    }
+    let (panic_hook, eyre_hook) = hooks
         .issue_url(format!("{}/issues/new", version.pkg_repo))
         .add_issue_metadata(
             "version",
             format!("{} {}", version.pkg_name, version.long_version),
         )
-        .install()
+        .into_hooks();
+    eyre_hook.install().inspect_err(|err| {
+        eprintln!("{}", i18n::text("error", &[("error", &err)]));
+    })?;
+    let panic_hook = panic_hook.into_panic_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Reported by the guard of the layer, which disables it
+        if trace::in_guarded_layer() {
+            return;
+        }
+        trace::check_shutdown_panic(info.location(), info.payload());
+        crash::report_panic(info.location(), info.payload(), || panic_hook(info));
+    }));
+
+    // Dump the CLI spec before parsing, so missing required arguments don't stop
+    // it.
+    if cli_spec::requested() {
+        let spec = cli_spec::spec(&command::<O>(version), &provider::<O>(), deprecated::FLAGS);
+        println!("{}", serde_json::to_string_pretty(&spec)?);
+        return Ok(());
+    }
+
+    // Print the version as JSON, also without the required arguments.
+    if crate::version::json_requested() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&crate::version::json(version))?
+        );
+        return Ok(());
+    }
+
+    // Explain a flag, also without the required arguments.
+    if let Some(request) = explain::requested() {
+        return request
+            .and_then(|request| {
+                explain::print(&command::<O>(version), &runner.explanations, &request)
+            })
+            .inspect_err(|err| {
+                eprintln!("{}", i18n::text("error", &[("error", err)]));
+            });
+    }
+
+    // List the declared debug checks, also without the required arguments.
+    if checks::list_requested() {
+        checks::list(&runner.checks);
+        return Ok(());
+    }
+
+    // Print a session log, also without the required arguments.
+    if let Some(path) = logs::requested() {
+        return logs::cat(&path);
+    }
+    #[cfg(feature = "binary-log")]
+    if let Some(path) = logs::decode_requested() {
+        return logs::decode(&path);
+    }
+    #[cfg(feature = "shmem-logs")]
+    if let Some(path) = logs::dump_shmem_requested() {
+        return logs::dump_shmem(&path);
+    }
+
+    // Parse CLI and handle help and version (which will stop the application).
+    let matches = command::<O>(version)
+        .after_help(help::examples(
+            version.pkg_name,
+            version.crate_name,
+            features(),
+            &runner.help_examples,
+        ))
+        .get_matches();
+    let mut options = Options::<O>::from_arg_matches(&matches)?;
+    options.tracing.use_verbosity_map(&runner.verbosity_map);
+
+    // The built-in log defaults of the app are below the command line and
+    // environment
+    options
+        .tracing
+        .use_log_defaults(runner.log_defaults, matches.value_source("log_format"))?;
+    // `RUST_LOG` is below `--log-filter` and `LOG_FILTER`
+    options
+        .tracing
+        .use_rust_log(matches.value_source("log_filter"));
+
+    // Report every problem with the options before starting anything
+    options
+        .config
+        .report(&options.validate(version))
+        .inspect_err(|err| {
+            eprintln!("{}", i18n::text("error", &[("error", err)]));
+        })?;
+    if options.config.check_requested() {
+        return Ok(());
+    }
+
+    if options.tracing.print_log_filter_requested() {
+        return options.tracing.print_log_filter(version);
+    }
+    if options.print_config.requested() {
+        return print_config::print(
+            &command::<O>(version),
+            &matches,
+            &options.tracing.built_in(),
+        );
+    }
+    if let Some(target) = options.deployment.requested() {
+        print!(
+            "{}",
+            deployment::generate(
+                target,
+                version,
+                &command::<O>(version),
+                &matches,
+                &options.tracing.built_in()
+            )
+        );
+        return Ok(());
+    }
+    #[cfg(feature = "binary-log")]
+    if let Some(path) = options.tracing.replay_requested() {
+        return options.tracing.replay(version, path);
+    }
+
+    // Collect a support bundle instead of running the app
+    #[cfg(feature = "support-bundle")]
+    let support_bundle = options.support_bundle.collector(&mut options.tracing);
+
+    // Detach from the terminal before any threads are started
+    #[cfg(all(unix, feature = "daemonize"))]
+    options
+        .daemon
+        .start(version, &mut options.tracing)
         .map_err(|err| {
-            eprintln!("Error: {}", err);
+            let error = format!("{err:#}");
+            eprintln!("{}", i18n::text("error", &[("error", &error)]));
             err
         })?;
 
-    // Parse CLI and handle help and version (which will stop the application).
-    let matches = Options::<O>::command()
-        .name(version.pkg_name)
-        .version(version.pkg_version)
-        .long_version(version.long_version)
-        .get_matches();
-    let options = Options::<O>::from_arg_matches(&matches)?;
+    // Join the trace of a pipeline, before any threads are started
+    #[cfg(unix)]
+    options.pipeline.init(&mut options.tracing).map_err(|err| {
+        let error = format!("{err:#}");
+        eprintln!("{}", i18n::text("error", &[("error", &error)]));
+        err
+    })?;
 
     // Start allocator metering (if enabled)
     allocator::start_metering();
@@ -129,53 +491,166 @@ where
 
     // Launch Tokio runtime
     // TODO: https://docs.rs/tokio/latest/tokio/runtime/struct.Builder.html#method.unhandled_panic
-    runtime::Builder::new_multi_thread()
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(cgroup::effective_cpus().get())
         .enable_all()
         .build()
-        .wrap_err("Error creating Tokio runtime")?
-        .block_on(async {
-            // Start heartbeat
-            let heartbeat = tokio::spawn(heartbeat());
-
-            // Monitor for Ctrl-C
-            #[cfg(feature = "signals")]
-            shutdown::watch_signals();
-
-            // Start log system
-            let load_addr = addr_of!(app) as usize;
-            options.tracing.init(version, load_addr).map_err(|err| {
-                eprintln!("Error: {}", err);
-                err
+        .wrap_err("Error creating Tokio runtime")?;
+    let main = async {
+        // Start heartbeat
+        let heartbeat = tokio::spawn(heartbeat());
+
+        // Monitor for Ctrl-C
+        #[cfg(feature = "signals")]
+        shutdown::watch_signals();
+
+        // Start log system
+        let load_addr = addr_of!(app) as usize;
+        let layers = runner
+            .layers
+            .iter()
+            .map(runner::LayerFactory::make)
+            .collect();
+        options
+            .tracing
+            .init(version, load_addr, layers)
+            .inspect_err(|err| {
+                eprintln!("{}", i18n::text("error", &[("error", err)]));
             })?;
 
-            #[cfg(feature = "rand")]
-            options.rand.init();
+        // Record load addresses for offline symbolization
+        options.symbols.init(version, load_addr);
+
+        // Enable debug checks
+        options.checks.init(&runner.checks);
+
+        // Check the environment
+        options
+            .preflight
+            .check(runner.min_open_files, &command::<O>(version), version);
+
+        // Check privileges
+        options.root.check(runner.root, &root::Process)?;
+
+        // Report renamed flags
+        options
+            .deprecated
+            .check(deprecated::FLAGS, std::env::args_os())?;
+
+        // Write the support bundle and stop
+        #[cfg(feature = "support-bundle")]
+        if let Some(support_bundle) = support_bundle {
+            support_bundle.write(
+                version,
+                &command::<O>(version),
+                &matches,
+                &options.tracing,
+                load_addr,
+            )?;
+            shutdown::shutdown();
+            trace::shutdown()?;
+            heartbeat.await?;
+            return Ok(());
+        }
+
+        // Compare with the environment of the last run
+        options.env_snapshot.update(
+            version,
+            &command::<O>(version),
+            &matches,
+            &options.tracing.built_in(),
+        );
 
-            #[cfg(feature = "rayon")]
-            options.rayon.init()?;
+        // Snapshot open file descriptors
+        let fd_report = options.fd_report.start();
 
-            // Start prometheus
-            #[cfg(feature = "prometheus")]
-            let prometheus = tokio::spawn(prometheus::main(options.prometheus));
+        // Accept log filter changes
+        #[cfg(unix)]
+        let log_control = options.tracing.start_log_control(version)?;
 
-            // Start main
-            app(options.app).await.map_err(E::into)?;
+        // Log the app at debug while errors spike
+        options.tracing.start_auto_debug(version);
 
-            // Initiate shutdown if main returns
-            shutdown::shutdown();
+        // Start latency reports
+        options.latency.init();
+        options.sync.init();
+
+        // Resume or restart batch jobs
+        options.checkpoint.init();
+
+        // Check memory use against the limits
+        options.memory.start();
+
+        #[cfg(feature = "rand")]
+        options.rand.init();
+
+        #[cfg(feature = "rayon")]
+        options.rayon.init(version.crate_name)?;
+
+        #[cfg(feature = "watch")]
+        options.watch.init();
+
+        // Start prometheus, it keeps serving until main has shut down
+        #[cfg(feature = "prometheus")]
+        let (stop_prometheus, stopped) = tokio::sync::oneshot::channel();
+        #[cfg(feature = "prometheus")]
+        let prometheus = tokio::spawn(prometheus::main(options.prometheus, stopped)?);
+
+        // Start main
+        let span = exit_hint::main_span();
+        #[cfg(all(unix, feature = "otlp"))]
+        if let Some(context) = pipeline::current() {
+            trace::set_parent(&span, &context.to_string());
+        }
+        #[cfg(feature = "otlp")]
+        trace::record_trace_url(&span);
+        let result = app(options.app)
+            .instrument(span.clone())
+            .await
+            .map_err(E::into);
+        // Save where batch jobs are, also when main failed
+        checkpoint::save_all().await;
+        exit_hint::record(&span, result.as_ref().err());
+        result?;
+
+        // Fail on errors logged by a successful main
+        options.tracing.check_error_logs()?;
+        memory_guard::check()?;
+
+        // Report file descriptors leaked by main
+        if let Some(fd_report) = fd_report {
+            fd_report.finish().await;
+        }
+
+        // Initiate shutdown if main returns
+        shutdown::shutdown();
 
-            // Wait for prometheus to finish
-            #[cfg(feature = "prometheus")]
+        // Stop prometheus and wait for it to finish
+        #[cfg(feature = "prometheus")]
+        {
+            drop(stop_prometheus);
             prometheus.await??;
+        }
 
-            // Submit remaining traces
-            trace::shutdown()?;
+        // Wait for the log control socket to close
+        #[cfg(unix)]
+        if let Some(log_control) = log_control {
+            log_control.await?;
+        }
 
-            // Join heartbeat thread
-            heartbeat.await?;
+        // Submit remaining traces
+        trace::shutdown()?;
 
-            Result::<(), EyreError>::Ok(())
-        })?;
+        // Join heartbeat thread
+        heartbeat.await?;
+
+        Result::<(), EyreError>::Ok(())
+    };
+    if runner.local {
+        LocalSet::new().block_on(&runtime, main)?;
+    } else {
+        runtime.block_on(main)?;
+    }
 
     // Terminate successfully
     info!("Program terminating normally");
@@ -213,4 +688,63 @@ pub mod test {
         assert!(logs_contain("logged on the warn level"));
         assert!(!logs_contain("logged on the error level"));
     }
+
+    #[test]
+    fn test_validate_all_at_once() {
+        use crate::{command, config_issue::Severity, trace::test::mock_version, Options};
+        use clap::{FromArgMatches, Parser};
+        use std::{env, fs};
+
+        #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+        #[group(skip)]
+        struct App {}
+
+        let dir = env::temp_dir().join(format!("cli-batteries-validate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        let below = |name: &str| file.join(name).display().to_string();
+        // `--version` is added by `command`
+        let parse = |args: &[String]| {
+            let matches = command::<App>(&mock_version())
+                .try_get_matches_from(args)
+                .unwrap();
+            Options::<App>::from_arg_matches(&matches).unwrap()
+        };
+
+        let options = parse(&[
+            "arg0".to_owned(),
+            "--log-filter=app=loud".to_owned(),
+            format!("--log-file={}", below("app.log")),
+            format!("--log-sink=format=json,target=file:{}", below("sink.json")),
+            format!("--trace-flame={}", dir.display()),
+            "--log-rate-limit=0".to_owned(),
+            "--log-redact-fields=password,".to_owned(),
+            format!("--session-log=dir={}", below("logs")),
+            "--session-log-keep=0".to_owned(),
+        ]);
+        let issues = options.validate(&mock_version());
+        let found = issues
+            .iter()
+            .map(|issue| (issue.severity, issue.field.as_str()))
+            .collect::<Vec<_>>();
+        for expected in [
+            (Severity::Error, "log-filter"),
+            (Severity::Error, "log-file"),
+            (Severity::Error, "log-sink"),
+            (Severity::Error, "trace-flame"),
+            (Severity::Warning, "log-rate-limit"),
+            (Severity::Warning, "log-redact-fields"),
+            (Severity::Error, "session-log-keep"),
+            (Severity::Error, "session-log"),
+        ] {
+            assert!(found.contains(&expected), "{expected:?} not in {issues:#?}");
+        }
+
+        let options = parse(&["arg0".to_owned()]);
+        assert_eq!(options.validate(&mock_version()), vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
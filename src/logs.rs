@@ -0,0 +1,615 @@
+//! Reading session logs and JSON log output.
+//!
+//! `--cat-session-log <path>` prints a session log, compressed or not, one
+//! line per event, and exits.
+//!
+//! [`parse_line`] turns a line of the `json` or `otlp` log formats back into a
+//! [`LogRecord`], and [`LogReader`] does so for a stream of lines. The crate's
+//! own tests parse its output with them, so they stay in sync with what is
+//! written.
+//!
+//! With the `binary-log` feature, [`BinaryLogReader`] reads the compact
+//! binary log format of `--binary-log`. `--decode-binary-log <path>` prints
+//! such a file as JSON lines and exits, `--cat-session-log` also accepts one.
+//!
+//! With the experimental `shmem-logs` feature, [`ShmemReader`] reads the
+//! shared memory ring of `--log-shmem`, also from another process.
+//! `--dump-shmem <path>` prints its events as JSON lines and exits.
+use chrono::{SecondsFormat, TimeZone as _, Utc};
+use clap::Parser;
+use eyre::{bail, eyre, Result as EyreResult, WrapErr as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _, Lines};
+
+#[cfg(feature = "binary-log")]
+use crate::trace::is_binary_log;
+#[cfg(any(feature = "binary-log", feature = "log-compress"))]
+use std::io::Read;
+
+#[cfg(feature = "binary-log")]
+pub use crate::trace::{BinaryLog, BinaryLogReader};
+
+#[cfg(feature = "shmem-logs")]
+pub use crate::trace::ShmemReader;
+
+const FLAG: &str = "--cat-session-log";
+
+#[cfg(feature = "binary-log")]
+const DECODE_FLAG: &str = "--decode-binary-log";
+
+#[cfg(feature = "shmem-logs")]
+const DUMP_SHMEM_FLAG: &str = "--dump-shmem";
+
+/// The zstd frame magic number.
+const ZSTD: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Print a session log file and exit.
+    #[clap(long, value_name = "PATH")]
+    cat_session_log: Option<PathBuf>,
+}
+
+/// A JSON log format written by this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// `--log-format json` and session logs.
+    Json,
+    /// `--log-format otlp`, the OpenTelemetry log data model.
+    Otlp,
+}
+
+/// A log event, as parsed by [`parse_line`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// RFC 3339 time in UTC.
+    pub timestamp: String,
+    /// `TRACE` to `ERROR`.
+    pub level:     String,
+    /// Not in the `otlp` format.
+    pub target:    Option<String>,
+    pub message:   String,
+    /// The current span, the `json` format only writes its name.
+    pub span:      Option<String>,
+    /// Event fields other than the message, including `--tag` fields.
+    pub fields:    Map<String, Value>,
+    /// Hex, only in the `otlp` format.
+    pub trace_id:  Option<String>,
+    /// Hex, only in the `otlp` format.
+    pub span_id:   Option<String>,
+}
+
+impl LogRecord {
+    /// The record as a line of the `json` log format.
+    #[must_use]
+    pub fn to_json_line(&self) -> String {
+        let mut fields = Map::new();
+        if !self.message.is_empty() {
+            fields.insert("message".to_owned(), self.message.clone().into());
+        }
+        fields.extend(self.fields.clone());
+        let mut event = Map::new();
+        event.insert("timestamp".to_owned(), self.timestamp.clone().into());
+        event.insert("level".to_owned(), self.level.clone().into());
+        event.insert("fields".to_owned(), fields.into());
+        if let Some(target) = &self.target {
+            event.insert("target".to_owned(), target.clone().into());
+        }
+        if let Some(span) = &self.span {
+            let mut name = Map::new();
+            name.insert("name".to_owned(), span.clone().into());
+            event.insert("span".to_owned(), name.into());
+        }
+        Value::Object(event).to_string()
+    }
+}
+
+/// Parse a line of JSON log output, detecting the format.
+///
+/// # Errors
+///
+/// Returns an error if the line is not a JSON log event, for example the
+/// truncated last line of a crashed run.
+pub fn parse_line(line: &str) -> EyreResult<LogRecord> {
+    parse(line, None)
+}
+
+/// Parse a line of JSON log output in a known format.
+///
+/// # Errors
+///
+/// Returns an error if the line is not a log event in `format`.
+pub fn parse_line_as(line: &str, format: Format) -> EyreResult<LogRecord> {
+    parse(line, Some(format))
+}
+
+fn parse(line: &str, format: Option<Format>) -> EyreResult<LogRecord> {
+    let Value::Object(mut event) = serde_json::from_str(line).wrap_err("Invalid JSON")? else {
+        bail!("Log event is not a JSON object");
+    };
+    let format = match format {
+        Some(format) => format,
+        None if event.contains_key("SeverityText") => Format::Otlp,
+        None if event.contains_key("level") => Format::Json,
+        None => bail!("Unknown log format"),
+    };
+    let mut take = |key: &str| match event.remove(key) {
+        Some(Value::String(value)) => Ok(value),
+        Some(value) => Err(eyre!("Invalid {key}: {value}")),
+        None => Err(eyre!("Missing {key}")),
+    };
+    Ok(match format {
+        Format::Json => {
+            let timestamp = take("timestamp")?;
+            let level = take("level")?;
+            let target = Some(take("target")?);
+            let (mut fields, span) = match event.remove("fields") {
+                Some(Value::Object(fields)) => {
+                    let span = event
+                        .get("span")
+                        .and_then(|span| span.get("name"))
+                        .and_then(Value::as_str)
+                        .map(ToOwned::to_owned);
+                    (fields, span)
+                }
+                Some(_) => bail!("Invalid fields"),
+                // `--log-json-flatten`, the rest of the line are the fields
+                None => {
+                    let span = match event.remove("span") {
+                        Some(Value::String(span)) => Some(span),
+                        _ => None,
+                    };
+                    event.remove("threadName");
+                    (event, span)
+                }
+            };
+            let message = match fields.remove("message") {
+                Some(Value::String(message)) => message,
+                Some(message) => message.to_string(),
+                None => String::new(),
+            };
+            LogRecord {
+                timestamp,
+                level,
+                target,
+                message,
+                span,
+                fields,
+                ..LogRecord::default()
+            }
+        }
+        Format::Otlp => {
+            let millis = take("Timestamp")?.parse().wrap_err("Invalid Timestamp")?;
+            let timestamp = Utc
+                .timestamp_millis_opt(millis)
+                .single()
+                .ok_or_else(|| eyre!("Timestamp out of range"))?
+                .to_rfc3339_opts(SecondsFormat::Millis, true);
+            let level = take("SeverityText")?;
+            let message = take("Body")?;
+            let trace_id = take("TraceId").ok();
+            let span_id = take("SpanId").ok();
+            let Some(Value::Object(fields)) = event.remove("Attributes") else {
+                bail!("Missing Attributes");
+            };
+            LogRecord {
+                timestamp,
+                level,
+                message,
+                fields,
+                trace_id,
+                span_id,
+                ..LogRecord::default()
+            }
+        }
+    })
+}
+
+/// Reads [`LogRecord`]s from a stream of JSON log lines.
+pub struct LogReader<R> {
+    lines:  Lines<R>,
+    format: Option<Format>,
+}
+
+impl<R: AsyncBufRead + Unpin> LogReader<R> {
+    /// Read records, detecting the format of every line.
+    #[must_use]
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines:  reader.lines(),
+            format: None,
+        }
+    }
+
+    /// Read records in a known format.
+    #[must_use]
+    pub fn with_format(reader: R, format: Format) -> Self {
+        Self {
+            lines:  reader.lines(),
+            format: Some(format),
+        }
+    }
+
+    /// The next record, or `None` at the end of the stream. Empty lines are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading fails or a line is not a log event. The
+    /// reader continues with the next line when called again.
+    pub async fn next_record(&mut self) -> EyreResult<Option<LogRecord>> {
+        while let Some(line) = self.lines.next_line().await? {
+            if !line.trim().is_empty() {
+                return parse(&line, self.format).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The path after `--cat-session-log`, if it is on the command line.
+///
+/// Like `--dump-cli-spec`, this is checked before regular argument parsing,
+/// so that it works even when required application arguments are missing.
+#[must_use]
+pub fn requested() -> Option<PathBuf> {
+    flag_value(FLAG)
+}
+
+/// The path after `--decode-binary-log`, if it is on the command line.
+#[cfg(feature = "binary-log")]
+#[must_use]
+pub fn decode_requested() -> Option<PathBuf> {
+    flag_value(DECODE_FLAG)
+}
+
+/// The path after `--dump-shmem`, if it is on the command line.
+#[cfg(feature = "shmem-logs")]
+#[must_use]
+pub fn dump_shmem_requested() -> Option<PathBuf> {
+    flag_value(DUMP_SHMEM_FLAG)
+}
+
+fn flag_value(flag: &str) -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1).take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(PathBuf::from);
+        }
+        let value = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix(flag)?.strip_prefix('='));
+        if let Some(value) = value {
+            return Some(OsString::from(value).into());
+        }
+    }
+    None
+}
+
+/// Read a log file, decompressing it if it is zstd compressed, like the log
+/// files of `--log-file-compress`.
+///
+/// A compressed file that was cut short, for example by a crash, is read up
+/// to the last complete frame or flushed block. Decompression errors after that
+/// are ignored, the data is unusable from there on anyway.
+///
+/// # Errors
+///
+/// Returns an error if the file can not be read or nothing of it can be
+/// decompressed, or if it is compressed and the `log-compress` feature is not
+/// enabled.
+pub fn read_compressed(path: &Path) -> EyreResult<Vec<u8>> {
+    let data =
+        fs::read(path).wrap_err_with(|| format!("Error reading log file {}", path.display()))?;
+    if !data.starts_with(&ZSTD) {
+        return Ok(data);
+    }
+    decompress(path, &data)
+}
+
+#[cfg(feature = "log-compress")]
+fn decompress(path: &Path, data: &[u8]) -> EyreResult<Vec<u8>> {
+    let mut decoder = zstd::Decoder::new(data)
+        .wrap_err_with(|| format!("Error decompressing {}", path.display()))?;
+    let mut result = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match decoder.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => result.extend_from_slice(&buffer[..n]),
+            Err(_) if !result.is_empty() => break,
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Error decompressing {}", path.display()))
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(not(feature = "log-compress"))]
+fn decompress(path: &Path, _data: &[u8]) -> EyreResult<Vec<u8>> {
+    bail!(
+        "{} is compressed, reading it needs the `log-compress` feature",
+        path.display()
+    )
+}
+
+/// Print the session log at `path` on stdout.
+///
+/// # Errors
+///
+/// Returns an error if the file can not be read or stdout is closed.
+pub fn cat(path: &Path) -> EyreResult<()> {
+    let data = read_compressed(path)?;
+    let mut stdout = io::stdout().lock();
+    #[cfg(feature = "binary-log")]
+    if is_binary_log(&data) {
+        return decode_to(data.as_slice(), &mut stdout, format_record);
+    }
+    for line in String::from_utf8_lossy(&data).lines() {
+        writeln!(stdout, "{}", format_line(line))?;
+    }
+    Ok(())
+}
+
+/// Print the binary log at `path` on stdout as JSON lines.
+///
+/// # Errors
+///
+/// Returns an error if the file is not a binary log, can not be read or
+/// stdout is closed.
+#[cfg(feature = "binary-log")]
+pub fn decode(path: &Path) -> EyreResult<()> {
+    let file = fs::File::open(path)
+        .wrap_err_with(|| format!("Error opening binary log {}", path.display()))?;
+    decode_to(
+        io::BufReader::new(file),
+        &mut io::stdout().lock(),
+        |record| record.to_json_line(),
+    )
+}
+
+/// Print the events in the `--log-shmem` ring at `path` on stdout as JSON
+/// lines.
+///
+/// # Errors
+///
+/// Returns an error if the file is not a log ring, can not be mapped or
+/// stdout is closed.
+#[cfg(feature = "shmem-logs")]
+pub fn dump_shmem(path: &Path) -> EyreResult<()> {
+    let mut reader = ShmemReader::attach(path)?;
+    let mut stdout = io::stdout().lock();
+    while let Some(record) = reader.next_record()? {
+        writeln!(stdout, "{}", record.to_json_line())?;
+    }
+    if reader.skipped() > 0 {
+        eprintln!(
+            "Warning: skipped {} bytes of overwritten or incomplete entries",
+            reader.skipped()
+        );
+    }
+    Ok(())
+}
+
+/// Write the records of a binary log, one line each, and warn about skipped
+/// corrupt data on stderr.
+#[cfg(feature = "binary-log")]
+fn decode_to(
+    reader: impl Read,
+    out: &mut impl Write,
+    format: impl Fn(LogRecord) -> String,
+) -> EyreResult<()> {
+    let mut reader = BinaryLogReader::new(reader)?;
+    while let Some(record) = reader.next_record()? {
+        writeln!(out, "{}", format(record))?;
+    }
+    if reader.skipped() > 0 {
+        eprintln!(
+            "Warning: skipped {} bytes of corrupt or incomplete data",
+            reader.skipped()
+        );
+    }
+    Ok(())
+}
+
+/// Format a JSON log line like `<time> <LEVEL> <target>: <message> k=v`.
+/// Lines that are not JSON events, like a truncated last line, are returned
+/// as is.
+fn format_line(line: &str) -> String {
+    parse_line(line).map_or_else(|_| line.to_owned(), format_record)
+}
+
+fn format_record(record: LogRecord) -> String {
+    let mut result = format!(
+        "{} {:<5} {}:",
+        record.timestamp,
+        record.level,
+        record.target.unwrap_or_default()
+    );
+    if !record.message.is_empty() {
+        let _ = write!(result, " {}", record.message);
+    }
+    for (key, value) in &record.fields {
+        match value {
+            Value::String(value) => write!(result, " {key}={value:?}"),
+            value => write!(result, " {key}={value}"),
+        }
+        .unwrap_or_default();
+    }
+    if let Some(span) = record.span {
+        let _ = write!(result, " [{span}]");
+    }
+    result
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::process::id as pid;
+    #[cfg(feature = "log-compress")]
+    use zstd::Encoder;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("cli-batteries-logs-{name}-{}", pid()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_plain() {
+        let path = temp_file("plain", b"one\ntwo\n");
+        assert_eq!(read_compressed(&path).unwrap(), b"one\ntwo\n");
+        fs::remove_file(&path).unwrap();
+        assert!(read_compressed(&path).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "log-compress")]
+    fn test_read_truncated() {
+        // A complete frame, then one that is cut short by the crash.
+        let mut encoder = Encoder::new(Vec::new(), 0).unwrap();
+        encoder.write_all(b"first frame\n").unwrap();
+        let mut data = encoder.finish().unwrap();
+        let first = data.len();
+        let mut encoder = Encoder::new(data, 0).unwrap();
+        encoder.write_all(b"flushed line\n").unwrap();
+        encoder.flush().unwrap();
+        let flushed = encoder.get_ref().len();
+        encoder.write_all(&b"lost line\n".repeat(100)).unwrap();
+        data = encoder.finish().unwrap();
+
+        // Complete file
+        let path = temp_file("complete", &data);
+        let complete = read_compressed(&path).unwrap();
+        assert!(complete.starts_with(b"first frame\nflushed line\nlost line\n"));
+        assert!(complete.ends_with(b"lost line\n"));
+
+        // Cut short after a frame or a flush
+        for (len, expected) in [
+            (first, &b"first frame\n"[..]),
+            (first + 5, b"first frame\n"),
+            (flushed, b"first frame\nflushed line\n"),
+            (data.len() - 4, b"first frame\nflushed line\n"),
+        ] {
+            fs::write(&path, &data[..len]).unwrap();
+            let partial = read_compressed(&path).unwrap();
+            assert!(partial.starts_with(expected), "{len}");
+            assert!(complete.starts_with(&partial), "{len}");
+        }
+
+        // Nothing readable
+        fs::write(&path, [0x28, 0xb5, 0x2f, 0xfd, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert!(read_compressed(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_format_line() {
+        let line = r#"{"timestamp":"2023-04-18T12:00:00.000000Z","level":"INFO","fields":{"message":"listening","port":8080,"host":"::"},"target":"app::server","span":{"name":"serve"}}"#;
+        assert_eq!(
+            format_line(line),
+            r#"2023-04-18T12:00:00.000000Z INFO  app::server: listening host="::" port=8080 [serve]"#
+        );
+        assert_eq!(
+            format_line(r#"{"timestamp":"2023-04"#),
+            r#"{"timestamp":"2023-04"#
+        );
+    }
+
+    #[test]
+    fn test_to_json_line() {
+        let line = r#"{"timestamp":"2023-04-18T12:00:00.000000Z","level":"INFO","fields":{"message":"listening","port":8080},"target":"app::server","span":{"name":"serve"}}"#;
+        let record = parse_line(line).unwrap();
+        assert_eq!(parse_line(&record.to_json_line()).unwrap(), record);
+        assert_eq!(format_line(&record.to_json_line()), format_line(line));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let line = r#"{"Timestamp":"1681819200000","TraceId":"0af7651916cd43dd8448eb211c80319c","SpanId":"b7ad6b7169203331","severity":"WARN","SeverityText":"WARN","SeverityNumber":13,"Body":"slow","Attributes":{"code.lineno":42,"elapsed_ms":1500}}"#;
+        let record = parse_line(line).unwrap();
+        assert_eq!(record, parse_line_as(line, Format::Otlp).unwrap());
+        assert_eq!(record.timestamp, "2023-04-18T12:00:00.000Z");
+        assert_eq!(record.level, "WARN");
+        assert_eq!(record.target, None);
+        assert_eq!(record.message, "slow");
+        assert_eq!(record.fields["elapsed_ms"], 1500);
+        assert_eq!(
+            record.trace_id.as_deref(),
+            Some("0af7651916cd43dd8448eb211c80319c")
+        );
+        assert_eq!(record.span_id.as_deref(), Some("b7ad6b7169203331"));
+
+        // Re-emitted records parse back the same.
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(serde_json::from_str::<LogRecord>(&json).unwrap(), record);
+
+        assert!(parse_line_as(line, Format::Json).is_err());
+        assert!(parse_line("[]").is_err());
+        assert!(parse_line(r#"{"message":"no level"}"#).is_err());
+    }
+
+    #[cfg(feature = "binary-log")]
+    #[test]
+    fn test_decode_to() {
+        use crate::trace::test::Capture;
+        use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+        let capture = Capture::default();
+        let log = BinaryLog::new(capture.clone()).unwrap();
+        tracing::subscriber::with_default(Registry::default().with(log.clone()), || {
+            tracing::info!(target: "app", n = 1, "decoded");
+        });
+        log.finish().unwrap();
+        let data = capture.bytes();
+        assert!(is_binary_log(&data));
+
+        let mut json = Vec::new();
+        decode_to(data.as_slice(), &mut json, |record| record.to_json_line()).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(json.lines().count(), 1, "{json}");
+        let record = parse_line(json.trim_end()).unwrap();
+        assert_eq!(record.target.as_deref(), Some("app"));
+        assert_eq!(record.message, "decoded");
+        assert_eq!(record.fields["n"], 1);
+
+        let mut text = Vec::new();
+        decode_to(data.as_slice(), &mut text, format_record).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains(" INFO  app: decoded n=1"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn test_log_reader() {
+        let input = concat!(
+            r#"{"timestamp":"2023-04-18T12:00:00.000000Z","level":"INFO","fields":{"message":"one"},"target":"app"}"#,
+            "\n\n",
+            r#"{"timestamp":"2023-04"#,
+            "\n",
+            r#"{"timestamp":"2023-04-18T12:00:01.000000Z","level":"DEBUG","fields":{"message":"two","n":2},"target":"app","span":{"name":"serve","id":7}}"#,
+            "\n",
+        );
+        let mut reader = LogReader::new(input.as_bytes());
+        let one = reader.next_record().await.unwrap().unwrap();
+        assert_eq!(one.message, "one");
+        assert!(one.fields.is_empty());
+        assert!(reader.next_record().await.is_err());
+        let two = reader.next_record().await.unwrap().unwrap();
+        assert_eq!(two.level, "DEBUG");
+        assert_eq!(two.span.as_deref(), Some("serve"));
+        assert_eq!(two.fields["n"], 2);
+        assert!(reader.next_record().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,171 @@
+//! Counters of telemetry that was deliberately or unavoidably discarded.
+//!
+//! Components that drop telemetry declare a static [`LossCounter`] and
+//! increment it. Counters register themselves on first use. Increases since
+//! the previous report are logged as a single `Telemetry loss` event with the
+//! heartbeat and at shutdown, and with the `prometheus` feature exported as
+//! `telemetry_loss_total`. Nothing is logged while no telemetry is lost.
+use once_cell::sync::Lazy;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+use tracing::warn;
+
+#[cfg(feature = "prometheus")]
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+/// Log events suppressed by rate limiting.
+pub static LOG_RATE_LIMITED: LossCounter = LossCounter::new("log_rate_limited");
+
+/// Spans dropped because the OpenTelemetry export queue was full.
+#[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+pub static SPANS_DROPPED: LossCounter = LossCounter::new("spans_dropped");
+
+/// Events dropped because the binary log writer was behind.
+#[cfg_attr(not(feature = "binary-log"), allow(dead_code))]
+pub static BINARY_LOG_DROPPED: LossCounter = LossCounter::new("binary_log_dropped");
+
+/// Events too large for the `--log-shmem` ring.
+#[cfg_attr(not(feature = "shmem-logs"), allow(dead_code))]
+pub static SHMEM_LOG_DROPPED: LossCounter = LossCounter::new("shmem_log_dropped");
+
+/// ERROR events not sent to `--error-webhook-url` because of its rate limit
+/// or a full queue.
+#[cfg_attr(not(feature = "webhook"), allow(dead_code))]
+pub static WEBHOOK_DROPPED: LossCounter = LossCounter::new("webhook_dropped");
+
+/// ERROR events `--error-webhook-url` did not accept after all retries.
+#[cfg_attr(not(feature = "webhook"), allow(dead_code))]
+pub static WEBHOOK_FAILED: LossCounter = LossCounter::new("webhook_failed");
+
+/// Log file events dropped because the disk was full.
+pub static LOG_DISK_FULL: LossCounter = LossCounter::new("log_disk_full");
+
+/// Interval ticks skipped because the task was running behind.
+pub static TICKS_SKIPPED: LossCounter = LossCounter::new("ticks_skipped");
+
+static COUNTERS: Lazy<Mutex<Vec<&'static LossCounter>>> = Lazy::new(Mutex::default);
+
+#[cfg(feature = "prometheus")]
+static TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "telemetry_loss_total",
+        "Telemetry discarded, by reason.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// A named count of discarded telemetry, see the [module docs](self).
+pub struct LossCounter {
+    name:       &'static str,
+    count:      AtomicU64,
+    reported:   AtomicU64,
+    registered: AtomicBool,
+}
+
+impl LossCounter {
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Count `n` discarded items.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn add(&'static self, n: u64) {
+        self.count.fetch_add(n, Ordering::Relaxed);
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            COUNTERS.lock().unwrap().push(self);
+        }
+        #[cfg(feature = "prometheus")]
+        TOTAL.with_label_values(&[self.name]).inc_by(n);
+    }
+
+    /// Total discarded so far.
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Increase since the previous call.
+    fn take_delta(&self) -> u64 {
+        let count = self.get();
+        count - self.reported.swap(count, Ordering::Relaxed)
+    }
+}
+
+/// Increases since the previous report, in registration order. Empty when
+/// nothing was lost.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn deltas() -> Vec<(&'static str, u64)> {
+    let counters = COUNTERS.lock().unwrap().clone();
+    counters
+        .into_iter()
+        .map(|counter| (counter.name, counter.take_delta()))
+        .filter(|(_, delta)| *delta > 0)
+        .collect()
+}
+
+/// Log the telemetry lost since the previous report, if any.
+pub fn log_report() {
+    let deltas = deltas();
+    if deltas.is_empty() {
+        return;
+    }
+    let telemetry_loss = deltas
+        .iter()
+        .map(|(name, delta)| format!("{name}={delta}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    warn!(%telemetry_loss, "Telemetry loss");
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing_test::traced_test;
+
+    static TEST_A: LossCounter = LossCounter::new("test_a");
+    static TEST_B: LossCounter = LossCounter::new("test_b");
+
+    #[test]
+    #[traced_test]
+    fn test_report() {
+        let ours = || {
+            deltas()
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("test_"))
+                .collect::<Vec<_>>()
+        };
+        TEST_A.add(3);
+        TEST_B.add(1);
+        TEST_A.add(2);
+        assert_eq!(ours(), vec![("test_a", 5), ("test_b", 1)]);
+        assert_eq!(ours(), vec![]);
+
+        TEST_B.add(4);
+        log_report();
+        assert!(logs_contain("Telemetry loss"));
+        assert!(logs_contain("test_b=4"));
+        assert_eq!(TEST_B.get(), 5);
+
+        // Nothing to report
+        log_report();
+        logs_assert(|lines| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("Telemetry loss"))
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("Expected one report, got {n}")),
+            }
+        });
+    }
+}
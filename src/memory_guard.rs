@@ -0,0 +1,386 @@
+//! `--memory-soft-limit` and `--memory-hard-limit`: act before the OOM killer.
+//!
+//! A process killed for running out of memory leaves no diagnostics. With
+//! these limits the resident memory of the process is checked every
+//! `--memory-check-interval`. Limits are sizes like `2GiB`, or percentages of
+//! the memory limit of the cgroup, and of the physical memory outside of one.
+//!
+//! Above the soft limit a warning is logged, with the heap in use if the
+//! `metered-allocator` feature is enabled, and [`memory_pressure`] is true so
+//! the app can shed load. It turns false again once memory use falls below
+//! 90% of the soft limit, so use around the limit does not log repeatedly.
+//!
+//! Above the hard limit the program logs an error and shuts down gracefully.
+//! It then fails with exit status [`EXIT_CODE`] instead of 1, even if main
+//! returned successfully.
+//!
+//! Resident memory is read from `/proc/self/statm`, so only Linux is
+//! supported. Elsewhere the limits are ignored with a warning.
+use crate::{cgroup::memory_limit, config_issue::ConfigIssue, shutdown, time::interval};
+use clap::Parser;
+use eyre::{bail, Result as EyreResult};
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// Exit status after the hard limit was crossed, `EX_TEMPFAIL`: the run may
+/// succeed when retried with more memory.
+pub const EXIT_CODE: i32 = 75;
+
+/// Memory pressure ends below this percentage of the soft limit.
+const HYSTERESIS: u64 = 90;
+
+static PRESSURE: AtomicBool = AtomicBool::new(false);
+
+static EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Field names are the flag names
+pub struct Options {
+    /// Warn and report memory pressure to the app above this resident memory,
+    /// like `2GiB` or `80%` of the memory limit.
+    #[clap(long, env, value_parser = parse_limit)]
+    memory_soft_limit: Option<Limit>,
+
+    /// Shut down gracefully and exit with status 75 above this resident
+    /// memory, like `3GiB` or `95%` of the memory limit.
+    #[clap(long, env, value_parser = parse_limit)]
+    memory_hard_limit: Option<Limit>,
+
+    /// How often to check memory use against the limits.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "1s")]
+    memory_check_interval: Duration,
+}
+
+crate::default_from_clap!(Options);
+
+/// A memory limit, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Limit {
+    Bytes(u64),
+    /// Of the memory limit of the cgroup, or of the physical memory.
+    Percent(u8),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{bytes}"),
+            Self::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+fn parse_limit(s: &str) -> Result<Limit, String> {
+    let Some(percent) = s.strip_suffix('%') else {
+        return crate::trace::parse_size(s)
+            .map(Limit::Bytes)
+            .ok_or_else(|| format!("expected a size like 2GiB or a percentage like 80%, got {s}"));
+    };
+    match percent.trim().parse::<u8>() {
+        Ok(percent @ 1..=100) => Ok(Limit::Percent(percent)),
+        _ => Err(format!("expected a percentage from 1 to 100, got {s}")),
+    }
+}
+
+impl Limit {
+    /// The limit in bytes, with percentages of `total`.
+    fn resolve(self, total: Option<u64>) -> Option<u64> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            Self::Percent(percent) => total.map(|total| total / 100 * u64::from(percent)),
+        }
+    }
+}
+
+impl Options {
+    /// Problems with the limits, like a soft limit that is not below the hard
+    /// limit and so never warns.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if let (Some(soft), Some(hard)) = (self.memory_soft_limit, self.memory_hard_limit) {
+            let comparable = matches!(
+                (soft, hard),
+                (Limit::Bytes(_), Limit::Bytes(_)) | (Limit::Percent(_), Limit::Percent(_))
+            );
+            if comparable && soft >= hard {
+                issues.push(
+                    ConfigIssue::warning("memory-soft-limit", soft, "is not below the hard limit")
+                        .suggest(format!("lower it below {hard}")),
+                );
+            }
+        }
+        if self.memory_check_interval.is_zero() {
+            issues.push(ConfigIssue::error(
+                "memory-check-interval",
+                "0s",
+                "must be longer than zero",
+            ));
+        }
+        issues
+    }
+
+    /// Start checking the resident memory of the process until shutdown, if a
+    /// limit is set.
+    pub fn start(&self) {
+        self.start_with(resident_memory);
+    }
+
+    /// Start checking the memory use returned by `usage`.
+    fn start_with<F>(&self, mut usage: F)
+    where
+        F: FnMut() -> Option<u64> + Send + 'static,
+    {
+        if self.memory_soft_limit.is_none() && self.memory_hard_limit.is_none() {
+            return;
+        }
+        if usage().is_none() {
+            warn!("Memory use can not be measured on this platform, ignoring memory limits");
+            return;
+        }
+        let total = memory_limit().or_else(physical_memory);
+        let mut guard = Guard::new(
+            self.memory_soft_limit
+                .and_then(|limit| limit.resolve(total)),
+            self.memory_hard_limit
+                .and_then(|limit| limit.resolve(total)),
+        );
+        info!(
+            soft_limit = guard.soft,
+            hard_limit = guard.hard,
+            "Memory limits enabled"
+        );
+        let period = self.memory_check_interval;
+        tokio::spawn(async move {
+            let mut interval = interval(period);
+            while interval.tick().await.is_some() {
+                let Some(resident) = usage() else {
+                    continue;
+                };
+                if let Some(crossing) = guard.check(resident) {
+                    crossing.apply(resident, &guard);
+                }
+            }
+        });
+    }
+}
+
+/// Whether memory use is above `--memory-soft-limit`. Apps can check this to
+/// shed load, like rejecting new work.
+#[must_use]
+pub fn memory_pressure() -> bool {
+    PRESSURE.load(Ordering::Relaxed)
+}
+
+/// Whether memory use crossed `--memory-hard-limit`.
+pub fn hard_limit_exceeded() -> bool {
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// Fail if memory use crossed `--memory-hard-limit`.
+pub fn check() -> EyreResult<()> {
+    if hard_limit_exceeded() {
+        bail!("Memory use exceeded --memory-hard-limit");
+    }
+    Ok(())
+}
+
+/// The state of the limits, with hysteresis on the soft limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Guard {
+    soft:     Option<u64>,
+    hard:     Option<u64>,
+    pressure: bool,
+    exceeded: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Crossing {
+    Pressure,
+    Relieved,
+    Exceeded,
+}
+
+impl Guard {
+    const fn new(soft: Option<u64>, hard: Option<u64>) -> Self {
+        Self {
+            soft,
+            hard,
+            pressure: false,
+            exceeded: false,
+        }
+    }
+
+    /// The limit crossed by the memory use `resident`, if any.
+    fn check(&mut self, resident: u64) -> Option<Crossing> {
+        if !self.exceeded && self.hard.is_some_and(|hard| resident >= hard) {
+            self.exceeded = true;
+            return Some(Crossing::Exceeded);
+        }
+        let soft = self.soft?;
+        if !self.pressure && resident >= soft {
+            self.pressure = true;
+            Some(Crossing::Pressure)
+        } else if self.pressure && resident < soft / 100 * HYSTERESIS {
+            self.pressure = false;
+            Some(Crossing::Relieved)
+        } else {
+            None
+        }
+    }
+}
+
+impl Crossing {
+    fn apply(self, resident: u64, guard: &Guard) {
+        match self {
+            Self::Pressure => {
+                PRESSURE.store(true, Ordering::Relaxed);
+                #[cfg(feature = "metered-allocator")]
+                let heap = Some(crate::metered_allocator::heap_in_use());
+                #[cfg(not(feature = "metered-allocator"))]
+                let heap = None::<u64>;
+                warn!(
+                    resident,
+                    soft_limit = guard.soft,
+                    heap,
+                    "Memory use above the soft limit"
+                );
+            }
+            Self::Relieved => {
+                PRESSURE.store(false, Ordering::Relaxed);
+                info!(
+                    resident,
+                    soft_limit = guard.soft,
+                    "Memory use back below the soft limit"
+                );
+            }
+            Self::Exceeded => {
+                PRESSURE.store(true, Ordering::Relaxed);
+                EXCEEDED.store(true, Ordering::Relaxed);
+                error!(
+                    resident,
+                    hard_limit = guard.hard,
+                    "Memory use above the hard limit, shutting down"
+                );
+                shutdown::shutdown();
+            }
+        }
+    }
+}
+
+/// Resident memory of this process in bytes.
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(pages * page_size()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn resident_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn page_size() -> Option<u64> {
+    // SAFETY: `sysconf` has no preconditions.
+    u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()
+}
+
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn physical_memory() -> Option<u64> {
+    // SAFETY: `sysconf` has no preconditions.
+    let pages = u64::try_from(unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) }).ok()?;
+    pages.checked_mul(page_size()?)
+}
+
+#[cfg(not(unix))]
+const fn physical_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("2GiB"), Ok(Limit::Bytes(2 << 30)));
+        assert_eq!(parse_limit("80%"), Ok(Limit::Percent(80)));
+        assert!(parse_limit("0%").is_err());
+        assert!(parse_limit("120%").is_err());
+        assert!(parse_limit("lots").is_err());
+
+        assert_eq!(Limit::Percent(50).resolve(Some(4000)), Some(2000));
+        assert_eq!(Limit::Percent(50).resolve(None), None);
+        assert_eq!(Limit::Bytes(10).resolve(None), Some(10));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let mut guard = Guard::new(Some(1000), Some(2000));
+        let crossings = [500, 1000, 1200, 950, 1100, 899, 1000, 2500, 3000]
+            .map(|resident| guard.check(resident));
+        assert_eq!(crossings, [
+            None,
+            Some(Crossing::Pressure),
+            None,
+            None,
+            None,
+            Some(Crossing::Relieved),
+            Some(Crossing::Pressure),
+            Some(Crossing::Exceeded),
+            None,
+        ]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |soft, hard| {
+            let args = [
+                "arg0",
+                "--memory-soft-limit",
+                soft,
+                "--memory-hard-limit",
+                hard,
+            ];
+            Options::try_parse_from(args).unwrap().validate()
+        };
+        let issues = validate("2GiB", "1GiB");
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "memory-soft-limit");
+        assert_eq!(validate("90%", "1GiB"), vec![]);
+        assert_eq!(validate("1GiB", "2GiB"), vec![]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_growth() {
+        let usage = Arc::new(AtomicU64::new(100));
+        let options = Options::try_parse_from(["arg0", "--memory-soft-limit=1000"]).unwrap();
+        options.start_with({
+            let usage = usage.clone();
+            move || Some(usage.load(Ordering::Relaxed))
+        });
+        let tick = || tokio::time::sleep(Duration::from_millis(1500));
+
+        tick().await;
+        assert!(!memory_pressure());
+        usage.store(1500, Ordering::Relaxed);
+        tick().await;
+        assert!(memory_pressure());
+        usage.store(950, Ordering::Relaxed);
+        tick().await;
+        assert!(memory_pressure());
+        usage.store(500, Ordering::Relaxed);
+        tick().await;
+        assert!(!memory_pressure());
+        assert!(!hard_limit_exceeded());
+    }
+}
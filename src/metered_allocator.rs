@@ -22,6 +22,11 @@ static SIZE: Lazy<Histogram> = Lazy::new(|| {
     .unwrap()
 });
 
+/// Bytes allocated and not freed since metering started.
+pub fn heap_in_use() -> u64 {
+    ALLOCATED.get().saturating_sub(FREED.get())
+}
+
 pub struct MeteredAllocator<T: GlobalAlloc> {
     inner:    T,
     metering: AtomicBool,
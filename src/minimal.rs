@@ -0,0 +1,153 @@
+//! `minimal` builds, for programs that care about binary size more than about
+//! telemetry.
+//!
+//! The command line has just `--verbose`, `--quiet` and `--log-filter` next to
+//! the options of the app, and the program logs in the `tiny` format to
+//! stderr. What is left is the version banner, signal handling and shutdown.
+//! The session log, log files and sinks, the other log formats, the `log`
+//! crate bridge, flame graphs and the diagnostic options are not referenced
+//! and are left out of the binary by the linker.
+//!
+//! The [`Runner`] settings for the root check, the open file limit, help
+//! examples, debug checks and flag explanations belong to the left out options
+//! and are ignored, with a warning at startup when they are set.
+//!
+//! The feature is additive: other features still compile, but those that only
+//! add options or outputs to the full startup, like `otlp` or
+//! `tokio-console`, have nothing to start and are reported the same way.
+#![cfg(feature = "minimal")]
+use crate::{preflight, runner::LayerFactory, shutdown, trace, Runner};
+use clap::{Args, CommandFactory, FromArgMatches, Parser};
+use eyre::{Report, Result as EyreResult, WrapErr};
+use std::future::Future;
+use tokio::{runtime, task::LocalSet};
+use tracing::{info, info_span, warn, Instrument};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+struct Options<O: Args> {
+    #[clap(flatten)]
+    tracing: trace::MinimalOptions,
+
+    #[clap(flatten)]
+    app: O,
+}
+
+/// The [`Runner`] settings that were changed, but have no options to apply
+/// them to.
+fn ignored(runner: &Runner) -> Vec<&'static str> {
+    [
+        (runner.root.expect, "expect_root"),
+        (runner.root.require, "require_root"),
+        (
+            runner.min_open_files != preflight::DEFAULT_MIN_OPEN_FILES,
+            "min_open_files",
+        ),
+        (!runner.help_examples.is_empty(), "help_example"),
+        (!runner.checks.is_empty(), "check"),
+        (!runner.explanations.is_empty(), "explain"),
+    ]
+    .into_iter()
+    .filter_map(|(set, setting)| set.then_some(setting))
+    .collect()
+}
+
+/// Enabled features that only add to the full startup.
+static IGNORED_FEATURES: &[&str] = &[
+    #[cfg(feature = "prometheus")]
+    "prometheus",
+    #[cfg(feature = "otlp")]
+    "otlp",
+    #[cfg(feature = "tokio-console")]
+    "tokio-console",
+    #[cfg(feature = "daemonize")]
+    "daemonize",
+    #[cfg(feature = "binary-log")]
+    "binary-log",
+    #[cfg(feature = "webhook")]
+    "webhook",
+    #[cfg(feature = "support-bundle")]
+    "support-bundle",
+    #[cfg(feature = "log-compress")]
+    "log-compress",
+    #[cfg(feature = "log-async")]
+    "log-async",
+];
+
+pub fn run_fallible<A, O, F, E>(runner: &Runner, app: A) -> EyreResult<()>
+where
+    A: FnOnce(O) -> F,
+    O: Args,
+    F: Future<Output = Result<(), E>>,
+    E: Into<Report> + Send + Sync + 'static,
+{
+    let version = &runner.version;
+
+    // Install panic handler
+    let (panic_hook, eyre_hook) = color_eyre::config::HookBuilder::default()
+        .issue_url(format!("{}/issues/new", version.pkg_repo))
+        .add_issue_metadata(
+            "version",
+            format!("{} {}", version.pkg_name, version.long_version),
+        )
+        .into_hooks();
+    eyre_hook.install()?;
+    let panic_hook = panic_hook.into_panic_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Reported by the guard of the layer, which disables it
+        if trace::in_guarded_layer() {
+            return;
+        }
+        panic_hook(info);
+    }));
+
+    // Parse CLI and handle help and version (which will stop the application).
+    let matches = Options::<O>::command()
+        .name(version.pkg_name)
+        .version(version.pkg_version)
+        .long_version(version.long_version)
+        .get_matches();
+    let mut options = Options::<O>::from_arg_matches(&matches)?;
+    options.tracing.use_verbosity_map(&runner.verbosity_map);
+    options.tracing.use_log_defaults(runner.log_defaults);
+
+    // Launch Tokio runtime
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .wrap_err("Error creating Tokio runtime")?;
+    let main = async {
+        // Monitor for Ctrl-C
+        #[cfg(feature = "signals")]
+        shutdown::watch_signals();
+
+        // Start log system
+        let layers = runner.layers.iter().map(LayerFactory::make).collect();
+        options.tracing.init(version, layers)?;
+        for setting in ignored(runner) {
+            warn!("`Runner::{setting}` is ignored in `minimal` builds");
+        }
+        for feature in IGNORED_FEATURES {
+            warn!("The `{feature}` feature is ignored in `minimal` builds");
+        }
+
+        // Start main
+        app(options.app)
+            .instrument(info_span!("main"))
+            .await
+            .map_err(E::into)?;
+
+        // Initiate shutdown if main returns
+        shutdown::shutdown();
+        EyreResult::<()>::Ok(())
+    };
+    if runner.local {
+        LocalSet::new().block_on(&runtime, main)?;
+    } else {
+        runtime.block_on(main)?;
+    }
+
+    // Terminate successfully
+    info!("Program terminating normally");
+    Ok(())
+}
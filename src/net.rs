@@ -0,0 +1,358 @@
+//! Instrumented DNS resolution.
+//!
+//! [`resolve`] looks up a host name with a timeout and caches answers for
+//! `--dns-cache-ttl` and failures for `--dns-negative-ttl`. Every lookup that
+//! misses the cache gets a `dns.resolve` span with `dns.question.name` and
+//! `dns.answers` fields, and lookups slower than `--dns-slow` log a warning.
+//! With the `prometheus` feature cache hits, misses and evictions are counted
+//! in `dns_cache_total`.
+use crate::default_from_clap;
+use clap::Parser;
+use futures::future::BoxFuture;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::{timeout, Instant};
+use tracing::{debug, field::Empty, info_span, warn, Instrument, Span};
+
+#[cfg(feature = "prometheus")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "prometheus")]
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+static RESOLVER: OnceCell<Resolver> = OnceCell::new();
+
+#[cfg(feature = "prometheus")]
+static CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("dns_cache_total", "DNS cache lookups and evictions.", &[
+        "result"
+    ])
+    .unwrap()
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Field names are the flag names
+pub struct Options {
+    /// Timeout for DNS lookups.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "5s")]
+    dns_timeout: Duration,
+
+    /// Warn about DNS lookups slower than this.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "100ms")]
+    dns_slow: Duration,
+
+    /// How long to cache DNS answers.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "30s")]
+    dns_cache_ttl: Duration,
+
+    /// How long to cache failed DNS lookups.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "5s")]
+    dns_negative_ttl: Duration,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Configure the resolver used by [`resolve`].
+    pub fn init(self) {
+        let _ = RESOLVER.set(Resolver::new(System, self));
+    }
+}
+
+/// Resolve `host` to its addresses using the resolver configured on the
+/// command line, see the [module docs](self).
+///
+/// # Errors
+///
+/// Fails with [`ErrorKind::TimedOut`] if the lookup times out, and otherwise
+/// with the resolver's error, e.g. [`ErrorKind::NotFound`] when the name
+/// does not exist.
+pub async fn resolve(host: &str) -> Result<Vec<IpAddr>> {
+    RESOLVER
+        .get_or_init(|| Resolver::new(System, Options::default()))
+        .resolve(host)
+        .await
+}
+
+/// Source of DNS answers.
+pub trait Lookup: Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>>;
+}
+
+/// The system resolver, through [`tokio::net::lookup_host`].
+pub struct System;
+
+impl Lookup for System {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Maximum number of cached names.
+const CAPACITY: usize = 1024;
+
+/// Caching resolver with a timeout.
+pub struct Resolver {
+    lookup:  Box<dyn Lookup>,
+    options: Options,
+    cache:   Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    expires: Instant,
+    /// Addresses, or the kind and message of the failure.
+    result:  std::result::Result<Vec<IpAddr>, (ErrorKind, String)>,
+}
+
+impl Resolver {
+    pub fn new(lookup: impl Lookup + 'static, options: Options) -> Self {
+        Self {
+            lookup: Box::new(lookup),
+            options,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// See [`resolve`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(host)
+            .filter(|cached| cached.expires > now)
+            .map(|cached| cached.result.clone());
+        if let Some(result) = cached {
+            count("hit");
+            return result.map_err(|(kind, message)| Error::new(kind, message));
+        }
+        count("miss");
+
+        let span = info_span!("dns.resolve", dns.question.name = host, dns.answers = Empty);
+        let result = async {
+            let result = timeout(self.options.dns_timeout, self.lookup.lookup(host))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!(
+                            "DNS lookup of {host} timed out after {:?}",
+                            self.options.dns_timeout
+                        ),
+                    ))
+                });
+            let elapsed = now.elapsed();
+            match &result {
+                Ok(addrs) => {
+                    Span::current().record("dns.answers", addrs.len());
+                    debug!(?elapsed, "Resolved {host}");
+                }
+                Err(err) => debug!(?elapsed, %err, "Could not resolve {host}"),
+            }
+            if elapsed > self.options.dns_slow {
+                warn!(dns.question.name = host, ?elapsed, "Slow DNS lookup");
+            }
+            result
+        }
+        .instrument(span)
+        .await;
+
+        // Timeouts are not cached, the next lookup may well succeed.
+        let ttl = match &result {
+            Ok(_) => Some(self.options.dns_cache_ttl),
+            Err(err) if err.kind() == ErrorKind::TimedOut => None,
+            Err(_) => Some(self.options.dns_negative_ttl),
+        };
+        if let Some(ttl) = ttl {
+            self.insert(host, Cached {
+                expires: Instant::now() + ttl,
+                result:  result
+                    .as_ref()
+                    .map(Clone::clone)
+                    .map_err(|err| (err.kind(), err.to_string())),
+            });
+        }
+        result
+    }
+
+    fn insert(&self, host: &str, cached: Cached) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CAPACITY && !cache.contains_key(host) {
+            // Evict the expired entries, or else the one expiring first.
+            let now = Instant::now();
+            let before = cache.len();
+            cache.retain(|_, cached| cached.expires > now);
+            if cache.len() == before {
+                let first = cache
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.expires)
+                    .map(|(host, _)| host.clone());
+                if let Some(first) = first {
+                    cache.remove(&first);
+                }
+            }
+            for _ in cache.len()..before {
+                count("eviction");
+            }
+        }
+        cache.insert(host.to_owned(), cached);
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn count(result: &str) {
+    CACHE.with_label_values(&[result]).inc();
+}
+
+#[cfg(not(feature = "prometheus"))]
+const fn count(_result: &str) {}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::time::sleep;
+    use tracing_test::traced_test;
+
+    /// Answers `known.test` after `delay`, hangs on `hang.test` and fails
+    /// with NXDOMAIN otherwise.
+    #[derive(Clone, Default)]
+    struct Mock {
+        delay: Duration,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Lookup for Mock {
+        fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                sleep(self.delay).await;
+                match host {
+                    "known.test" => Ok(vec![
+                        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+                    ]),
+                    "hang.test" => futures::future::pending().await,
+                    _ => Err(Error::new(ErrorKind::NotFound, format!("{host}: NXDOMAIN"))),
+                }
+            })
+        }
+    }
+
+    fn resolver(mock: &Mock) -> Resolver {
+        Resolver::new(mock.clone(), Options::default())
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_cache_hit() {
+        let mock = Mock::default();
+        let resolver = resolver(&mock);
+        let addrs = resolver.resolve("known.test").await.unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert!(logs_contain("dns.question.name=\"known.test\""));
+        assert!(logs_contain("dns.answers=2"));
+
+        sleep(Duration::from_secs(29)).await;
+        assert_eq!(resolver.resolve("known.test").await.unwrap(), addrs);
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 1);
+
+        // Expired
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(resolver.resolve("known.test").await.unwrap(), addrs);
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nxdomain() {
+        let mock = Mock::default();
+        let resolver = resolver(&mock);
+        let err = resolver.resolve("missing.test").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Cached for the negative TTL only.
+        let err = resolver.resolve("missing.test").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "missing.test: NXDOMAIN");
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 1);
+        sleep(Duration::from_secs(6)).await;
+        resolver.resolve("missing.test").await.unwrap_err();
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_timeout() {
+        let mock = Mock::default();
+        let resolver = resolver(&mock);
+        let err = resolver.resolve("hang.test").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            err.to_string(),
+            "DNS lookup of hang.test timed out after 5s"
+        );
+        assert!(logs_contain("Slow DNS lookup"));
+
+        // Not cached
+        resolver.resolve("hang.test").await.unwrap_err();
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_slow() {
+        let mock = Mock {
+            delay: Duration::from_millis(50),
+            ..Mock::default()
+        };
+        resolver(&mock).resolve("known.test").await.unwrap();
+        assert!(!logs_contain("Slow DNS lookup"));
+
+        let mock = Mock {
+            delay: Duration::from_millis(150),
+            ..Mock::default()
+        };
+        resolver(&mock).resolve("known.test").await.unwrap();
+        assert!(logs_contain("Slow DNS lookup"));
+        assert!(logs_contain("elapsed=150ms"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_eviction() {
+        let mock = Mock::default();
+        let resolver = resolver(&mock);
+        for i in 0..=CAPACITY {
+            resolver.resolve(&format!("{i}.test")).await.unwrap_err();
+            sleep(Duration::from_millis(1)).await;
+        }
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), CAPACITY);
+        assert!(!cache.contains_key("0.test"));
+        assert!(cache.contains_key("1.test"));
+        drop(cache);
+    }
+
+    #[tokio::test]
+    async fn test_system() {
+        let addrs = System.lookup("localhost").await.unwrap();
+        assert!(addrs.iter().all(IpAddr::is_loopback));
+    }
+}
@@ -0,0 +1,332 @@
+//! What the terminal can display.
+//!
+//! Serial consoles and some CI log viewers show color escapes and non-ASCII
+//! characters as garbage. With `--ascii-only`, or when `TERM=dumb`, log
+//! output and error reports are written without colors and phase progress is
+//! drawn as plain `.` ticks instead of a redrawn line.
+//!
+//! Colors are further controlled by `--color`. With the default `auto`, log
+//! output and error reports only have colors when written to a terminal and
+//! `NO_COLOR` is not set. `always` also colors redirected output and
+//! overrides `NO_COLOR` and `TERM=dumb`, `never` turns colors off.
+//!
+//! [`hyperlink`] makes text a link with the OSC 8 escape, for terminals known
+//! to support it from the variables they set, like `TERM_PROGRAM=WezTerm`.
+//! `FORCE_HYPERLINK=1` or `0` overrides the detection. Without support, with
+//! `--ascii-only`, `TERM=dumb` or `--color never`, the URL is written out.
+//!
+//! With the `clipboard` feature, [`copy_to_clipboard`] copies text to the
+//! system clipboard, if there is one.
+//!
+//! The [`capabilities`] are resolved once, before the error report hooks are
+//! installed and arguments are parsed, so like `--dump-cli-spec` the flags are
+//! read from the command line directly.
+use crate::default_from_clap;
+use clap::{Parser, ValueEnum};
+use once_cell::sync::OnceCell;
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, IsTerminal},
+};
+
+const FLAG: &str = "--ascii-only";
+const ENV: &str = "ASCII_ONLY";
+const COLOR_FLAG: &str = "--color";
+const COLOR_ENV: &str = "COLOR";
+
+static CAPABILITIES: OnceCell<OutputCapabilities> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Write only plain ASCII without colors, for serial consoles and
+    /// limited CI logs. Implied by `TERM=dumb`.
+    #[clap(long, env)]
+    ascii_only: bool,
+
+    /// Color log output and error reports: 'auto' on a terminal unless
+    /// `NO_COLOR` is set, 'always' or 'never'.
+    #[clap(long, env, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+}
+
+default_from_clap!(Options);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// What output may contain, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::struct_excessive_bools)] // Independent capabilities
+pub struct OutputCapabilities {
+    /// ANSI color and cursor escapes on a terminal.
+    pub color:        bool,
+    /// ANSI color escapes also when not writing to a terminal.
+    pub always_color: bool,
+    /// Characters outside of ASCII.
+    pub unicode:      bool,
+    /// OSC 8 hyperlinks on a terminal.
+    pub hyperlinks:   bool,
+}
+
+impl OutputCapabilities {
+    pub const ASCII_ONLY: Self = Self {
+        color:        false,
+        always_color: false,
+        unicode:      false,
+        hyperlinks:   false,
+    };
+    pub const FULL: Self = Self {
+        color:        true,
+        always_color: false,
+        unicode:      true,
+        hyperlinks:   true,
+    };
+
+    /// `hyperlinks` is whether the terminal supports them.
+    fn resolve(
+        ascii_only: bool,
+        color: ColorChoice,
+        term: Option<&OsStr>,
+        no_color: Option<&OsStr>,
+        hyperlinks: bool,
+    ) -> Self {
+        let dumb = term.is_some_and(|term| term == "dumb");
+        let capabilities = if ascii_only || dumb {
+            Self::ASCII_ONLY
+        } else {
+            Self {
+                hyperlinks: hyperlinks && color != ColorChoice::Never,
+                ..Self::FULL
+            }
+        };
+        match color {
+            _ if ascii_only => capabilities,
+            ColorChoice::Auto if no_color.is_some_and(|value| !value.is_empty()) => Self {
+                color: false,
+                ..capabilities
+            },
+            ColorChoice::Auto => capabilities,
+            ColorChoice::Always => Self {
+                color: true,
+                always_color: true,
+                ..capabilities
+            },
+            ColorChoice::Never => Self {
+                color: false,
+                ..capabilities
+            },
+        }
+    }
+
+    /// Whether output to a stream, a terminal or not, gets colors.
+    #[must_use]
+    pub const fn color_on(self, terminal: bool) -> bool {
+        self.always_color || (self.color && terminal)
+    }
+
+    /// Whether output to a stream gets hyperlinks, only on a terminal.
+    #[must_use]
+    pub const fn hyperlinks_on(self, terminal: bool) -> bool {
+        self.hyperlinks && terminal
+    }
+}
+
+/// The capabilities of this run, see the [module docs](self).
+pub fn capabilities() -> OutputCapabilities {
+    *CAPABILITIES.get_or_init(|| {
+        OutputCapabilities::resolve(
+            requested(),
+            requested_color(),
+            env::var_os("TERM").as_deref(),
+            env::var_os("NO_COLOR").as_deref(),
+            terminal_hyperlinks(|name| env::var(name).ok()),
+        )
+    })
+}
+
+/// `text` linking to `url` on stderr: a hyperlink if the terminal supports
+/// them, see the [module docs](self), or else `text (url)`, or the `url` if it
+/// is the text.
+#[must_use]
+pub fn hyperlink(text: &str, url: &str) -> String {
+    link(
+        text,
+        url,
+        capabilities().hyperlinks_on(io::stderr().is_terminal()),
+    )
+}
+
+/// Copy `text` to the system clipboard, whether that worked. Fails without
+/// a display, like over SSH.
+///
+/// On X11 and Wayland the text is served by this process and gone when it
+/// exits, unless a clipboard manager takes it over.
+#[cfg(feature = "clipboard")]
+#[must_use]
+pub fn copy_to_clipboard(text: &str) -> bool {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .is_ok()
+}
+
+fn link(text: &str, url: &str, hyperlinks: bool) -> String {
+    // A control character would end the escape early.
+    if hyperlinks && !url.chars().any(char::is_control) {
+        format!("\x1b]8;;{url}\x1b\\{text}\x1b]8;;\x1b\\")
+    } else if text == url {
+        text.to_owned()
+    } else {
+        format!("{text} ({url})")
+    }
+}
+
+/// Whether the terminal is known to support hyperlinks, from the environment
+/// variables it sets. Read through `var`.
+fn terminal_hyperlinks(var: impl Fn(&str) -> Option<String>) -> bool {
+    if let Some(force) = var("FORCE_HYPERLINK") {
+        return !force.is_empty() && force != "0";
+    }
+    let vte = var("VTE_VERSION")
+        .and_then(|version| version.parse::<u32>().ok())
+        .is_some_and(|version| version >= 5000);
+    let program = var("TERM_PROGRAM").is_some_and(|program| {
+        ["iTerm.app", "WezTerm", "vscode", "Hyper", "ghostty"].contains(&program.as_str())
+    });
+    let term = var("TERM").is_some_and(|term| {
+        ["xterm-kitty", "alacritty", "foot", "xterm-ghostty"].contains(&term.as_str())
+    });
+    let set = ["WT_SESSION", "KONSOLE_VERSION", "DOMTERM"]
+        .iter()
+        .any(|name| var(name).is_some());
+    vte || program || term || set
+}
+
+/// Whether `--ascii-only` is on the command line or set in the environment,
+/// with the same false values as clap.
+fn requested() -> bool {
+    let flag = env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == FLAG);
+    let env = env::var(ENV).is_ok_and(|value| {
+        !["", "n", "no", "f", "false", "off", "0"].contains(&value.to_lowercase().as_str())
+    });
+    flag || env
+}
+
+/// The `--color` choice on the command line or in the environment. Invalid
+/// values are left to the argument parser.
+fn requested_color() -> ColorChoice {
+    let mut args = env::args().skip(1).take_while(|arg| arg != "--");
+    let mut flag = None;
+    while let Some(arg) = args.next() {
+        if arg == COLOR_FLAG {
+            flag = args.next();
+        } else if let Some(value) = arg.strip_prefix(COLOR_FLAG) {
+            if let Some(value) = value.strip_prefix('=') {
+                flag = Some(value.to_owned());
+            }
+        }
+    }
+    flag.or_else(|| env::var(COLOR_ENV).ok())
+        .and_then(|value| ColorChoice::from_str(&value, true).ok())
+        .unwrap_or(ColorChoice::Auto)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let resolve = |ascii_only, term: Option<&str>| {
+            let term = term.map(OsStr::new);
+            OutputCapabilities::resolve(ascii_only, ColorChoice::Auto, term, None, true)
+        };
+        assert_eq!(resolve(false, None), OutputCapabilities::FULL);
+        assert_eq!(
+            resolve(false, Some("xterm-256color")),
+            OutputCapabilities::FULL
+        );
+        assert_eq!(resolve(false, Some("dumb")), OutputCapabilities::ASCII_ONLY);
+        assert_eq!(resolve(true, Some("xterm")), OutputCapabilities::ASCII_ONLY);
+    }
+    #[test]
+    fn test_color() {
+        let resolve = |color, term: Option<&str>, no_color: Option<&str>| {
+            let (term, no_color) = (term.map(OsStr::new), no_color.map(OsStr::new));
+            OutputCapabilities::resolve(false, color, term, no_color, true)
+        };
+        let auto = resolve(ColorChoice::Auto, None, None);
+        assert!(auto.color_on(true));
+        assert!(!auto.color_on(false));
+        assert!(!resolve(ColorChoice::Auto, None, Some("1")).color_on(true));
+        assert!(resolve(ColorChoice::Auto, None, Some("")).color_on(true));
+        assert!(!resolve(ColorChoice::Auto, Some("dumb"), None).color_on(true));
+        let always = resolve(ColorChoice::Always, Some("dumb"), Some("1"));
+        assert!(always.color_on(false));
+        assert!(!always.unicode);
+        assert!(!resolve(ColorChoice::Never, None, None).color_on(true));
+        let ascii_only = OutputCapabilities::resolve(true, ColorChoice::Always, None, None, true);
+        assert_eq!(ascii_only, OutputCapabilities::ASCII_ONLY);
+    }
+
+    #[test]
+    fn test_hyperlinks() {
+        let resolve = |color, term: Option<&str>, supported| {
+            OutputCapabilities::resolve(false, color, term.map(OsStr::new), None, supported)
+        };
+        assert!(resolve(ColorChoice::Auto, None, true).hyperlinks_on(true));
+        assert!(!resolve(ColorChoice::Auto, None, true).hyperlinks_on(false));
+        assert!(!resolve(ColorChoice::Auto, None, false).hyperlinks_on(true));
+        assert!(!resolve(ColorChoice::Auto, Some("dumb"), true).hyperlinks_on(true));
+        assert!(!resolve(ColorChoice::Never, None, true).hyperlinks_on(true));
+        assert!(resolve(ColorChoice::Always, None, true).hyperlinks_on(true));
+    }
+
+    #[test]
+    fn test_link() {
+        let url = "https://example.com/trace/1";
+        assert_eq!(
+            link("trace", url, true),
+            "\x1b]8;;https://example.com/trace/1\x1b\\trace\x1b]8;;\x1b\\"
+        );
+        assert_eq!(
+            link("trace", url, false),
+            "trace (https://example.com/trace/1)"
+        );
+        assert_eq!(link(url, url, false), url);
+        let escape = "https://x\x1b]0;title";
+        assert_eq!(link("trace", escape, true), format!("trace ({escape})"));
+    }
+
+    #[test]
+    fn test_terminal_hyperlinks() {
+        let detect = |vars: &[(&str, &str)]| {
+            terminal_hyperlinks(|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| (*value).to_owned())
+            })
+        };
+        assert!(!detect(&[]));
+        assert!(!detect(&[("TERM", "xterm-256color")]));
+        assert!(detect(&[("TERM", "xterm-kitty")]));
+        assert!(detect(&[("TERM_PROGRAM", "WezTerm")]));
+        assert!(!detect(&[("TERM_PROGRAM", "Apple_Terminal")]));
+        assert!(detect(&[("VTE_VERSION", "6800")]));
+        assert!(!detect(&[("VTE_VERSION", "4200")]));
+        assert!(detect(&[("WT_SESSION", "")]));
+        assert!(detect(&[("FORCE_HYPERLINK", "1")]));
+        assert!(!detect(&[
+            ("FORCE_HYPERLINK", "0"),
+            ("TERM_PROGRAM", "WezTerm")
+        ]));
+    }
+}
@@ -0,0 +1,362 @@
+//! Phases of long running programs.
+//!
+//! [`phase`] returns a guard that opens a `phase` span and logs when the phase
+//! starts and finishes. Phases started while another is running become
+//! sub-phases and are indented in the `tiny`, `compact` and `pretty` log
+//! formats. Steps completed with [`Phase::step`] are drawn as a progress line
+//! when stderr is a terminal, and otherwise logged every 10%. With
+//! `--ascii-only` the progress line is a `.` tick every 10% instead.
+//!
+//! A phase dropped without [`Phase::finish`], e.g. because an error was
+//! returned, is logged as abandoned. Abandoned and still running phases are
+//! listed when the program terminates abnormally, so a hang or failure can be
+//! attributed to a phase.
+use crate::output::{capabilities, OutputCapabilities};
+use once_cell::sync::Lazy;
+use std::{
+    io::{stderr, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{info, info_span, warn, Span};
+
+/// Phases that are running or were abandoned, in start order.
+static PHASES: Lazy<Mutex<Vec<Arc<State>>>> = Lazy::new(Mutex::default);
+
+/// Start a phase of `total_steps` steps, or `0` if the number of steps is not
+/// known.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn phase(name: &'static str, total_steps: u64) -> Phase {
+    let mut phases = PHASES.lock().unwrap();
+    let parent = phases.iter().rev().find(|state| state.running()).cloned();
+    let depth = parent.as_ref().map_or(0, |parent| parent.depth + 1);
+    let span = parent.as_ref().map_or_else(
+        || info_span!("phase", phase = name, total_steps),
+        |parent| info_span!(parent: &parent.span, "phase", phase = name, total_steps),
+    );
+    info!(parent: &span, phase = name, total_steps, "Phase {name} started");
+    let state = Arc::new(State {
+        name,
+        depth,
+        total: total_steps,
+        done: AtomicU64::new(0),
+        start: Instant::now(),
+        abandoned: Mutex::new(None),
+        span,
+    });
+    phases.push(state.clone());
+    drop(phases);
+    Phase {
+        state,
+        finished: false,
+    }
+}
+
+/// Log phases that are still running or were abandoned, see the [module
+/// docs](self). Each phase is reported once.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn log_unfinished() {
+    for (name, running) in unfinished() {
+        warn!(phase = name, ?running, "Phase {name} did not finish");
+    }
+}
+
+/// Names of unfinished phases and how long they ran. Forgets the abandoned
+/// ones.
+fn unfinished() -> Vec<(&'static str, Duration)> {
+    let mut phases = PHASES.lock().unwrap();
+    let unfinished = phases
+        .iter()
+        .map(|state| (state.name, state.running_for()))
+        .collect();
+    phases.retain(|state| state.running());
+    unfinished
+}
+
+/// Guard of a running phase, see [`phase`].
+#[must_use = "dropping the guard abandons the phase"]
+pub struct Phase {
+    state:    Arc<State>,
+    finished: bool,
+}
+
+struct State {
+    name:      &'static str,
+    depth:     usize,
+    total:     u64,
+    done:      AtomicU64,
+    start:     Instant,
+    /// How long the phase ran before it was abandoned.
+    abandoned: Mutex<Option<Duration>>,
+    span:      Span,
+}
+
+impl State {
+    fn running(&self) -> bool {
+        self.abandoned.lock().unwrap().is_none()
+    }
+
+    fn running_for(&self) -> Duration {
+        self.abandoned
+            .lock()
+            .unwrap()
+            .unwrap_or_else(|| self.start.elapsed())
+    }
+
+    fn percent(&self, done: u64) -> u64 {
+        done.min(self.total) * 100 / self.total
+    }
+
+    /// Progress line update for going from `before` to `after` percent.
+    fn progress(&self, output: OutputCapabilities, before: u64, after: u64) -> String {
+        let indent = 2 * self.depth;
+        if output.color {
+            format!(
+                "\r\x1b[2K{:indent$}{} {}/{} {after}%",
+                "",
+                self.name,
+                self.done.load(Ordering::Relaxed).min(self.total),
+                self.total,
+            )
+        } else {
+            let ticks = usize::try_from(after / 10 - before / 10).unwrap_or_default();
+            if ticks == 0 {
+                return String::new();
+            }
+            let name = if before < 10 {
+                format!("{:indent$}{} ", "", self.name)
+            } else {
+                String::new()
+            };
+            name + &".".repeat(ticks)
+        }
+    }
+
+    /// Clears or ends the progress line.
+    fn progress_end(&self, output: OutputCapabilities) -> &'static str {
+        if output.color {
+            "\r\x1b[2K"
+        } else if self.percent(self.done.load(Ordering::Relaxed)) >= 10 {
+            "\n"
+        } else {
+            ""
+        }
+    }
+}
+
+impl Phase {
+    /// The span of this phase, to instrument futures with.
+    #[must_use]
+    pub fn span(&self) -> &Span {
+        &self.state.span
+    }
+
+    /// Number of completed steps.
+    #[must_use]
+    pub fn done(&self) -> u64 {
+        self.state.done.load(Ordering::Relaxed)
+    }
+
+    /// Complete a step.
+    pub fn step(&self) {
+        self.advance(1);
+    }
+
+    /// Complete `steps` steps.
+    pub fn advance(&self, steps: u64) {
+        let state = &self.state;
+        let before = state.done.fetch_add(steps, Ordering::Relaxed);
+        if state.total == 0 {
+            return;
+        }
+        let (before, after) = (state.percent(before), state.percent(before + steps));
+        if before == after {
+            return;
+        }
+        if stderr().is_terminal() {
+            // Progress lines are best effort.
+            let _ = write!(
+                stderr(),
+                "{}",
+                state.progress(capabilities(), before, after)
+            );
+        } else if before / 10 != after / 10 {
+            info!(
+                parent: &state.span,
+                phase = state.name,
+                done = self.done(),
+                total_steps = state.total,
+                "Phase {} at {after}%",
+                state.name
+            );
+        }
+    }
+
+    /// Finish the phase, logging its duration.
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let state = &self.state;
+        let elapsed = state.start.elapsed();
+        if !self.finished {
+            *state.abandoned.lock().unwrap() = Some(elapsed);
+            warn!(
+                parent: &state.span,
+                phase = state.name,
+                ?elapsed,
+                "Phase {} abandoned",
+                state.name
+            );
+            return;
+        }
+        if state.total > 0 && stderr().is_terminal() {
+            let _ = write!(stderr(), "{}", state.progress_end(capabilities()));
+        }
+        info!(
+            parent: &state.span,
+            phase = state.name,
+            ?elapsed,
+            "Phase {} finished",
+            state.name
+        );
+        PHASES
+            .lock()
+            .unwrap()
+            .retain(|other| !Arc::ptr_eq(other, state));
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use eyre::{bail, Result as EyreResult};
+    use tokio::sync::Mutex;
+    use tracing_test::traced_test;
+
+    /// Phases are global, nesting tests must not overlap.
+    pub static SERIAL: Lazy<Mutex<()>> = Lazy::new(Mutex::default);
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_nesting() {
+        let _serial = SERIAL.lock().await;
+        let migrate = phase("test migrate", 0);
+        let apply = phase("test apply", 20);
+        assert_eq!(apply.state.depth, 1);
+        assert_eq!(apply.span().metadata().unwrap().name(), "phase");
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            apply.step();
+        }
+        assert_eq!(apply.done(), 20);
+        apply.finish();
+        let verify = phase("test verify", 0);
+        assert_eq!(verify.state.depth, 1);
+        verify.finish();
+        migrate.finish();
+
+        assert!(logs_contain("Phase test apply started"));
+        assert!(logs_contain("Phase test apply at 50%"));
+        assert!(logs_contain("Phase test apply finished"));
+        assert!(logs_contain("elapsed=2s"));
+        assert!(logs_contain("Phase test migrate finished"));
+        assert!(!logs_contain("abandoned"));
+        assert!(!unfinished()
+            .iter()
+            .any(|(name, _)| name.starts_with("test ")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_abandoned() {
+        async fn migrate() -> EyreResult<()> {
+            let phase = phase("test failing", 3);
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            phase.step();
+            bail!("constraint violated");
+        }
+
+        let _serial = SERIAL.lock().await;
+        let hung = phase("test hung", 0);
+        assert!(migrate().await.is_err());
+        assert!(logs_contain("Phase test failing abandoned"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // Abandoned phases do not become parents.
+        let later = phase("test later", 0);
+        assert_eq!(later.state.depth, 1);
+        later.finish();
+
+        let unfinished = unfinished()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("test "))
+            .collect::<Vec<_>>();
+        assert_eq!(unfinished, vec![
+            ("test hung", Duration::from_secs(5)),
+            ("test failing", Duration::from_secs(3)),
+        ]);
+
+        // Abandoned phases are reported once, running ones until they finish.
+        let unfinished = unfinished_names();
+        assert_eq!(unfinished, vec!["test hung"]);
+        hung.finish();
+        assert!(unfinished_names().is_empty());
+    }
+
+    #[test]
+    fn test_progress() {
+        let state = State {
+            name:      "apply",
+            depth:     1,
+            total:     40,
+            done:      AtomicU64::new(0),
+            start:     Instant::now(),
+            abandoned: std::sync::Mutex::new(None),
+            span:      Span::none(),
+        };
+        let render = |output, steps: u64| {
+            let before = state.percent(state.done.fetch_add(steps, Ordering::Relaxed));
+            let after = state.percent(state.done.load(Ordering::Relaxed));
+            state.progress(output, before, after)
+        };
+
+        let full = [2, 1, 10, 27]
+            .into_iter()
+            .map(|steps| render(OutputCapabilities::FULL, steps))
+            .collect::<Vec<_>>();
+        assert_eq!(full, [
+            "\r\x1b[2K  apply 2/40 5%",
+            "\r\x1b[2K  apply 3/40 7%",
+            "\r\x1b[2K  apply 13/40 32%",
+            "\r\x1b[2K  apply 40/40 100%",
+        ]);
+        assert_eq!(state.progress_end(OutputCapabilities::FULL), "\r\x1b[2K");
+
+        state.done.store(0, Ordering::Relaxed);
+        let ascii = [2, 1, 10, 27]
+            .into_iter()
+            .map(|steps| render(OutputCapabilities::ASCII_ONLY, steps))
+            .collect::<String>();
+        assert_eq!(ascii, "  apply ..........");
+        assert!(ascii.is_ascii());
+        assert_eq!(state.progress_end(OutputCapabilities::ASCII_ONLY), "\n");
+        state.done.store(3, Ordering::Relaxed);
+        assert_eq!(state.progress_end(OutputCapabilities::ASCII_ONLY), "");
+    }
+
+    fn unfinished_names() -> Vec<&'static str> {
+        unfinished()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with("test "))
+            .collect()
+    }
+}
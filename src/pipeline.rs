@@ -0,0 +1,368 @@
+//! Unix pipelines of cli-batteries programs as one trace.
+//!
+//! In `a | b | c` every stage is its own process, and the environment only
+//! flows from a parent to its children. The trace context, a W3C
+//! `traceparent`, is passed on in one of two ways:
+//!
+//! * A stage that starts the next one itself uses [`spawn_downstream`]. It
+//!   writes the `traceparent` to a pipe that the child reads with
+//!   `--trace-context-fd` at startup. A stage not in a trace yet starts one.
+//! * For pipelines composed by a shell, [`TRACE_FILE`] names a file shared by
+//!   all stages. The first stage to start creates it with a new trace, the
+//!   others read it.
+//!
+//! Every log event of a stage in a pipeline trace has a `trace_id` field.
+//! With the `otlp` feature the `main` span is a child of the `traceparent`,
+//! and [`spawn_downstream`] passes on the current span.
+//!
+//! # Caveats
+//!
+//! * The stages of a shell pipeline start concurrently. Which one creates the
+//!   trace file is a race, not the order in the pipe, but all of them end up in
+//!   the same trace.
+//! * The trace file is created by hard linking a complete temporary file, so it
+//!   is never read half written. File systems without hard links are not
+//!   supported.
+//! * The trace file is not removed. A file left over from an earlier pipeline
+//!   joins the next one to the old trace, use a fresh path per pipeline like
+//!   `CLI_BATTERIES_TRACE_FILE=$(mktemp -u)` and remove it afterwards.
+//! * `--trace-context-fd` blocks startup until a line or the end of the pipe is
+//!   read.
+//! * Without OpenTelemetry there are no span ids, only the trace id is
+//!   meaningful. The parent id in the `traceparent` is random.
+#![cfg(unix)]
+use crate::{default_from_clap, trace};
+use clap::Parser;
+use eyre::{bail, Report, Result as EyreResult, WrapErr as _};
+use once_cell::sync::OnceCell;
+use std::{
+    env,
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    mem::ManuallyDrop,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{self, Child, Command},
+    str::FromStr,
+};
+use tracing::info;
+
+/// Environment variable with the path of the trace file of a shell pipeline.
+pub const TRACE_FILE: &str = "CLI_BATTERIES_TRACE_FILE";
+
+/// Environment variable of `--trace-context-fd`.
+const TRACE_CONTEXT_FD: &str = "TRACE_CONTEXT_FD";
+
+/// The trace of the pipeline this process is a stage of.
+static CONTEXT: OnceCell<TraceParent> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Read the W3C traceparent of the upstream stage of a pipeline from this
+    /// file descriptor at startup. Set up by `pipeline::spawn_downstream`.
+    #[clap(long, env, value_parser = clap::value_parser!(RawFd).range(0..))]
+    trace_context_fd: Option<RawFd>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Join the trace of the pipeline from `--trace-context-fd` or the trace
+    /// file, if any, and add its id to the log output of `tracing`.
+    pub(crate) fn init(&self, tracing: &mut trace::Options) -> EyreResult<()> {
+        let context = match (self.trace_context_fd, env::var_os(TRACE_FILE)) {
+            (Some(fd), _) => {
+                // The descriptor is not passed to children not started by
+                // `spawn_downstream`.
+                env::remove_var(TRACE_CONTEXT_FD);
+                read_fd(fd)?
+            }
+            (None, Some(path)) => join_file(Path::new(&path))?,
+            (None, None) => return Ok(()),
+        };
+        tracing.add_tag("trace_id", context.trace_id());
+        let _ = CONTEXT.set(context);
+        Ok(())
+    }
+}
+
+/// A W3C Trace Context `traceparent` value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceParent {
+    trace_id:  [u8; 16],
+    parent_id: [u8; 8],
+    flags:     u8,
+}
+
+impl TraceParent {
+    /// A new sampled trace.
+    fn new() -> io::Result<Self> {
+        let mut trace_id = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut trace_id)?;
+        Ok(Self {
+            trace_id,
+            parent_id: random_id()?,
+            flags: 1,
+        })
+    }
+
+    /// The same trace with a new parent id.
+    fn child(&self) -> io::Result<Self> {
+        Ok(Self {
+            parent_id: random_id()?,
+            ..*self
+        })
+    }
+
+    /// The trace id in hex, like in log output of OpenTelemetry.
+    #[must_use]
+    pub fn trace_id(&self) -> String {
+        hex::encode(self.trace_id)
+    }
+}
+
+fn random_id() -> io::Result<[u8; 8]> {
+    let mut id = [0; 8];
+    File::open("/dev/urandom")?.read_exact(&mut id)?;
+    Ok(id)
+}
+
+impl FromStr for TraceParent {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [version, trace_id, parent_id, flags] = s.split('-').collect::<Vec<_>>()[..] else {
+            bail!("expected version-trace_id-parent_id-flags, got {s:?}");
+        };
+        if version != "00" {
+            bail!("unsupported traceparent version {version:?}");
+        }
+        let mut context = Self {
+            trace_id:  [0; 16],
+            parent_id: [0; 8],
+            flags:     0,
+        };
+        hex::decode_to_slice(trace_id, &mut context.trace_id).wrap_err("invalid trace id")?;
+        hex::decode_to_slice(parent_id, &mut context.parent_id).wrap_err("invalid parent id")?;
+        let mut flags_byte = [0; 1];
+        hex::decode_to_slice(flags, &mut flags_byte).wrap_err("invalid flags")?;
+        context.flags = flags_byte[0];
+        if context.trace_id == [0; 16] || context.parent_id == [0; 8] {
+            bail!("all zero ids are invalid");
+        }
+        Ok(context)
+    }
+}
+
+impl Display for TraceParent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.parent_id),
+            self.flags
+        )
+    }
+}
+
+/// The trace of the pipeline this process is a stage of, if any.
+pub fn current() -> Option<&'static TraceParent> {
+    CONTEXT.get()
+}
+
+/// Read a `traceparent` line from `fd`.
+///
+/// The descriptor is left open, it may be owned by other code.
+#[allow(unsafe_code)]
+fn read_fd(fd: RawFd) -> EyreResult<TraceParent> {
+    if (0..=2).contains(&fd) {
+        bail!("The trace context fd can not be stdin, stdout or stderr, got {fd}");
+    }
+    // SAFETY: `F_GETFD` only looks the descriptor up.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error())
+            .wrap_err_with(|| format!("The trace context fd {fd} is not open"));
+    }
+    // SAFETY: The descriptor is open and `ManuallyDrop` never closes it.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = String::new();
+    BufReader::new(&*file)
+        .read_line(&mut line)
+        .wrap_err_with(|| format!("Error reading the trace context from fd {fd}"))?;
+    line.trim_end()
+        .parse()
+        .wrap_err_with(|| format!("Invalid trace context on fd {fd}"))
+}
+
+/// Read the trace file at `path`, or create it with a new trace if this is
+/// the first stage.
+fn join_file(path: &Path) -> EyreResult<TraceParent> {
+    let context = TraceParent::new()?;
+    let mut temp = OsString::from(path);
+    temp.push(format!(".{}.tmp", process::id()));
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, format!("{context}\n"))
+        .wrap_err_with(|| format!("Error writing trace file {}", temp.display()))?;
+    // Linking fails if another stage was first.
+    let linked = fs::hard_link(&temp, path);
+    let _ = fs::remove_file(&temp);
+    match linked {
+        Ok(()) => Ok(context),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => fs::read_to_string(path)
+            .wrap_err_with(|| format!("Error reading trace file {}", path.display()))?
+            .trim_end()
+            .parse()
+            .wrap_err_with(|| format!("Invalid trace context in {}", path.display())),
+        Err(err) => {
+            Err(err).wrap_err_with(|| format!("Error creating trace file {}", path.display()))
+        }
+    }
+}
+
+/// The `traceparent` for a downstream stage: the current span with
+/// OpenTelemetry, else the trace of the pipeline, which is started if there
+/// is none yet.
+fn downstream() -> io::Result<TraceParent> {
+    #[cfg(feature = "otlp")]
+    if let Some(traceparent) = trace::current_traceparent() {
+        if let Ok(context) = traceparent.parse() {
+            return Ok(context);
+        }
+    }
+    let mut started = false;
+    let context = CONTEXT.get_or_try_init(|| {
+        started = true;
+        TraceParent::new()
+    })?;
+    if started {
+        info!(trace_id = context.trace_id(), "Started a pipeline trace");
+    }
+    context.child()
+}
+
+/// A close-on-exec pipe, `(reader, writer)`.
+#[allow(unsafe_code)]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: Both descriptors are new and owned here.
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    for fd in fds {
+        // SAFETY: `fd` is open, see above.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader, writer))
+}
+
+/// Spawn `command` as the next stage of the pipeline, in the trace of this
+/// process, see the [module docs](self).
+///
+/// The child reads the trace context with `--trace-context-fd`, it has to be
+/// a cli-batteries program. Use it once per [`Command`].
+///
+/// # Errors
+///
+/// Returns an error if the pipe can not be set up or spawning fails.
+#[allow(unsafe_code)]
+pub fn spawn_downstream(command: &mut Command) -> io::Result<Child> {
+    let context = downstream()?;
+    let (reader, mut writer) = pipe()?;
+    // Fits in the pipe buffer, the child reads it later.
+    writer.write_all(format!("{context}\n").as_bytes())?;
+    drop(writer);
+    let fd = reader.as_raw_fd();
+    command.env(TRACE_CONTEXT_FD, fd.to_string());
+    // SAFETY: `fcntl` is async-signal-safe, the closure does not allocate.
+    unsafe {
+        command.pre_exec(move || {
+            // Inherit the read end, it is close-on-exec.
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_parse() {
+        let context = TRACEPARENT.parse::<TraceParent>().unwrap();
+        assert_eq!(context.trace_id(), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(context.to_string(), TRACEPARENT);
+        let child = context.child().unwrap();
+        assert_eq!(child.trace_id(), context.trace_id());
+        assert_ne!(child, context);
+        for invalid in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-nothexnothexnoth-01",
+            "00-0af7651916cd-b7ad6b7169203331-01",
+        ] {
+            assert!(invalid.parse::<TraceParent>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let context = TraceParent::new().unwrap();
+        assert_eq!(context.to_string().parse::<TraceParent>().unwrap(), context);
+        assert_ne!(TraceParent::new().unwrap().trace_id, context.trace_id);
+    }
+
+    #[test]
+    fn test_join_file() {
+        let dir = env::temp_dir().join(format!("cli-batteries-pipeline-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace");
+        let first = join_file(&path).unwrap();
+        let second = join_file(&path).unwrap();
+        assert_eq!(second, first);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::write(&path, "garbage\n").unwrap();
+        assert!(join_file(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn test_read_fd() {
+        let (reader, mut writer) = pipe().unwrap();
+        writer
+            .write_all(format!("{TRACEPARENT}\n").as_bytes())
+            .unwrap();
+        drop(writer);
+        let fd = reader.as_raw_fd();
+        assert_eq!(read_fd(fd).unwrap().to_string(), TRACEPARENT);
+        // Still open, `reader` closes it.
+        // SAFETY: `F_GETFD` only looks the descriptor up.
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+        drop(reader);
+
+        for fd in [0, 1, 2, RawFd::MAX] {
+            assert!(read_fd(fd).is_err(), "{fd}");
+        }
+    }
+}
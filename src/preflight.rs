@@ -0,0 +1,353 @@
+//! Startup checks of the process environment.
+//!
+//! Warns about a non UTF-8 locale, an unparseable `TZ`, a low open file limit
+//! and environment variables that look like a setting but do not match its
+//! name, see [`config_key`](crate::config_key). By default the soft open file
+//! limit is raised to the hard limit.
+//! Individual checks can be disabled with `--skip-preflight`.
+use crate::{config_key, default_from_clap, Version};
+use clap::{ArgAction, Command, Parser, ValueEnum};
+use std::{
+    env,
+    path::{Component, Path},
+};
+use tracing::warn;
+
+/// Open file limit required by default, see
+/// [`Runner::min_open_files`](crate::Runner::min_open_files).
+pub const DEFAULT_MIN_OPEN_FILES: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Check {
+    /// Locale is UTF-8.
+    Locale,
+    /// `TZ` is a known zone or POSIX time zone.
+    Tz,
+    /// Soft `RLIMIT_NOFILE` meets the requirement.
+    Nofile,
+    /// No environment variable is a near miss of a setting, like
+    /// `log_format` or `LOG-FORMAT` for `LOG_FORMAT`.
+    Env,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Raise the soft open file limit to the hard limit on startup.
+    #[clap(long, env, default_value_t = true, action = ArgAction::Set)]
+    raise_nofile_limit: bool,
+
+    /// Startup checks to skip, comma separated.
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    skip_preflight: Vec<Check>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Run the checks that are not skipped, logging a warning for each
+    /// failing one. The settings are those of `command`.
+    pub fn check(&self, min_open_files: u64, command: &Command, version: &Version) {
+        let skip = |check| self.skip_preflight.contains(&check);
+        if !skip(Check::Locale) {
+            if let Err(message) = locale(|name| env::var(name).ok()) {
+                warn!(check = "locale", "{message}");
+            }
+        }
+        if !skip(Check::Tz) {
+            let zoneinfo = env::var("TZDIR").unwrap_or_else(|_| "/usr/share/zoneinfo".to_owned());
+            if let Err(message) = tz(env::var("TZ").ok().as_deref(), Path::new(&zoneinfo)) {
+                warn!(check = "tz", "{message}");
+            }
+        }
+        if !skip(Check::Nofile) {
+            if let Err(message) = nofile::check(min_open_files, self.raise_nofile_limit) {
+                warn!(check = "nofile", "{message}");
+            }
+        }
+        if !skip(Check::Env) {
+            let known = config_key::known_env(command);
+            let prefixes = [version.crate_name, version.pkg_name];
+            let vars = env::vars_os().filter_map(|(name, _)| name.into_string().ok());
+            for (name, setting) in config_key::near_misses(&known, &prefixes, vars) {
+                warn!(
+                    check = "env",
+                    "Environment variable {name} is ignored, did you mean {setting}?"
+                );
+            }
+        }
+    }
+}
+
+/// Check that the locale selected by `LC_ALL`, `LC_CTYPE` or `LANG` uses
+/// UTF-8.
+fn locale(var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    if cfg!(not(unix)) {
+        return Ok(());
+    }
+    let (name, value) = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .into_iter()
+        .find_map(|name| Some((name, var(name).filter(|value| !value.is_empty())?)))
+        .unwrap_or_else(|| ("LANG", "C".to_owned()));
+    let lower = value.to_ascii_lowercase();
+    if lower.contains("utf-8") || lower.contains("utf8") {
+        Ok(())
+    } else {
+        Err(format!(
+            "Locale {name}={value} is not UTF-8, non-ASCII paths and text may be mishandled. Set \
+             LANG=C.UTF-8 or similar."
+        ))
+    }
+}
+
+/// Check that `TZ`, if set, names a zone in `zoneinfo`, a zone file or a POSIX
+/// time zone such as `EST5EDT,M3.2.0,M11.1.0`.
+fn tz(value: Option<&str>, zoneinfo: &Path) -> Result<(), String> {
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return Ok(());
+    };
+    let name = value.strip_prefix(':').unwrap_or(value);
+    let path = Path::new(name);
+    let file = if path.is_absolute() {
+        path.is_file()
+    } else {
+        path.components().all(|c| matches!(c, Component::Normal(_)))
+            && zoneinfo.join(path).is_file()
+    };
+    if file || (!value.starts_with(':') && posix_tz(value)) {
+        Ok(())
+    } else {
+        Err(format!(
+            "TZ={value} is neither a known time zone nor a POSIX time zone, local times will be \
+             in UTC"
+        ))
+    }
+}
+
+/// Parse the `std offset [dst [offset] [,rule]]` prefix of a POSIX time zone.
+fn posix_tz(value: &str) -> bool {
+    fn name(s: &str) -> Option<&str> {
+        if let Some(rest) = s.strip_prefix('<') {
+            let end = rest.find('>')?;
+            let name = &rest[..end];
+            return (name.len() >= 3
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-'))
+            .then(|| &rest[end + 1..]);
+        }
+        let end = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        (end >= 3).then(|| &s[end..])
+    }
+    fn offset(s: &str) -> Option<&str> {
+        let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(s.len());
+        let mut parts = s[..end].split(':');
+        let hours = parts.next()?.parse::<u8>().ok()?;
+        let valid = hours <= 24
+            && parts.all(|part| part.len() == 2 && part.parse::<u8>().is_ok_and(|n| n < 60));
+        valid.then(|| &s[end..])
+    }
+
+    let Some(rest) = name(value).and_then(offset) else {
+        return false;
+    };
+    if rest.is_empty() || rest.starts_with(',') {
+        return true;
+    }
+    let Some(rest) = name(rest) else {
+        return false;
+    };
+    let rest = if rest.is_empty() || rest.starts_with(',') {
+        rest
+    } else if let Some(rest) = offset(rest) {
+        rest
+    } else {
+        return false;
+    };
+    rest.is_empty() || rest.starts_with(',')
+}
+
+#[cfg(unix)]
+mod nofile {
+    use std::io;
+    use tracing::{info, warn};
+
+    /// Soft and hard `RLIMIT_NOFILE`.
+    #[allow(unsafe_code)]
+    pub fn get() -> io::Result<(u64, u64)> {
+        // SAFETY: `getrlimit` only writes to the provided struct.
+        let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, std::ptr::addr_of_mut!(limit)) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::useless_conversion)] // `rlim_t` is not `u64` everywhere
+        Ok((limit.rlim_cur.into(), limit.rlim_max.into()))
+    }
+
+    /// Set the soft `RLIMIT_NOFILE`.
+    #[allow(unsafe_code)]
+    #[allow(clippy::useless_conversion)] // `rlim_t` is not `u64` everywhere
+    pub fn set(soft: u64) -> io::Result<()> {
+        let (_, hard) = get()?;
+        let limit = libc::rlimit {
+            rlim_cur: soft.try_into().unwrap_or(libc::RLIM_INFINITY),
+            rlim_max: hard.try_into().unwrap_or(libc::RLIM_INFINITY),
+        };
+        // SAFETY: `setrlimit` only reads the provided struct.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, std::ptr::addr_of!(limit)) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Raise the soft limit to the hard limit if `raise`, then check it
+    /// against `required`.
+    pub fn check(required: u64, raise: bool) -> Result<(), String> {
+        let (mut soft, hard) =
+            get().map_err(|err| format!("Could not read the open file limit: {err}"))?;
+        if raise && soft < hard {
+            match set(hard) {
+                Ok(()) => {
+                    info!(old = soft, new = hard, "Raised open file limit");
+                    soft = hard;
+                }
+                Err(err) => {
+                    warn!(%err, old = soft, new = hard, "Could not raise open file limit");
+                }
+            }
+        }
+        if soft < required {
+            return Err(format!(
+                "Open file limit {soft} is below the required {required} (hard limit {hard}), \
+                 raise it with `ulimit -n`"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod nofile {
+    #[allow(clippy::unnecessary_wraps)]
+    pub const fn check(_required: u64, _raise: bool) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::{collections::HashMap, fs};
+    use tracing_test::traced_test;
+
+    #[test]
+    fn test_options() {
+        let options = Options::default();
+        assert!(options.raise_nofile_limit);
+        assert!(options.skip_preflight.is_empty());
+
+        let options = Options::parse_from([
+            "arg0",
+            "--raise-nofile-limit",
+            "false",
+            "--skip-preflight",
+            "locale,tz",
+        ]);
+        assert!(!options.raise_nofile_limit);
+        assert_eq!(options.skip_preflight, vec![Check::Locale, Check::Tz]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_locale() {
+        let check = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect::<HashMap<_, _>>();
+            locale(|name| vars.get(name).cloned())
+        };
+        assert!(check(&[("LANG", "en_US.UTF-8")]).is_ok());
+        assert!(check(&[("LANG", "C"), ("LC_ALL", "C.utf8")]).is_ok());
+        assert!(check(&[("LC_ALL", ""), ("LANG", "de_DE.UTF-8")]).is_ok());
+        assert_eq!(
+            check(&[("LANG", "C.UTF-8"), ("LC_CTYPE", "POSIX")]).unwrap_err(),
+            "Locale LC_CTYPE=POSIX is not UTF-8, non-ASCII paths and text may be mishandled. Set \
+             LANG=C.UTF-8 or similar."
+        );
+        assert!(check(&[])
+            .unwrap_err()
+            .starts_with("Locale LANG=C is not UTF-8"));
+    }
+
+    #[test]
+    fn test_tz() {
+        let zoneinfo = env::temp_dir().join(format!("cli-batteries-tz-{}", std::process::id()));
+        fs::create_dir_all(zoneinfo.join("Europe")).unwrap();
+        fs::write(zoneinfo.join("Europe/Berlin"), b"TZif").unwrap();
+
+        for valid in [
+            "Europe/Berlin",
+            ":Europe/Berlin",
+            "UTC0",
+            "CET-1CEST,M3.5.0,M10.5.0/3",
+            "EST5EDT",
+            "<+0330>-3:30",
+            "NZST-12:00:00NZDT-13:00:00,M10.1.0,M3.3.0",
+        ] {
+            assert_eq!(tz(Some(valid), &zoneinfo), Ok(()), "{valid}");
+        }
+        assert_eq!(tz(None, &zoneinfo), Ok(()));
+        assert_eq!(tz(Some(""), &zoneinfo), Ok(()));
+        for invalid in [
+            "Europe/Atlantis",
+            ":UTC0",
+            "../Europe/Berlin",
+            "UTC",
+            "UT0",
+            "CET-25",
+            "EST5EDT4:6",
+        ] {
+            assert_eq!(
+                tz(Some(invalid), &zoneinfo),
+                Err(format!(
+                    "TZ={invalid} is neither a known time zone nor a POSIX time zone, local times \
+                     will be in UTC"
+                ))
+            );
+        }
+
+        fs::remove_dir_all(zoneinfo).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[traced_test]
+    fn test_raise_nofile() {
+        let (soft, hard) = nofile::get().unwrap();
+        if hard < 512 {
+            return;
+        }
+        nofile::set(256).unwrap();
+
+        let err = nofile::check(hard, false).unwrap_err();
+        assert!(
+            err.starts_with(&format!("Open file limit 256 is below the required {hard}")),
+            "{err}"
+        );
+        assert_eq!(nofile::get().unwrap(), (256, hard));
+
+        nofile::check(hard, true).unwrap();
+        assert_eq!(nofile::get().unwrap(), (hard, hard));
+        assert!(logs_contain(&format!(
+            "Raised open file limit old=256 new={hard}"
+        )));
+
+        nofile::set(soft).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+//! Everything a typical `main.rs` needs, in the versions this crate is built
+//! against.
+//!
+//! ```rust,ignore
+//! use cli_batteries::prelude::*;
+//! ```
+//!
+//! The `clap` derives and `#[instrument]` refer to `clap::` and `tracing::`
+//! paths, which resolve through the crates re-exported here. An app does not
+//! need `clap`, `tracing` or `eyre` as direct dependencies, and can not end up
+//! with a `clap` version that the derives of this crate do not match.
+//!
+//! For qualified access to the crates, see [`reexports`](crate::reexports).
+pub use crate::{
+    default_from_clap, include_log_defaults, run, version, ResultExt, Runner, Version,
+};
+pub use ::eyre::{self, bail, ensure, eyre, Result, WrapErr};
+pub use clap::{self, Args, Parser, ValueEnum};
+pub use tracing::{
+    self, debug, debug_span, error, error_span, info, info_span, instrument, trace, trace_span,
+    warn, warn_span, Instrument,
+};
@@ -0,0 +1,178 @@
+//! `--print-config`: the value and source of every flag of this run.
+//!
+//! Instead of running the application, a JSON object is printed with the
+//! value of each flag and where it came from: `command-line`, `environment`,
+//! `built-in` for the [log defaults](crate::LogDefaults) built into the
+//! binary, or `default`. Values of flags named like one of the [`SECRETS`] are
+//! masked.
+//!
+//! The same object is the `config.json` of a support bundle, and
+//! `--generate-deployment` and `--env-snapshot` read the settings from it.
+use crate::default_from_clap;
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command, Parser};
+use eyre::Result as EyreResult;
+use serde_json::{json, Map, Value};
+use std::env;
+
+/// Environment variables reported by [`environment`], if set.
+pub const ENVIRONMENT: &[&str] = &[
+    "LANG",
+    "LC_ALL",
+    "LC_CTYPE",
+    "TERM",
+    "COLORTERM",
+    "NO_COLOR",
+    "CLICOLOR",
+    "CLICOLOR_FORCE",
+    "TZ",
+    "RUST_BACKTRACE",
+    "RUST_LIB_BACKTRACE",
+    "RUST_LOG",
+    "XDG_STATE_HOME",
+];
+
+/// Flags with one of these in their name have their value masked.
+pub const SECRETS: &[&str] = &["key", "token", "secret", "password", "credential"];
+
+pub const MASK: &str = "***";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Print the value and source of every flag as JSON, with secrets
+    /// masked, and exit.
+    #[clap(long)]
+    print_config: bool,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Whether `--print-config` was given.
+    #[must_use]
+    pub const fn requested(self) -> bool {
+        self.print_config
+    }
+}
+
+/// Print the configuration of the parsed command line, with the `built_in`
+/// values of [`config`].
+///
+/// # Errors
+///
+/// If writing the JSON fails.
+pub fn print(
+    command: &Command,
+    matches: &ArgMatches,
+    built_in: &[(&str, String)],
+) -> EyreResult<()> {
+    let config = config(command, matches, built_in, |_| MASK.to_owned());
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
+
+/// Whether the flag with `id` holds a secret.
+pub fn is_secret(id: &str) -> bool {
+    let id = id.to_ascii_lowercase();
+    SECRETS.iter().any(|secret| id.contains(secret))
+}
+
+/// The value and source of every flag of `command`, secret values replaced by
+/// `mask`. Flags left at their default that have a value in `built_in`, by
+/// argument id, get that value with the source `built-in`.
+pub fn config(
+    command: &Command,
+    matches: &ArgMatches,
+    built_in: &[(&str, String)],
+    mask: fn(&str) -> String,
+) -> Value {
+    let mut config = Map::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Ok(Some(raw)) = matches.try_get_raw(id) else {
+            continue;
+        };
+        let values = raw
+            .map(|value| {
+                let value = value.to_string_lossy();
+                if is_secret(id) {
+                    mask(&value)
+                } else {
+                    value.into_owned()
+                }
+            })
+            .collect::<Vec<_>>();
+        let value = match (arg.get_action(), values.as_slice()) {
+            (ArgAction::Append, _) | (_, []) => json!(values),
+            (_, [value]) => json!(value),
+            (..) => json!(values),
+        };
+        let built_in = built_in.iter().find(|(built_in, _)| *built_in == id);
+        let (value, source) = match (matches.value_source(id), built_in) {
+            (Some(ValueSource::CommandLine), _) => (value, "command-line"),
+            (Some(ValueSource::EnvVariable), _) => (value, "environment"),
+            (_, Some((_, built_in))) => (json!(built_in), "built-in"),
+            _ => (value, "default"),
+        };
+        config.insert(id.to_owned(), json!({ "value": value, "source": source }));
+    }
+    Value::Object(config)
+}
+
+/// The allowed environment variables that are set.
+pub fn environment() -> Value {
+    ENVIRONMENT
+        .iter()
+        .filter_map(|name| {
+            let value = env::var_os(name)?;
+            Some(((*name).to_owned(), value.to_string_lossy().into()))
+        })
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_secret() {
+        assert!(is_secret("api_key"));
+        assert!(is_secret("DB_PASSWORD"));
+        assert!(!is_secret("log_keep"));
+        assert!(!is_secret("log_filter"));
+    }
+
+    #[test]
+    fn test_config() {
+        let command = Command::new("app")
+            .arg(clap::Arg::new("api_token").long("api-token"))
+            .arg(clap::Arg::new("name").long("name").default_value("world"))
+            .arg(clap::Arg::new("tag").long("tag").action(ArgAction::Append))
+            .arg(clap::Arg::new("unset").long("unset"));
+        let matches = command
+            .clone()
+            .try_get_matches_from(["app", "--api-token", "hunter2", "--tag", "a"])
+            .unwrap();
+        assert_eq!(
+            config(&command, &matches, &[], |_| MASK.to_owned()),
+            json!({
+                "api_token": { "value": "***", "source": "command-line" },
+                "name": { "value": "world", "source": "default" },
+                "tag": { "value": ["a"], "source": "command-line" },
+            })
+        );
+
+        // A built-in value replaces the default, not a given value
+        let built_in = [("name", "built".to_owned()), ("tag", "b".to_owned())];
+        let config = config(&command, &matches, &built_in, |_| MASK.to_owned());
+        assert_eq!(
+            config["name"],
+            json!({ "value": "built", "source": "built-in" })
+        );
+        assert_eq!(
+            config["tag"],
+            json!({ "value": ["a"], "source": "command-line" })
+        );
+    }
+}
@@ -1,5 +1,11 @@
 #![cfg(feature = "prometheus")]
-use crate::{default_from_clap, shutdown::await_shutdown};
+use crate::{
+    checks::{self, disable_check, enable_check},
+    config_issue::ConfigIssue,
+    default_from_clap,
+    health::{self, Readiness},
+    report_limit,
+};
 use clap::Parser;
 use eyre::{bail, ensure, Result as EyreResult, WrapErr as _};
 use hyper::{
@@ -13,8 +19,24 @@ use prometheus::{
     opts, register_counter, register_gauge, register_histogram, Counter, Encoder as _, Gauge,
     Histogram,
 };
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::sync::oneshot;
 use tracing::{error, info, instrument, trace};
+
+#[cfg(feature = "tls")]
+use crate::tls::ServerTls;
+#[cfg(feature = "tls")]
+use hyper::server::conn::Http;
+#[cfg(feature = "tls")]
+use std::{path::PathBuf, sync::Arc};
+#[cfg(feature = "tls")]
+use tokio::net::TcpListener;
+#[cfg(feature = "tls")]
+use tracing::debug;
 use url::{Host, Url};
 
 #[cfg(feature = "otlp")]
@@ -30,10 +52,69 @@ pub struct Options {
     // See <https://github.com/prometheus/prometheus/wiki/Default-port-allocations>
     #[clap(long, env, default_value = "http://127.0.0.1:9998/metrics")]
     pub prometheus: Url,
+
+    /// Certificate chain (PEM) to serve metrics over https://
+    #[cfg(feature = "tls")]
+    #[clap(long, env, requires = "metrics_tls_key")]
+    pub metrics_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) to serve metrics over https://
+    #[cfg(feature = "tls")]
+    #[clap(long, env, requires = "metrics_tls_cert")]
+    pub metrics_tls_key: Option<PathBuf>,
 }
 
 default_from_clap!(Options);
 
+impl Options {
+    /// Problems with the endpoint and the certificate files, the same that
+    /// [`main`] fails on.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        #[cfg(feature = "tls")]
+        let tls = self.metrics_tls_cert.is_some() && self.metrics_tls_key.is_some();
+        #[cfg(not(feature = "tls"))]
+        let tls = false;
+        let scheme = if tls { "https" } else { "http" };
+        let url = &self.prometheus;
+        if url.scheme() != scheme {
+            issues.push(ConfigIssue::error(
+                "prometheus",
+                url,
+                format!("only {scheme}:// is supported"),
+            ));
+        }
+        if url.path() != "/metrics" {
+            issues.push(ConfigIssue::error(
+                "prometheus",
+                url,
+                "only the path /metrics is supported",
+            ));
+        }
+        if matches!(url.host(), Some(Host::Domain(_))) {
+            issues.push(
+                ConfigIssue::error("prometheus", url, "can not bind a host name")
+                    .suggest("use an IP address, like 127.0.0.1"),
+            );
+        }
+        #[cfg(feature = "tls")]
+        {
+            use crate::tls;
+            issues.extend(
+                self.metrics_tls_cert
+                    .as_deref()
+                    .and_then(|cert| tls::check_certs("metrics-tls-cert", cert)),
+            );
+            issues.extend(
+                self.metrics_tls_key
+                    .as_deref()
+                    .and_then(|key| tls::check_key("metrics-tls-key", key)),
+            );
+        }
+        issues
+    }
+}
+
 static REQ_COUNTER: Lazy<Counter> = Lazy::new(|| {
     register_counter!(opts!(
         "prometheus_requests_total",
@@ -83,6 +164,95 @@ async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error>
     Ok(response)
 }
 
+/// Plain text body of the health endpoints.
+fn health_body(status: &str, uptime: Duration, version: &str) -> Body {
+    Body::from(format!(
+        "{status}\nuptime_seconds {}\nversion {version}\n",
+        uptime.as_secs()
+    ))
+}
+
+/// Live as long as the server runs.
+fn healthz(uptime: Duration, version: &str) -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .body(health_body("ok", uptime, version))
+        .unwrap()
+}
+
+/// The recent warnings and errors as a JSON array, oldest first.
+fn recent_errors() -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(crate::trace::recent_errors().to_string()))
+        .unwrap()
+}
+
+/// The error report backoff state as a JSON array.
+fn error_reports() -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(report_limit::to_json().to_string()))
+        .unwrap()
+}
+
+/// The known debug checks as a JSON array.
+fn list_checks() -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_string(&checks::checks()).unwrap(),
+        ))
+        .unwrap()
+}
+
+/// `POST /checks/<name>/enable` or `/disable`.
+fn toggle_check(path: &str) -> Response<Body> {
+    let action = path
+        .strip_prefix("/checks/")
+        .and_then(|rest| rest.rsplit_once('/'))
+        .filter(|(name, _)| !name.is_empty());
+    let status = match action {
+        Some((name, "enable")) => {
+            enable_check(name);
+            info!(
+                check = name,
+                "Debug check enabled through the metrics server"
+            );
+            200
+        }
+        Some((name, "disable")) => {
+            disable_check(name);
+            info!(
+                check = name,
+                "Debug check disabled through the metrics server"
+            );
+            200
+        }
+        _ => 404,
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::from(if status == 200 { "ok" } else { "404" }))
+        .unwrap()
+}
+
+/// Only 200 when [`Readiness::Ready`].
+fn readyz(readiness: Readiness, uptime: Duration, version: &str) -> Response<Body> {
+    let status = if readiness == Readiness::Ready {
+        200
+    } else {
+        503
+    };
+    Response::builder()
+        .status(status)
+        .body(health_body(&readiness.to_string(), uptime, version))
+        .unwrap()
+}
+
 #[allow(clippy::unused_async)] // We are implementing an interface
 #[instrument(level="debug", name="prometheus_request", skip(req), fields(http.uri = %req.uri(), http.method = %req.method()))]
 async fn route(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
@@ -95,6 +265,14 @@ async fn route(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
 
     let response = match (req.method(), req.uri().path()) {
         (&Method::GET, "/metrics") => serve_req(req).await?,
+        (&Method::GET, "/healthz") => healthz(health::uptime(), health::version()),
+        (&Method::GET, "/readyz") => {
+            readyz(health::readiness(), health::uptime(), health::version())
+        }
+        (&Method::GET, "/recent-errors") => recent_errors(),
+        (&Method::GET, "/error-reports") => error_reports(),
+        (&Method::GET, "/checks") => list_checks(),
+        (&Method::POST, path) if path.starts_with("/checks/") => toggle_check(path),
         _ => Response::builder()
             .status(404)
             .body(Body::from("404"))
@@ -107,10 +285,28 @@ async fn route(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     Ok(response)
 }
 
-pub async fn main(options: Options) -> EyreResult<()> {
+/// Validate the options and return the metrics server, which runs until
+/// `stop` is sent or dropped.
+///
+/// The server keeps running while the program shuts down, so `/readyz` can
+/// report it and metrics of the shutdown can be scraped.
+pub fn main(
+    options: Options,
+    stop: oneshot::Receiver<()>,
+) -> EyreResult<impl Future<Output = EyreResult<()>>> {
+    #[cfg(feature = "tls")]
+    let tls = match (&options.metrics_tls_cert, &options.metrics_tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(ServerTls::load(cert.clone(), key.clone())?)),
+        _ => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    let tls: Option<()> = None;
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
     ensure!(
-        options.prometheus.scheme() == "http",
-        "Only http:// is supported in {}",
+        options.prometheus.scheme() == scheme,
+        "Only {}:// is supported in {}",
+        scheme,
         options.prometheus
     );
     ensure!(
@@ -127,14 +323,143 @@ pub async fn main(options: Options) -> EyreResult<()> {
     let port = options.prometheus.port().unwrap_or(9998);
     let addr = SocketAddr::new(ip, port);
 
-    let server = Server::try_bind(&addr)
-        .wrap_err("Could not bind Prometheus server port")?
-        .serve(make_service_fn(|_| async {
-            Ok::<_, hyper::Error>(service_fn(route))
-        }))
-        .with_graceful_shutdown(await_shutdown());
-    info!(url = %options.prometheus, "Metrics server listening");
+    let stop = async {
+        let _ = stop.await;
+    };
+    Ok(async move {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = tls {
+            return serve_tls(addr, tls, options.prometheus, stop).await;
+        }
+
+        let server = Server::try_bind(&addr)
+            .wrap_err("Could not bind Prometheus server port")?
+            .serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(service_fn(route))
+            }))
+            .with_graceful_shutdown(stop);
+        info!(url = %options.prometheus, "Metrics server listening");
+
+        server.await?;
+        Ok(())
+    })
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls<F>(addr: SocketAddr, tls: Arc<ServerTls>, url: Url, stop: F) -> EyreResult<()>
+where
+    F: Future<Output = ()>,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .wrap_err("Could not bind Prometheus server port")?;
+    #[cfg(all(unix, feature = "signals"))]
+    crate::tls::reload_on_hangup(tls.clone())?;
+    info!(%url, "Metrics server listening");
 
-    server.await?;
+    tokio::pin!(stop);
+    loop {
+        let (stream, peer) = tokio::select! {
+            () = &mut stop => break,
+            accepted = listener.accept() => accepted?,
+        };
+        let acceptor = tls.acceptor();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(%peer, %err, "TLS handshake failed");
+                    return;
+                }
+            };
+            if let Err(err) = Http::new()
+                .serve_connection(stream, service_fn(route))
+                .await
+            {
+                debug!(%peer, %err, "Error serving metrics connection");
+            }
+        });
+    }
     Ok(())
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    async fn text(response: Response<Body>) -> (u16, String) {
+        let status = response.status().as_u16();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let response = healthz(Duration::from_millis(61_500), "1.2.3");
+        assert_eq!(
+            text(response).await,
+            (200, "ok\nuptime_seconds 61\nversion 1.2.3\n".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checks() {
+        let (status, _) = text(toggle_check("/checks/test-metrics/enable")).await;
+        assert_eq!(status, 200);
+        let (status, body) = text(list_checks()).await;
+        assert_eq!(status, 200);
+        assert!(
+            body.contains(r#"{"name":"test-metrics","description":null,"enabled":true}"#),
+            "{body}"
+        );
+        let (status, _) = text(toggle_check("/checks/test-metrics/disable")).await;
+        assert_eq!(status, 200);
+        let (_, body) = text(list_checks()).await;
+        assert!(
+            body.contains(r#"{"name":"test-metrics","description":null,"enabled":false}"#),
+            "{body}"
+        );
+        for path in [
+            "/checks/test-metrics",
+            "/checks//enable",
+            "/checks/x/restart",
+        ] {
+            assert_eq!(text(toggle_check(path)).await.0, 404, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_reports() {
+        let report = eyre::eyre!("upstream down");
+        assert!(report_limit::admit("src/prometheus.rs:test", &report).is_some());
+        assert!(report_limit::admit("src/prometheus.rs:test", &report).is_none());
+        let (status, body) = text(error_reports()).await;
+        assert_eq!(status, 200);
+        assert!(
+            body.contains(r#""callsite":"src/prometheus.rs:test""#),
+            "{body}"
+        );
+        assert!(
+            body.contains(r#""occurrences_since_last_report":1"#),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let uptime = Duration::from_secs(3);
+        for (readiness, status) in [
+            (Readiness::Starting, 503),
+            (Readiness::Ready, 200),
+            (Readiness::ShuttingDown, 503),
+        ] {
+            let (actual, body) = text(readyz(readiness, uptime, "1.2.3")).await;
+            assert_eq!(actual, status, "{readiness}");
+            assert_eq!(
+                body,
+                format!("{readiness}\nuptime_seconds 3\nversion 1.2.3\n")
+            );
+        }
+    }
+}
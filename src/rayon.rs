@@ -1,9 +1,50 @@
+//! Span context for [`rayon`](::rayon) worker threads.
+//!
+//! Work in a rayon pool runs on worker threads that have no current span, so
+//! events from a parallel section are not correlated with the request that
+//! started it. Wrap the section in [`in_span_scope`] to have its events
+//! parented to the span that is current where it is called:
+//!
+//! ```rust,ignore
+//! use cli_batteries::rayon::in_span_scope;
+//! use rayon::prelude::*;
+//!
+//! let sizes = in_span_scope(|| files.par_iter().map(|file| resize(file)).collect::<Vec<_>>());
+//! ```
+//!
+//! Rayon has no per-job hooks, so the span is entered on every worker of the
+//! current pool for the duration of the section. Sections started outside the
+//! pool run one at a time, and parallel work started elsewhere in the
+//! meantime shows up under the section's span as well. Nested sections,
+//! started from a worker thread, only enter their span on that thread.
+//!
+//! [`run`](crate::run) builds the global pool with `--threads` workers named
+//! `{crate}-rayon-{n}`. Without `run`, call [`install_span_propagation`] to
+//! build it.
 #![cfg(feature = "rayon")]
 use crate::default_from_clap;
+use ::rayon::{current_thread_index, ThreadPoolBuilder};
 use clap::Parser;
 use eyre::{Result, WrapErr};
-use rayon::ThreadPoolBuilder;
-use tracing::info;
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    sync::{Mutex, PoisonError},
+};
+use tracing::{
+    dispatcher::{self, DefaultGuard},
+    info,
+    span::EnteredSpan,
+    Span,
+};
+
+/// Held while a section started outside the pool runs.
+static SECTION: Lazy<Mutex<()>> = Lazy::new(Mutex::default);
+
+thread_local! {
+    /// The span and dispatcher of the section on a worker thread.
+    static WORKER_SCOPE: RefCell<Option<(EnteredSpan, DefaultGuard)>> = const { RefCell::new(None) };
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
 #[group(skip)]
@@ -16,18 +57,66 @@ pub struct Options {
 default_from_clap!(Options);
 
 impl Options {
-    pub fn init(&self) -> Result<()> {
-        let num_cpus = num_cpus::get();
-        let threads = self.threads.unwrap_or(num_cpus);
-        ThreadPoolBuilder::new()
-            .num_threads(threads)
-            .build_global()
-            .wrap_err("Failed to build thread pool.")?;
-        info!(
-            "Using {} compute threads on {} cores",
-            rayon::current_num_threads(),
-            num_cpus
-        );
-        Ok(())
+    pub fn init(&self, crate_name: &str) -> Result<()> {
+        build_global(crate_name, self.threads)
+    }
+}
+
+/// Build the global rayon pool with worker threads named
+/// `{crate_name}-rayon-{n}`, for [`in_span_scope`]. [`run`](crate::run) does
+/// this already, honoring `--threads`.
+///
+/// # Errors
+///
+/// Returns an error if the global pool was already built.
+pub fn install_span_propagation(crate_name: &str) -> Result<()> {
+    build_global(crate_name, None)
+}
+
+fn build_global(crate_name: &str, threads: Option<usize>) -> Result<()> {
+    let num_cpus = num_cpus::get();
+    let threads = threads.unwrap_or(num_cpus);
+    let crate_name = crate_name.to_owned();
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |index| format!("{crate_name}-rayon-{index}"))
+        .build_global()
+        .wrap_err("Failed to build thread pool.")?;
+    info!(
+        "Using {} compute threads on {} cores",
+        ::rayon::current_num_threads(),
+        num_cpus
+    );
+    Ok(())
+}
+
+/// Run `section` with the current span entered on the workers of the current
+/// rayon pool, see the [module docs](self).
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn in_span_scope<F, R>(section: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let span = Span::current();
+    if current_thread_index().is_some() {
+        // Nested in another section, whose span the other workers have.
+        return span.in_scope(section);
+    }
+    let _section = SECTION.lock().unwrap_or_else(PoisonError::into_inner);
+    let dispatch = dispatcher::get_default(Clone::clone);
+    ::rayon::broadcast(|_| {
+        let scope = (span.clone().entered(), dispatcher::set_default(&dispatch));
+        WORKER_SCOPE.with(|worker| *worker.borrow_mut() = Some(scope));
+    });
+    let _leave = LeaveWorkers;
+    section()
+}
+
+/// Leaves the section on the workers, also when it panics.
+struct LeaveWorkers;
+
+impl Drop for LeaveWorkers {
+    fn drop(&mut self) {
+        ::rayon::broadcast(|_| WORKER_SCOPE.with(|worker| drop(worker.borrow_mut().take())));
     }
 }
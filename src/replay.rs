@@ -0,0 +1,18 @@
+//! Recording events and replaying them through other log options.
+//!
+//! With `--record-events <path>` the spans and events that pass the log
+//! filter are written to a file, with the parents and lifecycle of the spans.
+//! `--replay <path>` sends them through the log output configured by the other
+//! log options and exits, to try another `--log-format` or `--log-filter` on
+//! real events:
+//!
+//! ```shell
+//! $ my-app --record-events events.bin
+//! $ my-app --replay events.bin --log-format json --log-filter my_app=debug
+//! ```
+//!
+//! In tests, [`play`] replays a recording into a writer, and a [`Recorder`]
+//! layer records without the command line.
+#![cfg(feature = "binary-log")]
+
+pub use crate::trace::{play, Recorder};
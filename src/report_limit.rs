@@ -0,0 +1,285 @@
+//! Backoff for repeated identical error reports.
+//!
+//! When a dependency is down, every request fails the same way, and a sink
+//! forwarding each error to an external service like Sentry or a pager gets
+//! rate limited when it matters most. Such sinks ask [`admit`] before they
+//! send a report.
+//!
+//! Reports are grouped by a [`Fingerprint`] of the callsite and the types in
+//! the error chain. The first occurrence of a fingerprint is forwarded, then
+//! the 10th, the 100th and so on, each with the number of occurrences since
+//! the previous forwarded one as `occurrences_since_last_report`. After
+//! [`QUIET_PERIOD`] without an occurrence the schedule starts over.
+//!
+//! Error types are recognized like `error.root_cause_type` of the `main`
+//! span, layers of other types only count by their position in the chain. At
+//! most [`MAX_FINGERPRINTS`] are tracked, the least recently seen is
+//! forgotten first. With the `prometheus` feature the state is served as JSON
+//! on `/error-reports` of the metrics server.
+use crate::exit_hint::error_type;
+use eyre::Report;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{self, Display, Formatter},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Time without an occurrence after which a fingerprint is reported again
+/// right away.
+pub const QUIET_PERIOD: Duration = Duration::from_mins(10);
+
+/// Maximum number of fingerprints tracked.
+pub const MAX_FINGERPRINTS: usize = 1024;
+
+/// Factor between the occurrences that are forwarded.
+const BACKOFF: u64 = 10;
+
+/// The limiter shared by all sinks.
+static LIMITER: Lazy<ReportLimiter> = Lazy::new(|| ReportLimiter::new(QUIET_PERIOD));
+
+/// Identifies reports that are the same error from the same place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    /// Of `report`, raised at `callsite`, like `src/db.rs:42`.
+    #[must_use]
+    pub fn new(callsite: &str, report: &Report) -> Self {
+        let mut hasher = DefaultHasher::new();
+        callsite.hash(&mut hasher);
+        for err in report.chain() {
+            error_type(err).hash(&mut hasher);
+        }
+        Self(hasher.finish())
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A report to forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Admission {
+    pub fingerprint:                   Fingerprint,
+    /// Occurrences since the previous forwarded report, including this one.
+    pub occurrences_since_last_report: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    callsite:      String,
+    /// Occurrences since the schedule started.
+    occurrences:   u64,
+    /// Occurrences since the previous forwarded report.
+    since_report:  u64,
+    /// The occurrence that is forwarded next.
+    next_report:   u64,
+    last_seen:     Instant,
+    last_reported: Instant,
+}
+
+/// Per fingerprint backoff, see the [module docs](self).
+#[derive(Debug)]
+pub struct ReportLimiter {
+    quiet:   Duration,
+    entries: Mutex<HashMap<Fingerprint, Entry>>,
+}
+
+impl ReportLimiter {
+    /// A limiter starting over after `quiet` without an occurrence.
+    #[must_use]
+    pub fn new(quiet: Duration) -> Self {
+        Self {
+            quiet,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Count an occurrence of `report` at `callsite`, returns whether to
+    /// forward it.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn admit(&self, callsite: &str, report: &Report) -> Option<Admission> {
+        let fingerprint = Fingerprint::new(callsite, report);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&fingerprint) && entries.len() >= MAX_FINGERPRINTS {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(fingerprint, _)| *fingerprint);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let entry = entries.entry(fingerprint).or_insert_with(|| Entry {
+            callsite:      callsite.to_owned(),
+            occurrences:   0,
+            since_report:  0,
+            next_report:   1,
+            last_seen:     now,
+            last_reported: now,
+        });
+        if now.duration_since(entry.last_seen) >= self.quiet {
+            entry.occurrences = 0;
+            entry.next_report = 1;
+        }
+        entry.occurrences += 1;
+        entry.since_report += 1;
+        entry.last_seen = now;
+        if entry.occurrences < entry.next_report {
+            return None;
+        }
+        let admission = Admission {
+            fingerprint,
+            occurrences_since_last_report: entry.since_report,
+        };
+        entry.next_report = entry.next_report.saturating_mul(BACKOFF);
+        entry.since_report = 0;
+        entry.last_reported = now;
+        drop(entries);
+        Some(admission)
+    }
+
+    /// The tracked fingerprints as a JSON array, most recently seen first.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn to_json(&self) -> Value {
+        let now = Instant::now();
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fingerprint, entry)| {
+                let state = json!({
+                    "fingerprint": fingerprint.to_string(),
+                    "callsite": entry.callsite,
+                    "occurrences": entry.occurrences,
+                    "occurrences_since_last_report": entry.since_report,
+                    "next_report": entry.next_report,
+                    "seconds_since_seen": now.duration_since(entry.last_seen).as_secs(),
+                    "seconds_since_reported": now.duration_since(entry.last_reported).as_secs(),
+                    "quiet": now.duration_since(entry.last_seen) >= self.quiet,
+                });
+                (entry.last_seen, state)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(last_seen, _)| Reverse(*last_seen));
+        entries.into_iter().map(|(_, state)| state).collect()
+    }
+}
+
+/// Count an occurrence of `report` at `callsite` with the shared limiter,
+/// returns whether to forward it.
+pub fn admit(callsite: &str, report: &Report) -> Option<Admission> {
+    LIMITER.admit(callsite, report)
+}
+
+/// The state of the shared limiter, see [`ReportLimiter::to_json`].
+pub fn to_json() -> Value {
+    LIMITER.to_json()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use eyre::{eyre, WrapErr as _};
+    use std::io;
+    use tokio::time::advance;
+
+    fn io_error() -> Report {
+        Report::new(io::Error::from(io::ErrorKind::ConnectionRefused)).wrap_err("query failed")
+    }
+
+    /// The occurrences among the first `n` that are forwarded, with their
+    /// counts.
+    fn forwarded(limiter: &ReportLimiter, n: u64) -> Vec<(u64, u64)> {
+        let report = io_error();
+        (1..=n)
+            .filter_map(|occurrence| {
+                let admission = limiter.admit("src/db.rs:42", &report)?;
+                Some((occurrence, admission.occurrences_since_last_report))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = Fingerprint::new("src/db.rs:42", &io_error());
+        // Messages do not matter, types and the callsite do.
+        let other_message = Report::new(io::Error::from(io::ErrorKind::TimedOut))
+            .wrap_err("query for user 7 failed");
+        assert_eq!(
+            Fingerprint::new("src/db.rs:42", &other_message),
+            fingerprint
+        );
+        assert_ne!(Fingerprint::new("src/db.rs:43", &io_error()), fingerprint);
+        assert_ne!(
+            Fingerprint::new("src/db.rs:42", &eyre!("query failed")),
+            fingerprint
+        );
+        let parse = "x".parse::<u32>().wrap_err("query failed").unwrap_err();
+        assert_ne!(Fingerprint::new("src/db.rs:42", &parse), fingerprint);
+        assert_eq!(fingerprint.to_string().len(), 16);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff() {
+        let limiter = ReportLimiter::new(QUIET_PERIOD);
+        assert_eq!(forwarded(&limiter, 2000), vec![
+            (1, 1),
+            (10, 9),
+            (100, 90),
+            (1000, 900)
+        ]);
+        // Occurrences keep the schedule going while they are not quiet.
+        advance(Duration::from_secs(599)).await;
+        assert_eq!(forwarded(&limiter, 8000), vec![(8000, 9000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quiet_period() {
+        let limiter = ReportLimiter::new(QUIET_PERIOD);
+        assert_eq!(forwarded(&limiter, 50), vec![(1, 1), (10, 9)]);
+        advance(QUIET_PERIOD).await;
+        // The count includes the occurrences suppressed before the quiet
+        // period.
+        assert_eq!(forwarded(&limiter, 10), vec![(1, 41), (10, 9)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_to_json() {
+        let limiter = ReportLimiter::new(QUIET_PERIOD);
+        forwarded(&limiter, 12);
+        advance(Duration::from_secs(5)).await;
+        let state = limiter.to_json();
+        assert_eq!(state[0]["callsite"], "src/db.rs:42");
+        assert_eq!(state[0]["occurrences"], 12);
+        assert_eq!(state[0]["occurrences_since_last_report"], 2);
+        assert_eq!(state[0]["next_report"], 100);
+        assert_eq!(state[0]["seconds_since_seen"], 5);
+        assert_eq!(state[0]["quiet"], false);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_fingerprints() {
+        let limiter = ReportLimiter::new(QUIET_PERIOD);
+        let report = io_error();
+        for i in 0..=MAX_FINGERPRINTS {
+            assert!(limiter.admit(&format!("src/db.rs:{i}"), &report).is_some());
+            advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(limiter.entries.lock().unwrap().len(), MAX_FINGERPRINTS);
+        // The least recently seen was forgotten, it is reported again.
+        assert!(limiter.admit("src/db.rs:0", &report).is_some());
+        assert!(limiter.admit("src/db.rs:2", &report).is_none());
+    }
+}
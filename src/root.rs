@@ -0,0 +1,170 @@
+use crate::i18n;
+use clap::Parser;
+use eyre::{bail, Result as EyreResult};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Do not warn when running as root (or elevated on Windows).
+    #[clap(long, env)]
+    allow_root: bool,
+}
+
+/// How the application expects to be run, see
+/// [`Runner::expect_root`](crate::Runner::expect_root).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Hash)]
+pub struct Policy {
+    pub expect:  bool,
+    pub require: bool,
+}
+
+/// Source of the current process privileges. Abstracted so the policy can be
+/// tested without switching users.
+pub trait Privileges {
+    /// Returns `true` if running with euid 0 or an elevated Windows token.
+    fn is_elevated(&self) -> bool;
+}
+
+/// Privileges of the current process.
+pub struct Process;
+
+impl Privileges for Process {
+    #[cfg(unix)]
+    fn is_elevated(&self) -> bool {
+        users::get_effective_uid() == 0
+    }
+
+    #[cfg(windows)]
+    #[allow(unsafe_code)]
+    fn is_elevated(&self) -> bool {
+        use std::mem::size_of;
+        use windows_sys::Win32::{
+            Foundation::CloseHandle,
+            Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+            System::Threading::{GetCurrentProcess, OpenProcessToken},
+        };
+
+        let mut token = 0;
+        let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+        let mut size = 0;
+        // SAFETY: Plain Win32 calls with correctly sized out-parameters.
+        unsafe {
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+                return false;
+            }
+            let ok = GetTokenInformation(
+                token,
+                TokenElevation,
+                std::ptr::addr_of_mut!(elevation).cast(),
+                size_of::<TOKEN_ELEVATION>() as u32,
+                &mut size,
+            );
+            CloseHandle(token);
+            ok != 0 && elevation.TokenIsElevated != 0
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn is_elevated(&self) -> bool {
+        false
+    }
+}
+
+impl Options {
+    /// Check the privileges of the process against the policy.
+    ///
+    /// Warns when running as root unexpectedly, and fails when root is
+    /// required but the process does not have it.
+    pub fn check(self, policy: Policy, privileges: &impl Privileges) -> EyreResult<()> {
+        let elevated = privileges.is_elevated();
+        if policy.require && !elevated {
+            bail!(i18n::text("root-required", &[]));
+        }
+        if elevated {
+            if policy.expect || policy.require || self.allow_root {
+                info!(euid = 0, "Running as root");
+            } else {
+                warn!(
+                    euid = 0,
+                    "Running as root. This is usually unnecessary, pass --allow-root to silence \
+                     this warning."
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing_test::traced_test;
+
+    struct Mock(bool);
+
+    impl Privileges for Mock {
+        fn is_elevated(&self) -> bool {
+            self.0
+        }
+    }
+
+    const DEFAULT: Policy = Policy {
+        expect:  false,
+        require: false,
+    };
+    const EXPECT: Policy = Policy {
+        expect:  true,
+        require: false,
+    };
+    const REQUIRE: Policy = Policy {
+        expect:  true,
+        require: true,
+    };
+
+    fn options(args: &[&str]) -> Options {
+        Options::try_parse_from(std::iter::once("arg0").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_warn_as_root() {
+        options(&[]).check(DEFAULT, &Mock(true)).unwrap();
+        assert!(logs_contain("Running as root. This is usually unnecessary"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_allow_root() {
+        options(&["--allow-root"])
+            .check(DEFAULT, &Mock(true))
+            .unwrap();
+        assert!(!logs_contain("usually unnecessary"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_expect_root() {
+        options(&[]).check(EXPECT, &Mock(true)).unwrap();
+        assert!(!logs_contain("usually unnecessary"));
+        options(&[]).check(EXPECT, &Mock(false)).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_require_root() {
+        options(&[]).check(REQUIRE, &Mock(true)).unwrap();
+        assert!(!logs_contain("usually unnecessary"));
+        let err = options(&["--allow-root"])
+            .check(REQUIRE, &Mock(false))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "This program must be run as root");
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_not_root() {
+        options(&[]).check(DEFAULT, &Mock(false)).unwrap();
+        assert!(!logs_contain("root"));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    default_verbosity,
+    explain::Explanation,
+    memory_guard, phase, preflight, root, trace,
+    trace::{ErrorReport, VerbosityMap},
+    LogDefaults, Version,
+};
+use clap::Args;
+use eyre::Report;
+use std::{
+    any::type_name,
+    error::Error,
+    fmt::{self, Debug, Formatter},
+    future::Future,
+    sync::Arc,
+};
+use tracing::error;
+use tracing_subscriber::{filter::LevelFilter, Layer, Registry};
+
+#[cfg(feature = "minimal")]
+use crate::minimal::run_fallible;
+#[cfg(not(feature = "minimal"))]
+use crate::run_fallible;
+#[cfg(feature = "watch")]
+use crate::watch;
+
+/// Builder to customize how the program is run.
+///
+/// [`run`](crate::run) is a shorthand for `Runner::new(version).run(app)`.
+pub struct Runner {
+    pub(crate) version:        Version,
+    pub(crate) verbosity_map:  VerbosityMap,
+    pub(crate) log_defaults:   LogDefaults,
+    pub(crate) root:           root::Policy,
+    pub(crate) min_open_files: u64,
+    pub(crate) local:          bool,
+    pub(crate) help_examples:  Vec<(String, String)>,
+    pub(crate) layers:         Vec<LayerFactory>,
+    pub(crate) checks:         Vec<(&'static str, &'static str)>,
+    pub(crate) explanations:   Vec<(&'static str, Explanation)>,
+}
+
+/// Creates a layer added with [`Runner::layer`].
+#[derive(Clone)]
+pub struct LayerFactory {
+    name: &'static str,
+    make: Arc<dyn Fn() -> Box<dyn Layer<Registry> + Send + Sync> + Send + Sync>,
+}
+
+impl Debug for Runner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Runner")
+            .field("version", &self.version)
+            .field("log_defaults", &self.log_defaults)
+            .field("root", &self.root)
+            .field("min_open_files", &self.min_open_files)
+            .field("local", &self.local)
+            .field("help_examples", &self.help_examples)
+            .field("layers", &self.layers)
+            .field("checks", &self.checks)
+            .field("explanations", &self.explanations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Debug for LayerFactory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl LayerFactory {
+    pub fn make(&self) -> trace::UserLayer {
+        (self.name, (self.make)())
+    }
+}
+
+impl Runner {
+    #[must_use]
+    pub fn new(version: Version) -> Self {
+        Self {
+            version,
+            verbosity_map: Box::new(default_verbosity),
+            log_defaults: LogDefaults::NONE,
+            root: root::Policy::default(),
+            min_open_files: preflight::DEFAULT_MIN_OPEN_FILES,
+            local: false,
+            help_examples: Vec::new(),
+            layers: Vec::new(),
+            checks: Vec::new(),
+            explanations: Vec::new(),
+        }
+    }
+
+    /// The program legitimately runs as root, do not warn when it does.
+    #[must_use]
+    pub const fn expect_root(mut self, expect: bool) -> Self {
+        self.root.expect = expect;
+        self
+    }
+
+    /// The program needs to run as root, fail at startup when it does not.
+    #[must_use]
+    pub const fn require_root(mut self, require: bool) -> Self {
+        self.root.require = require;
+        self
+    }
+
+    /// Warn at startup when the open file limit is below `count`, after
+    /// raising it to the hard limit (unless `--raise-nofile-limit false`).
+    /// Defaults to 4096.
+    #[must_use]
+    pub const fn min_open_files(mut self, count: u64) -> Self {
+        self.min_open_files = count;
+        self
+    }
+
+    /// Run the app future inside a [`LocalSet`](tokio::task::LocalSet), so it
+    /// can use [`spawn_local`](tokio::task::spawn_local) for `!Send` tasks.
+    ///
+    /// The app future and its local tasks run on the main thread, while the
+    /// heartbeat, signal handling and other background tasks keep running on
+    /// the worker threads. Shutdown works the same from local tasks. With
+    /// `tokio-console`, local tasks are listed but are all polled on the main
+    /// thread instead of being spread over the workers.
+    #[must_use]
+    pub const fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    /// Set the levels for each `--verbose` and `--quiet` count, instead of
+    /// [`default_verbosity`](crate::default_verbosity). `map` gets the number
+    /// of `-v` minus the number of `-q` and returns the level of all targets
+    /// and the level of the app crates, like
+    ///
+    /// ```rust,ignore
+    /// // Dependencies stay at INFO, however verbose
+    /// Runner::new(version!()).verbosity_map(|verbosity| {
+    ///     let (all, app) = default_verbosity(verbosity);
+    ///     (all.min(LevelFilter::INFO), app)
+    /// })
+    /// .run(app);
+    /// ```
+    #[must_use]
+    pub fn verbosity_map<F>(mut self, map: F) -> Self
+    where
+        F: Fn(i16) -> (LevelFilter, LevelFilter) + Send + Sync + 'static,
+    {
+        self.verbosity_map = Box::new(map);
+        self
+    }
+
+    /// Use the log defaults built into the binary by
+    /// [`build_rs`](crate::build_rs) from `log-defaults.toml`, the
+    /// `LOG_DEFAULTS` of
+    /// [`include_log_defaults!`](crate::include_log_defaults).
+    #[must_use]
+    pub const fn log_defaults(mut self, defaults: LogDefaults) -> Self {
+        self.log_defaults = defaults;
+        self
+    }
+
+    /// Add targets that `--verbose` and `--quiet` set the level of, like the
+    /// crates given to [`version!`](crate::version). Also logged in full to
+    /// the session log.
+    #[must_use]
+    pub fn app_targets<I, T>(mut self, targets: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.version
+            .app_crates
+            .extend(targets.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add an example to the examples section of `--help`, after the ones
+    /// for the compiled in features.
+    #[must_use]
+    pub fn help_example(mut self, description: &str, command: &str) -> Self {
+        self.help_examples
+            .push((description.to_owned(), command.to_owned()));
+        self
+    }
+
+    /// Add a [`Layer`] to the tracing stack, created by `make` when logging
+    /// starts. It sees all spans and events, unfiltered.
+    ///
+    /// If the layer panics, it is disabled for the rest of the process and an
+    /// alert is written to stderr. The other layers keep working.
+    #[must_use]
+    pub fn layer<L, F>(mut self, make: F) -> Self
+    where
+        L: Layer<Registry> + Send + Sync + 'static,
+        F: Fn() -> L + Send + Sync + 'static,
+    {
+        self.layers.push(LayerFactory {
+            name: type_name::<L>(),
+            make: Arc::new(move || Box::new(make())),
+        });
+        self
+    }
+
+    /// Declare a [`debug_check!`](crate::debug_check), so `--list-checks`
+    /// shows it and it is known before it first runs.
+    #[must_use]
+    pub fn check(mut self, name: &'static str, description: &'static str) -> Self {
+        self.checks.push((name, description));
+        self
+    }
+
+    /// Add the longer documentation `--explain` shows for the app flag
+    /// `flag`, its long name without dashes, like
+    ///
+    /// ```rust,ignore
+    /// Runner::new(version!())
+    ///     .explain("input", Explanation {
+    ///         details:  "A CSV file with a header line, '-' for stdin.",
+    ///         examples: &["{bin} --input data.csv"],
+    ///         related:  &["delimiter"],
+    ///     })
+    ///     .run(app);
+    /// ```
+    #[must_use]
+    pub fn explain(mut self, flag: &'static str, explanation: Explanation) -> Self {
+        self.explanations.push((flag, explanation));
+        self
+    }
+
+    /// Run the program.
+    pub fn run<A, O, F, E>(self, app: A)
+    where
+        A: FnOnce(O) -> F,
+        O: Args,
+        F: Future<Output = Result<(), E>>,
+        E: Into<Report> + Send + Sync + 'static,
+    {
+        if let Err(report) = run_fallible(&self, app) {
+            // The message is the full report, the field its parts
+            let error = ErrorReport(report);
+            error!(error = &error as &dyn Error, "{error:?}");
+            phase::log_unfinished();
+            trace::log_session_log_path();
+            trace::log_trace_url();
+            trace::log_summary();
+            error!("Program terminating abnormally");
+            trace::finish_webhook();
+            trace::finish_session_log();
+            trace::flush_log_output();
+            trace::report_late_events();
+            std::process::exit(if memory_guard::hard_limit_exceeded() {
+                memory_guard::EXIT_CODE
+            } else {
+                1
+            });
+        }
+        trace::finish_session_log();
+        trace::report_late_events();
+    }
+
+    /// Run the program, and with `--watch` run the app again whenever a
+    /// watched file changes, for development.
+    ///
+    /// Without `--watch` this is the same as [`run`](Self::run). With it, the
+    /// app options are parsed again for every run after the first, and
+    /// [`await_shutdown`](crate::await_shutdown) resolves when the current run
+    /// is stopped for a restart.
+    #[cfg(feature = "watch")]
+    pub fn run_watched<A, O, F, E>(self, app: A)
+    where
+        A: Fn(O) -> F,
+        O: Args,
+        F: Future<Output = Result<(), E>>,
+        E: Into<Report> + Send + Sync + 'static,
+    {
+        let version = self.version.clone();
+        self.run(move |options| watch::main(version, app, options));
+    }
+}
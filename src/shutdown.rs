@@ -6,13 +6,19 @@ use eyre::Result as EyreResult;
 #[cfg(feature = "signals")]
 use tracing::{error, info};
 
+/// Shutdown of the process.
 static NOTIFY: Lazy<(Sender<bool>, Receiver<bool>)> = Lazy::new(|| watch::channel(false));
 
+/// Shutdown of the app. Follows [`NOTIFY`], and in watch mode is also set to
+/// stop the current run and cleared before the next one.
+static RUN: Lazy<(Sender<bool>, Receiver<bool>)> = Lazy::new(|| watch::channel(false));
+
 /// Send the signal to shutdown the program.
 #[allow(clippy::missing_panics_doc)] // Never panics
 pub fn shutdown() {
-    // Does not fail because the channel never closes.
+    // Does not fail because the channels never close.
     NOTIFY.0.send(true).unwrap();
+    RUN.0.send(true).unwrap();
 }
 
 /// Reset the shutdown signal so it can be triggered again.
@@ -23,29 +29,69 @@ pub fn shutdown() {
 #[allow(clippy::missing_panics_doc)] // Never panics
 #[allow(clippy::module_name_repetitions)] // Never panics
 pub fn reset_shutdown() {
-    // Does not fail because the channel never closes.
+    // Does not fail because the channels never close.
     NOTIFY.0.send(false).unwrap();
+    RUN.0.send(false).unwrap();
 }
 
 /// Are we currently shutting down?
+///
+/// With `--watch`, also while the current run of the app is being stopped
+/// for a restart.
 #[must_use]
 pub fn is_shutting_down() -> bool {
-    *NOTIFY.1.borrow()
+    *RUN.1.borrow()
 }
 
 /// Wait for the program to shutdown.
 ///
-/// Resolves immediately if the program is already shutting down.
+/// Resolves immediately if the program is already shutting down. With
+/// `--watch`, also resolves when the current run of the app is being stopped
+/// for a restart.
 /// The resulting future is safe to cancel by dropping.
 #[allow(clippy::module_name_repetitions)]
-#[allow(clippy::missing_panics_doc)]
 pub async fn await_shutdown() {
-    let mut watch = NOTIFY.1.clone();
-    if *watch.borrow_and_update() {
-        return;
+    wait(&mut receiver()).await;
+}
+
+/// The shutdown signal, for futures that wait on it repeatedly with [`wait`].
+pub fn receiver() -> Receiver<bool> {
+    RUN.1.clone()
+}
+
+/// Whether the process is shutting down, for the tasks that outlive the runs
+/// of the app in watch mode.
+pub fn is_process_shutting_down() -> bool {
+    *NOTIFY.1.borrow()
+}
+
+/// The process shutdown signal, see [`is_process_shutting_down`].
+pub fn process_receiver() -> Receiver<bool> {
+    NOTIFY.1.clone()
+}
+
+/// Stop the current run of the app.
+#[cfg(feature = "watch")]
+pub fn stop_run() {
+    RUN.0.send_replace(true);
+}
+
+/// Clear the signal of the last run, unless the process is shutting down.
+#[cfg(feature = "watch")]
+pub fn start_run() {
+    // `shutdown` sets `NOTIFY` first, so it is not lost in between.
+    RUN.0
+        .send_modify(|stopped| *stopped = is_process_shutting_down());
+}
+
+/// Wait until `receiver` signals shutdown. Never resolves if the sender is
+/// gone without signalling.
+pub async fn wait(receiver: &mut Receiver<bool>) {
+    while !*receiver.borrow_and_update() {
+        if receiver.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
     }
-    // Does not fail because the channel never closes.
-    watch.changed().await.unwrap();
 }
 
 #[cfg(feature = "signals")]
@@ -0,0 +1,463 @@
+//! `--support-bundle <path>.tar.gz`: what a bug report needs, in one file.
+//!
+//! Instead of running the application, the program starts up as usual,
+//! writes a gzip compressed tar file with these parts and exits:
+//!
+//! * `config`: `config.json`, the value and source of every flag like
+//!   `--print-config` prints them. Values of flags named like a secret (see
+//!   [`SECRETS`](crate::print_config::SECRETS)) are masked.
+//! * `version`: `version.json`, the version, features and build-ids, like the
+//!   file of `--emit-symbol-info`.
+//! * `session-logs`: the newest [`SESSION_LOGS`] session logs of earlier runs.
+//! * `recent-errors`: `recent-errors.json`, the ring of `--recent-errors-size`.
+//!   It only holds the warnings of this run's startup, like failed preflight
+//!   checks.
+//! * `environment`: `environment.json`, the variables in
+//!   [`ENVIRONMENT`](crate::print_config::ENVIRONMENT) that are set.
+//! * `crash-reports`: `daemon.out` next to the session logs, where the panics
+//!   of a daemon end up.
+//! * `process`: `process.json` with the open file descriptors, limits and
+//!   `/proc/self/status` of this run.
+//! * `profiles`: the `--trace-flame` file, which this run does not overwrite.
+//!
+//! `--support-bundle-include` and `--support-bundle-exclude` pick the parts.
+//! Files are added in the order above until `--support-bundle-max-size` of
+//! uncompressed content is reached. The first file, `manifest.json`, lists
+//! every part with its status, its files and the files that were skipped
+//! because they are too large or could not be read.
+//!
+//! Writing the bundle needs the `support-bundle` feature.
+#![cfg(feature = "support-bundle")]
+use crate::{
+    default_from_clap, effective_cpus,
+    fd_report::Snapshot,
+    features, memory_limit,
+    print_config::{config, environment, MASK},
+    symbols,
+    trace::{self, parse_size},
+    Version,
+};
+use chrono::{SecondsFormat, Utc};
+use clap::{ArgMatches, Command, Parser, ValueEnum};
+use eyre::{Result as EyreResult, WrapErr as _};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Map, Value};
+use std::{
+    fs::{self, File},
+    io::Read,
+    iter,
+    path::{Path, PathBuf},
+    process::id as pid,
+};
+use tracing::info;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Number of session logs included.
+pub const SESSION_LOGS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Part {
+    Config,
+    Version,
+    SessionLogs,
+    RecentErrors,
+    Environment,
+    CrashReports,
+    Process,
+    Profiles,
+}
+
+impl Part {
+    const ALL: [Self; 8] = [
+        Self::Config,
+        Self::Version,
+        Self::SessionLogs,
+        Self::RecentErrors,
+        Self::Environment,
+        Self::CrashReports,
+        Self::Process,
+        Self::Profiles,
+    ];
+
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_owned())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Field names are the flag names
+pub struct Options {
+    /// Write a support bundle for bug reports to this `.tar.gz` file and exit.
+    #[clap(long, env, value_name = "PATH")]
+    support_bundle: Option<PathBuf>,
+
+    /// Parts of the support bundle: 'config', 'version', 'session-logs',
+    /// 'recent-errors', 'environment', 'crash-reports', 'process' and
+    /// 'profiles'.
+    #[clap(long, env, value_enum, value_delimiter = ',', default_values_t = Part::ALL)]
+    support_bundle_include: Vec<Part>,
+
+    /// Parts to leave out of the support bundle.
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    support_bundle_exclude: Vec<Part>,
+
+    /// Maximum size of the files in the support bundle before compression,
+    /// like '100MB'.
+    #[clap(long, env, value_parser = parse_max_size, default_value = "100MB")]
+    support_bundle_max_size: u64,
+}
+
+default_from_clap!(Options);
+
+fn parse_max_size(s: &str) -> Result<u64, String> {
+    parse_size(s).ok_or_else(|| format!("expected a size like 100MB, got `{s}`"))
+}
+
+/// A requested bundle, see [`Options::collector`].
+pub struct Collector {
+    path:     PathBuf,
+    parts:    Vec<Part>,
+    max_size: u64,
+    profiles: Vec<PathBuf>,
+}
+
+impl Options {
+    /// The collector of the bundle, if requested. Takes the profile files out
+    /// of `tracing`, so this run does not overwrite them.
+    pub fn collector(&self, tracing: &mut trace::Options) -> Option<Collector> {
+        let path = self.support_bundle.clone()?;
+        let parts = Part::ALL
+            .into_iter()
+            .filter(|part| {
+                self.support_bundle_include.contains(part)
+                    && !self.support_bundle_exclude.contains(part)
+            })
+            .collect();
+        Some(Collector {
+            path,
+            parts,
+            max_size: self.support_bundle_max_size,
+            profiles: tracing.take_profiles(),
+        })
+    }
+}
+
+impl Collector {
+    /// Collect the parts and write the bundle. `command` and `matches` are
+    /// the parsed command line, `main` the address logged at startup.
+    pub fn write(
+        self,
+        version: &Version,
+        command: &Command,
+        matches: &ArgMatches,
+        tracing: &trace::Options,
+        main: usize,
+    ) -> EyreResult<()> {
+        let mut bundle = Bundle::new(self.max_size);
+        let log_dir = tracing.session_log_dir(version)?;
+        for part in Part::ALL {
+            if !self.parts.contains(&part) {
+                bundle.status(part, "excluded", None);
+                continue;
+            }
+            match part {
+                Part::Config => {
+                    let config = config(command, matches, &tracing.built_in(), |_| MASK.to_owned());
+                    bundle.add_json(part, "config.json", &config);
+                }
+                Part::Version => bundle.add_json(
+                    part,
+                    "version.json",
+                    &json!({
+                        "name": version.pkg_name,
+                        "version": version.pkg_version,
+                        "long_version": version.long_version,
+                        "features": features(),
+                        "symbols": symbols::current(version, main),
+                    }),
+                ),
+                Part::SessionLogs => match &log_dir {
+                    Some(dir) => bundle.add_session_logs(dir),
+                    None => bundle.status(part, "missing", Some("session logs are off")),
+                },
+                Part::RecentErrors => {
+                    bundle.add_json(part, "recent-errors.json", &trace::recent_errors());
+                }
+                Part::Environment => bundle.add_json(part, "environment.json", &environment()),
+                Part::CrashReports => match &log_dir {
+                    Some(dir) => {
+                        bundle.add_file(part, "crash-reports/daemon.out", &dir.join("daemon.out"));
+                    }
+                    None => bundle.status(part, "missing", Some("session logs are off")),
+                },
+                Part::Process => bundle.add_json(part, "process.json", &process()),
+                Part::Profiles if self.profiles.is_empty() => {
+                    bundle.status(part, "missing", Some("no --trace-flame file"));
+                }
+                Part::Profiles => {
+                    for path in &self.profiles {
+                        bundle.add_file(part, &bundled_name("profiles", path), path);
+                    }
+                }
+            }
+        }
+        bundle
+            .write(version, &self.path)
+            .wrap_err_with(|| format!("Error writing support bundle {}", self.path.display()))?;
+        info!(path = ?self.path, "Wrote support bundle");
+        Ok(())
+    }
+}
+
+/// Files and manifest entries of a bundle, see the [module docs](self).
+struct Bundle {
+    max_size: u64,
+    size:     u64,
+    files:    Vec<(String, Vec<u8>)>,
+    parts:    Vec<(Part, Map<String, Value>)>,
+}
+
+impl Bundle {
+    const fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            size: 0,
+            files: Vec::new(),
+            parts: Vec::new(),
+        }
+    }
+
+    /// The manifest entry of `part`.
+    fn entry(&mut self, part: Part) -> &mut Map<String, Value> {
+        if !self.parts.iter().any(|(p, _)| *p == part) {
+            let mut entry = Map::new();
+            entry.insert("part".to_owned(), part.name().into());
+            entry.insert("files".to_owned(), json!([]));
+            entry.insert("skipped".to_owned(), json!([]));
+            self.parts.push((part, entry));
+        }
+        let (_, entry) = self.parts.iter_mut().find(|(p, _)| *p == part).unwrap();
+        entry
+    }
+
+    /// Set the status of `part`, instead of the one derived from its files.
+    fn status(&mut self, part: Part, status: &str, reason: Option<&str>) {
+        let entry = self.entry(part);
+        entry.insert("status".to_owned(), status.into());
+        if let Some(reason) = reason {
+            entry.insert("reason".to_owned(), reason.into());
+        }
+    }
+
+    fn skip(&mut self, part: Part, name: &str, reason: &str) {
+        if let Some(Value::Array(skipped)) = self.entry(part).get_mut("skipped") {
+            skipped.push(json!({ "file": name, "reason": reason }));
+        }
+    }
+
+    const fn fits(&self, size: u64) -> bool {
+        self.size.saturating_add(size) <= self.max_size
+    }
+
+    fn add(&mut self, part: Part, name: &str, contents: Vec<u8>) {
+        let size = contents.len() as u64;
+        if !self.fits(size) {
+            self.skip(part, name, "size limit");
+            return;
+        }
+        self.size += size;
+        if let Some(Value::Array(files)) = self.entry(part).get_mut("files") {
+            files.push(name.into());
+        }
+        self.files.push((name.to_owned(), contents));
+    }
+
+    fn add_json(&mut self, part: Part, name: &str, value: &Value) {
+        let contents = serde_json::to_vec_pretty(value).unwrap_or_default();
+        self.add(part, name, contents);
+    }
+
+    /// Add the file at `path` as `name`, if it exists.
+    fn add_file(&mut self, part: Part, name: &str, path: &Path) {
+        let read = |limit: u64| -> std::io::Result<Option<Vec<u8>>> {
+            let file = File::open(path)?;
+            if !self.fits(file.metadata()?.len()) {
+                return Ok(None);
+            }
+            let mut contents = Vec::new();
+            file.take(limit).read_to_end(&mut contents)?;
+            Ok(Some(contents))
+        };
+        match read(self.max_size - self.size) {
+            Ok(Some(contents)) => self.add(part, name, contents),
+            Ok(None) => self.skip(part, name, "size limit"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.skip(part, name, "not found");
+            }
+            Err(err) => self.skip(part, name, &err.to_string()),
+        }
+    }
+
+    /// Add the newest session logs in `dir`, except the one of this run.
+    fn add_session_logs(&mut self, dir: &Path) {
+        let logs = match trace::session_logs(dir) {
+            Ok(logs) => logs,
+            Err(err) => {
+                self.status(Part::SessionLogs, "missing", Some(&err.to_string()));
+                return;
+            }
+        };
+        let logs = logs
+            .iter()
+            .rev()
+            .filter(|path| Some(path.as_path()) != trace::session_log_path())
+            .take(SESSION_LOGS);
+        for path in logs {
+            self.add_file(Part::SessionLogs, &bundled_name("session-logs", path), path);
+        }
+    }
+
+    fn manifest(&self, version: &Version) -> Value {
+        let parts = self
+            .parts
+            .iter()
+            .map(|(_, entry)| {
+                let mut entry = entry.clone();
+                if !entry.contains_key("status") {
+                    let count = |key: &str| entry[key].as_array().map_or(0, Vec::len);
+                    let status = match (count("files"), count("skipped")) {
+                        (0, 0) => "missing",
+                        (_, 0) => "included",
+                        (0, _) => "skipped",
+                        _ => "partial",
+                    };
+                    entry.insert("status".to_owned(), status.into());
+                }
+                Value::Object(entry)
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "schema_version": SCHEMA_VERSION,
+            "name": version.pkg_name,
+            "version": version.pkg_version,
+            "created": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            "max_size": self.max_size,
+            "size": self.size,
+            "parts": parts,
+        })
+    }
+
+    /// Write the manifest and the files as a `.tar.gz` to `path`.
+    fn write(&self, version: &Version, path: &Path) -> EyreResult<()> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest(version))?;
+        let mtime = u64::try_from(Utc::now().timestamp()).unwrap_or_default();
+        let file = File::create(path)?;
+        let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let files = self.files.iter().map(|(name, data)| (name.as_str(), data));
+        for (name, data) in iter::once(("manifest.json", &manifest)).chain(files) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            archive.append_data(&mut header, name, data.as_slice())?;
+        }
+        archive.into_inner()?.finish()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// `dir/<file name of path>`.
+fn bundled_name(dir: &str, path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("{dir}/{name}")
+}
+
+/// Resources of this process.
+fn process() -> Value {
+    let read = |path: &str| fs::read_to_string(path).ok();
+    json!({
+        "pid": pid(),
+        "effective_cores": effective_cpus(),
+        "memory_limit": memory_limit(),
+        "file_descriptors": Snapshot::take().map(|snapshot| snapshot.to_json()),
+        "limits": read("/proc/self/limits"),
+        "status": read("/proc/self/status"),
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::mock_version;
+    use flate2::read::GzDecoder;
+    use std::{collections::HashMap, env};
+
+    /// The files in the bundle at `path`, by name.
+    pub fn unpack(path: &Path) -> HashMap<String, Vec<u8>> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path).unwrap()));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                (name, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let dir = env::temp_dir().join(format!("cli-batteries-bundle-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("small.log"), "small").unwrap();
+        fs::write(dir.join("large.log"), [b'x'; 100]).unwrap();
+
+        let mut bundle = Bundle::new(50);
+        bundle.add_json(Part::Config, "config.json", &json!({}));
+        bundle.add_file(
+            Part::SessionLogs,
+            "session-logs/large.log",
+            &dir.join("large.log"),
+        );
+        bundle.add_file(
+            Part::SessionLogs,
+            "session-logs/small.log",
+            &dir.join("small.log"),
+        );
+        bundle.add_file(
+            Part::CrashReports,
+            "crash-reports/daemon.out",
+            &dir.join("daemon.out"),
+        );
+        bundle.status(Part::Profiles, "excluded", None);
+        let path = dir.join("bundle.tar.gz");
+        bundle.write(&mock_version(), &path).unwrap();
+
+        let files = unpack(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files["session-logs/small.log"], b"small");
+        let manifest: Value = serde_json::from_slice(&files["manifest.json"]).unwrap();
+        assert_eq!(manifest["size"], 7);
+        let parts = manifest["parts"].as_array().unwrap();
+        assert_eq!(parts[0]["status"], "included");
+        assert_eq!(parts[1]["status"], "partial");
+        assert_eq!(
+            parts[1]["skipped"],
+            json!([
+                { "file": "session-logs/large.log", "reason": "size limit" }
+            ])
+        );
+        assert_eq!(parts[2]["status"], "skipped");
+        assert_eq!(parts[2]["skipped"][0]["reason"], "not found");
+        assert_eq!(parts[3]["status"], "excluded");
+    }
+}
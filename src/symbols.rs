@@ -0,0 +1,435 @@
+//! Symbolization sidecar.
+//!
+//! With `--emit-symbol-info <path>` a JSON file is written at startup with the
+//! executable path, its GNU build-id, the load address of every loaded module
+//! and the version information. Addresses in crash reports and profiles can
+//! then be symbolized offline against the matching debug info. Modules are
+//! read from `/proc/self/maps` on Linux and from dyld on macOS. The file is
+//! rewritten on the heartbeat when shared objects were loaded or unloaded.
+use crate::Version;
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process::id as pid,
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+static SIDECAR: OnceCell<Sidecar> = OnceCell::new();
+
+/// ELF program header type of notes.
+const PT_NOTE: u64 = 4;
+
+/// ELF note type of the GNU build-id.
+const NT_GNU_BUILD_ID: u64 = 3;
+
+/// Largest note segment read.
+const MAX_NOTES: u64 = 1 << 16;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Write build-ids and load addresses to this JSON file for offline
+    /// symbolization.
+    #[clap(long, env)]
+    emit_symbol_info: Option<PathBuf>,
+}
+
+impl Options {
+    /// Write the sidecar if requested. `main` is the address logged at
+    /// startup.
+    pub fn init(&self, version: &Version, main: usize) {
+        let Some(path) = &self.emit_symbol_info else {
+            return;
+        };
+        let sidecar = SIDECAR.get_or_init(|| Sidecar {
+            path: path.clone(),
+            version: version.clone(),
+            main,
+            written: Mutex::default(),
+        });
+        sidecar.refresh();
+    }
+}
+
+/// The symbol info of this process, like the sidecar. `main` is the address
+/// logged at startup.
+#[cfg(feature = "support-bundle")]
+pub fn current(version: &Version, main: usize) -> Value {
+    let exe = env::current_exe().unwrap_or_default();
+    render(version, main, &exe, &modules())
+}
+
+/// Rewrite the sidecar if the loaded modules changed.
+pub fn on_heartbeat() {
+    if let Some(sidecar) = SIDECAR.get() {
+        sidecar.refresh();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Module {
+    path: String,
+    base: u64,
+}
+
+struct Sidecar {
+    path:    PathBuf,
+    version: Version,
+    main:    usize,
+    /// Modules in the file last written.
+    written: Mutex<Option<Vec<Module>>>,
+}
+
+impl Sidecar {
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    fn refresh(&self) {
+        let modules = modules();
+        let mut written = self.written.lock().unwrap();
+        if written.as_ref() == Some(&modules) {
+            return;
+        }
+        let exe = env::current_exe().unwrap_or_default();
+        let contents = render(&self.version, self.main, &exe, &modules);
+        match write(&self.path, &contents) {
+            Ok(()) => {
+                debug!(path = ?self.path, modules = modules.len(), "Wrote symbol info");
+                *written = Some(modules);
+            }
+            Err(err) => warn!(%err, path = ?self.path, "Could not write symbol info"),
+        }
+        drop(written);
+    }
+}
+
+/// Write through a temporary file, so readers never see a partial file.
+fn write(path: &Path, contents: &Value) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, serde_json::to_vec_pretty(contents)?)?;
+    fs::rename(&temp, path)
+}
+
+fn render(version: &Version, main: usize, exe: &Path, modules: &[Module]) -> Value {
+    let exe = exe.to_string_lossy();
+    let load_address = modules
+        .iter()
+        .find(|module| module.path == exe)
+        .map(|module| hex(module.base));
+    let modules = modules
+        .iter()
+        .map(|module| {
+            json!({
+                "path": module.path,
+                "base": hex(module.base),
+                "build_id": file_build_id(Path::new(&module.path)),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "executable": exe,
+        "build_id": file_build_id(Path::new(exe.as_ref())),
+        "load_address": load_address,
+        "main": hex(main as u64),
+        "pid": pid(),
+        "modules": modules,
+        "version": {
+            "name": version.pkg_name,
+            "version": version.pkg_version,
+            "repository": version.pkg_repo,
+            "commit": version.commit_hash,
+            "target": version.target,
+        },
+    })
+}
+
+fn hex(address: u64) -> String {
+    format!("{address:#x}")
+}
+
+/// The build-id of an ELF file, if it has one.
+fn file_build_id(path: &Path) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    build_id(&mut file).ok().flatten()
+}
+
+/// Find the `NT_GNU_BUILD_ID` note in the program headers of an ELF image and
+/// return it as hex. Returns `None` for other file formats.
+fn build_id<R: Read + Seek>(elf: &mut R) -> io::Result<Option<String>> {
+    let mut header = Vec::with_capacity(64);
+    elf.take(64).read_to_end(&mut header)?;
+    if header.len() < 52 || !header.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+    let wide = match header[4] {
+        1 => false,
+        2 if header.len() == 64 => true,
+        _ => return Ok(None),
+    };
+    let big = match header[5] {
+        1 => false,
+        2 => true,
+        _ => return Ok(None),
+    };
+    let int = |bytes: &[u8]| {
+        let fold = |n: u64, &b: &u8| n << 8 | u64::from(b);
+        if big {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    };
+    let (phoff, phentsize, phnum) = if wide {
+        (
+            int(&header[32..40]),
+            int(&header[54..56]),
+            int(&header[56..58]),
+        )
+    } else {
+        (
+            int(&header[28..32]),
+            int(&header[42..44]),
+            int(&header[44..46]),
+        )
+    };
+
+    for index in 0..phnum {
+        let mut phdr = [0; 56];
+        let phdr = &mut phdr[..if wide { 56 } else { 32 }];
+        elf.seek(SeekFrom::Start(phoff.saturating_add(index * phentsize)))?;
+        elf.read_exact(phdr)?;
+        if int(&phdr[0..4]) != PT_NOTE {
+            continue;
+        }
+        let (offset, size) = if wide {
+            (int(&phdr[8..16]), int(&phdr[32..40]))
+        } else {
+            (int(&phdr[4..8]), int(&phdr[16..20]))
+        };
+        let mut notes = Vec::new();
+        elf.seek(SeekFrom::Start(offset))?;
+        elf.take(size.min(MAX_NOTES)).read_to_end(&mut notes)?;
+
+        // Notes are 4-byte words in both ELF classes.
+        let mut rest = notes.as_slice();
+        while rest.len() >= 12 {
+            let pad = |n: u64| usize::try_from((n + 3) & !3).unwrap_or(usize::MAX);
+            let (name_size, desc_size) = (int(&rest[0..4]), int(&rest[4..8]));
+            let kind = int(&rest[8..12]);
+            rest = &rest[12..];
+            let (name_len, desc_len) = (pad(name_size), pad(desc_size));
+            if rest.len() < name_len.saturating_add(desc_len) {
+                break;
+            }
+            let name = &rest[..name_len];
+            let desc = &rest[name_len..name_len + desc_len];
+            if kind == NT_GNU_BUILD_ID && name.starts_with(b"GNU\0") {
+                #[allow(clippy::cast_possible_truncation)] // Bounded by `desc_len`
+                let desc = &desc[..desc_size as usize];
+                return Ok(Some(desc.iter().fold(String::new(), |mut hex, b| {
+                    let _ = write!(hex, "{b:02x}");
+                    hex
+                })));
+            }
+            rest = &rest[name_len + desc_len..];
+        }
+    }
+    Ok(None)
+}
+
+/// Loaded modules and their base addresses.
+#[cfg(target_os = "linux")]
+fn modules() -> Vec<Module> {
+    fs::read_to_string("/proc/self/maps")
+        .map(|maps| parse_maps(&maps))
+        .unwrap_or_default()
+}
+
+/// Loaded modules and their base addresses.
+#[cfg(target_os = "macos")]
+#[allow(unsafe_code)]
+fn modules() -> Vec<Module> {
+    use std::ffi::CStr;
+
+    // SAFETY: dyld returns null for indices that were unloaded since counting,
+    // and otherwise pointers that stay valid while the image is loaded.
+    (0..unsafe { libc::_dyld_image_count() })
+        .filter_map(|index| {
+            let name = unsafe { libc::_dyld_get_image_name(index) };
+            let header = unsafe { libc::_dyld_get_image_header(index) };
+            if name.is_null() || header.is_null() {
+                return None;
+            }
+            Some(Module {
+                path: unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+                base: header as u64,
+            })
+        })
+        .collect()
+}
+
+/// Loaded modules and their base addresses.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const fn modules() -> Vec<Module> {
+    Vec::new()
+}
+
+/// Parse the file mappings at offset zero from `/proc/<pid>/maps`, first
+/// mapping per file.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_maps(maps: &str) -> Vec<Module> {
+    let mut modules = Vec::<Module>::new();
+    for line in maps.lines() {
+        // `start-end perms offset dev inode`, then the path after padding.
+        let mut fields = line.splitn(6, ' ');
+        let (Some(range), Some(_perms), Some(offset), Some(_dev), Some(_inode), Some(path)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            continue;
+        };
+        let path = path.trim();
+        if !path.starts_with('/') || u64::from_str_radix(offset, 16) != Ok(0) {
+            continue;
+        }
+        let Some(base) = range
+            .split_once('-')
+            .and_then(|(start, _)| u64::from_str_radix(start, 16).ok())
+        else {
+            continue;
+        };
+        if !modules.iter().any(|module| module.path == path) {
+            modules.push(Module {
+                path: path.to_owned(),
+                base,
+            });
+        }
+    }
+    modules
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::mock_version;
+
+    fn fixture(name: &str) -> Option<String> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/elf")
+            .join(name);
+        build_id(&mut fs::File::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_build_id() {
+        assert_eq!(
+            fixture("build-id-le64").as_deref(),
+            Some("8f4c2a1b0e9d7c6b5a493827161504f3e2d1c0b9")
+        );
+        assert_eq!(
+            fixture("build-id-be32").as_deref(),
+            Some("8f4c2a1b0e9d7c6b")
+        );
+        assert_eq!(fixture("no-build-id"), None);
+
+        let mut not_elf = io::Cursor::new(b"#!/bin/sh\necho hello\n".to_vec());
+        assert_eq!(build_id(&mut not_elf).unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_own_build_id() {
+        let exe = env::current_exe().unwrap();
+        build_id(&mut fs::File::open(exe).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+5581a0e00000-5581a0e52000 r--p 00000000 fd:01 1234    /usr/bin/app
+5581a0e52000-5581a1200000 r-xp 00052000 fd:01 1234    /usr/bin/app
+5581a2000000-5581a2021000 rw-p 00000000 00:00 0       [heap]
+7f3c1a000000-7f3c1a028000 r--p 00000000 fd:01 5678    /usr/lib/libc.so.6
+7f3c1b000000-7f3c1b001000 r--p 00000000 fd:01 9012    /opt/my libs/libx.so (deleted)
+7ffd2c000000-7ffd2c021000 rw-p 00000000 00:00 0       [stack]
+";
+        assert_eq!(parse_maps(maps), vec![
+            Module {
+                path: "/usr/bin/app".to_owned(),
+                base: 0x5581_a0e0_0000,
+            },
+            Module {
+                path: "/usr/lib/libc.so.6".to_owned(),
+                base: 0x7f3c_1a00_0000,
+            },
+            Module {
+                path: "/opt/my libs/libx.so (deleted)".to_owned(),
+                base: 0x7f3c_1b00_0000,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_render() {
+        let exe = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/build-id-le64");
+        let modules = [
+            Module {
+                path: "/nonexistent/libfoo.so".to_owned(),
+                base: 0x7f00_0000_0000,
+            },
+            Module {
+                path: exe.to_string_lossy().into_owned(),
+                base: 0x5500_0000_0000,
+            },
+        ];
+        let value = render(&mock_version(), 0x1234, &exe, &modules);
+        assert_eq!(
+            value["build_id"],
+            "8f4c2a1b0e9d7c6b5a493827161504f3e2d1c0b9"
+        );
+        assert_eq!(value["load_address"], "0x550000000000");
+        assert_eq!(value["main"], "0x1234");
+        assert_eq!(value["modules"][0]["base"], "0x7f0000000000");
+        assert_eq!(value["modules"][0]["build_id"], Value::Null);
+        assert_eq!(
+            value["modules"][1]["build_id"],
+            "8f4c2a1b0e9d7c6b5a493827161504f3e2d1c0b9"
+        );
+        assert_eq!(value["version"]["commit"], mock_version().commit_hash);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_write() {
+        let path = env::temp_dir().join(format!("cli-batteries-symbols-{}.json", pid()));
+        let sidecar = Sidecar {
+            path:    path.clone(),
+            version: mock_version(),
+            main:    0,
+            written: Mutex::default(),
+        };
+        sidecar.refresh();
+        let value: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let exe = env::current_exe().unwrap();
+        assert_eq!(value["executable"], exe.to_string_lossy().as_ref());
+        assert!(value["load_address"].is_string());
+        assert!(value["modules"].as_array().unwrap().len() > 1);
+
+        // Unchanged modules are not rewritten.
+        fs::remove_file(&path).unwrap();
+        sidecar.refresh();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,684 @@
+//! Instrumented channels.
+//!
+//! [`channel`] wraps [`tokio::sync::mpsc`] and keeps track of the number of
+//! queued messages. The depth of every live channel is logged with the
+//! heartbeat and, with the `prometheus` feature, exported as the
+//! `channel_depth` gauge. Senders that find the channel full for longer than
+//! the stall threshold log a (rate limited) warning, and receivers record how
+//! long each message waited as `queue.wait_ms` on the current span.
+//!
+//! [`config`] wraps [`tokio::sync::watch`] for values that are replaced rather
+//! than queued, such as configuration.
+//!
+//! [`Mutex`] and [`RwLock`] wrap the Tokio locks and measure how long each
+//! acquisition waited. Waits of at least [`WAIT_FLOOR`] are recorded as
+//! `lock.name` and `lock.wait_ms` on the current span, and into the latency
+//! histogram of the lock name, reported with the other
+//! [`measure`](crate::measure)d operations. Waits above `--warn-lock-wait`
+//! log a (rate limited) warning. An uncontended acquisition costs two reads
+//! of the clock.
+use crate::{default_from_clap, latency, loss};
+use clap::Parser;
+use once_cell::sync::Lazy;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch, MutexGuard, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    time::{sleep, Instant},
+};
+use tracing::{info, warn, Span};
+
+pub use tokio::sync::mpsc::error::SendError;
+
+#[cfg(feature = "prometheus")]
+use prometheus::{register_int_gauge_vec, IntGauge, IntGaugeVec};
+
+/// How long a channel can stay at capacity before senders warn.
+pub const DEFAULT_STALL: Duration = Duration::from_secs(5);
+
+/// Shorter waits for a lock are not recorded.
+pub const WAIT_FLOOR: Duration = Duration::from_millis(1);
+
+/// Minimum time between warnings about the same lock.
+const LOCK_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+static CHANNELS: Lazy<StdMutex<Vec<Weak<Stats>>>> = Lazy::new(StdMutex::default);
+
+/// Lock wait in microseconds above which a warning is logged, `0` for none.
+static WARN_LOCK_WAIT: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "prometheus")]
+static DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "channel_depth",
+        "Number of messages queued in a channel.",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static VERSION: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "channel_version",
+        "Number of updates sent on a config channel.",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+/// Create a bounded channel with the default stall threshold.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero, like [`mpsc::channel`].
+#[must_use]
+pub fn channel<T>(name: &'static str, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    channel_with_stall(name, capacity, DEFAULT_STALL)
+}
+
+/// Create a bounded channel that warns when it stays at capacity for longer
+/// than `stall`.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero, like [`mpsc::channel`].
+#[must_use]
+pub fn channel_with_stall<T>(
+    name: &'static str,
+    capacity: usize,
+    stall: Duration,
+) -> (Sender<T>, Receiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let stats = Arc::new(Stats {
+        name,
+        capacity,
+        stall,
+        depth: AtomicUsize::new(0),
+        last_warning: StdMutex::new(None),
+        #[cfg(feature = "prometheus")]
+        gauge: DEPTH.with_label_values(&[name]),
+    });
+    {
+        let mut channels = CHANNELS.lock().unwrap();
+        channels.retain(|stats| stats.strong_count() > 0);
+        channels.push(Arc::downgrade(&stats));
+    }
+    (
+        Sender {
+            inner: sender,
+            stats: stats.clone(),
+        },
+        Receiver {
+            inner: receiver,
+            stats,
+        },
+    )
+}
+
+/// Create a config channel holding the latest value.
+#[must_use]
+pub fn config<T: Debug>(name: &'static str, initial: T) -> (ConfigSender<T>, watch::Receiver<T>) {
+    let (sender, receiver) = watch::channel(initial);
+    (
+        ConfigSender {
+            inner: sender,
+            name,
+            version: AtomicU64::new(0),
+        },
+        receiver,
+    )
+}
+
+/// Name, depth and capacity of all live channels.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn depths() -> Vec<(&'static str, usize, usize)> {
+    CHANNELS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|stats| (stats.name, stats.depth(), stats.capacity))
+        .collect()
+}
+
+/// Log the depth of all live channels.
+pub fn log_depths() {
+    for (channel, depth, capacity) in depths() {
+        info!(channel, depth, capacity, "Channel depth");
+    }
+}
+
+struct Stats {
+    name:         &'static str,
+    capacity:     usize,
+    stall:        Duration,
+    depth:        AtomicUsize,
+    last_warning: StdMutex<Option<Instant>>,
+    #[cfg(feature = "prometheus")]
+    gauge:        IntGauge,
+}
+
+impl Stats {
+    fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    fn pushed(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.gauge.inc();
+    }
+
+    fn popped(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.gauge.dec();
+    }
+
+    /// Warn about a stalled sender, at most once per stall threshold.
+    fn stalled(&self, waited: Duration) {
+        let now = Instant::now();
+        {
+            let mut last_warning = self.last_warning.lock().unwrap();
+            if last_warning.is_some_and(|last| now.saturating_duration_since(last) < self.stall) {
+                loss::LOG_RATE_LIMITED.add(1);
+                return;
+            }
+            *last_warning = Some(now);
+        }
+        warn!(
+            channel = self.name,
+            capacity = self.capacity,
+            ?waited,
+            "Channel at capacity, sender stalled"
+        );
+    }
+}
+
+/// Sending half of an instrumented [`channel`].
+pub struct Sender<T> {
+    inner: mpsc::Sender<(Instant, T)>,
+    stats: Arc<Stats>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send a value, waiting for capacity if the channel is full.
+    ///
+    /// # Errors
+    ///
+    /// Returns the value if the receiver was dropped.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let permit = match self.inner.try_reserve() {
+            Ok(permit) => permit,
+            Err(TrySendError::Closed(())) => return Err(SendError(value)),
+            Err(TrySendError::Full(())) => {
+                let start = Instant::now();
+                let reserve = self.inner.reserve();
+                tokio::pin!(reserve);
+                let result = loop {
+                    tokio::select! {
+                        result = &mut reserve => break result,
+                        () = sleep(self.stats.stall) => self.stats.stalled(start.elapsed()),
+                    }
+                };
+                match result {
+                    Ok(permit) => permit,
+                    Err(_) => return Err(SendError(value)),
+                }
+            }
+        };
+        self.stats.pushed();
+        permit.send((Instant::now(), value));
+        Ok(())
+    }
+
+    /// Number of queued messages.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.stats.depth()
+    }
+}
+
+/// Receiving half of an instrumented [`channel`].
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<(Instant, T)>,
+    stats: Arc<Stats>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next value, or `None` if all senders were dropped.
+    ///
+    /// The time the value spent in the channel is recorded as `queue.wait_ms`
+    /// on the current span, if it declares the field.
+    pub async fn recv(&mut self) -> Option<T> {
+        let (sent, value) = self.inner.recv().await?;
+        self.stats.popped();
+        #[allow(clippy::cast_possible_truncation)]
+        let wait_ms = sent.elapsed().as_millis() as u64;
+        Span::current().record("queue.wait_ms", wait_ms);
+        Some(value)
+    }
+
+    /// Number of queued messages.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.stats.depth()
+    }
+}
+
+/// Sending half of a [`config`] channel.
+pub struct ConfigSender<T> {
+    inner:   watch::Sender<T>,
+    name:    &'static str,
+    version: AtomicU64,
+}
+
+impl<T: Debug> ConfigSender<T> {
+    /// Replace the current value, notifying all receivers.
+    ///
+    /// # Errors
+    ///
+    /// Returns the value if all receivers were dropped.
+    pub fn send(&self, value: T) -> Result<(), watch::error::SendError<T>> {
+        self.inner.send(value)?;
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(channel = self.name, version, "Config updated");
+        #[cfg(feature = "prometheus")]
+        #[allow(clippy::cast_possible_wrap)]
+        VERSION.with_label_values(&[self.name]).set(version as i64);
+        Ok(())
+    }
+
+    /// Number of updates sent so far.
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Create a new receiver.
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<T> {
+        self.inner.subscribe()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Warn when a task waits longer than this for a `sync::Mutex` or
+    /// `sync::RwLock`, e.g. `100ms`.
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    warn_lock_wait: Option<Duration>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    pub fn init(self) {
+        if let Some(threshold) = self.warn_lock_wait {
+            let micros = u64::try_from(threshold.as_micros()).unwrap_or(u64::MAX);
+            WARN_LOCK_WAIT.store(micros.max(1), Ordering::Relaxed);
+        }
+    }
+}
+
+/// The name of a lock and when it last warned.
+#[derive(Debug)]
+struct Contention {
+    name:         &'static str,
+    last_warning: StdMutex<Option<Instant>>,
+}
+
+impl Contention {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            last_warning: StdMutex::new(None),
+        }
+    }
+
+    /// Record an acquisition of the lock that waited since `start`.
+    fn acquired(&self, start: Instant) {
+        let waited = start.elapsed();
+        if waited < WAIT_FLOOR {
+            return;
+        }
+        let span = Span::current();
+        span.record("lock.name", self.name);
+        #[allow(clippy::cast_possible_truncation)]
+        span.record("lock.wait_ms", waited.as_millis() as u64);
+        latency::record(self.name, waited);
+
+        let threshold = WARN_LOCK_WAIT.load(Ordering::Relaxed);
+        if threshold == 0 || waited <= Duration::from_micros(threshold) {
+            return;
+        }
+        let now = Instant::now();
+        {
+            let mut last_warning = self.last_warning.lock().unwrap();
+            if last_warning
+                .is_some_and(|last| now.saturating_duration_since(last) < LOCK_WARNING_INTERVAL)
+            {
+                loss::LOG_RATE_LIMITED.add(1);
+                return;
+            }
+            *last_warning = Some(now);
+        }
+        warn!(
+            lock = self.name,
+            ?waited,
+            threshold = ?Duration::from_micros(threshold),
+            "Waited long for a lock"
+        );
+    }
+}
+
+/// A [`tokio::sync::Mutex`] that records how long [`lock`](Self::lock)
+/// waited, see the [module docs](self).
+#[derive(Debug)]
+pub struct Mutex<T: ?Sized> {
+    contention: Contention,
+    inner:      tokio::sync::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// A mutex named `name` in spans, latency reports and warnings.
+    pub fn new(name: &'static str, value: T) -> Self {
+        Self {
+            contention: Contention::new(name),
+            inner:      tokio::sync::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.contention.name
+    }
+
+    /// Lock the mutex, waiting until it is available.
+    ///
+    /// A wait of at least [`WAIT_FLOOR`] is recorded as `lock.name` and
+    /// `lock.wait_ms` on the current span, if it declares the fields.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.lock().await;
+        self.contention.acquired(start);
+        guard
+    }
+
+    /// Lock the mutex if it is available right away.
+    ///
+    /// # Errors
+    ///
+    /// If the mutex is locked.
+    pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, TryLockError> {
+        self.inner.try_lock()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+/// A [`tokio::sync::RwLock`] that records how long [`read`](Self::read) and
+/// [`write`](Self::write) waited, see the [module docs](self).
+#[derive(Debug)]
+pub struct RwLock<T: ?Sized> {
+    contention: Contention,
+    inner:      tokio::sync::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    /// A lock named `name` in spans, latency reports and warnings.
+    pub fn new(name: &'static str, value: T) -> Self {
+        Self {
+            contention: Contention::new(name),
+            inner:      tokio::sync::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.contention.name
+    }
+
+    /// Lock for reading, waiting while a writer holds the lock. Waits are
+    /// recorded like those of [`Mutex::lock`].
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    #[allow(clippy::future_not_send)] // Send if `T` is `Send` and `Sync`
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.read().await;
+        self.contention.acquired(start);
+        guard
+    }
+
+    /// Lock for writing, waiting while any reader or writer holds the lock.
+    /// Waits are recorded like those of [`Mutex::lock`].
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    #[allow(clippy::future_not_send)] // Send if `T` is `Send` and `Sync`
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let start = Instant::now();
+        let guard = self.inner.write().await;
+        self.contention.acquired(start);
+        guard
+    }
+
+    /// Lock for reading if no writer holds the lock.
+    ///
+    /// # Errors
+    ///
+    /// If a writer holds the lock.
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>, TryLockError> {
+        self.inner.try_read()
+    }
+
+    /// Lock for writing if nobody holds the lock.
+    ///
+    /// # Errors
+    ///
+    /// If the lock is held.
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, TryLockError> {
+        self.inner.try_write()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::{task::yield_now, time::sleep};
+    use tracing::{field::Empty, info_span, Instrument};
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    async fn test_depth() {
+        let (sender, mut receiver) = channel("test-depth", 4);
+        for i in 0..3 {
+            sender.send(i).await.unwrap();
+        }
+        assert_eq!(sender.depth(), 3);
+        assert_eq!(receiver.recv().await, Some(0));
+        assert_eq!(receiver.depth(), 2);
+        assert!(depths().contains(&("test-depth", 2, 4)));
+        drop(sender);
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, None);
+        assert_eq!(receiver.depth(), 0);
+        drop(receiver);
+        assert!(!depths().iter().any(|(name, ..)| *name == "test-depth"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_stall_warning() {
+        let stall = Duration::from_secs(2);
+        let (sender, mut receiver) = channel_with_stall("test-stall", 1, stall);
+        sender.send(1).await.unwrap();
+        let blocked = tokio::spawn(async move { sender.send(2).await.unwrap() });
+
+        sleep(Duration::from_millis(1999)).await;
+        assert!(!logs_contain("Channel at capacity"));
+
+        sleep(Duration::from_millis(2)).await;
+        yield_now().await;
+        assert!(logs_contain("Channel at capacity, sender stalled"));
+        assert!(logs_contain("channel=\"test-stall\""));
+
+        assert_eq!(receiver.recv().await, Some(1));
+        blocked.await.unwrap();
+        assert_eq!(receiver.depth(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_wait_time() {
+        let (sender, mut receiver) = channel("test-wait", 1);
+        sender.send(()).await.unwrap();
+        sleep(Duration::from_millis(250)).await;
+        async {
+            receiver.recv().await.unwrap();
+            info!("received");
+        }
+        .instrument(info_span!("worker", queue.wait_ms = Empty))
+        .await;
+        assert!(logs_contain("queue.wait_ms=250"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_lock_wait() {
+        Options {
+            warn_lock_wait: Some(Duration::from_millis(20)),
+        }
+        .init();
+        let mutex = Arc::new(Mutex::new("test.mutex", 0));
+        let holder = {
+            let mutex = mutex.clone();
+            tokio::spawn(async move {
+                let _guard = mutex.lock().await;
+                sleep(Duration::from_millis(50)).await;
+            })
+        };
+        yield_now().await;
+        async {
+            *mutex.lock().await += 1;
+            info!("locked");
+        }
+        .instrument(info_span!(
+            "worker",
+            lock.name = Empty,
+            lock.wait_ms = Empty
+        ))
+        .await;
+        holder.await.unwrap();
+        assert!(logs_contain("lock.name=\"test.mutex\" lock.wait_ms=50}"));
+        assert!(logs_contain(
+            "Waited long for a lock lock=\"test.mutex\" waited=50ms threshold=20ms"
+        ));
+
+        // Uncontended, not recorded
+        async {
+            *mutex.lock().await += 1;
+            info!("uncontended");
+        }
+        .instrument(info_span!("idle", lock.name = Empty, lock.wait_ms = Empty))
+        .await;
+        assert!(logs_contain("idle: cli_batteries::sync::test: uncontended"));
+        assert_eq!(*mutex.lock().await, 2);
+
+        let report = latency::report();
+        let (_, summary) = report
+            .iter()
+            .find(|(name, _)| *name == "test.mutex")
+            .unwrap();
+        assert_eq!(summary.count, 1);
+        assert_eq!(summary.max, Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_rwlock_wait() {
+        Options {
+            warn_lock_wait: Some(Duration::from_millis(20)),
+        }
+        .init();
+        let lock = Arc::new(RwLock::new("test.rwlock", 0));
+        for _ in 0..2 {
+            let writer = {
+                let lock = lock.clone();
+                tokio::spawn(async move {
+                    let _guard = lock.write().await;
+                    sleep(Duration::from_millis(30)).await;
+                })
+            };
+            yield_now().await;
+            assert_eq!(*lock.read().await, 0);
+            writer.await.unwrap();
+        }
+        // Warned once, the second time within the interval is rate limited
+        logs_assert(|lines| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("lock=\"test.rwlock\""))
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("Expected one warning, got {n}")),
+            }
+        });
+        let report = latency::report();
+        let (_, summary) = report
+            .iter()
+            .find(|(name, _)| *name == "test.rwlock")
+            .unwrap();
+        assert_eq!(summary.count, 2);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_config() {
+        let (sender, mut receiver) = config("test-config", 1);
+        sender.send(2).unwrap();
+        receiver.changed().await.unwrap();
+        assert_eq!(*receiver.borrow(), 2);
+        assert_eq!(sender.version(), 1);
+        assert!(logs_contain("Config updated"));
+    }
+}
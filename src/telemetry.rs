@@ -0,0 +1,211 @@
+//! The OpenTelemetry resource of this process.
+//!
+//! The resource describes the process to the telemetry backend. It is built
+//! once when tracing starts and shared by everything that exports telemetry.
+//! Applications can read it with [`resource`], for example to stamp outgoing
+//! messages with `service.instance.id`.
+//!
+//! Attributes are merged from these sources, later ones taking precedence:
+//!
+//! 1. Detected: `service.instance.id` (random per process), `process.pid`,
+//!    `os.type` and `host.arch`.
+//! 2. The [`Version`]: `service.name` and `service.version`.
+//! 3. The environment: `OTEL_RESOURCE_ATTRIBUTES` (`key=value,...`), then
+//!    `OTEL_SERVICE_NAME`, then `TRACE_RESOURCE_*`.
+//! 4. `--trace-resource` (or `--otlp-resource`) arguments.
+//! 5. `--tag` fields.
+#![cfg(feature = "otlp")]
+use crate::Version;
+use heck::ToSnakeCase;
+use once_cell::sync::OnceCell;
+use opentelemetry::{
+    sdk::{
+        trace::{IdGenerator as _, RandomIdGenerator},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_semantic_conventions::resource;
+use std::{
+    env::{
+        self,
+        consts::{ARCH, OS},
+    },
+    process,
+};
+
+static RESOURCE: OnceCell<Resource> = OnceCell::new();
+
+/// The resource of this process, once tracing is initialized.
+pub fn resource() -> Option<&'static Resource> {
+    RESOURCE.get()
+}
+
+/// Build the resource from the environment on first use.
+pub(crate) fn init(
+    version: &Version,
+    arguments: &[(String, String)],
+    tags: &[(&'static str, String)],
+) -> &'static Resource {
+    RESOURCE.get_or_init(|| build(version, env::vars(), arguments, tags))
+}
+
+/// See the [module docs](self).
+pub(crate) fn build(
+    version: &Version,
+    env: impl IntoIterator<Item = (String, String)>,
+    arguments: &[(String, String)],
+    tags: &[(&'static str, String)],
+) -> Resource {
+    let arguments = Resource::new(
+        arguments
+            .iter()
+            .map(|(k, v)| KeyValue::new(k.clone(), v.clone())),
+    );
+    let tags = Resource::new(tags.iter().map(|(k, v)| KeyValue::new(*k, v.clone())));
+    detected()
+        .merge(&from_version(version))
+        .merge(&from_env(env))
+        .merge(&arguments)
+        .merge(&tags)
+}
+
+fn detected() -> Resource {
+    let instance_id = RandomIdGenerator::default().new_trace_id();
+    Resource::new([
+        resource::SERVICE_INSTANCE_ID.string(format!("{instance_id:032x}")),
+        resource::PROCESS_PID.i64(i64::from(process::id())),
+        resource::OS_TYPE.string(OS),
+        resource::HOST_ARCH.string(ARCH),
+    ])
+}
+
+fn from_version(version: &Version) -> Resource {
+    Resource::new([
+        resource::SERVICE_NAME.string(version.pkg_name),
+        resource::SERVICE_VERSION
+            .string(format!("{}-{}", version.pkg_version, version.commit_hash)),
+    ])
+}
+
+fn from_env(env: impl IntoIterator<Item = (String, String)>) -> Resource {
+    let (mut standard, mut service_name, mut prefixed) = (Vec::new(), None, Vec::new());
+    for (key, value) in env {
+        match key.as_str() {
+            "OTEL_RESOURCE_ATTRIBUTES" => {
+                standard.extend(value.split(',').filter_map(|pair| {
+                    let (k, v) = pair.split_once('=')?;
+                    let k = k.trim();
+                    (!k.is_empty()).then(|| KeyValue::new(k.to_owned(), v.trim().to_owned()))
+                }));
+            }
+            "OTEL_SERVICE_NAME" if !value.is_empty() => {
+                service_name = Some(resource::SERVICE_NAME.string(value));
+            }
+            _ => {
+                if let Some(k) = key.strip_prefix("TRACE_RESOURCE_") {
+                    prefixed.push(KeyValue::new(k.to_snake_case().replace('_', "."), value));
+                }
+            }
+        }
+    }
+    Resource::new(standard)
+        .merge(&Resource::new(service_name))
+        .merge(&Resource::new(prefixed))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::mock_version;
+    use opentelemetry::Key;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect()
+    }
+
+    fn get(resource: &Resource, key: &'static str) -> Option<String> {
+        resource.get(Key::new(key)).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_detected() {
+        let resource = build(&mock_version(), [], &[], &[]);
+        let instance_id = get(&resource, "service.instance.id").unwrap();
+        assert_eq!(instance_id.len(), 32);
+        assert_ne!(
+            get(&build(&mock_version(), [], &[], &[]), "service.instance.id"),
+            Some(instance_id)
+        );
+        assert_eq!(
+            get(&resource, "process.pid"),
+            Some(process::id().to_string())
+        );
+        assert_eq!(get(&resource, "os.type").as_deref(), Some(OS));
+        assert_eq!(get(&resource, "host.arch").as_deref(), Some(ARCH));
+    }
+
+    #[test]
+    fn test_version() {
+        let resource = build(&mock_version(), [], &[], &[]);
+        assert_eq!(get(&resource, "service.name").as_deref(), Some("test-app"));
+        assert!(get(&resource, "service.version").is_some());
+    }
+
+    #[test]
+    fn test_env() {
+        let vars = env(&[
+            (
+                "OTEL_RESOURCE_ATTRIBUTES",
+                "deployment.environment=prod, service.name=from-otel,os.type=custom,bad",
+            ),
+            ("TRACE_RESOURCE_SERVICE_NAMESPACE", "payments"),
+            ("TRACE_RESOURCE_DEPLOYMENT_ENVIRONMENT", "staging"),
+            ("PATH", "/bin"),
+        ]);
+        let resource = build(&mock_version(), vars, &[], &[]);
+        // Over detected and version attributes
+        assert_eq!(get(&resource, "os.type").as_deref(), Some("custom"));
+        assert_eq!(get(&resource, "service.name").as_deref(), Some("from-otel"));
+        // `TRACE_RESOURCE_*` over `OTEL_RESOURCE_ATTRIBUTES`
+        assert_eq!(
+            get(&resource, "deployment.environment").as_deref(),
+            Some("staging")
+        );
+        assert_eq!(
+            get(&resource, "service.namespace").as_deref(),
+            Some("payments")
+        );
+        assert_eq!(get(&resource, "bad"), None);
+        assert_eq!(get(&resource, "path"), None);
+
+        let vars = env(&[
+            ("OTEL_RESOURCE_ATTRIBUTES", "service.name=from-otel"),
+            ("OTEL_SERVICE_NAME", "from-service-name"),
+        ]);
+        let resource = build(&mock_version(), vars, &[], &[]);
+        assert_eq!(
+            get(&resource, "service.name").as_deref(),
+            Some("from-service-name")
+        );
+    }
+
+    #[test]
+    fn test_arguments_and_tags() {
+        let vars = env(&[("OTEL_RESOURCE_ATTRIBUTES", "region=env,ticket=env")]);
+        let arguments = [
+            ("region".to_owned(), "argument".to_owned()),
+            ("ticket".to_owned(), "argument".to_owned()),
+            ("service.name".to_owned(), "argument".to_owned()),
+        ];
+        let tags = [("ticket", "tag".to_owned())];
+        let resource = build(&mock_version(), vars.clone(), &arguments, &[]);
+        assert_eq!(get(&resource, "region").as_deref(), Some("argument"));
+        assert_eq!(get(&resource, "service.name").as_deref(), Some("argument"));
+        let resource = build(&mock_version(), vars, &arguments, &tags);
+        assert_eq!(get(&resource, "region").as_deref(), Some("argument"));
+        assert_eq!(get(&resource, "ticket").as_deref(), Some("tag"));
+    }
+}
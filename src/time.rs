@@ -0,0 +1,236 @@
+//! Timers that stop at shutdown.
+//!
+//! [`interval`] and [`sleep`] work like their [`tokio::time`] counterparts,
+//! but end as soon as the program starts [shutting down](crate::shutdown).
+//!
+//! Interval ticks stay aligned to the schedule set when the interval was
+//! created: a late tick does not move the following ones. With the default
+//! [`MissedTickBehavior::Skip`], ticks that were missed entirely are skipped
+//! and counted as `ticks_skipped` telemetry loss, see [`LossCounter`].
+//!
+//! [`LossCounter`]: crate::LossCounter
+use crate::{
+    loss,
+    shutdown::{receiver, wait},
+};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::{
+    sync::watch::Receiver,
+    time::{self, Instant},
+};
+
+pub use tokio::time::MissedTickBehavior;
+
+/// Returned by [`sleep`] when it was interrupted by shutdown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Error)]
+#[error("cancelled by shutdown")]
+pub struct Cancelled;
+
+/// Wait for `duration`, or until shutdown.
+///
+/// # Errors
+///
+/// Returns [`Cancelled`] if the program started shutting down first.
+pub async fn sleep(duration: Duration) -> Result<(), Cancelled> {
+    sleep_with(duration, receiver()).await
+}
+
+async fn sleep_with(duration: Duration, mut shutdown: Receiver<bool>) -> Result<(), Cancelled> {
+    tokio::select! {
+        biased;
+        () = wait(&mut shutdown) => Err(Cancelled),
+        () = time::sleep(duration) => Ok(()),
+    }
+}
+
+/// Ticks every `period`, starting one `period` from now, until shutdown.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+#[must_use]
+pub fn interval(period: Duration) -> Interval {
+    Interval::new(period, receiver())
+}
+
+/// See [`interval`].
+#[derive(Debug)]
+pub struct Interval {
+    inner:    time::Interval,
+    shutdown: Receiver<bool>,
+    skipped:  u64,
+}
+
+impl Interval {
+    pub(crate) fn new(period: Duration, shutdown: Receiver<bool>) -> Self {
+        let mut inner = time::interval_at(Instant::now() + period, period);
+        inner.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        Self {
+            inner,
+            shutdown,
+            skipped: 0,
+        }
+    }
+
+    /// Wait for the next tick and return its scheduled time, or `None` once
+    /// the program is shutting down.
+    pub async fn tick(&mut self) -> Option<Instant> {
+        let scheduled = tokio::select! {
+            biased;
+            () = wait(&mut self.shutdown) => return None,
+            scheduled = self.inner.tick() => scheduled,
+        };
+        // Bursts catch up on the missed ticks instead, delays shift the schedule.
+        if self.inner.missed_tick_behavior() == MissedTickBehavior::Skip {
+            let missed = scheduled.elapsed().as_nanos() / self.period().as_nanos();
+            if missed > 0 {
+                let missed = u64::try_from(missed).unwrap_or(u64::MAX);
+                self.skipped = self.skipped.saturating_add(missed);
+                loss::TICKS_SKIPPED.add(missed);
+            }
+        }
+        Some(scheduled)
+    }
+
+    #[must_use]
+    pub fn period(&self) -> Duration {
+        self.inner.period()
+    }
+
+    #[must_use]
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.inner.missed_tick_behavior()
+    }
+
+    /// How to handle ticks the task was too late for,
+    /// [`MissedTickBehavior::Skip`] by default.
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.inner.set_missed_tick_behavior(behavior);
+    }
+
+    /// Number of ticks skipped so far.
+    #[must_use]
+    pub const fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::sync::watch;
+
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aligned() {
+        let (_sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        let mut interval = Interval::new(PERIOD, shutdown);
+        for n in 1..=5 {
+            assert_eq!(interval.tick().await, Some(start + secs(10 * n)));
+            // Work between ticks does not shift the schedule.
+            time::sleep(secs(3)).await;
+        }
+        assert_eq!(interval.skipped(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missed_ticks() {
+        let (_sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        let skipped = loss::TICKS_SKIPPED.get();
+        let mut interval = Interval::new(PERIOD, shutdown);
+        assert_eq!(interval.tick().await, Some(start + secs(10)));
+        time::sleep(secs(35)).await;
+
+        // The late tick fires immediately, the ones at 30s and 40s are skipped.
+        assert_eq!(interval.tick().await, Some(start + secs(20)));
+        assert_eq!(Instant::now(), start + secs(45));
+        assert_eq!(interval.skipped(), 2);
+        assert!(loss::TICKS_SKIPPED.get() - skipped >= 2);
+        assert_eq!(interval.tick().await, Some(start + secs(50)));
+        assert_eq!(interval.skipped(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst() {
+        let (_sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        let mut interval = Interval::new(PERIOD, shutdown);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        assert_eq!(interval.missed_tick_behavior(), MissedTickBehavior::Burst);
+        time::sleep(secs(35)).await;
+        for n in 1..=5 {
+            assert_eq!(interval.tick().await, Some(start + secs(10 * n)));
+        }
+        assert_eq!(Instant::now(), start + secs(50));
+        assert_eq!(interval.skipped(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay() {
+        let (_sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        let mut interval = Interval::new(PERIOD, shutdown);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        assert_eq!(interval.tick().await, Some(start + secs(10)));
+        time::sleep(secs(35)).await;
+
+        // The late tick fires immediately and the next one a period later.
+        assert_eq!(interval.tick().await, Some(start + secs(20)));
+        assert_eq!(interval.tick().await, Some(start + secs(55)));
+        assert_eq!(interval.skipped(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown() {
+        let (sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        let mut interval = Interval::new(PERIOD, shutdown.clone());
+        tokio::spawn(async move {
+            time::sleep(secs(15)).await;
+            sender.send(true).unwrap();
+            // Keep the channel open.
+            time::sleep(secs(3600)).await;
+        });
+        assert_eq!(interval.tick().await, Some(start + secs(10)));
+        assert_eq!(interval.tick().await, None);
+        assert_eq!(Instant::now(), start + secs(15));
+        assert_eq!(interval.tick().await, None);
+
+        // Already shutting down.
+        assert_eq!(sleep_with(secs(60), shutdown).await, Err(Cancelled));
+        assert_eq!(Instant::now(), start + secs(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sleep() {
+        let (sender, shutdown) = watch::channel(false);
+        let start = Instant::now();
+        assert_eq!(sleep_with(secs(60), shutdown.clone()).await, Ok(()));
+        assert_eq!(Instant::now(), start + secs(60));
+
+        let interrupt = tokio::spawn(async move {
+            time::sleep(secs(5)).await;
+            sender.send(true).unwrap();
+        });
+        assert_eq!(sleep_with(secs(60), shutdown).await, Err(Cancelled));
+        assert_eq!(Instant::now(), start + secs(65));
+        interrupt.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_public() {
+        let start = Instant::now();
+        let mut ticks = interval(PERIOD);
+        assert_eq!(ticks.period(), PERIOD);
+        assert_eq!(ticks.tick().await, Some(start + PERIOD));
+        assert_eq!(sleep(secs(1)).await, Ok(()));
+    }
+}
@@ -0,0 +1,262 @@
+#![cfg(feature = "tls")]
+#![cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+//! Loading and reloading of PEM certificates and keys.
+//!
+//! Files are validated when loaded, errors name the offending path. Server
+//! certificates can be reloaded at runtime; with the `signals` feature this
+//! happens on `SIGHUP`. A failed reload is logged and the previous
+//! certificate stays in use.
+use crate::config_issue::ConfigIssue;
+use eyre::{bail, ensure, Result as EyreResult, WrapErr};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+/// Read a PEM file.
+pub fn read(path: &Path) -> EyreResult<Vec<u8>> {
+    fs::read(path).wrap_err_with(|| format!("Could not read {}", path.display()))
+}
+
+/// Parse and validate the certificates in a PEM file.
+pub fn certs(path: &Path, pem: &[u8]) -> EyreResult<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &*pem)
+        .wrap_err_with(|| format!("Could not parse PEM in {}", path.display()))?;
+    ensure!(
+        !certs.is_empty(),
+        "No certificates found in {}",
+        path.display()
+    );
+    let certs = certs.into_iter().map(Certificate).collect::<Vec<_>>();
+    for cert in &certs {
+        // Parses the certificate, which is all we want here.
+        RootCertStore::empty()
+            .add(cert)
+            .wrap_err_with(|| format!("Invalid certificate in {}", path.display()))?;
+    }
+    Ok(certs)
+}
+
+/// Parse the first private key in a PEM file.
+pub fn key(path: &Path, pem: &[u8]) -> EyreResult<PrivateKey> {
+    let items = rustls_pemfile::read_all(&mut &*pem)
+        .wrap_err_with(|| format!("Could not parse PEM in {}", path.display()))?;
+    for item in items {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    bail!("No private key found in {}", path.display())
+}
+
+/// An error if `field` does not name a PEM file with certificates.
+pub fn check_certs(field: &str, path: &Path) -> Option<ConfigIssue> {
+    let err = read(path).and_then(|pem| certs(path, &pem)).err()?;
+    Some(ConfigIssue::error(
+        field,
+        path.display(),
+        format!("{err:#}"),
+    ))
+}
+
+/// An error if `field` does not name a PEM file with a private key.
+pub fn check_key(field: &str, path: &Path) -> Option<ConfigIssue> {
+    let err = read(path).and_then(|pem| key(path, &pem)).err()?;
+    Some(ConfigIssue::error(
+        field,
+        path.display(),
+        format!("{err:#}"),
+    ))
+}
+
+/// Build a server configuration from a certificate chain and key file.
+pub fn server_config(cert: &Path, key: &Path) -> EyreResult<ServerConfig> {
+    let chain = certs(cert, &read(cert)?)?;
+    let private = self::key(key, &read(key)?)?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, private)
+        .wrap_err_with(|| {
+            format!(
+                "Could not use certificate {} with key {}",
+                cert.display(),
+                key.display()
+            )
+        })
+}
+
+/// Server TLS configuration that can be reloaded from disk.
+pub struct ServerTls {
+    cert:   PathBuf,
+    key:    PathBuf,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ServerTls {
+    pub fn load(cert: PathBuf, key: PathBuf) -> EyreResult<Self> {
+        let config = server_config(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    /// Acceptor using the current certificate.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+    /// Reload the certificate and key. On failure the current ones stay in
+    /// use.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn reload(&self) -> bool {
+        match server_config(&self.cert, &self.key) {
+            Ok(config) => {
+                *self.config.write().unwrap() = Arc::new(config);
+                info!(cert = %self.cert.display(), "Reloaded TLS certificate");
+                true
+            }
+            Err(report) => {
+                error!(
+                    ?report,
+                    "Could not reload TLS certificate, keeping the current one"
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Reload the certificate whenever the process receives `SIGHUP`.
+#[cfg(all(unix, feature = "signals"))]
+pub fn reload_on_hangup(tls: Arc<ServerTls>) -> EyreResult<()> {
+    use crate::shutdown::{process_receiver, wait};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).wrap_err("Could not install SIGHUP handler")?;
+    let mut shutdown = process_receiver();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                () = wait(&mut shutdown) => break,
+                received = hangup.recv() => {
+                    if received.is_none() {
+                        break;
+                    }
+                    tls.reload();
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use rustls::{ClientConfig, ServerName};
+    use std::{env, process};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tracing_test::traced_test;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    /// Handshake with `acceptor` trusting `ca`, returning whether it
+    /// succeeded.
+    async fn handshake(acceptor: TlsAcceptor, ca: &str) -> bool {
+        let mut roots = RootCertStore::empty();
+        for cert in certs(&fixture(ca), &read(&fixture(ca)).unwrap()).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let (client, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            stream.write_all(b"hello").await?;
+            stream.shutdown().await
+        });
+        let name = ServerName::try_from("localhost").unwrap();
+        let Ok(mut stream) = connector.connect(name, client).await else {
+            return false;
+        };
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        server.await.unwrap().unwrap();
+        buffer == b"hello"
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let tls = ServerTls::load(fixture("cert-1.pem"), fixture("key-1.pem")).unwrap();
+        assert!(handshake(tls.acceptor(), "cert-1.pem").await);
+        assert!(!handshake(tls.acceptor(), "cert-2.pem").await);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = server_config(&fixture("missing.pem"), &fixture("key-1.pem")).unwrap_err();
+        assert!(err.to_string().contains("Could not read"), "{err}");
+        assert!(err.to_string().contains("missing.pem"), "{err}");
+
+        let err = server_config(&fixture("invalid.pem"), &fixture("key-1.pem")).unwrap_err();
+        assert!(err.to_string().contains("Invalid certificate in"), "{err}");
+        assert!(err.to_string().contains("invalid.pem"), "{err}");
+
+        let err = server_config(&fixture("key-1.pem"), &fixture("key-1.pem")).unwrap_err();
+        assert!(
+            err.to_string().contains("No certificates found in"),
+            "{err}"
+        );
+
+        let err = server_config(&fixture("cert-1.pem"), &fixture("cert-1.pem")).unwrap_err();
+        assert!(err.to_string().contains("No private key found in"), "{err}");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_reload() {
+        let dir = env::temp_dir().join(format!("cli-batteries-tls-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let install = |n: u8| {
+            fs::copy(fixture(&format!("cert-{n}.pem")), &cert).unwrap();
+            fs::copy(fixture(&format!("key-{n}.pem")), &key).unwrap();
+        };
+
+        install(1);
+        let tls = ServerTls::load(cert.clone(), key.clone()).unwrap();
+
+        // A broken certificate is rejected and the current one kept.
+        fs::copy(fixture("invalid.pem"), &cert).unwrap();
+        assert!(!tls.reload());
+        assert!(logs_contain("Could not reload TLS certificate"));
+        assert!(handshake(tls.acceptor(), "cert-1.pem").await);
+
+        install(2);
+        assert!(tls.reload());
+        assert!(handshake(tls.acceptor(), "cert-2.pem").await);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
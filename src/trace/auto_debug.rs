@@ -0,0 +1,272 @@
+//! `--auto-debug`: debug logs from the app while errors spike.
+//!
+//! When an incident starts, the logs that would explain it are usually at a
+//! level that is filtered out. With `--auto-debug` the errors counted by
+//! [`log_counters`](super::log_counters) are checked every second. When more
+//! than `--auto-debug-error-rate` were logged in the last minute, the app
+//! crates are logged at `DEBUG` for `--auto-debug-duration`, and then the
+//! configured filter is swapped back in.
+//!
+//! The raised filter is swapped into the reloadable filters like a `SIGHUP`
+//! reload, and filters reloaded during the window are raised as well. Levels
+//! are only raised: an app crate configured at `TRACE` stays at `TRACE`. A
+//! window starts at most once per `--auto-debug-cooldown`, counted from the
+//! start of the previous one, so a steady error rate does not keep debug
+//! logging on. The start and end of each window are logged.
+//!
+//! The filter of OTLP exports, `--otlp-log-filter`, is not raised. Traces are
+//! always sampled, so the window needs no sampler change.
+use super::{filter_reload::FilterOptions, log_counters};
+use crate::{config_issue::ConfigIssue, default_from_clap, time::interval};
+use clap::Parser;
+use eyre::Result as EyreResult;
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// How often the error count is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The span `--auto-debug-error-rate` is counted over.
+const RATE_WINDOW: Duration = Duration::from_mins(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Field names are the flag names
+pub struct Options {
+    /// Log the app crates at DEBUG for a while when the error rate spikes.
+    #[clap(long, env)]
+    auto_debug: bool,
+
+    /// Errors per minute above which `--auto-debug` starts a window.
+    #[clap(long, env, default_value = "10")]
+    auto_debug_error_rate: u64,
+
+    /// How long an `--auto-debug` window logs at DEBUG.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "2m")]
+    auto_debug_duration: Duration,
+
+    /// The least time from the start of one `--auto-debug` window to the
+    /// start of the next.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "10m")]
+    auto_debug_cooldown: Duration,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Problems with the settings, if `--auto-debug` is set.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if !self.auto_debug {
+            return issues;
+        }
+        if self.auto_debug_duration.is_zero() {
+            issues.push(ConfigIssue::error(
+                "auto-debug-duration",
+                "0s",
+                "must be longer than zero",
+            ));
+        }
+        if self.auto_debug_cooldown < self.auto_debug_duration {
+            issues.push(
+                ConfigIssue::warning(
+                    "auto-debug-cooldown",
+                    humantime::format_duration(self.auto_debug_cooldown),
+                    "is shorter than --auto-debug-duration, windows may follow each other",
+                )
+                .suggest(format!(
+                    "at least {}",
+                    humantime::format_duration(self.auto_debug_duration)
+                )),
+            );
+        }
+        issues
+    }
+
+    /// Watch the error rate until shutdown, if `--auto-debug` is set.
+    pub fn start(&self, filter: FilterOptions) {
+        self.start_with(
+            || log_counters().error,
+            move |raised| filter.set_raised(raised),
+        );
+    }
+
+    /// Watch the error total returned by `errors`, and call `set_raised` at
+    /// the start and end of each window.
+    fn start_with<E, R>(&self, mut errors: E, set_raised: R)
+    where
+        E: FnMut() -> u64 + Send + 'static,
+        R: Fn(bool) -> EyreResult<String> + Send + 'static,
+    {
+        if !self.auto_debug {
+            return;
+        }
+        let mut controller = Controller::new(*self, Instant::now(), errors());
+        tokio::spawn(async move {
+            let mut interval = interval(CHECK_INTERVAL);
+            while let Some(now) = interval.tick().await {
+                let Some(transition) = controller.check(now, errors()) else {
+                    continue;
+                };
+                let raised = matches!(transition, Transition::Raise { .. });
+                match (transition, set_raised(raised)) {
+                    (Transition::Raise { errors }, Ok(filter)) => warn!(
+                        errors,
+                        %filter,
+                        "{errors} errors in the last minute, logging the app at DEBUG for {}",
+                        humantime::format_duration(controller.options.auto_debug_duration)
+                    ),
+                    (Transition::Restore, Ok(filter)) => {
+                        info!(%filter, "Auto-debug window over, restored the log filter");
+                    }
+                    (_, Err(report)) => {
+                        error!(
+                            ?report,
+                            raised, "Could not change the log filter for auto-debug"
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transition {
+    /// The error rate crossed the threshold, with the errors of the last
+    /// minute.
+    Raise { errors: u64 },
+    /// The window is over.
+    Restore,
+}
+
+/// The state of `--auto-debug`, from the error totals it is given.
+#[derive(Clone, Debug)]
+struct Controller {
+    options: Options,
+    /// Error totals of the last minute, oldest first.
+    samples: VecDeque<(Instant, u64)>,
+    /// The start of the last window.
+    started: Option<Instant>,
+    raised:  bool,
+}
+
+impl Controller {
+    fn new(options: Options, now: Instant, errors: u64) -> Self {
+        Self {
+            options,
+            samples: VecDeque::from([(now, errors)]),
+            started: None,
+            raised: false,
+        }
+    }
+
+    /// The transition, if any, at `now` with `errors` logged in total.
+    fn check(&mut self, now: Instant, errors: u64) -> Option<Transition> {
+        while self.samples.len() > 1
+            && self
+                .samples
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let recent = errors.saturating_sub(self.samples.front().map_or(0, |(_, total)| *total));
+        self.samples.push_back((now, errors));
+
+        let options = &self.options;
+        if self.raised {
+            let started = self.started?;
+            if now.duration_since(started) < options.auto_debug_duration {
+                return None;
+            }
+            self.raised = false;
+            return Some(Transition::Restore);
+        }
+        let cooled = self
+            .started
+            .is_none_or(|started| now.duration_since(started) >= options.auto_debug_cooldown);
+        if recent > options.auto_debug_error_rate && cooled {
+            self.raised = true;
+            self.started = Some(now);
+            return Some(Transition::Raise { errors: recent });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::sleep;
+
+    fn options(args: &[&str]) -> Options {
+        Options::try_parse_from(["arg0", "--auto-debug"].iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn test_transitions() {
+        let options = options(&["--auto-debug-duration=2m", "--auto-debug-cooldown=10m"]);
+        let mut now = Instant::now();
+        let mut controller = Controller::new(options, now, 0);
+        let mut errors = 0;
+        let mut check = |seconds, new| {
+            now += Duration::from_secs(seconds);
+            errors += new;
+            controller.check(now, errors)
+        };
+        // Ten errors a minute is the limit, not above it
+        assert_eq!(check(30, 5), None);
+        assert_eq!(check(29, 5), None);
+        // The first five are more than a minute old
+        assert_eq!(check(2, 5), None);
+        assert_eq!(check(1, 6), Some(Transition::Raise { errors: 16 }));
+        // Raised until the window is over, however many errors
+        assert_eq!(check(60, 100), None);
+        assert_eq!(check(59, 100), None);
+        assert_eq!(check(1, 100), Some(Transition::Restore));
+        // Not again within the cool-down
+        assert_eq!(check(60, 100), None);
+        assert_eq!(check(7 * 60 - 1, 100), None);
+        assert_eq!(check(1, 100), Some(Transition::Raise { errors: 100 }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_start() {
+        let errors = Arc::new(Mutex::new(0));
+        let raised = Arc::new(Mutex::new(Vec::<bool>::new()));
+        options(&["--auto-debug-error-rate=2", "--auto-debug-duration=10s"]).start_with(
+            {
+                let errors = errors.clone();
+                move || *errors.lock().unwrap()
+            },
+            {
+                let raised = raised.clone();
+                move |value| {
+                    raised.lock().unwrap().push(value);
+                    Ok(String::new())
+                }
+            },
+        );
+        sleep(Duration::from_millis(2500)).await;
+        assert!(raised.lock().unwrap().is_empty());
+        *errors.lock().unwrap() = 3;
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(*raised.lock().unwrap(), [true]);
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(*raised.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Options::default().validate(), vec![]);
+        assert_eq!(options(&[]).validate(), vec![]);
+        let issues = options(&["--auto-debug-cooldown=1m"]).validate();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "auto-debug-cooldown");
+        let issues = options(&["--auto-debug-duration=0s"]).validate();
+        assert_eq!(issues[0].field, "auto-debug-duration");
+    }
+}
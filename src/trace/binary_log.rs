@@ -0,0 +1,943 @@
+//! Compact binary log output, for services that log faster than text can be
+//! written.
+//!
+//! `--binary-log <path>` writes every event that passes the log filter to a
+//! file in a length prefixed binary format. Targets, span names and field
+//! names are interned: a string is written once as a dictionary entry and
+//! events refer to it by number. The whole dictionary is written again every
+//! [`DICTIONARY_INTERVAL`] events, so a reader that lost entries to
+//! corruption recovers the names.
+//!
+//! Events are encoded into a buffer under a short lock and handed to a writer
+//! thread in chunks. The calling thread never waits for the file. When the
+//! writer falls behind by [`QUEUE_CHUNKS`] chunks, new chunks are dropped and
+//! counted as telemetry loss. Partial chunks are written every
+//! [`FLUSH_INTERVAL`] and at shutdown.
+//!
+//! The file starts with [`MAGIC`] and the format version as a little endian
+//! `u16`. Each frame is a sync marker, the payload length and its CRC-32,
+//! and the payload in [postcard](https://docs.rs/postcard). A reader that
+//! finds a damaged frame skips ahead to the next sync marker with a valid
+//! checksum. [`BinaryLogReader`] turns a file back into
+//! [`LogRecord`](crate::logs::LogRecord)s, `--decode-binary-log <path>`
+//! prints it as JSON lines and `--cat-session-log <path>` in the one line per
+//! event text format.
+//!
+//! `--record-events` writes the same format with the span lifecycle added, see
+//! [`replay`](super::replay). Readers of this version skip the span frames.
+use super::global_fields::Fields;
+use crate::{
+    config_issue::{self, ConfigIssue},
+    logs::LogRecord,
+    loss::BINARY_LOG_DROPPED,
+};
+use chrono::{SecondsFormat, TimeZone as _, Utc};
+use clap::Parser;
+use eyre::{bail, Result as EyreResult, WrapErr as _};
+use flate2::Crc;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Write as _},
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// First bytes of a binary log file, followed by the format version.
+pub const MAGIC: [u8; 6] = *b"CLIBLG";
+
+/// Version of the format written. Readers reject files of later versions.
+pub const VERSION: u16 = 1;
+
+/// Number of events between two copies of the whole dictionary.
+pub const DICTIONARY_INTERVAL: u64 = 4096;
+
+/// Number of chunks queued for the writer thread before chunks are dropped.
+pub const QUEUE_CHUNKS: usize = 64;
+
+/// How often a partial chunk is written.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Start of every frame.
+const SYNC: [u8; 4] = [0xb1, 0x0c, 0x5e, 0x7a];
+
+/// Sync marker, payload length and CRC-32.
+const FRAME_HEADER: usize = 12;
+
+/// Frames are never larger, a larger length is corruption.
+const MAX_PAYLOAD: usize = 1 << 24;
+
+/// A chunk is handed to the writer once it is this large.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The `--binary-log` output, for [`finish`].
+static FILE_LOG: OnceCell<BinaryLog> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Also write log events to this file in a compact binary format, with
+    /// the same filter as the log output.
+    #[clap(long, env, value_name = "PATH")]
+    binary_log: Option<PathBuf>,
+
+    /// Print a binary log file as JSON lines and exit.
+    #[clap(long, value_name = "PATH")]
+    decode_binary_log: Option<PathBuf>,
+}
+
+impl Options {
+    /// Create the binary log file, if requested.
+    pub fn open(&self, fields: Fields) -> EyreResult<Option<BinaryLog>> {
+        let Some(path) = &self.binary_log else {
+            return Ok(None);
+        };
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Error creating binary log {}", path.display()))?;
+        let log = BinaryLog::new(file)
+            .wrap_err("Error starting binary log")?
+            .with_fields(fields);
+        let _ = FILE_LOG.set(log.clone());
+        Ok(Some(log))
+    }
+
+    /// Whether the binary log can be created.
+    pub fn validate(&self) -> Option<ConfigIssue> {
+        config_issue::writable("binary-log", self.binary_log.as_deref()?)
+    }
+}
+
+/// Write the rest of the `--binary-log` file and stop its writer thread.
+pub fn finish() -> EyreResult<()> {
+    if let Some(log) = FILE_LOG.get() {
+        log.finish().wrap_err("Error writing binary log")?;
+    }
+    Ok(())
+}
+
+/// Whether `data` starts like a binary log.
+#[must_use]
+pub fn is_binary_log(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Frame payloads.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(super) enum Frame<'a> {
+    /// A new dictionary entry.
+    Intern {
+        id:    u32,
+        value: &'a str,
+    },
+    /// The whole dictionary, replacing the one of the reader.
+    Dictionary(#[serde(borrow)] Vec<(u32, &'a str)>),
+    Event(#[serde(borrow)] Record<'a>),
+    /// A new span of a recording, with its recorded id.
+    NewSpan(#[serde(borrow)] SpanRecord<'a>),
+    /// Fields recorded on a span after it was created.
+    Values {
+        span:   u64,
+        #[serde(borrow)]
+        fields: Vec<(u32, FieldValue<'a>)>,
+    },
+    Enter(u64),
+    Exit(u64),
+    Close(u64),
+    /// An event of a recording, with the recorded id of its span.
+    Scoped {
+        parent: Option<u64>,
+        #[serde(borrow)]
+        event:  Record<'a>,
+    },
+}
+
+/// An event, with strings known at compile time as dictionary ids.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct Record<'a> {
+    /// Nanoseconds since the Unix epoch.
+    pub timestamp: i64,
+    /// `0` for `TRACE` to `4` for `ERROR`.
+    pub level:     u8,
+    pub target:    u32,
+    /// Name of the span.
+    pub span:      Option<u32>,
+    pub message:   &'a str,
+    #[serde(borrow)]
+    pub fields:    Vec<(u32, FieldValue<'a>)>,
+}
+
+/// A span, with strings known at compile time as dictionary ids.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct SpanRecord<'a> {
+    pub id:        u64,
+    pub parent:    Option<u64>,
+    /// Nanoseconds since the Unix epoch.
+    pub timestamp: i64,
+    pub level:     u8,
+    pub target:    u32,
+    pub name:      u32,
+    /// All declared fields, also those recorded later.
+    pub names:     Vec<u32>,
+    #[serde(borrow)]
+    pub fields:    Vec<(u32, FieldValue<'a>)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) enum FieldValue<'a> {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(&'a str),
+    /// Recorded with `Debug`, only in recordings. The binary log writes
+    /// these as [`Str`](Self::Str).
+    Debug(&'a str),
+}
+
+pub(super) const fn level_to_u8(level: Level) -> u8 {
+    match level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
+    }
+}
+
+const fn level_name(level: u8) -> &'static str {
+    match level {
+        0 => "TRACE",
+        1 => "DEBUG",
+        2 => "INFO",
+        3 => "WARN",
+        _ => "ERROR",
+    }
+}
+
+pub(super) const fn level_from_u8(level: u8) -> Level {
+    match level {
+        0 => Level::TRACE,
+        1 => Level::DEBUG,
+        2 => Level::INFO,
+        3 => Level::WARN,
+        _ => Level::ERROR,
+    }
+}
+
+/// Append a frame with `payload` to `out`.
+pub(super) fn write_frame(out: &mut Vec<u8>, payload: &Frame<'_>) {
+    let start = out.len();
+    out.extend_from_slice(&SYNC);
+    out.extend_from_slice(&[0; 8]);
+    *out = postcard::to_extend(payload, mem::take(out)).expect("a Vec is never full");
+    let mut crc = Crc::new();
+    crc.update(&out[start + FRAME_HEADER..]);
+    #[allow(clippy::cast_possible_truncation)] // Events are far smaller
+    let len = (out.len() - start - FRAME_HEADER) as u32;
+    out[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+    out[start + 8..start + 12].copy_from_slice(&crc.sum().to_le_bytes());
+}
+
+/// Interns strings and counts events for the dictionary copies.
+#[derive(Default)]
+pub(super) struct Encoder {
+    ids:    HashMap<&'static str, u32>,
+    events: u64,
+}
+
+impl Encoder {
+    pub(super) fn intern(&mut self, value: &'static str, out: &mut Vec<u8>) -> u32 {
+        if let Some(id) = self.ids.get(value) {
+            return *id;
+        }
+        #[allow(clippy::cast_possible_truncation)] // Strings are `'static`
+        let id = self.ids.len() as u32;
+        self.ids.insert(value, id);
+        write_frame(out, &Frame::Intern { id, value });
+        id
+    }
+
+    /// Append an event, after a copy of the dictionary every
+    /// [`DICTIONARY_INTERVAL`] events.
+    pub(super) fn event(&mut self, event: &Frame<'_>, out: &mut Vec<u8>) {
+        self.events += 1;
+        #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` needs Rust 1.87
+        let repeat = self.events % DICTIONARY_INTERVAL == 0;
+        if repeat {
+            let entries = self.ids.iter().map(|(value, id)| (*id, *value)).collect();
+            write_frame(out, &Frame::Dictionary(entries));
+        }
+        write_frame(out, event);
+    }
+}
+
+/// Shared by the layer and the writer thread.
+struct State {
+    encoder: Encoder,
+    chunk:   Vec<u8>,
+    /// Events in `chunk`, counted as lost when it is dropped.
+    events:  u64,
+    /// `None` once finished or the writer failed.
+    sender:  Option<SyncSender<Vec<u8>>>,
+    thread:  Option<JoinHandle<io::Result<()>>>,
+}
+
+impl State {
+    /// Queue the chunk, or drop it when the writer is behind.
+    fn send(&mut self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        match sender.try_send(chunk) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                BINARY_LOG_DROPPED.add(self.events);
+                // The dropped dictionary entries are written again.
+                self.encoder = Encoder::default();
+            }
+            Err(TrySendError::Disconnected(_)) => self.sender = None,
+        }
+        self.events = 0;
+    }
+
+    /// Queued chunks and the partial one, oldest first. Chunks are only
+    /// queued with the lock held, so none can be queued in between.
+    fn take_pending(&mut self, receiver: &Receiver<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut pending = receiver.try_iter().collect::<Vec<_>>();
+        if !self.chunk.is_empty() {
+            pending.push(mem::take(&mut self.chunk));
+            self.events = 0;
+        }
+        pending
+    }
+}
+
+/// Layer writing events in the binary log format, see the
+/// [module docs](self).
+///
+/// Apply a filter, it writes every event it receives.
+#[derive(Clone)]
+pub struct BinaryLog {
+    state:  Arc<Mutex<State>>,
+    fields: Fields,
+}
+
+impl BinaryLog {
+    /// Write the file header to `writer` and start the writer thread.
+    ///
+    /// # Errors
+    ///
+    /// When the header can not be written or the thread not started.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        let (sender, receiver) = sync_channel(QUEUE_CHUNKS);
+        let state = Arc::new(Mutex::new(State {
+            encoder: Encoder::default(),
+            chunk:   Vec::with_capacity(CHUNK_SIZE),
+            events:  0,
+            sender:  Some(sender),
+            thread:  None,
+        }));
+        let weak = Arc::downgrade(&state);
+        let thread = thread::Builder::new()
+            .name("binary-log".to_owned())
+            .spawn(move || write_chunks(writer, &receiver, &weak))?;
+        state.lock().unwrap().thread = Some(thread);
+        Ok(Self {
+            state,
+            fields: Fields::default(),
+        })
+    }
+
+    /// Add the `--tag` fields to every event.
+    fn with_fields(mut self, fields: Fields) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Write the rest of the log and stop the writer thread. Later events are
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// When writing failed, now or earlier.
+    #[allow(clippy::missing_panics_doc)] // Only when poisoned
+    pub fn finish(&self) -> io::Result<()> {
+        let (chunk, sender, thread) = {
+            let mut state = self.state.lock().unwrap();
+            (
+                mem::take(&mut state.chunk),
+                state.sender.take(),
+                state.thread.take(),
+            )
+        };
+        // Sent without the lock, the writer may need it for a flush.
+        if let Some(sender) = sender {
+            if !chunk.is_empty() {
+                let _ = sender.send(chunk);
+            }
+        }
+        match thread.map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("binary log writer panicked")),
+            None => Ok(()),
+        }
+    }
+
+    /// Append frames with `encode`, counted as one event when the chunk is
+    /// dropped.
+    #[allow(clippy::missing_panics_doc)] // Only when poisoned
+    pub(super) fn write(&self, encode: impl FnOnce(&mut Encoder, &Fields, &mut Vec<u8>)) {
+        let mut state = self.state.lock().unwrap();
+        if state.sender.is_none() {
+            return;
+        }
+        let State { encoder, chunk, .. } = &mut *state;
+        encode(encoder, &self.fields, chunk);
+        state.events += 1;
+        if state.chunk.len() >= CHUNK_SIZE {
+            state.send();
+        }
+    }
+}
+
+impl<S> Layer<S> for BinaryLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let captured = Captured::new(event, ctx.event_span(event).map(|span| span.name()));
+        self.write(|encoder, fields, out| captured.encode(encoder, fields, out));
+    }
+}
+
+/// An event with its fields recorded, ready to be encoded.
+pub(super) struct Captured {
+    timestamp: i64,
+    metadata:  &'static Metadata<'static>,
+    span:      Option<&'static str>,
+    recorder:  Recorder,
+}
+
+impl Captured {
+    /// Record `event`, in the span named `span`.
+    pub(super) fn new(event: &Event<'_>, span: Option<&'static str>) -> Self {
+        let mut recorder = Recorder::default();
+        event.record(&mut recorder);
+        Self {
+            timestamp: timestamp(),
+            metadata: event.metadata(),
+            span,
+            recorder,
+        }
+    }
+
+    /// Append the frames of the event to `out`, with the `--tag` `fields`.
+    pub(super) fn encode(&self, encoder: &mut Encoder, fields: &Fields, out: &mut Vec<u8>) {
+        let record = self.record(encoder, fields, false, out);
+        encoder.event(&Frame::Event(record), out);
+    }
+
+    /// The event with its names interned, writing new dictionary entries to
+    /// `out`. Values recorded with `Debug` are kept apart if `debug`.
+    pub(super) fn record<'a>(
+        &'a self,
+        encoder: &mut Encoder,
+        fields: &'a Fields,
+        debug: bool,
+        out: &mut Vec<u8>,
+    ) -> Record<'a> {
+        let target = encoder.intern(self.metadata.target(), out);
+        let span = self.span.map(|name| encoder.intern(name, out));
+        let recorded = &self.recorder.fields;
+        let globals = fields
+            .iter()
+            .filter(|(name, _)| recorded.iter().all(|(field, _)| field != name))
+            .map(|(name, value)| (*name, FieldValue::Str(value)));
+        let fields = recorded
+            .iter()
+            .map(|(name, value)| (*name, value.as_field_value(debug)))
+            .chain(globals)
+            .map(|(name, value)| (encoder.intern(name, out), value))
+            .collect();
+        Record {
+            timestamp: self.timestamp,
+            level: level_to_u8(*self.metadata.level()),
+            target,
+            span,
+            message: &self.recorder.message,
+            fields,
+        }
+    }
+}
+
+/// Nanoseconds since the Unix epoch.
+pub(super) fn timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| i64::try_from(time.as_nanos()).unwrap_or(i64::MAX))
+}
+
+/// The writer thread. Stops when the log is finished or writing fails.
+fn write_chunks<W: Write>(
+    mut writer: W,
+    receiver: &Receiver<Vec<u8>>,
+    state: &Weak<Mutex<State>>,
+) -> io::Result<()> {
+    loop {
+        match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(chunk) => writer.write_all(&chunk)?,
+            Err(RecvTimeoutError::Timeout) => {
+                let Some(state) = state.upgrade() else {
+                    return writer.flush();
+                };
+                let pending = state.lock().unwrap().take_pending(receiver);
+                for chunk in pending {
+                    writer.write_all(&chunk)?;
+                }
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Disconnected) => return writer.flush(),
+        }
+    }
+}
+
+/// Event fields, with the message apart.
+#[derive(Default)]
+pub(super) struct Recorder {
+    message: String,
+    fields:  Vec<(&'static str, Recorded)>,
+}
+
+impl Recorder {
+    /// The fields with their names interned, for a span. A span has no
+    /// message, a field named so is kept with the others.
+    pub(super) fn span_fields(
+        &self,
+        encoder: &mut Encoder,
+        out: &mut Vec<u8>,
+    ) -> Vec<(u32, FieldValue<'_>)> {
+        let message =
+            (!self.message.is_empty()).then(|| ("message", FieldValue::Debug(&self.message)));
+        self.fields
+            .iter()
+            .map(|(name, value)| (*name, value.as_field_value(true)))
+            .chain(message)
+            .map(|(name, value)| (encoder.intern(name, out), value))
+            .collect()
+    }
+}
+
+enum Recorded {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Debug(String),
+}
+
+impl Recorded {
+    /// Values recorded with `Debug` as [`FieldValue::Str`], unless `debug`.
+    fn as_field_value(&self, debug: bool) -> FieldValue<'_> {
+        match self {
+            Self::I64(value) => FieldValue::I64(*value),
+            Self::U64(value) => FieldValue::U64(*value),
+            Self::F64(value) => FieldValue::F64(*value),
+            Self::Bool(value) => FieldValue::Bool(*value),
+            Self::Debug(value) if debug => FieldValue::Debug(value),
+            Self::Str(value) | Self::Debug(value) => FieldValue::Str(value),
+        }
+    }
+}
+
+impl Visit for Recorder {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.push((field.name(), Recorded::I64(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.push((field.name(), Recorded::U64(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.push((field.name(), Recorded::F64(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.push((field.name(), Recorded::Bool(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            value.clone_into(&mut self.message);
+        } else {
+            self.fields
+                .push((field.name(), Recorded::Str(value.to_owned())));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message.clear();
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            self.fields
+                .push((field.name(), Recorded::Debug(format!("{value:?}"))));
+        }
+    }
+}
+
+/// Reads [`LogRecord`]s from a binary log, see the [module docs](self).
+pub struct BinaryLogReader<R> {
+    reader:     R,
+    buffer:     Vec<u8>,
+    /// Start of the unread part of `buffer`.
+    position:   usize,
+    eof:        bool,
+    version:    u16,
+    dictionary: HashMap<u32, String>,
+    skipped:    u64,
+}
+
+impl<R: Read> BinaryLogReader<R> {
+    /// Read and check the file header.
+    ///
+    /// # Errors
+    ///
+    /// When reading fails, the input is not a binary log or of a later
+    /// version than this build reads.
+    pub fn new(reader: R) -> EyreResult<Self> {
+        let mut result = Self {
+            reader,
+            buffer: Vec::new(),
+            position: 0,
+            eof: false,
+            version: 0,
+            dictionary: HashMap::new(),
+            skipped: 0,
+        };
+        let header = MAGIC.len() + 2;
+        if !result.fill(header)? || result.buffer[..MAGIC.len()] != MAGIC {
+            bail!("Not a binary log");
+        }
+        result.version = u16::from_le_bytes([result.buffer[6], result.buffer[7]]);
+        if result.version > VERSION {
+            bail!(
+                "Binary log version {} is not supported, this build reads up to {VERSION}",
+                result.version
+            );
+        }
+        result.position = header;
+        Ok(result)
+    }
+
+    /// Format version of the file.
+    #[must_use]
+    pub const fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Bytes skipped so far because they were corrupt or cut short.
+    #[must_use]
+    pub const fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The next record, or `None` at the end. Corrupt data is skipped.
+    ///
+    /// # Errors
+    ///
+    /// When reading fails.
+    pub fn next_record(&mut self) -> EyreResult<Option<LogRecord>> {
+        self.next_with(|dictionary, frame| match frame {
+            Frame::Event(record) | Frame::Scoped { event: record, .. } => {
+                Some(to_log_record(dictionary, &record))
+            }
+            _ => None,
+        })
+    }
+
+    /// The next value `f` makes of a frame, or `None` at the end. Dictionary
+    /// frames are applied and not passed on, corrupt data is skipped.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub(super) fn next_with<T>(
+        &mut self,
+        mut f: impl FnMut(&HashMap<u32, String>, Frame<'_>) -> Option<T>,
+    ) -> EyreResult<Option<T>> {
+        loop {
+            if !self.fill(FRAME_HEADER)? {
+                self.skip(self.buffer.len() - self.position);
+                return Ok(None);
+            }
+            let header = &self.buffer[self.position..self.position + FRAME_HEADER];
+            if header[..4] != SYNC {
+                // Skip to the next possible marker.
+                let skip = header
+                    .windows(SYNC.len())
+                    .skip(1)
+                    .position(|window| window == SYNC)
+                    .map_or(FRAME_HEADER - SYNC.len() + 1, |index| index + 1);
+                self.skip(skip);
+                continue;
+            }
+            let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            if len > MAX_PAYLOAD || !self.fill(FRAME_HEADER + len)? {
+                self.skip(1);
+                continue;
+            }
+            let start = self.position + FRAME_HEADER;
+            let payload = &self.buffer[start..start + len];
+            let mut check = Crc::new();
+            check.update(payload);
+            let frame = if check.sum() == crc {
+                postcard::from_bytes::<Frame<'_>>(payload).ok()
+            } else {
+                None
+            };
+            let Some(frame) = frame else {
+                self.skip(1);
+                continue;
+            };
+            let value = match frame {
+                Frame::Intern { id, value } => {
+                    self.dictionary.insert(id, value.to_owned());
+                    None
+                }
+                Frame::Dictionary(entries) => {
+                    self.dictionary = entries
+                        .into_iter()
+                        .map(|(id, value)| (id, value.to_owned()))
+                        .collect();
+                    None
+                }
+                frame => f(&self.dictionary, frame),
+            };
+            self.position = start + len;
+            if let Some(value) = value {
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    const fn skip(&mut self, n: usize) {
+        self.position += n;
+        self.skipped += n as u64;
+    }
+
+    /// Make sure `n` unread bytes are buffered. Returns `false` if the input
+    /// ends first.
+    fn fill(&mut self, n: usize) -> io::Result<bool> {
+        if self.buffer.len() - self.position >= n {
+            return Ok(true);
+        }
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        let mut chunk = [0; 8192];
+        while self.buffer.len() < n && !self.eof {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.buffer.len() >= n)
+    }
+}
+
+fn to_log_record(dictionary: &HashMap<u32, String>, record: &Record<'_>) -> LogRecord {
+    // Entries lost to corruption show as their number.
+    let lookup = |id: u32| {
+        dictionary
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("#{id}"))
+    };
+    let fields = record
+        .fields
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                FieldValue::I64(value) => Value::from(*value),
+                FieldValue::U64(value) => Value::from(*value),
+                FieldValue::F64(value) => {
+                    Number::from_f64(*value).map_or_else(|| value.to_string().into(), Value::Number)
+                }
+                FieldValue::Bool(value) => Value::from(*value),
+                FieldValue::Str(value) | FieldValue::Debug(value) => Value::from(*value),
+            };
+            (lookup(*name), value)
+        })
+        .collect::<Map<_, _>>();
+    LogRecord {
+        timestamp: Utc
+            .timestamp_nanos(record.timestamp)
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        level: level_name(record.level).to_owned(),
+        target: Some(lookup(record.target)),
+        message: record.message.to_owned(),
+        span: record.span.map(lookup),
+        fields,
+        ..LogRecord::default()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use tracing::{debug, info, info_span, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    /// Log `count` events to a binary log, returning the file contents.
+    fn encode(count: u64) -> Vec<u8> {
+        let capture = Capture::default();
+        let log = BinaryLog::new(capture.clone()).unwrap();
+        let fields = Arc::from(vec![("run", "7".to_owned())]);
+        let subscriber = Registry::default().with(log.clone().with_fields(fields));
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..count {
+                info_span!("request").in_scope(|| {
+                    debug!(target: "app::db", i, ratio = 0.5, ok = true, "query {}", "done");
+                });
+            }
+            warn!(target: "app", run = "override", note = ?Some(1), "last");
+        });
+        log.finish().unwrap();
+        log.finish().unwrap();
+        capture.bytes()
+    }
+
+    fn decode(data: &[u8]) -> (Vec<LogRecord>, u64) {
+        let mut reader = BinaryLogReader::new(data).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        (records, reader.skipped())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = encode(3);
+        assert!(data.starts_with(&MAGIC));
+        let (records, skipped) = decode(&data);
+        assert_eq!(skipped, 0);
+        assert_eq!(records.len(), 4);
+        let first = &records[0];
+        assert_eq!(first.level, "DEBUG");
+        assert_eq!(first.target.as_deref(), Some("app::db"));
+        assert_eq!(first.message, "query done");
+        assert_eq!(first.span.as_deref(), Some("request"));
+        assert_eq!(first.fields["i"], 0);
+        assert_eq!(first.fields["ratio"], 0.5);
+        assert_eq!(first.fields["ok"], true);
+        assert_eq!(first.fields["run"], "7");
+        assert!(first.timestamp.ends_with('Z'), "{}", first.timestamp);
+        assert_eq!(records[2].fields["i"], 2);
+        let last = &records[3];
+        assert_eq!(last.level, "WARN");
+        assert_eq!(last.span, None);
+        assert_eq!(last.fields["run"], "override");
+        assert_eq!(last.fields["note"], "Some(1)");
+    }
+
+    #[test]
+    fn test_interning() {
+        // Names are written once, not per event.
+        let data = encode(100);
+        let count = |name: &[u8]| data.windows(name.len()).filter(|w| *w == name).count();
+        assert_eq!(count(b"app::db"), 1);
+        assert_eq!(count(b"ratio"), 1);
+        assert_eq!(count(b"query done"), 100);
+    }
+
+    #[test]
+    fn test_resync() {
+        let data = encode(2 * DICTIONARY_INTERVAL);
+        let (records, _) = decode(&data);
+        let total = records.len();
+
+        // Damage the middle, including the first dictionary entries.
+        let mut damaged = data.clone();
+        for byte in &mut damaged[8..200] {
+            *byte = 0xb1;
+        }
+        let middle = data.len() / 2;
+        damaged[middle..middle + 100].fill(0x5e);
+        let (records, skipped) = decode(&damaged);
+        assert!(skipped >= 292, "{skipped}");
+        assert!(records.len() < total);
+        assert!(records.len() > total - 20, "{} of {total}", records.len());
+        // Names are lost until the next dictionary copy.
+        assert_eq!(records[0].target.as_deref(), Some("#0"));
+        let last_debug = &records[records.len() - 2];
+        assert_eq!(last_debug.target.as_deref(), Some("app::db"));
+        assert_eq!(last_debug.fields["i"], 2 * DICTIONARY_INTERVAL - 1);
+
+        // Cut short.
+        let (records, skipped) = decode(&data[..data.len() - 3]);
+        assert_eq!(records.len(), total - 1);
+        assert!(skipped > 0);
+    }
+
+    #[test]
+    fn test_header() {
+        assert!(BinaryLogReader::new(&b"{\"timestamp\""[..]).is_err());
+        let mut data = encode(1);
+        assert_eq!(BinaryLogReader::new(&data[..]).unwrap().version(), VERSION);
+        data[6..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = BinaryLogReader::new(&data[..]).err().unwrap().to_string();
+        assert!(err.contains("not supported"), "{err}");
+    }
+
+    #[test]
+    fn test_dropped_when_behind() {
+        /// Blocks until released.
+        struct Stuck(Arc<Mutex<()>>);
+        impl Write for Stuck {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                drop(self.0.lock().unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let gate = Arc::new(Mutex::new(()));
+        let log = BinaryLog::new(Stuck(gate.clone())).unwrap();
+        let held = gate.lock().unwrap();
+        let before = BINARY_LOG_DROPPED.get();
+        let message = "x".repeat(1024);
+        tracing::subscriber::with_default(Registry::default().with(log.clone()), || {
+            for _ in 0..(QUEUE_CHUNKS + 16) * CHUNK_SIZE / 1024 {
+                info!("{message}");
+            }
+        });
+        assert!(BINARY_LOG_DROPPED.get() > before);
+        drop(held);
+        log.finish().unwrap();
+    }
+}
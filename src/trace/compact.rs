@@ -0,0 +1,276 @@
+//! The `compact` log format: the span names, target and message, followed by
+//! `key=value` pairs of the event fields and then the span fields.
+//!
+//! ```text
+//! 2023-04-18T12:00:00.000000Z  INFO serve:accept: app: accepted peer=10.0.0.1 serve.port=8080
+//! ```
+//!
+//! Values are quoted and escaped as in the `logfmt` format, only if needed.
+//! Span fields are prefixed with the name of their span, so an event field
+//! and a span field of the same name can be told apart. With
+//! `--log-field-width` longer values are cut, marked with `…`. Colors follow
+//! the ANSI setting of the writer.
+use super::{
+    log_timestamp::{LogTimestamp, Timer},
+    logfmt::{key, pairs, Visitor},
+};
+use ansi_term::{Colour, Style};
+use std::{fmt::Result, thread};
+use tracing::{span::Record, Event, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        format::Writer, time::FormatTime, FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    registry::{LookupSpan, Scope},
+};
+
+pub struct Compact {
+    timer:       Timer,
+    target:      bool,
+    thread:      bool,
+    /// Field values are cut after this many characters.
+    field_width: Option<usize>,
+}
+
+impl Default for Compact {
+    fn default() -> Self {
+        Self::new(Timer::new(LogTimestamp::Rfc3339))
+    }
+}
+
+impl Compact {
+    pub const fn new(timer: Timer) -> Self {
+        Self {
+            timer,
+            target: true,
+            thread: false,
+            field_width: None,
+        }
+    }
+
+    pub const fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// With the thread name and id after the level.
+    pub const fn with_thread(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    /// With field values cut after `width` characters. Of the span fields
+    /// only if set on the [`FormatFields`] of the layer as well.
+    pub const fn with_field_width(mut self, width: Option<usize>) -> Self {
+        self.field_width = width;
+        self
+    }
+}
+
+impl<S> FormatEvent<S, Self> for Compact
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, Self>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let normalized_meta = event.normalized_metadata();
+        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
+        let ansi = writer.has_ansi_escapes();
+        let style = |style: Style| {
+            if ansi {
+                style
+            } else {
+                Style::new()
+            }
+        };
+        let dimmed = style(Style::new().dimmed());
+        let bold = style(Style::new().bold());
+
+        if !self.timer.is_none() {
+            write!(writer, "{}", dimmed.prefix())?;
+            self.timer.format_time(&mut writer)?;
+            write!(writer, "{} ", dimmed.suffix())?;
+        }
+        let (level, colour) = match *meta.level() {
+            Level::TRACE => ("TRACE", Colour::Purple),
+            Level::DEBUG => ("DEBUG", Colour::Blue),
+            Level::INFO => (" INFO", Colour::Green),
+            Level::WARN => (" WARN", Colour::Yellow),
+            Level::ERROR => ("ERROR", Colour::Red),
+        };
+        write!(writer, "{} ", style(colour.normal()).paint(level))?;
+        if self.thread {
+            let thread = thread::current();
+            if let Some(name) = thread.name() {
+                write!(writer, "{name} ")?;
+            }
+            write!(writer, "{:0>2?} ", thread.id())?;
+        }
+
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(Scope::from_root)
+            .collect::<Vec<_>>();
+        for span in &spans {
+            write!(writer, "{}:", bold.paint(span.name()))?;
+        }
+        if !spans.is_empty() {
+            write!(writer, " ")?;
+        }
+        if self.target {
+            write!(
+                writer,
+                "{}{} ",
+                bold.paint(meta.target()),
+                dimmed.paint(":")
+            )?;
+        }
+
+        // Message, event fields, then span fields
+        let mut visitor = Visitor::with_width(self.field_width);
+        event.record(&mut visitor);
+        write!(writer, "{}", visitor.message)?;
+        let mut separator = if visitor.message.is_empty() { "" } else { " " };
+        for (key, value) in &visitor.fields {
+            write!(writer, "{separator}{key}={value}")?;
+            separator = " ";
+        }
+        for span in &spans {
+            let extensions = span.extensions();
+            let Some(fields) = extensions.get::<FormattedFields<Self>>() else {
+                continue;
+            };
+            let name = key(span.name());
+            for (key, value) in pairs(&fields.fields) {
+                write!(
+                    writer,
+                    "{separator}{}",
+                    dimmed.paint(format!("{name}.{key}={value}"))
+                )?;
+                separator = " ";
+            }
+        }
+
+        writeln!(writer)
+    }
+}
+
+impl<'writer> FormatFields<'writer> for Compact {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> Result {
+        let mut visitor = Visitor::with_width(self.field_width);
+        fields.record(&mut visitor);
+        visitor.write(&mut writer, true)
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> Result {
+        let empty = current.is_empty();
+        let mut visitor = Visitor::with_width(self.field_width);
+        fields.record(&mut visitor);
+        visitor.write(&mut current.as_writer(), empty)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{pretty_compact::test::check_golden, test::Capture};
+    use tracing::{debug, error, info, info_span, warn};
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+    fn render(field_width: Option<usize>, ansi: bool, f: impl FnOnce()) -> String {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(capture.clone())
+                .with_ansi(ansi)
+                .fmt_fields(Compact::default().with_field_width(field_width))
+                .event_format(
+                    Compact::new(Timer::new(LogTimestamp::None)).with_field_width(field_width),
+                ),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        capture.contents()
+    }
+
+    #[test]
+    fn test_golden_no_fields() {
+        let output = render(None, false, || {
+            info!(target: "app", "plain");
+            warn!(target: "app::server", "two words, and \"quotes\"");
+        });
+        check_golden("compact_no_fields.txt", &output);
+    }
+
+    #[test]
+    fn test_golden_one_field() {
+        let output = render(None, false, || {
+            info!(target: "app", user_id = 42, "logged in");
+            info!(target: "app", user = "ana", "");
+            debug!(target: "app", path = "/tmp/a b", "quoted");
+        });
+        check_golden("compact_one_field.txt", &output);
+    }
+
+    #[test]
+    fn test_golden_many_fields() {
+        let output = render(None, false, || {
+            let err = std::io::Error::other("boom\nagain");
+            error!(
+                target: "app",
+                port = 8080,
+                host = "example.com",
+                r#type = "a b",
+                ok = true,
+                dbg = ?"q",
+                error = &err as &dyn std::error::Error,
+                "many"
+            );
+            info_span!("outer", id = 1, user = "x y").in_scope(|| {
+                info_span!("inner", id = 2).in_scope(|| {
+                    info!(target: "app", id = 3, "in spans");
+                });
+            });
+        });
+        check_golden("compact_many_fields.txt", &output);
+    }
+
+    #[test]
+    fn test_field_width() {
+        let output = render(Some(4), false, || {
+            info_span!("request", path = "/users/42").in_scope(|| {
+                info!(target: "app", name = "ünïcödé", id = 1234, short = "abc", "cut");
+            });
+        });
+        assert_eq!(
+            output,
+            " INFO request: app: cut name=ünïc… id=1234 short=abc request.path=/use…\n"
+        );
+    }
+
+    #[test]
+    fn test_ansi() {
+        let output = render(None, true, || {
+            info_span!("request", id = 7).in_scope(|| info!(target: "app", count = 3, "colored"));
+        });
+        assert!(
+            output.contains(&Colour::Green.paint(" INFO").to_string()),
+            "{output}"
+        );
+        assert!(output.contains("colored count=3"), "{output}");
+        assert!(
+            output.contains(&Style::new().dimmed().paint("request.id=7").to_string()),
+            "{output}"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Find logging that is paid for but never shown.
+//!
+//! With `--warn-expensive-disabled-logging` every callsite is enabled, and for
+//! each event the log filter (`--verbose` and `--log-filter`) rejects, the
+//! time it takes to record its fields is measured per callsite. This is what
+//! eager field values cost, for example a large `?value` or a `format!` in
+//! the field list. The most expensive callsites are reported at shutdown, as
+//! candidates for [`lazy_field`](crate::lazy_field).
+//!
+//! Enabling every callsite makes the program slower, this is for diagnosis
+//! only.
+use once_cell::sync::OnceCell;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::{Debug, Write as _},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    warn, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{filter::Targets, layer::Context, Layer};
+
+/// Number of callsites reported at shutdown.
+pub const TOP: usize = 10;
+
+static STATS: OnceCell<Arc<Stats>> = OnceCell::new();
+
+/// Recording cost per callsite.
+#[derive(Default)]
+pub struct Stats(Mutex<HashMap<Identifier, Entry>>);
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    metadata: &'static Metadata<'static>,
+    events:   u64,
+    time:     Duration,
+}
+
+impl Stats {
+    #[allow(clippy::significant_drop_tightening)] // The entry is updated under the lock
+    fn add(&self, metadata: &'static Metadata<'static>, time: Duration) {
+        let mut entries = self.0.lock().unwrap();
+        let entry = entries.entry(metadata.callsite()).or_insert_with(|| Entry {
+            metadata,
+            events: 0,
+            time: Duration::ZERO,
+        });
+        entry.events += 1;
+        entry.time += time;
+    }
+
+    /// The `n` most expensive callsites, most expensive first.
+    fn top(&self, n: usize) -> Vec<Entry> {
+        let mut entries = self.0.lock().unwrap().values().copied().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| Reverse(entry.time));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// Measures events rejected by `filter`. Must not be filtered itself.
+pub struct DisabledCost {
+    filter: Targets,
+    stats:  Arc<Stats>,
+}
+
+impl DisabledCost {
+    /// Make the stats the ones [`report`] logs.
+    pub fn new(filter: Targets) -> Self {
+        let stats = STATS.get_or_init(Arc::default).clone();
+        Self { filter, stats }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for DisabledCost {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if self
+            .filter
+            .would_enable(metadata.target(), metadata.level())
+        {
+            return;
+        }
+        let mut visitor = Visitor(String::new());
+        let start = Instant::now();
+        event.record(&mut visitor);
+        self.stats.add(metadata, start.elapsed());
+    }
+}
+
+/// Formats every field like an output layer would, into a reused buffer.
+struct Visitor(String);
+
+impl Visit for Visitor {
+    fn record_debug(&mut self, _field: &Field, value: &dyn Debug) {
+        self.0.clear();
+        let _ = write!(self.0, "{value:?}");
+    }
+}
+
+/// Log the most expensive callsites, if measuring.
+#[allow(clippy::cast_precision_loss)] // Event counts fit
+pub fn report() {
+    let Some(stats) = STATS.get() else {
+        return;
+    };
+    for entry in stats.top(TOP) {
+        let metadata = entry.metadata;
+        warn!(
+            callsite = %format_args!(
+                "{}:{}",
+                metadata.file().unwrap_or("unknown"),
+                metadata.line().unwrap_or_default()
+            ),
+            callsite.target = metadata.target(),
+            callsite.level = %metadata.level(),
+            events = entry.events,
+            total_ms = entry.time.as_secs_f64() * 1e3,
+            mean_us = entry.time.as_secs_f64() * 1e6 / entry.events as f64,
+            "Expensive disabled logging"
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing::{debug, info, Level};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_measures_rejected_events() {
+        let filter = Targets::new().with_default(Level::INFO);
+        let stats = Arc::new(Stats::default());
+        let layer = DisabledCost {
+            filter,
+            stats: stats.clone(),
+        };
+        let large = vec![7_u64; 100_000];
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for _ in 0..3 {
+                debug!(large = ?large, "large");
+            }
+            debug!("small");
+            info!(large = ?large, "shown");
+        });
+
+        let top = stats.top(TOP);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].metadata.level(), &Level::DEBUG);
+        assert_eq!(top[0].events, 3);
+        assert!(top[0].metadata.name().contains(file!()), "{top:?}");
+        assert_eq!(top[1].events, 1);
+        assert!(top[0].time > top[1].time);
+        assert_eq!(stats.top(1).len(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+//! What log files do when the disk is full.
+//!
+//! A full disk makes every write to a log file fail, which the log layers
+//! can only report on stderr, once per event. [`DiskFull`] wraps the file
+//! writers of `--log-file` and the session log and applies the
+//! `--log-disk-full-policy`:
+//!
+//! * `drop`: discard the event and count it as `log_disk_full` telemetry loss.
+//! * `block`: retry the write, holding up the thread that logged, for at most
+//!   [`BLOCK_TIMEOUT`] per event before dropping it like `drop`.
+//! * `fallback-stderr`: alert once on stderr and write this and all later
+//!   events of the file to stderr instead.
+//!
+//! Writes are retried with every event, so `drop` and `block` resume writing
+//! the file once space is freed. Other errors are passed on.
+use crate::loss::{LossCounter, LOG_DISK_FULL};
+use clap::ValueEnum;
+use std::{
+    io::{self, stderr, ErrorKind, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The longest a `block` write waits for space.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pause between the retries of a `block` write.
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum DiskFullPolicy {
+    Drop,
+    Block,
+    FallbackStderr,
+}
+
+/// A log file writer with a [`DiskFullPolicy`].
+pub struct DiskFull<W> {
+    name:        &'static str,
+    inner:       Arc<W>,
+    policy:      DiskFullPolicy,
+    losses:      &'static LossCounter,
+    fallen_back: AtomicBool,
+}
+
+impl<W> DiskFull<W> {
+    pub fn new(name: &'static str, inner: Arc<W>, policy: DiskFullPolicy) -> Self {
+        Self {
+            name,
+            inner,
+            policy,
+            losses: &LOG_DISK_FULL,
+            fallen_back: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<W> DiskFull<W>
+where
+    for<'a> &'a W: Write,
+{
+    /// Write the rest of an event, advancing `rest` past what was written.
+    fn write_inner(&self, rest: &mut &[u8]) -> io::Result<()> {
+        while !rest.is_empty() {
+            match (&*self.inner).write(rest) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => *rest = &rest[n..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_event(&self, event: &[u8]) -> io::Result<()> {
+        let mut rest = event;
+        match self.write_inner(&mut rest) {
+            Err(err) if is_disk_full(&err) => {}
+            result => return result,
+        }
+        match self.policy {
+            DiskFullPolicy::Drop => self.losses.add(1),
+            DiskFullPolicy::Block => {
+                let deadline = Instant::now() + BLOCK_TIMEOUT;
+                loop {
+                    thread::sleep(RETRY_INTERVAL);
+                    match self.write_inner(&mut rest) {
+                        Err(err) if is_disk_full(&err) && Instant::now() < deadline => {}
+                        Err(err) if is_disk_full(&err) => {
+                            self.losses.add(1);
+                            break;
+                        }
+                        result => return result,
+                    }
+                }
+            }
+            DiskFullPolicy::FallbackStderr => {
+                if !self.fallen_back.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "Error: the disk of the {} is full, logging to stderr instead",
+                        self.name
+                    );
+                }
+                return stderr().write_all(rest);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W> Write for &DiskFull<W>
+where
+    for<'a> &'a W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fallen_back.load(Ordering::Relaxed) {
+            return stderr().write(buf);
+        }
+        self.write_event(buf).map(|()| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.fallen_back.load(Ordering::Relaxed) {
+            return stderr().flush();
+        }
+        match (&*self.inner).flush() {
+            Err(err) if is_disk_full(&err) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn is_disk_full(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::StorageFull
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A disk with room for `capacity` bytes.
+    struct Disk {
+        capacity: Mutex<usize>,
+        written:  Mutex<Vec<u8>>,
+    }
+
+    impl Disk {
+        fn new(capacity: usize) -> Arc<Self> {
+            Arc::new(Self {
+                capacity: Mutex::new(capacity),
+                written:  Mutex::default(),
+            })
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.written.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for &Disk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let capacity = *self.capacity.lock().unwrap();
+            let mut written = self.written.lock().unwrap();
+            let room = capacity.saturating_sub(written.len());
+            if room == 0 {
+                return Err(ErrorKind::StorageFull.into());
+            }
+            let n = buf.len().min(room);
+            written.extend_from_slice(&buf[..n]);
+            drop(written);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn writer(
+        disk: &Arc<Disk>,
+        policy: DiskFullPolicy,
+        losses: &'static LossCounter,
+    ) -> DiskFull<Disk> {
+        DiskFull {
+            losses,
+            ..DiskFull::new("test log", disk.clone(), policy)
+        }
+    }
+
+    #[test]
+    fn test_drop() {
+        static LOSSES: LossCounter = LossCounter::new("test_disk_full_drop");
+        let disk = Disk::new(10);
+        let writer = writer(&disk, DiskFullPolicy::Drop, &LOSSES);
+        assert_eq!((&writer).write(b"first\n").unwrap(), 6);
+        // Partially written, the rest of the event is dropped.
+        assert_eq!((&writer).write(b"second\n").unwrap(), 7);
+        assert_eq!((&writer).write(b"third\n").unwrap(), 6);
+        assert_eq!(LOSSES.get(), 2);
+        assert_eq!(disk.contents(), "first\nseco");
+    }
+
+    #[test]
+    fn test_block() {
+        static LOSSES: LossCounter = LossCounter::new("test_disk_full_block");
+        let disk = Disk::new(9);
+        let writer = writer(&disk, DiskFullPolicy::Block, &LOSSES);
+
+        // Space is freed while the write waits, it continues where it was.
+        let freeing = {
+            let disk = disk.clone();
+            thread::spawn(move || {
+                thread::sleep(BLOCK_TIMEOUT / 10);
+                *disk.capacity.lock().unwrap() = 100;
+            })
+        };
+        let start = Instant::now();
+        (&writer).write_all(b"first\nsecond\n").unwrap();
+        assert!(start.elapsed() >= BLOCK_TIMEOUT / 10);
+        freeing.join().unwrap();
+        assert_eq!(LOSSES.get(), 0);
+        assert_eq!(disk.contents(), "first\nsecond\n");
+
+        // Dropped after the timeout.
+        *disk.capacity.lock().unwrap() = disk.written.lock().unwrap().len();
+        let start = Instant::now();
+        (&writer).write_all(b"third\n").unwrap();
+        assert!(start.elapsed() >= BLOCK_TIMEOUT);
+        assert_eq!(LOSSES.get(), 1);
+        assert!(!disk.contents().contains("third"));
+    }
+
+    #[test]
+    fn test_fallback_stderr() {
+        static LOSSES: LossCounter = LossCounter::new("test_disk_full_fallback");
+        let disk = Disk::new(8);
+        let writer = writer(&disk, DiskFullPolicy::FallbackStderr, &LOSSES);
+        (&writer).write_all(b"first\n").unwrap();
+        assert!(!writer.fallen_back.load(Ordering::Relaxed));
+        // The rest of the event goes to stderr.
+        (&writer).write_all(b"second\n").unwrap();
+        assert!(writer.fallen_back.load(Ordering::Relaxed));
+
+        // Stays on stderr when space is freed.
+        *disk.capacity.lock().unwrap() = 100;
+        (&writer).write_all(b"third\n").unwrap();
+        assert_eq!(disk.contents(), "first\nse");
+        assert_eq!(LOSSES.get(), 0);
+    }
+
+    #[test]
+    fn test_other_errors() {
+        struct Broken;
+        impl Write for &Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(ErrorKind::PermissionDenied.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let writer = DiskFull::new("test log", Arc::new(Broken), DiskFullPolicy::Drop);
+        let err = (&writer).write(b"event\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+}
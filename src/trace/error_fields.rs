@@ -0,0 +1,337 @@
+//! Errors with their cause chain and span trace in the `json` and `otlp`
+//! formats.
+//!
+//! A field recorded as an error, like `error = &err as &dyn Error` or the
+//! [`ErrorReport`] of a failed run, is written as `error.message`,
+//! `error.chain` with the messages of its sources and `error.span_trace`. In
+//! the `otlp` format the first one becomes the `exception.message` and
+//! `exception.stacktrace` attributes of the OpenTelemetry exception
+//! conventions. The span trace is the one color-eyre captured for a report,
+//! or that of a [`TracedError`](tracing_error::TracedError) in the chain.
+//!
+//! Formatted as `error = %err` an error is only its message, there is no
+//! chain to render.
+//!
+//! The fields are spliced into the line the inner format wrote, so the keys
+//! keep their order and formats that rebuild the event, like the global
+//! fields, do not need to preserve errors.
+use eyre::Report;
+use serde_json::Value;
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display, Formatter, Result, Write as _},
+    iter::successors,
+    marker::PhantomData,
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_error::{ExtractSpanTrace, SpanTrace, SpanTraceStatus};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::LookupSpan,
+};
+
+/// A report as an error value, so it can be logged as a field.
+pub struct ErrorReport(pub Report);
+
+impl From<Report> for ErrorReport {
+    fn from(report: Report) -> Self {
+        Self(report)
+    }
+}
+
+impl Display for ErrorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Debug for ErrorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Error for ErrorReport {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// The parts of an error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub message:    String,
+    /// The messages of the sources, outermost first.
+    pub chain:      Vec<String>,
+    pub span_trace: Option<String>,
+}
+
+impl ErrorInfo {
+    pub fn new(error: &(dyn Error + 'static)) -> Self {
+        let span_trace = error
+            .downcast_ref::<ErrorReport>()
+            .and_then(|report| report.0.handler().downcast_ref::<color_eyre::Handler>())
+            .and_then(color_eyre::Handler::span_trace)
+            .or_else(|| successors(Some(error), |&error| error.source()).find_map(traced))
+            .filter(|trace| trace.status() == SpanTraceStatus::CAPTURED)
+            .map(ToString::to_string);
+        Self {
+            message: error.to_string(),
+            // A `TracedError` has its span trace as a source
+            chain: successors(error.source(), |&error| error.source())
+                .filter(|&error| traced(error).is_none())
+                .map(ToString::to_string)
+                .collect(),
+            span_trace,
+        }
+    }
+
+    /// The chain and span trace as text, for `exception.stacktrace`.
+    pub fn stacktrace(&self) -> String {
+        let mut text = self.message.clone();
+        if !self.chain.is_empty() {
+            text.push_str("\n\nCaused by:");
+            for (i, cause) in self.chain.iter().enumerate() {
+                let _ = write!(text, "\n{i:>4}: {cause}");
+            }
+        }
+        if let Some(span_trace) = &self.span_trace {
+            text.push_str("\n\nSpan trace:\n");
+            text.push_str(span_trace);
+        }
+        text
+    }
+}
+
+/// The span trace of a [`TracedError`](tracing_error::TracedError).
+fn traced<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a SpanTrace> {
+    error.span_trace()
+}
+
+/// How the error fields are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKeys {
+    /// `error.message`, `error.chain` and `error.span_trace`, by field name.
+    Fields,
+    /// `exception.message` and `exception.stacktrace`.
+    Exception,
+}
+
+impl ErrorKeys {
+    /// The keys and values replacing the field `name`.
+    fn render(self, name: &str, error: &ErrorInfo) -> String {
+        let key = |suffix: &str| Value::String(format!("{name}.{suffix}"));
+        match self {
+            Self::Fields => {
+                let mut keys = format!(
+                    "{}:{},{}:{}",
+                    key("message"),
+                    Value::from(error.message.as_str()),
+                    key("chain"),
+                    Value::from(error.chain.clone())
+                );
+                if let Some(span_trace) = &error.span_trace {
+                    let _ = write!(
+                        keys,
+                        ",{}:{}",
+                        key("span_trace"),
+                        Value::from(span_trace.as_str())
+                    );
+                }
+                keys
+            }
+            Self::Exception => format!(
+                r#""exception.message":{},"exception.stacktrace":{}"#,
+                Value::from(error.message.as_str()),
+                Value::from(error.stacktrace())
+            ),
+        }
+    }
+}
+
+/// Splices the parts of error fields into a JSON line.
+pub struct ErrorFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    inner:    Inner,
+    keys:     ErrorKeys,
+    _phantom: PhantomData<(S, N)>,
+}
+
+impl<Inner, S, N> ErrorFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    pub const fn new(inner: Inner, keys: ErrorKeys) -> Self {
+        Self {
+            inner,
+            keys,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// `line` with the fields of `errors` replaced. A field that can not be
+    /// found, like a redacted one, is left as it is.
+    fn splice(&self, mut line: String, errors: Vec<(&'static str, ErrorInfo)>) -> String {
+        for (name, error) in errors {
+            // Errors without a visitor of their own are recorded as the message
+            let field = format!(
+                "{}:{}",
+                Value::from(name),
+                Value::from(error.message.as_str())
+            );
+            if let Some(start) = line.find(&field) {
+                line.replace_range(start..start + field.len(), &self.keys.render(name, &error));
+                if self.keys == ErrorKeys::Exception {
+                    break;
+                }
+            }
+        }
+        line
+    }
+}
+
+impl<Inner, S, N> FormatEvent<S, N> for ErrorFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let mut errors = Errors::default();
+        event.record(&mut errors);
+        if errors.0.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+        // The only public way to a `Writer` into a string, without ANSI
+        // escapes like any JSON output
+        let mut line = FormattedFields::<()>::new(String::new());
+        self.inner.format_event(ctx, line.as_writer(), event)?;
+        writer.write_str(&self.splice(line.fields, errors.0))
+    }
+}
+
+/// Records the error fields of an event.
+#[derive(Default)]
+struct Errors(Vec<(&'static str, ErrorInfo)>);
+
+impl Visit for Errors {
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        self.0.push((field.name(), ErrorInfo::new(value)));
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{global_fields, test::Capture, LineOptions, LogFormat};
+    use eyre::{eyre, WrapErr as _};
+    use std::io;
+    use tracing::{error, info_span, subscriber::with_default};
+    use tracing_error::{ErrorLayer, InstrumentError};
+    use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, Registry};
+
+    /// The lines logged in `format`.
+    fn render(format: LogFormat, flatten: bool, log: impl FnOnce()) -> Vec<Value> {
+        let capture = Capture::default();
+        let line = LineOptions {
+            span_events: FmtSpan::NONE,
+            flatten,
+            ..LineOptions::default()
+        };
+        let fields = global_fields::fields(&[("run".into(), "7".into())]);
+        let layer = format.into_layer(capture.clone(), fields, &line, false);
+        with_default(
+            Registry::default().with(ErrorLayer::default()).with(layer),
+            log,
+        );
+        capture
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn failure() -> io::Error {
+        io::Error::other("disk full")
+    }
+
+    #[test]
+    fn test_info() {
+        let report = Err::<(), _>(failure())
+            .wrap_err("could not save")
+            .wrap_err("request failed")
+            .unwrap_err();
+        let info = ErrorInfo::new(&ErrorReport(report));
+        assert_eq!(info.message, "request failed");
+        assert_eq!(info.chain, ["could not save", "disk full"]);
+        assert_eq!(info.span_trace, None);
+        assert_eq!(
+            info.stacktrace(),
+            "request failed\n\nCaused by:\n   0: could not save\n   1: disk full"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let lines = render(LogFormat::Json, false, || {
+            info_span!("request", id = 3).in_scope(|| {
+                let error = failure().in_current_span();
+                error!(error = &error as &dyn Error, status = 500, "failed");
+                error!(error = %failure(), "as text");
+            });
+        });
+        let fields = &lines[0]["fields"];
+        assert_eq!(fields["error.message"], "disk full");
+        assert_eq!(fields["error.chain"], Value::Array(vec![]));
+        let span_trace = fields["error.span_trace"].as_str().unwrap();
+        assert!(span_trace.contains("request"), "{span_trace}");
+        assert_eq!(fields.get("error"), None);
+        assert_eq!(fields["status"], 500);
+        assert_eq!(fields["run"], "7");
+        assert_eq!(lines[1]["fields"]["error"], "disk full");
+    }
+
+    #[test]
+    fn test_flatten() {
+        let lines = render(LogFormat::Json, true, || {
+            let report = eyre!("disk full").wrap_err("request failed");
+            error!(cause = &ErrorReport(report) as &dyn Error, "failed");
+        });
+        assert_eq!(lines[0]["cause.message"], "request failed");
+        assert_eq!(lines[0]["cause.chain"], serde_json::json!(["disk full"]));
+        assert_eq!(lines[0].get("cause"), None);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp() {
+        let lines = render(LogFormat::Otlp, false, || {
+            let report = eyre!("disk full").wrap_err("request failed");
+            error!(error = &ErrorReport(report) as &dyn Error, "failed");
+        });
+        let attributes = &lines[0]["Attributes"];
+        assert_eq!(attributes["exception.message"], "request failed");
+        let stacktrace = attributes["exception.stacktrace"].as_str().unwrap();
+        assert!(
+            stacktrace.ends_with("Caused by:\n   0: disk full"),
+            "{stacktrace}"
+        );
+        assert_eq!(attributes.get("error"), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! Keep logged data from controlling the terminal.
+//!
+//! Log output is written through [`Escape`], which replaces control
+//! characters with visible escapes: `\r`, `\xNN` for the other C0 controls and
+//! DEL, `\u{NN}` for C1 controls. Newlines and tabs are kept, as are SGR color
+//! sequences (`ESC [ 1;31 m`) which the formatters emit themselves. Any other
+//! escape sequence, like an OSC window title, is broken up by escaping its
+//! `ESC`. Invalid UTF-8 is replaced with U+FFFD.
+//!
+//! With `--ascii-only` all output is escaped and characters outside of ASCII
+//! become `\u{NN}`, except `µ` of durations, which becomes `u`.
+use clap::ValueEnum;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum EscapeControl {
+    Always,
+    /// Only when the log stream is a terminal.
+    TtyOnly,
+    Never,
+}
+
+impl EscapeControl {
+    /// Whether to escape, `terminal` is whether the log stream is one.
+    pub const fn enabled(self, terminal: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::TtyOnly => terminal,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Wraps a [`MakeWriter`], escaping its output if enabled.
+pub struct Escape<M> {
+    inner:   M,
+    enabled: bool,
+    ascii:   bool,
+}
+
+impl<M> Escape<M> {
+    pub const fn new(inner: M, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            ascii: false,
+        }
+    }
+
+    /// Also escape everything outside of ASCII, implies enabled.
+    pub const fn with_ascii_only(mut self, ascii: bool) -> Self {
+        self.enabled |= ascii;
+        self.ascii = ascii;
+        self
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Escape<M> {
+    type Writer = EscapeWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EscapeWriter {
+            inner:   self.inner.make_writer(),
+            enabled: self.enabled,
+            ascii:   self.ascii,
+        }
+    }
+}
+
+pub struct EscapeWriter<W> {
+    inner:   W,
+    enabled: bool,
+    ascii:   bool,
+}
+
+impl<W: Write> Write for EscapeWriter<W> {
+    /// Events are formatted in full and written at once, so a write never
+    /// ends in the middle of a character or sequence.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.enabled {
+            self.inner.write_all(escape(buf, self.ascii).as_bytes())?;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// See the [module docs](self).
+fn escape(buf: &[u8], ascii: bool) -> String {
+    let text = String::from_utf8_lossy(buf);
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text.as_ref();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '\x1b' { sgr_len(rest) } else { 0 };
+        if len > 0 {
+            result.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+        match c {
+            '\n' | '\t' => result.push(c),
+            '\r' => result.push_str("\\r"),
+            '\0'..='\x1f' | '\x7f' => {
+                let _ = write!(result, "\\x{:02x}", c as u32);
+            }
+            '\u{80}'..='\u{9f}' => {
+                let _ = write!(result, "\\u{{{:x}}}", c as u32);
+            }
+            'µ' if ascii => result.push('u'),
+            _ if ascii && !c.is_ascii() => {
+                let _ = write!(result, "\\u{{{:x}}}", c as u32);
+            }
+            _ => result.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    result
+}
+
+/// Length of the SGR sequence `ESC [ <digits and ;> m` at the start of `s`,
+/// or zero.
+fn sgr_len(s: &str) -> usize {
+    let Some(params) = s.strip_prefix("\x1b[") else {
+        return 0;
+    };
+    let end = params
+        .find(|c: char| !(c.is_ascii_digit() || c == ';'))
+        .unwrap_or(params.len());
+    if params[end..].starts_with('m') {
+        2 + end + 1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{
+        global_fields::Fields,
+        log_timestamp::{LogTimestamp, Timer},
+        pretty_compact::PrettyCompact,
+        test::Capture,
+        LineOptions, LogFormat,
+    };
+    use tracing::{info, warn};
+    use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, Layer, Registry};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(b"plain\ttext\n", false), "plain\ttext\n");
+        assert_eq!(
+            escape(b"\x1b]0;pwned\x07title", false),
+            "\\x1b]0;pwned\\x07title"
+        );
+        assert_eq!(escape(b"done\rfake line", false), "done\\rfake line");
+        assert_eq!(escape(b"\x1b[2J\x1b[Hclear", false), "\\x1b[2J\\x1b[Hclear");
+        assert_eq!(
+            escape(b"\x1b[1;31mred\x1b[0m", false),
+            "\x1b[1;31mred\x1b[0m"
+        );
+        assert_eq!(escape(b"\x1b[31", false), "\\x1b[31");
+        assert_eq!(escape(b"\x00\x08\x7f", false), "\\x00\\x08\\x7f");
+        assert_eq!(
+            escape("\u{9b}31m\u{85}".as_bytes(), false),
+            "\\u{9b}31m\\u{85}"
+        );
+        assert_eq!(
+            escape(b"bad \xff\xfe utf8", false),
+            "bad \u{fffd}\u{fffd} utf8"
+        );
+        assert_eq!(escape("ünïcødé ✓".as_bytes(), false), "ünïcødé ✓");
+        assert_eq!(escape("é ✓ 5µs".as_bytes(), true), "\\u{e9} \\u{2713} 5us");
+        assert_eq!(
+            escape(b"\x1b[1;31mred\x1b[0m\r", true),
+            "\x1b[1;31mred\x1b[0m\\r"
+        );
+    }
+
+    fn render(enabled: bool) -> String {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(Escape::new(capture.clone(), enabled))
+                .with_ansi(false)
+                .event_format(PrettyCompact::new(4, Timer::new(LogTimestamp::None))),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", payload = %"\x1b]0;pwned\x07", "title");
+            warn!(target: "app", "progress 100%\roverwritten");
+            info!(target: "app", "\x1b[2J\u{9b}6n{}", String::from_utf8_lossy(b"\xff"));
+        });
+        capture.contents()
+    }
+
+    #[test]
+    fn test_log_output() {
+        assert_eq!(
+            render(true),
+            "INFO  app  title payload=\\x1b]0;pwned\\x07\nWARN  app  progress \
+             100%\\roverwritten\nINFO  app  \\x1b[2J\\u{9b}6n\u{fffd}\n"
+        );
+        assert_eq!(
+            render(false),
+            "INFO  app  title payload=\x1b]0;pwned\x07\nWARN  app  progress \
+             100%\roverwritten\nINFO  app  \x1b[2J\u{9b}6n\u{fffd}\n"
+        );
+    }
+
+    /// Colors of the formatter survive.
+    #[test]
+    fn test_colors_kept() {
+        let capture = Capture::default();
+        let layer = LogFormat::Tiny
+            .into_layer(
+                Escape::new(capture.clone(), true),
+                Fields::default(),
+                &LineOptions::default(),
+                true,
+            )
+            .with_filter(LevelFilter::INFO);
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", "title \x1b]0;pwned\x07");
+        });
+        let output = capture.contents();
+        assert!(output.contains("\x1b[32mI\x1b[0m"), "{output:?}");
+        assert!(output.ends_with("title \\x1b]0;pwned\\x07\n"), "{output:?}");
+    }
+}
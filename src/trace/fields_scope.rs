@@ -0,0 +1,462 @@
+//! Fields added to every span created below a scope.
+//!
+//! [`fields_scope`] registers key/values while its future is polled. The
+//! [`FieldsScopeLayer`] copies them into every new span beneath it: into the
+//! span extensions as [`ScopeFields`], so the children of the span inherit
+//! them also on other tasks and threads, into the formatted fields of the log
+//! output and into the OpenTelemetry attributes. Nested scopes merge, the
+//! inner value of a key wins, and a field of the span itself wins over both.
+//! Fields matching `--log-redact-fields` are copied as `"[REDACTED]"`.
+use super::{
+    compact::Compact,
+    logfmt::Logfmt,
+    redact::{RedactFields, REDACTED},
+    tiny_log_fmt::TinyLogFmt,
+};
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{display, Field, FieldSet},
+    span::{Attributes, Id, Record},
+    Span, Subscriber, Value,
+};
+use tracing_subscriber::{
+    fmt::{
+        format::{DefaultFields, JsonFields, Pretty},
+        FormatFields, FormattedFields,
+    },
+    layer::Context,
+    registry::{ExtensionsMut, LookupSpan},
+    Layer, Registry,
+};
+
+/// Maximum number of fields of a scope, see [`FieldSet::value_set`].
+const MAX_FIELDS: usize = 32;
+
+/// Leaked field name slices, one per distinct set of names.
+static NAMES: Lazy<Mutex<HashMap<Vec<&'static str>, &'static [&'static str]>>> =
+    Lazy::new(Mutex::default);
+
+thread_local! {
+    /// The scope of the future being polled on this thread.
+    static CURRENT: RefCell<Option<Arc<ScopeFields>>> = const { RefCell::new(None) };
+}
+
+/// The fields of a scope, with those of the outer scopes. Stored in the
+/// extensions of the spans below it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScopeFields {
+    names:  &'static [&'static str],
+    values: Vec<String>,
+}
+
+impl ScopeFields {
+    /// `outer` with `fields` added, a key in both gets the value in `fields`.
+    /// Names past [`MAX_FIELDS`] are dropped.
+    fn merge(
+        outer: Option<&Self>,
+        fields: impl IntoIterator<Item = (&'static str, String)>,
+    ) -> Self {
+        let mut pairs = outer.map_or_else(Vec::new, |outer| {
+            outer
+                .iter()
+                .map(|(name, value)| (name, value.to_owned()))
+                .collect()
+        });
+        for (name, value) in fields {
+            if let Some(pair) = pairs.iter_mut().find(|(existing, _)| *existing == name) {
+                pair.1 = value;
+            } else if pairs.len() < MAX_FIELDS {
+                pairs.push((name, value));
+            }
+        }
+        let (names, values): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        Self {
+            names: leak(names),
+            values,
+        }
+    }
+
+    /// Whether every field of `other` is here with the same value.
+    fn contains(&self, other: &Self) -> bool {
+        other
+            .iter()
+            .all(|(name, value)| self.get(name) == Some(value))
+    }
+
+    /// The value of `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(existing, _)| *existing == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The names and values, outer scopes first.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.names
+            .iter()
+            .copied()
+            .zip(self.values.iter().map(String::as_str))
+    }
+}
+
+/// The `'static` slice of `names`, leaked once per distinct set.
+#[allow(clippy::missing_panics_doc)] // Never panics
+fn leak(names: Vec<&'static str>) -> &'static [&'static str] {
+    let mut leaked = NAMES.lock().unwrap();
+    if let Some(slice) = leaked.get(&names) {
+        return slice;
+    }
+    let slice: &'static [&'static str] = Box::leak(names.clone().into_boxed_slice());
+    leaked.insert(names, slice);
+    drop(leaked);
+    slice
+}
+
+/// The scope of the future being polled, with that of the current span.
+fn current() -> Option<Arc<ScopeFields>> {
+    let span = Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let fields = span.extensions().get::<Arc<ScopeFields>>().cloned();
+            fields
+        })
+        .flatten();
+    inherit(span, CURRENT.with(|current| current.borrow().clone()))
+}
+
+/// The fields of a span below `parent` while `local` is being polled.
+fn inherit(
+    parent: Option<Arc<ScopeFields>>,
+    local: Option<Arc<ScopeFields>>,
+) -> Option<Arc<ScopeFields>> {
+    match (parent, local) {
+        (Some(parent), Some(local)) if !local.contains(&parent) => {
+            let local = local.iter().map(|(name, value)| (name, value.to_owned()));
+            Some(Arc::new(ScopeFields::merge(Some(&parent), local)))
+        }
+        (parent, local) => local.or(parent),
+    }
+}
+
+/// Restores the scope of the thread when dropped.
+struct Enter(Option<Arc<ScopeFields>>);
+
+impl Enter {
+    fn new(fields: Arc<ScopeFields>) -> Self {
+        Self(CURRENT.with(|current| current.replace(Some(fields))))
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+/// Run `future` with `fields` on every span created below it, see the
+/// [module docs](self).
+///
+/// ```
+/// # use cli_batteries::fields_scope;
+/// # use tracing::instrument;
+/// #[instrument]
+/// async fn lookup(key: u64) {}
+///
+/// async fn handle(request_id: u64) {
+///     // The `lookup` span has a `request_id` field
+///     fields_scope([("request_id", request_id)], lookup(7)).await;
+/// }
+/// ```
+///
+/// Spans inherit the fields from their parent, so they also reach spans of
+/// tasks and threads started below an instrumented span in the scope. The
+/// fields are not on the events themselves, but the log formats show the
+/// fields of the spans of an event.
+pub async fn fields_scope<I, V, F>(fields: I, future: F) -> F::Output
+where
+    I: IntoIterator<Item = (&'static str, V)>,
+    V: Display,
+    F: Future,
+{
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| (name, value.to_string()));
+    let scope = Arc::new(ScopeFields::merge(current().as_deref(), fields));
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        let _enter = Enter::new(scope.clone());
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Copies the scope fields into new spans, see the [module docs](self).
+///
+/// [`run`](crate::run) installs it. Other subscribers add it on top of the
+/// layers that store span data, so it finds theirs.
+#[derive(Default)]
+pub struct FieldsScopeLayer {
+    default: DefaultFields,
+    compact: Compact,
+    pretty:  Pretty,
+    tiny:    TinyLogFmt,
+    json:    JsonFields,
+    logfmt:  Logfmt,
+    redact:  RedactFields,
+}
+
+impl FieldsScopeLayer {
+    /// Copies the values of fields matching `redact` as `"[REDACTED]"`.
+    pub(crate) fn redacting(redact: RedactFields) -> Self {
+        Self {
+            redact,
+            ..Self::default()
+        }
+    }
+
+    /// The value of the field `name` as copied.
+    fn value<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.redact.matches(name) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+}
+
+impl<S> Layer<S> for FieldsScopeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<Arc<ScopeFields>>().cloned());
+        let local = CURRENT.with(|current| current.borrow().clone());
+        let Some(scope) = inherit(parent, local).filter(|scope| !scope.names.is_empty()) else {
+            return;
+        };
+
+        // Formatted like fields recorded on the span, without its own, values
+        // unquoted
+        let meta = attrs.metadata();
+        let field_set = FieldSet::new(scope.names, meta.callsite());
+        let fields = field_set.iter().collect::<Vec<_>>();
+        let mut values: [(&Field, Option<&dyn Value>); MAX_FIELDS] =
+            [(&fields[0], None); MAX_FIELDS];
+        let displayed = scope
+            .iter()
+            .map(|(name, value)| display(self.value(name, value)))
+            .collect::<Vec<_>>();
+        for (index, (field, value)) in fields.iter().zip(&displayed).enumerate() {
+            if meta.fields().field(field.name()).is_none() {
+                values[index] = (field, Some(value as &dyn Value));
+            }
+        }
+        let value_set = field_set.value_set(&values);
+        let record = Record::new(&value_set);
+        let mut extensions = span.extensions_mut();
+        add_fields(&self.default, &mut extensions, &record);
+        add_fields(&self.compact, &mut extensions, &record);
+        add_fields(&self.pretty, &mut extensions, &record);
+        add_fields(&self.tiny, &mut extensions, &record);
+        add_fields(&self.json, &mut extensions, &record);
+        add_fields(&self.logfmt, &mut extensions, &record);
+
+        // Attributes the span does not set itself
+        #[cfg(feature = "otlp")]
+        if let Some(otel) = extensions.get_mut::<tracing_opentelemetry::OtelData>() {
+            let attributes = otel.builder.attributes.get_or_insert_with(Default::default);
+            for (name, value) in scope.iter() {
+                let key = opentelemetry::Key::new(name);
+                if !attributes.contains_key(&key) {
+                    attributes.insert(key, self.value(name, value).to_owned().into());
+                }
+            }
+        }
+
+        extensions.insert(scope);
+    }
+}
+
+/// Add `record` to the fields formatted by `formatter`, if a log output with
+/// it has stored them.
+fn add_fields<N>(formatter: &N, extensions: &mut ExtensionsMut<'_>, record: &Record<'_>)
+where
+    N: for<'w> FormatFields<'w> + 'static,
+{
+    if let Some(fields) = extensions.get_mut::<FormattedFields<N>>() {
+        let _ = formatter.add_fields(fields, record);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{block_on, global_fields::Fields, test::Capture, LineOptions, LogFormat};
+    use std::{str::FromStr, thread};
+    use tracing::{dispatcher, info, info_span, Dispatch, Instrument};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// The scope fields of the current span.
+    fn span_fields() -> Vec<(&'static str, String)> {
+        Span::current()
+            .with_subscriber(|(id, dispatch)| {
+                let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+                let scope = span.extensions().get::<Arc<ScopeFields>>()?.clone();
+                let fields = scope.iter().map(|(name, value)| (name, value.to_owned()));
+                Some(fields.collect())
+            })
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn pairs(pairs: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (*name, (*value).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_nested_spans() {
+        let subscriber = Registry::default().with(FieldsScopeLayer::default());
+        tracing::subscriber::with_default(subscriber, || {
+            block_on(fields_scope([("request_id", 7)], async {
+                let outer = info_span!("outer");
+                async {
+                    tokio::task::yield_now().await;
+                    let inner = info_span!("inner").entered();
+                    let deep = info_span!("deep").entered();
+                    assert_eq!(span_fields(), pairs(&[("request_id", "7")]));
+                    drop((deep, inner));
+                }
+                .instrument(outer.clone())
+                .await;
+
+                // Inherited on another thread through the parent span
+                let dispatch = dispatcher::get_default(Dispatch::clone);
+                let on_thread = thread::scope(|scope| {
+                    scope
+                        .spawn(|| {
+                            dispatcher::with_default(&dispatch, || {
+                                let _span = info_span!(parent: &outer, "thread").entered();
+                                span_fields()
+                            })
+                        })
+                        .join()
+                        .unwrap()
+                });
+                assert_eq!(on_thread, pairs(&[("request_id", "7")]));
+            }));
+            let _outside = info_span!("outside").entered();
+            assert_eq!(span_fields(), []);
+        });
+    }
+
+    #[test]
+    fn test_inner_wins() {
+        let subscriber = Registry::default().with(FieldsScopeLayer::default());
+        tracing::subscriber::with_default(subscriber, || {
+            block_on(fields_scope([("request_id", "a"), ("user", "x")], async {
+                let outer = info_span!("outer");
+                fields_scope([("user", "y"), ("step", "1")], async {
+                    let _span = info_span!("inner").entered();
+                    assert_eq!(
+                        span_fields(),
+                        pairs(&[("request_id", "a"), ("user", "y"), ("step", "1")])
+                    );
+                })
+                .instrument(outer)
+                .await;
+                let _span = info_span!("after").entered();
+                assert_eq!(span_fields(), pairs(&[("request_id", "a"), ("user", "x")]));
+            }));
+        });
+    }
+
+    #[test]
+    fn test_log_output() {
+        for (format, expected) in [
+            ("compact", "handler.request_id=7"),
+            ("pretty", "request_id: 7"),
+            ("tiny", "request_id:7"),
+            ("pretty-compact", "request_id=7"),
+            ("json", "\"request_id\":\"7\""),
+            ("logfmt", "request_id=7"),
+        ] {
+            let capture = Capture::default();
+            let layer = LogFormat::from_str(format).unwrap().into_layer(
+                capture.clone(),
+                Fields::default(),
+                &LineOptions::default(),
+                false,
+            );
+            let subscriber = Registry::default()
+                .with(layer)
+                .with(FieldsScopeLayer::default());
+            tracing::subscriber::with_default(subscriber, || {
+                block_on(fields_scope(
+                    [("request_id", "7"), ("kind", "scoped")],
+                    async {
+                        info_span!("handler", kind = "own").in_scope(|| info!("handled"));
+                    },
+                ));
+            });
+            let output = capture.contents();
+            // The event and the span closing, `tiny` only shows span fields there
+            let line = &output[output.find("handled").unwrap()..];
+            assert!(line.contains(expected), "{format}: {output}");
+            assert!(line.contains("own"), "{format}: {output}");
+            assert!(!line.contains("scoped"), "{format}: {output}");
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_attributes() {
+        use crate::trace::open_telemetry::test::Memory;
+        use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _, Key};
+        use tracing_opentelemetry::OpenTelemetryLayer;
+
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .build();
+        let subscriber = Registry::default()
+            .with(OpenTelemetryLayer::new(provider.tracer("test")))
+            .with(FieldsScopeLayer::default());
+        tracing::subscriber::with_default(subscriber, || {
+            block_on(fields_scope([("request_id", 7)], async {
+                let _handler = info_span!("handler").entered();
+                let _query = info_span!("query").entered();
+                let _row = info_span!("row", request_id = 8).entered();
+            }));
+        });
+        drop(provider);
+
+        let spans = std::mem::take(&mut *memory.0.lock().unwrap());
+        let attribute = |name: &str| {
+            let span = spans.iter().find(|span| span.name == name).unwrap();
+            span.attributes
+                .get(&Key::new("request_id"))
+                .map(ToString::to_string)
+        };
+        assert_eq!(attribute("handler").as_deref(), Some("7"));
+        assert_eq!(attribute("query").as_deref(), Some("7"));
+        assert_eq!(attribute("row").as_deref(), Some("8"));
+    }
+}
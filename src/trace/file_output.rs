@@ -0,0 +1,257 @@
+//! Output to a log file, zstd compressed with `--log-file-compress`.
+//!
+//! A compressed file is a series of zstd frames. The current frame is ended
+//! every [`FLUSH_INTERVAL`] and when the file is finished or rotated, so a
+//! file cut short by a crash decompresses up to the last complete frame with
+//! [`read_compressed`](crate::logs::read_compressed). A frame is only started
+//! by a write, a quiet log adds no empty frames.
+//!
+//! Compression needs the `log-compress` feature, without it there is no
+//! `--log-file-compress` and every file is plain.
+#[cfg(feature = "log-compress")]
+use crate::default_from_clap;
+#[cfg(feature = "log-compress")]
+use clap::Parser;
+use std::{
+    fs::File,
+    io::{self, Write},
+    sync::Weak,
+    thread,
+    time::Duration,
+};
+#[cfg(feature = "log-compress")]
+use zstd::{stream::write::Encoder, DEFAULT_COMPRESSION_LEVEL};
+
+/// How often the frame of a compressed file is ended.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The file name extension of compressed files.
+pub const EXTENSION: &str = "zst";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[cfg(feature = "log-compress")]
+pub struct Options {
+    /// Compress the log file, the file log sinks and the session log with
+    /// zstd, to '.zst' files that are readable up to the last second after a
+    /// crash.
+    #[clap(long, env)]
+    log_file_compress: bool,
+}
+
+#[cfg(feature = "log-compress")]
+default_from_clap!(Options);
+
+#[cfg(feature = "log-compress")]
+impl Options {
+    /// Whether `--log-file-compress` was given.
+    #[must_use]
+    pub const fn requested(self) -> bool {
+        self.log_file_compress
+    }
+}
+
+/// A log file, compressed or not.
+pub enum FileOutput {
+    Plain(File),
+    #[cfg(feature = "log-compress")]
+    Zstd(Frames),
+}
+
+/// A file written as zstd frames.
+#[cfg(feature = "log-compress")]
+pub struct Frames(Option<Frame>);
+
+#[cfg(feature = "log-compress")]
+enum Frame {
+    /// Between frames.
+    Ended(File),
+    Open(Encoder<'static, File>),
+}
+
+impl FileOutput {
+    #[must_use]
+    #[cfg(feature = "log-compress")]
+    pub const fn new(file: File, compress: bool) -> Self {
+        if compress {
+            Self::Zstd(Frames(Some(Frame::Ended(file))))
+        } else {
+            Self::Plain(file)
+        }
+    }
+
+    /// A plain file, there is no `--log-file-compress` without the
+    /// `log-compress` feature.
+    #[must_use]
+    #[cfg(not(feature = "log-compress"))]
+    pub const fn new(file: File, _compress: bool) -> Self {
+        Self::Plain(file)
+    }
+
+    #[must_use]
+    pub const fn is_compressed(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(_) => true,
+        }
+    }
+
+    /// Complete the current frame, the next write starts a new one.
+    #[cfg_attr(
+        not(feature = "log-compress"),
+        allow(
+            clippy::unnecessary_wraps,
+            clippy::missing_const_for_fn,
+            clippy::needless_pass_by_ref_mut
+        )
+    )] // Infallible without it
+    pub fn end_frame(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(_) => Ok(()),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(frames) => frames.end_frame(),
+        }
+    }
+
+    /// End the current frame and sync the file to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.end_frame()?;
+        self.file()?.sync_data()
+    }
+
+    /// End the current frame and return the file.
+    pub fn finish(mut self) -> io::Result<File> {
+        self.end_frame()?;
+        match self {
+            Self::Plain(file) => Ok(file),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(Frames(Some(Frame::Ended(file)))) => Ok(file),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(_) => Err(broken()),
+        }
+    }
+
+    #[cfg_attr(
+        not(feature = "log-compress"),
+        allow(clippy::unnecessary_wraps, clippy::missing_const_for_fn)
+    )] // Infallible without it
+    fn file(&self) -> io::Result<&File> {
+        match self {
+            Self::Plain(file) => Ok(file),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(Frames(Some(Frame::Ended(file)))) => Ok(file),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(Frames(Some(Frame::Open(encoder)))) => Ok(encoder.get_ref()),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(Frames(None)) => Err(broken()),
+        }
+    }
+}
+
+#[cfg(feature = "log-compress")]
+impl Frames {
+    fn end_frame(&mut self) -> io::Result<()> {
+        if let Some(Frame::Open(_)) = self.0 {
+            let Some(Frame::Open(encoder)) = self.0.take() else {
+                unreachable!();
+            };
+            self.0 = Some(Frame::Ended(encoder.finish()?));
+        }
+        Ok(())
+    }
+
+    fn encoder(&mut self) -> io::Result<&mut Encoder<'static, File>> {
+        if let Some(Frame::Ended(_)) = self.0 {
+            let Some(Frame::Ended(file)) = self.0.take() else {
+                unreachable!();
+            };
+            self.0 = Some(Frame::Open(Encoder::new(file, DEFAULT_COMPRESSION_LEVEL)?));
+        }
+        match &mut self.0 {
+            Some(Frame::Open(encoder)) => Ok(encoder),
+            _ => Err(broken()),
+        }
+    }
+}
+
+impl Write for FileOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(frames) => frames.encoder()?.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(Frames(Some(Frame::Open(encoder)))) => encoder.flush(),
+            #[cfg(feature = "log-compress")]
+            Self::Zstd(_) => Ok(()),
+        }
+    }
+}
+
+/// A frame that failed to complete takes the file with it.
+#[cfg(feature = "log-compress")]
+fn broken() -> io::Error {
+    io::Error::other("compressed log file closed after an earlier error")
+}
+
+/// Call `end_frame` on `file` every [`FLUSH_INTERVAL`] until the file is
+/// dropped or `end_frame` returns `false`.
+pub fn end_frames_periodically<T>(file: Weak<T>, end_frame: fn(&T) -> bool)
+where
+    T: Send + Sync + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let Some(file) = file.upgrade() else {
+            break;
+        };
+        if !end_frame(&file) {
+            break;
+        }
+    });
+}
+
+#[cfg(all(test, feature = "log-compress"))]
+pub mod test {
+    use super::*;
+    use crate::logs::read_compressed;
+    use std::{env, fs, process::id as pid};
+
+    #[test]
+    fn test_frames() {
+        let path = env::temp_dir().join(format!("cli-batteries-file-output-{}.zst", pid()));
+        let mut output = FileOutput::new(File::create(&path).unwrap(), true);
+        output.write_all(b"first frame\n").unwrap();
+        output.end_frame().unwrap();
+        // No empty frame
+        output.end_frame().unwrap();
+        let first = fs::metadata(&path).unwrap().len();
+        output.write_all(b"second frame\n").unwrap();
+        output.flush().unwrap();
+
+        // What a crash would leave behind: one complete frame and a flushed
+        // block of the next, or part of it.
+        assert_eq!(
+            read_compressed(&path).unwrap(),
+            b"first frame\nsecond frame\n"
+        );
+        let data = fs::read(&path).unwrap();
+        let truncated = path.with_extension("truncated");
+        fs::write(&truncated, &data[..usize::try_from(first).unwrap() + 3]).unwrap();
+        assert_eq!(read_compressed(&truncated).unwrap(), b"first frame\n");
+
+        output.write_all(b"last line\n").unwrap();
+        drop(output.finish().unwrap());
+        let data = zstd::decode_all(File::open(&path).unwrap()).unwrap();
+        assert_eq!(data, b"first frame\nsecond frame\nlast line\n");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&truncated).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Reloading the log filter at runtime.
+//!
+//! The filters of the log output, the log sinks, the binary log and the shared
+//! memory ring are wrapped in a [`reload::Layer`]. With the `signals` feature,
+//! the filter is read again on `SIGHUP`, from `--log-filter-file` or from
+//! `LOG_FILTER`, and swapped in. A filter that does not parse is logged and
+//! the current one stays in use. `--log-control-socket` swaps in filters sent
+//! to it, see [`log_control`](super::log_control).
+//!
+//! While [`auto_debug`](super::auto_debug) has raised the app crates, every
+//! filter swapped in is raised the same way.
+use super::{directives, log_filter::LogFilter, Directives, Levels, LogDefaults};
+use crate::Version;
+use eyre::Result as EyreResult;
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use tracing::Subscriber;
+use tracing_subscriber::{filter::LevelFilter, reload};
+
+#[cfg(all(unix, feature = "signals"))]
+use {
+    crate::default_from_clap,
+    clap::Parser,
+    eyre::WrapErr as _,
+    std::{env, fs, path::PathBuf},
+    tracing::{error, info},
+};
+
+type Handle = Box<dyn Fn(&LogFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+/// Handles of the reloadable filters.
+static HANDLES: Mutex<Vec<Handle>> = Mutex::new(Vec::new());
+
+/// The log filter last swapped in and the flag it stands in for, `None`
+/// before the first.
+static CURRENT: Mutex<Option<(String, &'static str)>> = Mutex::new(None);
+
+/// Whether the app crates are raised to `DEBUG`.
+static RAISED: AtomicBool = AtomicBool::new(false);
+
+/// Wrap `filter` in a filter that [`FilterOptions::apply`] replaces.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn reloadable<S>(filter: LogFilter) -> reload::Layer<LogFilter, S>
+where
+    S: Subscriber + 'static,
+{
+    let (layer, handle) = reload::Layer::new(filter);
+    HANDLES
+        .lock()
+        .unwrap()
+        .push(Box::new(move |filter| handle.reload(filter.clone())));
+    layer
+}
+
+/// The options a new log filter is combined with, and the `--log-filter` of
+/// the command line.
+#[derive(Clone, Debug)]
+pub struct FilterOptions {
+    pub levels:       Levels,
+    pub log_defaults: LogDefaults,
+    pub log_filter:   String,
+    pub version:      Version,
+}
+
+impl FilterOptions {
+    /// Swap in `log_filter`, given as `flag`, in place of `--log-filter`.
+    /// Returns the effective filter. On failure the current one stays in use.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn apply(&self, log_filter: &str, flag: &'static str) -> EyreResult<String> {
+        let (default, mut directives) = directives(
+            self.levels,
+            self.log_defaults,
+            log_filter.trim(),
+            flag,
+            &self.version,
+        )?;
+        if RAISED.load(Ordering::Relaxed) {
+            raise(&mut directives, &self.version);
+        }
+        let filter = LogFilter::new(default, &directives)?;
+        *CURRENT.lock().unwrap() = Some((log_filter.to_owned(), flag));
+        // Handles of dropped subscribers are removed.
+        HANDLES
+            .lock()
+            .unwrap()
+            .retain(|reload| reload(&filter).is_ok());
+        Ok(effective(default, &directives))
+    }
+
+    /// Raise the app crates to `DEBUG` in the current filter, or swap the
+    /// current filter back in as it was given. Returns the effective filter.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn set_raised(&self, raised: bool) -> EyreResult<String> {
+        RAISED.store(raised, Ordering::Relaxed);
+        let current = CURRENT.lock().unwrap().clone();
+        let (log_filter, flag) = current.unwrap_or_else(|| (self.log_filter.clone(), "log-filter"));
+        self.apply(&log_filter, flag)
+    }
+}
+
+/// Raise the app crates of `version` to at least `DEBUG`.
+fn raise(directives: &mut Directives, version: &Version) {
+    for target in &version.app_crates {
+        if let Some((level, _)) = directives.get_mut(target) {
+            *level = (*level).max(LevelFilter::DEBUG);
+        }
+    }
+}
+
+/// The filter in `--log-filter` syntax, with the default level first.
+fn effective(default: LevelFilter, directives: &Directives) -> String {
+    let mut filter = default.to_string().to_lowercase();
+    for (target, (level, _)) in directives {
+        let _ = write!(filter, ",{target}={}", level.to_string().to_lowercase());
+    }
+    filter
+}
+
+#[cfg(all(unix, feature = "signals"))]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Read the log filter from this file on SIGHUP, in place of
+    /// `--log-filter`. Without it, SIGHUP reads `LOG_FILTER` again.
+    #[clap(long, env)]
+    log_filter_file: Option<PathBuf>,
+}
+
+#[cfg(all(unix, feature = "signals"))]
+default_from_clap!(Options);
+
+#[cfg(all(unix, feature = "signals"))]
+impl Options {
+    /// Reload the log filter on `SIGHUP`.
+    pub fn init(&self, filter: FilterOptions) -> EyreResult<()> {
+        reload_on_hangup(FilterReload {
+            filter,
+            file: self.log_filter_file.clone(),
+        })
+    }
+}
+
+/// Where the filter is read from again.
+#[cfg(all(unix, feature = "signals"))]
+struct FilterReload {
+    filter: FilterOptions,
+    file:   Option<PathBuf>,
+}
+
+#[cfg(all(unix, feature = "signals"))]
+impl FilterReload {
+    /// Read the filter and swap it in. On failure the current one stays in
+    /// use.
+    fn reload(&self) -> bool {
+        match self
+            .read()
+            .and_then(|(log_filter, flag)| self.filter.apply(&log_filter, flag))
+        {
+            Ok(filter) => {
+                info!(%filter, "Reloaded log filter");
+                true
+            }
+            Err(report) => {
+                error!(
+                    ?report,
+                    "Could not reload log filter, keeping the current one"
+                );
+                false
+            }
+        }
+    }
+
+    /// The new filter, and the flag it stands in for.
+    fn read(&self) -> EyreResult<(String, &'static str)> {
+        if let Some(file) = &self.file {
+            let log_filter = fs::read_to_string(file)
+                .wrap_err_with(|| format!("Error reading {}", file.display()))?;
+            Ok((log_filter, "log-filter-file"))
+        } else {
+            let log_filter =
+                env::var("LOG_FILTER").unwrap_or_else(|_| self.filter.log_filter.clone());
+            Ok((log_filter, "log-filter"))
+        }
+    }
+}
+
+/// Reload the log filter whenever the process receives `SIGHUP`.
+#[cfg(all(unix, feature = "signals"))]
+fn reload_on_hangup(reload: FilterReload) -> EyreResult<()> {
+    use crate::shutdown::{process_receiver, wait};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).wrap_err("Could not install SIGHUP handler")?;
+    let mut shutdown = process_receiver();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                () = wait(&mut shutdown) => break,
+                received = hangup.recv() => {
+                    if received.is_none() {
+                        break;
+                    }
+                    reload.reload();
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{default_verbosity, log_defaults::FilterSource, test::mock_version};
+
+    #[test]
+    fn test_raise() {
+        let version = mock_version();
+        let raised = |log_filter| {
+            let (_, mut directives) = directives(
+                default_verbosity(0),
+                LogDefaults::NONE,
+                log_filter,
+                "log-filter",
+                &version,
+            )
+            .unwrap();
+            raise(&mut directives, &version);
+            directives
+        };
+        let directives = raised("dep=warn");
+        assert_eq!(
+            directives["app"],
+            (LevelFilter::DEBUG, FilterSource::Verbose)
+        );
+        assert_eq!(
+            directives["dep"],
+            (LevelFilter::WARN, FilterSource::LogFilter)
+        );
+        // Never lowered
+        let directives = raised("app=trace");
+        assert_eq!(
+            directives["app"],
+            (LevelFilter::TRACE, FilterSource::LogFilter)
+        );
+    }
+}
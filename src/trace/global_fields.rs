@@ -0,0 +1,276 @@
+//! Fields added to every log event.
+//!
+//! The fields come from `--tag key=value`, so the logs of one particular run
+//! can be found later. Fields the event sets itself take precedence.
+//!
+//! With `--log-show-module` the module path of the event is added as the
+//! `module` field the same way.
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Result},
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    callsite::Identifier,
+    field::{display, DisplayValue, Field, FieldSet, Visit},
+    Event, Subscriber, Value,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Field names and values.
+pub type Fields = Arc<[(&'static str, String)]>;
+
+/// Extended field names by callsite and original field names.
+type Names = HashMap<(Identifier, Vec<&'static str>), &'static [&'static str]>;
+
+/// Maximum number of fields of an event, see [`FieldSet::value_set`].
+const MAX_FIELDS: usize = 32;
+
+/// Parse a `--tag` value.
+pub fn parse_tag(s: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got `{s}`"))?;
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if !valid {
+        return Err(format!(
+            "invalid key `{key}`, expected letters, digits, `_` and `.`"
+        ));
+    }
+    if key == "message" || key.starts_with("log.") {
+        return Err(format!("key `{key}` is reserved"));
+    }
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Turn `key=value` pairs into [`Fields`], the last value of a key wins.
+pub fn fields(pairs: &[(String, String)]) -> Fields {
+    let mut fields = Vec::<(&'static str, String)>::new();
+    for (key, value) in pairs {
+        if let Some(field) = fields.iter_mut().find(|(existing, _)| existing == key) {
+            field.1.clone_from(value);
+        } else {
+            // Leaked once per subscriber, field names must be `'static`.
+            fields.push((Box::leak(key.clone().into_boxed_str()), value.clone()));
+        }
+    }
+    fields.into()
+}
+
+pub struct GlobalFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    inner:    Inner,
+    fields:   Fields,
+    /// Whether the module path is added as the `module` field.
+    module:   bool,
+    names:    Mutex<Names>,
+    _phantom: PhantomData<(S, N)>,
+}
+
+impl<Inner, S, N> GlobalFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    pub fn new(inner: Inner, fields: Fields) -> Self {
+        Self {
+            inner,
+            fields,
+            module: false,
+            names: Mutex::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// With the module path of the event as the `module` field.
+    pub const fn with_module(mut self, module: bool) -> Self {
+        self.module = module;
+        self
+    }
+
+    /// The names of `event` followed by the global ones it does not set.
+    /// Leaked once per distinct set of names.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    fn names(&self, event: &Event<'_>) -> &'static [&'static str] {
+        let own = event.fields().map(|field| field.name()).collect::<Vec<_>>();
+        let key = (event.metadata().callsite(), own);
+        let mut names = self.names.lock().unwrap();
+        if let Some(names) = names.get(&key) {
+            return names;
+        }
+        // A `--tag module=...` wins over the module path
+        let module = self.module && !self.fields.iter().any(|(name, _)| *name == "module");
+        let extended = key
+            .1
+            .iter()
+            .copied()
+            .chain(
+                self.fields
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .chain(module.then_some("module"))
+                    .filter(|name| !key.1.contains(name)),
+            )
+            .take(MAX_FIELDS)
+            .collect::<Vec<_>>();
+        let extended: &'static [&'static str] = Box::leak(extended.into_boxed_slice());
+        names.insert(key, extended);
+        drop(names);
+        extended
+    }
+}
+
+impl<Inner, S, N> FormatEvent<S, N> for GlobalFields<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        if self.fields.is_empty() && !self.module {
+            return self.inner.format_event(ctx, writer, event);
+        }
+        let mut recorded = Recorder::default();
+        event.record(&mut recorded);
+
+        let names = self.names(event);
+        let field_set = FieldSet::new(names, event.metadata().callsite());
+        let fields = field_set.iter().collect::<Vec<_>>();
+        let normalized = event.normalized_metadata();
+        let module = normalized
+            .as_ref()
+            .unwrap_or_else(|| event.metadata())
+            .module_path()
+            .filter(|_| self.module)
+            .map(|module| ("module", Recorded::Str(module.to_owned())));
+        let globals = self
+            .fields
+            .iter()
+            .map(|(name, value)| (*name, Recorded::Str(value.clone())))
+            .chain(module);
+        let recorded = recorded.0.into_iter().chain(globals).collect::<Vec<_>>();
+
+        // Unused entries have no value and are skipped.
+        let mut values: [(&Field, Option<&dyn Value>); MAX_FIELDS] =
+            [(&fields[0], None); MAX_FIELDS];
+        for (name, value) in &recorded {
+            if let Some(index) = names.iter().position(|n| n == name) {
+                if values[index].1.is_none() {
+                    values[index] = (&fields[index], Some(value.as_value()));
+                }
+            }
+        }
+        let value_set = field_set.value_set(&values);
+        let event = if event.is_contextual() {
+            Event::new(event.metadata(), &value_set)
+        } else {
+            Event::new_child_of(event.parent().cloned(), event.metadata(), &value_set)
+        };
+        self.inner.format_event(ctx, writer, &event)
+    }
+}
+
+/// A field value, recorded with its type.
+enum Recorded {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Debug(DisplayValue<String>),
+}
+
+impl Recorded {
+    fn as_value(&self) -> &dyn Value {
+        match self {
+            Self::I64(value) => value,
+            Self::U64(value) => value,
+            Self::F64(value) => value,
+            Self::Bool(value) => value,
+            Self::Str(value) => value,
+            Self::Debug(value) => value,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Recorder(Vec<(&'static str, Recorded)>);
+
+impl Visit for Recorder {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name(), Recorded::I64(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name(), Recorded::U64(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.name(), Recorded::F64(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name(), Recorded::Bool(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), Recorded::Str(value.to_owned())));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .push((field.name(), Recorded::Debug(display(format!("{value:?}")))));
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("ticket=ABC-123"),
+            Ok(("ticket".to_owned(), "ABC-123".to_owned()))
+        );
+        assert_eq!(
+            parse_tag("run.note=a=b c"),
+            Ok(("run.note".to_owned(), "a=b c".to_owned()))
+        );
+        assert_eq!(parse_tag("_x="), Ok(("_x".to_owned(), String::new())));
+        assert!(parse_tag("ticket").is_err());
+        assert!(parse_tag("=value").is_err());
+        assert!(parse_tag("2nd=value").is_err());
+        assert!(parse_tag("my-key=value").is_err());
+        assert!(parse_tag("message=hi").is_err());
+        assert!(parse_tag("log.target=x").is_err());
+    }
+
+    #[test]
+    fn test_fields() {
+        let pairs = [("a", "1"), ("b", "2"), ("a", "3")]
+            .map(|(key, value)| (key.to_owned(), value.to_owned()));
+        assert_eq!(&*fields(&pairs), &[
+            ("a", "3".to_owned()),
+            ("b", "2".to_owned())
+        ]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Trace context propagation for [`tonic`] gRPC servers and clients.
+//!
+//! [`GrpcTraceLayer`] goes on the server. It continues the trace of the
+//! caller from the `traceparent` and `baggage` metadata, and runs every call
+//! in a span exported as `{package.Service}/{Method}` with the `rpc.system`,
+//! `rpc.service`, `rpc.method` and `rpc.grpc.status_code` attributes. Once the
+//! program is shutting down, new calls are refused as `UNAVAILABLE` while the
+//! ones in flight finish.
+//!
+//! [`inject_trace`] is the client interceptor, it sends the trace context and
+//! baggage of the current span along with outgoing calls.
+//!
+//! ```rust,ignore
+//! use cli_batteries::{await_shutdown, inject_trace, GrpcTraceLayer};
+//!
+//! Server::builder()
+//!     .layer(GrpcTraceLayer)
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve_with_shutdown(addr, await_shutdown())
+//!     .await?;
+//!
+//! let client = GreeterClient::with_interceptor(channel, inject_trace);
+//! ```
+#![cfg(feature = "tonic")]
+use crate::shutdown;
+use once_cell::sync::Lazy;
+use opentelemetry::{
+    propagation::{Injector, TextMapPropagator as _},
+    sdk::propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+};
+use opentelemetry_http::HeaderExtractor;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Body, BoxFuture, Bytes, Service},
+    metadata::{MetadataKey, MetadataMap},
+    Code, Request, Status,
+};
+use tower::Layer;
+use tracing::{field::Empty, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// W3C Trace Context and Baggage.
+static PROPAGATOR: Lazy<TextMapCompositePropagator> = Lazy::new(|| {
+    TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ])
+});
+
+/// Status codes that are errors of the server, the others are errors of the
+/// caller. See the OpenTelemetry semantic conventions for gRPC.
+const SERVER_ERRORS: [Code; 6] = [
+    Code::Unknown,
+    Code::DeadlineExceeded,
+    Code::Unimplemented,
+    Code::Internal,
+    Code::Unavailable,
+    Code::DataLoss,
+];
+
+/// Traces the calls to a tonic server, see the [module](self) documentation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GrpcTraceLayer;
+
+impl<S> Layer<S> for GrpcTraceLayer {
+    type Service = GrpcTraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcTraceService { inner }
+    }
+}
+
+/// The service made by [`GrpcTraceLayer`].
+#[derive(Clone, Debug)]
+pub struct GrpcTraceService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for GrpcTraceService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<GrpcTraceBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let span = server_span(request.uri().path());
+        span.set_parent(PROPAGATOR.extract(&HeaderExtractor(request.headers())));
+        if shutdown::is_shutting_down() {
+            let response = Status::unavailable("Shutting down").to_http();
+            return Box::pin(async move { Ok(GrpcTraceBody::wrap(response, span)) });
+        }
+        let response = span.in_scope(|| self.inner.call(request));
+        Box::pin(async move {
+            let response = response.instrument(span.clone()).await?;
+            Ok(GrpcTraceBody::wrap(response, span))
+        })
+    }
+}
+
+/// The response body of a traced call. Records the status from the trailers
+/// and keeps the span open until the response is sent.
+#[derive(Debug)]
+pub struct GrpcTraceBody {
+    inner: BoxBody,
+    span:  Span,
+}
+
+impl GrpcTraceBody {
+    /// Errors before the response are in the headers, without trailers.
+    fn wrap(response: http::Response<BoxBody>, span: Span) -> http::Response<Self> {
+        if let Some(status) = Status::from_header_map(response.headers()) {
+            record_status(&span, &status);
+        }
+        response.map(|inner| Self { inner, span })
+    }
+}
+
+impl Body for GrpcTraceBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = &mut *self;
+        let _entered = this.span.enter();
+        Pin::new(&mut this.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        let this = &mut *self;
+        let trailers = Pin::new(&mut this.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(trailers))) = &trailers {
+            if let Some(status) = Status::from_header_map(trailers) {
+                record_status(&this.span, &status);
+            }
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+/// Client interceptor that sends the trace context and baggage of the current
+/// span along with the call, see the [module](self) documentation.
+///
+/// # Errors
+///
+/// Never, the signature is the one of [`tonic::service::Interceptor`].
+#[allow(
+    clippy::needless_pass_by_value,
+    clippy::unnecessary_wraps,
+    clippy::result_large_err
+)] // Interceptor
+pub fn inject_trace(mut request: Request<()>) -> Result<Request<()>, Status> {
+    PROPAGATOR.inject_context(
+        &Span::current().context(),
+        &mut MetadataInjector(request.metadata_mut()),
+    );
+    Ok(request)
+}
+
+/// The span of a call to `path`, `/{package.Service}/{Method}`.
+fn server_span(path: &str) -> Span {
+    let name = path.trim_start_matches('/');
+    let (service, method) = name.split_once('/').unwrap_or((name, ""));
+    info_span!(
+        "grpc",
+        otel.name = name,
+        otel.kind = "server",
+        rpc.system = "grpc",
+        rpc.service = service,
+        rpc.method = method,
+        rpc.grpc.status_code = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    )
+}
+
+fn record_status(span: &Span, status: &Status) {
+    span.record("rpc.grpc.status_code", status.code() as i32);
+    if SERVER_ERRORS.contains(&status.code()) {
+        span.record("otel.status_code", "ERROR");
+        span.record("otel.status_message", status.message());
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (MetadataKey::from_bytes(key.as_bytes()), value.parse()) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::open_telemetry::test::Memory;
+    use futures::stream;
+    use opentelemetry::{
+        baggage::BaggageExt,
+        sdk::{export::trace::SpanData, trace::TracerProvider},
+        trace::{SpanKind, Status as SpanStatus, TraceContextExt as _, TracerProvider as _},
+        Context as OtelContext, KeyValue, Value,
+    };
+    use std::{convert::Infallible, time::Duration};
+    use tokio::net::TcpListener;
+    use tonic::{
+        client::Grpc as GrpcClient,
+        codec::ProstCodec,
+        codegen::http::uri::PathAndQuery,
+        server::{Grpc as GrpcServer, NamedService, UnaryService},
+        service::interceptor::InterceptedService,
+        transport::{self, Channel, Server},
+        Response,
+    };
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    struct Text {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    /// `test.Echo/Baggage` answers with the `tenant` baggage it received,
+    /// `test.Echo/Fail` fails.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl NamedService for Echo {
+        const NAME: &'static str = "test.Echo";
+    }
+
+    impl Service<http::Request<transport::Body>> for Echo {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<transport::Body>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = GrpcServer::new(ProstCodec::<Text, Text>::default());
+                Ok(grpc.unary(Method, request).await)
+            })
+        }
+    }
+
+    struct Method;
+
+    impl UnaryService<Text> for Method {
+        type Response = Text;
+        type Future = BoxFuture<Response<Text>, Status>;
+
+        fn call(&mut self, request: Request<Text>) -> Self::Future {
+            let baggage = Span::current().context().baggage().get("tenant").cloned();
+            let fail = request.into_inner().text == "fail";
+            Box::pin(async move {
+                if fail {
+                    return Err(Status::internal("failed as asked"));
+                }
+                Ok(Response::new(Text {
+                    text: baggage.map(|value| value.to_string()).unwrap_or_default(),
+                }))
+            })
+        }
+    }
+
+    async fn call(channel: Channel, text: &str) -> Result<String, Status> {
+        let mut client = GrpcClient::new(InterceptedService::new(channel, inject_trace));
+        client.ready().await.unwrap();
+        Ok(client
+            .unary(
+                Request::new(Text {
+                    text: text.to_owned(),
+                }),
+                PathAndQuery::from_static("/test.Echo/Call"),
+                ProstCodec::<Text, Text>::default(),
+            )
+            .await?
+            .into_inner()
+            .text)
+    }
+
+    /// The exported span named `name`, once it has ended.
+    async fn exported(memory: &Memory, name: &str) -> SpanData {
+        for _ in 0..200 {
+            let span = memory
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|span| span.name == name)
+                .cloned();
+            if let Some(span) = span {
+                return span;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("span {name} not exported");
+    }
+
+    fn attribute(span: &SpanData, key: &'static str) -> Option<Value> {
+        span.attributes.get(&key.into()).cloned()
+    }
+
+    #[tokio::test]
+    #[allow(clippy::significant_drop_tightening)] // The channel is used to the end
+    async fn test_loopback() {
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .build();
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .layer(GrpcTraceLayer)
+                .add_service(Echo)
+                .serve_with_incoming(incoming),
+        );
+        let channel = Channel::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        // Both the trace context and the baggage reach the server
+        let baggage = OtelContext::current_with_baggage([KeyValue::new("tenant", "acme")]);
+        let client = {
+            let _attached = baggage.attach();
+            info_span!("client")
+        };
+        let answer = call(channel.clone(), "hello")
+            .instrument(client.clone())
+            .await
+            .unwrap();
+        assert_eq!(answer, "acme");
+        let trace_id = client.context().span().span_context().trace_id();
+        let client_id = client.context().span().span_context().span_id();
+        drop(client);
+
+        let server = exported(&memory, "test.Echo/Call").await;
+        assert_eq!(server.span_context.trace_id(), trace_id);
+        assert_eq!(server.parent_span_id, client_id);
+        assert_eq!(server.span_kind, SpanKind::Server);
+        assert_eq!(attribute(&server, "rpc.system"), Some("grpc".into()));
+        assert_eq!(attribute(&server, "rpc.service"), Some("test.Echo".into()));
+        assert_eq!(attribute(&server, "rpc.method"), Some("Call".into()));
+        assert_eq!(
+            attribute(&server, "rpc.grpc.status_code"),
+            Some(0_i64.into())
+        );
+        assert_eq!(server.status, SpanStatus::Unset);
+
+        // Errors of the server are recorded on its span
+        memory.0.lock().unwrap().clear();
+        let status = call(channel, "fail").await.unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        let server = exported(&memory, "test.Echo/Call").await;
+        assert_eq!(
+            attribute(&server, "rpc.grpc.status_code"),
+            Some(13_i64.into())
+        );
+        assert_eq!(server.status, SpanStatus::error("failed as asked"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Contain panics in tracing layers.
+//!
+//! A layer that panics in a callback would unwind through whatever code was
+//! logging, in async code a runtime worker. [`Guard`] catches the panic, writes
+//! one alert with the layer name and panic message to stderr and disables the
+//! layer for the rest of the process. The other layers keep working.
+//!
+//! The panic hook still runs before the panic is caught. It checks
+//! [`in_layer`] and stays silent, so the alert is the only output.
+use std::{
+    any::{Any, TypeId},
+    cell::Cell,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::{
+    level_filters::LevelFilter,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Dispatch, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+thread_local! {
+    /// Whether this thread is inside a guarded layer.
+    static IN_LAYER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is running a layer callback whose panics are
+/// caught and reported by a [`Guard`].
+pub fn in_layer() -> bool {
+    IN_LAYER.with(Cell::get)
+}
+
+/// See the [module docs](self).
+pub struct Guard<L> {
+    name:     &'static str,
+    inner:    L,
+    disabled: AtomicBool,
+}
+
+impl<L> Guard<L> {
+    pub const fn new(name: &'static str, inner: L) -> Self {
+        Self {
+            name,
+            inner,
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Call into the inner layer, returning `default` if it is disabled or
+    /// panics.
+    fn call<T>(&self, default: T, f: impl FnOnce(&L) -> T) -> T {
+        if self.disabled.load(Ordering::Relaxed) {
+            return default;
+        }
+        let outer = IN_LAYER.with(|in_layer| in_layer.replace(true));
+        let result = catch_unwind(AssertUnwindSafe(|| f(&self.inner)));
+        IN_LAYER.with(|in_layer| in_layer.set(outer));
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                if !self.disabled.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "Error: the {} tracing layer panicked and is disabled: {}",
+                        self.name,
+                        message(payload.as_ref())
+                    );
+                }
+                default
+            }
+        }
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+impl<S, L> Layer<S> for Guard<L>
+where
+    S: Subscriber,
+    L: Layer<S>,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.call((), |inner| inner.on_register_dispatch(subscriber));
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.call(Interest::sometimes(), |inner| {
+            inner.register_callsite(metadata)
+        })
+    }
+
+    /// A disabled layer must not filter out events for the others.
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.call(true, |inner| inner.enabled(metadata, ctx))
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.call(None, Layer::max_level_hint)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_new_span(attrs, id, ctx));
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_record(span, values, ctx));
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_follows_from(span, follows, ctx));
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.call(true, |inner| inner.event_enabled(event, ctx))
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_event(event, ctx));
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_enter(id, ctx));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_exit(id, ctx));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_close(id, ctx));
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.call((), |inner| inner.on_id_change(old, new, ctx));
+    }
+
+    // Per-layer filters and span extensions like the OpenTelemetry context
+    // are found by downcasting, so it has to reach the inner layer.
+    #[allow(unsafe_code)]
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(std::ptr::from_ref(self).cast())
+        } else {
+            // SAFETY: Forwarded to the inner layer with the same contract.
+            unsafe { self.inner.downcast_raw(id) }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use std::sync::{atomic::AtomicUsize, Arc};
+    use tracing::{info, info_span};
+    use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, Registry};
+
+    /// Panics on events mentioning `boom`, counts the calls.
+    #[derive(Clone, Default)]
+    pub struct Panicking(pub Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for Panicking {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            assert!(!format!("{event:?}").contains("boom"), "formatter bug");
+        }
+    }
+
+    #[test]
+    fn test_panic_disables_layer() {
+        let capture = Capture::default();
+        let panicking = Panicking::default();
+        let subscriber = Registry::default()
+            .with(Guard::new("panicking", panicking.clone()))
+            .with(
+                fmt::Layer::new()
+                    .with_writer(capture.clone())
+                    .with_ansi(false)
+                    .with_filter(LevelFilter::INFO),
+            );
+        tracing::subscriber::with_default(subscriber, || {
+            info!("before");
+            info_span!("request").in_scope(|| info!("boom"));
+            info!("after");
+            info!("boom again");
+        });
+
+        let output = capture.contents();
+        for message in ["before", "boom", "after", "boom again"] {
+            assert!(output.contains(message), "{message}: {output}");
+        }
+        // Not called again after the panic.
+        assert_eq!(panicking.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_max_level_hint() {
+        let guard = Guard::new(
+            "filtered",
+            Panicking::default().with_filter(LevelFilter::WARN),
+        );
+        assert_eq!(
+            Layer::<Registry>::max_level_hint(&guard),
+            Some(LevelFilter::WARN)
+        );
+    }
+
+    #[test]
+    fn test_in_layer() {
+        /// Records [`in_layer`] on events.
+        struct Check(Arc<AtomicBool>);
+        impl<S: Subscriber> Layer<S> for Check {
+            fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+                self.0.store(in_layer(), Ordering::Relaxed);
+            }
+        }
+        let seen = Arc::new(AtomicBool::new(false));
+        let subscriber = Registry::default().with(Guard::new("check", Check(seen.clone())));
+        tracing::subscriber::with_default(subscriber, || info!("checked"));
+        assert!(seen.load(Ordering::Relaxed));
+        assert!(!in_layer());
+    }
+
+    #[test]
+    fn test_message() {
+        assert_eq!(message(&"static"), "static");
+        assert_eq!(message(&"owned".to_owned()), "owned");
+        assert_eq!(message(&7), "unknown panic");
+    }
+}
@@ -0,0 +1,266 @@
+//! The `json` log format with span fields as top-level keys,
+//! `--log-json-flatten`.
+//!
+//! The builtin `json` format nests the event fields under `fields` and the
+//! current span under `span`, which log stores like Loki can not index
+//! efficiently. Here the event fields and the fields of all spans in scope are
+//! keys of the line itself and `span` is the name of the innermost span.
+//!
+//! Of spans setting the same field the innermost wins. A span field named
+//! like an event field, or like one of the keys of the line, is prefixed with
+//! `span.`.
+use super::{log_timestamp::Timer, thread_name};
+use serde_json::Value;
+use std::fmt::{Debug, Result};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::{LookupSpan, Scope},
+};
+
+/// Keys of the line, whether or not it has them.
+const RESERVED: [&str; 5] = ["timestamp", "level", "target", "threadName", "span"];
+
+pub struct JsonFlatten {
+    /// Of the `timestamp` key, which is left out for
+    /// [`LogTimestamp::None`](super::LogTimestamp::None).
+    timer:  Timer,
+    /// Whether there is a `target` key.
+    target: bool,
+    /// Whether there is a `threadName` key.
+    thread: bool,
+}
+
+impl JsonFlatten {
+    pub const fn new(timer: Timer) -> Self {
+        Self {
+            timer,
+            target: true,
+            thread: false,
+        }
+    }
+
+    pub const fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub const fn with_thread(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFlatten
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut line = Vec::<(String, Value)>::new();
+        if !self.timer.is_none() {
+            line.push(("timestamp".to_owned(), self.timer.now().into()));
+        }
+        line.push(("level".to_owned(), meta.level().as_str().into()));
+        let mut visitor = Visitor {
+            skip_log: normalized.is_some(),
+            fields:   Vec::new(),
+        };
+        event.record(&mut visitor);
+        line.extend(visitor.fields);
+        if self.target {
+            line.push(("target".to_owned(), meta.target().into()));
+        }
+        if self.thread {
+            line.push(("threadName".to_owned(), thread_name().into()));
+        }
+
+        // From the root, so the innermost span wins
+        let mut spans = Vec::<(String, Value)>::new();
+        let mut name = None;
+        for span in ctx.event_scope().into_iter().flat_map(Scope::from_root) {
+            name = Some(span.name());
+            let extensions = span.extensions();
+            let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                continue;
+            };
+            let Ok(Value::Object(fields)) = serde_json::from_str(fields) else {
+                continue;
+            };
+            for (key, value) in fields {
+                match spans.iter_mut().find(|(existing, _)| *existing == key) {
+                    Some((_, existing)) => *existing = value,
+                    None => spans.push((key, value)),
+                }
+            }
+        }
+        for (key, value) in spans {
+            let taken = RESERVED.contains(&key.as_str())
+                || line.iter().any(|(existing, _)| *existing == key);
+            let key = if taken { format!("span.{key}") } else { key };
+            line.push((key, value));
+        }
+        if let Some(name) = name {
+            line.push(("span".to_owned(), name.into()));
+        }
+
+        writer.write_char('{')?;
+        for (i, (key, value)) in line.into_iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(writer, "{separator}{}:{value}", Value::String(key))?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// Records the event fields as JSON values.
+struct Visitor {
+    /// Whether to skip the `log.` fields of a normalized `log` record.
+    skip_log: bool,
+    fields:   Vec<(String, Value)>,
+}
+
+impl Visitor {
+    fn push(&mut self, field: &Field, value: Value) {
+        if !(self.skip_log && field.name().starts_with("log.")) {
+            self.fields.push((field.name().to_owned(), value));
+        }
+    }
+}
+
+impl Visit for Visitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.push(field, format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::{
+        logs::{parse_line_as, Format},
+        trace::{global_fields::Fields, test::Capture, LineOptions, LogFormat},
+    };
+    use serde_json::{json, Value};
+    use tracing::{info, info_span, subscriber::with_default};
+    use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, Registry};
+
+    /// The lines logged in the flattened `json` format.
+    fn render(log: impl FnOnce()) -> Vec<Value> {
+        let capture = Capture::default();
+        let line = LineOptions {
+            span_events: FmtSpan::NONE,
+            flatten: true,
+            ..LineOptions::default()
+        };
+        let layer = LogFormat::Json.into_layer(capture.clone(), Fields::default(), &line, false);
+        with_default(Registry::default().with(layer), log);
+        capture
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_nested_spans() {
+        let lines = render(|| {
+            info_span!("request", id = 7, user = "ann", kind = "outer").in_scope(|| {
+                info_span!("query", id = 8, table = "users").in_scope(|| {
+                    info!(target: "app::db", kind = "select", rows = 3, "query done");
+                });
+            });
+        });
+        let mut line = lines[0].as_object().unwrap().clone();
+        assert!(line.remove("timestamp").unwrap().is_string(), "{line:?}");
+        assert_eq!(
+            Value::Object(line),
+            json!({
+                "level": "INFO",
+                "message": "query done",
+                "kind": "select",
+                "rows": 3,
+                "target": "app::db",
+                "id": 8,
+                "user": "ann",
+                "span.kind": "outer",
+                "table": "users",
+                "span": "query",
+            })
+        );
+    }
+
+    #[test]
+    fn test_reserved() {
+        let lines = render(|| {
+            info_span!("request", level = "high", span = 1).in_scope(|| {
+                info!(target: "app", "in span");
+            });
+            info!(target: "app", "outside");
+        });
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["span.level"], "high");
+        assert_eq!(lines[0]["span.span"], 1);
+        assert_eq!(lines[0]["span"], "request");
+        assert_eq!(lines[1].get("span"), None);
+    }
+
+    #[test]
+    fn test_parse_back() {
+        let capture = Capture::default();
+        let line = LineOptions {
+            flatten: true,
+            ..LineOptions::default()
+        };
+        let layer = LogFormat::Json.into_layer(capture.clone(), Fields::default(), &line, false);
+        with_default(Registry::default().with(layer), || {
+            info_span!("request", id = 7).in_scope(|| {
+                info!(target: "app", count = 3, "plain text");
+            });
+        });
+        let output = capture.contents();
+        let records = output
+            .lines()
+            .map(|line| parse_line_as(line, Format::Json).unwrap())
+            .collect::<Vec<_>>();
+        // With the span events
+        assert_eq!(records.len(), 3, "{output}");
+        let record = &records[1];
+        assert_eq!(record.message, "plain text");
+        assert_eq!(record.target.as_deref(), Some("app"));
+        assert_eq!(record.span.as_deref(), Some("request"));
+        assert_eq!(record.fields["count"], 3);
+        assert_eq!(record.fields["id"], 7);
+    }
+}
@@ -0,0 +1,204 @@
+//! Find what the end of the process loses, `--debug-shutdown`.
+//!
+//! Once [`begin`] marks the start of the shutdown of the tracing outputs, the
+//! log file, the session log and the exporters complete and events arriving
+//! later may be lost. Typically a `Drop` impl logs while the runtime drops the
+//! remaining tasks. [`LateEvents`] stays installed to the very end and counts
+//! these events per callsite, [`report`] writes them to stderr as the last
+//! output of the process.
+//!
+//! A panic in a destructor while unwinding aborts the process, with only the
+//! generic `panic in a destructor during cleanup` from the standard library.
+//! After [`begin`], [`on_panic`] names it as a panic in drop during shutdown,
+//! with the thread and both panics.
+//!
+//! Always on in debug builds.
+use once_cell::sync::OnceCell;
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt::Write as _,
+    panic::Location,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Message of the panic the standard library raises for a panic in a
+/// destructor while unwinding, which aborts.
+const CLEANUP_PANIC: &str = "panic in a destructor during cleanup";
+
+/// Modules of the final lines of [`run`](crate::run), logged on purpose after
+/// the shutdown.
+const FINAL: [&str; 2] = ["cli_batteries", "cli_batteries::runner"];
+
+static EVENTS: OnceCell<Arc<Events>> = OnceCell::new();
+
+/// Whether the shutdown of the outputs has begun.
+static BEGUN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The last two panics of this thread, for the one in a destructor and the
+    /// one being unwound.
+    static PANICS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Late events per callsite, in order of the first one.
+#[derive(Default)]
+pub struct Events(Mutex<Vec<(&'static Metadata<'static>, u64)>>);
+
+impl Events {
+    fn add(&self, metadata: &'static Metadata<'static>) {
+        let mut entries = self.0.lock().unwrap();
+        match entries
+            .iter_mut()
+            .find(|(existing, _)| existing.callsite() == metadata.callsite())
+        {
+            Some((_, events)) => *events += 1,
+            None => entries.push((metadata, 1)),
+        }
+    }
+
+    /// The report, empty without late events.
+    #[allow(clippy::significant_drop_tightening)] // The entries are read under the lock
+    fn summary(&self) -> String {
+        let entries = self.0.lock().unwrap();
+        let total = entries.iter().map(|(_, events)| events).sum::<u64>();
+        if total == 0 {
+            return String::new();
+        }
+        let plural = if total == 1 { "" } else { "s" };
+        let mut summary = format!(
+            "Warning: {total} event{plural} arrived after shutdown began and may be missing from \
+             the log outputs:\n"
+        );
+        for (metadata, events) in entries.iter() {
+            let _ = writeln!(
+                summary,
+                "  {}:{} {} {} ({events}x)",
+                metadata.file().unwrap_or("unknown"),
+                metadata.line().unwrap_or_default(),
+                metadata.level(),
+                metadata.target(),
+            );
+        }
+        summary
+    }
+}
+
+/// Counts the events after [`begin`]. Must not be filtered itself.
+pub struct LateEvents {
+    events: Arc<Events>,
+}
+
+impl LateEvents {
+    /// Make the events the ones [`report`] writes, and have [`on_panic`]
+    /// check panics.
+    pub fn new() -> Self {
+        let events = EVENTS.get_or_init(Arc::default).clone();
+        Self { events }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LateEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let final_line = FINAL.contains(&metadata.module_path().unwrap_or(""));
+        if BEGUN.load(Ordering::Relaxed) && !final_line {
+            self.events.add(metadata);
+        }
+    }
+}
+
+/// Mark the start of the shutdown of the tracing outputs.
+pub fn begin() {
+    BEGUN.store(true, Ordering::Relaxed);
+}
+
+/// Write the callsites of the late events to stderr, if checking.
+pub fn report() {
+    if let Some(events) = EVENTS.get() {
+        eprint!("{}", events.summary());
+    }
+}
+
+/// Name a panic in a destructor while unwinding after [`begin`], if checking.
+/// Called by the panic hook.
+pub fn on_panic(location: Option<&Location<'_>>, payload: &(dyn Any + Send)) {
+    if EVENTS.get().is_none() {
+        return;
+    }
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    if message != CLEANUP_PANIC {
+        let panic = location.map_or_else(
+            || format!("'{message}'"),
+            |location| format!("'{message}' at {location}"),
+        );
+        let _ = PANICS.try_with(|panics| {
+            let mut panics = panics.borrow_mut();
+            if panics.len() == 2 {
+                panics.remove(0);
+            }
+            panics.push(panic);
+        });
+        return;
+    }
+    if !BEGUN.load(Ordering::Relaxed) {
+        return;
+    }
+    let panics = PANICS.try_with(RefCell::take).unwrap_or_default();
+    let (unwinding, in_drop) = match panics.as_slice() {
+        [unwinding, in_drop] => (unwinding.as_str(), in_drop.as_str()),
+        [in_drop] => ("an unknown panic", in_drop.as_str()),
+        _ => ("an unknown panic", "an unknown panic"),
+    };
+    let thread = thread::current();
+    eprintln!(
+        "Error: panic-in-drop during shutdown on thread '{}' ({:?}): a destructor panicked with \
+         {in_drop} while unwinding from {unwinding}",
+        thread.name().unwrap_or("<unnamed>"),
+        thread.id(),
+    );
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing::{info, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_summary() {
+        let events = Arc::new(Events::default());
+        let layer = LateEvents {
+            events: events.clone(),
+        };
+        assert_eq!(events.summary(), "");
+        begin();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for _ in 0..3 {
+                warn!(target: "app::pool", "connection closed");
+            }
+            info!(target: "app", "flushed");
+        });
+
+        let summary = events.summary();
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{summary}");
+        assert!(lines[0].starts_with("Warning: 4 events"), "{summary}");
+        assert!(
+            lines[1].starts_with(&format!("  {}:", file!())),
+            "{summary}"
+        );
+        assert!(lines[1].ends_with(" WARN app::pool (3x)"), "{summary}");
+        assert!(lines[2].ends_with(" INFO app (1x)"), "{summary}");
+    }
+}
@@ -0,0 +1,261 @@
+#![cfg(feature = "otlp")]
+//! Span exporter that does not hold up startup.
+//!
+//! Creating the OTLP exporter can hang on an unreachable collector, e.g. while
+//! the system resolver times out. [`LazyExporter::start`] creates it on a
+//! separate thread and waits at most `--telemetry-init-timeout`. If it is not
+//! ready by then, startup continues with the spans buffered (up to
+//! [`MAX_BUFFERED`]) while creation is retried in the background with
+//! backoff. `--telemetry-init-policy fail` makes this a startup error instead.
+use crate::{loss, shutdown::is_process_shutting_down};
+use clap::ValueEnum;
+use eyre::{eyre, Result as EyreResult};
+use futures::future::BoxFuture;
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use std::{
+    fmt::{self, Debug, Formatter},
+    mem::take,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+/// Spans kept while the exporter is not ready.
+pub const MAX_BUFFERED: usize = 2048;
+
+/// Delay before the first retry, doubled up to [`MAX_BACKOFF`].
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum InitPolicy {
+    /// Buffer spans and keep trying in the background.
+    Degrade,
+    /// Fail startup.
+    Fail,
+}
+
+/// See the [module docs](self).
+pub struct LazyExporter<E> {
+    state: Arc<Mutex<State<E>>>,
+}
+
+struct State<E> {
+    exporter: Option<E>,
+    buffered: Vec<SpanData>,
+}
+
+impl<E: SpanExporter + 'static> LazyExporter<E> {
+    /// Create the exporter with `create`, waiting at most `timeout` for it.
+    ///
+    /// # Errors
+    ///
+    /// With [`InitPolicy::Fail`], if the exporter could not be created within
+    /// `timeout`.
+    pub fn start<F>(create: F, timeout: Duration, policy: InitPolicy) -> EyreResult<Self>
+    where
+        F: Fn() -> EyreResult<E> + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(State {
+            exporter: None,
+            buffered: Vec::new(),
+        }));
+        let (sender, receiver) = mpsc::channel();
+        let runtime = Handle::try_current().ok();
+        let background = state.clone();
+        thread::spawn(move || {
+            // The tonic exporter spawns its connection task on the runtime.
+            let _guard = runtime.as_ref().map(Handle::enter);
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                match create() {
+                    Ok(exporter) => {
+                        background.lock().unwrap().exporter = Some(exporter);
+                        if sender.send(Ok(())).is_err() {
+                            info!("OpenTelemetry exporter ready");
+                        }
+                        return;
+                    }
+                    Err(err) => {
+                        // Only the first error is reported, the receiver is
+                        // gone afterwards.
+                        let _ = sender.send(Err(err));
+                    }
+                }
+                if is_process_shutting_down() {
+                    return;
+                }
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+
+        let err = match receiver.recv_timeout(timeout) {
+            Ok(Ok(())) => return Ok(Self { state }),
+            Ok(Err(err)) => err,
+            Err(_) => eyre!("OpenTelemetry exporter not ready after {timeout:?}"),
+        };
+        match policy {
+            InitPolicy::Fail => Err(err),
+            InitPolicy::Degrade => {
+                warn!(
+                    ?err,
+                    max_buffered = MAX_BUFFERED,
+                    "OpenTelemetry exporter not ready, buffering spans and retrying in the \
+                     background"
+                );
+                Ok(Self { state })
+            }
+        }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for LazyExporter<E> {
+    #[allow(clippy::significant_drop_tightening)] // Export calls are serialized anyway
+    fn export(&mut self, mut batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(exporter) = &mut state.exporter {
+            if !state.buffered.is_empty() {
+                let mut buffered = take(&mut state.buffered);
+                buffered.append(&mut batch);
+                batch = buffered;
+            }
+            return exporter.export(batch);
+        }
+        let room = MAX_BUFFERED - state.buffered.len();
+        if batch.len() > room {
+            loss::SPANS_DROPPED.add((batch.len() - room) as u64);
+            batch.truncate(room);
+        }
+        state.buffered.append(&mut batch);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(exporter) = &mut self.state.lock().unwrap().exporter {
+            exporter.shutdown();
+        }
+    }
+}
+
+impl<E> Debug for LazyExporter<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("LazyExporter")
+            .field("ready", &state.exporter.is_some())
+            .field("buffered", &state.buffered.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use opentelemetry::{
+        sdk::trace::TracerProvider,
+        trace::{Tracer as _, TracerProvider as _},
+    };
+    use std::time::Instant;
+    use tracing_test::traced_test;
+
+    /// Keeps exported spans in memory.
+    #[derive(Clone, Debug, Default)]
+    struct Memory(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Memory {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn emit(provider: &TracerProvider, names: &[&'static str]) {
+        let tracer = provider.tracer("test");
+        for name in names {
+            drop(tracer.start(*name));
+        }
+    }
+
+    fn exported(memory: &Memory) -> Vec<String> {
+        memory
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|span| span.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_slow_exporter() {
+        let memory = Memory::default();
+        let created = memory.clone();
+        let start = Instant::now();
+        let exporter = LazyExporter::start(
+            move || {
+                thread::sleep(Duration::from_millis(300));
+                Ok(created.clone())
+            },
+            Duration::from_millis(50),
+            InitPolicy::Degrade,
+        )
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert!(logs_contain("OpenTelemetry exporter not ready"));
+
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+        emit(&provider, &["early"]);
+        assert!(exported(&memory).is_empty());
+
+        // Buffered spans go out with the first batch once ready.
+        thread::sleep(Duration::from_millis(500));
+        emit(&provider, &["late"]);
+        // Shutting down waits for the exporter to finish.
+        drop(provider);
+        assert_eq!(exported(&memory), ["early", "late"]);
+    }
+
+    #[test]
+    fn test_fail_policy() {
+        let result = LazyExporter::<Memory>::start(
+            || {
+                thread::sleep(Duration::from_millis(300));
+                Ok(Memory::default())
+            },
+            Duration::from_millis(50),
+            InitPolicy::Fail,
+        );
+        assert!(result.unwrap_err().to_string().contains("not ready after"));
+
+        let result = LazyExporter::<Memory>::start(
+            || Err(eyre!("invalid endpoint")),
+            Duration::from_secs(1),
+            InitPolicy::Fail,
+        );
+        assert_eq!(result.unwrap_err().to_string(), "invalid endpoint");
+    }
+
+    #[test]
+    fn test_buffer_cap() {
+        let exporter = LazyExporter::<Memory>::start(
+            || Err(eyre!("unreachable")),
+            Duration::from_millis(10),
+            InitPolicy::Degrade,
+        )
+        .unwrap();
+        let dropped = loss::SPANS_DROPPED.get();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+        emit(&provider, &vec!["span"; MAX_BUFFERED + 3]);
+        drop(provider);
+        assert!(loss::SPANS_DROPPED.get() - dropped >= 3);
+    }
+}
@@ -0,0 +1,151 @@
+//! `--log-async`: the log output is written on a background thread.
+//!
+//! Events are still formatted on the thread that logs them, only the write to
+//! stderr or the log file is queued. The queue holds
+//! [`DEFAULT_BUFFERED_LINES_LIMIT`](tracing_appender::non_blocking::DEFAULT_BUFFERED_LINES_LIMIT)
+//! lines, logging waits while it is full, so no line is lost. [`finish`]
+//! drains the queue, lines logged after it are written directly.
+#![cfg(feature = "log-async")]
+use crate::default_from_clap;
+use clap::Parser;
+use eyre::{eyre, Result as EyreResult};
+use once_cell::sync::OnceCell;
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::fmt::{
+    writer::{BoxMakeWriter, EitherWriter},
+    MakeWriter,
+};
+
+/// Set by [`finish`].
+static DRAINED: AtomicBool = AtomicBool::new(false);
+
+/// Drains the queue of the log output when taken and dropped.
+static GUARD: OnceCell<Mutex<Option<WorkerGuard>>> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Write the log output on a background thread, so logging does not wait
+    /// for the terminal or the log file unless 128000 lines are queued.
+    #[clap(long, env)]
+    log_async: bool,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// The log output `writer`, through a queue with `--log-async`. The queue
+    /// is drained by [`flush`].
+    pub fn wrap(self, writer: BoxMakeWriter) -> EyreResult<BoxMakeWriter> {
+        if !self.log_async {
+            return Ok(writer);
+        }
+        let (writer, guard) = LogAsync::new(writer);
+        GUARD
+            .set(Mutex::new(Some(guard)))
+            .map_err(|_| eyre!("log output guard already initialized"))?;
+        Ok(BoxMakeWriter::new(writer))
+    }
+}
+
+/// A writer queueing lines for a worker thread until [`finish`].
+pub struct LogAsync<W> {
+    queue:  NonBlocking,
+    direct: Arc<W>,
+}
+
+impl<W> LogAsync<W>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    /// Start the worker writing to `direct`. Dropping the guard drains the
+    /// queue.
+    pub fn new(direct: W) -> (Self, WorkerGuard) {
+        let direct = Arc::new(direct);
+        let (queue, guard) = NonBlockingBuilder::default()
+            .lossy(false)
+            .finish(Direct(direct.clone()));
+        (Self { queue, direct }, guard)
+    }
+}
+
+impl<'a, W> MakeWriter<'a> for LogAsync<W>
+where
+    W: MakeWriter<'a>,
+{
+    type Writer = EitherWriter<NonBlocking, W::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        if DRAINED.load(Ordering::Acquire) {
+            EitherWriter::B(self.direct.make_writer())
+        } else {
+            EitherWriter::A(self.queue.make_writer())
+        }
+    }
+}
+
+/// The end of the queue: the worker writes lines like the direct log output.
+struct Direct<W>(Arc<W>);
+
+impl<W> Write for Direct<W>
+where
+    W: for<'a> MakeWriter<'a>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.make_writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.make_writer().flush()
+    }
+}
+
+/// Write the queued lines and stop the worker. Later lines are written
+/// directly.
+pub fn finish(guard: WorkerGuard) {
+    DRAINED.store(true, Ordering::Release);
+    drop(guard);
+}
+
+/// Write the lines queued for the log output by [`Options::wrap`], if any.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn flush() {
+    let guard = GUARD.get().and_then(|guard| guard.lock().unwrap().take());
+    if let Some(guard) = guard {
+        finish(guard);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use tracing::info;
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_log_async() {
+        let capture = Capture::default();
+        let (writer, guard) = LogAsync::new(capture.clone());
+        let subscriber =
+            Registry::default().with(fmt::layer().with_ansi(false).with_writer(writer));
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..1000 {
+                info!(i, "queued");
+            }
+            finish(guard);
+            info!("direct");
+        });
+        let output = capture.contents();
+        assert_eq!(output.matches("queued").count(), 1000);
+        assert!(output.contains("i=999"));
+        assert!(output.ends_with("direct\n"), "{output}");
+    }
+}
@@ -0,0 +1,236 @@
+//! `--log-control-socket`: change the log filter of a running program.
+//!
+//! Every line sent to the unix socket is a command, answered with one line,
+//! `ok` and the effective filter or `error` and what went wrong:
+//!
+//! ```shell
+//! $ echo 'filter mycrate=trace' | socat - UNIX-CONNECT:/run/app/log.sock
+//! ok error,mycrate=trace
+//! ```
+//!
+//! * `filter <directives>` replaces `--log-filter`, combined with `--verbose`
+//!   and `--quiet` like on the command line.
+//! * `filter reset` goes back to the `--log-filter` of the command line.
+//!
+//! A filter that does not parse is answered with an error, the current one
+//! stays in use and the connection stays open. A line longer than
+//! [`MAX_LINE`] bytes or not in UTF-8 is answered with an error and closes the
+//! connection.
+#![cfg(unix)]
+use super::filter_reload::FilterOptions;
+use crate::{
+    default_from_clap,
+    shutdown::{process_receiver, wait},
+};
+use clap::Parser;
+use eyre::{eyre, Result as EyreResult, WrapErr as _};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{info, warn};
+
+/// Longest command line in bytes.
+const MAX_LINE: usize = 16 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Listen on this unix socket for commands changing the log filter, like
+    /// `filter mycrate=trace` or `filter reset`.
+    #[clap(long, env)]
+    log_control_socket: Option<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Start listening, if a socket is given. The listener closes the
+    /// connections and removes the socket when the program shuts down, the
+    /// returned task ends after that.
+    pub fn start(&self, filter: FilterOptions) -> EyreResult<Option<JoinHandle<()>>> {
+        let Some(path) = &self.log_control_socket else {
+            return Ok(None);
+        };
+        // Left behind by an earlier run
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path).wrap_err_with(|| format!("Error removing {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .wrap_err_with(|| format!("Error binding log control socket {}", path.display()))?;
+        info!(path = %path.display(), "Listening for log filter commands");
+        Ok(Some(tokio::spawn(serve(
+            listener,
+            path.clone(),
+            Arc::new(filter),
+        ))))
+    }
+}
+
+async fn serve(listener: UnixListener, path: PathBuf, filter: Arc<FilterOptions>) {
+    let mut shutdown = process_receiver();
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            () = wait(&mut shutdown) => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(connection(stream, filter.clone()));
+                }
+                Err(err) => warn!(%err, "Could not accept log control connection"),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    if let Err(err) = fs::remove_file(&path) {
+        warn!(%err, path = %path.display(), "Could not remove log control socket");
+    }
+}
+
+async fn connection(stream: UnixStream, filter: Arc<FilterOptions>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut shutdown = process_receiver();
+    loop {
+        let line = tokio::select! {
+            () = wait(&mut shutdown) => break,
+            line = read_line(&mut reader) => match line {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) if err.kind() == ErrorKind::InvalidData => {
+                    warn!(%err, "Closing log control connection");
+                    let _ = writer.write_all(format!("error {err}\n").as_bytes()).await;
+                    break;
+                }
+                Err(err) => {
+                    warn!(%err, "Could not read log control command");
+                    break;
+                }
+            },
+        };
+        let reply = match command(&filter, &line) {
+            Ok(effective) => {
+                info!(filter = %effective, "Log filter changed through the control socket");
+                format!("ok {effective}\n")
+            }
+            Err(report) => format!("error {report:#}\n"),
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Read a line of at most [`MAX_LINE`] bytes without the line ending, `None`
+/// at the end of the stream. Longer lines and invalid UTF-8 are
+/// [`ErrorKind::InvalidData`] errors.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE as u64 + 1;
+    if reader.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_LINE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Command longer than {MAX_LINE} bytes"),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Run a command, returning the effective filter.
+fn command(filter: &FilterOptions, line: &str) -> EyreResult<String> {
+    let command = line.trim().split_once(' ');
+    match command.map(|(command, argument)| (command, argument.trim())) {
+        Some(("filter", "reset")) => filter.apply(&filter.log_filter, "log-filter"),
+        Some(("filter", directives)) => filter.apply(directives, "filter"),
+        _ => Err(eyre!(
+            "Unknown command {line:?}, expected `filter <directives>` or `filter reset`"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::{default_verbosity, test::mock_version, LogDefaults};
+
+    fn filter() -> FilterOptions {
+        FilterOptions {
+            levels:       default_verbosity(0),
+            log_defaults: LogDefaults::NONE,
+            log_filter:   String::new(),
+            version:      mock_version(),
+        }
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let filter = filter();
+        let err = command(&filter, "filter app=nonsense").unwrap_err();
+        assert!(format!("{err:#}").starts_with("Error parsing filter: "));
+        let err = command(&filter, "level debug").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown command \"level debug\", expected `filter <directives>` or `filter reset`"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_line() {
+        let mut reader = &b"filter reset\r\n\nlast"[..];
+        assert_eq!(
+            read_line(&mut reader).await.unwrap().unwrap(),
+            "filter reset"
+        );
+        assert_eq!(read_line(&mut reader).await.unwrap().unwrap(), "");
+        assert_eq!(read_line(&mut reader).await.unwrap().unwrap(), "last");
+        assert!(read_line(&mut reader).await.unwrap().is_none());
+
+        let longest = "x".repeat(MAX_LINE);
+        let input = format!("{longest}\n{longest}x\n");
+        let mut reader = input.as_bytes();
+        assert_eq!(read_line(&mut reader).await.unwrap().unwrap(), longest);
+        let err = read_line(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut reader = &b"filter \xff\n"[..];
+        let err = read_line(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_long_line_closes() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let connection = tokio::spawn(connection(server, Arc::new(filter())));
+        let (reader, mut writer) = client.into_split();
+        let line = "x".repeat(4 * MAX_LINE);
+        writer.write_all(line.as_bytes()).await.unwrap();
+        let mut replies = BufReader::new(reader).lines();
+        assert_eq!(
+            replies.next_line().await.unwrap().unwrap(),
+            format!("error Command longer than {MAX_LINE} bytes")
+        );
+        // Closed, reset if the rest of the line was not read.
+        assert!(!matches!(replies.next_line().await, Ok(Some(_))));
+        connection.await.unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Events logged per level, summarized at shutdown.
+//!
+//! [`Counting`] tallies the events that pass the filter of the log output in
+//! one atomic per level. It is a single layer next to the outputs, so an
+//! event is counted once however many outputs, exporters and flame graphs
+//! see it. At shutdown [`log_summary`] logs a line like `Finished with 3
+//! warnings, 0 errors`, and [`log_counters`] returns the totals at any time.
+//!
+//! With `--fail-on-error-logs` a run whose main returns successfully still
+//! fails if it logged errors, for batch tools that log and continue.
+use crate::default_from_clap;
+use clap::Parser;
+use eyre::{bail, Result as EyreResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{info, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+static COUNTS: Counts = Counts::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Fail the run if errors were logged, even if it otherwise succeeded.
+    #[clap(long, env)]
+    fail_on_error_logs: bool,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Fail if `--fail-on-error-logs` is set and errors were logged.
+    pub fn check(&self) -> EyreResult<()> {
+        let errors = log_counters().error;
+        if self.fail_on_error_logs && errors > 0 {
+            bail!("{} logged", plural(errors, "error"));
+        }
+        Ok(())
+    }
+}
+
+/// The number of events logged per level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogCounters {
+    pub trace: u64,
+    pub debug: u64,
+    pub info:  u64,
+    pub warn:  u64,
+    pub error: u64,
+}
+
+/// Events per level, from `TRACE` to `ERROR`.
+pub struct Counts([AtomicU64; 5]);
+
+impl Counts {
+    const fn new() -> Self {
+        Self([const { AtomicU64::new(0) }; 5])
+    }
+
+    fn add(&self, level: Level) {
+        self.0[index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> LogCounters {
+        let count = |level| self.0[index(level)].load(Ordering::Relaxed);
+        LogCounters {
+            trace: count(Level::TRACE),
+            debug: count(Level::DEBUG),
+            info:  count(Level::INFO),
+            warn:  count(Level::WARN),
+            error: count(Level::ERROR),
+        }
+    }
+}
+
+const fn index(level: Level) -> usize {
+    match level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
+    }
+}
+
+/// Counts the events it sees per level. Filter it like the log output.
+pub struct Counting(&'static Counts);
+
+impl Counting {
+    /// Counting into the totals of [`log_counters`].
+    pub const fn global() -> Self {
+        Self(&COUNTS)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Counting {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        self.0.add(*event.metadata().level());
+    }
+}
+
+/// The events logged so far in this process, per level.
+#[must_use]
+pub fn log_counters() -> LogCounters {
+    COUNTS.get()
+}
+
+/// Log the number of warnings and errors of this run.
+pub fn log_summary() {
+    let LogCounters { warn, error, .. } = log_counters();
+    info!(
+        warnings = warn,
+        errors = error,
+        "Finished with {}, {}",
+        plural(warn, "warning"),
+        plural(error, "error")
+    );
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io;
+    use tracing::{debug, error, trace, warn};
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_counting() {
+        static COUNTS: Counts = Counts::new();
+        let subscriber = Registry::default()
+            .with(Counting(&COUNTS).with_filter(LevelFilter::DEBUG))
+            .with(tracing_subscriber::fmt::layer().with_writer(io::sink));
+        tracing::subscriber::with_default(subscriber, || {
+            warn!("counted once, with two layers");
+            error!("failed");
+            error!("failed again");
+            debug!("debugging");
+            trace!("filtered out");
+        });
+        assert_eq!(COUNTS.get(), LogCounters {
+            trace: 0,
+            debug: 1,
+            info:  0,
+            warn:  1,
+            error: 2,
+        });
+
+        assert_eq!(plural(1, "error"), "1 error");
+        assert_eq!(plural(0, "warning"), "0 warnings");
+    }
+}
@@ -0,0 +1,281 @@
+//! Log defaults built into the binary.
+//!
+//! [`build_rs`](crate::build_rs) reads `log-defaults.toml` from the crate
+//! root, or the file named by [`PATH_VAR`], like
+//!
+//! ```toml
+//! # Filter directives, like `--log-filter`.
+//! filter = ["hyper=warn", "h2=warn"]
+//! # Targets that are never logged.
+//! suppress = ["tokio_util::codec"]
+//! # The log format if there is no `--log-format`.
+//! format = "json"
+//! ```
+//!
+//! and embeds it as a `LOG_DEFAULTS` constant, which
+//! [`include_log_defaults!`](crate::include_log_defaults) defines in the app
+//! for [`Runner::log_defaults`](crate::Runner::log_defaults):
+//!
+//! ```rust,ignore
+//! cli_batteries::include_log_defaults!();
+//!
+//! fn main() {
+//!     Runner::new(version!()).log_defaults(LOG_DEFAULTS).run(app);
+//! }
+//! ```
+//!
+//! The built-in filter has the lowest precedence: `--verbose` overrides it
+//! for the app crates and `--log-filter` for any target. Without
+//! `--log-filter` or `LOG_FILTER`, `RUST_LOG` takes its place.
+//! `--print-log-filter` shows where each directive comes from and
+//! `--print-config` which flags have a built-in value.
+//!
+//! The file is checked at build time. A `log-defaults.toml` created after the
+//! first build is only picked up once the build script reruns. Reading it
+//! needs the `log-defaults` feature of the build dependency.
+use super::LogFormat;
+use eyre::{bail, eyre, Result as EyreResult, WrapErr as _};
+#[cfg(feature = "log-defaults")]
+use serde::Deserialize;
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+use tracing_subscriber::filter::Targets;
+
+/// Define the `LOG_DEFAULTS` constant embedded by
+/// [`build_rs`](crate::build_rs), see [`LogDefaults`](crate::LogDefaults).
+#[macro_export]
+macro_rules! include_log_defaults {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/log_defaults.rs"));
+    };
+}
+
+/// Environment variable with the path of the log defaults file, relative to
+/// the crate root.
+pub const PATH_VAR: &str = "CLI_BATTERIES_LOG_DEFAULTS";
+
+/// The default file name, in the crate root.
+const FILE_NAME: &str = "log-defaults.toml";
+
+/// The file written to `OUT_DIR` by [`embed`].
+const OUT_FILE: &str = "log_defaults.rs";
+
+/// Log defaults of an app, see the [module docs](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogDefaults {
+    /// Filter directives, with the suppressed targets as `target=off`.
+    pub filter: &'static str,
+    /// The log format, empty for the default.
+    pub format: &'static str,
+}
+
+impl LogDefaults {
+    /// No built-in defaults, without
+    /// [`Runner::log_defaults`](crate::Runner::log_defaults).
+    pub const NONE: Self = Self {
+        filter: "",
+        format: "",
+    };
+
+    /// The built-in filter directives, by target.
+    pub(super) fn targets(&self) -> EyreResult<Targets> {
+        parse_filter(self.filter).wrap_err("Error parsing built-in log filter")
+    }
+
+    /// The built-in log format, if any.
+    pub(super) fn format(&self) -> EyreResult<Option<LogFormat>> {
+        if self.format.is_empty() {
+            return Ok(None);
+        }
+        self.format
+            .parse()
+            .map(Some)
+            .wrap_err("Error parsing built-in log format")
+    }
+
+    /// The `LOG_DEFAULTS` constant as Rust source.
+    fn to_rust(self) -> String {
+        let ty = "::cli_batteries::LogDefaults";
+        [
+            "/// The log defaults built into this binary.".to_owned(),
+            format!("pub const LOG_DEFAULTS: {ty} = {ty} {{"),
+            format!("    filter: {:?},", self.filter),
+            format!("    format: {:?},", self.format),
+            "};\n".to_owned(),
+        ]
+        .join("\n")
+    }
+}
+
+/// Where a directive of the log filter comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilterSource {
+    BuiltIn,
+    Verbose,
+    LogFilter,
+    RustLog,
+}
+
+impl fmt::Display for FilterSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BuiltIn => "built-in",
+            Self::Verbose => "--verbose",
+            Self::LogFilter => "--log-filter",
+            Self::RustLog => "RUST_LOG",
+        })
+    }
+}
+
+/// Where the log filter was read from, in decreasing precedence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilterOrigin {
+    Flag,
+    LogFilter,
+    RustLog,
+    /// Only the built-in filter and `--verbose`.
+    #[default]
+    None,
+}
+
+impl fmt::Display for FilterOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Flag => "--log-filter",
+            Self::LogFilter => "LOG_FILTER",
+            Self::RustLog => "RUST_LOG",
+            Self::None => "none",
+        })
+    }
+}
+
+/// The contents of `log-defaults.toml`.
+#[cfg(feature = "log-defaults")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    filter:   Vec<String>,
+    #[serde(default)]
+    suppress: Vec<String>,
+    format:   Option<String>,
+}
+
+#[cfg(feature = "log-defaults")]
+impl Config {
+    fn parse(toml: &str) -> EyreResult<LogDefaults> {
+        let config: Self = toml::from_str(toml)?;
+        let mut directives = config.filter;
+        for target in config.suppress {
+            if target.is_empty() || target.contains(['=', ',']) {
+                bail!("Invalid suppressed target `{target}`");
+            }
+            directives.push(format!("{target}=off"));
+        }
+        let filter = directives.join(",");
+        parse_filter(&filter)?;
+        let format = config.format.unwrap_or_default();
+        if !format.is_empty() {
+            format.parse::<LogFormat>()?;
+        }
+        Ok(LogDefaults {
+            filter: leak(filter),
+            format: leak(format),
+        })
+    }
+}
+
+#[cfg(feature = "log-defaults")]
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+/// Parse filter directives, which all need a target: the default level is
+/// set by `--verbose`.
+fn parse_filter(filter: &str) -> EyreResult<Targets> {
+    if filter.is_empty() {
+        return Ok(Targets::new());
+    }
+    let targets: Targets = filter.parse()?;
+    if targets.default_level().is_some() {
+        bail!("Directive without a target in `{filter}`");
+    }
+    Ok(targets)
+}
+
+/// Read and check the log defaults file at `path`.
+#[cfg(feature = "log-defaults")]
+fn read(path: &Path) -> EyreResult<LogDefaults> {
+    let toml =
+        fs::read_to_string(path).wrap_err_with(|| format!("Error reading {}", path.display()))?;
+    Config::parse(&toml).wrap_err_with(|| format!("Invalid log defaults in {}", path.display()))
+}
+
+#[cfg(not(feature = "log-defaults"))]
+fn read(path: &Path) -> EyreResult<LogDefaults> {
+    bail!(
+        "Reading {} needs the `log-defaults` feature",
+        path.display()
+    )
+}
+
+/// Read the log defaults file of the crate being built and embed it as the
+/// `LOG_DEFAULTS` constant in `$OUT_DIR/log_defaults.rs`.
+pub fn embed() -> EyreResult<()> {
+    println!("cargo:rerun-if-env-changed={PATH_VAR}");
+    let path = env::var_os(PATH_VAR).map_or_else(|| PathBuf::from(FILE_NAME), PathBuf::from);
+    let defaults = if path.exists() {
+        println!("cargo:rerun-if-changed={}", path.display());
+        read(&path)?
+    } else if env::var_os(PATH_VAR).is_some() {
+        bail!("Log defaults file {} not found", path.display());
+    } else {
+        LogDefaults::NONE
+    };
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| eyre!("OUT_DIR is not set"))?;
+    let path = PathBuf::from(out_dir).join(OUT_FILE);
+    fs::write(&path, defaults.to_rust())
+        .wrap_err_with(|| format!("Error writing {}", path.display()))
+}
+
+#[cfg(all(test, feature = "log-defaults"))]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let defaults = Config::parse(
+            r#"
+            filter = ["hyper=warn", "h2=warn"]
+            suppress = ["tokio_util::codec"]
+            format = "json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(defaults, LogDefaults {
+            filter: "hyper=warn,h2=warn,tokio_util::codec=off",
+            format: "json",
+        });
+        assert_eq!(defaults.format().unwrap(), Some(LogFormat::Json));
+        assert_eq!(defaults.targets().unwrap().iter().count(), 3);
+
+        let rust = defaults.to_rust();
+        assert!(rust.contains("    filter: \"hyper=warn,h2=warn,tokio_util::codec=off\",\n"));
+        assert!(rust.contains("    format: \"json\",\n"));
+
+        assert_eq!(Config::parse("").unwrap(), LogDefaults::NONE);
+        assert_eq!(LogDefaults::NONE.format().unwrap(), None);
+        for invalid in [
+            "filter = \"hyper=warn\"",
+            "filter = [\"warn\"]",
+            "filter = [\"hyper=loud\"]",
+            "suppress = [\"hyper=warn\"]",
+            "format = \"xml\"",
+            "level = \"warn\"",
+        ] {
+            assert!(Config::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! The `--log-file`, optionally rotated.
+//!
+//! With `--log-rotate` the file is renamed to `<name>.<time>` and a new one
+//! started when the UTC day or hour changes (`daily`, `hourly`), or before an
+//! event would grow it past a size (`size:100MB`). Only the newest
+//! `--log-keep` rotated files are kept.
+//!
+//! Rotation only happens at the start of a line, so an event is never split
+//! across files, also when several threads log at once.
+//!
+//! With `--log-file-compress` the file is zstd compressed to `<name>.zst` and
+//! rotated to `<name>.<time>.zst`, see [`file_output`](super::file_output).
+//! The size of `size:` rotation is that of the log before compression.
+use super::file_output::{self, FileOutput, EXTENSION};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use eyre::{bail, Result as EyreResult, WrapErr as _};
+use once_cell::sync::Lazy;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Format of the time suffix of rotated files, which sorts by age.
+const ROTATED_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// The `--log-file` and the files of `--log-sink`.
+static ACTIVE: Lazy<Mutex<Vec<Arc<RotatingFile>>>> = Lazy::new(Mutex::default);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rotation {
+    Daily,
+    Hourly,
+    /// Maximum size in bytes.
+    Size(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "hourly" => Ok(Self::Hourly),
+            _ => s
+                .strip_prefix("size:")
+                .and_then(parse_size)
+                .filter(|size| *size > 0)
+                .map(Self::Size)
+                .ok_or_else(|| format!("expected daily, hourly or size:<bytes>, got `{s}`")),
+        }
+    }
+}
+
+impl Rotation {
+    /// The period `time` is in, rotation happens when it changes.
+    fn period(self, time: DateTime<Utc>) -> i64 {
+        match self {
+            Self::Daily => time.timestamp().div_euclid(24 * 60 * 60),
+            Self::Hourly => time.timestamp().div_euclid(60 * 60),
+            Self::Size(_) => 0,
+        }
+    }
+}
+
+/// A size like `100MB`, `512KiB` or `4096`.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let unit = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// The log file, safe to write to from several threads.
+pub struct RotatingFile {
+    path:     PathBuf,
+    rotation: Option<Rotation>,
+    keep:     usize,
+    compress: bool,
+    state:    Mutex<State>,
+}
+
+struct State {
+    file:       FileOutput,
+    size:       u64,
+    period:     i64,
+    line_start: bool,
+    rotated:    Option<DateTime<Utc>>,
+}
+
+/// Open the log file for appending, creating it and its directory if missing,
+/// and remember it for [`finish`].
+pub fn open(
+    path: &Path,
+    rotation: Option<Rotation>,
+    keep: usize,
+    compress: bool,
+) -> EyreResult<Arc<RotatingFile>> {
+    let file = Arc::new(RotatingFile::open(path, rotation, keep, compress)?);
+    if compress {
+        file_output::end_frames_periodically(Arc::downgrade(&file), RotatingFile::end_frame);
+    }
+    ACTIVE.lock().unwrap().push(file.clone());
+    Ok(file)
+}
+
+/// Flush the log files to disk, if any.
+pub fn finish() -> EyreResult<()> {
+    let files = ACTIVE.lock().unwrap().clone();
+    for file in files {
+        file.sync()
+            .wrap_err_with(|| format!("Error flushing log file {}", file.path.display()))?;
+    }
+    Ok(())
+}
+
+impl RotatingFile {
+    fn open(
+        path: &Path,
+        rotation: Option<Rotation>,
+        keep: usize,
+        compress: bool,
+    ) -> EyreResult<Self> {
+        if let Some(dir) = parent(path) {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Error creating log file directory {}", dir.display()))?;
+        }
+        if path.file_name().is_none() {
+            bail!("Log file {} is not a file name", path.display());
+        }
+        let path = if compress {
+            with_suffix(path, EXTENSION)
+        } else {
+            path.to_owned()
+        };
+        let file = append(&path)
+            .wrap_err_with(|| format!("Error opening log file {} for writing", path.display()))?;
+        let metadata = file.metadata()?;
+        // Continue the period of an existing file, so it rotates after a restart.
+        let modified = metadata
+            .modified()
+            .map_or_else(|_| Utc::now(), DateTime::from);
+        Ok(Self {
+            path,
+            rotation,
+            keep,
+            compress,
+            state: Mutex::new(State {
+                file:       FileOutput::new(file, compress),
+                size:       metadata.len(),
+                period:     rotation.map_or(0, |rotation| rotation.period(modified)),
+                line_start: true,
+                rotated:    None,
+            }),
+        })
+    }
+
+    #[allow(clippy::significant_drop_tightening)] // The lock covers rotation
+    fn write_at(&self, buf: &[u8], now: DateTime<Utc>) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.line_start && self.is_due(&state, buf.len(), now) {
+            self.rotate(&mut state, now)?;
+        }
+        let written = state.file.write(buf)?;
+        state.size += written as u64;
+        if written > 0 {
+            state.line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn is_due(&self, state: &State, len: usize, now: DateTime<Utc>) -> bool {
+        match self.rotation {
+            None => false,
+            Some(Rotation::Size(limit)) => state.size > 0 && state.size + len as u64 > limit,
+            Some(rotation) => rotation.period(now) != state.period,
+        }
+    }
+
+    /// Move the file aside, start a new one and prune the old ones.
+    fn rotate(&self, state: &mut State, now: DateTime<Utc>) -> io::Result<()> {
+        // Distinct names for rotations within the same microsecond.
+        let time = state
+            .rotated
+            .map_or(now, |last| now.max(last + Duration::microseconds(1)));
+        state.rotated = Some(time);
+        // A compressed file is complete before it is moved.
+        state.file.end_frame()?;
+        let time = time.format(ROTATED_FORMAT).to_string();
+        let rotated = if self.compress {
+            with_suffix(&self.base(), &format!("{time}.{EXTENSION}"))
+        } else {
+            with_suffix(&self.path, &time)
+        };
+        match fs::rename(&self.path, rotated) {
+            // Removed by someone else, start a new one.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        state.file = FileOutput::new(append(&self.path)?, self.compress);
+        state.size = 0;
+        state.period = self.rotation.map_or(0, |rotation| rotation.period(now));
+        self.prune()
+    }
+
+    /// The path of the log file without the compression extension.
+    fn base(&self) -> PathBuf {
+        if self.compress {
+            self.path.with_extension("")
+        } else {
+            self.path.clone()
+        }
+    }
+
+    /// Remove all but the newest `keep` rotated files.
+    fn prune(&self) -> io::Result<()> {
+        let base = self.base();
+        let dir = parent(&base).unwrap_or_else(|| Path::new("."));
+        let name = base.file_name().unwrap_or_default().to_string_lossy();
+        let extension = format!(".{EXTENSION}");
+        let mut rotated = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        rotated.retain(|path| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            file_name
+                .strip_prefix(&*name)
+                .and_then(|suffix| suffix.strip_prefix('.'))
+                .map(|suffix| suffix.strip_suffix(&extension).unwrap_or(suffix))
+                .is_some_and(is_rotated)
+        });
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.keep);
+        for path in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Complete the frame of a compressed file and sync it to disk.
+    fn sync(&self) -> io::Result<()> {
+        self.state.lock().unwrap().file.sync()
+    }
+
+    /// End the current frame of a compressed file, whether that worked.
+    fn end_frame(&self) -> bool {
+        self.state.lock().unwrap().file.end_frame().is_ok()
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, Utc::now())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+/// `path` with `.suffix` appended to the file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(format!(".{suffix}"));
+    path.into()
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Whether `suffix` is the time suffix of a rotated file.
+fn is_rotated(suffix: &str) -> bool {
+    NaiveDateTime::parse_from_str(suffix, ROTATED_FORMAT).is_ok()
+}
+
+fn parent(path: &Path) -> Option<&Path> {
+    path.parent().filter(|dir| !dir.as_os_str().is_empty())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    #[cfg(feature = "log-compress")]
+    use crate::logs::read_compressed;
+    use chrono::TimeZone;
+    use std::{
+        env,
+        process::id as pid,
+        thread,
+        time::{Duration as StdDuration, SystemTime},
+    };
+
+    /// A fresh directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cli-batteries-log-file-{name}-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Contents of the rotated files, oldest first, and the active file.
+    fn contents(dir: &Path) -> Vec<String> {
+        let mut paths = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        // `app.log` sorts before its rotations.
+        paths.sort();
+        paths.rotate_left(1);
+        paths
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert_eq!("hourly".parse(), Ok(Rotation::Hourly));
+        assert_eq!("size:100MB".parse(), Ok(Rotation::Size(100_000_000)));
+        assert_eq!("size:512KiB".parse(), Ok(Rotation::Size(512 * 1024)));
+        assert_eq!("size:4096".parse(), Ok(Rotation::Size(4096)));
+        assert!("size:0".parse::<Rotation>().is_err());
+        assert!("size:MB".parse::<Rotation>().is_err());
+        assert!("size:10TB".parse::<Rotation>().is_err());
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn test_size() {
+        let dir = temp_dir("size");
+        let file =
+            RotatingFile::open(&dir.join("app.log"), Some(Rotation::Size(20)), 1, false).unwrap();
+        for i in 0..5 {
+            (&file)
+                .write_all(format!("event {i}\n").as_bytes())
+                .unwrap();
+        }
+        // Two events per file, the oldest pruned.
+        assert_eq!(contents(&dir), ["event 2\nevent 3\n", "event 4\n"]);
+        let mut writer = &file;
+        writeln!(writer, "event 5").unwrap();
+        writeln!(writer, "event 6").unwrap();
+        assert_eq!(contents(&dir), ["event 4\nevent 5\n", "event 6\n"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "log-compress")]
+    fn test_compressed() {
+        let dir = temp_dir("compressed");
+        let file =
+            RotatingFile::open(&dir.join("app.log"), Some(Rotation::Size(20)), 1, true).unwrap();
+        for i in 0..5 {
+            (&file)
+                .write_all(format!("event {i}\n").as_bytes())
+                .unwrap();
+        }
+        file.sync().unwrap();
+
+        // Rotated and pruned like an uncompressed file.
+        let mut paths = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        let names = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 2, "{names:?}");
+        let rotated = names[0].strip_prefix("app.log.").unwrap();
+        assert!(
+            is_rotated(rotated.strip_suffix(".zst").unwrap()),
+            "{names:?}"
+        );
+        assert_eq!(names[1], "app.log.zst");
+        let contents = paths
+            .iter()
+            .map(|path| String::from_utf8(read_compressed(path).unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["event 2\nevent 3\n", "event 4\n"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_daily() {
+        let dir = temp_dir("daily");
+        let path = dir.join("app.log");
+        let file = RotatingFile::open(&path, Some(Rotation::Daily), 10, false).unwrap();
+        let evening = Utc.with_ymd_and_hms(2030, 1, 1, 23, 59, 59).unwrap();
+        let midnight = Utc.with_ymd_and_hms(2030, 1, 2, 0, 0, 0).unwrap();
+        // The file is from today, not 2030.
+        file.write_at(b"before\n", evening).unwrap();
+        file.write_at(b"late ", evening).unwrap();
+        // Not in the middle of a line.
+        file.write_at(b"line\n", midnight).unwrap();
+        file.write_at(b"after\n", midnight).unwrap();
+        assert_eq!(contents(&dir), ["", "before\nlate line\n", "after\n"]);
+        assert!(dir.join("app.log.20300102T000000.000000Z").exists());
+
+        // A file from yesterday rotates after a restart.
+        drop(file);
+        let yesterday = SystemTime::now() - StdDuration::from_hours(24);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(yesterday)
+            .unwrap();
+        let file = RotatingFile::open(&path, Some(Rotation::Daily), 10, false).unwrap();
+        file.write_at(b"restarted\n", Utc::now()).unwrap();
+        assert_eq!(contents(&dir).last().unwrap(), "restarted\n");
+        assert_eq!(contents(&dir).len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let dir = temp_dir("concurrent");
+        let file = Arc::new(
+            RotatingFile::open(
+                &dir.join("app.log"),
+                Some(Rotation::Size(1000)),
+                1000,
+                false,
+            )
+            .unwrap(),
+        );
+        let threads = (0..8)
+            .map(|thread| {
+                let file = file.clone();
+                thread::spawn(move || {
+                    for event in 0..200 {
+                        let line = format!("thread {thread} event {event}\n");
+                        (&*file).write_all(line.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let files = contents(&dir);
+        assert!(files.len() > 20, "{}", files.len());
+        let lines = files
+            .iter()
+            .flat_map(|file| file.lines())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 8 * 200);
+        assert!(lines.iter().all(|line| line.starts_with("thread ")));
+        assert!(files
+            .iter()
+            .all(|file| file.len() <= 1000 && file.ends_with('\n')));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Span and field directives in the log filter.
+//!
+//! Plain `target=level` directives are matched by a [`Targets`] filter. A
+//! directive with a span, optionally with field values, like
+//! `my_crate[request{method=POST}]=debug`, enables events inside matching
+//! spans up to its level, in addition to what the other directives enable.
+//! Only a filter with such directives builds an [`EnvFilter`] for them, which
+//! tracks the spans that match on every span enter and exit.
+use super::Directives;
+use eyre::{Result as EyreResult, WrapErr as _};
+use std::str::FromStr;
+use tracing::{
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Metadata,
+};
+use tracing_subscriber::{
+    filter::{Directive, EnvFilter, LevelFilter, Targets},
+    layer::{Context, Filter},
+};
+
+/// The plain directives of `log_filter` and the span directives, by the
+/// text before their level.
+pub(super) fn parse(log_filter: &str) -> EyreResult<(Targets, Vec<(String, LevelFilter)>)> {
+    let mut plain = Vec::new();
+    let mut spans = Vec::new();
+    for directive in split(log_filter) {
+        if !directive.contains('[') {
+            plain.push(directive);
+            continue;
+        }
+        Directive::from_str(directive)?;
+        // The level follows the span, without one it is `trace`
+        let end = directive.rfind(']').unwrap_or_default() + 1;
+        let level = match directive[end..].strip_prefix('=') {
+            Some(level) => LevelFilter::from_str(level)
+                .wrap_err_with(|| format!("Invalid level in `{directive}`"))?,
+            None => LevelFilter::TRACE,
+        };
+        spans.push((directive[..end].to_owned(), level));
+    }
+    let targets = if plain.is_empty() {
+        Targets::new()
+    } else {
+        plain.join(",").parse()?
+    };
+    Ok((targets, spans))
+}
+
+/// The directives of `log_filter`, split at commas outside of spans and
+/// field values.
+fn split(log_filter: &str) -> Vec<&str> {
+    let mut directives = Vec::new();
+    let mut depth = 0_usize;
+    let mut start = 0;
+    for (i, c) in log_filter.char_indices() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                directives.push(&log_filter[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    directives.push(&log_filter[start..]);
+    directives
+        .into_iter()
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .collect()
+}
+
+/// The filter of the log outputs: the targets, and the span directives if
+/// there are any.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    targets: Targets,
+    spans:   Option<SpanFilter>,
+}
+
+/// The span directives, kept to build a filter for each clone, so layers do
+/// not share which spans they entered.
+#[derive(Debug)]
+struct SpanFilter {
+    directives: Vec<Directive>,
+    filter:     EnvFilter,
+}
+
+impl SpanFilter {
+    /// The filter of `directives`, added one by one: `EnvFilter` splits a
+    /// string at every comma, including those between field values.
+    fn new(directives: Vec<Directive>) -> Self {
+        let filter = directives.iter().cloned().fold(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::OFF.into())
+                .parse_lossy(""),
+            EnvFilter::add_directive,
+        );
+        Self { directives, filter }
+    }
+}
+
+impl Clone for SpanFilter {
+    fn clone(&self) -> Self {
+        Self::new(self.directives.clone())
+    }
+}
+
+impl LogFilter {
+    /// The filter of `default` and `directives`, the span directives among
+    /// them as [`parse`] returns them.
+    pub(super) fn new(default: LevelFilter, directives: &Directives) -> EyreResult<Self> {
+        let (spans, plain): (Vec<_>, Vec<_>) = directives
+            .iter()
+            .partition(|(target, _)| target.contains('['));
+        let targets = Targets::new().with_default(default).with_targets(
+            plain
+                .into_iter()
+                .map(|(target, (level, _))| (target.clone(), *level)),
+        );
+        let spans = spans
+            .iter()
+            .map(|(span, (level, _))| {
+                Directive::from_str(&format!("{span}={}", level.to_string().to_lowercase()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let spans = (!spans.is_empty()).then(|| SpanFilter::new(spans));
+        Ok(Self { targets, spans })
+    }
+
+    /// The filter without the span directives.
+    pub(super) const fn targets(&self) -> &Targets {
+        &self.targets
+    }
+
+    /// Whether events of `target` at `level` are enabled outside of the
+    /// spans of span directives.
+    #[cfg(test)]
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Same as `Targets::would_enable`
+    pub(super) fn would_enable(&self, target: &str, level: &tracing::Level) -> bool {
+        self.targets.would_enable(target, level)
+    }
+}
+
+impl<S> Filter<S> for LogFilter {
+    fn enabled(&self, metadata: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        Filter::enabled(&self.targets, metadata, cx)
+            || self
+                .spans
+                .as_ref()
+                .is_some_and(|spans| Filter::enabled(&spans.filter, metadata, cx))
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let targets = Filter::<S>::callsite_enabled(&self.targets, metadata);
+        let Some(spans) = &self.spans else {
+            return targets;
+        };
+        let spans = Filter::<S>::callsite_enabled(&spans.filter, metadata);
+        if targets.is_always() || spans.is_always() {
+            Interest::always()
+        } else if targets.is_never() && spans.is_never() {
+            Interest::never()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let targets = Filter::<S>::max_level_hint(&self.targets)?;
+        match &self.spans {
+            Some(spans) => Some(targets.max(Filter::<S>::max_level_hint(&spans.filter)?)),
+            None => Some(targets),
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, cx: Context<'_, S>) {
+        if let Some(spans) = &self.spans {
+            Filter::on_new_span(&spans.filter, attrs, id, cx);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, cx: Context<'_, S>) {
+        if let Some(spans) = &self.spans {
+            Filter::on_record(&spans.filter, id, values, cx);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, cx: Context<'_, S>) {
+        if let Some(spans) = &self.spans {
+            Filter::on_enter(&spans.filter, id, cx);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, cx: Context<'_, S>) {
+        if let Some(spans) = &self.spans {
+            Filter::on_exit(&spans.filter, id, cx);
+        }
+    }
+
+    fn on_close(&self, id: Id, cx: Context<'_, S>) {
+        if let Some(spans) = &self.spans {
+            Filter::on_close(&spans.filter, id, cx);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{log_defaults::FilterSource, test::Capture};
+    use std::collections::BTreeMap;
+    use tracing::{debug, info_span, subscriber::with_default, trace, Level};
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Layer, Registry};
+
+    #[test]
+    fn test_parse() {
+        let (targets, spans) =
+            parse("dep=warn, app[request{method=POST,id=1}]=debug,[tick],app=info").unwrap();
+        assert!(targets.would_enable("dep", &Level::WARN));
+        assert!(!targets.would_enable("dep", &Level::INFO));
+        assert!(targets.would_enable("app", &Level::INFO));
+        assert_eq!(spans, [
+            (
+                "app[request{method=POST,id=1}]".to_owned(),
+                LevelFilter::DEBUG
+            ),
+            ("[tick]".to_owned(), LevelFilter::TRACE),
+        ]);
+        assert!(parse("app[request{method=POST}]=loud").is_err());
+    }
+
+    #[test]
+    fn test_span_directives() {
+        let (targets, spans) = parse("app=info,app[request{method=POST}]=debug").unwrap();
+        let directives = targets
+            .iter()
+            .map(|(target, level)| (target.to_owned(), level))
+            .chain(spans)
+            .map(|(target, level)| (target, (level, FilterSource::LogFilter)))
+            .collect::<BTreeMap<_, _>>();
+        let filter = LogFilter::new(LevelFilter::ERROR, &directives).unwrap();
+        assert!(filter.would_enable("app", &Level::INFO));
+        assert!(!filter.would_enable("app", &Level::DEBUG));
+
+        let capture = Capture::default();
+        let layer = fmt::layer()
+            .with_writer(capture.clone())
+            .with_ansi(false)
+            .without_time()
+            .with_filter(filter);
+        with_default(Registry::default().with(layer), || {
+            info_span!(target: "app", "request", method = "POST").in_scope(|| {
+                debug!(target: "app", "post");
+                trace!(target: "app", "too detailed");
+            });
+            info_span!(target: "app", "request", method = "GET").in_scope(|| {
+                debug!(target: "app", "get");
+            });
+            debug!(target: "app", "outside");
+        });
+        let logs = capture.contents();
+        assert!(
+            logs.contains("request{method=\"POST\"}: app: post"),
+            "{logs}"
+        );
+        assert!(!logs.contains("too detailed"), "{logs}");
+        assert!(!logs.contains("get"), "{logs}");
+        assert!(!logs.contains("outside"), "{logs}");
+    }
+}
@@ -0,0 +1,132 @@
+//! `--log-sink`: more log outputs, each in its own format.
+//!
+//! A sink like `format=json,target=file:/var/log/app.json` gets the events of
+//! the log output, with its own copy of the log filter, and writes them to
+//! `stdout`, `stderr` or appends them to a file. Files rotate like the
+//! `--log-file` and are flushed at shutdown. Sinks are written to directly,
+//! also with `--log-async`.
+use super::{
+    disk_full::{DiskFull, DiskFullPolicy},
+    log_file::{self, Rotation},
+    LogFormat, LogStream,
+};
+use eyre::{bail, eyre, Report, Result as EyreResult};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogSink {
+    pub format: LogFormat,
+    pub target: SinkTarget,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SinkTarget {
+    Stream(LogStream),
+    File(PathBuf),
+}
+
+impl LogSink {
+    /// Whether the sink is a terminal, for `tty-only` escaping.
+    pub fn is_terminal(&self) -> bool {
+        match &self.target {
+            SinkTarget::Stream(stream) => stream.is_terminal(),
+            SinkTarget::File(_) => false,
+        }
+    }
+
+    /// Whether the sink is a file, which gets no colors.
+    pub const fn is_file(&self) -> bool {
+        matches!(self.target, SinkTarget::File(_))
+    }
+
+    /// Open the sink, files with the rotation, compression and disk full
+    /// policy of the `--log-file`.
+    pub fn writer(
+        &self,
+        rotation: Option<Rotation>,
+        keep: usize,
+        compress: bool,
+        policy: DiskFullPolicy,
+    ) -> EyreResult<BoxMakeWriter> {
+        Ok(match &self.target {
+            SinkTarget::Stream(stream) => stream.writer(),
+            SinkTarget::File(path) => BoxMakeWriter::new(Arc::new(DiskFull::new(
+                "log sink",
+                log_file::open(path, rotation, keep, compress)?,
+                policy,
+            ))),
+        })
+    }
+}
+
+impl FromStr for LogSink {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = None;
+        let mut target = None;
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| eyre!("expected key=value, got `{pair}`"))?;
+            match key {
+                "format" => format = Some(value.parse()?),
+                "target" => {
+                    target = Some(match value {
+                        "stdout" => SinkTarget::Stream(LogStream::Stdout),
+                        "stderr" => SinkTarget::Stream(LogStream::Stderr),
+                        _ => match value.strip_prefix("file:") {
+                            Some(path) if !path.is_empty() => SinkTarget::File(path.into()),
+                            _ => bail!(
+                                "Invalid log sink target `{value}`, expected stdout, stderr or \
+                                 file:<path>"
+                            ),
+                        },
+                    });
+                }
+                _ => bail!("Unknown log sink key `{key}`, expected format or target"),
+            }
+        }
+        Ok(Self {
+            format: format.ok_or_else(|| eyre!("Log sink `{s}` has no format"))?,
+            target: target.ok_or_else(|| eyre!("Log sink `{s}` has no target"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "format=json,target=file:/var/log/app.json"
+                .parse::<LogSink>()
+                .unwrap(),
+            LogSink {
+                format: LogFormat::Json,
+                target: SinkTarget::File("/var/log/app.json".into()),
+            }
+        );
+        assert_eq!(
+            "target=stdout,format=pretty".parse::<LogSink>().unwrap(),
+            LogSink {
+                format: LogFormat::Pretty,
+                target: SinkTarget::Stream(LogStream::Stdout),
+            }
+        );
+        for invalid in [
+            "",
+            "format=json",
+            "target=stderr",
+            "format=xml,target=stderr",
+            "format=json,target=file:",
+            "format=json,target=syslog",
+            "format=json,target=stderr,filter=info",
+        ] {
+            assert!(invalid.parse::<LogSink>().is_err(), "{invalid}");
+        }
+    }
+}
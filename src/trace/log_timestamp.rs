@@ -0,0 +1,280 @@
+//! The timestamp at the start of every log line, `--log-timestamp`.
+//!
+//! Without the flag every format keeps its own: `uptime` for `tiny` and
+//! `pretty-compact`, `rfc3339` in UTC for the others. `none` leaves the
+//! timestamp out, for tools that add their own.
+//!
+//! `rfc3339` is in UTC with a `Z` suffix, or with `--log-local` in the local
+//! time zone with its offset, like `+02:00`.
+//!
+//! While an event is [replayed](super::replay), [`at`] sets the clock of the
+//! formatters to the time it was recorded.
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use clap::ValueEnum;
+use std::{
+    cell::Cell,
+    fmt::{Result, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+thread_local! {
+    /// The time of the replayed event and its `uptime`.
+    static REPLAYED: Cell<Option<(SystemTime, Duration)>> = const { Cell::new(None) };
+}
+
+/// Run `f` with the clock of the formatters at `time`, `uptime` after the
+/// start of the log output.
+#[cfg_attr(not(feature = "binary-log"), allow(dead_code))]
+pub fn at<T>(time: SystemTime, uptime: Duration, f: impl FnOnce() -> T) -> T {
+    let previous = REPLAYED.replace(Some((time, uptime)));
+    let result = f();
+    REPLAYED.set(previous);
+    result
+}
+
+/// The time for log lines, the recorded one while replaying.
+pub fn now() -> SystemTime {
+    REPLAYED
+        .get()
+        .map_or_else(SystemTime::now, |(time, _)| time)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum LogTimestamp {
+    /// Like `2023-04-18T12:00:00.000000Z`, or
+    /// `2023-04-18T14:00:00.000000+02:00` in local time.
+    Rfc3339,
+    /// Seconds since the Unix epoch, like `1681819200.000000`.
+    Unix,
+    /// Milliseconds since the Unix epoch, like `1681819200000`.
+    UnixMs,
+    /// Seconds since the log output started, like `   0.001234`.
+    Uptime,
+    None,
+}
+
+/// Writes the current time in a [`LogTimestamp`] style.
+#[derive(Clone, Copy, Debug)]
+pub struct Timer {
+    style:  LogTimestamp,
+    /// Start of `uptime`.
+    epoch:  Instant,
+    /// Whether `uptime` is padded to line up in a column.
+    padded: bool,
+    /// Whether `rfc3339` is in the local time zone instead of UTC.
+    local:  bool,
+}
+
+impl Timer {
+    pub fn new(style: LogTimestamp) -> Self {
+        Self {
+            style,
+            epoch: Instant::now(),
+            padded: true,
+            local: false,
+        }
+    }
+
+    /// In the local time zone if `local`, otherwise in UTC.
+    pub const fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    /// Without padding, for structured formats.
+    pub const fn unpadded(mut self) -> Self {
+        self.padded = false;
+        self
+    }
+
+    /// Whether nothing is written.
+    pub fn is_none(&self) -> bool {
+        self.style == LogTimestamp::None
+    }
+
+    /// The current time, as written.
+    pub fn now(&self) -> String {
+        let mut now = String::new();
+        let _ = self.write(&mut now);
+        now
+    }
+
+    fn write(&self, w: &mut impl Write) -> Result {
+        let since_epoch = || now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.style {
+            LogTimestamp::Rfc3339 if self.local => {
+                let now =
+                    DateTime::<Local>::from(now()).to_rfc3339_opts(SecondsFormat::Micros, false);
+                w.write_str(&now)
+            }
+            LogTimestamp::Rfc3339 => {
+                let now = DateTime::<Utc>::from(now()).to_rfc3339_opts(SecondsFormat::Micros, true);
+                w.write_str(&now)
+            }
+            LogTimestamp::Unix => {
+                let now = since_epoch();
+                write!(w, "{}.{:06}", now.as_secs(), now.subsec_micros())
+            }
+            LogTimestamp::UnixMs => write!(w, "{}", since_epoch().as_millis()),
+            LogTimestamp::Uptime => {
+                let e = REPLAYED
+                    .get()
+                    .map_or_else(|| self.epoch.elapsed(), |(_, uptime)| uptime);
+                let width = if self.padded { 4 } else { 0 };
+                write!(w, "{:width$}.{:06}", e.as_secs(), e.subsec_micros())
+            }
+            LogTimestamp::None => Ok(()),
+        }
+    }
+}
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> Result {
+        self.write(w)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{global_fields::Fields, test::Capture, LineOptions, LogFormat};
+    use chrono::DateTime;
+    use serde_json::Value;
+    use std::str::FromStr;
+    use tracing::warn;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    /// The timestamp of a line logged in `format` with `timestamp`, and the
+    /// rest of the line.
+    fn render(
+        format: &str,
+        timestamp: Option<LogTimestamp>,
+        local: bool,
+    ) -> (Option<String>, String) {
+        let capture = Capture::default();
+        let layer = LogFormat::from_str(format).unwrap().into_layer(
+            capture.clone(),
+            Fields::default(),
+            &LineOptions {
+                target_width: 4,
+                timestamp,
+                local,
+                ..LineOptions::default()
+            },
+            false,
+        );
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            warn!(target: "app", "plain text");
+        });
+        let output = capture.contents();
+        let line = output.lines().next().unwrap().to_owned();
+        let timestamp = match format {
+            "json" => {
+                let record: Value = serde_json::from_str(&line).unwrap();
+                record["timestamp"].as_str().map(str::to_owned)
+            }
+            "logfmt" => line
+                .strip_prefix("ts=")
+                .map(|rest| rest.split_once(' ').unwrap().0.to_owned()),
+            _ => {
+                let first = line.split_whitespace().next().unwrap();
+                (first.len() > 4 && !first.starts_with("WARN")).then(|| first.to_owned())
+            }
+        };
+        (timestamp, line)
+    }
+
+    fn seconds_since_epoch() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+    }
+
+    #[test]
+    fn test_parse_back() {
+        for format in [
+            "tiny",
+            "compact",
+            "pretty",
+            "pretty-compact",
+            "json",
+            "logfmt",
+        ] {
+            for style in LogTimestamp::value_variants() {
+                let (timestamp, line) = render(format, Some(*style), false);
+                let context = format!("{format} {style:?}: {line}");
+                if *style == LogTimestamp::None {
+                    assert_eq!(timestamp, None, "{context}");
+                    // The level comes first
+                    let first = line.trim_start().starts_with(['W', 'l', '{']);
+                    assert!(first, "{context}");
+                    continue;
+                }
+                let timestamp = timestamp.unwrap_or_else(|| panic!("{context}"));
+                let age = match style {
+                    LogTimestamp::Rfc3339 => {
+                        let time = DateTime::parse_from_rfc3339(&timestamp).unwrap();
+                        assert!(timestamp.ends_with('Z'), "{context}");
+                        Utc::now()
+                            .signed_duration_since(time)
+                            .to_std()
+                            .unwrap()
+                            .as_secs_f64()
+                    }
+                    LogTimestamp::Unix => seconds_since_epoch() - timestamp.parse::<f64>().unwrap(),
+                    LogTimestamp::UnixMs => {
+                        #[allow(clippy::cast_precision_loss)] // Milliseconds fit
+                        let ms = timestamp.parse::<u64>().unwrap() as f64;
+                        seconds_since_epoch() - ms / 1000.0
+                    }
+                    LogTimestamp::Uptime => timestamp.parse::<f64>().unwrap(),
+                    LogTimestamp::None => unreachable!(),
+                };
+                assert!((0.0..60.0).contains(&age), "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        for (format, uptime) in [
+            ("tiny", true),
+            ("compact", false),
+            ("pretty", false),
+            ("pretty-compact", true),
+            ("json", false),
+            ("logfmt", false),
+        ] {
+            let (timestamp, line) = render(format, None, false);
+            let timestamp = timestamp.unwrap_or_else(|| panic!("{format}: {line}"));
+            assert_eq!(timestamp.parse::<f64>().is_ok(), uptime, "{format}: {line}");
+            assert_eq!(
+                DateTime::parse_from_rfc3339(&timestamp).is_ok(),
+                !uptime,
+                "{format}: {line}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_local() {
+        let offset = Local::now().format("%:z").to_string();
+        for format in ["compact", "pretty", "json", "logfmt"] {
+            for (local, suffix) in [(false, "Z"), (true, offset.as_str())] {
+                let (timestamp, line) = render(format, None, local);
+                let context = format!("{format} local={local}: {line}");
+                let timestamp = timestamp.unwrap_or_else(|| panic!("{context}"));
+                assert!(
+                    DateTime::parse_from_rfc3339(&timestamp).is_ok(),
+                    "{context}"
+                );
+                assert!(timestamp.ends_with(suffix), "{context}");
+            }
+        }
+        // Uptime is the same in any time zone
+        let (timestamp, line) = render("tiny", None, true);
+        assert!(timestamp.unwrap().parse::<f64>().is_ok(), "{line}");
+    }
+}
@@ -0,0 +1,392 @@
+//! The `logfmt` log format: one line of `key=value` pairs per event.
+//!
+//! ```text
+//! ts=2023-04-18T12:00:00.000000Z level=info target=app::server msg="listening" port=8080
+//! ```
+//!
+//! Values with spaces, `=`, quotes or control characters are quoted, with
+//! `\"`, `\\`, `\n`, `\r`, `\t` and `\u00XX` escapes inside the quotes. Keys
+//! have these characters replaced by `_`. The fields of the spans in scope
+//! follow the event fields, an event field wins over a span field and an
+//! inner span over an outer one.
+use super::{
+    log_timestamp::{LogTimestamp, Timer},
+    thread_name,
+};
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Result, Write},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::Record,
+    Event, Level, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::{LookupSpan, Scope},
+};
+
+pub struct Logfmt {
+    /// Of the `ts` pair, which is left out for [`LogTimestamp::None`].
+    timer:  Timer,
+    /// Whether there is a `target` pair.
+    target: bool,
+    /// Whether there is a `thread` pair.
+    thread: bool,
+}
+
+impl Default for Logfmt {
+    fn default() -> Self {
+        Self::new(Timer::new(LogTimestamp::Rfc3339))
+    }
+}
+
+impl Logfmt {
+    /// With `timer` for `ts`, which should be [unpadded](Timer::unpadded).
+    pub const fn new(timer: Timer) -> Self {
+        Self {
+            timer,
+            target: true,
+            thread: false,
+        }
+    }
+
+    pub const fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub const fn with_thread(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+}
+
+impl<S> FormatEvent<S, Self> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, Self>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let normalized_meta = event.normalized_metadata();
+        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
+
+        if !self.timer.is_none() {
+            write!(writer, "ts={} ", encode(&self.timer.now()))?;
+        }
+        let level = match *meta.level() {
+            Level::TRACE => "trace",
+            Level::DEBUG => "debug",
+            Level::INFO => "info",
+            Level::WARN => "warn",
+            Level::ERROR => "error",
+        };
+        write!(writer, "level={level}")?;
+        if self.target {
+            write!(writer, " target={}", encode(meta.target()))?;
+        }
+        if self.thread {
+            write!(writer, " thread={}", encode(&thread_name()))?;
+        }
+
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        write!(writer, " msg={}", encode(&visitor.message))?;
+        for (key, value) in &visitor.fields {
+            write!(writer, " {key}={value}")?;
+        }
+
+        // Span fields, the inner span wins
+        let mut span_fields = Vec::<(&str, &str)>::new();
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(Scope::from_root)
+            .collect::<Vec<_>>();
+        let extensions = spans
+            .iter()
+            .map(|span| span.extensions())
+            .collect::<Vec<_>>();
+        for extension in &extensions {
+            if let Some(fields) = extension.get::<FormattedFields<Self>>() {
+                for (key, value) in pairs(&fields.fields) {
+                    span_fields.retain(|(existing, _)| *existing != key);
+                    span_fields.push((key, value));
+                }
+            }
+        }
+        for (key, value) in span_fields {
+            if !visitor.fields.iter().any(|(existing, _)| existing == key) {
+                write!(writer, " {key}={value}")?;
+            }
+        }
+
+        writeln!(writer)
+    }
+}
+
+impl<'writer> FormatFields<'writer> for Logfmt {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> Result {
+        let mut visitor = Visitor::default();
+        fields.record(&mut visitor);
+        visitor.write(&mut writer, true)
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> Result {
+        let empty = current.is_empty();
+        let mut visitor = Visitor::default();
+        fields.record(&mut visitor);
+        visitor.write(&mut current.as_writer(), empty)
+    }
+}
+
+/// Collects the message and the other fields, with encoded values.
+#[derive(Default)]
+pub(super) struct Visitor {
+    pub(super) message: String,
+    pub(super) fields:  Vec<(String, String)>,
+    /// Values are cut after this many characters.
+    width:              Option<usize>,
+}
+
+impl Visitor {
+    /// With values cut after `width` characters, marked with `…`.
+    pub(super) fn with_width(width: Option<usize>) -> Self {
+        Self {
+            width,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, field: &Field, value: &str) {
+        let name = field.name();
+        let key = key(name.strip_prefix("r#").unwrap_or(name));
+        let value = match self.width.and_then(|width| value.char_indices().nth(width)) {
+            Some((end, _)) => Cow::Owned(format!("{}…", &value[..end])),
+            None => Cow::Borrowed(value),
+        };
+        self.fields.push((key, encode(&value).into_owned()));
+    }
+
+    /// Write the pairs of span fields, the message as `msg`.
+    pub(super) fn write(&self, writer: &mut Writer<'_>, mut empty: bool) -> Result {
+        let message = (!self.message.is_empty()).then(|| ("msg", encode(&self.message)));
+        let fields = self
+            .fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), Cow::Borrowed(value.as_str())));
+        for (key, value) in message.into_iter().chain(fields) {
+            let separator = if empty { "" } else { " " };
+            write!(writer, "{separator}{key}={value}")?;
+            empty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Visit for Visitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.push(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let name = field.name();
+        if name == "message" {
+            let _ = write!(self.message, "{value:?}");
+            return;
+        }
+        // Log metadata has already been handled
+        if name.starts_with("log.") {
+            return;
+        }
+        self.push(field, &format!("{value:?}"));
+    }
+}
+
+fn needs_quotes(c: char) -> bool {
+    c <= ' ' || c == '=' || c == '"' || c.is_control()
+}
+
+/// A logfmt key, with the characters that would need quotes replaced by `_`.
+pub(super) fn key(name: &str) -> String {
+    let key = name.replace(needs_quotes, "_");
+    if key.is_empty() {
+        "_".to_owned()
+    } else {
+        key
+    }
+}
+
+/// A logfmt value, quoted and escaped if needed.
+pub(super) fn encode(value: &str) -> Cow<'_, str> {
+    if !value.is_empty() && !value.contains(needs_quotes) {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Split pairs written by [`Logfmt`], the values stay encoded.
+pub(super) fn pairs(line: &str) -> Vec<(&str, &str)> {
+    let mut pairs = Vec::new();
+    let mut rest = line.trim_start();
+    while let Some((key, after)) = rest.split_once('=') {
+        let end = if after.starts_with('"') {
+            let mut escaped = false;
+            after
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| {
+                    let end = !escaped && c == '"';
+                    escaped = !escaped && c == '\\';
+                    end
+                })
+                .map_or(after.len(), |(i, _)| i + 1)
+        } else {
+            after.find(' ').unwrap_or(after.len())
+        };
+        pairs.push((key, &after[..end]));
+        rest = after[end..].trim_start();
+    }
+    pairs
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use tracing::{debug, info, info_span, warn};
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_encode() {
+        for (value, encoded) in [
+            ("plain", "plain"),
+            ("", r#""""#),
+            ("two words", r#""two words""#),
+            ("a=b", r#""a=b""#),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            (r"back\slash", r"back\slash"),
+            (r#"both \ and ""#, r#""both \\ and \"""#),
+            ("line\nbreak", r#""line\nbreak""#),
+            ("tab\there\r", r#""tab\there\r""#),
+            ("\x1b[31mred", r#""\u001b[31mred""#),
+            ("del\x7f", r#""del\u007f""#),
+            ("ünïcödé", "ünïcödé"),
+        ] {
+            assert_eq!(encode(value), encoded, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn test_pairs() {
+        assert_eq!(pairs(""), []);
+        assert_eq!(pairs(r#"a=1 b="x \"y\" = z" c="" d=\ e="\\""#), [
+            ("a", "1"),
+            ("b", r#""x \"y\" = z""#),
+            ("c", r#""""#),
+            ("d", r"\"),
+            ("e", r#""\\""#),
+        ]);
+    }
+
+    fn render(f: impl FnOnce()) -> String {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(capture.clone())
+                .fmt_fields(Logfmt::default())
+                .event_format(Logfmt::new(Timer::new(LogTimestamp::None))),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        capture.contents()
+    }
+
+    #[test]
+    fn test_format() {
+        let output = render(|| {
+            info!(target: "app", "plain");
+            warn!(
+                target: "app::server",
+                port = 8080,
+                host = "example.com",
+                r#type = "a b",
+                note = "say \"hi\"",
+                "with fields"
+            );
+            info_span!("outer", a = 1, b = "x y", c = true).in_scope(|| {
+                info_span!("inner", b = 2).in_scope(|| {
+                    debug!(target: "app", a = 3, "in spans");
+                });
+            });
+            let err = std::io::Error::other("boom\nagain");
+            info!(target: "app", error = &err as &dyn std::error::Error, dbg = ?"q", "");
+        });
+        assert_eq!(
+            output,
+            [
+                "level=info target=app msg=plain\n",
+                "level=warn target=app::server msg=\"with fields\" port=8080 host=example.com \
+                 type=\"a b\" note=\"say \\\"hi\\\"\"\n",
+                "level=debug target=app msg=\"in spans\" a=3 c=true b=2\n",
+                "level=info target=app msg=\"\" error=\"boom\\nagain\" dbg=\"\\\"q\\\"\"\n",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(capture.clone())
+                .fmt_fields(Logfmt::default())
+                .event_format(Logfmt::default()),
+        );
+        tracing::subscriber::with_default(subscriber, || info!(target: "app", "timed"));
+        let output = capture.contents();
+        let pairs = pairs(output.trim_end());
+        assert_eq!(pairs[0].0, "ts");
+        assert!(pairs[0].1.ends_with('Z'), "{output}");
+        assert_eq!(pairs[1..], [
+            ("level", "info"),
+            ("target", "app"),
+            ("msg", "timed")
+        ]);
+    }
+}
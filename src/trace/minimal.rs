@@ -0,0 +1,112 @@
+//! Log output of `minimal` builds: the `tiny` format on stderr, filtered by
+//! `--verbose`, `--quiet` and `--log-filter` or `RUST_LOG` like the full
+//! build.
+#![cfg(feature = "minimal")]
+use super::{
+    default_verbosity, directives,
+    guard::Guard,
+    tiny_log_fmt::TinyLogFmt,
+    verbosity::{verbosity, Verbosity},
+    Levels, LogDefaults, UserLayer, VerbosityMap, RUST_LOG,
+};
+use crate::{output, Version};
+use clap::{ArgAction, Parser};
+use eyre::Result as EyreResult;
+use std::{
+    env,
+    io::{self, IsTerminal},
+    process::id as pid,
+};
+use tracing::info;
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{filter::Targets, fmt, layer::SubscriberExt, Layer, Registry};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    #[clap(flatten)]
+    verbose: Verbosity,
+
+    /// Quiet mode (-q, -qq, -qqq): app logs at warn, error or off. Each -q
+    /// cancels a -v.
+    #[clap(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Apply an `env_filter` compatible log filter, `RUST_LOG` if not given
+    #[allow(clippy::doc_markdown)] // Same help as the full build
+    #[clap(long, env, default_value_t)]
+    log_filter: String,
+
+    /// The levels of `--verbose` and `--quiet` mapped by the app.
+    #[clap(skip)]
+    levels: Option<Levels>,
+
+    /// The log filter built into the app.
+    #[clap(skip)]
+    log_defaults: LogDefaults,
+}
+
+impl Options {
+    /// Map `--verbose` and `--quiet` to levels with `map` instead of
+    /// [`default_verbosity`].
+    pub fn use_verbosity_map(&mut self, map: &VerbosityMap) {
+        self.levels = Some(map(verbosity(self.verbose, self.quiet)));
+    }
+
+    /// Merge the log filter built into the app below the log filter. There
+    /// is only the `tiny` format, the built-in format is ignored.
+    pub const fn use_log_defaults(&mut self, defaults: LogDefaults) {
+        self.log_defaults = defaults;
+    }
+
+    /// Install the log output, with the layers of the app below it.
+    pub fn init(&self, version: &Version, layers: Vec<UserLayer>) -> EyreResult<()> {
+        let rust_log = env::var(RUST_LOG).unwrap_or_default();
+        let (log_filter, flag) = if self.log_filter.trim().is_empty() {
+            (rust_log.as_str(), RUST_LOG)
+        } else {
+            (self.log_filter.as_str(), "log-filter")
+        };
+        let levels = self
+            .levels
+            .unwrap_or_else(|| default_verbosity(verbosity(self.verbose, self.quiet)));
+        let (default, directives) =
+            directives(levels, self.log_defaults, log_filter, flag, version)?;
+        let targets = Targets::new().with_default(default).with_targets(
+            directives
+                .into_iter()
+                .map(|(target, (level, _))| (target, level)),
+        );
+        let color = output::capabilities().color_on(io::stderr().is_terminal());
+
+        // An empty `Vec` layer would disable all callsites.
+        let subscriber = Registry::default()
+            .with((!layers.is_empty()).then(|| {
+                layers
+                    .into_iter()
+                    .map(|(name, layer)| Guard::new(name, layer))
+                    .collect::<Vec<_>>()
+            }))
+            .with(ErrorLayer::default())
+            .with(Guard::new(
+                "log output",
+                fmt::Layer::new()
+                    .with_writer(io::stderr)
+                    .with_ansi(color)
+                    .event_format(TinyLogFmt::default())
+                    .fmt_fields(TinyLogFmt::default())
+                    .with_filter(targets),
+            ));
+        tracing::subscriber::set_global_default(subscriber)?;
+
+        info!(
+            host = version.target,
+            pid = pid(),
+            commit = &version.commit_hash[..8],
+            "{name} {version}",
+            name = version.crate_name,
+            version = version.pkg_version,
+        );
+        Ok(())
+    }
+}
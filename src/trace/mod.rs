@@ -1,88 +1,353 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
+mod auto_debug;
+#[cfg(feature = "binary-log")]
+mod binary_log;
+mod compact;
+mod disabled_cost;
+mod disk_full;
+mod error_fields;
+mod escape_control;
+mod fields_scope;
+mod file_output;
+mod filter_reload;
+mod global_fields;
+mod grpc;
+mod guard;
+mod json_flatten;
+mod late_events;
+mod lazy_export;
+#[cfg(feature = "log-async")]
+mod log_async;
+mod log_control;
+mod log_counters;
+mod log_defaults;
+mod log_file;
+mod log_filter;
+mod log_sink;
+mod log_timestamp;
+mod logfmt;
+mod minimal;
 mod open_telemetry;
 mod otlp_format;
+mod phase_indent;
+mod pretty_compact;
+mod rate_limit;
+mod recent_errors;
+mod redact;
+#[cfg(feature = "binary-log")]
+mod replay;
+mod session_log;
+#[cfg(feature = "shmem-logs")]
+mod shmem_log;
+mod slow_span;
+mod span_cardinality;
 mod span_formatter;
+mod syslog;
+mod test_capture;
 mod tiny_log_fmt;
 mod tokio_console;
+mod trace_url;
+mod verbosity;
+#[cfg(feature = "webhook")]
+mod webhook;
 
-use self::{span_formatter::SpanFormatter, tiny_log_fmt::TinyLogFmt};
-use crate::{default_from_clap, Version};
-use ::clap::ArgAction;
-use clap::Parser;
+use self::{
+    compact::Compact,
+    disabled_cost::DisabledCost,
+    disk_full::{DiskFull, DiskFullPolicy},
+    error_fields::{ErrorFields, ErrorKeys},
+    escape_control::{Escape, EscapeControl},
+    filter_reload::reloadable,
+    global_fields::{Fields, GlobalFields},
+    guard::Guard,
+    json_flatten::JsonFlatten,
+    late_events::LateEvents,
+    log_counters::Counting,
+    log_defaults::{FilterOrigin, FilterSource},
+    log_file::Rotation,
+    log_filter::LogFilter,
+    log_sink::{LogSink, SinkTarget},
+    log_timestamp::{LogTimestamp, Timer},
+    logfmt::Logfmt,
+    phase_indent::PhaseIndent,
+    pretty_compact::PrettyCompact,
+    rate_limit::RateLimited,
+    recent_errors::{RecentErrors, Ring},
+    redact::{Redact, RedactFields},
+    slow_span::{SlowSpanThreshold, SlowSpans},
+    span_cardinality::SpanCardinality,
+    span_formatter::SpanFormatter,
+    syslog::{Facility, Syslog, SyslogFormat},
+    tiny_log_fmt::TinyLogFmt,
+    verbosity::{verbosity, Verbosity},
+};
+use crate::{
+    config_issue::{self, ConfigIssue},
+    default_from_clap, effective_cpus, features, memory_limit, output,
+    verbosity::Elevatable,
+    Version,
+};
+use clap::{parser::ValueSource, ArgAction, Command, Parser, ValueEnum};
 use core::str::FromStr;
 use eyre::{bail, eyre, Error as EyreError, Result as EyreResult, WrapErr as _};
 use once_cell::sync::OnceCell;
 use std::{
-    cmp::max, env, fs::File, io::BufWriter, path::PathBuf, process::id as pid,
-    thread::available_parallelism,
+    borrow::Cow,
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{self, BufWriter, IsTerminal},
+    path::{Path, PathBuf},
+    process::id as pid,
+    sync::Arc,
+    thread::{self, available_parallelism},
 };
-use tracing::{info, Level, Subscriber};
+use tracing::{debug, info, warn, Subscriber};
 use tracing_error::ErrorLayer;
 use tracing_flame::{FlameLayer, FlushGuard};
 use tracing_log::{InterestCacheConfig, LogTracer};
 use tracing_subscriber::{
-    filter::Targets,
-    fmt::{self, format::FmtSpan},
+    filter::{LevelFilter, Targets},
+    fmt::{self, format::FmtSpan, writer::BoxMakeWriter, FormatEvent, FormatFields, MakeWriter},
     layer::SubscriberExt,
+    registry::LookupSpan,
     Layer, Registry,
 };
 use users::{get_current_gid, get_current_uid};
 
+pub use self::{
+    error_fields::ErrorReport,
+    fields_scope::{fields_scope, FieldsScopeLayer, ScopeFields},
+    test_capture::{block_on, test_subscriber, Outcome, TestSubscriber},
+};
+
+#[cfg(feature = "shmem-logs")]
+pub use self::shmem_log::ShmemReader;
+#[cfg(feature = "binary-log")]
+pub use self::{
+    binary_log::{is_binary_log, BinaryLog, BinaryLogReader},
+    replay::{play, Recorder},
+};
+
 #[cfg(feature = "otlp")]
 use otlp_format::OtlpFormatter;
 
+#[cfg(feature = "tonic")]
+pub use self::grpc::{inject_trace, GrpcTraceBody, GrpcTraceLayer, GrpcTraceService};
 #[cfg(feature = "otlp")]
 #[allow(clippy::useless_attribute, clippy::module_name_repetitions)]
-pub use self::open_telemetry::{trace_from_headers, trace_to_headers};
+pub use self::open_telemetry::{
+    current_traceparent, link_to, set_parent, span_with_links, trace_from_headers, trace_to_headers,
+};
+#[cfg(feature = "otlp")]
+pub use self::trace_url::record as record_trace_url;
+
+#[cfg(feature = "minimal")]
+pub use self::minimal::Options as MinimalOptions;
+
+pub use self::{
+    guard::in_layer as in_guarded_layer,
+    late_events::{on_panic as check_shutdown_panic, report as report_late_events},
+    log_counters::{log_counters, log_summary, LogCounters},
+    log_defaults::{embed as embed_log_defaults, LogDefaults},
+    log_file::parse_size,
+    rate_limit::log_report as log_rate_limited,
+    verbosity::{default_verbosity, VerbosityMap},
+};
+
+#[cfg(any(feature = "support-bundle", feature = "prometheus"))]
+pub use self::recent_errors::to_json as recent_errors;
+#[cfg(feature = "support-bundle")]
+pub use self::session_log::{list as session_logs, path as session_log_path};
 
 static FLAME_FLUSH_GUARD: OnceCell<Option<FlushGuard<BufWriter<File>>>> = OnceCell::new();
 
+/// The environment variable read when there is no `--log-filter`.
+const RUST_LOG: &str = "RUST_LOG";
+
+/// Log filter levels by target, with where they come from.
+type Directives = BTreeMap<String, (LevelFilter, FilterSource)>;
+
+/// The level of all targets and the level of the app crates.
+type Levels = (LevelFilter, LevelFilter);
+
+/// A layer added with [`Runner::layer`](crate::Runner::layer), with its name.
+pub type UserLayer = (&'static str, Box<dyn Layer<Registry> + Send + Sync>);
+
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Hash, Eq)]
 enum LogFormat {
     Tiny,
     Compact,
     Pretty,
+    PrettyCompact,
     Json,
+    Logfmt,
     #[cfg(feature = "otlp")]
     Otlp,
 }
 
 impl LogFormat {
-    fn into_layer<S>(self) -> impl Layer<S>
+    /// The timestamp without `--log-timestamp`.
+    const fn default_timestamp(self) -> LogTimestamp {
+        match self {
+            Self::Tiny | Self::PrettyCompact => LogTimestamp::Uptime,
+            _ => LogTimestamp::Rfc3339,
+        }
+    }
+
+    fn into_layer<S, W>(
+        self,
+        writer: W,
+        fields: Fields,
+        line: &LineOptions,
+        ansi: bool,
+    ) -> impl Layer<S>
     where
-        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> + Send + Sync,
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
     {
+        let style = line.timestamp.unwrap_or_else(|| self.default_timestamp());
+        let timer = Timer::new(style).local(line.local);
         let layer = fmt::Layer::new()
-            .with_writer(std::io::stderr)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE);
-        match self {
-            Self::Tiny => Box::new(
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .with_span_events(line.span_events.clone())
+            .with_target(line.target)
+            .with_thread_names(line.thread)
+            .with_thread_ids(line.thread);
+        let module = line.module;
+        match (self, timer.is_none()) {
+            (Self::Tiny, _) => text_layer(
                 layer
-                    .event_format(TinyLogFmt::default())
-                    .fmt_fields(TinyLogFmt::default())
-                    .map_event_format(SpanFormatter::new),
-            ) as Box<dyn Layer<S> + Send + Sync>,
-            Self::Compact => Box::new(layer.compact().map_event_format(SpanFormatter::new)),
-            Self::Pretty => Box::new(layer.pretty().map_event_format(SpanFormatter::new)),
-            Self::Json => Box::new(
+                    .event_format(TinyLogFmt::new(timer).with_thread(line.thread))
+                    .fmt_fields(TinyLogFmt::default()),
+                fields,
+                module,
+            ),
+            (Self::Compact, _) => text_layer(
+                layer
+                    .fmt_fields(Compact::default().with_field_width(line.field_width))
+                    .event_format(
+                        Compact::new(timer)
+                            .with_target(line.target)
+                            .with_thread(line.thread)
+                            .with_field_width(line.field_width),
+                    ),
+                fields,
+                module,
+            ),
+            (Self::Pretty, false) => text_layer(layer.pretty().with_timer(timer), fields, module),
+            (Self::Pretty, true) => text_layer(layer.pretty().without_time(), fields, module),
+            (Self::PrettyCompact, _) => text_layer(
+                layer.event_format(
+                    PrettyCompact::new(line.target_width, timer)
+                        .with_target(line.target)
+                        .with_thread(line.thread),
+                ),
+                fields,
+                module,
+            ),
+            (Self::Json, _) if line.flatten => json_layer(
+                layer.json().event_format(
+                    JsonFlatten::new(timer.unpadded())
+                        .with_target(line.target)
+                        .with_thread(line.thread),
+                ),
+                fields,
+                module,
+                Some(ErrorKeys::Fields),
+            ),
+            (Self::Json, false) => json_layer(
                 layer
                     .json()
                     .with_current_span(true)
                     .with_span_list(false)
-                    .map_event_format(SpanFormatter::new),
+                    .with_timer(timer.unpadded()),
+                fields,
+                module,
+                Some(ErrorKeys::Fields),
             ),
-            #[cfg(feature = "otlp")]
-            Self::Otlp => Box::new(
+            (Self::Json, true) => json_layer(
                 layer
                     .json()
-                    .event_format(OtlpFormatter)
-                    .map_event_format(SpanFormatter::new),
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .without_time(),
+                fields,
+                module,
+                Some(ErrorKeys::Fields),
+            ),
+            (Self::Logfmt, _) => json_layer(
+                layer.fmt_fields(Logfmt::default()).event_format(
+                    Logfmt::new(timer.unpadded())
+                        .with_target(line.target)
+                        .with_thread(line.thread),
+                ),
+                fields,
+                module,
+                None,
+            ),
+            // `code.namespace` is the module
+            #[cfg(feature = "otlp")]
+            (Self::Otlp, _) => json_layer(
+                layer.json().event_format(OtlpFormatter::new(line.thread)),
+                fields,
+                false,
+                Some(ErrorKeys::Exception),
             ),
         }
     }
 }
 
+/// Box a line based log output layer, with the global fields, the module if
+/// `module`, and phase indentation.
+fn text_layer<S, N, E, W>(
+    layer: fmt::Layer<S, N, E, W>,
+    fields: Fields,
+    module: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    N: for<'w> FormatFields<'w> + Send + Sync + 'static,
+    E: FormatEvent<S, N> + Send + Sync + 'static,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    Box::new(
+        layer
+            .map_event_format(|format| GlobalFields::new(format, fields).with_module(module))
+            .map_event_format(SpanFormatter::new)
+            .map_event_format(PhaseIndent::new),
+    )
+}
+
+/// Box a structured log output layer, with the global fields, the module if
+/// `module`, and error fields written as `errors` if it is JSON.
+fn json_layer<S, N, E, W>(
+    layer: fmt::Layer<S, N, E, W>,
+    fields: Fields,
+    module: bool,
+    errors: Option<ErrorKeys>,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    N: for<'w> FormatFields<'w> + Send + Sync + 'static,
+    E: FormatEvent<S, N> + Send + Sync + 'static,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer =
+        layer.map_event_format(|format| GlobalFields::new(format, fields).with_module(module));
+    match errors {
+        // Outside the global fields, which rebuild the event without errors
+        Some(keys) => Box::new(
+            layer
+                .map_event_format(|format| ErrorFields::new(format, keys))
+                .map_event_format(SpanFormatter::new),
+        ),
+        None => Box::new(layer.map_event_format(SpanFormatter::new)),
+    }
+}
+
 impl FromStr for LogFormat {
     type Err = EyreError;
 
@@ -91,7 +356,9 @@ impl FromStr for LogFormat {
             "tiny" => Self::Tiny,
             "compact" => Self::Compact,
             "pretty" => Self::Pretty,
+            "pretty-compact" => Self::PrettyCompact,
             "json" => Self::Json,
+            "logfmt" => Self::Logfmt,
             #[cfg(feature = "otlp")]
             "otlp" => Self::Otlp,
             _ => bail!("Invalid log format: {}", s),
@@ -99,108 +366,521 @@ impl FromStr for LogFormat {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum LogTarget {
+    Stderr,
+    Syslog,
+    #[cfg(feature = "journald")]
+    Journald,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// Span lifecycle events logged as lines, `--log-span-events`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+enum LogSpanEvents {
+    None,
+    New,
+    Close,
+    Enter,
+    Exit,
+    Full,
+}
+
+impl LogSpanEvents {
+    const DEFAULT: [Self; 2] = [Self::New, Self::Close];
+
+    fn fmt_span(events: &[Self]) -> FmtSpan {
+        events.iter().fold(FmtSpan::NONE, |span, event| {
+            span | match event {
+                Self::None => FmtSpan::NONE,
+                Self::New => FmtSpan::NEW,
+                Self::Close => FmtSpan::CLOSE,
+                Self::Enter => FmtSpan::ENTER,
+                Self::Exit => FmtSpan::EXIT,
+                Self::Full => FmtSpan::FULL,
+            }
+        })
+    }
+}
+
+/// How log lines are written, from the `--log-*` options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // Independent flags
+struct LineOptions {
+    /// Of the target column of `pretty-compact`.
+    target_width: usize,
+    /// Of field values in `compact`, `None` for no limit.
+    field_width:  Option<usize>,
+    /// `None` for the default of the format.
+    timestamp:    Option<LogTimestamp>,
+    local:        bool,
+    span_events:  FmtSpan,
+    thread:       bool,
+    target:       bool,
+    module:       bool,
+    /// Span fields as top-level keys of `json`.
+    flatten:      bool,
+}
+
+impl Default for LineOptions {
+    fn default() -> Self {
+        Self {
+            target_width: 0,
+            field_width:  None,
+            timestamp:    None,
+            local:        false,
+            span_events:  LogSpanEvents::fmt_span(&LogSpanEvents::DEFAULT),
+            thread:       false,
+            target:       true,
+            module:       false,
+            flatten:      false,
+        }
+    }
+}
+
+/// The name of the current thread, or its id if it has none.
+fn thread_name() -> String {
+    let thread = thread::current();
+    thread
+        .name()
+        .map_or_else(|| format!("{:?}", thread.id()), str::to_owned)
+}
+
+impl LogStream {
+    fn is_terminal(self) -> bool {
+        match self {
+            Self::Stdout => io::stdout().is_terminal(),
+            Self::Stderr => io::stderr().is_terminal(),
+        }
+    }
+
+    fn writer(self) -> BoxMakeWriter {
+        match self {
+            Self::Stdout => BoxMakeWriter::new(io::stdout),
+            Self::Stderr => BoxMakeWriter::new(io::stderr),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
 #[group(skip)]
+#[allow(clippy::struct_excessive_bools)] // Independent flags
 pub struct Options {
-    /// Verbose mode (-v, -vv, -vvv, etc.)
-    #[clap(short, long, env, action = ArgAction::Count)]
-    verbose: u8,
+    #[clap(flatten)]
+    verbose: Verbosity,
 
-    /// Apply an env_filter compatible log filter
+    /// Quiet mode (-q, -qq, -qqq): app logs at warn, error or off. Each -q
+    /// cancels a -v.
+    #[clap(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Apply an `env_filter` compatible log filter, `RUST_LOG` if not given
     #[clap(long, env, default_value_t)]
     log_filter: String,
 
-    /// Log format, one of 'tiny', 'compact', 'pretty', 'json', or 'otlp' (if
-    /// enabled)
+    /// Where the log filter was read from, see [`Self::use_rust_log`].
+    #[clap(skip)]
+    log_filter_origin: FilterOrigin,
+
+    /// The levels of `--verbose` and `--quiet` mapped by the app, see
+    /// [`Self::use_verbosity_map`].
+    #[clap(skip)]
+    levels: Option<Levels>,
+
+    /// The log defaults built into the app, see [`Self::use_log_defaults`].
+    #[clap(skip)]
+    log_defaults: LogDefaults,
+
+    /// Print the log filter, with where each directive comes from, and exit.
+    #[clap(long)]
+    print_log_filter: bool,
+
+    /// Log format, one of 'tiny', 'compact', 'pretty', 'pretty-compact',
+    /// 'json', 'logfmt', or 'otlp' (if enabled)
     #[clap(long, env, default_value = "tiny")]
     log_format: LogFormat,
 
+    /// Timestamp of log lines: 'rfc3339', 'unix', 'unix-ms', 'uptime'
+    /// or 'none'. By default 'uptime' for the 'tiny' and 'pretty-compact'
+    /// formats and 'rfc3339' for the others.
+    #[clap(long, env, value_enum)]
+    log_timestamp: Option<LogTimestamp>,
+
+    /// Write 'rfc3339' timestamps in UTC, with a 'Z' suffix. The default.
+    #[clap(long, env)]
+    log_utc: bool,
+
+    /// Write 'rfc3339' timestamps in the local time zone, with its offset
+    /// like '+02:00'.
+    #[clap(long, env, conflicts_with = "log_utc")]
+    log_local: bool,
+
+    /// Span events logged as lines: 'none', 'new', 'close', 'enter', 'exit'
+    /// or 'full', separated by commas.
+    #[clap(
+        long,
+        env,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = LogSpanEvents::DEFAULT
+    )]
+    log_span_events: Vec<LogSpanEvents>,
+
+    /// Show the name, or the id, of the thread in log lines.
+    #[clap(long, env)]
+    log_show_thread: bool,
+
+    /// Show the target in log lines, not shown by the 'tiny' format.
+    #[clap(long, env, default_value_t = true, action = ArgAction::Set)]
+    log_show_target: bool,
+
+    /// Show the module path in log lines, as a 'module' field.
+    #[clap(long, env)]
+    log_show_module: bool,
+
+    /// With the 'json' format, write the event fields and the fields of the
+    /// spans in scope as top-level keys, instead of nested under 'fields' and
+    /// 'span'.
+    #[clap(long, env)]
+    log_json_flatten: bool,
+
+    /// Write the log output to 'stdout' or 'stderr'.
+    #[clap(long, env, value_enum, default_value_t = LogStream::Stderr)]
+    log_stream: LogStream,
+
+    /// Append the log output to this file instead of writing it to the log
+    /// stream.
+    #[clap(long, env)]
+    log_file: Option<PathBuf>,
+
+    /// Write the log output to 'stderr' or send it to the local 'syslog'
+    /// daemon or 'journald' (if enabled), falling back to stderr if there is
+    /// none.
+    #[clap(
+        long,
+        env,
+        value_enum,
+        default_value_t = LogTarget::Stderr,
+        conflicts_with = "log_file"
+    )]
+    log_target: LogTarget,
+
+    /// Syslog facility of `--log-target syslog`, like 'daemon', 'user' or
+    /// 'local0'.
+    #[clap(long, env, value_enum, default_value_t = Facility::Daemon)]
+    syslog_facility: Facility,
+
+    /// Also write the log output to a sink in its own format, like
+    /// 'format=json,target=file:/var/log/app.json'. The target is 'stdout',
+    /// 'stderr' or 'file:<path>'. Repeatable, or separated by ';'.
+    #[clap(long, env, value_delimiter = ';')]
+    log_sink: Vec<LogSink>,
+
+    /// Show at most this many log lines per second from each callsite, with
+    /// a summary of the suppressed ones with the heartbeat. The log sinks and
+    /// the OpenTelemetry export still get every event.
+    #[clap(long, env)]
+    log_rate_limit: Option<u32>,
+
+    #[cfg(feature = "log-async")]
+    #[clap(flatten)]
+    log_async: log_async::Options,
+
+    /// What the log file and session log do when the disk is full: 'drop'
+    /// the event, 'block' the logging thread for up to a second, or
+    /// 'fallback-stderr'.
+    #[clap(long, env, value_enum, default_value_t = DiskFullPolicy::Drop)]
+    log_disk_full_policy: DiskFullPolicy,
+
+    /// Rotate the log file 'daily', 'hourly' (in UTC) or at a size, like
+    /// 'size:100MB'.
+    #[clap(long, env)]
+    log_rotate: Option<Rotation>,
+
+    /// Number of rotated log files to keep.
+    #[clap(long, env, default_value_t = 10)]
+    log_keep: usize,
+
+    #[cfg(feature = "log-compress")]
+    #[clap(flatten)]
+    log_file_compress: file_output::Options,
+
+    /// Width of the target column of the 'pretty-compact' log format.
+    #[clap(long, env, default_value_t = 24)]
+    log_target_width: usize,
+
+    /// Cut field values of the 'compact' log format after this many
+    /// characters, marked with '…'.
+    #[clap(long, env)]
+    log_field_width: Option<usize>,
+
+    /// Escape control characters and invalid UTF-8 in log output, so logged
+    /// data can not control the terminal.
+    #[clap(long, env, value_enum, default_value_t = EscapeControl::TtyOnly)]
+    log_escape_control: EscapeControl,
+
+    /// Replace the values of fields with these names with "[REDACTED]" in all
+    /// output, like 'password,authorization,*token'. Ignores case, '*' and '?'
+    /// are wildcards.
+    #[clap(long, env, value_delimiter = ',')]
+    log_redact_fields: Vec<String>,
+
+    /// Add a field to every log event and the OpenTelemetry resource of this
+    /// run, e.g. `--tag ticket=ABC-123 --tag attempt=2`.
+    #[clap(long, value_parser = global_fields::parse_tag)]
+    tag: Vec<(String, String)>,
+
     /// Store traces in a flame graph file for processing with inferno.
     #[clap(long, env)]
     trace_flame: Option<PathBuf>,
 
+    #[clap(flatten)]
+    session_log: session_log::Options,
+
+    /// Number of recent warnings and errors kept in memory for
+    /// `/recent-errors`, 0 to disable.
+    #[clap(long, env, default_value_t = 256)]
+    recent_errors_size: usize,
+
+    #[clap(flatten)]
+    log_counters: log_counters::Options,
+
+    /// Measure the cost of recording events the log filter rejects and report
+    /// the most expensive callsites at shutdown. Slows down the program.
+    #[clap(long, env)]
+    warn_expensive_disabled_logging: bool,
+
+    /// Warn once when a span callsite produces more distinct values of an
+    /// indexed field than the limit, like ids in `otel.name`. Always on in
+    /// debug builds.
+    #[clap(long, env)]
+    warn_span_cardinality: bool,
+
+    /// Number of distinct values per span callsite and indexed field before
+    /// `--warn-span-cardinality` warns.
+    #[clap(long, env, default_value_t = 100)]
+    span_cardinality_limit: usize,
+
+    /// Span field that trace backends index, checked by
+    /// `--warn-span-cardinality`.
+    #[clap(long, default_value = "otel.name")]
+    span_cardinality_field: Vec<String>,
+
+    /// Warn when a span takes longer than this, like '500ms', or than the
+    /// threshold for its target, like 'db=100ms'. Repeatable.
+    #[clap(long, env, value_delimiter = ',')]
+    slow_span_threshold: Vec<SlowSpanThreshold>,
+
+    /// Report events logged after shutdown began, which the log outputs may
+    /// lose, and name panics in destructors while unwinding during shutdown.
+    /// Always on in debug builds.
+    #[clap(long, env)]
+    debug_shutdown: bool,
+
+    #[clap(flatten)]
+    auto_debug: auto_debug::Options,
+
     #[cfg(feature = "tokio-console")]
     #[clap(flatten)]
     pub tokio_console: tokio_console::Options,
 
+    #[cfg(feature = "binary-log")]
+    #[clap(flatten)]
+    binary_log: binary_log::Options,
+
+    #[cfg(feature = "binary-log")]
+    #[clap(flatten)]
+    replay: replay::Options,
+
+    #[cfg(feature = "shmem-logs")]
+    #[clap(flatten)]
+    shmem_log: shmem_log::Options,
+
+    #[cfg(feature = "webhook")]
+    #[clap(flatten)]
+    webhook: webhook::Options,
+
+    #[cfg(all(unix, feature = "signals"))]
+    #[clap(flatten)]
+    filter_reload: filter_reload::Options,
+
+    #[cfg(unix)]
+    #[clap(flatten)]
+    log_control: log_control::Options,
+
     #[cfg(feature = "otlp")]
     #[clap(flatten)]
     open_telemetry: open_telemetry::Options,
+
+    #[cfg(feature = "otlp")]
+    #[clap(flatten)]
+    trace_url: trace_url::Options,
 }
 
 default_from_clap!(Options);
 
 impl Options {
+    /// The settings of log lines, for the log output and the sinks.
+    fn line_options(&self) -> LineOptions {
+        LineOptions {
+            target_width: self.log_target_width,
+            field_width:  self.log_field_width,
+            timestamp:    self.log_timestamp,
+            local:        self.log_local,
+            span_events:  LogSpanEvents::fmt_span(&self.log_span_events),
+            thread:       self.log_show_thread,
+            target:       self.log_show_target,
+            module:       self.log_show_module,
+            flatten:      self.log_json_flatten,
+        }
+    }
+
     #[allow(clippy::borrow_as_ptr)] // ptr::addr_of! does not work here.
-    pub fn init(&self, version: &Version, load_addr: usize) -> EyreResult<()> {
-        // Hack: ENV parsing for a `action = ArgAction::Count` argument
-        // is not supported. So we have to do it manually.
-        let verbose = env::var("VERBOSE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .map_or(self.verbose, |e| max(e, self.verbose));
-
-        // Log filtering is a combination of `--log-filter` and `--verbose` arguments.
-        let verbosity = {
-            let (all, app) = match verbose {
-                0 => (Level::ERROR, Level::INFO),
-                1 => (Level::INFO, Level::INFO),
-                2 => (Level::INFO, Level::DEBUG),
-                3 => (Level::INFO, Level::TRACE),
-                4 => (Level::DEBUG, Level::TRACE),
-                _ => (Level::TRACE, Level::TRACE),
-            };
-            Targets::new()
-                .with_default(all)
-                .with_targets(version.app_crates.iter().map(|c| (c, app)))
+    #[allow(clippy::too_many_lines)] // Linear sequence of layers
+    pub fn init(
+        &self,
+        version: &Version,
+        load_addr: usize,
+        layers: Vec<UserLayer>,
+    ) -> EyreResult<()> {
+        let (options, syslog, target_error) = self.connect_log_target();
+        let writer = options.writer(syslog)?;
+        let (subscriber, flame_guard) = options.subscriber(version, writer, layers)?;
+        FLAME_FLUSH_GUARD
+            .set(flame_guard)
+            .map_err(|_| eyre!("flame flush guard already initialized"))?;
+
+        // Full log of this run in a file
+        let session_log = self.session_log.open(version, self.compress())?;
+        let subscriber = subscriber.with(session_log.map(|session_log| {
+            Guard::new(
+                "session log",
+                session_log.into_layer(
+                    version,
+                    global_fields::fields(&self.tag),
+                    self.log_disk_full_policy,
+                ),
+            )
+        }));
+
+        // Compact binary log file, with the filter of the log output
+        #[cfg(feature = "binary-log")]
+        let subscriber = {
+            let binary_log = self.binary_log.open(global_fields::fields(&self.tag))?;
+            let targets = Elevatable::new(reloadable(self.filter(version)?));
+            subscriber.with(
+                binary_log
+                    .map(|binary_log| Guard::new("binary log", binary_log.with_filter(targets))),
+            )
         };
-        let log_filter = if self.log_filter.is_empty() {
-            Targets::new()
-        } else {
-            self.log_filter
-                .parse()
-                .wrap_err("Error parsing log-filter")?
+
+        // Spans and events for `--replay`, with the filter of the log output
+        #[cfg(feature = "binary-log")]
+        let subscriber = {
+            let recorder = self.replay.open()?;
+            let targets = Elevatable::new(reloadable(self.filter(version)?));
+            subscriber.with(
+                recorder
+                    .map(|recorder| Guard::new("event recording", recorder.with_filter(targets))),
+            )
         };
-        let targets = verbosity.with_targets(log_filter);
 
-        // Tracing stack
-        let subscriber = Registry::default();
+        // Shared memory ring for sidecars, with the filter of the log output
+        #[cfg(feature = "shmem-logs")]
+        let subscriber = {
+            let shmem_log = self
+                .shmem_log
+                .open(version.crate_name, global_fields::fields(&self.tag))?;
+            let targets = Elevatable::new(reloadable(self.filter(version)?));
+            subscriber.with(
+                shmem_log.map(|shmem_log| Guard::new("log ring", shmem_log.with_filter(targets))),
+            )
+        };
 
-        // OpenTelemetry layer
-        #[cfg(feature = "otlp")]
-        let subscriber = subscriber.with(
-            self.open_telemetry
-                .to_layer(version)?
-                .with_filter(targets.clone()),
-        );
+        // Recent warnings and errors, regardless of the log filter
+        let ring =
+            (self.recent_errors_size > 0).then(|| Arc::new(Ring::new(self.recent_errors_size)));
+        let subscriber = subscriber.with(ring.clone().map(|ring| {
+            Guard::new(
+                "recent errors",
+                RecentErrors(ring).with_filter(LevelFilter::WARN),
+            )
+        }));
 
-        // Optional trace flame layer
-        let (flame, guard) = match self
-            .trace_flame
-            .as_ref()
-            .map(FlameLayer::with_file)
-            .transpose()?
-        {
-            Some((flame, guard)) => (Some(flame), Some(guard)),
-            None => (None, None),
-        };
-        let subscriber = subscriber.with(flame);
-        FLAME_FLUSH_GUARD
-            .set(guard)
-            .map_err(|_| eyre!("flame flush guard already initialized"))?;
+        // Events per level, with the filter of the log output
+        let targets = Elevatable::new(reloadable(self.filter(version)?));
+        let subscriber = subscriber.with(Guard::new(
+            "log counters",
+            Counting::global().with_filter(targets),
+        ));
 
-        // Tokio Console layer
-        #[cfg(feature = "tokio-console")]
-        let subscriber = subscriber.with(self.tokio_console.into_layer());
+        // Errors posted to a webhook, regardless of the log filter
+        #[cfg(feature = "webhook")]
+        let subscriber =
+            subscriber.with(self.webhook.start(version)?.map(|webhook| {
+                Guard::new("error webhook", webhook.with_filter(LevelFilter::ERROR))
+            }));
 
-        // Include span traces in errors
-        let subscriber = subscriber.with(ErrorLayer::default());
+        // Cost of events rejected by the log filter, which has to see all
+        let disabled_cost = self
+            .warn_expensive_disabled_logging
+            .then(|| self.filter(version))
+            .transpose()?;
+        let subscriber = subscriber.with(disabled_cost.map(|filter| {
+            Guard::new(
+                "disabled logging cost",
+                DisabledCost::new(filter.targets().clone()),
+            )
+        }));
 
-        // Log output
-        let subscriber = subscriber.with(self.log_format.into_layer().with_filter(targets));
+        // Unbounded values of indexed span fields
+        let span_cardinality = (self.warn_span_cardinality || cfg!(debug_assertions))
+            .then(|| self.filter(version))
+            .transpose()?;
+        let subscriber = subscriber.with(span_cardinality.map(|targets| {
+            Guard::new(
+                "span cardinality",
+                SpanCardinality::new(
+                    self.span_cardinality_field.clone(),
+                    self.span_cardinality_limit,
+                )
+                .with_filter(targets),
+            )
+        }));
 
-        // Install
-        tracing::subscriber::set_global_default(subscriber)?;
+        // Spans taking longer than their threshold
+        let slow_spans = (!self.slow_span_threshold.is_empty())
+            .then(|| self.filter(version))
+            .transpose()?
+            .map(|targets| SlowSpans::new(&self.slow_span_threshold).with_filter(targets));
+        let subscriber = subscriber.with(slow_spans.map(|layer| Guard::new("slow spans", layer)));
+
+        // Events after shutdown began, unfiltered to see those of the outputs
+        let late_events = (self.debug_shutdown || cfg!(debug_assertions)).then(LateEvents::new);
+        let subscriber = subscriber.with(late_events.map(|layer| Guard::new("late events", layer)));
+
+        // Fields of `fields_scope` on new spans, after the layers storing span
+        // data
+        let redact = RedactFields::new(&self.log_redact_fields);
+        let subscriber = subscriber.with(Guard::new(
+            "fields scope",
+            FieldsScopeLayer::redacting(redact.clone()),
+        ));
+
+        // Install, with `--log-redact-fields` applied before any layer
+        tracing::subscriber::set_global_default(Redact::new(subscriber, redact))?;
+        if let Some(ring) = ring {
+            recent_errors::publish(ring);
+            #[cfg(all(unix, feature = "signals", not(feature = "prometheus")))]
+            recent_errors::dump_on_signal()?;
+        }
+        #[cfg(all(unix, feature = "signals"))]
+        self.filter_reload.init(self.filter_options(version))?;
 
         // Route `log` crate events to `tracing`
         LogTracer::builder()
@@ -214,18 +894,673 @@ impl Options {
             uid = get_current_uid(),
             gid = get_current_gid(),
             cores = available_parallelism()?,
+            effective_cores = effective_cpus(),
+            memory_limit = memory_limit(),
             main = load_addr,
             commit = &version.commit_hash[..8],
+            features = ?features(),
             "{name} {version}",
             name = version.crate_name,
             version = version.pkg_version,
         );
+        debug!(
+            filter = %self.log_filter,
+            source = %self.log_filter_origin,
+            "Log filter"
+        );
+        if let Some(err) = target_error {
+            warn!(
+                %err,
+                log_target = ?self.log_target,
+                "Could not connect to the log target, logging to stderr"
+            );
+        }
+
+        #[cfg(feature = "otlp")]
+        self.trace_url.init();
 
         Ok(())
     }
+
+    /// Connect to the syslog daemon or journald for `--log-target`. Without
+    /// one, the log output stays on stderr: the returned options say so, with
+    /// the error to log once logging works.
+    fn connect_log_target(&self) -> (Cow<'_, Self>, Option<Syslog>, Option<io::Error>) {
+        let connected = match self.log_target {
+            LogTarget::Stderr => return (Cow::Borrowed(self), None, None),
+            LogTarget::Syslog => Syslog::connect(Path::new(syslog::SOCKET)).map(Some),
+            // Only checked, the subscriber connects its own layer.
+            #[cfg(feature = "journald")]
+            LogTarget::Journald => tracing_journald::layer().map(|_| None),
+        };
+        match connected {
+            Ok(syslog) => (Cow::Borrowed(self), syslog, None),
+            Err(err) => {
+                let options = Self {
+                    log_target: LogTarget::Stderr,
+                    ..self.clone()
+                };
+                (Cow::Owned(options), None, Some(err))
+            }
+        }
+    }
+
+    /// Whether the log files are compressed, with `--log-file-compress`.
+    #[cfg(feature = "log-compress")]
+    const fn compress(&self) -> bool {
+        self.log_file_compress.requested()
+    }
+
+    /// Log files are not compressed without the `log-compress` feature.
+    #[cfg(not(feature = "log-compress"))]
+    #[allow(clippy::unused_self)] // Same signature as with the feature
+    const fn compress(&self) -> bool {
+        false
+    }
+
+    /// Where the log output goes: the connected `syslog`, the `--log-file` or
+    /// the `--log-stream`, with `--log-async` through a queue.
+    fn writer(&self, syslog: Option<Syslog>) -> EyreResult<BoxMakeWriter> {
+        let writer = match (syslog, &self.log_file) {
+            (Some(syslog), _) => BoxMakeWriter::new(syslog),
+            (None, Some(path)) => BoxMakeWriter::new(Arc::new(DiskFull::new(
+                "log file",
+                log_file::open(path, self.log_rotate, self.log_keep, self.compress())?,
+                self.log_disk_full_policy,
+            ))),
+            (None, None) => self.log_stream.writer(),
+        };
+        #[cfg(feature = "log-async")]
+        let writer = self.log_async.wrap(writer)?;
+        Ok(writer)
+    }
+
+    /// Build the tracing stack, with log output going to `writer`.
+    ///
+    /// Layers are stacked on the [`Registry`] in a fixed order, which is part
+    /// of the stable behaviour of this crate:
+    ///
+    /// 0. Layers added with [`Runner::layer`](crate::Runner::layer).
+    /// 1. [`ErrorLayer`] capturing span traces for errors.
+    /// 2. Flame graph layer (`--trace-flame`).
+    /// 3. Tokio console layer (`--tokio-console`).
+    /// 4. OpenTelemetry layer (`--trace-otlp`).
+    /// 5. Log output.
+    /// 6. Log sinks (`--log-sink`).
+    ///
+    /// [`Options::init`] adds the session log file (`--session-log`), the
+    /// binary log file (`--binary-log`), the event recording
+    /// (`--record-events`), the shared memory ring
+    /// (`--log-shmem`), the recent errors ring
+    /// (`--recent-errors-size`), the unfiltered
+    /// `--warn-expensive-disabled-logging` measurement, the
+    /// `--warn-span-cardinality` check, the `--slow-span-threshold`
+    /// measurement, the `--debug-shutdown` check and the [`fields_scope`]
+    /// fields on top, and wraps the stack in [`Redact`] for
+    /// `--log-redact-fields`.
+    ///
+    /// The registry has no global filter. Every output layer gets its own
+    /// [`Filter`](tracing_subscriber::layer::Filter) instance, so adding or
+    /// removing a layer never changes which events the other layers receive.
+    /// The error layer is deliberately left unfiltered so span traces are
+    /// complete. The filters of the log output, OpenTelemetry, binary log and
+    /// shared memory ring also pass what
+    /// [`elevated`](crate::verbosity::elevated) asks for, and are replaced
+    /// when `SIGHUP` reloads the log filter. Between its filter and the log
+    /// output sits the `--log-rate-limit` counter.
+    ///
+    /// All layers but the error layer are wrapped in a [`Guard`], so a panic
+    /// disables the layer instead of unwinding into the code that logged.
+    fn subscriber<W>(
+        &self,
+        version: &Version,
+        writer: W,
+        layers: Vec<UserLayer>,
+    ) -> EyreResult<(
+        impl Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+        Option<FlushGuard<BufWriter<File>>>,
+    )>
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let targets = self.filter(version)?;
+        let fields = global_fields::fields(&self.tag);
+
+        // Tracing stack
+        let subscriber = Registry::default();
+
+        // Layers of the application. An empty `Vec` layer would disable all
+        // callsites.
+        let subscriber = subscriber.with((!layers.is_empty()).then(|| {
+            layers
+                .into_iter()
+                .map(|(name, layer)| Guard::new(name, layer))
+                .collect::<Vec<_>>()
+        }));
+
+        // Include span traces in errors
+        let subscriber = subscriber.with(ErrorLayer::default());
+
+        // Optional trace flame layer
+        let (flame, guard) = match self
+            .trace_flame
+            .as_ref()
+            .map(FlameLayer::with_file)
+            .transpose()?
+        {
+            Some((flame, guard)) => (Some(flame), Some(guard)),
+            None => (None, None),
+        };
+        let subscriber = subscriber.with(Guard::new("flame", flame.with_filter(targets.clone())));
+
+        // Tokio Console layer
+        #[cfg(feature = "tokio-console")]
+        let subscriber = subscriber.with(Guard::new(
+            "tokio-console",
+            self.tokio_console
+                .into_layer()
+                .with_filter(tokio_console::targets()),
+        ));
+
+        // OpenTelemetry layer
+        #[cfg(feature = "otlp")]
+        let subscriber = subscriber.with(Guard::new(
+            "OpenTelemetry",
+            self.open_telemetry
+                .to_layer(version, &fields)?
+                .with_filter(Elevatable::new(self.otlp_filter(version)?)),
+        ));
+
+        // Log output, without colors in a log file, syslog or journald
+        let output = output::capabilities();
+        let color = output.color_on(self.log_stream.is_terminal()) && self.log_file.is_none();
+        let escape = self
+            .log_escape_control
+            .enabled(self.log_stream.is_terminal());
+        let writer = Escape::new(writer, escape).with_ascii_only(!output.unicode);
+        let line = self.line_options();
+        let log_output = match self.log_target {
+            LogTarget::Stderr => {
+                Box::new(
+                    self.log_format
+                        .into_layer(writer, fields.clone(), &line, color),
+                ) as Box<dyn Layer<_> + Send + Sync>
+            }
+            LogTarget::Syslog => Box::new(
+                fmt::Layer::new()
+                    .with_writer(writer)
+                    .with_ansi(false)
+                    .event_format(SyslogFormat::new(self.syslog_facility, version.crate_name))
+                    .map_event_format(|format| GlobalFields::new(format, fields.clone())),
+            ),
+            // Fields are sent as `F_<NAME>`, so they can not overwrite the
+            // journal's own like `PRIORITY`.
+            #[cfg(feature = "journald")]
+            LogTarget::Journald => Box::new(
+                tracing_journald::layer()
+                    .wrap_err("Error connecting to journald")?
+                    .with_syslog_identifier(version.crate_name.to_owned()),
+            ),
+        };
+        let subscriber = subscriber.with(Guard::new(
+            "log output",
+            RateLimited::new(self.log_rate_limit, log_output)
+                .with_filter(Elevatable::new(reloadable(targets.clone()))),
+        ));
+
+        // Log sinks, each with its own filter. An empty `Vec` layer would
+        // disable all callsites.
+        let sinks = self
+            .log_sink
+            .iter()
+            .map(|sink| {
+                let writer = sink.writer(
+                    self.log_rotate,
+                    self.log_keep,
+                    self.compress(),
+                    self.log_disk_full_policy,
+                )?;
+                let escape = self.log_escape_control.enabled(sink.is_terminal());
+                let writer = Escape::new(writer, escape).with_ascii_only(!output.unicode);
+                let color = output.color_on(sink.is_terminal()) && !sink.is_file();
+                let layer = sink.format.into_layer(writer, fields.clone(), &line, color);
+                Ok(Guard::new(
+                    "log sink",
+                    layer.with_filter(Elevatable::new(reloadable(targets.clone()))),
+                ))
+            })
+            .collect::<EyreResult<Vec<_>>>()?;
+        let subscriber = subscriber.with((!sinks.is_empty()).then_some(sinks));
+
+        Ok((subscriber, guard))
+    }
+
+    /// The directory of the session logs, also of earlier runs. `None` if
+    /// they are off.
+    #[cfg(feature = "support-bundle")]
+    pub fn session_log_dir(&self, version: &Version) -> EyreResult<Option<PathBuf>> {
+        self.session_log.dir(version)
+    }
+
+    /// Add a field to every log event, like `--tag`.
+    pub fn add_tag(&mut self, key: &str, value: String) {
+        self.tag.push((key.to_owned(), value));
+    }
+
+    /// Take the `--trace-flame` file out of the options, so this run leaves
+    /// the one of an earlier run alone.
+    #[cfg(feature = "support-bundle")]
+    pub fn take_profiles(&mut self) -> Vec<PathBuf> {
+        self.trace_flame.take().into_iter().collect()
+    }
+
+    /// Where a daemon's stdout and stderr go: `daemon.out` next to the session
+    /// logs, which are on for a daemon unless disabled.
+    #[cfg(all(unix, feature = "daemonize"))]
+    pub fn daemon_output(&mut self, version: &Version) -> EyreResult<Option<PathBuf>> {
+        Ok(self
+            .session_log
+            .daemon_dir(version)?
+            .map(|dir| dir.join("daemon.out")))
+    }
+
+    /// Fall back to `RUST_LOG` when neither `--log-filter` nor `LOG_FILTER`
+    /// set a log filter, with `source` where clap found `--log-filter`.
+    pub fn use_rust_log(&mut self, source: Option<ValueSource>) {
+        self.resolve_log_filter(source, env::var(RUST_LOG).ok());
+    }
+
+    fn resolve_log_filter(&mut self, source: Option<ValueSource>, rust_log: Option<String>) {
+        self.log_filter_origin = if !self.log_filter.trim().is_empty() {
+            if source == Some(ValueSource::EnvVariable) {
+                FilterOrigin::LogFilter
+            } else {
+                FilterOrigin::Flag
+            }
+        } else if let Some(rust_log) = rust_log.filter(|rust_log| !rust_log.trim().is_empty()) {
+            self.log_filter = rust_log;
+            FilterOrigin::RustLog
+        } else {
+            FilterOrigin::None
+        };
+    }
+
+    /// The name the log filter is reported by in errors.
+    const fn log_filter_flag(&self) -> &'static str {
+        match self.log_filter_origin {
+            FilterOrigin::RustLog => RUST_LOG,
+            _ => "log-filter",
+        }
+    }
+
+    /// Map `--verbose` and `--quiet` to levels with `map` instead of
+    /// [`default_verbosity`].
+    pub fn use_verbosity_map(&mut self, map: &VerbosityMap) {
+        self.levels = Some(map(verbosity(self.verbose, self.quiet)));
+    }
+
+    /// The levels of `--verbose` and `--quiet`.
+    fn levels(&self) -> Levels {
+        self.levels
+            .unwrap_or_else(|| default_verbosity(verbosity(self.verbose, self.quiet)))
+    }
+
+    /// Merge the log defaults built into the app below the log filter, and
+    /// use their log format, if any, when `format_source` shows there is no
+    /// `--log-format`.
+    pub fn use_log_defaults(
+        &mut self,
+        defaults: LogDefaults,
+        format_source: Option<ValueSource>,
+    ) -> EyreResult<()> {
+        self.log_defaults = defaults;
+        if format_source == Some(ValueSource::DefaultValue) {
+            if let Some(format) = defaults.format()? {
+                self.log_format = format;
+            }
+        }
+        Ok(())
+    }
+
+    /// The built-in values of the flags that have one, by argument id, for
+    /// `--print-config`. They apply where the flag is left at its default.
+    #[must_use]
+    pub fn built_in(&self) -> Vec<(&'static str, String)> {
+        let defaults = [
+            ("log_filter", self.log_defaults.filter),
+            ("log_format", self.log_defaults.format),
+        ];
+        defaults
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(id, value)| (id, value.to_owned()))
+            .collect()
+    }
+
+    /// Every problem with the tracing options, see
+    /// [`config_issue`](crate::config_issue).
+    pub fn validate(&self, version: &Version) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if let Err(err) = self.filter_directives(version) {
+            let suggestion = if self.log_filter_origin == FilterOrigin::RustLog {
+                "from RUST_LOG, like 'info,app=debug'"
+            } else {
+                "like 'info,app=debug'"
+            };
+            issues.push(
+                ConfigIssue::error("log-filter", &self.log_filter, err.root_cause())
+                    .suggest(suggestion),
+            );
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(log_filter) = self.open_telemetry.log_filter() {
+            if let Err(err) = directives(
+                self.levels(),
+                self.log_defaults,
+                log_filter,
+                "otlp-log-filter",
+                version,
+            ) {
+                issues.push(
+                    ConfigIssue::error("otlp-log-filter", log_filter, err.root_cause())
+                        .suggest("like 'info,app=trace'"),
+                );
+            }
+        }
+        if let Some(path) = &self.log_file {
+            issues.extend(config_issue::writable("log-file", path));
+        }
+        for sink in &self.log_sink {
+            if let SinkTarget::File(path) = &sink.target {
+                issues.extend(config_issue::writable("log-sink", path));
+            }
+        }
+        if let Some(path) = &self.trace_flame {
+            issues.extend(config_issue::writable("trace-flame", path));
+        }
+        if self.log_rate_limit == Some(0) {
+            issues.push(
+                ConfigIssue::warning("log-rate-limit", 0, "suppresses every log line")
+                    .suggest("leave it out for no limit"),
+            );
+        }
+        if self.log_redact_fields.iter().any(String::is_empty) {
+            issues.push(ConfigIssue::warning(
+                "log-redact-fields",
+                self.log_redact_fields.join(","),
+                "an empty name matches no field",
+            ));
+        }
+        issues.extend(self.session_log.validate());
+        issues.extend(self.auto_debug.validate());
+        #[cfg(feature = "binary-log")]
+        issues.extend(self.binary_log.validate());
+        #[cfg(feature = "binary-log")]
+        issues.extend(self.replay.validate());
+        #[cfg(feature = "otlp")]
+        issues.extend(self.open_telemetry.validate());
+        issues
+    }
+
+    /// Fail if `--fail-on-error-logs` is set and errors were logged.
+    pub fn check_error_logs(&self) -> EyreResult<()> {
+        self.log_counters.check()
+    }
+
+    /// Whether `--print-log-filter` was given.
+    pub const fn print_log_filter_requested(&self) -> bool {
+        self.print_log_filter
+    }
+
+    /// Print the log filter for `--print-log-filter`, one target per line
+    /// with the level and where it comes from.
+    pub fn print_log_filter(&self, version: &Version) -> EyreResult<()> {
+        let (default, directives) = self.filter_directives(version)?;
+        let width = directives.keys().map(String::len).max().unwrap_or(0).max(7);
+        println!(
+            "{:width$}  {default:5}  {}",
+            "default",
+            FilterSource::Verbose
+        );
+        for (target, (level, source)) in directives {
+            println!("{target:width$}  {level:5}  {source}");
+        }
+        Ok(())
+    }
+
+    /// The `--replay` file, if given.
+    #[cfg(feature = "binary-log")]
+    pub fn replay_requested(&self) -> Option<&Path> {
+        self.replay.requested()
+    }
+
+    /// Replay the recording at `path` through the log output of these
+    /// options, for `--replay`.
+    #[cfg(feature = "binary-log")]
+    pub fn replay(&self, version: &Version, path: &Path) -> EyreResult<()> {
+        let (options, syslog, target_error) = self.connect_log_target();
+        if let Some(err) = target_error {
+            eprintln!("Could not connect to the log target, logging to stderr: {err}");
+        }
+        let writer = options.writer(syslog)?;
+        let result = replay::play_with(&options, path, version, writer);
+        flush_log_output();
+        result.map(drop)
+    }
+
+    /// Log filtering is a combination of the built-in filter, `--verbose` and
+    /// `--log-filter`, in increasing precedence.
+    fn filter(&self, version: &Version) -> EyreResult<LogFilter> {
+        let (default, directives) = self.filter_directives(version)?;
+        LogFilter::new(default, &directives)
+    }
+
+    /// Listen on `--log-control-socket`, if given, once logging works. The
+    /// returned task ends after the shutdown.
+    #[cfg(unix)]
+    pub fn start_log_control(
+        &self,
+        version: &Version,
+    ) -> EyreResult<Option<tokio::task::JoinHandle<()>>> {
+        self.log_control.start(self.filter_options(version))
+    }
+
+    /// Log the app at `DEBUG` while errors spike, with `--auto-debug`.
+    pub fn start_auto_debug(&self, version: &Version) {
+        self.auto_debug.start(self.filter_options(version));
+    }
+
+    /// What a log filter swapped in at runtime is combined with.
+    fn filter_options(&self, version: &Version) -> filter_reload::FilterOptions {
+        filter_reload::FilterOptions {
+            levels:       self.levels(),
+            log_defaults: self.log_defaults,
+            log_filter:   self.log_filter.clone(),
+            version:      version.clone(),
+        }
+    }
+
+    /// The default level and the level of each target, with its source.
+    fn filter_directives(&self, version: &Version) -> EyreResult<(LevelFilter, Directives)> {
+        let flag = self.log_filter_flag();
+        directives(
+            self.levels(),
+            self.log_defaults,
+            &self.log_filter,
+            flag,
+            version,
+        )
+    }
+
+    /// The filter of the OpenTelemetry layer: `--otlp-log-filter` in place of
+    /// `--log-filter` if set, otherwise that of the log output.
+    #[cfg(feature = "otlp")]
+    fn otlp_filter(&self, version: &Version) -> EyreResult<LogFilter> {
+        let Some(log_filter) = self.open_telemetry.log_filter() else {
+            return self.filter(version);
+        };
+        let (default, directives) = directives(
+            self.levels(),
+            self.log_defaults,
+            log_filter,
+            "otlp-log-filter",
+            version,
+        )?;
+        LogFilter::new(default, &directives)
+    }
+}
+
+/// The default level and the level of each target, with its source, for
+/// the `levels` of `--verbose` and `--quiet`, the built-in `defaults` and the
+/// log filter given as `flag`.
+fn directives(
+    (all, app): Levels,
+    defaults: LogDefaults,
+    log_filter: &str,
+    flag: &str,
+    version: &Version,
+) -> EyreResult<(LevelFilter, Directives)> {
+    let (log_filter, spans) =
+        log_filter::parse(log_filter).wrap_err_with(|| format!("Error parsing {flag}"))?;
+    let mut directives = BTreeMap::new();
+    // `-qqq` silences the built-in targets as well
+    let built_in = if all == LevelFilter::OFF {
+        Targets::new()
+    } else {
+        defaults.targets()?
+    };
+    let defaults = built_in
+        .iter()
+        .map(|(target, level)| (target, level, FilterSource::BuiltIn));
+    let app_crates = version
+        .app_crates
+        .iter()
+        .map(|target| (target.as_str(), app, FilterSource::Verbose));
+    let source = if flag == RUST_LOG {
+        FilterSource::RustLog
+    } else {
+        FilterSource::LogFilter
+    };
+    // Span directives after the targets, as `EnvFilter` orders them
+    let log_filter = log_filter
+        .iter()
+        .map(|(target, level)| (target, level, source))
+        .chain(
+            spans
+                .iter()
+                .map(|(span, level)| (span.as_str(), *level, source)),
+        );
+    for (target, level, source) in defaults.chain(app_crates).chain(log_filter) {
+        directives.insert(target.to_owned(), (level, source));
+    }
+    Ok((all, directives))
+}
+
+/// Commands holding the arguments of optional features, by feature name.
+pub fn feature_commands() -> Vec<(&'static str, Command)> {
+    Vec::from([
+        #[cfg(feature = "tokio-console")]
+        (
+            "tokio-console",
+            <tokio_console::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "otlp")]
+        (
+            "otlp",
+            <open_telemetry::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "otlp")]
+        (
+            "otlp",
+            <trace_url::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "binary-log")]
+        (
+            "binary-log",
+            <binary_log::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "binary-log")]
+        (
+            "binary-log",
+            <replay::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "shmem-logs")]
+        (
+            "shmem-logs",
+            <shmem_log::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "webhook")]
+        (
+            "webhook",
+            <webhook::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "log-async")]
+        (
+            "log-async",
+            <log_async::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(feature = "log-compress")]
+        (
+            "log-compress",
+            <file_output::Options as clap::CommandFactory>::command(),
+        ),
+        #[cfg(all(unix, feature = "signals"))]
+        (
+            "signals",
+            <filter_reload::Options as clap::CommandFactory>::command(),
+        ),
+    ])
+}
+
+/// Point to the session log of this run, if any, after an error report.
+pub fn log_session_log_path() {
+    session_log::log_path(session_log::path());
+}
+
+/// Point to the trace of this run after an error report, if `--trace-url` is
+/// set.
+#[cfg_attr(not(feature = "otlp"), allow(clippy::missing_const_for_fn))] // Empty without it
+pub fn log_trace_url() {
+    #[cfg(feature = "otlp")]
+    trace_url::log();
+}
+
+/// Complete the session log file. Must be called last, later events are not
+/// written to it.
+pub fn finish_session_log() {
+    if let Err(err) = session_log::finish() {
+        eprintln!("Error: {err:#}");
+    }
+}
+
+/// Flush and complete all telemetry before the process image is replaced.
+/// Events logged afterwards only reach stderr.
+pub fn prepare_exec() -> EyreResult<()> {
+    shutdown()?;
+    session_log::finish()
+}
+
+/// Deliver the error events queued for `--error-webhook-url`, if enabled.
+/// Later events are not posted.
+#[cfg_attr(not(feature = "webhook"), allow(clippy::missing_const_for_fn))] // Empty without it
+pub fn finish_webhook() {
+    #[cfg(feature = "webhook")]
+    webhook::finish();
+}
+
+/// Write the log lines queued by `--log-async`. Later events are written
+/// directly, so this is safe to call more than once.
+#[cfg_attr(not(feature = "log-async"), allow(clippy::missing_const_for_fn))] // Empty without it
+pub fn flush_log_output() {
+    #[cfg(feature = "log-async")]
+    log_async::flush();
 }
 
 pub fn shutdown() -> EyreResult<()> {
+    log_summary();
+    late_events::begin();
+    disabled_cost::report();
+
     if let Some(Some(flush_guard)) = FLAME_FLUSH_GUARD.get() {
         flush_guard.flush()?;
     }
@@ -233,26 +1568,928 @@ pub fn shutdown() -> EyreResult<()> {
     #[cfg(feature = "otlp")]
     open_telemetry::shutdown();
 
+    #[cfg(feature = "binary-log")]
+    binary_log::finish()?;
+
+    #[cfg(feature = "binary-log")]
+    replay::finish()?;
+
+    #[cfg(feature = "shmem-logs")]
+    shmem_log::finish()?;
+
+    #[cfg(feature = "webhook")]
+    webhook::finish();
+
+    flush_log_output();
+    log_file::finish()?;
+
     Ok(())
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::{
+        logs::{parse_line, parse_line_as, Format},
+        runner::LayerFactory,
+        Runner,
+    };
+    use std::{
+        fs,
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+    use tracing::{error, info, info_span, warn, Level};
+    use tracing_subscriber::filter::LevelFilter;
+
+    /// In-memory log output.
+    #[derive(Clone, Default)]
+    pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Capture {
+        pub fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+
+        #[cfg_attr(not(feature = "binary-log"), allow(dead_code))]
+        pub fn bytes(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Capture {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    pub fn mock_version() -> Version {
+        Version {
+            pkg_name:     "test-app",
+            pkg_version:  "0.0.0",
+            pkg_repo:     "https://github.com/recmo/cli-batteries",
+            crate_name:   "test_app",
+            commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
+            long_version: "0.0.0",
+            target:       "unknown",
+            app_crates:   vec!["app".to_owned()],
+        }
+    }
+
+    fn emit_events() {
+        info!(target: "app", "app info");
+        info!(target: "dep", "dep info");
+        warn!(target: "dep", "dep warn");
+        error!(target: "dep", "dep error");
+    }
 
     #[test]
     fn test_parse_args() {
         let cmd = "arg0 -v --log-filter foo -vvv";
         let options = Options::try_parse_from(cmd.split(' ')).unwrap();
         assert_eq!(options, Options {
-            verbose: 4,
+            verbose: Verbosity(4),
+            quiet: 0,
             log_filter: "foo".to_owned(),
+            log_filter_origin: FilterOrigin::None,
+            levels: None,
+            log_defaults: LogDefaults::NONE,
+            print_log_filter: false,
             log_format: LogFormat::Tiny,
+            log_timestamp: None,
+            log_utc: false,
+            log_local: false,
+            log_span_events: LogSpanEvents::DEFAULT.to_vec(),
+            log_show_thread: false,
+            log_show_target: true,
+            log_show_module: false,
+            log_json_flatten: false,
+            log_stream: LogStream::Stderr,
+            log_file: None,
+            log_target: LogTarget::Stderr,
+            syslog_facility: Facility::Daemon,
+            log_sink: vec![],
+            log_rate_limit: None,
+            #[cfg(feature = "log-async")]
+            log_async: log_async::Options::default(),
+            log_disk_full_policy: DiskFullPolicy::Drop,
+            log_rotate: None,
+            log_keep: 10,
+            #[cfg(feature = "log-compress")]
+            log_file_compress: file_output::Options::default(),
+            log_target_width: 24,
+            log_field_width: None,
+            log_escape_control: EscapeControl::TtyOnly,
+            log_redact_fields: Vec::new(),
+            tag: vec![],
             trace_flame: None,
+            session_log: session_log::Options::default(),
+            recent_errors_size: 256,
+            log_counters: log_counters::Options::default(),
+            warn_expensive_disabled_logging: false,
+            warn_span_cardinality: false,
+            span_cardinality_limit: 100,
+            span_cardinality_field: vec!["otel.name".to_owned()],
+            slow_span_threshold: vec![],
+            debug_shutdown: false,
+            auto_debug: auto_debug::Options::default(),
             #[cfg(feature = "tokio-console")]
             tokio_console: tokio_console::Options::default(),
+            #[cfg(feature = "binary-log")]
+            binary_log: binary_log::Options::default(),
+            #[cfg(feature = "binary-log")]
+            replay: replay::Options::default(),
+            #[cfg(feature = "shmem-logs")]
+            shmem_log: shmem_log::Options::default(),
+            #[cfg(feature = "webhook")]
+            webhook: webhook::Options::default(),
+            #[cfg(all(unix, feature = "signals"))]
+            filter_reload: filter_reload::Options::default(),
+            #[cfg(unix)]
+            log_control: log_control::Options::default(),
             #[cfg(feature = "otlp")]
             open_telemetry: open_telemetry::Options::default(),
+            #[cfg(feature = "otlp")]
+            trace_url: trace_url::Options::default(),
+        });
+
+        for (cmd, verbose, quiet) in [
+            ("arg0 -vq", 1, 1),
+            ("arg0 -qvq", 1, 2),
+            ("arg0 -vv --quiet", 2, 1),
+            ("arg0 -qqq --verbose=debug", 2, 3),
+        ] {
+            let options = Options::try_parse_from(cmd.split(' ')).unwrap();
+            assert_eq!(
+                (options.verbose, options.quiet),
+                (Verbosity(verbose), quiet),
+                "{cmd}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_quiet() {
+        let version = mock_version();
+        let defaults = LogDefaults {
+            filter: "dep=warn",
+            format: "",
+        };
+        let parse = |cmd: &str| {
+            let mut options = Options::try_parse_from(cmd.split(' ')).unwrap();
+            options.use_log_defaults(defaults, None).unwrap();
+            options
+        };
+        for (cmd, default, app) in [
+            ("arg0 -q", LevelFilter::ERROR, LevelFilter::WARN),
+            ("arg0 -qq", LevelFilter::ERROR, LevelFilter::ERROR),
+            ("arg0 -vq", LevelFilter::ERROR, LevelFilter::INFO),
+            ("arg0 -vvvq", LevelFilter::INFO, LevelFilter::DEBUG),
+            ("arg0 -qqqqv", LevelFilter::OFF, LevelFilter::OFF),
+        ] {
+            let (all, directives) = parse(cmd).filter_directives(&version).unwrap();
+            assert_eq!((all, directives["app"].0), (default, app), "{cmd}");
+        }
+
+        // `-qqq` turns off the built-in targets too, `--log-filter` still wins
+        let cmd = "arg0 -qqq --log-filter other=info";
+        let (_, directives) = parse(cmd).filter_directives(&version).unwrap();
+        assert_eq!(directives.into_iter().collect::<Vec<_>>(), [
+            ("app".to_owned(), (LevelFilter::OFF, FilterSource::Verbose)),
+            (
+                "other".to_owned(),
+                (LevelFilter::INFO, FilterSource::LogFilter)
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_verbosity_map() {
+        // Default levels unless the app maps them
+        let version = mock_version();
+        let options = Options::try_parse_from(["arg0", "-vvvv"]).unwrap();
+        let (all, directives) = options.filter_directives(&version).unwrap();
+        assert_eq!(
+            (all, directives["app"].0),
+            (LevelFilter::DEBUG, LevelFilter::TRACE)
+        );
+
+        let runner = Runner::new(mock_version())
+            .verbosity_map(|verbosity| match verbosity {
+                ..=0 => (LevelFilter::WARN, LevelFilter::INFO),
+                _ => (LevelFilter::INFO, LevelFilter::DEBUG),
+            })
+            .app_targets(["worker"]);
+        for (cmd, default, app) in [
+            ("arg0", LevelFilter::WARN, LevelFilter::INFO),
+            ("arg0 -q", LevelFilter::WARN, LevelFilter::INFO),
+            ("arg0 -vvvv", LevelFilter::INFO, LevelFilter::DEBUG),
+        ] {
+            let mut options = Options::try_parse_from(cmd.split(' ')).unwrap();
+            options.use_verbosity_map(&runner.verbosity_map);
+            let (all, directives) = options.filter_directives(&runner.version).unwrap();
+            assert_eq!((all, directives["app"].0), (default, app), "{cmd}");
+            assert_eq!(directives["worker"], (app, FilterSource::Verbose), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn test_rust_log() {
+        let version = mock_version();
+        let resolve = |args: &[&str], source, rust_log: Option<&str>| {
+            let mut options = Options::try_parse_from(args).unwrap();
+            options.resolve_log_filter(source, rust_log.map(str::to_owned));
+            options
+        };
+        let flag = Some(ValueSource::CommandLine);
+        let options = resolve(
+            &["arg0", "--log-filter", "dep=warn"],
+            flag,
+            Some("dep=trace"),
+        );
+        assert_eq!(options.log_filter_origin, FilterOrigin::Flag);
+        assert_eq!(options.log_filter, "dep=warn");
+        let env = Some(ValueSource::EnvVariable);
+        let options = resolve(
+            &["arg0", "--log-filter", "dep=warn"],
+            env,
+            Some("dep=trace"),
+        );
+        assert_eq!(options.log_filter_origin, FilterOrigin::LogFilter);
+        let default = Some(ValueSource::DefaultValue);
+        let options = resolve(&["arg0"], default, Some(" "));
+        assert_eq!(options.log_filter_origin, FilterOrigin::None);
+
+        let options = resolve(&["arg0"], default, Some("dep=trace"));
+        assert_eq!(options.log_filter_origin, FilterOrigin::RustLog);
+        let (_, directives) = options.filter_directives(&version).unwrap();
+        assert_eq!(
+            directives["dep"],
+            (LevelFilter::TRACE, FilterSource::RustLog)
+        );
+
+        let options = resolve(&["arg0"], default, Some("dep=loud"));
+        let err = options.filter_directives(&version).unwrap_err();
+        assert_eq!(err.to_string(), "Error parsing RUST_LOG");
+        assert_eq!(options.validate(&version)[0].field, "log-filter");
+    }
+
+    #[test]
+    #[cfg(feature = "otlp")]
+    fn test_otlp_log_filter() {
+        let version = mock_version();
+        let cmd = "arg0 --log-filter dep=warn --otlp-log-filter app=trace";
+        let options = Options::try_parse_from(cmd.split(' ')).unwrap();
+        let targets = options.filter(&version).unwrap();
+        let otlp = options.otlp_filter(&version).unwrap();
+        assert!(!targets.would_enable("app", &Level::DEBUG));
+        assert!(otlp.would_enable("app", &Level::TRACE));
+        assert!(targets.would_enable("dep", &Level::WARN));
+        assert!(!otlp.would_enable("dep", &Level::WARN));
+
+        // The same as the log output without it
+        let options = Options::try_parse_from(["arg0", "--log-filter", "dep=warn"]).unwrap();
+        let otlp = options.otlp_filter(&version).unwrap();
+        assert!(otlp.would_enable("dep", &Level::WARN));
+        assert!(!otlp.would_enable("app", &Level::DEBUG));
+
+        let options = Options::try_parse_from(["arg0", "--otlp-log-filter", "app=loud"]).unwrap();
+        let err = options.otlp_filter(&version).unwrap_err();
+        assert_eq!(err.to_string(), "Error parsing otlp-log-filter");
+    }
+
+    #[test]
+    fn test_filter_precedence() {
+        let version = mock_version();
+        let defaults = LogDefaults {
+            filter: "dep=warn,app=debug,noisy=off",
+            format: "json",
+        };
+        let mut options = Options::try_parse_from(["arg0", "--log-filter", "dep=error"]).unwrap();
+        options
+            .use_log_defaults(defaults, Some(ValueSource::DefaultValue))
+            .unwrap();
+        assert_eq!(options.log_format, LogFormat::Json);
+        assert_eq!(options.built_in(), [
+            ("log_filter", "dep=warn,app=debug,noisy=off".to_owned()),
+            ("log_format", "json".to_owned()),
+        ]);
+        let (default, directives) = options.filter_directives(&version).unwrap();
+        assert_eq!(default, LevelFilter::ERROR);
+        assert_eq!(directives.into_iter().collect::<Vec<_>>(), [
+            ("app".to_owned(), (LevelFilter::INFO, FilterSource::Verbose)),
+            (
+                "dep".to_owned(),
+                (LevelFilter::ERROR, FilterSource::LogFilter)
+            ),
+            (
+                "noisy".to_owned(),
+                (LevelFilter::OFF, FilterSource::BuiltIn)
+            ),
+        ]);
+
+        // `--log-format` and `LOG_FORMAT` win over the built-in format
+        let mut options = Options::try_parse_from(["arg0", "--log-format", "compact"]).unwrap();
+        options
+            .use_log_defaults(defaults, Some(ValueSource::CommandLine))
+            .unwrap();
+        assert_eq!(options.log_format, LogFormat::Compact);
+    }
+
+    #[test]
+    fn test_span_filter() {
+        let version = mock_version();
+        let log_filter = "dep=warn,app[request{method=POST,retry=1}]=trace";
+        let options = Options::try_parse_from(["arg0", "-v", "--log-filter", log_filter]).unwrap();
+        let (default, directives) = options.filter_directives(&version).unwrap();
+        assert_eq!(default, LevelFilter::INFO);
+        assert_eq!(directives.into_iter().collect::<Vec<_>>(), [
+            ("app".to_owned(), (LevelFilter::INFO, FilterSource::Verbose)),
+            (
+                "app[request{method=POST,retry=1}]".to_owned(),
+                (LevelFilter::TRACE, FilterSource::LogFilter)
+            ),
+            (
+                "dep".to_owned(),
+                (LevelFilter::WARN, FilterSource::LogFilter)
+            ),
+        ]);
+        // `--verbose` still applies outside the span
+        let filter = options.filter(&version).unwrap();
+        assert!(filter.would_enable("app", &Level::INFO));
+        assert!(!filter.would_enable("app", &Level::DEBUG));
+        assert!(!filter.would_enable("dep", &Level::INFO));
+
+        let options =
+            Options::try_parse_from(["arg0", "--log-filter", "app[request]=loud"]).unwrap();
+        assert_eq!(options.validate(&version)[0].field, "log-filter");
+    }
+
+    #[test]
+    fn test_log_file() {
+        let dir = std::env::temp_dir().join(format!("cli-batteries-log-file-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("logs").join("app.log");
+        for format in ["tiny", "pretty", "json"] {
+            let args = ["arg0", "--log-format", format, "--log-file"];
+            let options = Options::try_parse_from(args.into_iter().chain(path.to_str())).unwrap();
+            assert_eq!(options.log_file.as_ref(), Some(&path));
+            let writer = log_file::open(&path, None, 10, false).unwrap();
+            let (subscriber, _) = options
+                .subscriber(&mock_version(), writer, Vec::new())
+                .unwrap();
+            tracing::subscriber::with_default(subscriber, || {
+                info!(target: "app", "written as {format}");
+            });
+        }
+        // Appended, in the requested formats and without colors.
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("written as tiny"), "{contents}");
+        assert!(contents.contains("written as pretty"), "{contents}");
+        let json = contents.lines().last().unwrap();
+        assert_eq!(parse_line(json).unwrap().message, "written as json");
+        assert!(!contents.contains('\x1b'), "{contents}");
+
+        // A file where the directory should be.
+        let error = log_file::open(&path.join("nested.log"), None, 10, false)
+            .err()
+            .unwrap();
+        assert!(format!("{error}").starts_with("Error creating log file directory"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_filter() {
+        let options = Options::try_parse_from(["arg0", "--log-filter", "dep=warn"]).unwrap();
+        let capture = Capture::default();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), capture.clone(), Vec::new())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, emit_events);
+
+        let output = capture.contents();
+        assert!(output.contains("app info"));
+        assert!(!output.contains("dep info"));
+        assert!(output.contains("dep warn"));
+        assert!(output.contains("dep error"));
+    }
+
+    #[test]
+    fn test_log_sinks() {
+        let dir = std::env::temp_dir().join(format!("cli-batteries-log-sink-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        let json = dir.join("app.json");
+        let compact = dir.join("app.log");
+        let sinks = format!(
+            "format=json,target=file:{};format=compact,target=file:{}",
+            json.display(),
+            compact.display()
+        );
+        let args = ["arg0", "--log-format", "pretty", "--log-filter", "dep=warn"];
+        let options =
+            Options::try_parse_from(args.into_iter().chain(["--log-sink", &sinks])).unwrap();
+        let capture = Capture::default();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), capture.clone(), Vec::new())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, emit_events);
+        shutdown().unwrap();
+
+        // Every output in its format, with the same filter.
+        let pretty = capture.contents();
+        let json = fs::read_to_string(&json).unwrap();
+        let compact = fs::read_to_string(&compact).unwrap();
+        let messages = json
+            .lines()
+            .map(|line| parse_line(line).unwrap().message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["app info", "dep warn", "dep error"]);
+        assert_eq!(compact.lines().count(), 3, "{compact}");
+        assert!(compact.contains("app info") && !compact.contains("dep info"));
+        assert!(
+            !compact.contains('{') && !compact.contains('\x1b'),
+            "{compact}"
+        );
+        assert!(pretty.contains("app info") && !pretty.contains("dep info"));
+        assert!(pretty.contains(" src/trace/mod.rs:"), "{pretty}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "journald")]
+    #[test]
+    fn test_journald_fallback() {
+        // Only where there is no journal, like in containers.
+        if Path::new("/run/systemd/journal/socket").exists() {
+            return;
+        }
+        let options = Options::try_parse_from(["arg0", "--log-target", "journald"]).unwrap();
+        let (options, syslog, error) = options.connect_log_target();
+        assert_eq!(options.log_target, LogTarget::Stderr);
+        assert!(syslog.is_none());
+        assert!(error.is_some());
+    }
+
+    #[test]
+    fn test_panicking_layer() {
+        let panicking = guard::test::Panicking::default();
+        let runner = Runner::new(mock_version()).layer({
+            let panicking = panicking.clone();
+            move || panicking.clone()
+        });
+        let layers = runner.layers.iter().map(LayerFactory::make).collect();
+        let capture = Capture::default();
+        let (subscriber, _) = Options::default()
+            .subscriber(&mock_version(), capture.clone(), layers)
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", "before");
+            info!(target: "app", "boom");
+            info!(target: "app", "after");
         });
+
+        let output = capture.contents();
+        for message in ["before", "boom", "after"] {
+            assert!(output.contains(message), "{message}: {output}");
+        }
+        assert_eq!(panicking.0.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_layers_filter_independently() {
+        let options = Options::default();
+        let capture = Capture::default();
+        let verbose = Capture::default();
+        let quiet = Capture::default();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), capture.clone(), Vec::new())
+            .unwrap();
+
+        // Stack additional outputs with more and less permissive filters.
+        let subscriber = subscriber
+            .with(
+                fmt::Layer::new()
+                    .with_writer(verbose.clone())
+                    .with_filter(LevelFilter::TRACE),
+            )
+            .with(
+                fmt::Layer::new()
+                    .with_writer(quiet.clone())
+                    .with_filter(Targets::new().with_target("nothing", Level::ERROR)),
+            );
+        tracing::subscriber::with_default(subscriber, emit_events);
+
+        // Default verbosity: app at INFO, everything else at ERROR.
+        let output = capture.contents();
+        assert!(output.contains("app info"));
+        assert!(!output.contains("dep info"));
+        assert!(!output.contains("dep warn"));
+        assert!(output.contains("dep error"));
+
+        let output = verbose.contents();
+        for message in ["app info", "dep info", "dep warn", "dep error"] {
+            assert!(output.contains(message));
+        }
+
+        assert_eq!(quiet.contents(), "");
+    }
+
+    #[test]
+    fn test_log_bridge() {
+        use tracing_log::{format_trace, log};
+
+        let log = |target: &str, message: &str| {
+            format_trace(
+                &log::Record::builder()
+                    .target(target)
+                    .level(log::Level::Info)
+                    .args(format_args!("{message}"))
+                    .build(),
+            )
+            .unwrap();
+        };
+        for format in ["compact", "json"] {
+            let options = Options::try_parse_from(["arg0", "-v", "--log-format", format]).unwrap();
+            let capture = Capture::default();
+            let (subscriber, _) = options
+                .subscriber(&mock_version(), capture.clone(), Vec::new())
+                .unwrap();
+            tracing::subscriber::with_default(subscriber, || {
+                log("dep", "from log");
+                // What `tracing` with the `log-always` feature echoes for a span.
+                log("tracing::span", "++ request; id=1");
+                log("tracing::span::active", "-> request;");
+                log("tracing::span::active", "<- request;");
+                log("tracing::span", "-- request;");
+                info_span!("request").in_scope(|| info!("inside"));
+            });
+
+            let output = capture.contents();
+            let lines = output.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 4, "{format}: {output}");
+            assert!(lines[0].contains("from log"), "{format}: {output}");
+            assert!(lines[1].contains("begin"), "{format}: {output}");
+            assert!(lines[2].contains("inside"), "{format}: {output}");
+            assert!(lines[3].contains("end"), "{format}: {output}");
+        }
+    }
+
+    fn strip_ansi(text: &str) -> String {
+        let mut parts = text.split('\x1b');
+        let mut result = parts.next().unwrap_or_default().to_owned();
+        for part in parts {
+            result.push_str(part.split_once('m').map_or(part, |(_, rest)| rest));
+        }
+        result
+    }
+
+    #[test]
+    fn test_tags() {
+        use tracing_log::{format_trace, log};
+
+        for format in ["compact", "json"] {
+            let options = Options::try_parse_from([
+                "arg0",
+                "-v",
+                "--tag",
+                "ticket=ABC-123",
+                "--tag",
+                "attempt=1",
+                "--tag",
+                "attempt=2",
+                "--log-format",
+                format,
+            ])
+            .unwrap();
+            let capture = Capture::default();
+            let (subscriber, _) = options
+                .subscriber(&mock_version(), capture.clone(), Vec::new())
+                .unwrap();
+            tracing::subscriber::with_default(subscriber, || {
+                info_span!("request", id = 7).in_scope(|| {
+                    info!(target: "app", count = 3, ticket = "own", "tagged");
+                });
+                format_trace(
+                    &log::Record::builder()
+                        .target("dep")
+                        .level(log::Level::Info)
+                        .args(format_args!("from log"))
+                        .build(),
+                )
+                .unwrap();
+            });
+
+            let output = strip_ansi(&capture.contents());
+            let line = |needle: &str| {
+                output
+                    .lines()
+                    .find(|line| line.contains(needle))
+                    .unwrap_or_else(|| panic!("{format}: no {needle} in {output}"))
+                    .to_owned()
+            };
+            assert_eq!(output.lines().count(), 4, "{format}: {output}");
+            let (begin, tagged, log) = (line("begin"), line("tagged"), line("from log"));
+            if format == "json" {
+                assert!(tagged.contains(r#""id":7"#), "{tagged}");
+                let [begin, tagged, log] =
+                    [begin, tagged, log].map(|line| parse_line(&line).unwrap());
+                for record in [&begin, &log] {
+                    assert_eq!(record.fields["ticket"], "ABC-123", "{record:?}");
+                    assert_eq!(record.fields["attempt"], "2", "{record:?}");
+                }
+                assert_eq!(tagged.fields["ticket"], "own", "{tagged:?}");
+                assert_eq!(tagged.fields["count"], 3, "{tagged:?}");
+                assert_eq!(tagged.span.as_deref(), Some("request"), "{tagged:?}");
+                assert_eq!(log.target.as_deref(), Some("dep"), "{log:?}");
+            } else {
+                for line in [&begin, &log] {
+                    assert!(line.contains("ticket=ABC-123"), "{line}");
+                    assert!(line.contains("attempt=2"), "{line}");
+                }
+                assert!(tagged.contains("ticket=own"), "{tagged}");
+                assert!(tagged.contains("count=3"), "{tagged}");
+                assert!(tagged.contains("request: app: tagged"), "{tagged}");
+                assert!(tagged.contains("request.id=7"), "{tagged}");
+                assert!(log.contains("dep:"), "{log}");
+            }
+            assert!(!output.contains("attempt=\"1\""), "{output}");
+        }
+        assert!(Options::try_parse_from(["arg0", "--tag", "bad key=x"]).is_err());
+    }
+
+    #[test]
+    fn test_phase_indent() {
+        let _serial = crate::phase::test::SERIAL.blocking_lock();
+        // Lines of the tiny format start with the padded uptime.
+        let args = [
+            "arg0",
+            "--log-filter",
+            "cli_batteries=info",
+            "--log-format",
+            "compact",
+        ];
+        let options = Options::try_parse_from(args).unwrap();
+        let capture = Capture::default();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), capture.clone(), Vec::new())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = crate::phase("indent outer", 0);
+            let inner = crate::phase("indent inner", 0);
+            inner.span().in_scope(|| info!("inside inner"));
+            inner.finish();
+            outer.finish();
+        });
+
+        let output = capture.contents();
+        let line = |needle: &str| {
+            output
+                .lines()
+                .find(|line| line.contains(needle))
+                .unwrap()
+                .to_owned()
+        };
+        assert!(!line("Phase indent outer started").starts_with(' '));
+        assert!(line("Phase indent inner started").starts_with("  "));
+        assert!(line("inside inner").starts_with("  "));
+        assert!(!line("inside inner").starts_with("   "));
+        assert!(line("Phase indent inner finished").starts_with("  "));
+        assert!(!line("Phase indent outer finished").starts_with(' '));
+    }
+
+    #[test]
+    fn test_logfmt() {
+        let capture = Capture::default();
+        let fields = global_fields::fields(&[("ticket".to_owned(), "ABC 123".to_owned())]);
+        let layer =
+            LogFormat::Logfmt.into_layer(capture.clone(), fields, &LineOptions::default(), false);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            info_span!("request", id = 7, path = "/").in_scope(|| {
+                warn!(target: "app", id = 8, "plain text");
+            });
+        });
+        let output = capture.contents();
+        let lines = output
+            .lines()
+            .map(|line| line.split_once(' ').unwrap())
+            .inspect(|(ts, _)| assert!(ts.starts_with("ts="), "{output}"))
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>();
+        assert!(lines[0].contains(" msg=request span=begin "), "{output}");
+        assert!(
+            lines[0].ends_with(r#" ticket="ABC 123" id=7 path=/"#),
+            "{output}"
+        );
+        // The event field wins over the span field.
+        assert_eq!(
+            lines[1],
+            r#"level=warn target=app msg="plain text" id=8 ticket="ABC 123" path=/"#
+        );
+    }
+
+    #[test]
+    fn test_span_events() {
+        let formats = [
+            LogFormat::Tiny,
+            LogFormat::Compact,
+            LogFormat::PrettyCompact,
+            LogFormat::Json,
+            LogFormat::Logfmt,
+            #[cfg(feature = "otlp")]
+            LogFormat::Otlp,
+        ];
+        for format in formats {
+            let lines = |span_events: &[LogSpanEvents]| {
+                let capture = Capture::default();
+                let line = LineOptions {
+                    span_events: LogSpanEvents::fmt_span(span_events),
+                    ..LineOptions::default()
+                };
+                let layer = format.into_layer(capture.clone(), Fields::default(), &line, false);
+                tracing::subscriber::with_default(Registry::default().with(layer), || {
+                    info_span!("request", id = 7).in_scope(|| {
+                        info!(target: "app", "first");
+                        info!(target: "app", "second");
+                    });
+                });
+                capture.contents().lines().count()
+            };
+            assert_eq!(lines(&[LogSpanEvents::None]), 2, "{format:?}");
+            assert_eq!(lines(&LogSpanEvents::DEFAULT), 4, "{format:?}");
+            assert_eq!(lines(&[LogSpanEvents::Close]), 3, "{format:?}");
+            assert_eq!(lines(&[LogSpanEvents::Full]), 6, "{format:?}");
+        }
+    }
+
+    /// Every format with the thread, target and module toggles flipped. Set
+    /// `UPDATE_SNAPSHOTS=1` to update the snapshot after an intentional change.
+    #[test]
+    fn test_log_show() {
+        use std::fmt::Write as _;
+
+        let formats = [
+            LogFormat::Tiny,
+            LogFormat::Compact,
+            LogFormat::PrettyCompact,
+            LogFormat::Json,
+            LogFormat::Logfmt,
+        ];
+        let base = LineOptions {
+            timestamp: Some(LogTimestamp::None),
+            span_events: FmtSpan::NONE,
+            ..LineOptions::default()
+        };
+        let toggles = [
+            ("default", base.clone()),
+            ("thread", LineOptions {
+                thread: true,
+                ..base.clone()
+            }),
+            ("no target", LineOptions {
+                target: false,
+                ..base.clone()
+            }),
+            ("module", LineOptions {
+                module: true,
+                ..base
+            }),
+        ];
+        let mut actual = String::new();
+        for format in formats {
+            for (name, line) in &toggles {
+                let capture = Capture::default();
+                let layer = format.into_layer(capture.clone(), Fields::default(), line, false);
+                // Named, the test thread names depend on the harness
+                thread::Builder::new()
+                    .name("worker".to_owned())
+                    .spawn(|| {
+                        tracing::subscriber::with_default(Registry::default().with(layer), || {
+                            info!(target: "app::db", rows = 3, "query done");
+                        });
+                    })
+                    .unwrap()
+                    .join()
+                    .unwrap();
+                let _ = write!(actual, "{format:?} {name}: {}", capture.contents());
+            }
+        }
+        // Thread ids depend on the order the tests run in
+        let actual = actual
+            .split("ThreadId(")
+            .enumerate()
+            .map(|(i, part)| {
+                if i == 0 {
+                    part
+                } else {
+                    part.trim_start_matches(|c: char| c.is_ascii_digit())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("ThreadId(N");
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/log_show.txt");
+        if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::write(&path, &actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            actual, expected,
+            "Log lines changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+        );
+    }
+
+    /// Everything the JSON formats write parses back.
+    #[test]
+    fn test_parse_own_output() {
+        let formats = [
+            (LogFormat::Json, Format::Json),
+            #[cfg(feature = "otlp")]
+            (LogFormat::Otlp, Format::Otlp),
+        ];
+        for (log_format, format) in formats {
+            let capture = Capture::default();
+            let fields = global_fields::fields(&[("ticket".to_owned(), "ABC-123".to_owned())]);
+            let layer = log_format
+                .into_layer(capture.clone(), fields, &LineOptions::default(), false)
+                .with_filter(LevelFilter::DEBUG);
+            tracing::subscriber::with_default(Registry::default().with(layer), || {
+                info_span!("request", id = 7).in_scope(|| {
+                    warn!(target: "app", count = 3, text = "a \"quoted\"\nline", "plain text");
+                });
+            });
+
+            let output = capture.contents();
+            let records = output
+                .lines()
+                .map(|line| {
+                    let record = parse_line_as(line, format).unwrap();
+                    assert_eq!(parse_line(line).unwrap(), record, "{line}");
+                    chrono::DateTime::parse_from_rfc3339(&record.timestamp).unwrap();
+                    record
+                })
+                .collect::<Vec<_>>();
+            let event = records
+                .iter()
+                .find(|record| record.message == "plain text")
+                .unwrap_or_else(|| panic!("{format:?}: {output}"));
+            assert_eq!(event.level, "WARN");
+            assert_eq!(event.fields["count"], 3);
+            assert_eq!(event.fields["text"], "a \"quoted\"\nline");
+            assert_eq!(event.fields["ticket"], "ABC-123");
+            if format == Format::Json {
+                assert_eq!(event.target.as_deref(), Some("app"));
+                assert_eq!(event.span.as_deref(), Some("request"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ascii_only() {
+        for format in ["tiny", "compact", "pretty", "pretty-compact"] {
+            let render = |ansi: bool| {
+                let capture = Capture::default();
+                let layer = LogFormat::from_str(format)
+                    .unwrap()
+                    .into_layer(
+                        Escape::new(capture.clone(), false).with_ascii_only(!ansi),
+                        Fields::default(),
+                        &LineOptions {
+                            target_width: 24,
+                            ..LineOptions::default()
+                        },
+                        ansi,
+                    )
+                    .with_filter(LevelFilter::INFO);
+                tracing::subscriber::with_default(Registry::default().with(layer), || {
+                    info_span!("request", id = 7).in_scope(|| {
+                        warn!(target: "app", count = 3, "plain text");
+                    });
+                });
+                capture.contents()
+            };
+            let color = render(true);
+            assert!(color.contains('\x1b'), "{format}: {color}");
+
+            let ascii = render(false);
+            assert!(ascii.contains("plain text"), "{format}: {ascii}");
+            assert!(ascii.is_ascii(), "{format}: {ascii}");
+            assert!(!ascii.contains('\x1b'), "{format}: {ascii}");
+            assert_eq!(
+                strip_ansi(&color).lines().count(),
+                ascii.lines().count(),
+                "{format}"
+            );
+        }
     }
 }
@@ -1,47 +1,94 @@
 #![cfg(feature = "otlp")]
-use crate::{default_from_clap, Version};
+use super::{
+    global_fields::Fields,
+    lazy_export::{InitPolicy, LazyExporter},
+};
+use crate::{config_issue::ConfigIssue, default_from_clap, loss, telemetry, Version};
 use clap::Parser;
-use eyre::{eyre, Result as EyreResult};
-use heck::ToSnakeCase;
+use eyre::{bail, Result as EyreResult};
 use http::header::HeaderMap;
+use itertools::Itertools as _;
 use opentelemetry::{
     global::{self, get_text_map_propagator},
+    propagation::TextMapPropagator as _,
     runtime::Tokio,
     sdk::{
         propagation::TraceContextPropagator,
         trace::{self, RandomIdGenerator, Sampler, TracerProvider},
-        Resource,
     },
-    trace::TracerProvider as _,
-    KeyValue,
+    trace::{SpanContext, TraceContextExt as _, TracerProvider as _},
 };
 use opentelemetry_http::{HeaderExtractor, HeaderInjector};
-use opentelemetry_semantic_conventions::resource;
-use std::{env, error::Error, str::FromStr, time::Duration};
-use tracing::{error, Span, Subscriber};
+use opentelemetry_otlp::{
+    new_exporter, Protocol, SpanExporter, SpanExporterBuilder, TonicExporterBuilder,
+    WithExportConfig,
+};
+use std::{collections::HashMap, env, error::Error, path::Path, str::FromStr, time::Duration};
+use tonic::transport::Channel;
+use tracing::{error, info_span, warn, Span, Subscriber};
 use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
 use tracing_subscriber::{registry::LookupSpan, Layer};
 use url::Url;
 
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+
+/// Timeout for exporting a batch of spans.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
 #[group(skip)]
 pub struct Options {
     /// Push telemetry traces to an OpenTelemetry node.
-    /// Example: grpc://localhost:4317
+    /// Example: grpc://localhost:4317, or unix:///run/otel/collector.sock for
+    /// gRPC over a unix domain socket (without TLS).
     #[clap(long, env)]
     trace_otlp: Option<Url>,
 
     /// Attributes to set on the trace submitting entity. By default
-    /// `service.name` and `service.version` are set.
+    /// `service.name`, `service.version`, `service.instance.id` and some
+    /// process and host attributes are set.
     ///
     /// You can supply multiple arguments like
     /// `--trace-resource env=prod --trace-resource region=us-east-1`.
     ///
-    /// They can also be set via the `TRACE_RESOURCE_*` environment variables
-    /// where `*` is the attribute name converted to SHOUTY_SNAKE_CASE:
-    /// `TRACE_RESOURCE_SERVICE_NAMESPACE=prod`.
-    #[clap(long, value_parser = parse_key_val::<String, String>)]
+    /// They can also be set via `OTEL_RESOURCE_ATTRIBUTES=env=prod,...` or the
+    /// `TRACE_RESOURCE_*` environment variables where `*` is the attribute
+    /// name converted to SHOUTY_SNAKE_CASE:
+    /// `TRACE_RESOURCE_SERVICE_NAMESPACE=prod`. Arguments take precedence.
+    #[clap(long, alias = "otlp-resource", value_parser = parse_key_val::<String, String>)]
     trace_resource: Vec<(String, String)>,
+
+    /// Log filter for the OpenTelemetry export, in place of `--log-filter`,
+    /// to push more detail than the log output shows. Defaults to the filter
+    /// of the log output.
+    #[clap(long, env)]
+    otlp_log_filter: Option<String>,
+
+    /// Maximum time startup waits for the OpenTelemetry exporter.
+    #[clap(long, env, value_parser = humantime::parse_duration, default_value = "3s")]
+    telemetry_init_timeout: Duration,
+
+    /// What to do when the exporter is not ready in time: `degrade` buffers
+    /// spans and keeps trying in the background, `fail` aborts startup.
+    #[clap(long, env, value_enum, default_value = "degrade")]
+    telemetry_init_policy: InitPolicy,
+
+    /// CA certificate (PEM) to verify the OpenTelemetry node with.
+    #[cfg(feature = "tls")]
+    #[clap(long, env)]
+    otlp_tls_ca: Option<PathBuf>,
+
+    /// Client certificate chain (PEM) for mutual TLS with the OpenTelemetry
+    /// node.
+    #[cfg(feature = "tls")]
+    #[clap(long, env, requires = "otlp_tls_key")]
+    otlp_tls_cert: Option<PathBuf>,
+
+    /// Client private key (PEM) for mutual TLS with the OpenTelemetry node.
+    #[cfg(feature = "tls")]
+    #[clap(long, env, requires = "otlp_tls_cert")]
+    otlp_tls_key: Option<PathBuf>,
 }
 
 default_from_clap!(Options);
@@ -60,12 +107,118 @@ where
 }
 
 impl Options {
-    pub fn to_layer<S>(&self, version: &Version) -> EyreResult<impl Layer<S>>
+    /// Problems with the endpoint, options that need it and the TLS files.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if let Some(url) = &self.trace_otlp {
+            if let Err(err) = protocol(url) {
+                issues.push(
+                    ConfigIssue::error("trace-otlp", url, err)
+                        .suggest("like grpc://localhost:4317"),
+                );
+            } else if url.scheme() == "unix" && cfg!(not(unix)) {
+                issues.push(ConfigIssue::error(
+                    "trace-otlp",
+                    url,
+                    "unix domain sockets are not supported on this platform",
+                ));
+            }
+        } else if let Some(log_filter) = &self.otlp_log_filter {
+            issues.push(ConfigIssue::warning(
+                "otlp-log-filter",
+                log_filter,
+                "has no effect without --trace-otlp",
+            ));
+        }
+        #[cfg(feature = "tls")]
+        {
+            use crate::tls;
+            issues.extend(
+                self.otlp_tls_ca
+                    .as_deref()
+                    .and_then(|ca| tls::check_certs("otlp-tls-ca", ca)),
+            );
+            issues.extend(
+                self.otlp_tls_cert
+                    .as_deref()
+                    .and_then(|cert| tls::check_certs("otlp-tls-cert", cert)),
+            );
+            issues.extend(
+                self.otlp_tls_key
+                    .as_deref()
+                    .and_then(|key| tls::check_key("otlp-tls-key", key)),
+            );
+        }
+        issues
+    }
+
+    /// `--otlp-log-filter`, if set.
+    pub fn log_filter(&self) -> Option<&str> {
+        self.otlp_log_filter.as_deref()
+    }
+
+    /// Configure TLS on the exporter, if any TLS option is set. The files are
+    /// validated here so errors name the offending path.
+    #[cfg(feature = "tls")]
+    fn with_tls(&self, exporter: TonicExporterBuilder) -> EyreResult<TonicExporterBuilder> {
+        use crate::tls;
+        use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+        if self.otlp_tls_ca.is_none() && self.otlp_tls_cert.is_none() {
+            return Ok(exporter);
+        }
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.otlp_tls_ca {
+            let pem = tls::read(ca)?;
+            tls::certs(ca, &pem)?;
+            config = config.ca_certificate(Certificate::from_pem(pem));
+        }
+        if let (Some(cert), Some(key)) = (&self.otlp_tls_cert, &self.otlp_tls_key) {
+            let cert_pem = tls::read(cert)?;
+            tls::certs(cert, &cert_pem)?;
+            let key_pem = tls::read(key)?;
+            tls::key(key, &key_pem)?;
+            config = config.identity(Identity::from_pem(cert_pem, key_pem));
+        }
+        Ok(exporter.with_tls_config(config))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[allow(clippy::unnecessary_wraps, clippy::unused_self)]
+    fn with_tls(&self, exporter: TonicExporterBuilder) -> EyreResult<TonicExporterBuilder> {
+        Ok(exporter)
+    }
+
+    /// Create the exporter for `--trace-otlp`.
+    fn exporter(&self, url: &Url) -> EyreResult<SpanExporter> {
+        // See <https://docs.rs/opentelemetry-otlp/0.10.0/opentelemetry_otlp/#kitchen-sink-full-configuration>
+        let exporter = self.with_tls(
+            new_exporter()
+                .tonic()
+                .with_endpoint(url.to_string())
+                .with_protocol(protocol(url)?)
+                .with_timeout(EXPORT_TIMEOUT),
+        )?;
+        let exporter = if url.scheme() == "unix" {
+            exporter.with_channel(unix_channel(Path::new(url.path()))?)
+        } else {
+            exporter
+        };
+        Ok(SpanExporterBuilder::from(exporter).build_span_exporter()?)
+    }
+
+    pub fn to_layer<S>(&self, version: &Version, fields: &Fields) -> EyreResult<impl Layer<S>>
     where
         S: Subscriber + for<'a> LookupSpan<'a> + Sized + Send + Sync,
     {
-        // Propagate errors in the OpenTelemetry stack to the log.
+        // Propagate errors in the OpenTelemetry stack to the log. Spans dropped
+        // on a full export queue are counted instead, as there can be many.
         global::set_error_handler(|error| {
+            if matches!(&error, global::Error::Trace(err) if err.to_string().contains("channel is full"))
+            {
+                loss::SPANS_DROPPED.add(1);
+                return;
+            }
             error!("Error in OpenTelemetry: {:?}", eyre::Report::from(error));
         })?;
 
@@ -73,27 +226,7 @@ impl Options {
         // W3C Trace Context <https://www.w3.org/TR/trace-context/>
         global::set_text_map_propagator(TraceContextPropagator::new());
 
-        // Attributes for the trace generating entity.
-        // See https://opentelemetry.io/docs/reference/specification/resource/semantic_conventions/
-        let resource = {
-            let build = Resource::new([
-                resource::SERVICE_NAME.string(version.pkg_name),
-                resource::SERVICE_VERSION
-                    .string(format!("{}-{}", version.pkg_version, version.commit_hash)),
-            ]);
-            let env_vals = Resource::new(env::vars().filter_map(|(k, v)| {
-                k.strip_prefix("TRACE_RESOURCE_")
-                    .map(|k| KeyValue::new(k.to_snake_case().replace('_', "."), v))
-            }));
-            let cli = Resource::new(
-                self.trace_resource
-                    .iter()
-                    .map(|(k, v)| KeyValue::new(k.clone(), v.clone())),
-            );
-
-            // Order of precedence: command line arguments, environment, build info.
-            build.merge(&env_vals).merge(&cli)
-        };
+        let resource = telemetry::init(version, &self.trace_resource, fields).clone();
 
         let trace_config = trace::config()
             .with_sampler(Sampler::AlwaysOn)
@@ -104,32 +237,30 @@ impl Options {
             .with_resource(resource);
 
         if let Some(url) = &self.trace_otlp {
-            use opentelemetry_otlp::{new_exporter, new_pipeline, Protocol, WithExportConfig};
-
-            let protocol = match url.scheme() {
-                "http" => Protocol::HttpBinary,
-                "grpc" => Protocol::Grpc,
-                _ => {
-                    return Err(eyre!(
-                        "Invalid protocol: {} expecting 'http' or 'grpc'",
-                        url.scheme()
-                    ))
-                }
-            };
-
-            // See <https://docs.rs/opentelemetry-otlp/0.10.0/opentelemetry_otlp/#kitchen-sink-full-configuration>
-
-            let exporter = new_exporter()
-                .tonic()
-                .with_endpoint(url.to_string())
-                .with_protocol(protocol)
-                .with_timeout(Duration::from_secs(3));
+            // The scheme and TLS files are checked up front, retrying would not
+            // fix them.
+            protocol(url)?;
+            self.with_tls(new_exporter().tonic())?;
 
-            let tracer = new_pipeline()
-                .tracing()
-                .with_exporter(exporter)
-                .with_trace_config(trace_config)
-                .install_batch(Tokio)?;
+            let options = self.clone();
+            let url = url.clone();
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(
+                    LazyExporter::start(
+                        move || options.exporter(&url),
+                        self.telemetry_init_timeout,
+                        self.telemetry_init_policy,
+                    )?,
+                    Tokio,
+                )
+                .with_config(trace_config)
+                .build();
+            let tracer = provider.versioned_tracer(
+                "opentelemetry-otlp",
+                Some(env!("CARGO_PKG_VERSION")),
+                None,
+            );
+            let _old_provider = global::set_tracer_provider(provider);
 
             Ok(OpenTelemetryLayer::new(tracer)
                 .with_tracked_inactivity(true)
@@ -165,6 +296,374 @@ pub fn trace_to_headers(headers: &mut HeaderMap) {
     });
 }
 
+/// The W3C `traceparent` of the current span, if there is one.
+pub fn current_traceparent() -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+    carrier.remove("traceparent")
+}
+
+/// Make `span` a child of the span in a W3C `traceparent` value, which may be
+/// in another process.
+pub fn set_parent(span: &Span, traceparent: &str) {
+    let carrier = HashMap::from([("traceparent".to_owned(), traceparent.to_owned())]);
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
+
+/// Parse a W3C `traceparent` value. Invalid values are logged and ignored.
+fn parse_traceparent(traceparent: &str) -> Option<SpanContext> {
+    let carrier = HashMap::from([("traceparent".to_owned(), traceparent.to_owned())]);
+    let context = TraceContextPropagator::new().extract(&carrier);
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() {
+        Some(span_context)
+    } else {
+        warn!(traceparent, "Ignoring invalid traceparent for span link");
+        None
+    }
+}
+
+/// Link the current span to the span in a W3C `traceparent` value, as found
+/// in message headers.
+///
+/// Invalid values are logged and ignored.
+pub fn link_to(traceparent: &str) {
+    if let Some(context) = parse_traceparent(traceparent) {
+        Span::current().add_link(context);
+    }
+}
+
+/// Create a span that links to the spans in the W3C `traceparent` values, for
+/// example one processing span for a batch of messages from different traces.
+///
+/// The span is exported as `name`. The linked trace ids are recorded in the
+/// `links` field, which shows up in the span open event of the log. Invalid
+/// values are logged and ignored.
+pub fn span_with_links<I>(name: &str, traceparents: I) -> Span
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let links = traceparents
+        .into_iter()
+        .filter_map(|traceparent| parse_traceparent(traceparent.as_ref()))
+        .collect::<Vec<_>>();
+    let span = if links.is_empty() {
+        info_span!("span_with_links", otel.name = name)
+    } else {
+        let trace_ids = links.iter().map(SpanContext::trace_id).join(",");
+        info_span!("span_with_links", otel.name = name, links = %trace_ids)
+    };
+    for link in links {
+        span.add_link(link);
+    }
+    span
+}
+
+/// The transport for an endpoint URL.
+fn protocol(url: &Url) -> EyreResult<Protocol> {
+    Ok(match url.scheme() {
+        "http" => Protocol::HttpBinary,
+        "grpc" | "unix" => Protocol::Grpc,
+        scheme => bail!("Invalid protocol: {scheme} expecting 'http', 'grpc' or 'unix'"),
+    })
+}
+
+/// A gRPC channel over the unix domain socket at `path`.
+///
+/// The socket is connected once to fail early, the channel itself connects
+/// lazily like the TCP ones.
+#[cfg(unix)]
+fn unix_channel(path: &Path) -> EyreResult<Channel> {
+    use eyre::WrapErr as _;
+    use tokio::net::UnixStream;
+    use tonic::transport::{Endpoint, Uri};
+    use tower::service_fn;
+
+    std::os::unix::net::UnixStream::connect(path)
+        .wrap_err_with(|| format!("Can not connect to OpenTelemetry socket {}", path.display()))?;
+    let path = path.to_owned();
+    // The URI is only used for the HTTP/2 authority.
+    Ok(Endpoint::from_static("http://localhost")
+        .timeout(EXPORT_TIMEOUT)
+        .connect_with_connector_lazy(service_fn(move |_: Uri| UnixStream::connect(path.clone()))))
+}
+
+#[cfg(not(unix))]
+fn unix_channel(_path: &Path) -> EyreResult<Channel> {
+    bail!("Unix domain sockets are not supported on this platform")
+}
+
 pub fn shutdown() {
     global::shutdown_tracer_provider();
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        exit_hint,
+        trace::{
+            global_fields,
+            test::{mock_version, Capture},
+            LineOptions, LogFormat,
+        },
+    };
+    use eyre::WrapErr as _;
+    use futures::future::BoxFuture;
+    use opentelemetry::{
+        sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{Status, TraceId},
+    };
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    const PRODUCER_A: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+    const PRODUCER_B: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    /// Keeps exported spans in memory.
+    #[derive(Clone, Debug, Default)]
+    pub struct Memory(pub Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Memory {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn trace_id(traceparent: &str) -> TraceId {
+        parse_traceparent(traceparent).unwrap().trace_id()
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        assert!(parse_traceparent(PRODUCER_A).is_some());
+        assert!(parse_traceparent("").is_none());
+        assert!(parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-nothex-01").is_none());
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-b7ad6b7169203331-01").is_none()
+        );
+    }
+
+    #[test]
+    fn test_tags_in_resource() {
+        let options = Options::try_parse_from([
+            "arg0",
+            "--trace-resource",
+            "ticket=from-resource",
+            "--trace-resource",
+            "region=eu",
+        ])
+        .unwrap();
+        let fields = global_fields::fields(&[
+            ("ticket".to_owned(), "ABC-123".to_owned()),
+            ("attempt".to_owned(), "2".to_owned()),
+        ]);
+        let resource = telemetry::build(&mock_version(), [], &options.trace_resource, &fields);
+
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .with_config(trace::config().with_resource(resource))
+            .build();
+        let subscriber = Registry::default().with(OpenTelemetryLayer::new(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("request").in_scope(|| {});
+        });
+        drop(provider);
+
+        let spans = std::mem::take(&mut *memory.0.lock().unwrap());
+        assert_eq!(spans.len(), 1);
+        let attribute = |key: &'static str| {
+            spans[0]
+                .resource
+                .get(opentelemetry::Key::new(key))
+                .map(|value| value.to_string())
+        };
+        assert_eq!(attribute("ticket").as_deref(), Some("ABC-123"));
+        assert_eq!(attribute("attempt").as_deref(), Some("2"));
+        assert_eq!(attribute("region").as_deref(), Some("eu"));
+        assert_eq!(attribute("service.name").as_deref(), Some("test-app"));
+    }
+
+    /// A black-holed collector does not hold up startup.
+    #[tokio::test]
+    async fn test_unreachable_endpoint() {
+        let options = Options::try_parse_from([
+            "arg0",
+            "--trace-otlp",
+            "grpc://10.255.255.1:4317",
+            "--telemetry-init-timeout",
+            "1s",
+        ])
+        .unwrap();
+        let start = std::time::Instant::now();
+        let _layer = options
+            .to_layer::<Registry>(&mock_version(), &Fields::default())
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Spans arrive at a collector listening on a unix socket.
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unix_socket() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::UnixListener,
+            sync::oneshot,
+            time::timeout,
+        };
+
+        let path = env::temp_dir().join(format!("cli-batteries-otlp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let url = Url::parse(&format!("unix://{}", path.display())).unwrap();
+
+        // A minimal HTTP/2 peer: send empty settings and look for the span
+        // name in the (uncompressed) request body.
+        let (received, arrived) = oneshot::channel();
+        tokio::spawn(async move {
+            // The first connection is the check when creating the exporter.
+            drop(listener.accept().await.unwrap());
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let mut data = Vec::new();
+            let mut buffer = [0; 4096];
+            while !data.windows(13).any(|window| window == b"uds_test_span") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0, "connection closed");
+                data.extend_from_slice(&buffer[..n]);
+            }
+            let _ = received.send(());
+        });
+
+        let options = Options::try_parse_from(["arg0", "--trace-otlp", url.as_str()]).unwrap();
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(options.exporter(&url).unwrap(), Tokio)
+            .build();
+        let subscriber = Registry::default().with(OpenTelemetryLayer::new(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("uds_test_span").in_scope(|| {});
+        });
+        // Flushing blocks until the export completes, which this collector never
+        // answers.
+        tokio::task::spawn_blocking(move || provider.force_flush());
+        timeout(Duration::from_secs(10), arrived)
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_socket_missing() {
+        let url = Url::parse("unix:///nonexistent/collector.sock").unwrap();
+        let err = Options::default().exporter(&url).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can not connect to OpenTelemetry socket /nonexistent/collector.sock"
+        );
+        let url = Url::parse("ftp://localhost").unwrap();
+        assert!(Options::default().exporter(&url).is_err());
+    }
+
+    #[test]
+    fn test_main_span_error() {
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .build();
+        let subscriber = Registry::default().with(OpenTelemetryLayer::new(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = exit_hint::main_span();
+            let report = Err::<(), _>(exit_hint::test::nested_error())
+                .wrap_err("Error starting")
+                .unwrap_err();
+            exit_hint::record(&span, Some(&report));
+            exit_hint::record(&exit_hint::main_span(), None);
+        });
+        drop(provider);
+
+        let spans = std::mem::take(&mut *memory.0.lock().unwrap());
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.name == "main"));
+        let (failed, succeeded) = if spans[0].status == Status::Ok {
+            (&spans[1], &spans[0])
+        } else {
+            (&spans[0], &spans[1])
+        };
+        let attribute = |key: &'static str| {
+            failed
+                .attributes
+                .get(&opentelemetry::Key::new(key))
+                .map(ToString::to_string)
+        };
+        assert_eq!(attribute("error.chain_depth").as_deref(), Some("4"));
+        assert_eq!(
+            attribute("error.root_cause_type").as_deref(),
+            Some("std::io::error::Error")
+        );
+        assert_eq!(attribute("error.kind").as_deref(), Some("transient"));
+        assert_eq!(
+            failed.status,
+            Status::error(
+                "Error starting: Error fetching config: Error reading response: deadline exceeded"
+            )
+        );
+        assert_eq!(succeeded.status, Status::Ok);
+        assert_eq!(
+            succeeded
+                .attributes
+                .get(&opentelemetry::Key::new("error.kind")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_span_links() {
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .build();
+        let capture = Capture::default();
+        let subscriber = Registry::default()
+            .with(OpenTelemetryLayer::new(provider.tracer("test")))
+            .with(LogFormat::Json.into_layer(
+                capture.clone(),
+                Fields::default(),
+                &LineOptions::default(),
+                false,
+            ));
+        tracing::subscriber::with_default(subscriber, || {
+            let batch = span_with_links("batch", [PRODUCER_A, "garbage"]);
+            batch.in_scope(|| {
+                link_to(PRODUCER_B);
+                link_to("garbage");
+            });
+        });
+        // Shutting down waits for the exporter to finish.
+        drop(provider);
+
+        let spans = std::mem::take(&mut *memory.0.lock().unwrap());
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "batch");
+        let links = spans[0]
+            .links
+            .iter()
+            .map(|link| link.span_context.trace_id())
+            .collect::<Vec<_>>();
+        assert_eq!(links, [trace_id(PRODUCER_A), trace_id(PRODUCER_B)]);
+
+        let output = capture.contents();
+        assert!(
+            output.contains(&format!("\"links\":\"{}\"", trace_id(PRODUCER_A))),
+            "{output}"
+        );
+        assert!(output.contains("Ignoring invalid traceparent"), "{output}");
+    }
+}
@@ -1,10 +1,11 @@
 #![cfg(feature = "otlp")]
-use chrono::Utc;
+use super::{log_timestamp, thread_name};
 use serde::{ser::SerializeMap, Serializer};
 use serde_json::Value;
 use std::{
     fmt::{Error, Result},
-    io, thread,
+    io,
+    time::UNIX_EPOCH,
 };
 use tracing::{Event, Level, Subscriber};
 use tracing_opentelemetry::OtelData;
@@ -23,7 +24,16 @@ use tracing_subscriber::{
 // Note that span ids can get recycled and are not up to the standards from
 // OTLP. https://docs.rs/tracing-subscriber/latest/tracing_subscriber/struct.Registry.html#span-id-generation
 
-pub struct OtlpFormatter;
+pub struct OtlpFormatter {
+    /// Whether there is a `thread.name` attribute.
+    thread: bool,
+}
+
+impl OtlpFormatter {
+    pub const fn new(thread: bool) -> Self {
+        Self { thread }
+    }
+}
 
 #[derive(Debug)]
 pub struct TraceInfo {
@@ -53,7 +63,10 @@ where
             .or_else(|| ctx.lookup_current());
 
         // Event metadata
-        let timestamp = Utc::now().timestamp_millis();
+        // Always UTC, whatever `--log-local`
+        let timestamp = log_timestamp::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
         let mut trace_id = None;
         let mut span_id = span.as_ref().map(|s| s.id().into_u64());
         let (severity_text, severity_number) = match *meta.level() {
@@ -108,13 +121,9 @@ where
 
         // https://opentelemetry.io/docs/reference/specification/trace/semantic_conventions/span-general/#source-code-attributes
         // tracing-subscriber does. TODO (blocked): https://github.com/rust-lang/rust/issues/67939
-        attributes.insert(
-            "thread.name".into(),
-            thread::current().name().map_or_else(
-                || format!("{:?}", thread::current().id()).into(),
-                Into::into,
-            ),
-        );
+        if self.thread {
+            attributes.insert("thread.name".into(), thread_name().into());
+        }
 
         // Collect event fields
         let fields = serde_json::to_value(&event.field_map()).map_err(|_| Error)?;
@@ -158,7 +167,9 @@ where
         (|| {
             let mut serializer = serde_json::Serializer::new(WriteAdaptor::new(&mut writer));
             let mut log_map = serializer.serialize_map(None)?;
-            log_map.serialize_entry("Timestamp", &format_args!("{}", timestamp))?;
+            log_map.serialize_entry("Timestamp", &format_args!("{}", timestamp.as_millis()))?;
+            // As in the OTLP JSON encoding, a string for the 64 bit integer
+            log_map.serialize_entry("TimeUnixNano", &format_args!("{}", timestamp.as_nanos()))?;
             if let Some(trace_id) = trace_id {
                 log_map.serialize_entry("TraceId", &format_args!("{:032x}", trace_id))?;
             }
@@ -0,0 +1,59 @@
+use std::{fmt::Result, marker::PhantomData};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Indent events by the number of enclosing [`phase`](crate::phase) spans.
+pub struct PhaseIndent<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    inner:    Inner,
+    _phantom: PhantomData<(S, N)>,
+}
+
+impl<Inner, S, N> PhaseIndent<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    pub const fn new(inner: Inner) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<Inner, S, N> FormatEvent<S, N> for PhaseIndent<Inner, S, N>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let depth = ctx.event_scope().map_or(0, |scope| {
+            scope
+                .filter(|span| {
+                    span.name() == "phase" && span.metadata().target() == "cli_batteries::phase"
+                })
+                .count()
+        });
+        // The phase's own events line up with its parent.
+        let indent = depth.saturating_sub(1);
+        if indent > 0 {
+            write!(writer, "{:indent$}", "", indent = 2 * indent)?;
+        }
+        self.inner.format_event(ctx, writer, event)
+    }
+}
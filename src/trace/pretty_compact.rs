@@ -0,0 +1,275 @@
+//! Single line variant of the `pretty` log format.
+//!
+//! Every event is one line: the timestamp, uptime by default, colored level,
+//! the target padded to `--log-target-width`, the message, dimmed `key=value`
+//! fields and the span scope in brackets:
+//!
+//! ```text
+//!    0.001234 INFO  app::server              listening port=8080 [serve{addr="::"} > accept]
+//! ```
+//!
+//! Colors follow the ANSI setting of the writer.
+use super::{log_timestamp::Timer, thread_name};
+use ansi_term::{Colour, Style};
+use std::fmt::{Debug, Result, Write};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{
+        format::Writer, time::FormatTime, FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    registry::LookupSpan,
+};
+
+pub struct PrettyCompact {
+    timer:        Timer,
+    target_width: usize,
+    target:       bool,
+    thread:       bool,
+}
+
+impl PrettyCompact {
+    pub const fn new(target_width: usize, timer: Timer) -> Self {
+        Self {
+            timer,
+            target_width,
+            target: true,
+            thread: false,
+        }
+    }
+
+    /// Without the target column if not `target`.
+    pub const fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// With the thread name before the target.
+    pub const fn with_thread(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for PrettyCompact
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        let normalized_meta = event.normalized_metadata();
+        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
+        let ansi = writer.has_ansi_escapes();
+        let style = |style: Style| {
+            if ansi {
+                style
+            } else {
+                Style::new()
+            }
+        };
+        let dimmed = style(Style::new().dimmed());
+
+        // Timestamp
+        if !self.timer.is_none() {
+            write!(writer, "{}", dimmed.prefix())?;
+            self.timer.format_time(&mut writer)?;
+            write!(writer, "{} ", dimmed.suffix())?;
+        }
+
+        // Level badge
+        let level = style(
+            match *meta.level() {
+                Level::TRACE => Colour::Purple,
+                Level::DEBUG => Colour::Blue,
+                Level::INFO => Colour::Green,
+                Level::WARN => Colour::Yellow,
+                Level::ERROR => Colour::Red,
+            }
+            .bold(),
+        );
+        write!(
+            writer,
+            "{}{:<5}{} ",
+            level.prefix(),
+            meta.level(),
+            level.suffix()
+        )?;
+
+        // Thread
+        if self.thread {
+            write!(
+                writer,
+                "{}[{}]{} ",
+                dimmed.prefix(),
+                thread_name(),
+                dimmed.suffix()
+            )?;
+        }
+
+        // Target, longer ones push the rest of the line
+        if self.target {
+            write!(
+                writer,
+                "{}{:<width$}{} ",
+                dimmed.prefix(),
+                meta.target(),
+                dimmed.suffix(),
+                width = self.target_width
+            )?;
+        }
+
+        // Message and fields
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        write!(writer, "{}", visitor.message)?;
+        if !visitor.fields.is_empty() {
+            write!(
+                writer,
+                " {}{}{}",
+                dimmed.prefix(),
+                visitor.fields,
+                dimmed.suffix()
+            )?;
+        }
+
+        // Span scope
+        if let Some(scope) = ctx.event_scope() {
+            let mut separator = " [";
+            for span in scope.from_root() {
+                write!(writer, "{separator}{}", span.name())?;
+                let exts = span.extensions();
+                if let Some(fields) = exts.get::<FormattedFields<N>>() {
+                    if !fields.is_empty() {
+                        write!(writer, "{{{fields}}}")?;
+                    }
+                }
+                separator = " > ";
+            }
+            if separator != " [" {
+                write!(writer, "]")?;
+            }
+        }
+
+        writeln!(writer)
+    }
+}
+
+/// Collects the message and the other fields separately.
+#[derive(Default)]
+struct Visitor {
+    message: String,
+    fields:  String,
+}
+
+impl Visit for Visitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let name = field.name();
+        if name == "message" {
+            let _ = write!(self.message, "{value:?}");
+            return;
+        }
+        // Log metadata has already been handled
+        if name.starts_with("log.") {
+            return;
+        }
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let name = name.strip_prefix("r#").unwrap_or(name);
+        let _ = write!(self.fields, "{name}={value:?}");
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{log_timestamp::LogTimestamp, test::Capture};
+    use std::{env, fs, path::Path};
+    use tracing::{debug, error, info, info_span, warn};
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+    fn render(ansi: bool) -> String {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(capture.clone())
+                .with_ansi(ansi)
+                .event_format(PrettyCompact::new(12, Timer::new(LogTimestamp::None))),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", "plain");
+            warn!(target: "app::server", port = 8080, host = "example.com", "with fields");
+            info_span!("serve", addr = "::").in_scope(|| {
+                info_span!("accept").in_scope(|| {
+                    debug!(target: "app", r#type = "tcp", "in spans");
+                });
+            });
+            let err = std::io::Error::other("boom");
+            error!(target: "a_very_long_target_name", error = &err as &dyn std::error::Error, "long target");
+        });
+        capture.contents()
+    }
+
+    /// Compare with `tests/snapshots/<name>`, which is written instead when
+    /// `UPDATE_SNAPSHOTS` is set.
+    pub fn check_golden(name: &str, actual: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/snapshots")
+            .join(name);
+        if env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::write(&path, actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            actual, expected,
+            "{name} changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+        );
+    }
+
+    #[test]
+    fn test_golden() {
+        check_golden("pretty_compact.txt", &render(false));
+    }
+
+    #[test]
+    fn test_golden_ansi() {
+        let output = render(true);
+        assert!(output.contains('\x1b'));
+        check_golden("pretty_compact_ansi.txt", &output);
+    }
+
+    #[test]
+    fn test_uptime() {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            fmt::Layer::new()
+                .with_writer(capture.clone())
+                .with_ansi(false)
+                .event_format(PrettyCompact::new(4, Timer::new(LogTimestamp::Uptime))),
+        );
+        tracing::subscriber::with_default(subscriber, || info!(target: "app", "timed"));
+        let output = capture.contents();
+        assert!(output.starts_with("   0.0"), "{output}");
+        assert!(output.ends_with(" INFO  app  timed\n"), "{output}");
+    }
+}
@@ -0,0 +1,315 @@
+//! `--log-rate-limit`: suppress repeated log lines.
+//!
+//! A callsite in a hot loop can drown the log output and the program with it.
+//! [`RateLimited`] sits between the filter of the log output and its formatter
+//! and passes at most the limit of events per callsite, and so per target and
+//! level, every second. The suppressed events are counted in
+//! [`LOG_RATE_LIMITED`](crate::loss::LOG_RATE_LIMITED) and with the heartbeat
+//! [`log_report`] logs a summary per callsite, like `Suppressed 12,345 similar
+//! messages`.
+//!
+//! The log sinks, the session log, the binary log and the OpenTelemetry export
+//! are not wrapped, so the collector still sees every event.
+//!
+//! Counters live in one atomic per callsite. Events take a read lock to find
+//! it, only the first event of a callsite takes the write lock.
+use crate::loss::LOG_RATE_LIMITED;
+use once_cell::sync::Lazy;
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Instant,
+};
+use tracing::{
+    callsite::Identifier,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    warn, Dispatch, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Start of the one second windows.
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+static CALLSITES: Lazy<RwLock<HashMap<Identifier, Arc<Callsite>>>> = Lazy::new(RwLock::default);
+
+/// Passes at most `limit` events per callsite and second on to the inner
+/// layer, see the [module docs](self).
+pub struct RateLimited<L> {
+    /// `None` for no limit.
+    limit: Option<u64>,
+    inner: L,
+}
+
+impl<L> RateLimited<L> {
+    pub fn new(limit: Option<u32>, inner: L) -> Self {
+        Self {
+            limit: limit.map(u64::from),
+            inner,
+        }
+    }
+}
+
+impl<S, L> Layer<S> for RateLimited<L>
+where
+    S: Subscriber,
+    L: Layer<S>,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.inner.on_follows_from(span, follows, ctx);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.event_enabled(event, ctx)
+    }
+
+    // Counted here rather than in a filter, so only events that passed the
+    // filter of the log output count against the limit.
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if let Some(limit) = self.limit {
+            if !callsite(event.metadata()).allow(limit, START.elapsed().as_secs()) {
+                LOG_RATE_LIMITED.add(1);
+                return;
+            }
+        }
+        self.inner.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+
+    #[allow(unsafe_code)]
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(std::ptr::from_ref(self).cast())
+        } else {
+            // SAFETY: Forwarded to the inner layer with the same contract.
+            unsafe { self.inner.downcast_raw(id) }
+        }
+    }
+}
+
+/// The events of one callsite in the current window.
+struct Callsite {
+    metadata:   &'static Metadata<'static>,
+    window:     AtomicU64,
+    count:      AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl Callsite {
+    const fn new(metadata: &'static Metadata<'static>) -> Self {
+        Self {
+            metadata,
+            window: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Count an event at `second`, whether it is within `limit`. Events racing
+    /// the start of a window may be counted in the previous one.
+    fn allow(&self, limit: u64, second: u64) -> bool {
+        let window = self.window.load(Ordering::Relaxed);
+        if window != second
+            && self
+                .window
+                .compare_exchange(window, second, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < limit {
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+#[allow(clippy::missing_panics_doc)] // Never panics
+fn callsite(metadata: &'static Metadata<'static>) -> Arc<Callsite> {
+    let id = metadata.callsite();
+    if let Some(callsite) = CALLSITES.read().unwrap().get(&id) {
+        return callsite.clone();
+    }
+    CALLSITES
+        .write()
+        .unwrap()
+        .entry(id)
+        .or_insert_with(|| Arc::new(Callsite::new(metadata)))
+        .clone()
+}
+
+/// Callsites with events suppressed since the previous report and how many,
+/// sorted by target and line. Resets the counts.
+#[allow(clippy::missing_panics_doc)] // Never panics
+pub fn report() -> Vec<(&'static Metadata<'static>, u64)> {
+    let mut suppressed = CALLSITES
+        .read()
+        .unwrap()
+        .values()
+        .map(|callsite| {
+            (
+                callsite.metadata,
+                callsite.suppressed.swap(0, Ordering::Relaxed),
+            )
+        })
+        .filter(|(_, suppressed)| *suppressed > 0)
+        .collect::<Vec<_>>();
+    suppressed.sort_unstable_by_key(|(metadata, _)| (metadata.target(), metadata.line()));
+    suppressed
+}
+
+/// Log a summary of every callsite with events suppressed since the previous
+/// report.
+pub fn log_report() {
+    for (metadata, suppressed) in report() {
+        let location = format!(
+            "{}:{}",
+            metadata.file().unwrap_or("<unknown>"),
+            metadata.line().unwrap_or_default()
+        );
+        warn!(
+            callsite.target = metadata.target(),
+            callsite.level = %metadata.level(),
+            callsite.location = location,
+            suppressed,
+            "Suppressed {} similar messages",
+            grouped(suppressed)
+        );
+    }
+}
+
+/// `n` with thousands separators, like `12,345`.
+fn grouped(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` needs Rust 1.87
+        let separator = i > 0 && (digits.len() - i) % 3 == 0;
+        if separator {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use tracing::{info, info_span};
+    use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_window() {
+        let metadata = tracing::subscriber::with_default(Registry::default(), || {
+            info_span!("window").metadata().unwrap()
+        });
+        let callsite = Callsite::new(metadata);
+        let allowed = |second| (0..5).filter(|_| callsite.allow(3, second)).count();
+        assert_eq!(allowed(0), 3);
+        assert_eq!(allowed(0), 0);
+        assert_eq!(callsite.suppressed.load(Ordering::Relaxed), 7);
+        assert_eq!(allowed(1), 3);
+        assert_eq!(allowed(5), 3);
+    }
+
+    #[test]
+    fn test_grouped() {
+        assert_eq!(grouped(0), "0");
+        assert_eq!(grouped(999), "999");
+        assert_eq!(grouped(1000), "1,000");
+        assert_eq!(grouped(12_345), "12,345");
+        assert_eq!(grouped(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limited = Capture::default();
+        let unlimited = Capture::default();
+        let subscriber = Registry::default()
+            .with(
+                RateLimited::new(
+                    Some(2),
+                    fmt::Layer::new()
+                        .with_writer(limited.clone())
+                        .with_ansi(false),
+                )
+                .with_filter(LevelFilter::INFO),
+            )
+            .with(
+                fmt::Layer::new()
+                    .with_writer(unlimited.clone())
+                    .with_ansi(false)
+                    .with_filter(LevelFilter::INFO),
+            );
+        let dispatch = Dispatch::new(subscriber);
+        let lost = LOG_RATE_LIMITED.get();
+        tracing::dispatcher::with_default(&dispatch, || {
+            for i in 0..100 {
+                info!(i, "hot loop");
+            }
+        });
+        let shown = limited.contents().matches("hot loop").count();
+        // Two per second, the loop may straddle a second
+        assert!((2..=4).contains(&shown), "{shown}");
+        assert_eq!(unlimited.contents().matches("hot loop").count(), 100);
+        assert!(LOG_RATE_LIMITED.get() - lost >= 96);
+
+        tracing::dispatcher::with_default(&dispatch, log_report);
+        let contents = unlimited.contents();
+        let summary = contents
+            .lines()
+            .find(|line| line.contains("similar messages"))
+            .unwrap();
+        assert!(summary.contains(&format!("Suppressed {} similar messages", 100 - shown)));
+        assert!(summary.contains("callsite.target=\"cli_batteries::trace::rate_limit::test\""));
+        assert!(summary.contains("callsite.level=INFO"));
+        assert!(report().is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+//! The most recent warnings and errors, kept in memory.
+//!
+//! During an incident the first question is what the last errors were. WARN
+//! and ERROR events are kept in a ring of `--recent-errors-size` records,
+//! independent of the log filter. The ring is served as JSON on
+//! `/recent-errors` of the metrics server and, without the `prometheus`
+//! feature, dumped to stderr on `SIGUSR1`.
+//!
+//! Memory is bounded: messages and field values are truncated to
+//! [`MAX_VALUE_LEN`] bytes and at most [`MAX_FIELDS`] fields are kept.
+#![cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+use super::error_fields::ErrorReport;
+use chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::{Debug, Write as _},
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+pub const MAX_VALUE_LEN: usize = 1024;
+pub const MAX_FIELDS: usize = 16;
+
+static RING: OnceCell<Arc<Ring>> = OnceCell::new();
+
+/// A recorded event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    timestamp: String,
+    level:     &'static str,
+    target:    String,
+    message:   String,
+    fields:    Vec<(&'static str, String)>,
+    trace_id:  Option<String>,
+}
+
+impl Record {
+    fn to_json(&self) -> Value {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), Value::from(value.as_str())))
+            .collect::<Map<_, _>>();
+        json!({
+            "timestamp": self.timestamp,
+            "level": self.level,
+            "target": self.target,
+            "message": self.message,
+            "fields": fields,
+            "trace_id": self.trace_id,
+        })
+    }
+}
+
+/// Bounded buffer of the most recent records.
+pub struct Ring {
+    capacity: usize,
+    records:  Mutex<VecDeque<Record>>,
+}
+
+impl Ring {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, record: Record) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Oldest first.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.records().iter().map(Record::to_json).collect()
+    }
+}
+
+/// Make `ring` the one served by [`to_json`].
+pub fn publish(ring: Arc<Ring>) {
+    let _ = RING.set(ring);
+}
+
+/// The recent errors as a JSON array, oldest first.
+pub fn to_json() -> Value {
+    RING.get().map_or_else(|| json!([]), |ring| ring.to_json())
+}
+
+/// Adds every event it sees to the ring. Filter it to WARN.
+pub struct RecentErrors(pub Arc<Ring>);
+
+impl<S> Layer<S> for RecentErrors
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        self.0.push(Record {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            level:     meta.level().as_str(),
+            target:    truncate(meta.target().to_owned()),
+            message:   visitor.message,
+            fields:    visitor.fields,
+            trace_id:  trace_id(event, &ctx),
+        });
+    }
+}
+
+#[cfg(feature = "otlp")]
+pub fn trace_id<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Option<String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    use tracing_opentelemetry::OtelData;
+    ctx.event_scope(event)?.find_map(|span| {
+        let extensions = span.extensions();
+        let otel = extensions.get::<OtelData>()?;
+        otel.builder.trace_id.map(|id| id.to_string())
+    })
+}
+
+#[cfg(not(feature = "otlp"))]
+#[allow(clippy::unnecessary_wraps)]
+pub const fn trace_id<S>(_event: &Event<'_>, _ctx: &Context<'_, S>) -> Option<String> {
+    None
+}
+
+/// Cut `value` to [`MAX_VALUE_LEN`] bytes on a character boundary, marking
+/// the cut with `…`.
+fn truncate(mut value: String) -> String {
+    if value.len() > MAX_VALUE_LEN {
+        let mut end = MAX_VALUE_LEN;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+        value.push('…');
+    }
+    value
+}
+
+/// The message and fields of an event, truncated.
+#[derive(Default)]
+pub struct Visitor {
+    pub message: String,
+    pub fields:  Vec<(&'static str, String)>,
+}
+
+impl Visit for Visitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = truncate(value.to_owned());
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let name = field.name();
+        if name == "message" {
+            self.message = truncate(format!("{value:?}"));
+        } else if !name.starts_with("log.") && self.fields.len() < MAX_FIELDS {
+            let mut formatted = String::new();
+            let _ = write!(formatted, "{value:?}");
+            self.fields.push((name, truncate(formatted)));
+        }
+    }
+
+    /// The message of the error a run fails with is the full report, keep
+    /// only the error instead. The message is visited first.
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        if value.is::<ErrorReport>() {
+            self.message = truncate(value.to_string());
+        }
+        self.record_debug(field, &format_args!("{value}"));
+    }
+}
+
+/// Dump the ring to stderr on every `SIGUSR1`, one JSON record per line.
+#[cfg(all(unix, feature = "signals", not(feature = "prometheus")))]
+pub fn dump_on_signal() -> eyre::Result<()> {
+    use crate::shutdown::{process_receiver, wait};
+    use eyre::WrapErr as _;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 =
+        signal(SignalKind::user_defined1()).wrap_err("Could not install SIGUSR1 handler")?;
+    let mut shutdown = process_receiver();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                () = wait(&mut shutdown) => break,
+                received = usr1.recv() => {
+                    if received.is_none() {
+                        break;
+                    }
+                    if let Value::Array(records) = to_json() {
+                        for record in records {
+                            eprintln!("{record}");
+                        }
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing::{error, info, info_span, warn};
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Registry};
+
+    fn record(ring: &Arc<Ring>, f: impl FnOnce()) {
+        let subscriber =
+            Registry::default().with(RecentErrors(ring.clone()).with_filter(LevelFilter::WARN));
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    #[test]
+    fn test_ring_order() {
+        let ring = Arc::new(Ring::new(4));
+        record(&ring, || {
+            for i in 0..10 {
+                warn!(target: "app", i, "warning {i}");
+                info!(target: "app", "not recorded");
+            }
+            info_span!("request").in_scope(|| error!(target: "dep", code = 7, "failed"));
+        });
+        let records = ring.records();
+        assert_eq!(records.len(), 4);
+        let messages = records
+            .iter()
+            .map(|r| r.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["warning 7", "warning 8", "warning 9", "failed"]);
+        assert_eq!(records[0].fields, [("i", "7".to_owned())]);
+        assert_eq!(records[3].level, "ERROR");
+        assert_eq!(records[3].target, "dep");
+        assert_eq!(records[3].fields, [("code", "7".to_owned())]);
+        assert!(records.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        let json = ring.to_json();
+        assert_eq!(json[3]["message"], "failed");
+        assert_eq!(json[3]["fields"]["code"], "7");
+        assert_eq!(json[3]["trace_id"], Value::Null);
+    }
+
+    #[test]
+    fn test_truncation() {
+        let ring = Arc::new(Ring::new(2));
+        let long = "é".repeat(MAX_VALUE_LEN);
+        record(&ring, || {
+            warn!(target: "app", payload = %long, "{long}");
+        });
+        let record = &ring.records()[0];
+        assert_eq!(record.message.len(), MAX_VALUE_LEN + '…'.len_utf8());
+        assert!(record.message.ends_with("é…"));
+        assert_eq!(record.fields[0].1.len(), MAX_VALUE_LEN + '…'.len_utf8());
+
+        assert_eq!(truncate("short".to_owned()), "short");
+    }
+
+    #[test]
+    fn test_field_limit() {
+        let ring = Arc::new(Ring::new(1));
+        record(&ring, || {
+            warn!(
+                target: "app",
+                f00 = 0, f01 = 1, f02 = 2, f03 = 3, f04 = 4, f05 = 5, f06 = 6, f07 = 7,
+                f08 = 8, f09 = 9, f10 = 10, f11 = 11, f12 = 12, f13 = 13, f14 = 14, f15 = 15,
+                f16 = 16, f17 = 17, f18 = 18, f19 = 19, f20 = 20, f21 = 21, f22 = 22,
+                f23 = 23, f24 = 24, f25 = 25, f26 = 26, f27 = 27, f28 = 28, f29 = 29,
+                f30 = 30, "many fields"
+            );
+        });
+        let record = &ring.records()[0];
+        assert_eq!(record.message, "many fields");
+        assert_eq!(record.fields.len(), MAX_FIELDS);
+        assert_eq!(record.fields[0], ("f00", "0".to_owned()));
+        assert_eq!(record.fields[MAX_FIELDS - 1], ("f15", "15".to_owned()));
+    }
+
+    #[test]
+    fn test_error_report() {
+        let ring = Arc::new(Ring::new(1));
+        let error = ErrorReport(eyre::eyre!("caused").wrap_err("giving up"));
+        record(&ring, || {
+            error!(error = &error as &dyn Error, "{error:?}");
+        });
+        let record = &ring.records()[0];
+        assert_eq!(record.message, "giving up");
+        assert_eq!(record.fields, [("error", "giving up".to_owned())]);
+    }
+}
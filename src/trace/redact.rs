@@ -0,0 +1,490 @@
+//! `--log-redact-fields`: keep secrets out of the logs.
+//!
+//! [`Redact`] wraps the whole tracing stack and replaces the values of fields
+//! whose name matches one of the patterns with `"[REDACTED]"` before any layer
+//! sees them. So they reach neither the log output and sinks, nor the `otlp`
+//! format, the OpenTelemetry export or the session and binary logs. This
+//! holds for the fields of events, of spans when they are created and those
+//! recorded later with [`Span::record`](tracing::Span::record). The
+//! [`FieldsScopeLayer`](super::FieldsScopeLayer) redacts the fields of
+//! [`fields_scope`](super::fields_scope) itself.
+//!
+//! Patterns match field names ignoring case, with `*` for any number of
+//! characters and `?` for one, like `*token` for `access_token`.
+//!
+//! Callsites without matching fields pass through untouched. The values of the
+//! others are copied, which turns errors recorded in them into their text.
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+use tracing::{
+    callsite::Identifier,
+    field::{display, Field, ValueSet, Visit},
+    level_filters::LevelFilter,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Dispatch, Event, Metadata, Subscriber, Value,
+};
+use tracing_core::span::Current;
+use tracing_subscriber::{
+    filter::FilterId,
+    registry::{LookupSpan, SpanData},
+};
+
+/// The value of redacted fields.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Maximum number of fields of a callsite, see [`FieldSet::value_set`](
+/// tracing::field::FieldSet::value_set).
+const MAX_FIELDS: usize = 32;
+
+/// The patterns of `--log-redact-fields`, in lowercase.
+#[derive(Clone, Debug, Default)]
+pub struct RedactFields(Arc<[String]>);
+
+impl RedactFields {
+    pub fn new(patterns: &[String]) -> Self {
+        Self(
+            patterns
+                .iter()
+                .map(|pattern| pattern.to_lowercase())
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the value of the field `name` is redacted.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.0
+            .iter()
+            .any(|pattern| glob(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+/// Whether `name` matches `pattern`, with `*` for any run of bytes and `?` for
+/// one.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob(rest, name)
+                || name
+                    .split_first()
+                    .is_some_and(|(_, tail)| glob(pattern, tail))
+        }
+        (Some((expected, rest)), Some((byte, tail))) => {
+            (*expected == b'?' || expected == byte) && glob(rest, tail)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// Passes spans and events on to the inner subscriber with the values of
+/// matching fields replaced, see the [module docs](self).
+pub struct Redact<S> {
+    inner:     S,
+    fields:    RedactFields,
+    /// Whether a callsite has fields to redact.
+    callsites: RwLock<HashMap<Identifier, bool>>,
+}
+
+impl<S> Redact<S> {
+    pub fn new(inner: S, fields: RedactFields) -> Self {
+        Self {
+            inner,
+            fields,
+            callsites: RwLock::default(),
+        }
+    }
+
+    /// Whether `metadata` has fields to redact.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    fn redacts(&self, metadata: &'static Metadata<'static>) -> bool {
+        if self.fields.is_empty() {
+            return false;
+        }
+        let callsite = metadata.callsite();
+        if let Some(redacts) = self.callsites.read().unwrap().get(&callsite) {
+            return *redacts;
+        }
+        let redacts = metadata
+            .fields()
+            .iter()
+            .any(|field| self.fields.matches(field.name()));
+        self.callsites.write().unwrap().insert(callsite, redacts);
+        redacts
+    }
+
+    /// The values recorded by `record`, redacted.
+    fn collect(&self, record: impl FnOnce(&mut dyn Visit)) -> Vec<(Field, Owned)> {
+        let mut collect = Collect {
+            fields: &self.fields,
+            values: Vec::new(),
+        };
+        record(&mut collect);
+        collect.values
+    }
+}
+
+impl<S> Subscriber for Redact<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.inner.max_level_hint()
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let metadata = attrs.metadata();
+        if !self.redacts(metadata) {
+            return self.inner.new_span(attrs);
+        }
+        let values = self.collect(|visitor| attrs.record(visitor));
+        with_values(metadata, &values, |values| {
+            let attrs = match attrs.parent() {
+                Some(parent) => Attributes::child_of(parent.clone(), metadata, values),
+                None if attrs.is_root() => Attributes::new_root(metadata, values),
+                None => Attributes::new(metadata, values),
+            };
+            self.inner.new_span(&attrs)
+        })
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let metadata = if self.fields.is_empty() {
+            None
+        } else {
+            self.inner.span_data(span).map(|data| data.metadata())
+        };
+        match metadata {
+            Some(metadata) if self.redacts(metadata) => {
+                let values = self.collect(|visitor| values.record(visitor));
+                with_values(metadata, &values, |values| {
+                    self.inner.record(span, &Record::new(values));
+                });
+            }
+            _ => self.inner.record(span, values),
+        }
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.inner.record_follows_from(span, follows);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>) -> bool {
+        self.inner.event_enabled(event)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        if !self.redacts(metadata) {
+            return self.inner.event(event);
+        }
+        let values = self.collect(|visitor| event.record(visitor));
+        with_values(metadata, &values, |values| {
+            let event = match event.parent() {
+                Some(parent) => Event::new_child_of(parent.clone(), metadata, values),
+                None if event.is_root() => Event::new_child_of(None, metadata, values),
+                None => Event::new(metadata, values),
+            };
+            self.inner.event(&event);
+        });
+    }
+
+    fn enter(&self, span: &Id) {
+        self.inner.enter(span);
+    }
+
+    fn exit(&self, span: &Id) {
+        self.inner.exit(span);
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.inner.try_close(id)
+    }
+
+    fn current_span(&self) -> Current {
+        self.inner.current_span()
+    }
+
+    #[allow(unsafe_code)]
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(std::ptr::from_ref(self).cast())
+        } else {
+            // SAFETY: Forwarded to the inner subscriber with the same contract.
+            unsafe { self.inner.downcast_raw(id) }
+        }
+    }
+}
+
+impl<'a, S> LookupSpan<'a> for Redact<S>
+where
+    S: LookupSpan<'a>,
+{
+    type Data = S::Data;
+
+    fn span_data(&'a self, id: &Id) -> Option<Self::Data> {
+        self.inner.span_data(id)
+    }
+
+    fn register_filter(&mut self) -> FilterId {
+        self.inner.register_filter()
+    }
+}
+
+/// A field value, owned so it can be passed on in a new value set.
+enum Owned {
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Debug(String),
+}
+
+/// Collects values, with those of matching fields replaced.
+struct Collect<'a> {
+    fields: &'a RedactFields,
+    values: Vec<(Field, Owned)>,
+}
+
+impl Collect<'_> {
+    fn push(&mut self, field: &Field, value: Owned) {
+        let value = if self.fields.matches(field.name()) {
+            Owned::Str(REDACTED.to_owned())
+        } else {
+            value
+        };
+        self.values.push((field.clone(), value));
+    }
+}
+
+impl Visit for Collect<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, Owned::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Owned::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, Owned::U64(value));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.push(field, Owned::I128(value));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.push(field, Owned::U128(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Owned::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, Owned::Str(value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.push(field, Owned::Debug(format!("{value:?}")));
+    }
+}
+
+/// Call `f` with `values` as a value set of `metadata`. `Debug` values are
+/// passed on as their text, so they are formatted as before.
+fn with_values<T>(
+    metadata: &'static Metadata<'static>,
+    values: &[(Field, Owned)],
+    f: impl FnOnce(&ValueSet<'_>) -> T,
+) -> T {
+    let set = metadata.fields();
+    let Some(first) = set.iter().next() else {
+        return f(&set.value_set(&[]));
+    };
+    let shown = values
+        .iter()
+        .map(|(_, value)| match value {
+            Owned::Debug(text) => Some(display(text)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut entries: [(&Field, Option<&dyn Value>); MAX_FIELDS] = [(&first, None); MAX_FIELDS];
+    for (entry, ((field, value), shown)) in entries.iter_mut().zip(values.iter().zip(&shown)) {
+        let value: &dyn Value = match (value, shown) {
+            (Owned::I64(value), _) => value,
+            (Owned::U64(value), _) => value,
+            (Owned::I128(value), _) => value,
+            (Owned::U128(value), _) => value,
+            (Owned::F64(value), _) => value,
+            (Owned::Bool(value), _) => value,
+            (Owned::Str(value), _) => value,
+            (Owned::Debug(_), Some(shown)) => shown,
+            (Owned::Debug(_), None) => unreachable!("shown for every debug value"),
+        };
+        *entry = (field, Some(value));
+    }
+    f(&set.value_set(&entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::{
+        block_on, fields_scope, global_fields::Fields, test::Capture, FieldsScopeLayer,
+        LineOptions, LogFormat,
+    };
+    use tracing::{error, field, info, info_span};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    const SECRETS: [&str; 4] = ["hunter2", "Bearer s3cret", "xyz-token-value", "k3y"];
+
+    fn redact_fields() -> RedactFields {
+        let patterns = ["password", "AUTHORIZATION", "*token", "api_?ey"];
+        RedactFields::new(&patterns.map(str::to_owned))
+    }
+
+    /// Log a secret in every way a field can carry one.
+    fn emit_secrets() {
+        block_on(fields_scope([("api_key", "k3y")], async {
+            let span = info_span!(
+                "request",
+                authorization = "Bearer s3cret",
+                access_token = field::Empty,
+                id = 7
+            );
+            let _enter = span.enter();
+            span.record("access_token", "xyz-token-value");
+            info!(target: "app", Password = "hunter2", user = "ann", "login");
+            error!(target: "app", password = ?"hunter2", "failed");
+        }));
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob(b"token", b"token"));
+        assert!(!glob(b"token", b"tokens"));
+        assert!(glob(b"*token", b"access_token"));
+        assert!(glob(b"*token*", b"tokenizer"));
+        assert!(glob(b"api_?ey", b"api_key"));
+        assert!(!glob(b"api_?ey", b"api_ey"));
+        assert!(glob(b"*", b""));
+        assert!(!glob(b"a*b", b"acd"));
+
+        let fields = redact_fields();
+        assert!(fields.matches("Authorization"));
+        assert!(fields.matches("REFRESH_TOKEN"));
+        assert!(!fields.matches("user"));
+        assert!(!RedactFields::default().matches("password"));
+    }
+
+    #[test]
+    fn test_formats() {
+        let formats = [
+            LogFormat::Tiny,
+            LogFormat::Compact,
+            LogFormat::Pretty,
+            LogFormat::PrettyCompact,
+            LogFormat::Json,
+            LogFormat::Logfmt,
+            #[cfg(feature = "otlp")]
+            LogFormat::Otlp,
+        ];
+        for format in formats {
+            let capture = Capture::default();
+            let layer = format.into_layer(
+                capture.clone(),
+                Fields::default(),
+                &LineOptions::default(),
+                false,
+            );
+            let subscriber = Registry::default()
+                .with(layer)
+                .with(FieldsScopeLayer::redacting(redact_fields()));
+            let subscriber = Redact::new(subscriber, redact_fields());
+            tracing::subscriber::with_default(subscriber, emit_secrets);
+
+            let output = capture.contents();
+            for secret in SECRETS {
+                assert!(!output.contains(secret), "{format:?}: {output}");
+            }
+            assert!(output.contains(REDACTED), "{format:?}: {output}");
+            assert!(output.contains("ann"), "{format:?}: {output}");
+            assert!(output.contains("login"), "{format:?}: {output}");
+        }
+
+        // Without patterns nothing changes
+        let capture = Capture::default();
+        let layer = LogFormat::Json.into_layer(
+            capture.clone(),
+            Fields::default(),
+            &LineOptions::default(),
+            false,
+        );
+        let subscriber = Registry::default()
+            .with(layer)
+            .with(FieldsScopeLayer::default());
+        tracing::subscriber::with_default(
+            Redact::new(subscriber, RedactFields::default()),
+            emit_secrets,
+        );
+        let output = capture.contents();
+        assert!(output.contains("hunter2"), "{output}");
+        assert!(output.contains("Bearer s3cret"), "{output}");
+        assert!(!output.contains(REDACTED), "{output}");
+    }
+
+    #[test]
+    #[cfg(feature = "otlp")]
+    fn test_open_telemetry() {
+        use crate::trace::open_telemetry::test::Memory;
+        use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _};
+        use tracing_opentelemetry::OpenTelemetryLayer;
+
+        let memory = Memory::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(memory.clone())
+            .build();
+        let subscriber = Registry::default()
+            .with(OpenTelemetryLayer::new(provider.tracer("test")))
+            .with(FieldsScopeLayer::redacting(redact_fields()));
+        tracing::subscriber::with_default(Redact::new(subscriber, redact_fields()), emit_secrets);
+        drop(provider);
+
+        let spans = std::mem::take(&mut *memory.0.lock().unwrap());
+        let exported = format!("{spans:?}");
+        for secret in SECRETS {
+            assert!(!exported.contains(secret), "{exported}");
+        }
+        assert!(exported.contains(REDACTED), "{exported}");
+        assert!(exported.contains("ann"), "{exported}");
+    }
+}
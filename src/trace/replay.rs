@@ -0,0 +1,725 @@
+//! Recording events and replaying them through another log configuration.
+//!
+//! `--record-events <path>` writes the spans and events that pass the log
+//! filter to a file in the [binary log](super::binary_log) format, with the
+//! lifecycle of the spans: created with their parent and fields, recorded,
+//! entered, exited and closed. [`play`] and `--replay <path>` send them
+//! through a freshly built tracing stack configured by other log options,
+//! like another `--log-format` or `--log-filter`, to try changes to formats
+//! and filters on real events offline. Plain `--binary-log` files replay too,
+//! without spans.
+//!
+//! Replayed spans and events have the recorded parents, and the formatters
+//! see the recorded time through [`log_timestamp::at`], `uptime` counted from
+//! the first recorded span or event. Durations the formatters measure
+//! themselves, like the busy time of closed spans, are those of the replay.
+//! Replayed events have no file and line and at most [`MAX_FIELDS`] fields,
+//! the message included.
+#![cfg(feature = "binary-log")]
+use super::{
+    binary_log::{
+        self, level_from_u8, level_to_u8, BinaryLog, Captured, FieldValue, Frame, SpanRecord,
+    },
+    log_timestamp, BinaryLogReader, Options as TraceOptions,
+};
+use crate::{
+    config_issue::{self, ConfigIssue},
+    Version,
+};
+use clap::Parser;
+use eyre::{Result as EyreResult, WrapErr as _};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Write},
+    iter,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    callsite::{self, Callsite, Identifier},
+    dispatcher,
+    field::{display, Field, FieldSet, ValueSet},
+    metadata::Kind,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Dispatch, Event, Metadata, Span, Subscriber, Value,
+};
+use tracing_subscriber::{fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+
+/// Most fields of a replayed span or event, the rest is left out.
+pub const MAX_FIELDS: usize = 32;
+
+/// The `--record-events` output, for [`finish`].
+static RECORDING: OnceCell<Recorder> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Record the spans and events that pass the log filter to this file, for
+    /// `--replay`.
+    #[clap(long, env, value_name = "PATH")]
+    record_events: Option<PathBuf>,
+
+    /// Replay a `--record-events` or `--binary-log` file through the log
+    /// output configured by the other log options, and exit.
+    #[clap(long, value_name = "PATH")]
+    replay: Option<PathBuf>,
+}
+
+impl Options {
+    /// Create the recording file, if requested.
+    pub fn open(&self) -> EyreResult<Option<Recorder>> {
+        let Some(path) = &self.record_events else {
+            return Ok(None);
+        };
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Error creating recording {}", path.display()))?;
+        let recorder = Recorder::new(file).wrap_err("Error starting recording")?;
+        let _ = RECORDING.set(recorder.clone());
+        Ok(Some(recorder))
+    }
+
+    /// Whether the recording can be created.
+    pub fn validate(&self) -> Option<ConfigIssue> {
+        config_issue::writable("record-events", self.record_events.as_deref()?)
+    }
+
+    /// The `--replay` file, if requested.
+    pub fn requested(&self) -> Option<&Path> {
+        self.replay.as_deref()
+    }
+}
+
+/// Write the rest of the `--record-events` file and stop its writer thread.
+pub fn finish() -> EyreResult<()> {
+    if let Some(recorder) = RECORDING.get() {
+        recorder.finish().wrap_err("Error writing recording")?;
+    }
+    Ok(())
+}
+
+/// Layer recording spans and events for [`play`], see the
+/// [module docs](self).
+///
+/// Apply a filter, it records every span and event it receives.
+#[derive(Clone)]
+pub struct Recorder(BinaryLog);
+
+impl Recorder {
+    /// Write the file header to `writer` and start the writer thread.
+    ///
+    /// # Errors
+    ///
+    /// When the header can not be written or the thread not started.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        BinaryLog::new(writer).map(Self)
+    }
+
+    /// Write the rest of the recording and stop the writer thread. Later
+    /// spans and events are dropped.
+    ///
+    /// # Errors
+    ///
+    /// When writing failed, now or earlier.
+    pub fn finish(&self) -> io::Result<()> {
+        self.0.finish()
+    }
+}
+
+impl<S> Layer<S> for Recorder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.id().into_u64());
+        let timestamp = binary_log::timestamp();
+        let metadata = attrs.metadata();
+        let mut recorder = binary_log::Recorder::default();
+        attrs.record(&mut recorder);
+        self.0.write(|encoder, _, out| {
+            let span = SpanRecord {
+                id: id.into_u64(),
+                parent,
+                timestamp,
+                level: level_to_u8(*metadata.level()),
+                target: encoder.intern(metadata.target(), out),
+                name: encoder.intern(metadata.name(), out),
+                names: metadata
+                    .fields()
+                    .iter()
+                    .map(|field| encoder.intern(field.name(), out))
+                    .collect(),
+                fields: recorder.span_fields(encoder, out),
+            };
+            binary_log::write_frame(out, &Frame::NewSpan(span));
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        let mut recorder = binary_log::Recorder::default();
+        values.record(&mut recorder);
+        self.0.write(|encoder, _, out| {
+            let fields = recorder.span_fields(encoder, out);
+            binary_log::write_frame(out, &Frame::Values {
+                span: id.into_u64(),
+                fields,
+            });
+        });
+    }
+
+    fn on_enter(&self, id: &Id, _ctx: Context<'_, S>) {
+        self.0
+            .write(|_, _, out| binary_log::write_frame(out, &Frame::Enter(id.into_u64())));
+    }
+
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        self.0
+            .write(|_, _, out| binary_log::write_frame(out, &Frame::Exit(id.into_u64())));
+    }
+
+    fn on_close(&self, id: Id, _ctx: Context<'_, S>) {
+        self.0
+            .write(|_, _, out| binary_log::write_frame(out, &Frame::Close(id.into_u64())));
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let span = ctx.event_span(event);
+        let parent = span.as_ref().map(|span| span.id().into_u64());
+        let captured = Captured::new(event, span.map(|span| span.name()));
+        self.0.write(|encoder, fields, out| {
+            let event = captured.record(encoder, fields, true, out);
+            encoder.event(&Frame::Scoped { parent, event }, out);
+        });
+    }
+}
+
+/// Replay the recording at `path` into `writer`, returning the number of
+/// events replayed.
+///
+/// The log output is configured by the log options in `args`, like
+/// `["--log-format", "json"]`, as in a program of `version`. The session log
+/// and the other outputs [`Runner`](crate::Runner) adds at startup are left
+/// out.
+///
+/// # Errors
+///
+/// When `args` are not valid log options, or the recording can not be read.
+pub fn play<W>(path: &Path, version: &Version, args: &[&str], writer: W) -> EyreResult<u64>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let options = TraceOptions::try_parse_from(iter::once("replay").chain(args.iter().copied()))
+        .wrap_err("Invalid log options")?;
+    play_with(&options, path, version, writer)
+}
+
+/// Replay the recording at `path` through the tracing stack of `options`.
+pub(super) fn play_with<W>(
+    options: &TraceOptions,
+    path: &Path,
+    version: &Version,
+    writer: W,
+) -> EyreResult<u64>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let file =
+        File::open(path).wrap_err_with(|| format!("Error opening recording {}", path.display()))?;
+    let mut reader = BinaryLogReader::new(BufReader::new(file))?;
+    let (subscriber, flame) = options.subscriber(version, writer, Vec::new())?;
+    let dispatch = Dispatch::new(subscriber);
+    let events = dispatcher::with_default(&dispatch, || {
+        let mut player = Player::new(dispatch.clone());
+        while let Some(step) = reader.next_with(Step::new)? {
+            player.play(step);
+        }
+        Ok::<_, eyre::Report>(player.events)
+    })?;
+    if let Some(flame) = flame {
+        flame.flush()?;
+    }
+    Ok(events)
+}
+
+/// A recorded value, owned.
+#[derive(Clone, Debug)]
+enum Owned {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Debug(String),
+}
+
+impl Owned {
+    fn new(value: &FieldValue<'_>) -> Self {
+        match value {
+            FieldValue::I64(value) => Self::I64(*value),
+            FieldValue::U64(value) => Self::U64(*value),
+            FieldValue::F64(value) => Self::F64(*value),
+            FieldValue::Bool(value) => Self::Bool(*value),
+            FieldValue::Str(value) => Self::Str((*value).to_owned()),
+            FieldValue::Debug(value) => Self::Debug((*value).to_owned()),
+        }
+    }
+}
+
+/// A frame of a recording, with the names looked up.
+enum Step {
+    Span {
+        id:        u64,
+        parent:    Option<u64>,
+        timestamp: i64,
+        shape:     Shape,
+        fields:    Vec<(String, Owned)>,
+    },
+    Values {
+        span:   u64,
+        fields: Vec<(String, Owned)>,
+    },
+    Enter(u64),
+    Exit(u64),
+    Close(u64),
+    Event {
+        parent:    Option<u64>,
+        timestamp: i64,
+        shape:     Shape,
+        message:   String,
+        fields:    Vec<(String, Owned)>,
+    },
+}
+
+impl Step {
+    fn new(dictionary: &HashMap<u32, String>, frame: Frame<'_>) -> Option<Self> {
+        // Entries lost to corruption show as their number.
+        let lookup = |id: u32| {
+            dictionary
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| format!("#{id}"))
+        };
+        let fields = |fields: &[(u32, FieldValue<'_>)]| {
+            fields
+                .iter()
+                .map(|(name, value)| (lookup(*name), Owned::new(value)))
+                .collect::<Vec<_>>()
+        };
+        Some(match frame {
+            Frame::NewSpan(span) => Self::Span {
+                id:        span.id,
+                parent:    span.parent,
+                timestamp: span.timestamp,
+                shape:     Shape {
+                    span:   true,
+                    name:   lookup(span.name),
+                    target: lookup(span.target),
+                    level:  span.level,
+                    fields: span.names.iter().map(|name| lookup(*name)).collect(),
+                },
+                fields:    fields(&span.fields),
+            },
+            Frame::Values {
+                span,
+                fields: values,
+            } => Self::Values {
+                span,
+                fields: fields(&values),
+            },
+            Frame::Enter(span) => Self::Enter(span),
+            Frame::Exit(span) => Self::Exit(span),
+            Frame::Close(span) => Self::Close(span),
+            Frame::Event(event) => Self::event(None, &event, lookup, fields(&event.fields)),
+            Frame::Scoped { parent, event } => {
+                Self::event(parent, &event, lookup, fields(&event.fields))
+            }
+            Frame::Intern { .. } | Frame::Dictionary(_) => return None,
+        })
+    }
+
+    fn event(
+        parent: Option<u64>,
+        event: &binary_log::Record<'_>,
+        lookup: impl Fn(u32) -> String,
+        fields: Vec<(String, Owned)>,
+    ) -> Self {
+        let names = iter::once("message".to_owned())
+            .chain(fields.iter().map(|(name, _)| name.clone()))
+            .collect();
+        Self::Event {
+            parent,
+            timestamp: event.timestamp,
+            shape: Shape {
+                span:   false,
+                name:   "event".to_owned(),
+                target: lookup(event.target),
+                level:  event.level,
+                fields: names,
+            },
+            message: event.message.to_owned(),
+            fields,
+        }
+    }
+}
+
+/// What makes up a callsite of replayed spans or events.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Shape {
+    /// A span rather than an event.
+    span:   bool,
+    name:   String,
+    target: String,
+    level:  u8,
+    fields: Vec<String>,
+}
+
+/// A callsite of replayed spans or events of one [`Shape`]. Leaked, like
+/// the static callsites of the `tracing` macros.
+struct Replayed(OnceCell<Metadata<'static>>);
+
+impl Callsite for Replayed {
+    fn set_interest(&self, _interest: Interest) {}
+
+    fn metadata(&self) -> &Metadata<'_> {
+        self.0.get().expect("set before the callsite is registered")
+    }
+}
+
+impl Shape {
+    fn into_metadata(self) -> &'static Metadata<'static> {
+        let leak = |value: String| -> &'static str { Box::leak(value.into_boxed_str()) };
+        let callsite: &'static Replayed = Box::leak(Box::new(Replayed(OnceCell::new())));
+        let fields = self
+            .fields
+            .into_iter()
+            .take(MAX_FIELDS)
+            .map(leak)
+            .collect::<Vec<_>>();
+        let fields = FieldSet::new(Box::leak(fields.into_boxed_slice()), Identifier(callsite));
+        let metadata = Metadata::new(
+            leak(self.name),
+            leak(self.target),
+            level_from_u8(self.level),
+            None,
+            None,
+            None,
+            fields,
+            if self.span { Kind::SPAN } else { Kind::EVENT },
+        );
+        let _ = callsite.0.set(metadata);
+        callsite::register(callsite);
+        callsite.0.get().unwrap()
+    }
+}
+
+/// The replay state.
+struct Player {
+    /// The replay stack, also the default dispatcher. Called directly rather
+    /// than through [`dispatcher::get_default`], which would hide it from the
+    /// subscriber itself, like the registry closing an exited span.
+    dispatch:  Dispatch,
+    callsites: HashMap<Shape, &'static Metadata<'static>>,
+    /// Replayed spans by recorded id. Closed spans are removed, the id may
+    /// be used again.
+    spans:     HashMap<u64, Span>,
+    /// Time of the first recorded span or event, the start of `uptime`.
+    start:     Option<i64>,
+    /// Time of the latest recorded span or event.
+    now:       i64,
+    events:    u64,
+}
+
+impl Player {
+    fn new(dispatch: Dispatch) -> Self {
+        Self {
+            dispatch,
+            callsites: HashMap::new(),
+            spans: HashMap::new(),
+            start: None,
+            now: 0,
+            events: 0,
+        }
+    }
+
+    fn play(&mut self, step: Step) {
+        match step {
+            Step::Span {
+                id,
+                parent,
+                timestamp,
+                shape,
+                fields,
+            } => {
+                let metadata = self.callsite(shape);
+                let parent = self.parent(parent);
+                let (time, uptime) = self.clock(timestamp);
+                let span = log_timestamp::at(time, uptime, || {
+                    if !self.dispatch.enabled(metadata) {
+                        return Span::none();
+                    }
+                    with_values(metadata, &fields, None, |values| {
+                        Span::child_of(parent, metadata, values)
+                    })
+                });
+                self.spans.insert(id, span);
+            }
+            Step::Values { span, fields } => {
+                if let Some(span) = self.spans.get(&span) {
+                    for (name, value) in &fields {
+                        span.record(name.as_str(), as_value(value, &display(value_text(value))));
+                    }
+                }
+            }
+            Step::Enter(span) => {
+                if let Some(id) = self.spans.get(&span).and_then(Span::id) {
+                    let (time, uptime) = self.clock(self.now);
+                    log_timestamp::at(time, uptime, || self.dispatch.enter(&id));
+                }
+            }
+            Step::Exit(span) => {
+                if let Some(id) = self.spans.get(&span).and_then(Span::id) {
+                    let (time, uptime) = self.clock(self.now);
+                    log_timestamp::at(time, uptime, || self.dispatch.exit(&id));
+                }
+            }
+            Step::Close(span) => {
+                if let Some(span) = self.spans.remove(&span) {
+                    let (time, uptime) = self.clock(self.now);
+                    log_timestamp::at(time, uptime, || drop(span));
+                }
+            }
+            Step::Event {
+                parent,
+                timestamp,
+                shape,
+                message,
+                fields,
+            } => {
+                let metadata = self.callsite(shape);
+                let parent = self.parent(parent);
+                let (time, uptime) = self.clock(timestamp);
+                self.events += 1;
+                if self.dispatch.enabled(metadata) {
+                    log_timestamp::at(time, uptime, || {
+                        with_values(metadata, &fields, Some(&message), |values| {
+                            Event::child_of(parent, metadata, values);
+                        });
+                    });
+                }
+            }
+        }
+    }
+
+    fn callsite(&mut self, shape: Shape) -> &'static Metadata<'static> {
+        if let Some(metadata) = self.callsites.get(&shape) {
+            return metadata;
+        }
+        let metadata = shape.clone().into_metadata();
+        self.callsites.insert(shape, metadata);
+        metadata
+    }
+
+    /// The replayed span of the recorded `parent`. `None` if it was not
+    /// recorded or not replayed, the span or event is then a root.
+    fn parent(&self, parent: Option<u64>) -> Option<Id> {
+        parent.and_then(|parent| self.spans.get(&parent)?.id())
+    }
+
+    /// Move the clock to the recorded `timestamp`, returning the time and
+    /// `uptime` for [`log_timestamp::at`].
+    fn clock(&mut self, timestamp: i64) -> (SystemTime, Duration) {
+        let start = *self.start.get_or_insert(timestamp);
+        self.now = timestamp;
+        let nanos = |nanos: i64| Duration::from_nanos(u64::try_from(nanos).unwrap_or_default());
+        (UNIX_EPOCH + nanos(timestamp), nanos(timestamp - start))
+    }
+}
+
+/// The text of a value recorded with `Debug`.
+fn value_text(value: &Owned) -> &str {
+    match value {
+        Owned::Str(text) | Owned::Debug(text) => text,
+        _ => "",
+    }
+}
+
+/// `value` as recorded, with `shown` standing in for `Debug` values.
+fn as_value<'a>(value: &'a Owned, shown: &'a dyn Value) -> &'a dyn Value {
+    match value {
+        Owned::I64(value) => value,
+        Owned::U64(value) => value,
+        Owned::F64(value) => value,
+        Owned::Bool(value) => value,
+        Owned::Str(value) => value,
+        Owned::Debug(_) => shown,
+    }
+}
+
+/// Call `f` with the values of `fields`, and the `message` of an event, in
+/// the order of the fields of `metadata`.
+fn with_values<T>(
+    metadata: &'static Metadata<'static>,
+    fields: &[(String, Owned)],
+    message: Option<&str>,
+    f: impl FnOnce(&ValueSet<'_>) -> T,
+) -> T {
+    let set = metadata.fields();
+    let Some(first) = set.iter().next() else {
+        return f(&set.value_set(&[]));
+    };
+    let shown = fields
+        .iter()
+        .map(|(_, value)| display(value_text(value)))
+        .collect::<Vec<_>>();
+    let message = message.map(display);
+    let mut values = fields
+        .iter()
+        .zip(&shown)
+        .filter_map(|((name, value), shown)| Some((set.field(name)?, as_value(value, shown))))
+        .collect::<Vec<(Field, &dyn Value)>>();
+    if let (Some(message), Some(field)) = (&message, set.field("message")) {
+        values.push((field, message));
+    }
+    let mut entries: [(&Field, Option<&dyn Value>); MAX_FIELDS] = [(&first, None); MAX_FIELDS];
+    for (entry, (field, value)) in entries.iter_mut().zip(&values) {
+        *entry = (field, Some(*value));
+    }
+    f(&set.value_set(&entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::test::{mock_version, Capture};
+    use std::{env, fs};
+    use tracing::{debug, field, info, info_span, warn};
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Registry};
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay/recording.bin")
+    }
+
+    /// The events of the fixture.
+    fn scenario() {
+        info!(version = "1.2.3", "Starting");
+        let request = info_span!("request", id = 7_u64, user = field::Empty);
+        request.in_scope(|| {
+            debug!(path = "/items", "Handling");
+            request.record("user", "alice");
+            info_span!("query", table = "items").in_scope(|| {
+                info!(rows = 3_i64, cached = false, ratio = 0.5, "Fetched");
+            });
+            warn!(status = ?Some(404), "Not found");
+        });
+        drop(request);
+        info!("Done");
+    }
+
+    /// Run with `--ignored` to record the fixture again, then update the
+    /// snapshots with `UPDATE_SNAPSHOTS=1`.
+    #[test]
+    #[ignore = "records the fixture"]
+    fn record_fixture() {
+        let capture = Capture::default();
+        let recorder = Recorder::new(capture.clone()).unwrap();
+        let subscriber = Registry::default().with(recorder.clone().with_filter(LevelFilter::DEBUG));
+        tracing::subscriber::with_default(subscriber, scenario);
+        recorder.finish().unwrap();
+        fs::write(fixture(), capture.bytes()).unwrap();
+    }
+
+    fn replay(args: &[&str]) -> String {
+        let capture = Capture::default();
+        let version = mock_version();
+        play(&fixture(), &version, args, capture.clone()).unwrap();
+        capture.contents()
+    }
+
+    /// Set `UPDATE_SNAPSHOTS=1` to update the snapshots after an intentional
+    /// change.
+    #[test]
+    fn test_golden() {
+        for format in [
+            "tiny",
+            "compact",
+            "pretty",
+            "pretty-compact",
+            "json",
+            "logfmt",
+        ] {
+            let actual = replay(&[
+                "--log-format",
+                format,
+                "--log-filter",
+                "cli_batteries=debug",
+                // Busy and idle times of closed spans are those of the replay
+                "--log-span-events",
+                "new",
+            ]);
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/snapshots/replay")
+                .join(format!("{format}.txt"));
+            if env::var("UPDATE_SNAPSHOTS").is_ok() {
+                fs::write(&path, &actual).unwrap();
+            }
+            let expected = fs::read_to_string(&path).unwrap();
+            assert_eq!(
+                actual, expected,
+                "{format} replay changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let output = replay(&[
+            "--log-format",
+            "compact",
+            "--log-filter",
+            "cli_batteries=info",
+        ]);
+        assert!(output.contains("Starting"), "{output}");
+        assert!(!output.contains("Handling"), "{output}");
+        // Parents and values recorded later are kept
+        assert!(
+            output.contains("request:query: cli_batteries::trace::replay::test: rows=3"),
+            "{output}"
+        );
+        assert!(
+            output.contains("id=7 user=\"alice\" table=\"items\""),
+            "{output}"
+        );
+        assert!(output.contains("status=Some(404)"), "{output}");
+        assert!(output.contains("query span=end"), "{output}");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let output = replay(&[
+            "--log-format",
+            "json",
+            "--log-filter",
+            "cli_batteries=info",
+            "--log-span-events",
+            "none",
+        ]);
+        let timestamps = output
+            .lines()
+            .map(|line| crate::logs::parse_line(line).unwrap().timestamp)
+            .collect::<Vec<_>>();
+        let mut reader =
+            BinaryLogReader::new(BufReader::new(File::open(fixture()).unwrap())).unwrap();
+        let mut recorded = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            if record.level != "DEBUG" {
+                recorded.push(record.timestamp);
+            }
+        }
+        assert_eq!(timestamps, recorded);
+    }
+}
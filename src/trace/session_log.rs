@@ -0,0 +1,509 @@
+//! Full log of every invocation in a file.
+//!
+//! Interactive tools get a JSON log of each run at DEBUG (TRACE for the app
+//! crates) in `~/.local/state/<app>/logs/<timestamp>-<pid>.log`, regardless of
+//! the stderr verbosity, so it can be attached to a support request. Only
+//! the newest `--session-log-keep` files are kept. The path is logged after
+//! the error report when the run fails.
+//!
+//! With `--log-file-compress` the log is zstd compressed to `.log.zst`, in
+//! frames of at most [`FLUSH_INTERVAL`](file_output::FLUSH_INTERVAL), so after
+//! a crash all but the last moments can still be read with
+//! [`read_compressed`](crate::logs::read_compressed).
+use super::{
+    disk_full::{DiskFull, DiskFullPolicy},
+    file_output::{self, FileOutput, EXTENSION},
+    global_fields::Fields,
+    LineOptions, LogFormat,
+};
+use crate::{
+    config_issue::{self, ConfigIssue},
+    default_from_clap, i18n, Version,
+};
+use chrono::Utc;
+use clap::Parser;
+use eyre::{bail, eyre, Result as EyreResult, WrapErr as _};
+use once_cell::sync::OnceCell;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, stderr, stdin, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::id as pid,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, Level, Subscriber};
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+static PATH: OnceCell<PathBuf> = OnceCell::new();
+static FILE: OnceCell<Arc<LogFile>> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mode {
+    On,
+    Off,
+    Dir(PathBuf),
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(Self::On),
+            "off" => Ok(Self::Off),
+            _ => match s.strip_prefix("dir=") {
+                Some(dir) if !dir.is_empty() => Ok(Self::Dir(dir.into())),
+                _ => Err(format!("expected on, off or dir=<path>, got `{s}`")),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Field names are the flag names
+pub struct Options {
+    /// Write a full debug log of every run to a file: 'on' (in
+    /// ~/.local/state/<app>/logs), 'off' or 'dir=<path>'. On by default when
+    /// run interactively.
+    #[clap(long, env)]
+    session_log: Option<Mode>,
+
+    /// Number of session log files to keep.
+    #[clap(long, env, default_value_t = 20)]
+    session_log_keep: usize,
+}
+
+default_from_clap!(Options);
+
+/// An opened session log file.
+pub struct SessionLog {
+    path: PathBuf,
+    file: LogFile,
+}
+
+/// Session log output, optionally compressed. `None` after [`finish`], later
+/// writes are dropped.
+pub struct LogFile(Mutex<Option<FileOutput>>);
+
+impl Options {
+    /// Prune old session logs and create the one for this run, if enabled,
+    /// zstd compressed if `compress`.
+    ///
+    /// When not explicitly enabled, failures are reported on stderr and the
+    /// run continues without a session log.
+    pub fn open(&self, version: &Version, compress: bool) -> EyreResult<Option<SessionLog>> {
+        let dir = match &self.session_log {
+            Some(Mode::Off) => return Ok(None),
+            Some(Mode::Dir(dir)) => Ok(dir.clone()),
+            None if !(stderr().is_terminal() && stdin().is_terminal()) => return Ok(None),
+            Some(Mode::On) | None => default_dir(version.pkg_name),
+        };
+        let open = |dir: PathBuf| open_in(&dir, self.session_log_keep, compress);
+        match dir.and_then(open) {
+            Ok(session_log) => Ok(Some(session_log)),
+            Err(err) if self.session_log.is_none() => {
+                let error = format!("{err:#}");
+                eprintln!(
+                    "{}",
+                    i18n::text("session-log-disabled", &[("error", &error)])
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Problems that keep the session log from being written. Those of a
+    /// session log that is not explicitly enabled are warnings, without it the
+    /// run continues.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if self.session_log == Some(Mode::Off) {
+            return issues;
+        }
+        if self.session_log_keep == 0 {
+            let issue = ConfigIssue::error("session-log-keep", 0, "must be at least 1");
+            issues.push(if self.session_log.is_some() {
+                issue
+            } else {
+                ConfigIssue::warning(&issue.field, &issue.value, &issue.problem)
+            });
+        }
+        if let Some(Mode::Dir(dir)) = &self.session_log {
+            issues.extend(config_issue::writable_dir("session-log", dir));
+        }
+        issues
+    }
+
+    /// The log directory of a daemon. A daemon has no terminal to decide by,
+    /// so the session log is on unless explicitly disabled.
+    #[cfg(all(unix, feature = "daemonize"))]
+    pub fn daemon_dir(&mut self, version: &Version) -> EyreResult<Option<PathBuf>> {
+        let dir = self.dir(version)?;
+        if let Some(dir) = &dir {
+            self.session_log = Some(Mode::Dir(dir.clone()));
+        }
+        Ok(dir)
+    }
+
+    /// The log directory, whether or not this run writes a log to it. `None`
+    /// if session logs are off.
+    #[cfg(any(feature = "support-bundle", all(unix, feature = "daemonize")))]
+    pub fn dir(&self, version: &Version) -> EyreResult<Option<PathBuf>> {
+        Ok(match &self.session_log {
+            Some(Mode::Off) => None,
+            Some(Mode::Dir(dir)) => Some(dir.clone()),
+            Some(Mode::On) | None => Some(default_dir(version.pkg_name)?),
+        })
+    }
+}
+
+impl SessionLog {
+    /// Log output to the file, with its own filter. Remembers the path for
+    /// [`log_path`] and the file for [`finish`].
+    pub fn into_layer<S>(
+        self,
+        version: &Version,
+        fields: Fields,
+        disk_full: DiskFullPolicy,
+    ) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        let _ = PATH.set(self.path);
+        let file = Arc::new(self.file);
+        let _ = FILE.set(file.clone());
+        if file.is_compressed() {
+            file_output::end_frames_periodically(Arc::downgrade(&file), LogFile::end_frame);
+        }
+        let writer = Arc::new(DiskFull::new("session log", file, disk_full));
+        LogFormat::Json
+            .into_layer(writer, fields, &LineOptions::default(), false)
+            .with_filter(targets(version))
+    }
+}
+
+impl LogFile {
+    const fn new(file: File, compress: bool) -> Self {
+        Self(Mutex::new(Some(FileOutput::new(file, compress))))
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(FileOutput::is_compressed)
+    }
+
+    /// End the current frame of a compressed log, whether the log is still
+    /// open.
+    fn end_frame(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|output| output.end_frame().is_ok())
+    }
+
+    /// Complete the file. Later writes are dropped.
+    fn finish(&self) -> io::Result<()> {
+        let output = self.0.lock().unwrap().take();
+        output.map_or(Ok(()), |output| output.finish().map(drop))
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map_or(Ok(buf.len()), |output| output.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().as_mut().map_or(Ok(()), Write::flush)
+    }
+}
+
+/// DEBUG for everything, TRACE for the app.
+fn targets(version: &Version) -> Targets {
+    Targets::new()
+        .with_default(Level::DEBUG)
+        .with_targets(version.app_crates.iter().map(|c| (c, Level::TRACE)))
+}
+
+/// `$XDG_STATE_HOME/<app>/logs`, by default in `~/.local/state`.
+fn default_dir(app: &str) -> EyreResult<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .ok_or_else(|| eyre!("HOME is not set"))?;
+    Ok(state.join(app).join("logs"))
+}
+
+/// Create the log file for this run in `dir`, keeping `keep` files in total.
+fn open_in(dir: &Path, keep: usize, compress: bool) -> EyreResult<SessionLog> {
+    if keep == 0 {
+        bail!("--session-log-keep must be at least 1");
+    }
+    fs::create_dir_all(dir)
+        .wrap_err_with(|| format!("Error creating session log directory {}", dir.display()))?;
+    prune(dir, keep - 1)?;
+    let name = format!(
+        "{}-{}.log{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        pid(),
+        if compress {
+            format!(".{EXTENSION}")
+        } else {
+            String::new()
+        }
+    );
+    let path = dir.join(name);
+    let file = File::create(&path)
+        .wrap_err_with(|| format!("Error creating session log {}", path.display()))?;
+    Ok(SessionLog {
+        path,
+        file: LogFile::new(file, compress),
+    })
+}
+
+/// The log files in `dir`, compressed or not, oldest first. The names start
+/// with the time, so they sort by age.
+pub fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut logs = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    logs.retain(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let compressed = format!(".log.{EXTENSION}");
+        (name.ends_with(".log") || name.ends_with(&compressed)) && path.is_file()
+    });
+    logs.sort();
+    Ok(logs)
+}
+
+/// Remove all but the newest `keep` log files in `dir`.
+fn prune(dir: &Path, keep: usize) -> EyreResult<()> {
+    let logs = list(dir)?;
+    let excess = logs.len().saturating_sub(keep);
+    for path in &logs[..excess] {
+        fs::remove_file(path)
+            .wrap_err_with(|| format!("Error removing session log {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The session log of this run, if any.
+pub fn path() -> Option<&'static Path> {
+    PATH.get().map(PathBuf::as_path)
+}
+
+/// Flush and complete the session log, if any.
+pub fn finish() -> EyreResult<()> {
+    if let Some(file) = FILE.get() {
+        file.finish().wrap_err("Error writing session log")?;
+    }
+    Ok(())
+}
+
+/// Point to the session log after an error report.
+pub fn log_path(path: Option<&Path>) {
+    if let Some(path) = path {
+        let path = path.display();
+        error!("{}", i18n::text("session-log-path", &[("path", &path)]));
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        logs::parse_line,
+        trace::test::{mock_version, Capture},
+    };
+    use tracing::{debug, info, trace, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_test::traced_test;
+
+    /// A fresh directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cli-batteries-session-log-{name}-{}", pid()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!("on".parse(), Ok(Mode::On));
+        assert_eq!("off".parse(), Ok(Mode::Off));
+        assert_eq!("dir=/tmp/logs".parse(), Ok(Mode::Dir("/tmp/logs".into())));
+        assert!("dir=".parse::<Mode>().is_err());
+        assert!("yes".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = temp_dir("prune");
+        for name in [
+            "20230101T000000.000Z-1.log",
+            "20230102T000000.000Z-1.log",
+            "20230103T000000.000Z-1.log",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let session_log = open_in(&dir, 3, false).unwrap();
+        let names = names(&dir);
+        assert_eq!(names.len(), 4, "{names:?}");
+        assert_eq!(names[0], "20230102T000000.000Z-1.log");
+        assert_eq!(names[1], "20230103T000000.000Z-1.log");
+        assert!(names[2].ends_with(&format!("-{}.log", pid())), "{names:?}");
+        assert_eq!(names[3], "notes.txt");
+        assert_eq!(session_log.path, dir.join(&names[2]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "log-compress")]
+    fn test_compressed() {
+        use crate::logs::read_compressed;
+        use tracing_subscriber::Registry;
+
+        let dir = temp_dir("compressed");
+        for name in [
+            "20230101T000000.000Z-1.log.zst",
+            "20230102T000000.000Z-1.log",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let session_log = open_in(&dir, 2, true).unwrap();
+        let names = names(&dir);
+        assert_eq!(names.len(), 2, "{names:?}");
+        assert_eq!(names[0], "20230102T000000.000Z-1.log");
+        assert!(
+            names[1].ends_with(&format!("-{}.log.zst", pid())),
+            "{names:?}"
+        );
+
+        let path = session_log.path;
+        let file = Arc::new(session_log.file);
+        let subscriber = Registry::default().with(LogFormat::Json.into_layer(
+            file.clone(),
+            Fields::default(),
+            &LineOptions::default(),
+            false,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", "before flush");
+            assert!(file.end_frame());
+            info!(target: "app", "after flush");
+        });
+
+        // What a crash would leave behind.
+        let log = String::from_utf8(read_compressed(&path).unwrap()).unwrap();
+        assert!(log.contains("before flush"), "{log}");
+        assert!(!log.contains("after flush"), "{log}");
+
+        file.finish().unwrap();
+        (&*file).write_all(b"dropped").unwrap();
+        file.finish().unwrap();
+        let log = String::from_utf8(read_compressed(&path).unwrap()).unwrap();
+        assert_eq!(log.lines().count(), 2, "{log}");
+        assert!(log.contains("after flush"), "{log}");
+        assert!(log.lines().all(|line| parse_line(line).is_ok()), "{log}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_off() {
+        let options = Options::try_parse_from(["arg0", "--session-log", "off"]).unwrap();
+        assert!(options.open(&mock_version(), false).unwrap().is_none());
+        assert!(Options::try_parse_from(["arg0", "--session-log", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn test_stderr_unaffected() {
+        let dir = temp_dir("filter");
+        let options = super::super::Options::try_parse_from([
+            "arg0".to_owned(),
+            "--session-log".to_owned(),
+            format!("dir={}", dir.display()),
+        ])
+        .unwrap();
+        let capture = Capture::default();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), capture.clone(), Vec::new())
+            .unwrap();
+        let session_log = options
+            .session_log
+            .open(&mock_version(), false)
+            .unwrap()
+            .unwrap();
+        let path = session_log.path.clone();
+        let subscriber = subscriber.with(session_log.into_layer(
+            &mock_version(),
+            Fields::default(),
+            DiskFullPolicy::Drop,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            trace!(target: "app", "app trace");
+            info!(target: "app", "app info");
+            trace!(target: "dep", "dep trace");
+            debug!(target: "dep", "dep debug");
+            warn!(target: "dep", "dep warn");
+            error!(target: "dep", "dep error");
+        });
+
+        // Default verbosity on stderr.
+        let output = capture.contents();
+        assert!(output.contains("app info"));
+        assert!(!output.contains("app trace"));
+        assert!(!output.contains("dep debug"));
+        assert!(!output.contains("dep warn"));
+        assert!(output.contains("dep error"));
+
+        let log = fs::read_to_string(&path).unwrap();
+        for message in [
+            "app trace",
+            "app info",
+            "dep debug",
+            "dep warn",
+            "dep error",
+        ] {
+            assert!(log.contains(message), "{message}: {log}");
+        }
+        assert!(!log.contains("dep trace"));
+        assert!(log.lines().all(|line| parse_line(line).is_ok()), "{log}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_footer() {
+        log_path(None);
+        assert!(!logs_contain("full log"));
+        log_path(Some(Path::new(
+            "/state/app/logs/20230101T000000.000Z-1.log",
+        )));
+        assert!(logs_contain(
+            "The full log of this run is in /state/app/logs/20230101T000000.000Z-1.log"
+        ));
+    }
+}
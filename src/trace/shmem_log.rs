@@ -0,0 +1,611 @@
+//! Log events in a shared memory ring, for a sidecar to scrape.
+//!
+//! `--log-shmem` maps a fixed size file, by default
+//! `/dev/shm/{crate}-{pid}.ring`, and writes every event that passes the log
+//! filter to it. Old events are overwritten, the file never grows. Nothing
+//! is written to disk or a socket, so a scraper can not slow the service
+//! down, and the last events are still in the file after a crash. The file
+//! is removed at a regular shutdown.
+//!
+//! The file starts with a [`HEADER`] sized header: [`MAGIC`], the ring
+//! [`VERSION`] and the binary log format version of the entries as little
+//! endian `u16`s at 6 and 8, the ring capacity as `u64` at 16, the write
+//! cursor as `u64` at 24 and the process id as `u32` at 32. The cursor counts
+//! all bytes ever reserved, an entry at cursor position `p` starts at byte
+//! `p % capacity` of the ring.
+//!
+//! Writers reserve an entry by adding its size to the cursor, without a lock.
+//! An entry is an 8 byte sequence number, its payload length as `u32` and
+//! the payload: the frames of one event in the
+//! [binary log](super::binary_log) format, with its own dictionary entries so
+//! it can be read alone. Entries are padded to 8 bytes and wrap around the
+//! end of the ring. The sequence number is set to `p + 1` after the rest of
+//! the entry is written. A reader only accepts an entry whose sequence number
+//! matches its position and that was not overwritten while it was copied, so
+//! it never sees a partly written event, also not one of a writer that died
+//! writing it.
+//!
+//! [`ShmemReader`] reads the ring of a running or crashed process,
+//! `--dump-shmem <path>` prints it as JSON lines.
+use super::{
+    binary_log::{self, Captured, Encoder},
+    global_fields::Fields,
+};
+use crate::{default_from_clap, logs::LogRecord, loss::SHMEM_LOG_DROPPED};
+use clap::Parser;
+use eyre::{bail, Result as EyreResult, WrapErr as _};
+use memmap2::{MmapOptions, MmapRaw};
+use once_cell::sync::OnceCell;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read as _},
+    path::{Path, PathBuf},
+    process, ptr,
+    sync::{
+        atomic::{fence, AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// First bytes of a ring file.
+pub const MAGIC: [u8; 6] = *b"CLISHM";
+
+/// Version of the ring layout. Readers reject files of later versions.
+pub const VERSION: u16 = 1;
+
+/// Size of the file header, the ring follows it.
+pub const HEADER: usize = 64;
+
+/// The smallest ring, smaller sizes are rounded up.
+pub const MIN_SIZE: u64 = 4096;
+
+const DEFAULT_PATH: &str = "/dev/shm/{crate}-{pid}.ring";
+
+const SCHEMA_AT: usize = 8;
+const CAPACITY_AT: usize = 16;
+const CURSOR_AT: usize = 24;
+const PID_AT: usize = 32;
+
+/// Sequence number and payload length.
+const ENTRY_HEADER: u64 = 12;
+
+/// Alignment of entries, so sequence numbers never wrap around.
+const ALIGN: u64 = 8;
+
+/// The `--log-shmem` ring, for [`finish`].
+static ACTIVE: OnceCell<(PathBuf, Arc<Ring>)> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Also write log events to a ring in shared memory for a sidecar to
+    /// read, with the same filter as the log output. `{crate}` and `{pid}`
+    /// in the path are replaced.
+    #[clap(
+        long,
+        env,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_PATH
+    )]
+    log_shmem: Option<String>,
+
+    /// Size of the `--log-shmem` ring, like `4MiB`.
+    #[clap(
+        long,
+        env,
+        value_name = "SIZE",
+        default_value = "4MiB",
+        value_parser = parse_ring_size
+    )]
+    log_shmem_size: u64,
+
+    /// Print the events in a `--log-shmem` ring as JSON lines and exit.
+    #[clap(long, value_name = "PATH")]
+    dump_shmem: Option<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+fn parse_ring_size(s: &str) -> Result<u64, String> {
+    super::log_file::parse_size(s).ok_or_else(|| format!("expected a size like 4MiB, got `{s}`"))
+}
+
+impl Options {
+    /// Create the ring file, if requested.
+    pub fn open(&self, crate_name: &str, fields: Fields) -> EyreResult<Option<ShmemLog>> {
+        let Some(path) = &self.log_shmem else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(
+            path.replace("{crate}", crate_name)
+                .replace("{pid}", &process::id().to_string()),
+        );
+        let ring = Ring::create(&path, self.log_shmem_size)
+            .wrap_err_with(|| format!("Error creating log ring {}", path.display()))?;
+        let ring = Arc::new(ring);
+        let _ = ACTIVE.set((path, ring.clone()));
+        Ok(Some(ShmemLog { ring, fields }))
+    }
+}
+
+/// Remove the `--log-shmem` file. The mapping stays valid, later events are
+/// still written but no longer readable.
+pub fn finish() -> EyreResult<()> {
+    if let Some((path, _)) = ACTIVE.get() {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Error removing log ring {}", path.display()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A mapped ring file, see the [module docs](self).
+struct Ring {
+    map:      MmapRaw,
+    capacity: u64,
+}
+
+impl Ring {
+    fn create(path: &Path, size: u64) -> EyreResult<Self> {
+        let capacity = size.max(MIN_SIZE).next_multiple_of(ALIGN);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(HEADER as u64 + capacity)?;
+        let ring = Self {
+            map: MmapOptions::new().map_raw(&file)?,
+            capacity,
+        };
+        ring.write_header(SCHEMA_AT, &binary_log::VERSION.to_le_bytes());
+        ring.write_header(CAPACITY_AT, &capacity.to_le_bytes());
+        ring.write_header(PID_AT, &process::id().to_le_bytes());
+        ring.write_header(MAGIC.len(), &VERSION.to_le_bytes());
+        // Readers check the magic, it is written last.
+        fence(Ordering::Release);
+        ring.write_header(0, &MAGIC);
+        Ok(ring)
+    }
+
+    fn attach(file: &File) -> EyreResult<Self> {
+        let map = MmapOptions::new().map_raw_read_only(file)?;
+        if map.len() < HEADER {
+            bail!("Not a log ring");
+        }
+        let mut ring = Self { map, capacity: 0 };
+        if ring.read_header::<6>(0) != MAGIC {
+            bail!("Not a log ring");
+        }
+        let version = u16::from_le_bytes(ring.read_header(MAGIC.len()));
+        if version > VERSION {
+            bail!("Log ring version {version} is not supported, this build reads up to {VERSION}");
+        }
+        let schema = u16::from_le_bytes(ring.read_header(SCHEMA_AT));
+        if schema > binary_log::VERSION {
+            bail!(
+                "Log ring entries are binary log version {schema}, this build reads up to {}",
+                binary_log::VERSION
+            );
+        }
+        let capacity = u64::from_le_bytes(ring.read_header(CAPACITY_AT));
+        #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` needs Rust 1.87
+        let aligned = capacity % ALIGN == 0;
+        if capacity == 0 || !aligned || capacity != (ring.map.len() - HEADER) as u64 {
+            bail!("Log ring capacity {capacity} does not match the file");
+        }
+        ring.capacity = capacity;
+        Ok(ring)
+    }
+
+    fn write_header(&self, at: usize, bytes: &[u8]) {
+        // SAFETY: Within the header, only written before the ring is shared.
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.map.as_mut_ptr().add(at), bytes.len());
+        }
+    }
+
+    fn read_header<const N: usize>(&self, at: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        // SAFETY: Within the header, checked to be mapped.
+        unsafe { ptr::copy_nonoverlapping(self.map.as_ptr().add(at), bytes.as_mut_ptr(), N) };
+        bytes
+    }
+
+    #[allow(clippy::cast_ptr_alignment)] // Offsets are multiples of 8
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY: Mapped and 8 byte aligned, the map is page aligned and the
+        // offsets are multiples of 8.
+        unsafe { &*self.map.as_ptr().add(offset).cast::<AtomicU64>() }
+    }
+
+    fn cursor(&self) -> &AtomicU64 {
+        self.atomic(CURSOR_AT)
+    }
+
+    /// The sequence number of an entry at `position`.
+    #[allow(clippy::cast_possible_truncation)] // Less than the mapped size
+    fn sequence(&self, position: u64) -> &AtomicU64 {
+        self.atomic(HEADER + (position % self.capacity) as usize)
+    }
+
+    /// A byte of the ring.
+    fn byte(&self, offset: usize) -> &AtomicU8 {
+        // SAFETY: Mapped, callers keep `offset` within the ring.
+        unsafe { &*self.map.as_ptr().add(HEADER + offset).cast::<AtomicU8>() }
+    }
+
+    /// Copy `data` to the ring at `position`, wrapping around its end.
+    ///
+    /// Readers may copy the range concurrently, so the bytes are stored
+    /// atomically. They discard what they copied unless the sequence number
+    /// shows it was complete and the cursor shows it was not overwritten.
+    #[allow(clippy::cast_possible_truncation)] // Less than the mapped size
+    fn write(&self, position: u64, data: &[u8]) {
+        let start = (position % self.capacity) as usize;
+        let capacity = self.capacity as usize;
+        for (i, &byte) in data.iter().enumerate() {
+            self.byte((start + i) % capacity)
+                .store(byte, Ordering::Relaxed);
+        }
+    }
+
+    /// Copy from the ring at `position` into `data`, wrapping around its end.
+    #[allow(clippy::cast_possible_truncation)] // Less than the mapped size
+    fn read(&self, position: u64, data: &mut [u8]) {
+        let start = (position % self.capacity) as usize;
+        let capacity = self.capacity as usize;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.byte((start + i) % capacity).load(Ordering::Relaxed);
+        }
+    }
+
+    /// Append an entry with `payload`. Returns `false` if it is larger than
+    /// the ring.
+    fn push(&self, payload: &[u8]) -> bool {
+        let size = (ENTRY_HEADER + payload.len() as u64).next_multiple_of(ALIGN);
+        let Ok(len) = u32::try_from(payload.len()) else {
+            return false;
+        };
+        if size > self.capacity {
+            return false;
+        }
+        let position = self.cursor().fetch_add(size, Ordering::AcqRel);
+        // Readers that see any of the entry see the cursor moved past it,
+        // paired with the fence in `ShmemReader::read_entry`.
+        fence(Ordering::Release);
+        self.write(position + 8, &len.to_le_bytes());
+        self.write(position + ENTRY_HEADER, payload);
+        self.sequence(position)
+            .store(position + 1, Ordering::Release);
+        true
+    }
+}
+
+/// Layer writing events to a shared memory ring, see the
+/// [module docs](self).
+///
+/// Apply a filter, it writes every event it receives.
+#[derive(Clone)]
+pub struct ShmemLog {
+    ring:   Arc<Ring>,
+    fields: Fields,
+}
+
+impl<S> Layer<S> for ShmemLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut payload = Vec::with_capacity(256);
+        // Every entry interns its names again, so it can be read alone.
+        let span = ctx.event_span(event).map(|span| span.name());
+        Captured::new(event, span).encode(&mut Encoder::default(), &self.fields, &mut payload);
+        if !self.ring.push(&payload) {
+            SHMEM_LOG_DROPPED.add(1);
+        }
+    }
+}
+
+/// An entry read at a position.
+enum Entry {
+    /// The payload and size of a complete entry.
+    Complete(Vec<u8>, u64),
+    /// Being written, abandoned by a writer or not an entry.
+    Incomplete,
+    /// Overwritten while it was read.
+    Overwritten,
+}
+
+/// Reads [`LogRecord`]s from a `--log-shmem` ring, see the
+/// [module docs](self).
+///
+/// Reading does not change the ring, any number of readers can attach. A
+/// reader starts at the oldest event still in the ring.
+/// [`next_record`](Self::next_record) returns `None` when it caught up with
+/// the writers, later calls return the events logged since.
+pub struct ShmemReader {
+    ring:    Ring,
+    /// Cursor position of the next entry.
+    next:    u64,
+    schema:  u16,
+    pid:     u32,
+    skipped: u64,
+}
+
+impl ShmemReader {
+    /// Map the ring at `path` and check its header.
+    ///
+    /// # Errors
+    ///
+    /// When the file can not be mapped, is not a log ring or of a later
+    /// version than this build reads.
+    pub fn attach(path: impl AsRef<Path>) -> EyreResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .wrap_err_with(|| format!("Error opening log ring {}", path.display()))?;
+        let ring = Ring::attach(&file)
+            .wrap_err_with(|| format!("Error attaching to log ring {}", path.display()))?;
+        let next = ring
+            .cursor()
+            .load(Ordering::Acquire)
+            .saturating_sub(ring.capacity);
+        Ok(Self {
+            schema: u16::from_le_bytes(ring.read_header(SCHEMA_AT)),
+            pid: u32::from_le_bytes(ring.read_header(PID_AT)),
+            ring,
+            next,
+            skipped: 0,
+        })
+    }
+
+    /// Process id of the writer.
+    #[must_use]
+    pub const fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Binary log format version of the entries.
+    #[must_use]
+    pub const fn schema(&self) -> u16 {
+        self.schema
+    }
+
+    /// Bytes skipped so far because they were overwritten before they were
+    /// read or are not a complete entry.
+    #[must_use]
+    pub const fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The next record, or `None` when there is none yet.
+    ///
+    /// An entry that is still being written is waited for, unless a later
+    /// one is complete: then it is skipped, as its writer may have died.
+    ///
+    /// # Errors
+    ///
+    /// When a complete entry can not be decoded.
+    pub fn next_record(&mut self) -> EyreResult<Option<LogRecord>> {
+        loop {
+            let cursor = self.ring.cursor().load(Ordering::Acquire);
+            if self.next >= cursor {
+                return Ok(None);
+            }
+            let oldest = cursor.saturating_sub(self.ring.capacity);
+            if self.next < oldest {
+                self.skip_to(oldest);
+            }
+            match self.read_entry(cursor) {
+                Entry::Complete(payload, size) => {
+                    self.next += size;
+                    if let Some(record) = self.decode(&payload)? {
+                        return Ok(Some(record));
+                    }
+                }
+                Entry::Incomplete => {
+                    let Some(position) = self.find_entry(cursor) else {
+                        return Ok(None);
+                    };
+                    self.skip_to(position);
+                }
+                Entry::Overwritten => {}
+            }
+        }
+    }
+
+    const fn skip_to(&mut self, position: u64) {
+        self.skipped += position - self.next;
+        self.next = position;
+    }
+
+    fn read_entry(&self, cursor: u64) -> Entry {
+        let position = self.next;
+        if self.ring.sequence(position).load(Ordering::Acquire) != position + 1 {
+            return Entry::Incomplete;
+        }
+        let mut len = [0; 4];
+        self.ring.read(position + 8, &mut len);
+        let len = u64::from(u32::from_le_bytes(len));
+        let size = (ENTRY_HEADER + len).next_multiple_of(ALIGN);
+        if position + size > cursor {
+            return Entry::Incomplete;
+        }
+        #[allow(clippy::cast_possible_truncation)] // Less than the cursor difference
+        let mut payload = vec![0; len as usize];
+        self.ring.read(position + ENTRY_HEADER, &mut payload);
+        // Paired with the fence in `Ring::push`: a writer that reserved space
+        // over the entry and changed any of the copied bytes moved the cursor.
+        fence(Ordering::Acquire);
+        if self.ring.cursor().load(Ordering::Acquire) > position + self.ring.capacity {
+            return Entry::Overwritten;
+        }
+        Entry::Complete(payload, size)
+    }
+
+    /// The first complete entry after `next`.
+    fn find_entry(&self, cursor: u64) -> Option<u64> {
+        let mut position = self.next + ALIGN;
+        while position < cursor {
+            if self.ring.sequence(position).load(Ordering::Acquire) == position + 1 {
+                return Some(position);
+            }
+            position += ALIGN;
+        }
+        None
+    }
+
+    /// The event in an entry payload.
+    fn decode(&mut self, payload: &[u8]) -> EyreResult<Option<LogRecord>> {
+        let mut header = binary_log::MAGIC.to_vec();
+        header.extend_from_slice(&self.schema.to_le_bytes());
+        let mut reader = binary_log::BinaryLogReader::new(header.as_slice().chain(payload))?;
+        let record = reader.next_record()?;
+        self.skipped += reader.skipped();
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::env;
+    use tracing::{info, info_span, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    /// A ring in a fresh temporary file.
+    fn ring(name: &str, size: u64) -> (PathBuf, Arc<Ring>) {
+        let path = env::temp_dir().join(format!("cli-batteries-{name}-{}.ring", process::id()));
+        let ring = Arc::new(Ring::create(&path, size).unwrap());
+        (path, ring)
+    }
+
+    fn log(ring: &Arc<Ring>, f: impl FnOnce()) {
+        let layer = ShmemLog {
+            ring:   ring.clone(),
+            fields: Arc::from(vec![("run", "7".to_owned())]),
+        };
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+    }
+
+    fn read_all(reader: &mut ShmemReader) -> Vec<LogRecord> {
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (path, ring) = ring("shmem-round-trip", MIN_SIZE);
+        log(&ring, || {
+            info_span!("request").in_scope(|| info!(target: "app::db", i = 1, "query"));
+        });
+        let mut reader = ShmemReader::attach(&path).unwrap();
+        assert_eq!(reader.pid(), process::id());
+        assert_eq!(reader.schema(), binary_log::VERSION);
+        let records = read_all(&mut reader);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].level, "INFO");
+        assert_eq!(records[0].target.as_deref(), Some("app::db"));
+        assert_eq!(records[0].span.as_deref(), Some("request"));
+        assert_eq!(records[0].message, "query");
+        assert_eq!(records[0].fields["i"], 1);
+        assert_eq!(records[0].fields["run"], "7");
+
+        // Events logged later are read by the same reader.
+        log(&ring, || warn!("later"));
+        let records = read_all(&mut reader);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, "later");
+        assert_eq!(reader.skipped(), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let (path, ring) = ring("shmem-wrap", MIN_SIZE);
+        log(&ring, || info!(i = 0, "first"));
+        let mut reader = ShmemReader::attach(&path).unwrap();
+        log(&ring, || {
+            for i in 1..1000 {
+                info!(i, "event");
+            }
+        });
+        // Older events were overwritten, the rest is in order.
+        let records = read_all(&mut reader);
+        assert!(reader.skipped() > 0);
+        assert!(
+            records.len() > 10 && records.len() < 999,
+            "{}",
+            records.len()
+        );
+        let numbers = records.iter().map(|r| r.fields["i"].as_u64().unwrap());
+        assert!(numbers
+            .clone()
+            .zip(numbers.skip(1))
+            .all(|(a, b)| b == a + 1));
+        assert_eq!(records.last().unwrap().fields["i"], 999);
+
+        // A new reader starts at the oldest complete entry.
+        let mut late = ShmemReader::attach(&path).unwrap();
+        let recent = read_all(&mut late);
+        assert!(!recent.is_empty());
+        assert_eq!(recent.last().unwrap().fields["i"], 999);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let (path, ring) = ring("shmem-torn", MIN_SIZE);
+        // A writer that reserved space and died before completing it.
+        let torn = ring.cursor().fetch_add(64, Ordering::AcqRel);
+        ring.write(torn + 8, &52_u32.to_le_bytes());
+        ring.write(torn + ENTRY_HEADER, &[0xb1; 52]);
+        let mut reader = ShmemReader::attach(&path).unwrap();
+        assert!(reader.next_record().unwrap().is_none());
+
+        // Skipped once a later entry is complete.
+        log(&ring, || info!("after"));
+        let records = read_all(&mut reader);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message, "after");
+        assert_eq!(reader.skipped(), 64);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_too_large() {
+        let (path, ring) = ring("shmem-too-large", MIN_SIZE);
+        let before = SHMEM_LOG_DROPPED.get();
+        let message = "x".repeat(2 * usize::try_from(MIN_SIZE).unwrap());
+        log(&ring, || info!("{message}"));
+        assert_eq!(SHMEM_LOG_DROPPED.get(), before + 1);
+        assert_eq!(ring.cursor().load(Ordering::Relaxed), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_header() {
+        let path = env::temp_dir().join(format!("cli-batteries-shmem-header-{}", process::id()));
+        fs::write(&path, vec![0; HEADER + usize::try_from(MIN_SIZE).unwrap()]).unwrap();
+        let err = ShmemReader::attach(&path).err().unwrap();
+        assert!(format!("{err:#}").contains("Not a log ring"), "{err:#}");
+        fs::remove_file(path).unwrap();
+
+        let (path, ring) = ring("shmem-header", MIN_SIZE);
+        ring.write_header(MAGIC.len(), &(VERSION + 1).to_le_bytes());
+        let err = ShmemReader::attach(&path).err().unwrap();
+        assert!(format!("{err:#}").contains("not supported"), "{err:#}");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+//! Warn about spans that take too long.
+//!
+//! With `--slow-span-threshold 500ms` the busy and idle time of every span is
+//! measured, and when a span closes after more than the threshold a WARN
+//! event with target [`TARGET`] reports its name, durations and fields. This
+//! does not depend on the span events of the log format or on OpenTelemetry.
+//!
+//! Thresholds for targets override the default, like
+//! `--slow-span-threshold db::*=100ms`. A target matches like in the log
+//! filter, `db` also matches `db::pool`, `db::*` does the same and `db*` also
+//! matches `dbus`. The longest matching target wins. Spans without a matching
+//! threshold, and spans the log filter rejects, are not measured.
+//!
+//! Warnings are limited to one per span name in [`WARN_INTERVAL`], the next
+//! one counts those `suppressed` in between.
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::{self, Debug, Display, Formatter, Write as _},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Target of the warnings.
+pub const TARGET: &str = "cli_batteries::slow_span";
+
+/// Time after a warning in which further ones for the span name are only
+/// counted.
+pub const WARN_INTERVAL: Duration = Duration::from_mins(1);
+
+/// A `--slow-span-threshold`: a duration, for spans of `target` if given.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SlowSpanThreshold {
+    target:    Option<String>,
+    threshold: Duration,
+}
+
+impl SlowSpanThreshold {
+    /// Whether it applies to spans of `target`.
+    fn matches(&self, target: &str) -> bool {
+        let Some(pattern) = &self.target else {
+            return true;
+        };
+        if let Some(prefix) = pattern.strip_suffix('*') {
+            return target.starts_with(prefix) || prefix.strip_suffix("::") == Some(target);
+        }
+        target
+            .strip_prefix(pattern.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+
+    /// Length of the target, the default has none.
+    fn specificity(&self) -> Option<usize> {
+        self.target.as_ref().map(String::len)
+    }
+}
+
+impl FromStr for SlowSpanThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, threshold) = match s.rsplit_once('=') {
+            Some((target, threshold)) if !target.is_empty() => (Some(target.to_owned()), threshold),
+            _ => (None, s),
+        };
+        let threshold = humantime::parse_duration(threshold)
+            .map_err(|err| format!("{err}, expected like '500ms' or 'db::*=100ms'"))?;
+        Ok(Self { target, threshold })
+    }
+}
+
+impl Display for SlowSpanThreshold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(target) = &self.target {
+            write!(f, "{target}=")?;
+        }
+        write!(f, "{}", humantime::format_duration(self.threshold))
+    }
+}
+
+/// Measures spans and warns about slow ones, see the [module docs](self).
+pub struct SlowSpans {
+    /// Most specific first.
+    thresholds: Vec<SlowSpanThreshold>,
+    warned:     Mutex<HashMap<&'static str, Warned>>,
+}
+
+struct Warned {
+    at:         Instant,
+    suppressed: u64,
+}
+
+/// Span extension of measured spans.
+struct Timing {
+    threshold: Duration,
+    busy:      Duration,
+    idle:      Duration,
+    /// Of the last enter or exit.
+    last:      Instant,
+    fields:    Vec<(&'static str, String)>,
+}
+
+impl SlowSpans {
+    pub fn new(thresholds: &[SlowSpanThreshold]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_by_key(|threshold| Reverse(threshold.specificity()));
+        Self {
+            thresholds,
+            warned: Mutex::default(),
+        }
+    }
+
+    /// The threshold of spans of `target`, if they are measured.
+    fn threshold(&self, target: &str) -> Option<Duration> {
+        self.thresholds
+            .iter()
+            .find(|threshold| threshold.matches(target))
+            .map(|threshold| threshold.threshold)
+    }
+
+    /// Count a slow span. Returns the number of suppressed warnings if this
+    /// one is due.
+    #[allow(clippy::significant_drop_tightening)] // The entry is updated under the lock
+    fn admit(&self, name: &'static str, now: Instant) -> Option<u64> {
+        let mut warned = self.warned.lock().unwrap();
+        match warned.get_mut(name) {
+            Some(entry) if now.duration_since(entry.at) < WARN_INTERVAL => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                let suppressed = entry.suppressed;
+                *entry = Warned {
+                    at:         now,
+                    suppressed: 0,
+                };
+                Some(suppressed)
+            }
+            None => {
+                warned.insert(name, Warned {
+                    at:         now,
+                    suppressed: 0,
+                });
+                Some(0)
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for SlowSpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(threshold) = self.threshold(attrs.metadata().target()) else {
+            return;
+        };
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Vec::new();
+        attrs.record(&mut Visitor(&mut fields));
+        span.extensions_mut().insert(Timing {
+            threshold,
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last: Instant::now(),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<Timing>() {
+            values.record(&mut Visitor(&mut timing.fields));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<Timing>() {
+            let now = Instant::now();
+            timing.idle += now.duration_since(timing.last);
+            timing.last = now;
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<Timing>() {
+            let now = Instant::now();
+            timing.busy += now.duration_since(timing.last);
+            timing.last = now;
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<Timing>() else {
+            return;
+        };
+        let now = Instant::now();
+        let idle = timing.idle + now.duration_since(timing.last);
+        let duration = timing.busy + idle;
+        if duration <= timing.threshold {
+            return;
+        }
+        let name = span.name();
+        let Some(suppressed) = self.admit(name, now) else {
+            return;
+        };
+        // Unlike events in other callbacks, events of a closing span are
+        // dispatched.
+        warn!(
+            target: TARGET,
+            span = name,
+            span.target = span.metadata().target(),
+            ?duration,
+            busy = ?timing.busy,
+            ?idle,
+            threshold = ?timing.threshold,
+            fields = %FieldList(&timing.fields),
+            suppressed,
+            "Span {name} took {duration:?}, more than {:?}",
+            timing.threshold
+        );
+    }
+}
+
+/// Collects the latest value of each field.
+struct Visitor<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let value = format!("{value:?}");
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+/// Formats fields as `key=value` pairs.
+struct FieldList<'a>(&'a [(&'static str, String)]);
+
+impl Display for FieldList<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use serde_json::Value;
+    use tokio::time::advance;
+    use tracing::{info_span, Instrument};
+    use tracing_subscriber::{fmt, layer::SubscriberExt, Registry};
+
+    fn thresholds(args: &[&str]) -> SlowSpans {
+        let thresholds = args
+            .iter()
+            .map(|arg| arg.parse().unwrap())
+            .collect::<Vec<SlowSpanThreshold>>();
+        SlowSpans::new(&thresholds)
+    }
+
+    /// The warnings logged while running `f`.
+    async fn warnings<F>(layer: SlowSpans, f: F) -> Vec<Value>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let capture = Capture::default();
+        let subscriber = Registry::default()
+            .with(layer)
+            .with(fmt::Layer::new().json().with_writer(capture.clone()));
+        let guard = tracing::subscriber::set_default(subscriber);
+        f.await;
+        drop(guard);
+        capture
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let threshold = "500ms".parse::<SlowSpanThreshold>().unwrap();
+        assert_eq!(threshold, SlowSpanThreshold {
+            target:    None,
+            threshold: Duration::from_millis(500),
+        });
+        let threshold = "db::*=1s 100ms".parse::<SlowSpanThreshold>().unwrap();
+        assert_eq!(threshold.target.as_deref(), Some("db::*"));
+        assert_eq!(threshold.threshold, Duration::from_millis(1100));
+        assert_eq!(threshold.to_string(), "db::*=1s 100ms");
+        assert!("db::*=fast".parse::<SlowSpanThreshold>().is_err());
+        assert!("db::*".parse::<SlowSpanThreshold>().is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let layer = thresholds(&["500ms", "db::*=100ms", "db::pool=2s", "cache*=1s"]);
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(layer.threshold("app"), ms(500));
+        assert_eq!(layer.threshold("db"), ms(100));
+        assert_eq!(layer.threshold("db::query"), ms(100));
+        assert_eq!(layer.threshold("db::pool"), ms(2000));
+        assert_eq!(layer.threshold("db::pool::conn"), ms(2000));
+        assert_eq!(layer.threshold("db::pooled"), ms(100));
+        assert_eq!(layer.threshold("dbus"), ms(500));
+        assert_eq!(layer.threshold("caches"), ms(1000));
+
+        // Without a default, other spans are not measured.
+        let layer = thresholds(&["db=100ms"]);
+        assert_eq!(layer.threshold("db::query"), ms(100));
+        assert_eq!(layer.threshold("dbus"), None);
+        assert_eq!(layer.threshold("app"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_span() {
+        let layer = thresholds(&["500ms", "db::*=100ms"]);
+        let warnings = warnings(layer, async {
+            let span = info_span!(target: "app", "request", user = "ann", attempt = 2);
+            span.record("attempt", 3);
+            async { advance(Duration::from_millis(300)).await }
+                .instrument(span.clone())
+                .await;
+            advance(Duration::from_millis(300)).await;
+            drop(span);
+
+            let fast = info_span!(target: "app", "fast");
+            advance(Duration::from_millis(400)).await;
+            drop(fast);
+
+            let query = info_span!(target: "db::query", "query");
+            advance(Duration::from_millis(150)).await;
+            drop(query);
+        })
+        .await;
+
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        let request = &warnings[0];
+        assert_eq!(request["level"], "WARN");
+        assert_eq!(request["target"], TARGET);
+        assert_eq!(request["fields"]["span"], "request");
+        assert_eq!(request["fields"]["span.target"], "app");
+        assert_eq!(request["fields"]["duration"], "600ms");
+        assert_eq!(request["fields"]["busy"], "300ms");
+        assert_eq!(request["fields"]["idle"], "300ms");
+        assert_eq!(request["fields"]["fields"], r#"user="ann" attempt=3"#);
+        assert_eq!(request["fields"]["suppressed"], 0);
+        assert_eq!(
+            request["fields"]["message"],
+            "Span request took 600ms, more than 500ms"
+        );
+        assert_eq!(warnings[1]["fields"]["span"], "query");
+        assert_eq!(warnings[1]["fields"]["duration"], "150ms");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let layer = thresholds(&["100ms"]);
+        let warnings = warnings(layer, async {
+            for _ in 0..5 {
+                let span = info_span!("request");
+                advance(Duration::from_secs(1)).await;
+                drop(span);
+            }
+            // Other span names have their own limit.
+            let span = info_span!("job");
+            advance(Duration::from_secs(1)).await;
+            drop(span);
+            advance(WARN_INTERVAL).await;
+            let span = info_span!("request");
+            advance(Duration::from_secs(1)).await;
+            drop(span);
+        })
+        .await;
+
+        let spans = warnings
+            .iter()
+            .map(|warning| {
+                let fields = &warning["fields"];
+                (
+                    fields["span"].as_str().unwrap(),
+                    fields["suppressed"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![("request", 0), ("job", 0), ("request", 4)]);
+    }
+}
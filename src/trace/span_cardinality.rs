@@ -0,0 +1,194 @@
+//! Warn about span names that are not bounded.
+//!
+//! Trace backends index spans by name, so a user id in a span name creates a
+//! new series per user. Span names are static per callsite in `tracing`, the
+//! name that reaches the backend is overridden with the `otel.name` field.
+//! With `--warn-span-cardinality`, and always in debug builds, the distinct
+//! values of such indexed fields (`--span-cardinality-field`, `otel.name` by
+//! default) are counted per callsite. When a callsite produces more than
+//! `--span-cardinality-limit` of them, a warning points at the callsite, once.
+//! Counting then stops for it.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::Mutex,
+    thread,
+};
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Distinct values per callsite and field.
+pub struct SpanCardinality {
+    fields:  Vec<String>,
+    limit:   usize,
+    entries: Mutex<HashMap<(Identifier, &'static str), Entry>>,
+}
+
+enum Entry {
+    Counting(HashSet<String>),
+    Warned,
+}
+
+impl SpanCardinality {
+    pub fn new(fields: Vec<String>, limit: usize) -> Self {
+        Self {
+            fields,
+            limit,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Count a value. Returns `true` the first time the callsite exceeds the
+    /// limit for the field.
+    #[allow(clippy::significant_drop_tightening)] // The entry is updated under the lock
+    fn observe(&self, callsite: Identifier, field: &'static str, value: String) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry((callsite, field))
+            .or_insert_with(|| Entry::Counting(HashSet::new()));
+        let Entry::Counting(values) = entry else {
+            return false;
+        };
+        values.insert(value);
+        if values.len() <= self.limit {
+            return false;
+        }
+        *entry = Entry::Warned;
+        true
+    }
+
+    fn check(&self, metadata: &'static Metadata<'static>, values: Vec<(&'static str, String)>) {
+        for (field, value) in values {
+            if self.observe(metadata.callsite(), field, value) {
+                // `tracing` drops events from within a layer callback, the
+                // warning is logged on another thread. The entries are
+                // unlocked, the event passes through this layer.
+                thread::scope(|scope| {
+                    scope.spawn(|| self.warn(metadata, field));
+                });
+            }
+        }
+    }
+
+    fn warn(&self, metadata: &'static Metadata<'static>, field: &str) {
+        warn!(
+            callsite = %format_args!(
+                "{}:{}",
+                metadata.file().unwrap_or("unknown"),
+                metadata.line().unwrap_or_default()
+            ),
+            span = metadata.name(),
+            field,
+            limit = self.limit,
+            "Span {field} has more than {} distinct values, put the varying part in a regular \
+             field",
+            self.limit
+        );
+    }
+}
+
+impl<S> Layer<S> for SpanCardinality
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        let mut visitor = Visitor {
+            fields: &self.fields,
+            values: Vec::new(),
+        };
+        attrs.record(&mut visitor);
+        self.check(attrs.metadata(), visitor.values);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = Visitor {
+            fields: &self.fields,
+            values: Vec::new(),
+        };
+        values.record(&mut visitor);
+        self.check(span.metadata(), visitor.values);
+    }
+}
+
+/// Collects the values of the indexed fields.
+struct Visitor<'a> {
+    fields: &'a [String],
+    values: Vec<(&'static str, String)>,
+}
+
+impl Visit for Visitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.fields.iter().any(|name| name == field.name()) {
+            self.values.push((field.name(), value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if self.fields.iter().any(|name| name == field.name()) {
+            self.values.push((field.name(), format!("{value:?}")));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing::{field::Empty, info_span, Dispatch, Span};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_observe() {
+        let layer = SpanCardinality::new(vec!["otel.name".to_owned()], 3);
+        let (callsite, other) = tracing::subscriber::with_default(Registry::default(), || {
+            let callsite = |span: Span| span.metadata().unwrap().callsite();
+            (callsite(info_span!("a")), callsite(info_span!("b")))
+        });
+        let warnings = (0..10)
+            .filter(|i| layer.observe(callsite.clone(), "otel.name", format!("user {i}")))
+            .count();
+        assert_eq!(warnings, 1);
+        // Repeated values do not count.
+        for _ in 0..10 {
+            assert!(!layer.observe(other.clone(), "otel.name", "same".to_owned()));
+        }
+        assert!(!layer.observe(other, "user", "other field".to_owned()));
+    }
+
+    #[test]
+    fn test_fields() {
+        let layer = SpanCardinality::new(vec!["otel.name".to_owned(), "route".to_owned()], 2);
+        let dispatch = Dispatch::new(Registry::default().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            for i in 0..5 {
+                let name = format!("GET /users/{i}");
+                let _span = info_span!("request", otel.name = %name, route = "/users");
+                let span = info_span!("late", otel.name = Empty);
+                span.record("otel.name", format!("job {i}").as_str());
+            }
+        });
+        // Warnings are dropped within a scoped subscriber, the entries show
+        // which were due.
+        let layer = dispatch.downcast_ref::<SpanCardinality>().unwrap();
+        let names = layer
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((_, field), entry)| (*field, matches!(entry, Entry::Warned)))
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 3, "{names:?}");
+        assert!(names.contains(&("route", false)));
+        let warned = names
+            .iter()
+            .filter(|(field, warned)| *field == "otel.name" && *warned);
+        assert_eq!(warned.count(), 2);
+    }
+}
@@ -6,6 +6,7 @@ use tracing::{
     field::{display, Field, FieldSet, Visit},
     Event, Subscriber, Value,
 };
+use tracing_log::NormalizeEvent;
 use tracing_subscriber::{
     fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
     registry::LookupSpan,
@@ -108,9 +109,18 @@ where
                     Event::new_child_of(event.parent().cloned(), event.metadata(), &value_set);
                 self.inner.format_event(ctx, writer, &event)?;
             }
-        } else {
+        } else if !is_span_echo(event) {
             self.inner.format_event(ctx, writer, event)?;
         }
         Ok(())
     }
 }
+
+/// Whether `event` is a span lifecycle record that `tracing` emitted through
+/// `log` (with its `log` features enabled by a dependency) and `LogTracer`
+/// bridged back. These duplicate the span events above without any fields.
+fn is_span_echo(event: &Event<'_>) -> bool {
+    event
+        .normalized_metadata()
+        .is_some_and(|meta| matches!(meta.target(), "tracing::span" | "tracing::span::active"))
+}
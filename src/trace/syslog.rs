@@ -0,0 +1,233 @@
+//! `--log-target syslog`: the log output as messages to the syslog daemon.
+//!
+//! Every event is one datagram on [`SOCKET`] in the traditional format
+//! `<priority>tag[pid]: message key=value ...`, tagged with the crate name.
+//! The priority combines the `--syslog-facility` with the severity of the
+//! level: `err` for ERROR, `warning` for WARN, `info` for INFO and `debug` for
+//! DEBUG and TRACE. The daemon adds the timestamp and host name.
+//!
+//! Without a syslog daemon the log output stays on stderr, with a warning.
+use clap::ValueEnum;
+use std::{
+    fmt::{self, Debug, Write as _},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, MakeWriter},
+    registry::LookupSpan,
+};
+
+/// Where the syslog daemon listens.
+pub const SOCKET: &str = "/dev/log";
+
+/// Syslog facilities, with their codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Facility {
+    Kern     = 0,
+    User     = 1,
+    Mail     = 2,
+    Daemon   = 3,
+    Auth     = 4,
+    Syslog   = 5,
+    Lpr      = 6,
+    News     = 7,
+    Uucp     = 8,
+    Cron     = 9,
+    Authpriv = 10,
+    Ftp      = 11,
+    Local0   = 16,
+    Local1   = 17,
+    Local2   = 18,
+    Local3   = 19,
+    Local4   = 20,
+    Local5   = 21,
+    Local6   = 22,
+    Local7   = 23,
+}
+
+impl Facility {
+    /// The priority of an event with `level`.
+    const fn priority(self, level: Level) -> u8 {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        self as u8 * 8 + severity
+    }
+}
+
+/// A writer sending every write as one message to the syslog daemon.
+pub struct Syslog {
+    path:   PathBuf,
+    socket: UnixDatagram,
+}
+
+impl Syslog {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            socket,
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = &'a Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+impl Write for &Syslog {
+    /// Events are formatted in full and written at once. When the daemon was
+    /// restarted, the socket is connected again once.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let message = buf.strip_suffix(b"\n").unwrap_or(buf);
+        if self.socket.send(message).is_err() {
+            self.socket.connect(&self.path)?;
+            self.socket.send(message)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats events as syslog messages, see the [module docs](self).
+pub struct SyslogFormat {
+    facility: Facility,
+    tag:      &'static str,
+    pid:      u32,
+}
+
+impl SyslogFormat {
+    pub fn new(facility: Facility, tag: &'static str) -> Self {
+        Self {
+            facility,
+            tag,
+            pid: process::id(),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for SyslogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut visitor = KeyValues::default();
+        event.record(&mut visitor);
+        let priority = self.facility.priority(*event.metadata().level());
+        write!(
+            writer,
+            "<{priority}>{}[{}]: {}{}",
+            self.tag, self.pid, visitor.message, visitor.fields
+        )
+    }
+}
+
+/// The message, and the other fields as ` key=value` pairs.
+#[derive(Default)]
+struct KeyValues {
+    message: String,
+    fields:  String,
+}
+
+impl Visit for KeyValues {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{value:?}");
+            }
+            // Metadata of `log` crate events
+            name if name.starts_with("log.") => {}
+            name => {
+                let _ = write!(self.fields, " {name}={value:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::{test::mock_version, Options};
+    use clap::Parser;
+    use std::{env, fs};
+    use tracing::{error, info, trace, warn};
+
+    #[test]
+    fn test_priority() {
+        assert_eq!(Facility::Daemon.priority(Level::ERROR), 27);
+        assert_eq!(Facility::Kern.priority(Level::WARN), 4);
+        assert_eq!(Facility::Local7.priority(Level::TRACE), 191);
+    }
+
+    #[test]
+    fn test_syslog() {
+        let path = env::temp_dir().join(format!("cli-batteries-syslog-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let options = Options::try_parse_from([
+            "arg0",
+            "--log-target",
+            "syslog",
+            "--syslog-facility",
+            "local3",
+            "--tag",
+            "run=7",
+        ])
+        .unwrap();
+        let (subscriber, _) = options
+            .subscriber(&mock_version(), Syslog::connect(&path).unwrap(), Vec::new())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "app", user = "ann", attempt = 2, "logged in");
+            warn!(target: "app", "disk almost full");
+            error!(target: "app", error = %"timeout", "request failed");
+            trace!(target: "app", "filtered");
+        });
+        fs::remove_file(&path).unwrap();
+
+        let pid = process::id();
+        let mut buf = [0; 1024];
+        let mut recv = || {
+            let len = daemon.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+        assert_eq!(
+            recv(),
+            format!(r#"<158>test_app[{pid}]: logged in user="ann" attempt=2 run="7""#)
+        );
+        assert_eq!(
+            recv(),
+            format!(r#"<156>test_app[{pid}]: disk almost full run="7""#)
+        );
+        assert_eq!(
+            recv(),
+            format!(r#"<155>test_app[{pid}]: request failed error=timeout run="7""#)
+        );
+        daemon.set_nonblocking(true).unwrap();
+        assert!(daemon.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Tracing output of tests, shown only when they fail.
+//!
+//! Like libtest captures stdout, [`test_subscriber`] installs a subscriber
+//! for the current thread that buffers every event in the `pretty` format.
+//! When the guard is dropped while the test panics, or after
+//! [`TestSubscriber::fail`], the buffer is written to stderr, where libtest
+//! captures it and shows it with the failure. Passing tests stay quiet. With
+//! `--nocapture` the output of failing tests goes straight to stderr.
+//!
+//! `LOG_FILTER` limits what is buffered, by default everything is. Guards
+//! nest, the innermost one receives the events until it is dropped.
+//!
+//! The subscriber is thread local. Multi threaded runtimes and spawned
+//! threads log to the global subscriber instead, use a current thread
+//! runtime like `#[cli_batteries::test]` and `#[tokio::test]` do.
+use super::{fields_scope::FieldsScopeLayer, global_fields::Fields, LineOptions, LogFormat};
+use std::{
+    cell::Cell,
+    env,
+    future::Future,
+    io::{self, stderr, Write},
+    sync::{Arc, Mutex},
+    thread,
+};
+use tracing::{subscriber::DefaultGuard, Level};
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{filter::Targets, fmt::MakeWriter, layer::SubscriberExt, Layer, Registry};
+
+/// Guard returned by [`test_subscriber`], see the [module docs](self).
+#[must_use = "events are only captured while the guard is alive"]
+pub struct TestSubscriber {
+    buffer:   Buffer,
+    failed:   Cell<bool>,
+    _default: DefaultGuard,
+}
+
+/// The captured output, shared with the writers of the layer.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Buffer {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Capture the tracing output of the current thread until the guard is
+/// dropped, and show it only when the test fails.
+///
+/// Use `#[cli_batteries::test]` to do this for a whole test.
+pub fn test_subscriber() -> TestSubscriber {
+    let targets = env::var("LOG_FILTER")
+        .ok()
+        .and_then(|filter| filter.parse().ok())
+        .unwrap_or_else(|| Targets::new().with_default(Level::TRACE));
+    let buffer = Buffer::default();
+    let subscriber = Registry::default()
+        .with(ErrorLayer::default())
+        .with(
+            LogFormat::Pretty
+                .into_layer(
+                    buffer.clone(),
+                    Fields::default(),
+                    &LineOptions::default(),
+                    false,
+                )
+                .with_filter(targets),
+        )
+        .with(FieldsScopeLayer::default());
+    TestSubscriber {
+        buffer,
+        failed: Cell::new(false),
+        _default: tracing::subscriber::set_default(subscriber),
+    }
+}
+
+impl TestSubscriber {
+    /// Show the output when the guard is dropped, even if the test does not
+    /// panic. For tests that report failure by returning an error.
+    pub fn fail(&self) {
+        self.failed.set(true);
+    }
+
+    /// The output captured so far.
+    #[must_use]
+    pub fn output(&self) -> String {
+        self.buffer.contents()
+    }
+}
+
+impl Drop for TestSubscriber {
+    fn drop(&mut self) {
+        if self.failed.get() || thread::panicking() {
+            // `eprint!` instead of `stderr()` so libtest captures it.
+            eprint!(
+                "---- tracing output ----\n{}------------------------\n",
+                self.output()
+            );
+            let _ = stderr().flush();
+        }
+    }
+}
+
+/// Whether a test result is a failure. Used by `#[cli_batteries::test]`.
+pub trait Outcome {
+    fn failed(&self) -> bool;
+}
+
+impl Outcome for () {
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+impl<T, E> Outcome for Result<T, E> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+
+/// Run an async test on a current thread runtime, so the thread local
+/// subscriber sees all of its events.
+#[allow(clippy::missing_panics_doc)] // Only when the runtime can not be built
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Error creating Tokio runtime")
+        .block_on(future)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tracing::{debug, info, info_span};
+
+    #[test]
+    fn test_capture() {
+        let outer = test_subscriber();
+        info_span!("request", id = 7).in_scope(|| debug!(count = 3, "outer event"));
+        {
+            let inner = test_subscriber();
+            info!("inner event");
+            assert!(inner.output().contains("inner event"));
+        }
+        info!("outer again");
+
+        let output = outer.output();
+        assert!(output.contains("outer event"), "{output}");
+        assert!(output.contains("count: 3"), "{output}");
+        assert!(output.contains("outer again"), "{output}");
+        assert!(!output.contains("inner event"), "{output}");
+        assert!(!output.contains('\x1b'), "{output}");
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert!(!().failed());
+        assert!(!Result::<(), ()>::Ok(()).failed());
+        assert!(Result::<(), &str>::Err("failed").failed());
+    }
+
+    #[test]
+    fn test_block_on() {
+        let _subscriber = test_subscriber();
+        let output = block_on(async {
+            tokio::task::yield_now().await;
+            info!("from async");
+            7
+        });
+        assert_eq!(output, 7);
+    }
+}
@@ -1,8 +1,9 @@
-use ansi_term::{Colour, Style};
-use std::{
-    fmt::{Debug, Error, Result, Write},
-    time::Instant,
+use super::{
+    log_timestamp::{LogTimestamp, Timer},
+    thread_name,
 };
+use ansi_term::{Colour, Style};
+use std::fmt::{Debug, Error, Result, Write};
 use tracing::{
     field::{Field, Visit},
     span::Record,
@@ -11,12 +12,15 @@ use tracing::{
 use tracing_log::NormalizeEvent;
 use tracing_subscriber::{
     field::{MakeVisitor, RecordFields, VisitFmt, VisitOutput},
-    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    fmt::{
+        format::Writer, time::FormatTime, FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
     registry::LookupSpan,
 };
 
 pub struct TinyLogFmt {
-    epoch: Instant,
+    timer:  Timer,
+    thread: bool,
 }
 
 struct TinyFields;
@@ -29,10 +33,23 @@ struct TinyVisitor<'a> {
 
 impl Default for TinyLogFmt {
     fn default() -> Self {
+        Self::new(Timer::new(LogTimestamp::Uptime))
+    }
+}
+
+impl TinyLogFmt {
+    pub const fn new(timer: Timer) -> Self {
         Self {
-            epoch: Instant::now(),
+            timer,
+            thread: false,
         }
     }
+
+    /// With the thread name after the level.
+    pub const fn with_thread(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
 }
 
 impl<S, N> FormatEvent<S, N> for TinyLogFmt
@@ -49,26 +66,41 @@ where
         let normalized_meta = event.normalized_metadata();
         let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
 
-        let dimmed = Style::new().dimmed();
-        let bold = Style::new().bold();
-
-        // Uptime
-        let e = self.epoch.elapsed();
-        write!(writer, "{}", dimmed.prefix())?;
-        write!(writer, "{:4}.{:06} ", e.as_secs(), e.subsec_micros())?;
-        write!(writer, "{}", dimmed.suffix())?;
+        let ansi = writer.has_ansi_escapes();
+        let style = |style: Style| {
+            if ansi {
+                style
+            } else {
+                Style::new()
+            }
+        };
+        let dimmed = style(Style::new().dimmed());
+        let bold = style(Style::new().bold());
+
+        // Timestamp
+        if !self.timer.is_none() {
+            write!(writer, "{}", dimmed.prefix())?;
+            self.timer.format_time(&mut writer)?;
+            write!(writer, " {}", dimmed.suffix())?;
+        }
 
         // Log level
         write!(writer, "{}", bold.prefix())?;
-        write!(writer, "{} ", match *meta.level() {
-            Level::TRACE => Colour::Purple.paint("T"),
-            Level::DEBUG => Colour::Blue.paint("D"),
-            Level::INFO => Colour::Green.paint("I"),
-            Level::WARN => Colour::Yellow.paint("W"),
-            Level::ERROR => Colour::Red.paint("E"),
-        })?;
+        let (colour, letter) = match *meta.level() {
+            Level::TRACE => (Colour::Purple, "T"),
+            Level::DEBUG => (Colour::Blue, "D"),
+            Level::INFO => (Colour::Green, "I"),
+            Level::WARN => (Colour::Yellow, "W"),
+            Level::ERROR => (Colour::Red, "E"),
+        };
+        write!(writer, "{} ", style(colour.normal()).paint(letter))?;
         write!(writer, "{}", bold.suffix())?;
 
+        // Thread
+        if self.thread {
+            write!(writer, "{}", dimmed.paint(format!("[{}] ", thread_name())))?;
+        }
+
         // Fields
         ctx.format_fields(writer.by_ref(), event)?;
 
@@ -161,9 +193,17 @@ impl<'a> Visit for TinyVisitor<'a> {
             return;
         }
 
+        let ansi = self.writer.has_ansi_escapes();
+        let style = |style: Style| {
+            if ansi {
+                style
+            } else {
+                Style::new()
+            }
+        };
         let message_style = Style::default();
-        let trace_style = Style::default().italic();
-        let key_style = Style::default().dimmed().italic();
+        let trace_style = style(Style::default().italic());
+        let key_style = style(Style::default().dimmed().italic());
         let value_style = Style::default();
 
         match field.name() {
@@ -1,8 +1,8 @@
 #![cfg(feature = "tokio-console")]
 use clap::Parser;
 use console_subscriber::ConsoleLayer;
-use tracing::Subscriber;
-use tracing_subscriber::{registry::LookupSpan, Layer};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
 
 #[derive(Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
 #[group(skip)]
@@ -34,3 +34,10 @@ impl Options {
         }
     }
 }
+
+/// The console only needs the runtime's own instrumentation.
+pub fn targets() -> Targets {
+    Targets::new()
+        .with_target("tokio", Level::TRACE)
+        .with_target("runtime", Level::TRACE)
+}
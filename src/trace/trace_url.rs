@@ -0,0 +1,128 @@
+//! `--trace-url`: point to the trace of a failed run.
+//!
+//! After the error report of a failed run, a line like `The trace of this run
+//! is at https://jaeger.example.com/trace/4bf92f3577b34da6a3ce929d0e0e4736`
+//! leads to the trace of the `main` span in the tracing backend. The URL is
+//! the `--trace-url` template with `{trace_id}` replaced. Without
+//! `--trace-otlp` there is no trace and no line.
+//!
+//! With the `clipboard` feature, `--copy-trace-url` also copies the URL to the
+//! clipboard. Without a clipboard, like over SSH, the line is the same as
+//! without the flag.
+#![cfg(feature = "otlp")]
+use crate::{default_from_clap, i18n};
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use opentelemetry::trace::{TraceContextExt as _, TraceId};
+use tracing::{error, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+/// Replaced by the trace id in `--trace-url`.
+const PLACEHOLDER: &str = "{trace_id}";
+
+/// The options of this run, once tracing is initialized.
+static OPTIONS: OnceCell<Options> = OnceCell::new();
+
+/// The trace of the `main` span.
+static TRACE_ID: OnceCell<TraceId> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Point to the trace of a failed run after the error report, with
+    /// `{trace_id}` replaced, like
+    /// `https://jaeger.example.com/trace/{trace_id}`.
+    #[clap(long, env, value_parser = parse_template)]
+    trace_url: Option<String>,
+
+    /// Also copy the `--trace-url` of a failed run to the clipboard.
+    #[cfg(feature = "clipboard")]
+    #[clap(long, env, requires = "trace_url")]
+    copy_trace_url: bool,
+}
+
+default_from_clap!(Options);
+
+fn parse_template(template: &str) -> Result<String, String> {
+    if template.contains(PLACEHOLDER) {
+        Ok(template.to_owned())
+    } else {
+        Err(format!("expected {PLACEHOLDER} in the URL"))
+    }
+}
+
+impl Options {
+    /// Keep the options for [`log`].
+    pub fn init(&self) {
+        let _ = OPTIONS.set(self.clone());
+    }
+
+    fn url(&self, trace_id: TraceId) -> Option<String> {
+        let template = self.trace_url.as_ref()?;
+        Some(template.replace(PLACEHOLDER, &trace_id.to_string()))
+    }
+
+    #[cfg(feature = "clipboard")]
+    fn copy(&self, url: &str) -> bool {
+        self.copy_trace_url && crate::output::copy_to_clipboard(url)
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    #[allow(clippy::unused_self)] // Same as with the feature
+    const fn copy(&self, _url: &str) -> bool {
+        false
+    }
+}
+
+/// Remember the trace of the `main` span, if it has one.
+pub fn record(span: &Span) {
+    let trace_id = span.context().span().span_context().trace_id();
+    if trace_id != TraceId::INVALID {
+        let _ = TRACE_ID.set(trace_id);
+    }
+}
+
+/// Point to the trace of this run, if `--trace-url` is set and there is one.
+pub fn log() {
+    let (Some(options), Some(trace_id)) = (OPTIONS.get(), TRACE_ID.get()) else {
+        return;
+    };
+    if let Some(url) = options.url(*trace_id) {
+        let key = if options.copy(&url) {
+            "trace-url-copied"
+        } else {
+            "trace-url"
+        };
+        error!("{}", i18n::text(key, &[("url", &url)]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)] // The placeholders
+    fn test_url() {
+        let options = Options::try_parse_from([
+            "arg0",
+            "--trace-url",
+            "https://jaeger.example.com/trace/{trace_id}?uiFind={trace_id}",
+        ])
+        .unwrap();
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        assert_eq!(
+            options.url(trace_id).unwrap(),
+            "https://jaeger.example.com/trace/4bf92f3577b34da6a3ce929d0e0e4736?uiFind=\
+             4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(Options::default().url(trace_id), None);
+
+        let err = Options::try_parse_from(["arg0", "--trace-url", "https://jaeger.example.com"])
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("expected {trace_id} in the URL"),
+            "{err}"
+        );
+    }
+}
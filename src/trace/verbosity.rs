@@ -0,0 +1,180 @@
+//! `--verbose` as an occurrence count or a level.
+//!
+//! `-vvv` is awkward when flags are templated, so `--verbose=debug` and
+//! `VERBOSE=debug` are accepted as well and mapped to the equivalent count:
+//! `error` and `warn` are the default, `info`, `debug` and `trace` equal `-v`,
+//! `-vv` and `-vvv`. Mixing the two forms is an error.
+//!
+//! The count, less the `--quiet` count, is mapped to levels by a
+//! [`VerbosityMap`], [`default_verbosity`] unless the app sets its own with
+//! [`Runner::verbosity_map`](crate::Runner::verbosity_map).
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgAction, ArgMatches, Args, Command, Error,
+    FromArgMatches,
+};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Argument id, also the name of the flag.
+const ID: &str = "verbose";
+
+/// Normalized `--verbose` occurrence count.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Verbosity(pub u8);
+
+impl Verbosity {
+    fn arg(env: &'static str) -> clap::Arg {
+        clap::Arg::new(ID)
+            .short('v')
+            .long(ID)
+            .env(env)
+            .value_name("LEVEL")
+            .help("Verbose mode (-v, -vv, -vvv, etc.), or a level (--verbose=debug)")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("")
+            .action(ArgAction::Append)
+            .value_parser(parse)
+    }
+}
+
+/// The level of all targets and the level of the app crates for the number
+/// of `-v` minus the number of `-q`.
+pub type VerbosityMap = Box<dyn Fn(i16) -> (LevelFilter, LevelFilter) + Send + Sync>;
+
+/// The number of `-v` minus the number of `-q`: each `-q` cancels a `-v`,
+/// below zero they lower the app level.
+pub fn verbosity(verbose: Verbosity, quiet: u8) -> i16 {
+    i16::from(verbose.0) - i16::from(quiet)
+}
+
+/// The default [`VerbosityMap`]: the app crates log `INFO` and everything
+/// else `ERROR`. Each `-v` raises one of them by a level, each `-q` lowers
+/// the app crates and `-qqq` turns off all logs.
+#[must_use]
+pub const fn default_verbosity(verbosity: i16) -> (LevelFilter, LevelFilter) {
+    match verbosity {
+        ..=-3 => (LevelFilter::OFF, LevelFilter::OFF),
+        -2 => (LevelFilter::ERROR, LevelFilter::ERROR),
+        -1 => (LevelFilter::ERROR, LevelFilter::WARN),
+        0 => (LevelFilter::ERROR, LevelFilter::INFO),
+        1 => (LevelFilter::INFO, LevelFilter::INFO),
+        2 => (LevelFilter::INFO, LevelFilter::DEBUG),
+        3 => (LevelFilter::INFO, LevelFilter::TRACE),
+        4 => (LevelFilter::DEBUG, LevelFilter::TRACE),
+        _ => (LevelFilter::TRACE, LevelFilter::TRACE),
+    }
+}
+
+/// Parse a level name or count. A bare `-v` is `None`.
+fn parse(value: &str) -> Result<Option<u8>, String> {
+    Ok(Some(match value.to_ascii_lowercase().as_str() {
+        "" => return Ok(None),
+        "error" | "warn" => 0,
+        "info" => 1,
+        "debug" => 2,
+        "trace" => 3,
+        count => count
+            .parse()
+            .map_err(|_| "expected error, warn, info, debug, trace or a count".to_owned())?,
+    }))
+}
+
+impl FromArgMatches for Verbosity {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        let values = matches
+            .get_many::<Option<u8>>(ID)
+            .map(|values| values.copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        match values.as_slice() {
+            [None] if matches.value_source(ID) == Some(ValueSource::EnvVariable) => Ok(Self(0)),
+            [Some(level)] => Ok(Self(*level)),
+            flags if flags.iter().all(Option::is_none) => {
+                Ok(Self(u8::try_from(flags.len()).unwrap_or(u8::MAX)))
+            }
+            _ => Err(Error::raw(
+                ErrorKind::ArgumentConflict,
+                "--verbose takes either repeated -v flags or a single --verbose=LEVEL, not both\n",
+            )),
+        }
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), Error> {
+        if matches.contains_id(ID) {
+            *self = Self::from_arg_matches(matches)?;
+        }
+        Ok(())
+    }
+}
+
+impl Args for Verbosity {
+    fn augment_args(cmd: Command) -> Command {
+        cmd.arg(Self::arg("VERBOSE"))
+    }
+
+    fn augment_args_for_update(cmd: Command) -> Command {
+        Self::augment_args(cmd)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::env;
+
+    fn parse_with(env: &'static str, args: &[&str]) -> Result<u8, Error> {
+        let matches = Command::new("arg0")
+            .arg(Verbosity::arg(env))
+            .try_get_matches_from(std::iter::once(&"arg0").chain(args))?;
+        Verbosity::from_arg_matches(&matches).map(|verbosity| verbosity.0)
+    }
+
+    fn parse_args(args: &[&str]) -> Result<u8, Error> {
+        parse_with("CLI_BATTERIES_TEST_VERBOSE_UNSET", args)
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(parse_args(&[]).unwrap(), 0);
+        assert_eq!(parse_args(&["-v"]).unwrap(), 1);
+        assert_eq!(parse_args(&["-v", "-v"]).unwrap(), 2);
+        assert_eq!(parse_args(&["-vvv", "--verbose"]).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(parse_args(&["--verbose=warn"]).unwrap(), 0);
+        assert_eq!(parse_args(&["--verbose=info"]).unwrap(), 1);
+        assert_eq!(parse_args(&["--verbose=DEBUG"]).unwrap(), 2);
+        assert_eq!(parse_args(&["-v=trace"]).unwrap(), 3);
+        assert_eq!(parse_args(&["--verbose=5"]).unwrap(), 5);
+        let err = parse_args(&["--verbose=loud"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_env() {
+        // Setting `VERBOSE` itself would leak into concurrently running tests.
+        env::set_var("CLI_BATTERIES_TEST_VERBOSE", "debug");
+        assert_eq!(parse_with("CLI_BATTERIES_TEST_VERBOSE", &[]).unwrap(), 2);
+        // Flags take precedence over the environment.
+        assert_eq!(
+            parse_with("CLI_BATTERIES_TEST_VERBOSE", &["-v"]).unwrap(),
+            1
+        );
+        env::set_var("CLI_BATTERIES_TEST_VERBOSE", "3");
+        assert_eq!(parse_with("CLI_BATTERIES_TEST_VERBOSE", &[]).unwrap(), 3);
+        env::remove_var("CLI_BATTERIES_TEST_VERBOSE");
+    }
+
+    #[test]
+    fn test_mixed() {
+        for args in [&["-v", "--verbose=debug"][..], &[
+            "--verbose=info",
+            "--verbose=debug",
+        ]] {
+            let err = parse_args(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+            assert!(err.to_string().contains("not both"), "{err}");
+        }
+    }
+}
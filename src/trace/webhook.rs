@@ -0,0 +1,623 @@
+//! ERROR events posted to a webhook, for deployments without a logging stack.
+//!
+//! `--error-webhook-url <url>` posts every ERROR event, regardless of the log
+//! filter, as a Slack compatible `{"text": ...}` message or, with
+//! `--error-webhook-format json`, as an object with the message, target,
+//! fields, trace id, version and hostname. Repeats from the same callsite
+//! are deduplicated by the [report limiter](crate::report_limit), and at most
+//! `--error-webhook-rate` events are sent per minute.
+//!
+//! Events are queued for a background thread with its own runtime, so the
+//! error that terminates the program is still delivered after the main
+//! runtime is gone. Failed requests are retried [`ATTEMPTS`] times with
+//! backoff. Logging never waits for the webhook: events over the rate limit
+//! or the [`QUEUE_LEN`], and events that could not be delivered, are counted
+//! as telemetry loss. At exit, queued events get at most [`FINISH_TIMEOUT`].
+//!
+//! `https` URLs, like those of Slack, need the `tls` feature. Their
+//! certificates are checked against the Mozilla root certificates compiled
+//! into the binary.
+use super::recent_errors::{trace_id, Visitor};
+use crate::{
+    crash::hostname,
+    default_from_clap,
+    loss::{WEBHOOK_DROPPED, WEBHOOK_FAILED},
+    report_limit, Version,
+};
+use chrono::{SecondsFormat, Utc};
+use clap::{Parser, ValueEnum};
+use eyre::{Report, Result as EyreResult, WrapErr as _};
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request, Uri};
+#[cfg(feature = "tls")]
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Map, Value};
+use std::{
+    fmt::Write as _,
+    sync::{
+        mpsc::{self as std_mpsc, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::{sleep, timeout},
+};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Number of events waiting for delivery before new ones are dropped.
+pub const QUEUE_LEN: usize = 64;
+
+/// Number of requests made for an event before it counts as failed.
+pub const ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for every next one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time a request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to deliver the queued events at exit.
+pub const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Window of `--error-webhook-rate`.
+const RATE_WINDOW: Duration = Duration::from_mins(1);
+
+/// The `--error-webhook-url` layer, for [`finish`].
+static WEBHOOK: OnceCell<Webhook> = OnceCell::new();
+
+/// Payload of the webhook requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum WebhookFormat {
+    /// `{"text": ...}`, understood by Slack and compatible chat services.
+    Slack,
+    /// All details of the event as a JSON object.
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // Flag names
+pub struct Options {
+    /// Post ERROR events to this 'http' or, with the 'tls' feature, 'https'
+    /// URL, deduplicated and rate limited.
+    #[clap(long, env, value_parser = parse_url)]
+    error_webhook_url: Option<String>,
+
+    /// Payload of `--error-webhook-url`: a Slack compatible 'slack' message
+    /// or a 'json' object with all details.
+    #[clap(long, env, value_enum, default_value_t = WebhookFormat::Slack)]
+    error_webhook_format: WebhookFormat,
+
+    /// Maximum number of events posted to `--error-webhook-url` per minute.
+    #[clap(long, env, default_value_t = 10)]
+    error_webhook_rate: u32,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Start the delivery thread, if requested.
+    pub fn start(&self, version: &Version) -> EyreResult<Option<Webhook>> {
+        let Some(url) = &self.error_webhook_url else {
+            return Ok(None);
+        };
+        let source = Source {
+            app:      version.crate_name,
+            version:  version.pkg_version,
+            hostname: hostname(),
+        };
+        let webhook = Webhook::start(
+            url.parse()?,
+            self.error_webhook_format,
+            self.error_webhook_rate,
+            source,
+            RETRY_DELAY,
+        )?;
+        let _ = WEBHOOK.set(webhook.clone());
+        Ok(Some(webhook))
+    }
+}
+
+/// Deliver the events queued for `--error-webhook-url`, waiting at most
+/// [`FINISH_TIMEOUT`]. Later events are dropped.
+pub fn finish() {
+    if let Some(webhook) = WEBHOOK.get() {
+        webhook.finish();
+    }
+}
+
+fn parse_url(s: &str) -> Result<String, String> {
+    let url = s.parse::<Uri>().map_err(|err| err.to_string())?;
+    match url.scheme_str() {
+        Some("http") if url.host().is_some() => Ok(s.to_owned()),
+        #[cfg(feature = "tls")]
+        Some("https") if url.host().is_some() => Ok(s.to_owned()),
+        #[cfg(not(feature = "tls"))]
+        Some("https") => Err("https needs the `tls` feature".to_owned()),
+        _ => Err(format!("expected an http URL, got `{s}`")),
+    }
+}
+
+/// Where the events come from.
+#[derive(Clone, Debug)]
+struct Source {
+    app:      &'static str,
+    version:  &'static str,
+    hostname: String,
+}
+
+/// Queues every event it sees for the webhook. Filter it to ERROR.
+#[derive(Clone)]
+pub struct Webhook(Arc<Inner>);
+
+struct Inner {
+    format: WebhookFormat,
+    source: Source,
+    rate:   RateLimit,
+    /// Taken by [`Webhook::finish`], which ends the delivery thread.
+    queue:  Mutex<Option<Sender<Value>>>,
+    /// Disconnected when the delivery thread ends.
+    done:   Mutex<Option<std_mpsc::Receiver<()>>>,
+}
+
+impl Webhook {
+    fn start(
+        url: Uri,
+        format: WebhookFormat,
+        per_minute: u32,
+        source: Source,
+        retry_delay: Duration,
+    ) -> EyreResult<Self> {
+        let (sender, receiver) = channel(QUEUE_LEN);
+        let (done_sender, done) = std_mpsc::channel::<()>();
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .wrap_err("Error creating the error webhook runtime")?;
+        thread::Builder::new()
+            .name("error-webhook".to_owned())
+            .spawn(move || {
+                runtime.block_on(deliver(url, receiver, retry_delay));
+                drop(done_sender);
+            })
+            .wrap_err("Error starting the error webhook thread")?;
+        Ok(Self(Arc::new(Inner {
+            format,
+            source,
+            rate: RateLimit::new(per_minute, Instant::now()),
+            queue: Mutex::new(Some(sender)),
+            done: Mutex::new(Some(done)),
+        })))
+    }
+
+    /// Stop taking events and wait at most [`FINISH_TIMEOUT`] for the queued
+    /// ones to be delivered.
+    #[allow(clippy::missing_panics_doc)] // Never panics
+    pub fn finish(&self) {
+        drop(self.0.queue.lock().unwrap().take());
+        let done = self.0.done.lock().unwrap().take();
+        if let Some(done) = done {
+            if done.recv_timeout(FINISH_TIMEOUT) == Err(RecvTimeoutError::Timeout) {
+                eprintln!("Error: error webhook did not finish in time");
+            }
+        }
+    }
+
+    fn payload(&self, alert: &Alert) -> Value {
+        let Source {
+            app,
+            version,
+            hostname,
+        } = &self.0.source;
+        match self.0.format {
+            WebhookFormat::Slack => {
+                let mut text = format!(
+                    "*{app} {version}* on `{hostname}`: {}\ntarget `{}`",
+                    slack_escape(&alert.message),
+                    slack_escape(&alert.target)
+                );
+                if let Some(trace_id) = &alert.trace_id {
+                    let _ = write!(text, ", trace `{trace_id}`");
+                }
+                if alert.occurrences > 1 {
+                    let _ = write!(
+                        text,
+                        ", {} occurrences since the last report",
+                        alert.occurrences
+                    );
+                }
+                json!({ "text": text })
+            }
+            WebhookFormat::Json => {
+                let fields = alert
+                    .fields
+                    .iter()
+                    .map(|(name, value)| ((*name).to_owned(), Value::from(value.as_str())))
+                    .collect::<Map<_, _>>();
+                json!({
+                    "timestamp": alert.timestamp,
+                    "level": "ERROR",
+                    "app": app,
+                    "version": version,
+                    "hostname": hostname,
+                    "target": alert.target,
+                    "message": alert.message,
+                    "fields": fields,
+                    "trace_id": alert.trace_id,
+                    "occurrences": alert.occurrences,
+                })
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for Webhook
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        // The level filter lets the first event after installation through
+        if *meta.level() != Level::ERROR {
+            return;
+        }
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+
+        // Events carry no error report, so repeats are grouped by callsite.
+        let callsite = match (meta.file(), meta.line()) {
+            (Some(file), Some(line)) => format!("{file}:{line}"),
+            _ => meta.name().to_owned(),
+        };
+        let Some(admission) = report_limit::admit(&callsite, &Report::msg("event")) else {
+            return;
+        };
+        if !self.0.rate.admit(Instant::now()) {
+            WEBHOOK_DROPPED.add(1);
+            return;
+        }
+
+        let alert = Alert {
+            timestamp:   Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            target:      meta.target().to_owned(),
+            message:     visitor.message,
+            fields:      visitor.fields,
+            trace_id:    trace_id(event, &ctx),
+            occurrences: admission.occurrences_since_last_report,
+        };
+        let payload = self.payload(&alert);
+        let sent = self
+            .0
+            .queue
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|queue| queue.try_send(payload));
+        if let None | Some(Err(TrySendError::Full(_) | TrySendError::Closed(_))) = sent {
+            WEBHOOK_DROPPED.add(1);
+        }
+    }
+}
+
+/// An event to post.
+struct Alert {
+    timestamp:   String,
+    target:      String,
+    message:     String,
+    fields:      Vec<(&'static str, String)>,
+    trace_id:    Option<String>,
+    occurrences: u64,
+}
+
+#[cfg(feature = "tls")]
+type Connector = HttpsConnector<HttpConnector>;
+
+#[cfg(not(feature = "tls"))]
+type Connector = HttpConnector;
+
+#[cfg(feature = "tls")]
+fn client() -> Client<Connector> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+#[cfg(not(feature = "tls"))]
+fn client() -> Client<Connector> {
+    Client::new()
+}
+
+/// Post the payloads from `queue` until it is closed and empty.
+async fn deliver(url: Uri, mut queue: Receiver<Value>, retry_delay: Duration) {
+    let client = client();
+    while let Some(payload) = queue.recv().await {
+        if !post(&client, &url, &payload.to_string(), retry_delay).await {
+            WEBHOOK_FAILED.add(1);
+        }
+    }
+}
+
+/// Post `body`, with retries. Returns whether it was accepted.
+async fn post(client: &Client<Connector>, url: &Uri, body: &str, retry_delay: Duration) -> bool {
+    let mut delay = retry_delay;
+    for attempt in 1..=ATTEMPTS {
+        let request = Request::post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        if let Ok(Ok(response)) = timeout(REQUEST_TIMEOUT, client.request(request)).await {
+            let status = response.status();
+            if status.is_success() {
+                return true;
+            }
+            // The same request would be rejected again, unless throttled
+            if status.is_client_error() && status.as_u16() != 429 {
+                return false;
+            }
+        }
+        if attempt < ATTEMPTS {
+            sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    false
+}
+
+/// At most `per_minute` admissions per window of a minute.
+struct RateLimit {
+    per_minute: u32,
+    /// Start of the current window and the admissions in it.
+    window:     Mutex<(Instant, u32)>,
+}
+
+impl RateLimit {
+    const fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            per_minute,
+            window: Mutex::new((now, 0)),
+        }
+    }
+
+    fn admit(&self, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= RATE_WINDOW {
+            *window = (now, 0);
+        }
+        if window.1 >= self.per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+/// Escape the characters Slack interprets as markup in a message.
+fn slack_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+    use std::{convert::Infallible, net::SocketAddr};
+    use tracing::{error, info, info_span};
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Registry};
+
+    fn source() -> Source {
+        Source {
+            app:      "test",
+            version:  "v1.2.3",
+            hostname: "host-1".to_owned(),
+        }
+    }
+
+    /// A webhook endpoint capturing the posted payloads.
+    struct Endpoint {
+        url:      String,
+        payloads: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl Endpoint {
+        /// Responds with `statuses` in turn, then with 200.
+        fn start(statuses: Vec<StatusCode>) -> Self {
+            let payloads = Arc::new(Mutex::new(Vec::new()));
+            let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+            let (addr_sender, addr) = std_mpsc::channel::<SocketAddr>();
+            let captured = payloads.clone();
+            thread::spawn(move || {
+                let runtime = runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async move {
+                    let make_service = make_service_fn(move |_| {
+                        let captured = captured.clone();
+                        let statuses = statuses.clone();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |request| {
+                                let captured = captured.clone();
+                                let status = statuses.lock().unwrap().next();
+                                async move {
+                                    let body = hyper::body::to_bytes(request.into_body()).await?;
+                                    captured
+                                        .lock()
+                                        .unwrap()
+                                        .push(serde_json::from_slice(&body).unwrap());
+                                    let mut response = Response::new(Body::empty());
+                                    *response.status_mut() = status.unwrap_or(StatusCode::OK);
+                                    Ok::<_, hyper::Error>(response)
+                                }
+                            }))
+                        }
+                    });
+                    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+                    addr_sender.send(server.local_addr()).unwrap();
+                    server.await.unwrap();
+                });
+            });
+            let url = format!("http://{}/hook", addr.recv().unwrap());
+            Self { url, payloads }
+        }
+
+        fn payloads(&self) -> Vec<Value> {
+            self.payloads.lock().unwrap().clone()
+        }
+    }
+
+    fn start(endpoint: &Endpoint, format: WebhookFormat, per_minute: u32) -> Webhook {
+        let url = endpoint.url.parse().unwrap();
+        Webhook::start(url, format, per_minute, source(), Duration::from_millis(10)).unwrap()
+    }
+
+    /// Run `f` with `webhook` as the only layer and deliver its events.
+    fn with_webhook(webhook: &Webhook, f: impl FnOnce()) {
+        let subscriber = Registry::default().with(webhook.clone().with_filter(LevelFilter::ERROR));
+        tracing::subscriber::with_default(subscriber, f);
+        webhook.finish();
+    }
+
+    #[test]
+    fn test_json() {
+        let endpoint = Endpoint::start(vec![]);
+        let webhook = start(&endpoint, WebhookFormat::Json, 10);
+        with_webhook(&webhook, || {
+            info!("not an error");
+            info_span!("request", id = 7).in_scope(|| {
+                error!(target: "app::db", table = "users", "query failed");
+            });
+            for _ in 0..5 {
+                error!(target: "app::db", "repeated");
+            }
+        });
+        let payloads = endpoint.payloads();
+        assert_eq!(payloads.len(), 2, "{payloads:?}");
+        let mut first = payloads[0].clone();
+        assert!(first["timestamp"].as_str().unwrap().ends_with('Z'));
+        first["timestamp"] = Value::Null;
+        assert_eq!(
+            first,
+            json!({
+                "timestamp": null,
+                "level": "ERROR",
+                "app": "test",
+                "version": "v1.2.3",
+                "hostname": "host-1",
+                "target": "app::db",
+                "message": "query failed",
+                "fields": { "table": "\"users\"" },
+                "trace_id": null,
+                "occurrences": 1,
+            })
+        );
+        // Repeats are deduplicated
+        assert_eq!(payloads[1]["message"], "repeated");
+    }
+
+    #[test]
+    fn test_slack() {
+        let endpoint = Endpoint::start(vec![]);
+        let webhook = start(&endpoint, WebhookFormat::Slack, 10);
+        with_webhook(&webhook, || {
+            error!(target: "app", "disk <full> & failing");
+        });
+        assert_eq!(endpoint.payloads(), [json!({
+            "text": "*test v1.2.3* on `host-1`: disk &lt;full&gt; &amp; failing\ntarget `app`"
+        })]);
+    }
+
+    #[test]
+    fn test_rate() {
+        let endpoint = Endpoint::start(vec![]);
+        let webhook = start(&endpoint, WebhookFormat::Json, 2);
+        let dropped = WEBHOOK_DROPPED.get();
+        with_webhook(&webhook, || {
+            error!("first");
+            error!("second");
+            error!("third");
+        });
+        let messages = endpoint
+            .payloads()
+            .iter()
+            .map(|payload| payload["message"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["first", "second"]);
+        assert!(WEBHOOK_DROPPED.get() > dropped);
+
+        let now = Instant::now();
+        let rate = RateLimit::new(2, now);
+        let admitted = [0, 10, 20, 59, 60, 61, 62]
+            .map(|seconds| rate.admit(now + Duration::from_secs(seconds)));
+        assert_eq!(admitted, [true, true, false, false, true, true, false]);
+    }
+
+    #[test]
+    fn test_retry() {
+        let endpoint = Endpoint::start(vec![StatusCode::SERVICE_UNAVAILABLE]);
+        let webhook = start(&endpoint, WebhookFormat::Json, 10);
+        with_webhook(&webhook, || error!("retried"));
+        let messages = endpoint
+            .payloads()
+            .iter()
+            .map(|payload| payload["message"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["retried", "retried"]);
+
+        let endpoint = Endpoint::start(vec![StatusCode::INTERNAL_SERVER_ERROR; 3]);
+        let webhook = start(&endpoint, WebhookFormat::Json, 10);
+        let failed = WEBHOOK_FAILED.get();
+        with_webhook(&webhook, || error!("failing"));
+        assert_eq!(endpoint.payloads().len(), 3);
+        assert!(WEBHOOK_FAILED.get() > failed);
+
+        let endpoint = Endpoint::start(vec![StatusCode::BAD_REQUEST]);
+        let webhook = start(&endpoint, WebhookFormat::Json, 10);
+        with_webhook(&webhook, || error!("rejected"));
+        assert_eq!(endpoint.payloads().len(), 1);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        // Nothing listens on the discard port
+        let url = "http://127.0.0.1:9/hook".parse().unwrap();
+        let webhook = Webhook::start(
+            url,
+            WebhookFormat::Json,
+            10,
+            source(),
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        let failed = WEBHOOK_FAILED.get();
+        with_webhook(&webhook, || error!("unreachable"));
+        assert!(WEBHOOK_FAILED.get() > failed);
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert!(parse_url("http://localhost:8080/hook").is_ok());
+        #[cfg(feature = "tls")]
+        assert!(parse_url("https://hooks.slack.com/services/x").is_ok());
+        #[cfg(not(feature = "tls"))]
+        assert_eq!(
+            parse_url("https://hooks.slack.com/services/x").unwrap_err(),
+            "https needs the `tls` feature"
+        );
+        assert!(parse_url("/hook").is_err());
+        assert!(parse_url("ftp://host/hook").is_err());
+        assert!(parse_url("not a url").is_err());
+    }
+}
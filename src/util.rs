@@ -0,0 +1,560 @@
+//! Small helpers for application code.
+use once_cell::unsync::Lazy;
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    future::Future,
+    thread,
+};
+use tokio::runtime::Handle;
+use tracing::{debug, error, warn, Instrument, Span};
+
+/// Run code when the current scope exits.
+///
+/// The cleanup runs on normal exit, early return and unwinding alike. Whether
+/// the scope exited by unwinding is logged, and the outcome is recorded as
+/// `cleanup.ok` on the span that was current when the guard was created. The
+/// span must declare the field (`cleanup.ok = tracing::field::Empty`) for the
+/// value to show up.
+///
+/// ```
+/// # use cli_batteries::defer;
+/// # use tracing::info;
+/// defer! { info!("released lock") }
+/// ```
+#[macro_export]
+macro_rules! defer {
+    ($($body:tt)*) => {
+        let _defer_guard = $crate::util::Defer::new(|| {
+            $($body)*
+        });
+    };
+}
+
+/// Run fallible async code when the current scope exits.
+///
+/// The body is wrapped in an `async move` block and spawned on the Tokio
+/// runtime that is current when the guard is dropped. It must evaluate to a
+/// `Result` whose error implements [`Display`]; `cleanup.ok` is recorded
+/// accordingly. Without a runtime, or when the runtime shuts down before the
+/// cleanup completed, a warning is logged and `cleanup.ok` is `false`.
+///
+/// ```ignore
+/// defer_result! { connection.close().await }
+/// ```
+#[macro_export]
+macro_rules! defer_result {
+    ($($body:tt)*) => {
+        let _defer_guard = $crate::util::AsyncDefer::new(async move {
+            $($body)*
+        });
+    };
+}
+
+/// Panic if an invariant does not hold, logging it first.
+///
+/// Unlike [`assert!`], the violation is logged as an error event through the
+/// subscriber before unwinding, with the message, the `key = value` fields,
+/// the source location and the current span path. Field values are recorded
+/// with their [`Debug`](std::fmt::Debug) implementation.
+///
+/// ```
+/// # use cli_batteries::ensure_invariant;
+/// let remaining = 0;
+/// ensure_invariant!(remaining == 0, "queue drained", remaining = remaining);
+/// ```
+#[macro_export]
+macro_rules! ensure_invariant {
+    ($cond:expr, $message:expr $(, $($key:ident).+ = $value:expr)* $(,)?) => {
+        if !$cond {
+            let message = $message;
+            $crate::__log_invariant!($cond, message $(, $($key).+ = $value)*);
+            ::std::panic!("Invariant violated: {}", message);
+        }
+    };
+}
+
+/// Like [`ensure_invariant!`](crate::ensure_invariant), but return an
+/// [`eyre::Report`] from the enclosing function instead of panicking.
+///
+/// ```
+/// # use cli_batteries::try_ensure_invariant;
+/// fn drain(remaining: usize) -> eyre::Result<()> {
+///     try_ensure_invariant!(remaining == 0, "queue drained", remaining = remaining);
+///     Ok(())
+/// }
+/// assert!(drain(3).is_err());
+/// ```
+#[macro_export]
+macro_rules! try_ensure_invariant {
+    ($cond:expr, $message:expr $(, $($key:ident).+ = $value:expr)* $(,)?) => {
+        if !$cond {
+            let message = $message;
+            $crate::__log_invariant!($cond, message $(, $($key).+ = $value)*);
+            return ::std::result::Result::Err(
+                $crate::util::__eyre::eyre!("Invariant violated: {}", message).into(),
+            );
+        }
+    };
+}
+
+/// [`debug!`](tracing::debug) with field values computed by closures, only
+/// when a layer records them. Each value is wrapped in [`lazy_field`] and
+/// recorded with its [`Debug`] implementation.
+///
+/// ```
+/// # use cli_batteries::debug_lazy;
+/// let rows = vec![1, 2, 3];
+/// debug_lazy!(target: "app::db", total = || rows.iter().sum::<i32>(), "loaded");
+/// debug_lazy!(rows.max = || rows.iter().max(), "loaded {} rows", rows.len());
+/// ```
+#[macro_export]
+macro_rules! debug_lazy {
+    (target: $target:expr, $($rest:tt)+) => {
+        $crate::debug_lazy!(@fields (target: $target,) () $($rest)+)
+    };
+    // Fields are taken one at a time, a `,` does not tell them from the message.
+    (@fields ($($target:tt)*) ($($fields:tt)*) $($key:ident).+ = $value:expr, $($rest:tt)+) => {
+        $crate::debug_lazy!(
+            @fields ($($target)*) ($($fields)* $($key).+ = ?$crate::lazy_field($value),) $($rest)+
+        )
+    };
+    (@fields ($($target:tt)*) ($($fields:tt)*) $($arg:tt)+) => {
+        $crate::util::__tracing::debug!($($target)* $($fields)* $($arg)+)
+    };
+    ($($rest:tt)+) => {
+        $crate::debug_lazy!(@fields () () $($rest)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_invariant {
+    ($cond:expr, $message:ident $(, $($key:ident).+ = $value:expr)*) => {
+        $crate::util::__tracing::error!(
+            invariant = ::std::stringify!($cond),
+            $($($key).+ = ?$value,)*
+            file = ::std::file!(),
+            line = ::std::line!(),
+            span_path = %$crate::util::span_path(),
+            "Invariant violated: {}",
+            $message
+        )
+    };
+}
+
+#[doc(hidden)]
+pub use eyre as __eyre;
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// The current span path, like `handle_request{id=7}::query`.
+///
+/// Requires the [`ErrorLayer`](tracing_error::ErrorLayer) that is part of the
+/// default subscriber, and is empty without it.
+#[must_use]
+pub fn span_path() -> String {
+    crate::context::span_path(None)
+}
+
+/// Compute a field value only when a layer records it.
+///
+/// `tracing` already skips the field expressions of events that no layer is
+/// interested in. But a value computed before the event, or an event enabled
+/// for one layer (like the session log at DEBUG), pays for the computation
+/// even when no output shows it. Recording the field with `%` or `?` calls
+/// `compute` the first time and reuses the result for the other layers.
+///
+/// ```
+/// # use cli_batteries::lazy_field;
+/// # use tracing::debug;
+/// let rows = vec![1, 2, 3];
+/// debug!(json = %lazy_field(|| serde_json::to_string(&rows).unwrap()), "loaded");
+/// ```
+pub const fn lazy_field<T, F: FnOnce() -> T>(compute: F) -> LazyField<T, F> {
+    LazyField(Lazy::new(compute))
+}
+
+/// Value created by [`lazy_field`], formatted like the result of the closure.
+pub struct LazyField<T, F: FnOnce() -> T>(Lazy<T, F>);
+
+impl<T: Debug, F: FnOnce() -> T> Debug for LazyField<T, F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+impl<T: Display, F: FnOnce() -> T> Display for LazyField<T, F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&*self.0, f)
+    }
+}
+
+/// Guard created by [`defer!`](crate::defer).
+#[must_use = "the cleanup runs when the guard is dropped"]
+pub struct Defer<F: FnOnce()> {
+    cleanup: Option<F>,
+    span:    Span,
+}
+
+impl<F: FnOnce()> Defer<F> {
+    pub fn new(cleanup: F) -> Self {
+        Self {
+            cleanup: Some(cleanup),
+            span:    Span::current(),
+        }
+    }
+}
+
+impl<F: FnOnce()> Drop for Defer<F> {
+    fn drop(&mut self) {
+        let unwinding = thread::panicking();
+        let _enter = self.span.enter();
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup();
+        }
+        log_exit(unwinding);
+        self.span.record("cleanup.ok", true);
+    }
+}
+
+/// Guard created by [`defer_result!`](crate::defer_result).
+#[must_use = "the cleanup runs when the guard is dropped"]
+pub struct AsyncDefer<Fut, T, E>
+where
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    E: Display,
+{
+    cleanup: Option<Fut>,
+    span:    Span,
+}
+
+impl<Fut, T, E> AsyncDefer<Fut, T, E>
+where
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    E: Display,
+{
+    pub fn new(cleanup: Fut) -> Self {
+        Self {
+            cleanup: Some(cleanup),
+            span:    Span::current(),
+        }
+    }
+}
+
+impl<Fut, T, E> Drop for AsyncDefer<Fut, T, E>
+where
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    E: Display,
+{
+    fn drop(&mut self) {
+        let unwinding = thread::panicking();
+        let _enter = self.span.enter();
+        log_exit(unwinding);
+        let Some(cleanup) = self.cleanup.take() else {
+            return;
+        };
+        let Ok(runtime) = Handle::try_current() else {
+            warn!("No Tokio runtime available, skipping deferred cleanup");
+            self.span.record("cleanup.ok", false);
+            return;
+        };
+        // A runtime that is shutting down drops the task without running it.
+        let unfinished = Unfinished(Some(self.span.clone()));
+        runtime.spawn(
+            async move {
+                let result = cleanup.await;
+                unfinished.finish();
+                if let Err(err) = &result {
+                    error!(%err, "Deferred cleanup failed");
+                }
+                Span::current().record("cleanup.ok", result.is_ok());
+            }
+            .instrument(self.span.clone()),
+        );
+    }
+}
+
+/// Part of the task of an [`AsyncDefer`], warns when the task is dropped before
+/// the cleanup completed.
+struct Unfinished(Option<Span>);
+
+impl Unfinished {
+    fn finish(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        if let Some(span) = self.0.take() {
+            let _enter = span.enter();
+            warn!("Tokio runtime shut down before the deferred cleanup completed");
+            span.record("cleanup.ok", false);
+        }
+    }
+}
+
+fn log_exit(unwinding: bool) {
+    if unwinding {
+        warn!("Scope exited by unwinding, running deferred cleanup");
+    } else {
+        debug!("Scope exited, running deferred cleanup");
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::AsyncDefer;
+    use crate::trace::test::Capture;
+    use std::{
+        future::pending,
+        panic::catch_unwind,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+    use tokio::{runtime::Builder, sync::oneshot, task::yield_now};
+    use tracing::{field::Empty, info, info_span};
+    use tracing_error::ErrorLayer;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+    use tracing_test::traced_test;
+
+    #[test]
+    #[traced_test]
+    fn test_normal_exit() {
+        let count = AtomicUsize::new(0);
+        let span = info_span!("scope", cleanup.ok = Empty);
+        let _enter = span.enter();
+        {
+            defer! { count.fetch_add(1, Ordering::SeqCst); }
+            assert_eq!(count.load(Ordering::SeqCst), 0);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        info!("after scope");
+        assert!(logs_contain("Scope exited, running deferred cleanup"));
+        assert!(logs_contain("cleanup.ok=true"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_early_return() {
+        let count = AtomicUsize::new(0);
+        let early = |stop: bool| {
+            defer! { count.fetch_add(1, Ordering::SeqCst); }
+            if stop {
+                return;
+            }
+            count.fetch_add(10, Ordering::SeqCst);
+        };
+        early(true);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        early(false);
+        assert_eq!(count.load(Ordering::SeqCst), 12);
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_panic() {
+        let count = AtomicUsize::new(0);
+        let result = catch_unwind(|| {
+            defer! { count.fetch_add(1, Ordering::SeqCst); }
+            panic!("boom");
+        });
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(logs_contain("Scope exited by unwinding"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_async_cleanup() {
+        let (sender, receiver) = oneshot::channel();
+        {
+            defer_result! {
+                sender.send(()).map_err(|()| "receiver dropped")
+            }
+        }
+        receiver.await.unwrap();
+        assert!(!logs_contain("before the deferred cleanup completed"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_async_cleanup_error() {
+        {
+            defer_result! { Result::<(), &str>::Err("could not close") }
+        }
+        // Give the cleanup task a chance to run.
+        for _ in 0..10 {
+            yield_now().await;
+        }
+        assert!(logs_contain("Deferred cleanup failed"));
+        assert!(logs_contain("could not close"));
+    }
+
+    /// Runs `f` with a subscriber that logs to the returned capture.
+    fn captured(f: impl FnOnce()) -> String {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(capture.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        capture.contents()
+    }
+
+    #[test]
+    fn test_async_cleanup_without_runtime() {
+        let log = captured(|| {
+            let _span = info_span!("scope", cleanup.ok = Empty).entered();
+            let runtime = Builder::new_current_thread().build().unwrap();
+            let guard = runtime.block_on(async { AsyncDefer::new(async { Ok::<_, &str>(()) }) });
+            drop(runtime);
+            drop(guard);
+            info!("after scope");
+        });
+        assert!(log.contains("No Tokio runtime available"), "{log}");
+        assert!(
+            log.contains("scope{cleanup.ok=false}: cli_batteries::util::test: after scope"),
+            "{log}"
+        );
+    }
+
+    #[test]
+    fn test_async_cleanup_during_shutdown() {
+        let log = captured(|| {
+            let span = info_span!("scope", cleanup.ok = Empty);
+            let runtime = Builder::new_current_thread().build().unwrap();
+            let task_span = span.clone();
+            runtime.block_on(async move {
+                tokio::spawn(async move {
+                    let _guard =
+                        task_span.in_scope(|| AsyncDefer::new(async { Ok::<_, &str>(()) }));
+                    pending::<()>().await;
+                });
+                yield_now().await;
+            });
+            // Dropping the runtime drops the pending task and with it the guard.
+            drop(runtime);
+            span.in_scope(|| info!("after scope"));
+        });
+        assert!(log.contains("deferred cleanup completed"), "{log}");
+        assert!(
+            log.contains("scope{cleanup.ok=false}: cli_batteries::util::test: after scope"),
+            "{log}"
+        );
+    }
+
+    #[test]
+    fn test_invariant_logged_before_unwind() {
+        let capture = Capture::default();
+        let subscriber = Registry::default().with(ErrorLayer::default()).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(capture.clone()),
+        );
+        let logged_while_unwinding = AtomicBool::new(false);
+        let result = tracing::subscriber::with_default(subscriber, || {
+            catch_unwind(|| {
+                let _span = info_span!("drain", queue = "jobs").entered();
+                defer! {
+                    logged_while_unwinding.store(
+                        capture.contents().contains("Invariant violated: queue drained"),
+                        Ordering::SeqCst,
+                    );
+                }
+                let remaining = 3;
+                ensure_invariant!(remaining == 0, "queue drained", remaining = remaining);
+            })
+        });
+        let panic = result.unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            "Invariant violated: queue drained"
+        );
+        assert!(logged_while_unwinding.load(Ordering::SeqCst));
+        let log = capture.contents();
+        assert!(log.contains("ERROR"), "{log}");
+        assert!(log.contains("invariant=\"remaining == 0\""), "{log}");
+        assert!(log.contains("remaining=3"), "{log}");
+        assert!(log.contains("file=\"src/util.rs\""), "{log}");
+        assert!(log.contains("span_path=drain{queue=\"jobs\"}"), "{log}");
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_invariant_holds() {
+        ensure_invariant!(1 + 1 == 2, "arithmetic works");
+        assert!(!logs_contain("Invariant violated"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_try_invariant() {
+        fn drain(remaining: usize, queue: &str) -> eyre::Result<()> {
+            try_ensure_invariant!(
+                remaining == 0,
+                format!("{queue} drained"),
+                remaining = remaining,
+                queue.name = queue,
+            );
+            Ok(())
+        }
+        drain(0, "jobs").unwrap();
+        let err = drain(2, "jobs").unwrap_err();
+        assert_eq!(err.to_string(), "Invariant violated: jobs drained");
+        assert!(logs_contain("Invariant violated: jobs drained"));
+        assert!(logs_contain("remaining=2"));
+        assert!(logs_contain("queue.name=\"jobs\""));
+    }
+
+    #[test]
+    fn test_lazy_field() {
+        use crate::lazy_field;
+        use tracing::{debug, trace};
+        use tracing_subscriber::{filter::LevelFilter, fmt, Layer};
+
+        let calls = AtomicUsize::new(0);
+        let compute = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            "expensive"
+        };
+        let capture = Capture::default();
+        let other = Capture::default();
+        let subscriber = Registry::default()
+            .with(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(capture.clone())
+                    .with_filter(LevelFilter::DEBUG),
+            )
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_writer(other.clone())
+                    .with_filter(LevelFilter::DEBUG),
+            );
+        tracing::subscriber::with_default(subscriber, || {
+            trace!(value = %lazy_field(compute), "filtered");
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+            debug!(value = %lazy_field(compute), "recorded");
+            debug_lazy!(target: "app", value = compute, count = || 2, "macro");
+        });
+
+        // Once per event, shared by both layers.
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let log = capture.contents();
+        assert!(log.contains("recorded value=expensive"), "{log}");
+        assert!(log.contains(r#"macro value="expensive" count=2"#), "{log}");
+        assert!(other.contents().contains(r#""value":"expensive""#));
+        assert_eq!(format!("{:?}", lazy_field(|| "text")), r#""text""#);
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_async_without_runtime() {
+        {
+            defer_result! { Result::<(), &str>::Ok(()) }
+        }
+        assert!(logs_contain("No Tokio runtime available"));
+    }
+}
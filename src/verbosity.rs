@@ -0,0 +1,233 @@
+//! Temporarily more verbose logging for one request.
+//!
+//! [`elevated`] runs a future in a marker span. Events and spans inside it,
+//! including those of tasks spawned with
+//! [`in_current_span`](tracing::Instrument::in_current_span), are logged and
+//! exported up to the given level, regardless of `--log-filter` and
+//! `--verbose`. Everything outside keeps the configured filter.
+//!
+//! ```rust,ignore
+//! use cli_batteries::verbosity::elevated;
+//! use tracing::Level;
+//!
+//! let level = if allowlist.contains(&customer) { Level::TRACE } else { Level::INFO };
+//! let response = elevated(level, handle(request)).await;
+//! ```
+//!
+//! Until the first elevation, the log filters behave as before. After it,
+//! callsites the filters reject are checked for a marker on every call, up
+//! to [`MAX_DEPTH`] spans up the scope.
+use std::{
+    fmt::Debug,
+    future::Future,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::{
+    callsite::rebuild_interest_cache,
+    field::{Field, Visit},
+    instrument::Instrumented,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Instrument, Level, Metadata, Span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    layer::{Context, Filter},
+    registry::LookupSpan,
+};
+
+/// How many spans up the scope a marker is looked for.
+pub const MAX_DEPTH: usize = 32;
+
+const MARKER: &str = "elevated_logging";
+
+/// Set by the first elevation.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// The elevated level, in the extensions of a marker span.
+struct Elevation(LevelFilter);
+
+/// Run `future` with events up to `level` logged, see the
+/// [module docs](self).
+pub fn elevated<F: Future>(level: Level, future: F) -> Instrumented<F> {
+    future.instrument(elevated_span(level))
+}
+
+/// A marker span for synchronous code, like [`elevated`]:
+///
+/// ```rust,ignore
+/// elevated_span(Level::DEBUG).in_scope(|| retry(attempt));
+/// ```
+#[must_use]
+pub fn elevated_span(level: Level) -> Span {
+    if !IN_USE.swap(true, Ordering::Relaxed) {
+        // Filters cached their rejections, ask them again.
+        rebuild_interest_cache();
+    }
+    tracing::trace_span!(MARKER, level = %level)
+}
+
+/// A per-layer filter that also passes what an [`elevated`] scope asks for.
+pub(crate) struct Elevatable<F> {
+    inner: F,
+}
+
+impl<F> Elevatable<F> {
+    pub(crate) const fn new(inner: F) -> Self {
+        Self { inner }
+    }
+}
+
+/// The level of the nearest marker in the current scope.
+fn elevation<S>(cx: &Context<'_, S>) -> LevelFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    cx.lookup_current()
+        .into_iter()
+        .flat_map(|span| span.scope())
+        .take(MAX_DEPTH)
+        .find_map(|span| {
+            span.extensions()
+                .get::<Elevation>()
+                .map(|elevation| elevation.0)
+        })
+        .unwrap_or(LevelFilter::OFF)
+}
+
+fn is_marker(metadata: &Metadata<'_>) -> bool {
+    metadata.name() == MARKER && metadata.target() == module_path!()
+}
+
+impl<S, F> Filter<S> for Elevatable<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    F: Filter<S>,
+{
+    fn enabled(&self, metadata: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, cx)
+            || (IN_USE.load(Ordering::Relaxed)
+                && (is_marker(metadata) || elevation(cx) >= *metadata.level()))
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let interest = self.inner.callsite_enabled(metadata);
+        if interest.is_always() || !IN_USE.load(Ordering::Relaxed) {
+            interest
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &Context<'_, S>) -> bool {
+        self.inner.event_enabled(event, cx)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        if IN_USE.load(Ordering::Relaxed) {
+            Some(LevelFilter::TRACE)
+        } else {
+            self.inner.max_level_hint()
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if is_marker(attrs.metadata()) {
+            let mut visitor = LevelVisitor(None);
+            attrs.record(&mut visitor);
+            if let (Some(level), Some(span)) = (visitor.0, ctx.span(id)) {
+                // Every layer with this filter records the same marker.
+                span.extensions_mut().replace(Elevation(level));
+            }
+        }
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(id, values, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+}
+
+/// Reads the `level` field of a marker.
+struct LevelVisitor(Option<LevelFilter>);
+
+impl Visit for LevelVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "level" {
+            self.0 = LevelFilter::from_str(&format!("{value:?}")).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::trace::test::Capture;
+    use tokio::runtime;
+    use tracing::{debug, info, trace, trace_span};
+    use tracing_subscriber::{filter::Targets, fmt, layer::SubscriberExt, Layer, Registry};
+
+    #[test]
+    fn test_elevated() {
+        let capture = Capture::default();
+        let filter = Elevatable::new(Targets::new().with_default(Level::INFO));
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(capture.clone())
+                .with_filter(filter),
+        );
+        let runtime = runtime::Builder::new_current_thread().build().unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("debug before");
+            runtime.block_on(async {
+                elevated(Level::DEBUG, async {
+                    debug!("debug inside");
+                    trace!("trace inside");
+                    tokio::spawn(async { debug!("debug in child") }.in_current_span())
+                        .await
+                        .unwrap();
+                    trace_span!("nested").in_scope(|| debug!("debug nested"));
+                })
+                .await;
+                tokio::spawn(async { debug!("debug in other task") })
+                    .await
+                    .unwrap();
+            });
+            elevated_span(Level::TRACE).in_scope(|| trace!("trace in sync scope"));
+            debug!("debug after");
+            info!("info after");
+        });
+        let output = capture.contents();
+        for inside in [
+            "debug inside",
+            "debug in child",
+            "debug nested",
+            "trace in sync scope",
+            "info after",
+        ] {
+            assert!(output.contains(inside), "{inside} missing in {output}");
+        }
+        for outside in [
+            "debug before",
+            "trace inside",
+            "debug in other task",
+            "debug after",
+        ] {
+            assert!(!output.contains(outside), "{outside} in {output}");
+        }
+    }
+}
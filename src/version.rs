@@ -1,3 +1,11 @@
+use crate::features;
+use serde_json::{json, Value};
+use std::env;
+
+/// Only a modifier of `--version`, not a flag of its own, so it does not
+/// take the name from the application.
+const FLAG: &str = "--json";
+
 #[derive(Clone, Debug)]
 pub struct Version {
     pub pkg_name:     &'static str,
@@ -48,3 +56,28 @@ macro_rules! version {
         }
     };
 }
+
+/// Returns `true` if `--version --json` is on the command line.
+///
+/// Like `--dump-cli-spec`, this is checked before regular argument parsing, so
+/// that it works even when required application arguments are missing.
+pub fn json_requested() -> bool {
+    let args = env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .collect::<Vec<_>>();
+    args.iter().any(|arg| arg == FLAG) && args.iter().any(|arg| arg == "--version" || arg == "-V")
+}
+
+/// The version of `--version --json`, with the cargo features of this crate
+/// compiled into the binary.
+pub fn json(version: &Version) -> Value {
+    json!({
+        "name": version.pkg_name,
+        "version": version.pkg_version,
+        "repository": version.pkg_repo,
+        "commit": version.commit_hash,
+        "target": version.target,
+        "features": features(),
+    })
+}
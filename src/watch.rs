@@ -0,0 +1,178 @@
+//! `--watch`: rerun the app when files change, for a quick edit and run loop
+//! during development.
+//!
+//! The process, with its runtime and log outputs, stays up between the runs,
+//! so startup is paid once. Each run gets a root span `run` with the field
+//! `run.iteration`, counting from one. On a change the current run is stopped
+//! through [`await_shutdown`](crate::await_shutdown) and
+//! [`is_shutting_down`](crate::is_shutting_down), which in watch mode cover
+//! just that run, and the app is started again with its options parsed anew
+//! from the command line, so it reads its configuration files again.
+//!
+//! Changes are debounced, so saving several files starts one run. A run that
+//! ends, with or without an error, waits for the next change. Shutdown of the
+//! process, like Ctrl-C, ends the loop.
+#![cfg(feature = "watch")]
+use crate::{command, default_from_clap, exit_hint, shutdown, ErrorReport, Version};
+use clap::{Args, Parser};
+use eyre::{Report, Result, WrapErr};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use once_cell::sync::OnceCell;
+use std::{
+    env,
+    error::Error,
+    future::{pending, Future},
+    path::PathBuf,
+    pin::pin,
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::timeout,
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// How long to wait for more changes before starting a run.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// How long a stopped run has to finish before it is dropped.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+static PATHS: OnceCell<Vec<PathBuf>> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Rerun the app when a file in this file or directory changes, for
+    /// development. Repeatable. Ignored by apps that do not support it.
+    #[clap(long, env, value_name = "PATH", value_delimiter = ',')]
+    watch: Vec<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    pub fn init(self) {
+        let _ = PATHS.set(self.watch);
+    }
+}
+
+/// Run `app` once, or with `--watch` again on every change.
+pub async fn main<A, O, F, E>(version: Version, app: A, options: O) -> Result<()>
+where
+    A: Fn(O) -> F,
+    O: Args,
+    F: Future<Output = std::result::Result<(), E>>,
+    E: Into<Report> + Send + Sync + 'static,
+{
+    let paths = PATHS.get().map(Vec::as_slice).unwrap_or_default();
+    if paths.is_empty() {
+        return app(options).await.map_err(E::into);
+    }
+
+    let (sender, mut changes) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        match event {
+            // Reads, like those of the app itself, are not changes
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                let _ = sender.send(event.paths);
+            }
+            Ok(_) => {}
+            Err(err) => warn!(%err, "Error watching files"),
+        }
+    })
+    .wrap_err("Error starting file watcher")?;
+    for path in paths {
+        watcher
+            .watch(path, RecursiveMode::Recursive)
+            .wrap_err_with(|| format!("Error watching {}", path.display()))?;
+    }
+    info!(?paths, "Watching for changes");
+
+    let mut options = Some(options);
+    for iteration in 1_u64.. {
+        let span = info_span!(parent: None, "run", run.iteration = iteration);
+
+        // The first run uses the options parsed at startup
+        let options = match options.take().map_or_else(|| parse(&version), Ok) {
+            Ok(options) => options,
+            Err(report) => {
+                let error = ErrorReport(report);
+                span.in_scope(|| error!(error = &error as &dyn Error, "{error:?}"));
+                if next_change(&mut changes).await.is_none() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        shutdown::start_run();
+        let mut run = pin!(app(options).instrument(span.clone()));
+        let result = tokio::select! {
+            result = &mut run => Some(result.map_err(E::into)),
+            changed = debounced(&mut changes) => {
+                info!(?changed, "Files changed, restarting");
+                shutdown::stop_run();
+                timeout(STOP_GRACE, run).await.map_or_else(
+                    |_| {
+                        warn!(grace = ?STOP_GRACE, "Run did not stop in time, dropped it");
+                        None
+                    },
+                    |result| Some(result.map_err(E::into)),
+                )
+            },
+        };
+        if let Some(result) = result {
+            exit_hint::record(&span, result.as_ref().err());
+            if let Err(report) = result {
+                let error = ErrorReport(report);
+                span.in_scope(|| error!(error = &error as &dyn Error, "{error:?}"));
+            }
+        }
+        if shutdown::is_shutting_down() {
+            // Stopped for a restart
+            if !shutdown::is_process_shutting_down() {
+                continue;
+            }
+            break;
+        }
+        info!("Run finished, waiting for changes");
+        if next_change(&mut changes).await.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The app options from the command line and environment.
+fn parse<O: Args>(version: &Version) -> Result<O> {
+    let matches = command::<O>(version)
+        .try_get_matches_from(env::args_os())
+        .wrap_err("Error parsing the options again")?;
+    Ok(O::from_arg_matches(&matches)?)
+}
+
+/// The paths of the next debounced change, or `None` on process shutdown.
+async fn next_change(changes: &mut UnboundedReceiver<Vec<PathBuf>>) -> Option<Vec<PathBuf>> {
+    let mut process = shutdown::process_receiver();
+    tokio::select! {
+        changed = debounced(changes) => {
+            info!(?changed, "Files changed, restarting");
+            Some(changed)
+        }
+        () = shutdown::wait(&mut process) => None,
+    }
+}
+
+/// Wait for a change, and for the ones quickly following it.
+async fn debounced(changes: &mut UnboundedReceiver<Vec<PathBuf>>) -> Vec<PathBuf> {
+    let Some(mut paths) = changes.recv().await else {
+        return pending().await;
+    };
+    while let Ok(Some(more)) = timeout(DEBOUNCE, changes.recv()).await {
+        paths.extend(more);
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
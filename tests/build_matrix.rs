@@ -0,0 +1,274 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Feature combination harness.
+//!
+//! Builds `examples/minimal.rs` for a representative set of feature
+//! combinations and checks that `--help` lists exactly the flags each feature
+//! provides. These tests spawn nested `cargo` builds and are slow, so they are
+//! ignored by default. Run them with `cargo build-matrix`.
+use std::{env, path::PathBuf, process::Command};
+
+/// Flags provided by the crate and the features that all need to be enabled
+/// for them. An empty list means the flag is always present.
+const FLAGS: &[(&[&str], &str)] = &[
+    (&[], "--verbose"),
+    (&[], "--log-filter"),
+    (&[], "--log-format"),
+    (&[], "--trace-flame"),
+    (&["support-bundle"], "--support-bundle"),
+    (&["support-bundle"], "--support-bundle-include"),
+    (&["log-compress"], "--log-file-compress"),
+    (&["log-async"], "--log-async"),
+    (&["signals"], "--log-filter-file"),
+    (&["rand"], "--random-seed"),
+    (&["rayon"], "--threads"),
+    (&["prometheus"], "--prometheus"),
+    (&["prometheus", "tls"], "--metrics-tls-cert"),
+    (&["prometheus", "tls"], "--metrics-tls-key"),
+    (&["tokio-console"], "--tokio-console"),
+    (&["otlp"], "--trace-otlp"),
+    (&["otlp"], "--trace-resource"),
+    (&["otlp"], "--otlp-log-filter"),
+    (&["otlp"], "--trace-url"),
+    (&["otlp", "clipboard"], "--copy-trace-url"),
+    (&["otlp", "tls"], "--otlp-tls-ca"),
+    (&["otlp", "tls"], "--otlp-tls-cert"),
+    (&["otlp", "tls"], "--otlp-tls-key"),
+    (&["daemonize"], "--daemonize"),
+    (&["daemonize"], "--pid-file"),
+    (&["binary-log"], "--binary-log"),
+    (&["binary-log"], "--decode-binary-log"),
+    (&["binary-log"], "--record-events"),
+    (&["binary-log"], "--replay"),
+    (&["shmem-logs"], "--log-shmem"),
+    (&["shmem-logs"], "--log-shmem-size"),
+    (&["shmem-logs"], "--dump-shmem"),
+    (&["webhook"], "--error-webhook-url"),
+    (&["webhook"], "--error-webhook-format"),
+    (&["webhook"], "--error-webhook-rate"),
+    (&["watch"], "--watch"),
+];
+
+/// The only flags of a `minimal` build, next to those of the app.
+const MINIMAL: &[&str] = &[
+    "--verbose",
+    "--quiet",
+    "--log-filter",
+    "--help",
+    "--version",
+];
+
+/// Features implied by other features, see `Cargo.toml`.
+const IMPLIED: &[(&str, &[&str])] = &[
+    ("metered-allocator", &["prometheus"]),
+    ("shmem-logs", &["binary-log"]),
+    ("tonic", &["otlp"]),
+    ("full", &[
+        "support-bundle",
+        "log-compress",
+        "log-async",
+        "log-defaults",
+        "signals",
+        "metered-allocator",
+        "tokio-console",
+        "mimalloc",
+        "rand",
+        "rayon",
+        "prometheus",
+        "otlp",
+        "tls",
+        "daemonize",
+        "binary-log",
+        "journald",
+        "webhook",
+        "watch",
+        "tonic",
+        "clipboard",
+    ]),
+];
+
+fn enabled(features: &[&str]) -> Vec<String> {
+    let mut result: Vec<String> = features.iter().map(ToString::to_string).collect();
+    let mut i = 0;
+    while i < result.len() {
+        if let Some((_, implied)) = IMPLIED.iter().find(|(f, _)| *f == result[i]) {
+            result.extend(implied.iter().map(ToString::to_string));
+        }
+        i += 1;
+    }
+    result
+}
+
+fn help_output(features: &[&str]) -> String {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let output = Command::new(cargo)
+        .current_dir(&root)
+        .args([
+            "run",
+            "--quiet",
+            "--example",
+            "minimal",
+            "--no-default-features",
+        ])
+        .args(["--features", &features.join(",")])
+        // Separate target dir to avoid contending for the lock of the outer build.
+        .arg("--target-dir")
+        .arg(root.join("target").join("build-matrix"))
+        .args(["--", "--help"])
+        .output()
+        .expect("Could not run cargo");
+    assert!(
+        output.status.success(),
+        "Building with features {features:?} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn check(features: &[&str]) {
+    let help = help_output(features);
+    let enabled = enabled(features);
+    for (features, flag) in FLAGS {
+        let expected = features.iter().all(|f| enabled.iter().any(|e| e == f));
+        let found = help
+            .split_whitespace()
+            .any(|word| word.trim_end_matches([',', '.']) == *flag);
+        assert_eq!(
+            found, expected,
+            "Flag {flag} expected {expected} with features {features:?}, help was:\n{help}"
+        );
+    }
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn no_features() {
+    check(&[]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn signals() {
+    check(&["signals"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn rand_rayon() {
+    check(&["rand", "rayon"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn metrics_without_otlp() {
+    check(&["metered-allocator"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn console_without_otlp() {
+    check(&["tokio-console"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn otlp_without_console() {
+    check(&["otlp"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn otlp_with_metrics() {
+    check(&["otlp", "prometheus"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn tls_with_metrics() {
+    check(&["tls", "prometheus"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn shmem_logs() {
+    check(&["shmem-logs"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn journald() {
+    check(&["journald"]);
+    let help = help_output(&["journald"]);
+    assert!(
+        help.contains("[possible values: stderr, syslog, journald]"),
+        "Log target journald expected, help was:\n{help}"
+    );
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn webhook() {
+    check(&["webhook"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn watch() {
+    check(&["watch"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn tonic() {
+    check(&["tonic"]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn log_outputs() {
+    check(&[
+        "support-bundle",
+        "log-compress",
+        "log-async",
+        "log-defaults",
+    ]);
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn minimal() {
+    check_minimal(&["minimal", "signals"]);
+}
+
+/// Features that only add to the full startup build next to `minimal`.
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn minimal_is_additive() {
+    check_minimal(&["minimal", "tokio-console", "watch", "support-bundle"]);
+}
+
+fn check_minimal(features: &[&str]) {
+    let help = help_output(features);
+    let flags = help
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+        .filter(|word| word.starts_with("--") && *word != "--name")
+        .collect::<Vec<_>>();
+    for flag in MINIMAL {
+        assert!(
+            flags.contains(flag),
+            "Flag {flag} expected, help was:\n{help}"
+        );
+    }
+    for flag in flags {
+        assert!(
+            MINIMAL.contains(&flag),
+            "Flag {flag} unexpected, help was:\n{help}"
+        );
+    }
+}
+
+#[test]
+#[ignore = "slow, run with `cargo build-matrix`"]
+fn full() {
+    check(&["full"]);
+}
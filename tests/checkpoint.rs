@@ -0,0 +1,128 @@
+#![cfg(all(unix, feature = "signals", not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! A batch job killed and stopped mid-run, in a child process: the test
+//! binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::{await_shutdown, checkpoint::Store, is_shutting_down, run};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use serde_json::Value;
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    process::{self, Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// The directory of the checkpoints, in the child process.
+const DIR: &str = "CLI_BATTERIES_TEST_CHECKPOINTS";
+
+/// Items of the job, more than a child gets through.
+const ITEMS: u64 = 1_000_000;
+
+/// Process items until shut down, starting where the last run stopped.
+async fn app(_options: Options) -> Result<()> {
+    let store = Store::open(env::var(DIR)?)?;
+    let start = store.load::<u64>("items")?.unwrap_or(0);
+    println!("started at {start}");
+    let position = Arc::new(AtomicU64::new(start));
+    store.save_on_shutdown("items", {
+        let position = position.clone();
+        move || position.load(Ordering::Relaxed)
+    });
+    for item in start..ITEMS {
+        if is_shutting_down() {
+            break;
+        }
+        sleep(Duration::from_millis(1)).await;
+        position.store(item + 1, Ordering::Relaxed);
+    }
+    await_shutdown().await;
+    Ok(())
+}
+
+/// Start a child with `env` and wait until it started the job.
+fn spawn(dir: &Path, env: &[(&str, &str)]) -> (Child, u64) {
+    let mut child = child("checkpoint_resume", "1")
+        .env(DIR, dir)
+        .env("SESSION_LOG", "off")
+        .env("LOG_FILTER", "checkpoint=info,cli_batteries=info")
+        .envs(env.iter().copied())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let start = loop {
+        let mut line = String::new();
+        assert_ne!(stdout.read_line(&mut line).unwrap(), 0, "child exited");
+        // After the test name, libtest does not end its line before the test
+        if let Some((_, start)) = line.trim().split_once("started at ") {
+            break start.parse().unwrap();
+        }
+    };
+    // Keep reading, libtest fails on a closed stdout after the test
+    thread::spawn(move || io::copy(&mut stdout, &mut io::sink()));
+    (child, start)
+}
+
+/// The position in the checkpoint file.
+fn saved(dir: &Path) -> u64 {
+    let checkpoint: Value =
+        serde_json::from_slice(&fs::read(dir.join("items.json")).unwrap()).unwrap();
+    checkpoint["value"].as_u64().unwrap()
+}
+
+#[test]
+fn checkpoint_resume() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let dir = env::temp_dir().join(format!("cli-batteries-checkpoint-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    // Killed, after a few periodic saves
+    let (mut child, start) = spawn(&dir, &[("CHECKPOINT_INTERVAL", "50ms")]);
+    assert_eq!(start, 0);
+    thread::sleep(Duration::from_millis(500));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let killed = saved(&dir);
+    assert!(killed > 0);
+
+    // Resumed from the last periodic save, and saved when stopped
+    let (child, start) = spawn(&dir, &[]);
+    assert_eq!(start, killed);
+    thread::sleep(Duration::from_millis(200));
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Resuming from checkpoint saved"),
+        "{stderr}"
+    );
+    assert!(stderr.contains(&format!("ago at {killed}")), "{stderr}");
+    let stopped = saved(&dir);
+    assert!(stopped > killed);
+
+    // From the beginning
+    let (mut child, start) = spawn(&dir, &[("IGNORE_CHECKPOINT", "true")]);
+    assert_eq!(start, 0);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(saved(&dir), stopped);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,48 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--dump-cli-spec` of the `minimal` example, compared with
+//! `tests/snapshots/cli_spec.json`.
+use serde_json::{json, Value};
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// The `minimal` example, built by `cargo test` next to the test binaries.
+fn minimal() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("examples")
+        .join("minimal")
+}
+
+/// Set `UPDATE_SNAPSHOTS=1` to update the snapshot after an intentional
+/// change.
+#[test]
+fn snapshot() {
+    let output = Command::new(minimal())
+        .arg("--dump-cli-spec")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let mut spec: Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    // Only the version and feature independent part is stable across builds.
+    spec["version"] = Value::Null;
+    spec["features"] = json!([]);
+    spec["args"]
+        .as_array_mut()
+        .unwrap()
+        .retain(|arg| arg["feature"].is_null());
+    let actual = serde_json::to_string_pretty(&spec).unwrap() + "\n";
+
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/cli_spec.json");
+    if env::var("UPDATE_SNAPSHOTS").is_ok() {
+        fs::write(&path, &actual).unwrap();
+    }
+    let expected = fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        actual, expected,
+        "CLI spec changed, rerun with UPDATE_SNAPSHOTS=1 if intended"
+    );
+}
@@ -0,0 +1,59 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--color` and `NO_COLOR` in a child process with redirected output: the
+//! test binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{bail, Result};
+use std::process::Output;
+use tracing::info;
+
+/// Logs and fails, for an error report.
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    info!("logged event");
+    bail!("reported error");
+}
+
+/// Run the test `name` in a child process with `format` and the environment
+/// variables `envs`.
+fn run_child(name: &str, format: &str, envs: &[(&str, &str)]) -> Output {
+    child(name, "1")
+        .env("LOG_FILTER", "color=info")
+        .env("LOG_FORMAT", format)
+        .env_remove("COLOR")
+        .env_remove("NO_COLOR")
+        .env_remove("TERM")
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn color() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    for format in ["tiny", "compact", "pretty", "pretty-compact"] {
+        for (envs, colored) in [
+            (&[][..], false),
+            (&[("COLOR", "auto")][..], false),
+            (&[("COLOR", "always")][..], true),
+            (&[("COLOR", "always"), ("NO_COLOR", "1")][..], true),
+            (&[("COLOR", "always"), ("TERM", "dumb")][..], true),
+            (&[("COLOR", "never")][..], false),
+            (&[("NO_COLOR", "1")][..], false),
+        ] {
+            let output = run_child("color", format, envs);
+            assert!(!output.status.success(), "{output:?}");
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let context = format!("{format} {envs:?}: {stderr}");
+            assert!(stderr.contains("logged event"), "{context}");
+            assert!(stderr.contains("reported error"), "{context}");
+            assert_eq!(stderr.contains('\x1b'), colored, "{context}");
+        }
+    }
+}
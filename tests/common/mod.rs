@@ -0,0 +1,53 @@
+//! The child process fixture of the integration tests: a test runs the test
+//! binary again with [`CHILD`] set and only itself selected, and the child
+//! runs the app.
+#![allow(dead_code)] // Every test uses only part of it
+
+use clap::Parser;
+use cli_batteries::{default_from_clap, Version};
+use std::{env, ffi::OsStr, process::Command};
+
+pub const MOCK_VERSION: Version = Version {
+    pkg_name:     "cli-test",
+    pkg_version:  "v0.0.0",
+    pkg_repo:     "https://github.com/recmo/cli-batteries",
+    crate_name:   "test",
+    commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
+    long_version: "v0.0.0 First release",
+    target:       "aarch64-apple-darwin",
+    app_crates:   vec![],
+};
+
+/// Set in the child process.
+pub const CHILD: &str = "CLI_BATTERIES_TEST_CHILD";
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// The test runner arguments the child is started with.
+    filter: Option<String>,
+
+    #[clap(long)]
+    exact: bool,
+
+    #[clap(long)]
+    nocapture: bool,
+}
+
+default_from_clap!(Options);
+
+/// Whether this is the child process.
+pub fn is_child() -> bool {
+    env::var_os(CHILD).is_some()
+}
+
+/// Run the test `name` alone in a child process, with [`CHILD`] set to
+/// `value` and the session log off.
+pub fn child(name: &str, value: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new(env::current_exe().unwrap());
+    command
+        .args([name, "--exact", "--nocapture"])
+        .env(CHILD, value)
+        .env("SESSION_LOG", "off");
+    command
+}
@@ -0,0 +1,160 @@
+#![cfg(all(unix, not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Lifecycle handoffs, each run in a child process: the test binary runs
+//! itself again with [`CHILD`] set and only the one test selected.
+mod common;
+
+use cli_batteries::{logs::read_compressed, prepare_exec, run};
+use common::{child, Options, CHILD, MOCK_VERSION};
+use eyre::Result;
+use std::{
+    env, fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Command, Output, Stdio},
+};
+use tracing::info;
+
+#[cfg(feature = "daemonize")]
+use std::{io::Read, thread::sleep, time::Duration};
+
+/// A fresh directory for one test.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("cli-batteries-{name}-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The directory of the child, if this is one.
+fn child_dir() -> Option<PathBuf> {
+    env::var_os(CHILD).map(PathBuf::from)
+}
+
+/// Run `test` in a child process working in `dir`, with extra environment.
+fn spawn(test: &str, dir: &Path, envs: &[(&str, String)]) -> Output {
+    child(test, dir)
+        .env("SESSION_LOG", format!("dir={}", dir.display()))
+        .envs(envs.iter().cloned())
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// Session logs in `dir`.
+fn session_logs(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.ends_with(".log") || name.ends_with(".log.zst")
+        })
+        .collect()
+}
+
+async fn exec_app(_options: Options) -> Result<()> {
+    info!("before exec");
+    prepare_exec()?;
+    Err(Command::new("true").exec().into())
+}
+
+#[test]
+fn exec() {
+    if child_dir().is_some() {
+        run(MOCK_VERSION, exec_app);
+        return;
+    }
+    let dir = temp_dir("exec");
+    let output = spawn("exec", &dir, &[("LOG_FILE_COMPRESS", "true".to_owned())]);
+    assert!(output.status.success(), "{output:?}");
+
+    // With `log-compress`, complete zstd frames, not one cut short at the exec.
+    let logs = session_logs(&dir);
+    assert_eq!(logs.len(), 1, "{logs:?}");
+    let log = read_compressed(&logs[0]).unwrap();
+    let log = String::from_utf8(log).unwrap();
+    assert!(log.contains("before exec"), "{log}");
+    assert!(log.contains("Preparing to exec"), "{log}");
+    assert!(!log.contains("terminating"), "{log}");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Records what the daemon looks like, then waits for `release`.
+#[cfg(feature = "daemonize")]
+async fn daemon_app(_options: Options) -> Result<()> {
+    let dir = child_dir().unwrap();
+    #[allow(unsafe_code)]
+    // SAFETY: No preconditions.
+    let (pid, sid) = unsafe { (libc::getpid(), libc::getsid(0)) };
+    let mut stdin = String::new();
+    std::io::stdin().read_to_string(&mut stdin)?;
+    eprintln!("daemon stderr");
+    fs::write(dir.join("daemon"), format!("{pid} {sid} {stdin:?}\n"))?;
+    while !dir.join("release").exists() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    info!("daemon released");
+    Ok(())
+}
+
+/// Wait up to ten seconds for `path` to satisfy `done`.
+#[cfg(feature = "daemonize")]
+fn wait_for(path: &Path, done: impl Fn(&str) -> bool) -> String {
+    for _ in 0..200 {
+        if let Ok(contents) = fs::read_to_string(path) {
+            if done(&contents) {
+                return contents;
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+    panic!("timeout waiting for {}", path.display());
+}
+
+#[cfg(feature = "daemonize")]
+#[test]
+fn daemonize() {
+    if child_dir().is_some() {
+        run(MOCK_VERSION, daemon_app);
+        // Only the forked test thread is left in the daemon, exit for it.
+        process::exit(0);
+    }
+    let dir = temp_dir("daemonize");
+    let envs = [
+        ("DAEMONIZE", "true".to_owned()),
+        ("PID_FILE", dir.join("pid").display().to_string()),
+    ];
+
+    // Returns while the daemon waits, so its output no longer goes to the
+    // pipes of the original process.
+    let output = spawn("daemonize", &dir, &envs);
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stderr.is_empty(), "{output:?}");
+
+    let daemon = wait_for(&dir.join("daemon"), |text| text.ends_with('\n'));
+    let (pid, rest) = daemon.split_once(' ').unwrap();
+    let (sid, stdin) = rest.split_once(' ').unwrap();
+    assert_ne!(pid, sid, "daemon is a session leader");
+    assert_eq!(stdin.trim(), r#""""#, "stdin is not /dev/null");
+    let pid_file = wait_for(&dir.join("pid"), |text| text.ends_with('\n'));
+    assert_eq!(pid_file.trim(), pid);
+
+    // A second instance fails on the lock, in the foreground.
+    let output = spawn("daemonize", &dir, &envs);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("already running as pid {pid}")),
+        "{stderr}"
+    );
+
+    fs::write(dir.join("release"), "").unwrap();
+    let logs = session_logs(&dir);
+    assert_eq!(logs.len(), 1, "{logs:?}");
+    let log = wait_for(&logs[0], |log| log.contains("Program terminating normally"));
+    assert!(log.contains("daemon released"), "{log}");
+    let out = fs::read_to_string(dir.join("daemon.out")).unwrap();
+    assert!(out.contains("daemon stderr"), "{out}");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,110 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--debug-shutdown` with `Drop` impls that log or panic while the runtime
+//! drops the remaining tasks: the test binary runs itself again with
+//! [`common::CHILD`] set to the app to run.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::{future::pending, process::Output, thread};
+use tracing::warn;
+
+/// Logs when dropped.
+struct LogsOnDrop;
+
+impl Drop for LogsOnDrop {
+    fn drop(&mut self) {
+        warn!("connection closed");
+    }
+}
+
+/// Panics when dropped.
+struct PanicsOnDrop;
+
+impl Drop for PanicsOnDrop {
+    fn drop(&mut self) {
+        panic!("close failed");
+    }
+}
+
+/// Panics when dropped while unwinding, which aborts.
+struct PanicsWhileUnwinding;
+
+impl Drop for PanicsWhileUnwinding {
+    fn drop(&mut self) {
+        assert!(!thread::panicking(), "cleanup failed");
+    }
+}
+
+/// Leaves a task running, which the runtime drops after the shutdown.
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn late_event(_options: Options) -> Result<()> {
+    tokio::spawn(async {
+        let _connection = LogsOnDrop;
+        pending::<()>().await;
+    });
+    Ok(())
+}
+
+/// Like [`late_event`], with a task whose drop panics and panics again while
+/// unwinding.
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn panic_in_drop(_options: Options) -> Result<()> {
+    tokio::spawn(async {
+        let _guards = (PanicsOnDrop, PanicsWhileUnwinding);
+        pending::<()>().await;
+    });
+    Ok(())
+}
+
+/// Run the test `name` in a child process.
+fn run_child(name: &str) -> Output {
+    child(name, name)
+        .env("LOG_FILTER", "debug_shutdown=info")
+        .env("DEBUG_SHUTDOWN", "true")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn late_events() {
+    if is_child() {
+        run(MOCK_VERSION, late_event);
+        return;
+    }
+    let output = run_child("late_events");
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let summary = stderr
+        .split_once("Warning: 1 event arrived after shutdown began")
+        .unwrap_or_else(|| panic!("{stderr}"))
+        .1;
+    assert!(summary.contains("tests/debug_shutdown.rs:"), "{stderr}");
+    assert!(summary.contains(" WARN debug_shutdown (1x)"), "{stderr}");
+}
+
+#[test]
+fn panics_in_drop() {
+    if is_child() {
+        run(MOCK_VERSION, panic_in_drop);
+        return;
+    }
+    let output = run_child("panics_in_drop");
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let report = stderr
+        .lines()
+        .find(|line| line.contains("panic-in-drop during shutdown"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    assert!(report.contains("on thread '"), "{stderr}");
+    assert!(
+        report.contains("'cleanup failed' at tests/debug_shutdown.rs:"),
+        "{stderr}"
+    );
+    assert!(
+        report.contains("while unwinding from 'close failed'"),
+        "{stderr}"
+    );
+}
@@ -0,0 +1,50 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! The error a run fails with, in the `json` format in a child process: the
+//! test binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{Result, WrapErr as _};
+use serde_json::Value;
+use std::io;
+use tracing::instrument;
+
+#[instrument(fields(id = 3))]
+fn save() -> Result<()> {
+    Err(io::Error::other("disk full")).wrap_err("could not save")
+}
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    save().wrap_err("request failed")
+}
+
+#[test]
+fn error_fields_json() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let output = child("error_fields_json", "1")
+        .env("LOG_FORMAT", "json")
+        .env("LOG_FILTER", "error_fields=info,cli_batteries=info")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let fields = stderr
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .map(|line| line["fields"].clone())
+        .find(|fields| fields.get("error.message").is_some())
+        .unwrap_or_else(|| panic!("no error fields in {stderr}"));
+    assert_eq!(fields["error.message"], "request failed");
+    assert_eq!(
+        fields["error.chain"],
+        serde_json::json!(["could not save", "disk full"])
+    );
+    let span_trace = fields["error.span_trace"].as_str().unwrap();
+    assert!(span_trace.contains("error_fields::save"), "{span_trace}");
+}
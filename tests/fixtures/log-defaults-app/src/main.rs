@@ -0,0 +1,23 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+use cli_batteries::prelude::*;
+
+include_log_defaults!();
+
+#[derive(Clone, Debug, Parser)]
+#[group(skip)]
+struct Options {}
+
+default_from_clap!(Options);
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    info!("app info");
+    info!(target: "dep", "dep info");
+    warn!(target: "dep", "dep warn");
+    error!(target: "noisy", "noisy error");
+    Ok(())
+}
+
+fn main() {
+    Runner::new(version!()).log_defaults(LOG_DEFAULTS).run(app);
+}
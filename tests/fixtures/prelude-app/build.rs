@@ -0,0 +1,3 @@
+fn main() {
+    cli_batteries::build_rs().unwrap();
+}
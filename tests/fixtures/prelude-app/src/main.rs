@@ -0,0 +1,40 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+use cli_batteries::prelude::*;
+
+#[derive(Clone, Debug, Parser)]
+#[group(skip)]
+struct Options {
+    /// Name to greet
+    #[clap(long, env, default_value = "world")]
+    name: String,
+
+    /// How to greet
+    #[clap(long, value_enum, default_value_t = Greeting::Hello)]
+    greeting: Greeting,
+}
+
+default_from_clap!(Options);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Greeting {
+    Hello,
+    Goodbye,
+}
+
+#[instrument]
+async fn greet(greeting: Greeting, name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "No one to greet");
+    info!("{greeting:?}, {name}!");
+    Ok(())
+}
+
+async fn app(options: Options) -> Result<()> {
+    greet(options.greeting, &options.name)
+        .instrument(info_span!("app"))
+        .await
+        .wrap_err("Greeting failed")
+}
+
+fn main() {
+    run(version!(), app);
+}
@@ -0,0 +1,103 @@
+#![cfg(feature = "prometheus")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+use clap::Parser;
+use cli_batteries::{default_from_clap, ready, run, shutdown, Version};
+use eyre::{ensure, eyre, Result};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::sleep,
+};
+use tracing::{info, warn};
+
+const MOCK_VERSION: Version = Version {
+    pkg_name:     "cli-test",
+    pkg_version:  "v0.0.0",
+    pkg_repo:     "https://github.com/recmo/cli-batteries",
+    crate_name:   "test",
+    commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
+    long_version: "v0.0.0 First release",
+    target:       "aarch64-apple-darwin",
+    app_crates:   vec![],
+};
+
+const ADDR: &str = "127.0.0.1:19998";
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+struct Options {
+    /// Hack to make tests pass with `--nocapture`. The tests share arguments
+    /// with the test runner.
+    #[clap(long)]
+    nocapture: bool,
+}
+
+default_from_clap!(Options);
+
+/// Status code and body of `GET path`, waiting for the server to come up.
+async fn get(path: &str) -> Result<(u16, String)> {
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(connected) = TcpStream::connect(ADDR).await {
+            stream = Some(connected);
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let mut stream = stream.ok_or_else(|| eyre!("metrics server not listening"))?;
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {ADDR}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| eyre!("invalid response {response:?}"))?;
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    Ok((status, body.to_owned()))
+}
+
+async fn app(_options: Options) -> Result<()> {
+    let (status, body) = get("/healthz").await?;
+    ensure!(status == 200, "healthz {status}");
+    ensure!(body.starts_with("ok\nuptime_seconds "), "{body:?}");
+    ensure!(body.ends_with("\nversion v0.0.0\n"), "{body:?}");
+
+    // Starting
+    let (status, body) = get("/readyz").await?;
+    ensure!(status == 503, "readyz while starting {status}");
+    ensure!(body.starts_with("starting\n"), "{body:?}");
+
+    info!("not recorded");
+    warn!(attempt = 3, "disk almost full");
+    let (status, body) = get("/recent-errors").await?;
+    ensure!(status == 200, "recent-errors {status}");
+    let records: serde_json::Value = serde_json::from_str(&body)?;
+    let last = &records[records.as_array().map_or(0, Vec::len).saturating_sub(1)];
+    ensure!(last["message"] == "disk almost full", "{body}");
+    ensure!(last["level"] == "WARN", "{body}");
+    ensure!(last["fields"]["attempt"] == "3", "{body}");
+    ensure!(!body.contains("not recorded"), "{body}");
+
+    ready();
+    let (status, body) = get("/readyz").await?;
+    ensure!(status == 200, "readyz when ready {status}");
+    ensure!(body.starts_with("ready\n"), "{body:?}");
+
+    // Still serving while draining
+    shutdown();
+    let (status, body) = get("/readyz").await?;
+    ensure!(status == 503, "readyz while shutting down {status}");
+    ensure!(body.starts_with("shutting down\n"), "{body:?}");
+    let (status, _) = get("/healthz").await?;
+    ensure!(status == 200, "healthz while shutting down {status}");
+    Ok(())
+}
+
+#[test]
+fn health() {
+    std::env::set_var("PROMETHEUS", format!("http://{ADDR}/metrics"));
+    run(MOCK_VERSION, app);
+}
@@ -0,0 +1,85 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Messages in the language of a catalog the app registered: the test binary
+//! runs itself again with [`common::CHILD`] set to the app to run.
+mod common;
+
+use cli_batteries::{
+    i18n::{set_catalog, Catalog},
+    run,
+};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{eyre, Result};
+use std::{
+    env, fs,
+    process::{self, Output},
+};
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn fail(_options: Options) -> Result<()> {
+    Err(eyre!("boom"))
+}
+
+fn register() {
+    set_catalog(
+        Catalog::new("de")
+            .message(
+                "session-log-path",
+                "Das vollständige Log dieses Laufs ist in { $path }",
+            )
+            .message("error", "Fehler: { $error }"),
+    );
+}
+
+/// Run the test `name` in a child process with `LANG` set to `lang`.
+fn run_child(name: &str, lang: &str, envs: &[(&str, &str)]) -> Output {
+    child(name, name)
+        .env("LANG", lang)
+        .env("LOG_FILTER", "i18n=info")
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn footer() {
+    if is_child() {
+        register();
+        run(MOCK_VERSION, fail);
+        return;
+    }
+    let dir = env::temp_dir().join(format!("cli-batteries-i18n-{}", process::id()));
+    let session_log = format!("dir={}", dir.display());
+    let envs = [("SESSION_LOG", session_log.as_str())];
+
+    let output = run_child("footer", "de_DE.UTF-8", &envs);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Das vollständige Log dieses Laufs ist in "),
+        "{stderr}"
+    );
+
+    // English without a catalog for the language
+    let output = run_child("footer", "fr_FR.UTF-8", &envs);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("The full log of this run is in "),
+        "{stderr}"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn error() {
+    if is_child() {
+        register();
+        run(MOCK_VERSION, fail);
+        return;
+    }
+    let envs = [("LOG_FILTER", "i18n=bogus")];
+    let output = run_child("error", "de_AT.UTF-8", &envs);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Fehler: "), "{stderr}");
+}
@@ -0,0 +1,49 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! A panicking `Runner::layer` under the real panic hook: the test binary
+//! runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::Runner;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Panics on events mentioning `boom`.
+struct Panicking;
+
+impl<S: Subscriber> Layer<S> for Panicking {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        assert!(!format!("{event:?}").contains("boom"), "formatter bug");
+    }
+}
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    info!("boom");
+    info!("after the panic");
+    Ok(())
+}
+
+#[test]
+fn single_alert() {
+    if is_child() {
+        Runner::new(MOCK_VERSION).layer(|| Panicking).run(app);
+        return;
+    }
+    let output = child("single_alert", "1")
+        .env("LOG_FILTER", "layer_panic=info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("formatter bug").count(), 1, "{stderr}");
+    assert!(
+        stderr.contains("tracing layer panicked and is disabled"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("after the panic"), "{stderr}");
+    // Neither the crash output nor the regular panic report
+    assert!(!stderr.contains("pid="), "{stderr}");
+    assert!(!stderr.contains("panicked at"), "{stderr}");
+}
@@ -0,0 +1,53 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+use clap::Parser;
+use cli_batteries::{await_shutdown, default_from_clap, shutdown, Runner, Version};
+use eyre::{ensure, Result};
+use std::{cell::Cell, rc::Rc};
+use tokio::task::spawn_local;
+
+const MOCK_VERSION: Version = Version {
+    pkg_name:     "cli-test",
+    pkg_version:  "v0.0.0",
+    pkg_repo:     "https://github.com/recmo/cli-batteries",
+    crate_name:   "test",
+    commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
+    long_version: "v0.0.0 First release",
+    target:       "aarch64-apple-darwin",
+    app_crates:   vec![],
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+struct Options {
+    /// Hack to make tests pass with `--nocapture`. The tests share arguments
+    /// with the test runner.
+    #[clap(long)]
+    nocapture: bool,
+}
+
+default_from_clap!(Options);
+
+/// An app that is not `Send`: it holds an `Rc` across awaits and spawns local
+/// tasks that wait for shutdown.
+#[allow(clippy::future_not_send)]
+async fn app(_options: Options) -> Result<()> {
+    let counter = Rc::new(Cell::new(0));
+    let waiter = spawn_local({
+        let counter = counter.clone();
+        async move {
+            await_shutdown().await;
+            counter.set(counter.get() + 1);
+        }
+    });
+    tokio::task::yield_now().await;
+    ensure!(counter.get() == 0, "shut down early");
+    shutdown();
+    waiter.await?;
+    ensure!(counter.get() == 1, "local task did not observe shutdown");
+    Ok(())
+}
+
+#[test]
+fn local() {
+    Runner::new(MOCK_VERSION).local(true).run(app);
+}
@@ -0,0 +1,77 @@
+#![cfg(all(feature = "log-async", not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--log-async` drains its queue before the process exits, in a child
+//! process: the test binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{bail, Result};
+use std::process::Output;
+use tracing::info;
+
+const EVENTS: usize = 10_000;
+
+fn log_events() {
+    for i in 0..EVENTS {
+        info!(i, "event");
+    }
+}
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn succeed(_options: Options) -> Result<()> {
+    log_events();
+    Ok(())
+}
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn fail(_options: Options) -> Result<()> {
+    log_events();
+    bail!("app failed");
+}
+
+/// Run the test `name` in a child process with `--log-async`.
+fn run_child(name: &str) -> Output {
+    child(name, "1")
+        .env("LOG_FILTER", "log_async=info,cli_batteries=info")
+        .env("LOG_FORMAT", "json")
+        .env("LOG_ASYNC", "true")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn drains_on_success() {
+    if is_child() {
+        run(MOCK_VERSION, succeed);
+        return;
+    }
+    let output = run_child("drains_on_success");
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches(r#""message":"event""#).count(),
+        EVENTS,
+        "{stderr}"
+    );
+    let last = stderr.lines().last().unwrap();
+    assert!(last.contains("Program terminating normally"), "{stderr}");
+}
+
+#[test]
+fn drains_on_error() {
+    if is_child() {
+        run(MOCK_VERSION, fail);
+        return;
+    }
+    let output = run_child("drains_on_error");
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches(r#""message":"event""#).count(),
+        EVENTS,
+        "{stderr}"
+    );
+    let last = stderr.lines().last().unwrap();
+    assert!(last.contains("Program terminating abnormally"), "{stderr}");
+}
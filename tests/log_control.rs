@@ -0,0 +1,85 @@
+#![cfg(all(unix, not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--log-control-socket` in a child process: the test binary runs itself
+//! again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::{env, process};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+use tracing::{debug, info};
+
+async fn app(_options: Options) -> Result<()> {
+    let stream = UnixStream::connect(env::var("LOG_CONTROL_SOCKET")?).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut replies = BufReader::new(reader).lines();
+    for command in [
+        "filter log_control=debug",
+        "filter log_control=nonsense",
+        "filter reset",
+    ] {
+        debug!(command, "before");
+        writer.write_all(format!("{command}\n").as_bytes()).await?;
+        let reply = replies.next_line().await?.unwrap_or_default();
+        info!(reply, "replied");
+        debug!(command, "after");
+    }
+    Ok(())
+}
+
+#[test]
+fn log_control() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let socket = env::temp_dir().join(format!("log-control-{}.sock", process::id()));
+    let output = child("log_control", "1")
+        .env("LOG_FORMAT", "compact")
+        .env("LOG_FILTER", "log_control=info")
+        .env("LOG_CONTROL_SOCKET", &socket)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        !stderr.contains("before command=\"filter log_control=debug\""),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("replied reply=\"ok error,log_control=debug\""),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("after command=\"filter log_control=debug\""),
+        "{stderr}"
+    );
+
+    // A bad filter is answered on the same connection, the old one stays
+    assert!(
+        stderr.contains("replied reply=\"error Error parsing filter: "),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("after command=\"filter log_control=nonsense\""),
+        "{stderr}"
+    );
+
+    // Back to the filter of the command line
+    assert!(
+        stderr.contains("replied reply=\"ok error,log_control=info\""),
+        "{stderr}"
+    );
+    assert!(
+        !stderr.contains("after command=\"filter reset\""),
+        "{stderr}"
+    );
+
+    // Removed at shutdown
+    assert!(!socket.exists());
+}
@@ -0,0 +1,53 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! The warning and error summary and `--fail-on-error-logs` in a child
+//! process: the test binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::{log_counters, run};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{ensure, Result};
+use std::process::Output;
+use tracing::{error, warn};
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    // Startup may warn about the environment, like running as root
+    let before = log_counters();
+    warn!("skipped a record");
+    error!("could not write a record");
+    error!("could not write another record");
+    let after = log_counters();
+    ensure!(after.warn - before.warn == 1, "{before:?} {after:?}");
+    ensure!(after.error - before.error == 2, "{before:?} {after:?}");
+    Ok(())
+}
+
+/// Run the test `name` in a child process, with `--fail-on-error-logs` if
+/// `fail`.
+fn run_child(name: &str, fail: bool) -> Output {
+    child(name, "1")
+        .env("LOG_FILTER", "log_counters=info,cli_batteries=info")
+        .env("FAIL_ON_ERROR_LOGS", fail.to_string())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn log_counters_summary() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let output = run_child("log_counters_summary", false);
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(", 2 errors"), "{stderr}");
+
+    let output = run_child("log_counters_summary", true);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 errors logged"), "{stderr}");
+    // The error report is one more
+    assert!(stderr.contains(", 3 errors"), "{stderr}");
+}
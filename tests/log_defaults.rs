@@ -0,0 +1,120 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Builds `tests/fixtures/log-defaults-app`, which has a `log-defaults.toml`,
+//! and checks the embedded defaults and their precedence. Spawns a nested
+//! `cargo` build, so it is ignored by default.
+use serde_json::Value;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn build() -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    // Separate target dir to avoid contending for the lock of the outer build.
+    let target_dir = root.join("target").join("log-defaults-app");
+    let output = Command::new(cargo)
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(root.join("tests/fixtures/log-defaults-app/Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env_remove("CLI_BATTERIES_LOG_DEFAULTS")
+        .output()
+        .expect("Could not run cargo");
+    assert!(
+        output.status.success(),
+        "Building the log defaults app failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    target_dir.join("debug").join("log-defaults-app")
+}
+
+fn run(app: &Path, args: &[&str], envs: &[(&str, &str)]) -> Output {
+    let output = Command::new(app)
+        .args(args)
+        .env("SESSION_LOG", "off")
+        .env_remove("LOG_FILTER")
+        .env_remove("LOG_FORMAT")
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    output
+}
+
+/// The `--print-log-filter` lines as words.
+fn print_log_filter(app: &Path, args: &[&str]) -> Vec<Vec<String>> {
+    let output = run(app, &[&["--print-log-filter"], args].concat(), &[]);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split_whitespace().map(str::to_owned).collect())
+        .collect()
+}
+
+#[test]
+#[ignore = "slow, spawns a nested cargo build"]
+fn log_defaults() {
+    let app = build();
+
+    assert_eq!(print_log_filter(&app, &[]), [
+        ["default", "error", "--verbose"],
+        ["cli_batteries", "info", "--verbose"],
+        ["dep", "warn", "built-in"],
+        ["log_defaults_app", "info", "--verbose"],
+        ["noisy", "off", "built-in"],
+    ]);
+    let filter = print_log_filter(&app, &["--log-filter", "dep=debug,noisy=error"]);
+    assert_eq!(filter[2], ["dep", "debug", "--log-filter"]);
+    assert_eq!(filter[4], ["noisy", "error", "--log-filter"]);
+
+    // `--print-config` marks the flags with a built-in value
+    let config = |args: &[&str]| -> Value {
+        let output = run(&app, &[&["--print-config"], args].concat(), &[]);
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+    let built_in = config(&[]);
+    assert_eq!(
+        built_in["log_filter"],
+        serde_json::json!({
+            "value": "dep=warn,log_defaults_app=debug,noisy=off",
+            "source": "built-in",
+        })
+    );
+    assert_eq!(built_in["log_format"]["value"], "json");
+    assert_eq!(built_in["log_format"]["source"], "built-in");
+    let given = config(&["--log-format", "compact"]);
+    assert_eq!(given["log_format"]["source"], "command-line");
+
+    // The built-in format is JSON, and the built-in filter applies.
+    let output = run(&app, &[], &[]);
+    let messages = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .map(|event| event["fields"]["message"].as_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    for (message, logged) in [
+        ("app info", true),
+        ("dep info", false),
+        ("dep warn", true),
+        ("noisy error", false),
+    ] {
+        assert_eq!(
+            messages.iter().any(|m| m == message),
+            logged,
+            "{messages:?}"
+        );
+    }
+
+    // The command line and environment override the built-in format.
+    for (args, envs) in [
+        (&["--log-format", "compact"][..], &[][..]),
+        (&[][..], &[("LOG_FORMAT", "compact")][..]),
+    ] {
+        let stderr = String::from_utf8(run(&app, args, envs).stderr).unwrap();
+        assert!(stderr.contains("app info"), "{stderr}");
+        assert!(!stderr.starts_with('{'), "{stderr}");
+    }
+}
@@ -0,0 +1,63 @@
+#![cfg(all(unix, feature = "signals", not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Reloading `--log-filter-file` on `SIGHUP` in a child process: the test
+//! binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::{
+    env, fs,
+    process::{self, Command},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::debug;
+
+/// Write `filter` to the filter file and send `SIGHUP` to this process.
+async fn reload(filter: &str) -> Result<()> {
+    fs::write(env::var("LOG_FILTER_FILE")?, filter)?;
+    Command::new("kill")
+        .args(["-HUP", &process::id().to_string()])
+        .status()?;
+    sleep(Duration::from_millis(500)).await;
+    Ok(())
+}
+
+async fn app(_options: Options) -> Result<()> {
+    debug!("before reload");
+    reload("log_filter_reload=debug,cli_batteries=info").await?;
+    debug!("after reload");
+    reload("log_filter_reload=nonsense").await?;
+    debug!("after failed reload");
+    Ok(())
+}
+
+#[test]
+fn log_filter_reload() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let file = env::temp_dir().join(format!("log-filter-reload-{}", process::id()));
+    let output = child("log_filter_reload", "1")
+        .env("LOG_FILTER", "log_filter_reload=info,cli_batteries=info")
+        .env("LOG_FILTER_FILE", &file)
+        .output()
+        .unwrap();
+    let _ = fs::remove_file(&file);
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("before reload"), "{stderr}");
+    let reloaded = "Reloaded log filter filter:error,cli_batteries=info,log_filter_reload=debug";
+    assert!(stderr.contains(reloaded), "{stderr}");
+    assert!(stderr.contains("after reload"), "{stderr}");
+
+    // The old filter stays in use
+    assert!(
+        stderr.contains("Could not reload log filter, keeping the current one"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("after failed reload"), "{stderr}");
+}
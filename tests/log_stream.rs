@@ -0,0 +1,53 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--log-stream` in a child process: the test binary runs itself again with
+//! [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::process::Output;
+use tracing::info;
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    info!("logged event");
+    Ok(())
+}
+
+/// Run the test `name` in a child process with `format` and `stream`.
+fn run_child(name: &str, format: &str, stream: Option<&str>) -> Output {
+    let mut command = child(name, "1");
+    command
+        .env("LOG_FILTER", "log_stream=info")
+        .env("LOG_FORMAT", format);
+    if let Some(stream) = stream {
+        command.env("LOG_STREAM", stream);
+    }
+    command.output().unwrap()
+}
+
+#[test]
+fn log_stream() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    for format in ["compact", "pretty", "json"] {
+        let streams = [
+            (None, false),
+            (Some("stderr"), false),
+            (Some("stdout"), true),
+        ];
+        for (stream, to_stdout) in streams {
+            let output = run_child("log_stream", format, stream);
+            assert!(output.status.success(), "{output:?}");
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let context = format!("{format} {stream:?}: {output:?}");
+            assert_eq!(stdout.contains("logged event"), to_stdout, "{context}");
+            assert_eq!(stderr.contains("logged event"), !to_stdout, "{context}");
+        }
+    }
+}
@@ -0,0 +1,41 @@
+#![cfg(all(target_os = "linux", not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--memory-hard-limit` in a child process: the test binary runs itself
+//! again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::{await_shutdown, memory_pressure, run};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{ensure, Result};
+
+/// Runs until the memory guard shuts it down, and then succeeds.
+async fn app(_options: Options) -> Result<()> {
+    await_shutdown().await;
+    ensure!(memory_pressure(), "shut down without memory pressure");
+    Ok(())
+}
+
+#[test]
+fn hard_limit() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    // Any process is above a limit of one kibibyte
+    let output = child("hard_limit", "1")
+        .env("LOG_FILTER", "memory_guard=info,cli_batteries=info")
+        .env("MEMORY_HARD_LIMIT", "1KiB")
+        .env("MEMORY_CHECK_INTERVAL", "10ms")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(75), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Memory use above the hard limit, shutting down"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Memory use exceeded --memory-hard-limit"),
+        "{stderr}"
+    );
+}
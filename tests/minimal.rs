@@ -0,0 +1,75 @@
+#![cfg(feature = "minimal")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! The `minimal` build in a child process: the test binary runs itself again
+//! with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::{run, Runner};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::process::Output;
+use tracing::{debug, info};
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    info!(answer = 42, "logged event");
+    debug!("debug event");
+    Ok(())
+}
+
+/// Run the test `name` in a child process with the environment `vars`.
+fn run_child(name: &str, vars: &[(&str, &str)]) -> Output {
+    child(name, "1")
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn minimal() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+
+    // Tiny format on stderr with the version banner, the options of the full
+    // build are not there
+    let output = run_child("minimal", &[
+        ("LOG_FILTER", "minimal=info,cli_batteries=info"),
+        ("LOG_FORMAT", "json"),
+        ("SESSION_LOG", "on"),
+    ]);
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("test v0.0.0"), "{stderr}");
+    assert!(stderr.contains("logged event answer:42"), "{stderr}");
+    assert!(!stderr.contains("debug event"), "{stderr}");
+    assert!(!stderr.contains('{'), "{stderr}");
+
+    // The filter works like in the full build
+    let output = run_child("minimal", &[("LOG_FILTER", "minimal=debug")]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("debug event"), "{stderr}");
+}
+
+#[test]
+fn ignored_settings() {
+    if is_child() {
+        Runner::new(MOCK_VERSION)
+            .min_open_files(1)
+            .check("slow", "Slow consistency checks")
+            .run(app);
+        return;
+    }
+
+    // Settings without options in the minimal build are reported
+    let output = run_child("ignored_settings", &[("LOG_FILTER", "cli_batteries=warn")]);
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`Runner::min_open_files` is ignored"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("`Runner::check` is ignored"), "{stderr}");
+    assert!(!stderr.contains("`Runner::explain`"), "{stderr}");
+}
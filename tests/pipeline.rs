@@ -0,0 +1,117 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+#![cfg(all(unix, not(feature = "minimal")))]
+//! Pipelines of the `minimal` example as one trace. The stage spawning the
+//! next one is the test binary, run again with [`CHILD`] set.
+mod common;
+
+use cli_batteries::{pipeline, run};
+use common::{is_child, Options, CHILD, MOCK_VERSION};
+use eyre::{ensure, Result};
+use serde_json::Value;
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{id as pid, Command, Stdio},
+};
+
+/// The `minimal` example, built by `cargo test` next to the test binaries.
+fn minimal() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("examples")
+        .join("minimal")
+}
+
+/// A JSON log output command, for the `minimal` example by default.
+fn stage(program: PathBuf) -> Command {
+    let mut command = Command::new(program);
+    command
+        .env_remove(pipeline::TRACE_FILE)
+        .env("SESSION_LOG", "off")
+        // Stages run at the same time, each needs its own metrics port.
+        .env("PROMETHEUS", "http://127.0.0.1:0/metrics")
+        .env(
+            "LOG_FILTER",
+            "minimal=info,pipeline=info,cli_batteries=info",
+        )
+        .env("LOG_FORMAT", "json");
+    command
+}
+
+/// The `trace_id` fields of the events with `message` in a JSON log output.
+fn trace_ids(log: &[u8], message: &str) -> Vec<String> {
+    String::from_utf8_lossy(log)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["fields"]["message"] == message)
+        .map(|event| {
+            event["fields"]["trace_id"]
+                .as_str()
+                .unwrap_or("none")
+                .to_owned()
+        })
+        .collect()
+}
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn spawn_minimal(_options: Options) -> Result<()> {
+    let status = pipeline::spawn_downstream(&mut stage(minimal()))?.wait()?;
+    ensure!(status.success(), "downstream failed: {status}");
+    Ok(())
+}
+
+#[test]
+fn spawn_downstream() {
+    if is_child() {
+        run(MOCK_VERSION, spawn_minimal);
+        return;
+    }
+    let output = stage(env::current_exe().unwrap())
+        .args(["spawn_downstream", "--exact", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    // The downstream stage writes to the same stderr.
+    let started = trace_ids(&output.stderr, "Started a pipeline trace");
+    let downstream = trace_ids(&output.stderr, "Hello, world!");
+    assert_eq!(started.len(), 1, "{output:?}");
+    assert_eq!(started[0].len(), 32, "{output:?}");
+    assert_eq!(downstream, started, "{output:?}");
+}
+
+#[test]
+fn trace_file() {
+    let path = env::temp_dir().join(format!("cli-batteries-trace-file-{}", pid()));
+    let _ = fs::remove_file(&path);
+
+    // Like `minimal | minimal` in a shell.
+    let mut first = stage(minimal())
+        .env(pipeline::TRACE_FILE, &path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let second = stage(minimal())
+        .env(pipeline::TRACE_FILE, &path)
+        .stdin(first.stdout.take().unwrap())
+        .output()
+        .unwrap();
+    let first = first.wait_with_output().unwrap();
+    assert!(first.status.success(), "{first:?}");
+    assert!(second.status.success(), "{second:?}");
+
+    let traceparent = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let trace_id = traceparent.split('-').nth(1).unwrap().to_owned();
+    assert_eq!(trace_ids(&first.stderr, "Hello, world!"), [
+        trace_id.as_str()
+    ]);
+    assert_eq!(trace_ids(&second.stderr, "Hello, world!"), [
+        trace_id.as_str()
+    ]);
+}
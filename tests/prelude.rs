@@ -0,0 +1,29 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Builds `tests/fixtures/prelude-app`, which only depends on `cli-batteries`
+//! and imports everything from the prelude. Spawns a nested `cargo` build, so
+//! it is ignored by default.
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+#[ignore = "slow, spawns a nested cargo build"]
+fn prelude_app() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let output = Command::new(cargo)
+        .args(["run", "--quiet", "--manifest-path"])
+        .arg(root.join("tests/fixtures/prelude-app/Cargo.toml"))
+        // Separate target dir to avoid contending for the lock of the outer build.
+        .arg("--target-dir")
+        .arg(root.join("target").join("prelude-app"))
+        .args(["--", "--help"])
+        .output()
+        .expect("Could not run cargo");
+    assert!(
+        output.status.success(),
+        "Building the prelude app failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let help = String::from_utf8(output.stdout).unwrap();
+    assert!(help.contains("--greeting"), "{help}");
+    assert!(help.contains("--log-filter"), "{help}");
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "rayon")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! Parentage of events from rayon workers in [`in_span_scope`].
+use cli_batteries::rayon::{in_span_scope, install_span_propagation};
+use rayon::prelude::*;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+use tracing::{dispatcher, info, info_span, Event, Subscriber};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+/// Records the span path and thread name of every event.
+#[derive(Clone, Default)]
+struct Parents(Arc<Mutex<Vec<(String, String)>>>);
+
+impl<S> Layer<S> for Parents
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let path = ctx.event_scope(event).map_or_else(String::new, |scope| {
+            scope
+                .from_root()
+                .map(|span| span.name())
+                .collect::<Vec<_>>()
+                .join(":")
+        });
+        let thread = thread::current().name().unwrap_or_default().to_owned();
+        self.0.lock().unwrap().push((path, thread));
+    }
+}
+
+#[test]
+fn parallel_map_parentage() {
+    install_span_propagation("test").unwrap();
+    let parents = Parents::default();
+    let subscriber = Registry::default().with(parents.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        info_span!("request").in_scope(|| {
+            let squares = in_span_scope(|| {
+                (0..1000_u64)
+                    .into_par_iter()
+                    .map(|i| {
+                        info_span!("item").in_scope(|| info!(i, "squared"));
+                        i * i
+                    })
+                    .sum::<u64>()
+            });
+            assert_eq!(squares, 332_833_500);
+        });
+        // Outside the section the workers have neither span nor subscriber.
+        let dispatch = dispatcher::get_default(Clone::clone);
+        rayon::broadcast(|_| dispatcher::with_default(&dispatch, || info!("after")));
+    });
+
+    let events = parents.0.lock().unwrap().clone();
+    let (squared, after): (Vec<_>, Vec<_>) =
+        events.iter().partition(|(path, _)| path.ends_with("item"));
+    assert_eq!(squared.len(), 1000);
+    assert!(squared.iter().all(|(path, _)| path == "request:item"));
+    assert!(squared
+        .iter()
+        .all(|(_, thread)| thread.starts_with("test-rayon-")));
+    assert_eq!(after.len(), rayon::current_num_threads());
+    assert!(after.iter().all(|(path, _)| path.is_empty()), "{after:?}");
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "shmem-logs")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--log-shmem` read from another process: the test binary runs itself again
+//! with [`common::CHILD`] set, as the writer.
+mod common;
+
+use cli_batteries::{logs::ShmemReader, run};
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Write},
+    process::Stdio,
+};
+use tracing::info;
+
+const EVENTS: u64 = 100;
+
+/// Log, then wait for the reader before shutting down.
+async fn app(_options: Options) -> Result<()> {
+    for i in 0..EVENTS {
+        info!(i, "event");
+    }
+    println!("ready");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(())
+}
+
+#[test]
+fn read_from_other_process() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let dir = env::temp_dir();
+    let mut child = child("read_from_other_process", "1")
+        .env("LOG_FILTER", "shmem_log=info")
+        .env("LOG_SHMEM", dir.join("{crate}-{pid}.ring"))
+        .env("LOG_SHMEM_SIZE", "1MiB")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let path = dir.join(format!("test-{}.ring", child.id()));
+
+    // The test runner prints its own output first, without a newline before
+    // ours. Kept open until the child exits, it also prints the test result.
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert!(stdout.any(|line| line.unwrap().ends_with("ready")));
+
+    let mut reader = ShmemReader::attach(&path).unwrap();
+    assert_eq!(reader.pid(), child.id());
+    let mut events = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        if record.message == "event" {
+            events.push(record.fields["i"].as_u64().unwrap());
+        }
+    }
+    assert_eq!(events, (0..EVENTS).collect::<Vec<_>>());
+    assert_eq!(reader.skipped(), 0);
+
+    // Removed at shutdown.
+    writeln!(child.stdin.take().unwrap()).unwrap();
+    assert!(child.wait().unwrap().success());
+    drop(stdout);
+    assert!(!path.exists());
+}
@@ -0,0 +1,43 @@
+#![cfg(not(feature = "minimal"))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--warn-span-cardinality` with the global subscriber, in a child process:
+//! the test binary runs itself again with [`common::CHILD`] set.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::Result;
+use tracing::{info, info_span};
+
+async fn app(_options: Options) -> Result<()> {
+    for user in 0..20 {
+        let name = format!("load user {user}");
+        info_span!("load_user", otel.name = %name).in_scope(|| info!("loaded"));
+        info_span!("request", otel.name = "GET /users").in_scope(|| info!("served"));
+    }
+    Ok(())
+}
+
+#[test]
+fn warns_once() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let output = child("warns_once", "1")
+        .env("LOG_FILTER", "span_cardinality=info,cli_batteries=warn")
+        .env("LOG_FORMAT", "json")
+        .env("WARN_SPAN_CARDINALITY", "true")
+        .env("SPAN_CARDINALITY_LIMIT", "5")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("distinct values").count(), 1, "{stderr}");
+    assert!(
+        stderr.contains("Span otel.name has more than 5"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("load_user"), "{stderr}");
+    assert!(stderr.contains("span_cardinality.rs:"), "{stderr}");
+}
@@ -0,0 +1,138 @@
+#![cfg(all(feature = "support-bundle", not(feature = "minimal")))]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--support-bundle` in a child process: the test binary runs itself again
+//! with [`common::CHILD`] set.
+mod common;
+
+use clap::Parser;
+use cli_batteries::{default_from_clap, run};
+use common::{child, is_child, MOCK_VERSION};
+use eyre::{bail, Result};
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use std::{collections::HashMap, env, fs, io::Read, path::Path, process::id as pid};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+struct Options {
+    /// The test runner arguments the child is started with.
+    filter: Option<String>,
+
+    #[clap(long)]
+    exact: bool,
+
+    #[clap(long)]
+    nocapture: bool,
+
+    /// Masked in the bundle.
+    #[clap(long, env = "TEST_API_TOKEN")]
+    api_token: Option<String>,
+}
+
+default_from_clap!(Options);
+
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    bail!("the app does not run");
+}
+
+/// The files in the bundle at `path`, by name.
+fn unpack(path: &Path) -> HashMap<String, Vec<u8>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(fs::File::open(path).unwrap()));
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            (name, contents)
+        })
+        .collect()
+}
+
+#[test]
+fn write_bundle() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    let dir = env::temp_dir().join(format!("cli-batteries-support-bundle-{}", pid()));
+    let _ = fs::remove_dir_all(&dir);
+    let logs = dir.join("logs");
+    fs::create_dir_all(&logs).unwrap();
+    for i in 1..=4 {
+        let name = format!("20240101T00000{i}.000Z-{i}.log");
+        fs::write(logs.join(name), format!("run {i}\n")).unwrap();
+    }
+    fs::write(logs.join("daemon.out"), "panicked at src/main.rs:1:1\n").unwrap();
+    let flame = dir.join("flame.folded");
+    fs::write(&flame, "main;work 10\n").unwrap();
+    let bundle = dir.join("bundle.tar.gz");
+
+    let output = child("write_bundle", "1")
+        .env("SUPPORT_BUNDLE", &bundle)
+        .env("SUPPORT_BUNDLE_EXCLUDE", "process")
+        .env("TRACE_FLAME", &flame)
+        .env("SESSION_LOG", format!("dir={}", logs.display()))
+        .env("TEST_API_TOKEN", "hunter2")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let files = unpack(&bundle);
+    let manifest: Value = serde_json::from_slice(&files["manifest.json"]).unwrap();
+    let status = |part: &str| {
+        let parts = manifest["parts"].as_array().unwrap();
+        let part = parts.iter().find(|entry| entry["part"] == part).unwrap();
+        part["status"].as_str().unwrap().to_owned()
+    };
+    assert_eq!(manifest["name"], "cli-test");
+    for part in [
+        "config",
+        "version",
+        "session-logs",
+        "recent-errors",
+        "environment",
+        "crash-reports",
+        "profiles",
+    ] {
+        assert_eq!(status(part), "included", "{part}: {manifest:#}");
+    }
+    assert_eq!(status("process"), "excluded");
+
+    // Secrets are masked, the source is kept.
+    let config: Value = serde_json::from_slice(&files["config.json"]).unwrap();
+    assert_eq!(config["api_token"]["value"], "***");
+    assert_eq!(config["api_token"]["source"], "environment");
+    assert_eq!(
+        config["support_bundle_exclude"]["value"],
+        json!(["process"])
+    );
+    assert!(!files
+        .values()
+        .any(|file| { String::from_utf8_lossy(file).contains("hunter2") }));
+
+    // The newest session logs of earlier runs, not the one of this run.
+    let mut session_logs = files
+        .keys()
+        .filter(|name| name.starts_with("session-logs/"))
+        .cloned()
+        .collect::<Vec<_>>();
+    session_logs.sort();
+    assert_eq!(session_logs, [
+        "session-logs/20240101T000002.000Z-2.log",
+        "session-logs/20240101T000003.000Z-3.log",
+        "session-logs/20240101T000004.000Z-4.log",
+    ]);
+    assert_eq!(
+        files["crash-reports/daemon.out"],
+        b"panicked at src/main.rs:1:1\n"
+    );
+
+    // The profile of an earlier run is bundled, not overwritten.
+    assert_eq!(files["profiles/flame.folded"], b"main;work 10\n");
+    assert_eq!(fs::read(&flame).unwrap(), b"main;work 10\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
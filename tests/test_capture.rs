@@ -0,0 +1,93 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `#[cli_batteries::test]`, each case run in a child process: the test
+//! binary runs itself again with only the one ignored case selected.
+use eyre::{bail, Result};
+use std::{
+    env,
+    process::{Command, Output},
+};
+use tracing::{debug, info};
+
+/// Run the ignored case `test`.
+fn spawn(test: &str, nocapture: bool) -> Output {
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.args([test, "--exact", "--ignored"]);
+    if nocapture {
+        command.arg("--nocapture");
+    }
+    command.env_remove("LOG_FILTER").output().unwrap()
+}
+
+#[cli_batteries::test]
+#[ignore = "run by `sync_failure`"]
+fn failing_sync() {
+    debug!(answer = 42, "before the panic");
+    panic!("sync failure");
+}
+
+#[cli_batteries::test]
+#[ignore = "run by `async_failure`"]
+async fn failing_async() -> Result<()> {
+    tokio::task::yield_now().await;
+    info!("before the error");
+    bail!("async failure");
+}
+
+#[cli_batteries::test]
+#[ignore = "run by `success`"]
+async fn passing() -> Result<()> {
+    info!("quiet on success");
+    Ok(())
+}
+
+#[test]
+fn sync_failure() {
+    let output = spawn("failing_sync", false);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("---- tracing output ----"), "{stdout}");
+    assert!(stdout.contains("before the panic"), "{stdout}");
+    assert!(stdout.contains("answer: 42"), "{stdout}");
+    assert!(stdout.contains("sync failure"), "{stdout}");
+}
+
+#[test]
+fn async_failure() {
+    let output = spawn("failing_async", false);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("before the error"), "{stdout}");
+    assert!(stdout.contains("async failure"), "{stdout}");
+}
+
+#[test]
+fn success() {
+    let output = spawn("passing", false);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stdout.contains("quiet on success"), "{stdout}");
+    assert!(!stderr.contains("quiet on success"), "{stderr}");
+}
+
+#[test]
+fn nocapture() {
+    let output = spawn("failing_sync", true);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("before the panic"), "{stderr}");
+    // Shown once, not again by a global subscriber.
+    assert_eq!(stderr.matches("before the panic").count(), 1, "{stderr}");
+
+    let output = spawn("passing", true);
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("quiet on success"), "{stderr}");
+}
+
+#[test]
+fn guard() {
+    let subscriber = cli_batteries::test_subscriber();
+    info!(target: "guard", "captured");
+    assert!(subscriber.output().contains("captured"));
+}
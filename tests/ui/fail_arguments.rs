@@ -0,0 +1,6 @@
+#[cli_batteries::test]
+fn arguments(value: u32) {
+    assert_eq!(value, 0);
+}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[cli_batteries::test(flavor = "multi_thread")]
+fn attribute_arguments() {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[cli_batteries::test]
+fn generic<T: Default>() {
+    let _ = T::default();
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+#[cli_batteries::test]
+async fn unit() {
+    tokio::task::yield_now().await;
+}
+
+#[cli_batteries::test]
+async fn result() -> eyre::Result<()> {
+    tokio::task::yield_now().await;
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+#[cli_batteries::test]
+fn result() -> Result<(), String> {
+    Ok(())
+}
+
+#[cli_batteries::test]
+pub fn public() -> std::io::Result<()> {
+    let _guard = cli_batteries::test_subscriber();
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+#[cli_batteries::test]
+fn sync() {
+    tracing::info!("sync");
+}
+
+#[cli_batteries::test]
+#[should_panic(expected = "expected")]
+fn should_panic() {
+    panic!("expected");
+}
+
+fn main() {}
@@ -0,0 +1,127 @@
+#![cfg(feature = "watch")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--watch` in a child process: the test binary runs itself again with
+//! [`common::CHILD`] set, and the test changes the watched config file after
+//! every run it sees in the log output.
+mod common;
+
+use clap::Parser;
+use cli_batteries::{await_shutdown, default_from_clap, shutdown, Runner};
+use common::{child, is_child, MOCK_VERSION};
+use eyre::Result;
+use serde_json::Value;
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{self, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tracing::info;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+struct Options {
+    /// The test runner arguments the child is started with.
+    filter: Option<String>,
+
+    #[clap(long)]
+    exact: bool,
+
+    #[clap(long)]
+    nocapture: bool,
+
+    /// Read at the start of every run.
+    #[clap(long, env = "TEST_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+/// Logs the config, then runs until stopped, or shuts down the process if the
+/// config says so.
+async fn app(options: Options) -> Result<()> {
+    let config = fs::read_to_string(options.config.unwrap())?;
+    info!(config, "app started");
+    if config == "stop" {
+        shutdown();
+        return Ok(());
+    }
+    await_shutdown().await;
+    Ok(())
+}
+
+#[test]
+fn watch() {
+    if is_child() {
+        Runner::new(MOCK_VERSION).run_watched(app);
+        return;
+    }
+    let dir = env::temp_dir().join(format!("cli-batteries-watch-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config");
+    fs::write(&config, "one").unwrap();
+
+    let mut child = child("watch", "1")
+        .env("LOG_FORMAT", "json")
+        .env("LOG_FILTER", "watch=info,cli_batteries=info")
+        .env("WATCH", &dir)
+        .env("TEST_CONFIG", &config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (sender, lines) = mpsc::channel();
+    let stderr = child.stderr.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let _ = sender.send(line.unwrap());
+        }
+    });
+
+    // The iteration and config of every run
+    let mut runs = Vec::new();
+    let mut output = Vec::new();
+    while runs.len() < 3 {
+        let Ok(line) = lines.recv_timeout(Duration::from_secs(30)) else {
+            break;
+        };
+        output.push(line.clone());
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if event["fields"]["message"] != "app started" {
+            continue;
+        }
+        runs.push((
+            event["span"]["run.iteration"].clone(),
+            event["fields"]["config"].clone(),
+        ));
+        match runs.len() {
+            1 => fs::write(&config, "two").unwrap(),
+            2 => fs::write(&config, "stop").unwrap(),
+            _ => {}
+        }
+    }
+    // Runs until stopped otherwise
+    if runs.len() < 3 {
+        let _ = child.kill();
+    }
+    let status = child.wait().unwrap();
+    output.extend(lines.try_iter());
+    let _ = fs::remove_dir_all(&dir);
+
+    assert!(status.success(), "{output:#?}");
+    assert_eq!(
+        runs,
+        [
+            (1.into(), "one".into()),
+            (2.into(), "two".into()),
+            (3.into(), "stop".into())
+        ],
+        "{output:#?}"
+    );
+}
@@ -0,0 +1,92 @@
+#![cfg(feature = "webhook")]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+//! `--error-webhook-url` of a failing app: the test binary runs itself again
+//! with [`common::CHILD`] set, and a local server captures what it posts.
+mod common;
+
+use cli_batteries::run;
+use common::{child, is_child, Options, MOCK_VERSION};
+use eyre::{bail, Result};
+use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+use serde_json::Value;
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::runtime::Runtime;
+use tracing::{error, warn};
+
+/// Logs errors, then fails.
+#[allow(clippy::unused_async)] // Signature required by `run`
+async fn app(_options: Options) -> Result<()> {
+    warn!("not posted");
+    for attempt in 0..3 {
+        error!(attempt, "connection lost");
+    }
+    bail!("giving up");
+}
+
+#[test]
+fn webhook() {
+    if is_child() {
+        run(MOCK_VERSION, app);
+        return;
+    }
+    Runtime::new().unwrap().block_on(capture_payloads());
+}
+
+async fn capture_payloads() {
+    let payloads = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let captured = payloads.clone();
+    let make_service = make_service_fn(move |_| {
+        let captured = captured.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let captured = captured.clone();
+                async move {
+                    let body = to_bytes(request.into_body()).await?;
+                    captured
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_slice(&body).unwrap());
+                    Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let url = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+
+    // The server keeps running while the child is waited for on another thread
+    let mut command = child("webhook", "1");
+    command
+        .env("ERROR_WEBHOOK_URL", &url)
+        .env("ERROR_WEBHOOK_FORMAT", "json");
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!output.status.success(), "{output:?}");
+
+    // The repeated error once, and the report after the runtime has ended.
+    let payloads = payloads.lock().unwrap().clone();
+    let messages = payloads
+        .iter()
+        .map(|payload| payload["message"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, [
+        "connection lost",
+        "giving up",
+        "Program terminating abnormally"
+    ]);
+    for payload in &payloads {
+        assert_eq!(payload["app"], "test");
+        assert_eq!(payload["version"], "v0.0.0");
+        assert!(!payload["hostname"].as_str().unwrap().is_empty());
+    }
+}